@@ -0,0 +1,383 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Measuring the throughput of the maps of BPF programs
+//!
+//! `cargo bpf bench` loads a probe, attaches one of its programs and counts
+//! what the program puts in a map for a fixed duration, while the workload
+//! run by the user triggers the program. The events of perf maps are
+//! counted, and the `u64` counter at index 0 of array maps is sampled every
+//! second. The events per second of each second and of the whole duration
+//! are printed, with the mean interval between events and, for perf maps,
+//! the latency of reading the events.
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use futures::stream::StreamExt;
+use goblin::elf::Elf;
+use libbpf_sys::{BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_PERCPU_ARRAY, BPF_MAP_TYPE_PERF_EVENT_ARRAY};
+use redbpf::load::{Loaded, Loader};
+use redbpf::{xdp, Array, Module, PerCpuArray, Program::*};
+use tokio::runtime;
+use tokio::time::{self, Instant};
+
+use crate::CommandError;
+
+pub struct BenchOptions {
+    /// The ELF file built by `cargo bpf build`
+    pub elf: PathBuf,
+    /// The name of the program to attach
+    pub program: String,
+    /// The name of the perf map or array map to sample
+    pub map: String,
+    pub duration: Duration,
+    /// The interface to attach XDP programs to
+    pub interface: Option<String>,
+    /// Only check that the ELF file has the program and the map, without
+    /// loading it
+    pub dry_run: bool,
+}
+
+/// The number of events counted in each second of a benchmark
+#[derive(Debug)]
+pub struct BenchSummary {
+    pub counts: Vec<u64>,
+    pub elapsed: Duration,
+    /// The intervals between the batches of events read from a perf map.
+    /// An event waits in the perf buffer for up to the interval before the
+    /// batch it belongs to is read, so they tell the latency of reading
+    /// events. Empty for array maps, which are sampled every second.
+    pub batch_intervals: Vec<Duration>,
+}
+
+impl BenchSummary {
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn events_per_sec(&self) -> f64 {
+        if self.elapsed.as_secs_f64() == 0.0 {
+            return 0.0;
+        }
+        self.total() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// The mean interval between events, or `None` if no event was counted
+    pub fn mean_event_interval(&self) -> Option<Duration> {
+        match self.total() {
+            0 => None,
+            total => Some(self.elapsed.div_f64(total as f64)),
+        }
+    }
+
+    /// The mean and the maximum of `batch_intervals`
+    pub fn batch_latency(&self) -> Option<(Duration, Duration)> {
+        let max = self.batch_intervals.iter().max().copied()?;
+        let sum: Duration = self.batch_intervals.iter().sum();
+        Some((sum / self.batch_intervals.len() as u32, max))
+    }
+
+    fn print(&self, map: &str) {
+        println!(
+            "{}: {} events in {:.1}s, {:.1} events/s",
+            map,
+            self.total(),
+            self.elapsed.as_secs_f64(),
+            self.events_per_sec()
+        );
+        let min = self.counts.iter().min().copied().unwrap_or(0);
+        let max = self.counts.iter().max().copied().unwrap_or(0);
+        println!("events per second: min {}, max {}", min, max);
+        if let Some(interval) = self.mean_event_interval() {
+            println!(
+                "mean interval between events: {:.3}us",
+                interval.as_secs_f64() * 1e6
+            );
+        }
+        if let Some((mean, max)) = self.batch_latency() {
+            println!(
+                "latency of reading events: mean {:.3}ms, max {:.3}ms in {} batches",
+                mean.as_secs_f64() * 1e3,
+                max.as_secs_f64() * 1e3,
+                self.batch_intervals.len()
+            );
+        }
+    }
+}
+
+/// Parse the duration of `cargo bpf bench`, e.g., `10`, `10s`, `500ms` or
+/// `2m`
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => duration.split_at(idx),
+        None => (duration, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: `{}'", duration))?;
+    let duration = match unit {
+        "ms" => Duration::from_millis(number),
+        "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number * 60),
+        _ => return Err(format!("invalid unit of duration: `{}'", unit)),
+    };
+    if duration.as_millis() == 0 {
+        return Err("the duration must be positive".to_string());
+    }
+    Ok(duration)
+}
+
+pub fn bench(opts: &BenchOptions) -> Result<(), CommandError> {
+    check_elf(opts)?;
+    if opts.dry_run {
+        println!(
+            "would sample the `{}' map of the `{}' program for {:?}",
+            opts.map, opts.program, opts.duration
+        );
+        return Ok(());
+    }
+    let rt = runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let summary = rt.block_on(run(opts))?;
+    summary.print(&opts.map);
+    Ok(())
+}
+
+// Check that the ELF file has the sections of the program and the map
+fn check_elf(opts: &BenchOptions) -> Result<(), CommandError> {
+    let bytes = fs::read(&opts.elf)
+        .map_err(|e| CommandError(format!("failed to read {:?}: {}", opts.elf, e)))?;
+    let binary = Elf::parse(&bytes)
+        .map_err(|e| CommandError(format!("failed to parse {:?}: {}", opts.elf, e)))?;
+    let section_names: Vec<&str> = binary
+        .section_headers
+        .iter()
+        .filter_map(|shdr| binary.shdr_strtab.get_at(shdr.sh_name))
+        .collect();
+    let has_program = section_names.iter().any(|name| {
+        !name.starts_with("maps")
+            && name.contains('/')
+            && name.rsplit('/').next() == Some(opts.program.as_str())
+    });
+    if !has_program {
+        return Err(CommandError(format!(
+            "program `{}' not found in {:?}",
+            opts.program, opts.elf
+        )));
+    }
+    // maps are placed in `maps/<name>` sections or the legacy `maps` section
+    let map_section = format!("maps/{}", opts.map);
+    let has_map = section_names.iter().any(|name| *name == map_section)
+        || binary.syms.iter().any(|sym| {
+            binary.strtab.get_at(sym.st_name) == Some(opts.map.as_str())
+                && binary
+                    .section_headers
+                    .get(sym.st_shndx)
+                    .and_then(|shdr| binary.shdr_strtab.get_at(shdr.sh_name))
+                    == Some("maps")
+        });
+    if !has_map {
+        return Err(CommandError(format!(
+            "map `{}' not found in {:?}",
+            opts.map, opts.elf
+        )));
+    }
+    Ok(())
+}
+
+async fn run(opts: &BenchOptions) -> Result<BenchSummary, CommandError> {
+    let mut loader = Loader::load_file(&opts.elf)
+        .map_err(|e| CommandError(format!("failed to load {:?}: {:?}", opts.elf, e)))?;
+    attach(&mut loader.module, &opts.program, opts.interface.as_deref())?;
+    let kind = loader
+        .module
+        .map(&opts.map)
+        .map(|map| map.kind)
+        .ok_or_else(|| CommandError(format!("map `{}' not found", opts.map)))?;
+    match kind {
+        BPF_MAP_TYPE_PERF_EVENT_ARRAY => {
+            Ok(count_events(&mut loader, &opts.map, opts.duration).await)
+        }
+        BPF_MAP_TYPE_ARRAY | BPF_MAP_TYPE_PERCPU_ARRAY => {
+            sample_counter(&loader.module, &opts.map, opts.duration).await
+        }
+        _ => Err(CommandError(format!(
+            "the `{}' map is neither a perf map nor an array map",
+            opts.map
+        ))),
+    }
+}
+
+fn attach(module: &mut Module, name: &str, interface: Option<&str>) -> Result<(), CommandError> {
+    let program = module
+        .program_mut(name)
+        .ok_or_else(|| CommandError(format!("program `{}' not found", name)))?;
+    let ret = match program {
+        XDP(prog) => {
+            let iface = interface.ok_or_else(|| {
+                CommandError("XDP program found, but no interface specified".to_string())
+            })?;
+            prog.attach_xdp(iface, xdp::Flags::default())
+        }
+        KProbe(prog) | KRetProbe(prog) => prog.attach_kprobe(name, 0),
+        _ => {
+            return Err(CommandError(format!(
+                "attaching the `{}' program is not supported by bench",
+                name
+            )))
+        }
+    };
+    ret.map_err(|e| CommandError(format!("failed to attach program {}: {:?}", name, e)))
+}
+
+// Count the events of the perf map `map` in each second of `duration`
+async fn count_events(loader: &mut Loaded, map: &str, duration: Duration) -> BenchSummary {
+    let start = Instant::now();
+    let deadline = start + duration;
+    let mut counts = vec![0; seconds(duration)];
+    let mut batch_intervals = vec![];
+    let mut last_batch = start;
+    while let Ok(Some((name, events))) = time::timeout_at(deadline, loader.events.next()).await {
+        if name == map {
+            let now = Instant::now();
+            let second = (now.duration_since(start).as_secs() as usize).min(counts.len() - 1);
+            counts[second] += events.len() as u64;
+            batch_intervals.push(now.duration_since(last_batch));
+            last_batch = now;
+        }
+    }
+    BenchSummary {
+        counts,
+        elapsed: start.elapsed(),
+        batch_intervals,
+    }
+}
+
+// Sample the counter at index 0 of the array map `map` every second of
+// `duration`
+async fn sample_counter(
+    module: &Module,
+    map: &str,
+    duration: Duration,
+) -> Result<BenchSummary, CommandError> {
+    let map = module.map(map).unwrap();
+    let read = || -> Result<u64, CommandError> {
+        let value = if map.kind == BPF_MAP_TYPE_ARRAY {
+            Array::<u64>::new(map).ok().and_then(|array| array.get(0))
+        } else {
+            PerCpuArray::<u64>::new(map)
+                .ok()
+                .and_then(|array| array.get(0))
+                .map(|values| values.sum())
+        };
+        value.ok_or_else(|| {
+            CommandError(format!(
+                "failed to read a u64 counter from the `{}' map",
+                map.name
+            ))
+        })
+    };
+    let start = Instant::now();
+    let mut last = read()?;
+    let mut counts = vec![];
+    for i in 0..seconds(duration) {
+        let end = (Duration::from_secs(i as u64 + 1)).min(duration);
+        time::sleep_until(start + end).await;
+        let value = read()?;
+        counts.push(value.wrapping_sub(last));
+        last = value;
+    }
+    Ok(BenchSummary {
+        counts,
+        elapsed: start.elapsed(),
+        batch_intervals: vec![],
+    })
+}
+
+// The number of seconds of `duration` rounded up
+fn seconds(duration: Duration) -> usize {
+    ((duration.as_millis() + 999) / 1000).max(1) as usize
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::elf_with_sections;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("10s"), Ok(Duration::from_secs(10)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert!(parse_duration("0").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10h").is_err());
+        assert!(parse_duration("-1").is_err());
+        assert_eq!(seconds(Duration::from_millis(1500)), 2);
+        assert_eq!(seconds(Duration::from_millis(500)), 1);
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let dir = tempdir().unwrap();
+        let elf = dir.path().join("probe.elf");
+        fs::write(
+            &elf,
+            elf_with_sections(&["kprobe/do_sys_open", "maps/OPEN_EVENTS"]),
+        )
+        .unwrap();
+        let mut opts = BenchOptions {
+            elf,
+            program: "do_sys_open".to_string(),
+            map: "OPEN_EVENTS".to_string(),
+            duration: Duration::from_secs(1),
+            interface: None,
+            dry_run: true,
+        };
+        assert!(bench(&opts).is_ok());
+
+        opts.map = "MISSING".to_string();
+        let e = bench(&opts).err().unwrap();
+        assert!(e.0.contains("map `MISSING'"), "{}", e.0);
+
+        opts.program = "do_sys_close".to_string();
+        let e = bench(&opts).err().unwrap();
+        assert!(e.0.contains("program `do_sys_close'"), "{}", e.0);
+    }
+
+    #[test]
+    fn test_summary() {
+        let summary = BenchSummary {
+            counts: vec![100, 300],
+            elapsed: Duration::from_secs(2),
+            batch_intervals: vec![Duration::from_millis(10), Duration::from_millis(30)],
+        };
+        assert_eq!(summary.total(), 400);
+        assert_eq!(summary.events_per_sec(), 200.0);
+        assert_eq!(
+            summary.mean_event_interval(),
+            Some(Duration::from_millis(5))
+        );
+        assert_eq!(
+            summary.batch_latency(),
+            Some((Duration::from_millis(20), Duration::from_millis(30)))
+        );
+
+        let idle = BenchSummary {
+            counts: vec![0],
+            elapsed: Duration::from_secs(1),
+            batch_intervals: vec![],
+        };
+        assert_eq!(idle.mean_event_interval(), None);
+        assert_eq!(idle.batch_latency(), None);
+    }
+}
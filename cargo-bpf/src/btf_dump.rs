@@ -0,0 +1,32 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::fs;
+use std::path::Path;
+
+use redbpf::btf;
+
+use crate::CommandError;
+
+/// Pretty-print the `.BTF` section of the ELF file `program`
+///
+/// If `output` is given, the raw `.BTF` section is also written to it so
+/// that it can be examined by other BTF tools.
+pub fn btf_dump(program: &Path, output: Option<&Path>) -> Result<(), CommandError> {
+    let bytes = fs::read(program)
+        .map_err(|e| CommandError(format!("failed to read {:?}: {}", program, e)))?;
+    if let Some(output) = output {
+        let raw = btf::extract_btf_section(&bytes).map_err(|e| {
+            CommandError(format!("failed to extract BTF of {:?}: {:?}", program, e))
+        })?;
+        fs::write(output, raw)?;
+    }
+    let dump = btf::dump_btf_section(&bytes)
+        .map_err(|e| CommandError(format!("failed to parse BTF of {:?}: {:?}", program, e)))?;
+    print!("{}", dump);
+    Ok(())
+}
@@ -5,28 +5,121 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use bpf_sys::headers::build_kernel_version;
-use glob::{glob, PatternError};
-use goblin::elf::{sym::STT_SECTION, Elf};
+use bpf_sys::headers::{build_kernel_version, KernelVersion};
+use glob::{glob, Pattern, PatternError};
+use goblin::elf::{section_header::SHF_EXECINSTR, sym::STT_SECTION, Elf};
 use semver::Version;
+use std::collections::{HashSet, VecDeque};
 use std::convert::From;
 use std::env;
 use std::fmt::{self, Display};
 use std::fs;
-use std::io;
+use std::io::{self, Write};
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
 use toml_edit::{Document, Item};
 
-use redbpf::btf;
+use redbpf::{btf, cpus};
 
 use crate::llvm;
+use crate::verify;
 use crate::CommandError;
 
 pub struct BuildOptions {
     pub target_dir: PathBuf,
     pub force_loop_unroll: bool,
+    /// The eBPF instruction set version to target, from 1 to 4. This is
+    /// passed to LLVM as `-mcpu=vN`. When `None`, LLVM's `generic` CPU is
+    /// used.
+    ///
+    /// Newer versions generate better code (v2: extended conditional jumps,
+    /// v3: 32-bit jumps and ALU32 subregisters, v4: sign extension, signed
+    /// division and unconditional jumps with 32-bit offsets) but programs
+    /// compiled for them are rejected by older kernels.
+    pub cpu_version: Option<u8>,
+    /// The optimization level of rustc from 0 to 3, passed as
+    /// `-C opt-level=N`. Lower levels inline less aggressively, which helps
+    /// to find the programs that the verifier rejects only at `3`.
+    pub opt_level: u8,
+    /// Write the messages of the optimization remarks of LLVM, e.g., of
+    /// loops not unrolled, to `<probe>.remarks.yaml` in the directory of the
+    /// artifacts of each program. Each message is a `--- !Remark` YAML
+    /// document with only a `Message`, not a record of the format of
+    /// `-fsave-optimization-record`, since the C API of LLVM does not tell
+    /// the pass, the function or the arguments of remarks.
+    pub emit_remark_messages: bool,
+    /// Strip every section that is not needed to load the programs, keeping
+    /// `.BTF` and `.BTF.ext`. See `llvm::strip_for_size`.
+    pub strip_for_size: bool,
+    /// Fail if stripping the debug sections and `.text` of programs fails.
+    /// By default the failure is ignored and the sections are left as they
+    /// are.
+    pub strict_strip: bool,
+    /// A hook to process the optimized bitcode of each program
+    ///
+    /// It is called with the path of the `.bc.opt` file after all the LLVM
+    /// passes of cargo-bpf are run. If it returns `Ok`, the ELF relocatable
+    /// file of the program is re-generated from the `.bc.opt` file, so the
+    /// hook can run custom passes by rewriting the file in place. If it
+    /// returns `Err`, building stops and the error is returned as it is.
+    pub post_compile_hook: Option<Box<dyn Fn(&Path) -> Result<(), Error>>>,
+    /// Check that the ELF file of each program has sections of BPF programs
+    /// (see `PROGRAM_SECTION_PREFIXES`), and load every program on the
+    /// running kernel after building, to find the programs that the verifier
+    /// rejects. Loading requires privilege. See `verify::verify_probe`.
+    pub verify: bool,
+    /// Build every program even if its sources, features and build options
+    /// are the same as the last build. See `FINGERPRINT_FILE`.
+    pub force: bool,
+    /// The maximum number of programs built at the same time. Programs are
+    /// built one at a time if `post_compile_hook` is set.
+    pub jobs: usize,
+    /// How the result of building each program is reported
+    pub message_format: MessageFormat,
+    /// The cargo executable to build programs with, instead of the one given
+    /// to `build` or `build_with_features`. The version of LLVM that rustc
+    /// generates bitcode with is checked with the rustc in the same
+    /// directory, unless `RUSTC` is set.
+    pub cargo_path: Option<PathBuf>,
+    /// The rustup toolchain to build programs with, e.g., `nightly-2021-05-01`.
+    /// It is passed to cargo as `+<toolchain>`, so `cargo` must be the rustup
+    /// proxy.
+    pub toolchain: Option<String>,
+    /// The version and patch level of the kernel to build programs for,
+    /// e.g., `(6, 1)`. When `None`, the version of the kernel headers found
+    /// on the host is used. Set it to cross-build programs for another
+    /// kernel.
+    pub kernel_version: Option<(u32, u32)>,
+    /// The package to build programs of, like `cargo --package`. When the
+    /// manifest given to `build` is of a workspace and this is `None`, the
+    /// only member with `[[bin]]` targets is built.
+    pub package: Option<String>,
+}
+
+/// The format of the messages reporting the result of building programs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageFormat {
+    /// Human readable text
+    Human,
+    /// A JSON object per line for each program, e.g.,
+    /// `{"probe":"foo","status":"failed","kind":"Compile","message":"..."}`.
+    /// `status` is one of `built`, `fresh` and `failed`, and `kind` and
+    /// `message` are only set if building failed.
+    ///
+    /// With `verify`, a JSON object per line is also written for each
+    /// section the verifier checked, e.g.,
+    /// `{"probe":"foo","program":"kprobe/bar","status":"rejected","message":"..."}`.
+    /// `status` is one of `verified` and `rejected`, and `message` is only
+    /// set if the verifier rejected the program.
+    ///
+    /// Nothing else is written to stdout. The output of the compiler and
+    /// other progress messages are written to stderr instead.
+    Json,
 }
 
 impl Default for BuildOptions {
@@ -34,6 +127,20 @@ impl Default for BuildOptions {
         BuildOptions {
             target_dir: env::current_dir().unwrap().join("target"),
             force_loop_unroll: false,
+            cpu_version: None,
+            opt_level: 3,
+            emit_remark_messages: false,
+            strip_for_size: false,
+            strict_strip: false,
+            post_compile_hook: None,
+            verify: false,
+            force: false,
+            jobs: cpus::get_online().map(|cpus| cpus.len()).unwrap_or(1),
+            message_format: MessageFormat::Human,
+            cargo_path: None,
+            toolchain: None,
+            kernel_version: None,
+            package: None,
         }
     }
 }
@@ -52,6 +159,56 @@ pub enum Error {
     BTF,
     InvalidLLVMVersion(String),
     IllegalProgram(String),
+    InvalidCpuVersion(String),
+    InvalidOptLevel(u8),
+    Verify(Vec<String>),
+    /// The program needs a nightly toolchain to compile
+    ToolchainMismatch(String),
+    /// Stripping the sections of the program failed with `strict_strip`
+    StripFailed(String),
+    /// The ELF file of the program has no sections of BPF programs
+    NoRecognizableSections(String),
+    /// The user's `RUSTFLAGS` prevent generating the bitcode of programs
+    ConflictingFlags(String),
+    /// Building several programs failed with the errors
+    Probes(Vec<(String, Error)>),
+    /// The selected package is neither the package nor a member of the
+    /// workspace
+    UnknownPackage(String),
+    /// Several members of the workspace have `[[bin]]` targets and no
+    /// package is selected
+    AmbiguousPackage(Vec<String>),
+}
+
+impl Error {
+    /// Returns the name of the variant, e.g., `"IllegalProgram"`
+    pub fn kind(&self) -> &'static str {
+        use Error::*;
+        match self {
+            MissingManifest(_) => "MissingManifest",
+            NoPrograms => "NoPrograms",
+            NoLLC => "NoLLC",
+            NoOPT => "NoOPT",
+            Compile(..) => "Compile",
+            MissingBitcode(_) => "MissingBitcode",
+            Link(_) => "Link",
+            IOError(_) => "IOError",
+            PatternError(_) => "PatternError",
+            BTF => "BTF",
+            InvalidLLVMVersion(_) => "InvalidLLVMVersion",
+            IllegalProgram(_) => "IllegalProgram",
+            InvalidCpuVersion(_) => "InvalidCpuVersion",
+            InvalidOptLevel(_) => "InvalidOptLevel",
+            Verify(_) => "Verify",
+            ConflictingFlags(_) => "ConflictingFlags",
+            NoRecognizableSections(_) => "NoRecognizableSections",
+            StripFailed(_) => "StripFailed",
+            ToolchainMismatch(_) => "ToolchainMismatch",
+            Probes(_) => "Probes",
+            UnknownPackage(_) => "UnknownPackage",
+            AmbiguousPackage(_) => "AmbiguousPackage",
+        }
+    }
 }
 
 impl std::error::Error for Error {
@@ -79,13 +236,37 @@ impl Display for Error {
             Compile(p, None) => write!(f, "failed to compile the `{}' program", p),
             MissingBitcode(p) => write!(f, "failed to generate bitcode for the `{}' program", p),
             Link(p) => write!(f, "failed to generate bitcode for the `{}' program", p),
-            NoOPT => write!(f, "no usable opt executable found, expecting version 9"),
-            NoLLC => write!(f, "no usable llc executable found, expecting version 9"),
+            NoOPT => write!(f, "no usable opt executable found"),
+            NoLLC => write!(f, "no usable llc executable found"),
             IOError(e) => write!(f, "{}", e),
             PatternError(e) => write!(f, "couldn't list probe files: {}", e),
             BTF => write!(f, "failed to fix BTF section"),
             InvalidLLVMVersion(p) => write!(f, "Invalid LLVMVersion: {}", p),
             IllegalProgram(p) => write!(f, "Illegal Program: {}", p),
+            InvalidCpuVersion(p) => write!(f, "Invalid CPU version: {}", p),
+            InvalidOptLevel(p) => write!(f, "Invalid opt level: {}, expecting 0 to 3", p),
+            Verify(p) => write!(f, "failed to verify programs: {}", p.join(", ")),
+            ConflictingFlags(p) => write!(f, "conflicting rustc flags: {}", p),
+            StripFailed(p) => write!(f, "failed to strip sections: {}", p),
+            ToolchainMismatch(p) => write!(f, "toolchain mismatch: {}", p),
+            NoRecognizableSections(p) => write!(
+                f,
+                "the `{}' program has no sections of BPF programs. Are its functions annotated with attributes such as `#[kprobe]'?",
+                p
+            ),
+            Probes(errors) => {
+                write!(f, "failed to build {} programs", errors.len())?;
+                for (probe, e) in errors {
+                    write!(f, "\n`{}': {}", probe, e)?;
+                }
+                Ok(())
+            }
+            UnknownPackage(p) => write!(f, "package `{}' not found in the workspace", p),
+            AmbiguousPackage(p) => write!(
+                f,
+                "several packages of the workspace contain programs: {}. Select one with --package",
+                p.join(", ")
+            ),
         }
     }
 }
@@ -122,13 +303,705 @@ fn create_rustflags() -> (String, String) {
     ("RUSTFLAGS".to_string(), flags)
 }
 
-fn build_probe(
-    cargo: &Path,
+/// Return an error if the flags of `var`, either `RUSTFLAGS` or
+/// `CARGO_ENCODED_RUSTFLAGS`, turn off LTO or bitcode embedding
+///
+/// Programs are built from the bitcode that rustc generates with LTO, so
+/// these flags make the bitcode missing.
+fn check_rustflags(var: &str, flags: &str) -> Result<(), Error> {
+    let flags: Vec<&str> = if var == "CARGO_ENCODED_RUSTFLAGS" {
+        flags
+            .split('\x1f')
+            .filter(|flag| !flag.is_empty())
+            .collect()
+    } else {
+        flags.split_whitespace().collect()
+    };
+    let mut flags = flags.into_iter();
+    while let Some(flag) = flags.next() {
+        let option = match flag {
+            "-C" | "--codegen" => flags.next().unwrap_or(""),
+            flag if flag.starts_with("--codegen=") => &flag["--codegen=".len()..],
+            flag if flag.starts_with("-C") => &flag[2..],
+            _ => continue,
+        };
+        let (name, value) = match option.find('=') {
+            Some(eq) => (&option[..eq], &option[eq + 1..]),
+            None => (option, ""),
+        };
+        // every spelling of a disabled boolean codegen option that rustc
+        // accepts
+        let off = ["off", "no", "n", "false"].contains(&value);
+        if (name == "lto" || name == "embed-bitcode") && off {
+            return Err(Error::ConflictingFlags(format!(
+                "`-C {}' in {} prevents generating the bitcode of programs",
+                option, var
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The oldest kernel versions that accept each eBPF instruction set version
+const CPU_VERSION_MIN_KERNEL: [(u8, (u8, u8)); 4] =
+    [(1, (0, 0)), (2, (4, 14)), (3, (5, 1)), (4, (6, 6))];
+
+/// Return the LLVM CPU name of the eBPF instruction set `cpu_version`
+///
+/// The version is checked against the LLVM version cargo-bpf is linked to
+/// and, if known, the version of the kernel headers programs are built
+/// against.
+fn cpu_name(
+    cpu_version: Option<u8>,
+    llvm_version: &Version,
+    kernel_version: Option<&KernelVersion>,
+) -> Result<String, Error> {
+    let cpu_version = match cpu_version {
+        Some(v) => v,
+        None => return Ok("generic".to_string()),
+    };
+    let (_, (major, minor)) = CPU_VERSION_MIN_KERNEL
+        .iter()
+        .find(|(v, _)| *v == cpu_version)
+        .copied()
+        .ok_or_else(|| {
+            Error::InvalidCpuVersion(format!(
+                "v{} is not supported, expecting a version from 1 to 4",
+                cpu_version
+            ))
+        })?;
+    if cpu_version == 4 && llvm_version.major < 18 {
+        return Err(Error::InvalidCpuVersion(format!(
+            "v4 requires LLVM 18 or newer but cargo-bpf is linked to LLVM {}.{}. Re-build cargo-bpf with a newer LLVM or use v3",
+            llvm_version.major, llvm_version.minor
+        )));
+    }
+    if let Some(kv) = kernel_version {
+        if (kv.version, kv.patchlevel) < (major, minor) {
+            return Err(Error::InvalidCpuVersion(format!(
+                "v{} requires Linux {}.{} or newer but the kernel headers are of version {}.{}. Use v{} or lower",
+                cpu_version,
+                major,
+                minor,
+                kv.version,
+                kv.patchlevel,
+                CPU_VERSION_MIN_KERNEL
+                    .iter()
+                    .rev()
+                    .find(|(_, min)| (kv.version, kv.patchlevel) >= *min)
+                    .map(|(v, _)| *v)
+                    .unwrap_or(1)
+            )));
+        }
+    }
+    Ok(format!("v{}", cpu_version))
+}
+
+/// Compare the LLVM version that rustc depends on and the LLVM version that
+/// cargo-bpf is linked to, and select how the bitcode of rustc is processed
+///
+/// The bitcode generated by rustc must be read by the linked LLVM, which can
+/// read the bitcode of its own major version or older ones. So the major
+/// version of the linked LLVM must be greater than or equal to the one of
+/// rustc. If rustc does not report its LLVM version, the build is attempted
+/// anyway.
+fn check_llvm_version(
+    linked_llvm_version: &Version,
+    rustc_meta: &rustc_version::VersionMeta,
+) -> Result<llvm::Codegen, Error> {
+    let llvm_version = match rustc_meta.llvm_version.as_ref() {
+        Some(v) => v,
+        None => {
+            eprintln!(
+                "warning: the LLVM version of rustc is unknown. Assuming it is LLVM {}",
+                linked_llvm_version.major
+            );
+            return Ok(llvm::Codegen {
+                opaque_pointers: linked_llvm_version.major >= llvm::OPAQUE_POINTERS_MIN_LLVM,
+            });
+        }
+    };
+    if linked_llvm_version.major < llvm_version.major {
+        return Err(Error::InvalidLLVMVersion(format!(
+            "rustc {} depends on LLVM {}.{} but cargo-bpf is linked to LLVM {}.{}, which can not read the newer bitcode. You should re-build cargo-bpf with LLVM {} or newer, or use rustc that depends on LLVM {} or older",
+            rustc_meta.semver,
+            llvm_version.major,
+            llvm_version.minor,
+            linked_llvm_version.major,
+            linked_llvm_version.minor,
+            llvm_version.major,
+            linked_llvm_version.major,
+        )));
+    }
+    Ok(llvm::Codegen {
+        opaque_pointers: llvm_version.major >= llvm::OPAQUE_POINTERS_MIN_LLVM,
+    })
+}
+
+/// Return the version of the kernel that probes are built for
+///
+/// `kernel_version` overrides the version of the kernel headers found on the
+/// host.
+fn target_kernel_version(kernel_version: Option<(u32, u32)>) -> Option<KernelVersion> {
+    match kernel_version {
+        Some((version, patchlevel)) => Some(KernelVersion {
+            version: version.min(u8::MAX.into()) as u8,
+            patchlevel: patchlevel.min(u8::MAX.into()) as u8,
+            sublevel: 0,
+        }),
+        None => build_kernel_version().ok(),
+    }
+}
+
+/// Return the `kernel_version` cfg option that probes are compiled with
+fn kernel_version_cfg(kernel_version: Option<&KernelVersion>) -> String {
+    kernel_version
+        .map(|v| {
+            let patchlevel = if v.version >= 5 && v.patchlevel >= 7 {
+                7
+            } else {
+                v.patchlevel
+            };
+            format!(r#"kernel_version="{}.{}""#, v.version, patchlevel)
+        })
+        .unwrap_or_else(|| r#"kernel_version="unknown""#.to_string())
+}
+
+/// The name of the file that keeps the fingerprint of the last build of a
+/// probe, in the directory of its artifacts
+///
+/// If the fingerprint of the next build is the same, the probe is not built
+/// again.
+pub const FINGERPRINT_FILE: &str = ".fingerprint";
+
+/// Compute the fingerprint of building `probe`
+///
+/// It is a hash of the source files and manifests of `package` and of its
+/// path dependencies, the lock file of `package`, `features`, the
+/// `kernel_version` cfg option, the path of `cargo` that builds it,
+/// `rustc_version` (the output of `rustc -vV`, which includes the LLVM
+/// version), the build `options` that change the output and the version of
+/// cargo-bpf.
+///
+/// The hash is computed with [`StableHasher`] so that the fingerprint of the
+/// same build does not change with the Rust release cargo-bpf is built with.
+fn fingerprint(
     package: &Path,
-    target_dir: &Path,
+    cargo: &Path,
     probe: &str,
-    features: &Vec<String>,
+    features: &[String],
+    kernel_version_cfg: &str,
+    rustc_version: &str,
+    options: &FingerprintOptions<'_>,
+) -> Result<String, Error> {
+    let mut hasher = StableHasher::new();
+    hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+    hasher.update(cargo.to_string_lossy().as_bytes());
+    hasher.update(probe.as_bytes());
+    hasher.update(&(features.len() as u64).to_le_bytes());
+    for feature in features {
+        hasher.update(feature.as_bytes());
+    }
+    hasher.update(kernel_version_cfg.as_bytes());
+    let (env_name, env_value) = create_rustflags();
+    hasher.update(env_name.as_bytes());
+    hasher.update(env_value.as_bytes());
+    hasher.update(rustc_version.as_bytes());
+    options.update(&mut hasher);
+
+    let mut sources = vec![package.join("Cargo.lock").to_string_lossy().into_owned()];
+    for dir in iter::once(package.to_path_buf()).chain(path_dependencies(package)?) {
+        let mut files = probe_files(&dir)?;
+        files.sort();
+        sources.extend(files);
+        sources.push(dir.join("build.rs").to_string_lossy().into_owned());
+        sources.push(dir.join("Cargo.toml").to_string_lossy().into_owned());
+    }
+    for source in sources {
+        hasher.update(source.as_bytes());
+        match fs::read(&source) {
+            Ok(contents) => hasher.update(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => hasher.update(&[]),
+            Err(e) => return Err(Error::IOError(e)),
+        }
+    }
+    Ok(format!("{:016x}", hasher.finish()))
+}
+
+// The options of `BuildOptions` that change the output of a build
+#[derive(Debug, Clone, Copy, Default)]
+struct FingerprintOptions<'a> {
+    force_loop_unroll: bool,
+    cpu_version: Option<u8>,
+    strip_for_size: bool,
+    toolchain: Option<&'a str>,
+    opt_level: u8,
+    emit_remark_messages: bool,
+}
+
+impl FingerprintOptions<'_> {
+    fn update(&self, hasher: &mut StableHasher) {
+        hasher.update(&[
+            self.force_loop_unroll as u8,
+            self.cpu_version.is_some() as u8,
+            self.cpu_version.unwrap_or(0),
+            self.strip_for_size as u8,
+            self.toolchain.is_some() as u8,
+            self.opt_level,
+            self.emit_remark_messages as u8,
+        ]);
+        hasher.update(self.toolchain.unwrap_or_default().as_bytes());
+    }
+}
+
+/// A 64-bit FNV-1a hasher
+///
+/// Unlike `DefaultHasher`, its output is the same for every Rust release.
+/// Each input is prefixed with its length so that the boundaries between
+/// inputs are part of the hash.
+struct StableHasher(u64);
+
+impl StableHasher {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    fn new() -> Self {
+        StableHasher(Self::OFFSET_BASIS)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        let len = (bytes.len() as u64).to_le_bytes();
+        for byte in len.iter().chain(bytes) {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(Self::PRIME);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Return the directories of the path dependencies of `package`, and of
+/// theirs
+///
+/// The `[dependencies]` and `[build-dependencies]` tables, including the
+/// ones of `[target.'cfg(..)']`, are searched. Directories without a
+/// manifest are skipped.
+fn path_dependencies(package: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut seen = HashSet::new();
+    seen.insert(
+        package
+            .canonicalize()
+            .unwrap_or_else(|_| package.to_path_buf()),
+    );
+    let mut dirs = vec![];
+    let mut queue = vec![package.to_path_buf()];
+    while let Some(dir) = queue.pop() {
+        let doc = match load_package(&dir) {
+            Ok(doc) => doc,
+            Err(Error::MissingManifest(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let mut tables = vec![&doc["dependencies"], &doc["build-dependencies"]];
+        if let Some(targets) = doc["target"].as_table_like() {
+            for (_, target) in targets.iter() {
+                tables.push(&target["dependencies"]);
+                tables.push(&target["build-dependencies"]);
+            }
+        }
+        for deps in tables.into_iter().filter_map(|deps| deps.as_table_like()) {
+            for (_, dep) in deps.iter() {
+                if let Some(path) = dep["path"].as_str() {
+                    let dep_dir = dir.join(path);
+                    let key = dep_dir.canonicalize().unwrap_or_else(|_| dep_dir.clone());
+                    if seen.insert(key) {
+                        dirs.push(dep_dir.clone());
+                        queue.push(dep_dir);
+                    }
+                }
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+// Return the output of `rustc -vV` of the rustc that `cargo` builds probes
+// with, which includes the version of LLVM that generates their bitcode
+fn rustc_version_verbose(cargo: &Path, toolchain: Option<&str>) -> Result<String, Error> {
+    let output = rustc_command(cargo, toolchain)
+        .arg("-vV")
+        .output()
+        .map_err(|e| {
+            Error::InvalidLLVMVersion(format!("Failed to get LLVM version of rustc: {}", e))
+        })?;
+    if !output.status.success() {
+        return Err(Error::InvalidLLVMVersion(format!(
+            "Failed to get LLVM version of rustc: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Return true if `artifacts_dir` has the ELF file of `probe` built with
+/// `fingerprint`
+fn is_fresh(artifacts_dir: &Path, probe: &str, fingerprint: &str) -> bool {
+    artifacts_dir.join(format!("{}.elf", probe)).exists()
+        && fs::read_to_string(artifacts_dir.join(FINGERPRINT_FILE))
+            .map(|last| last == fingerprint)
+            .unwrap_or(false)
+}
+
+// The result of building a probe that did not fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BuildStatus {
+    Built,
+    Fresh,
+}
+
+// What is needed to build a probe in a worker thread
+struct ProbeJob {
+    cargo: PathBuf,
+    toolchain: Option<String>,
+    package: PathBuf,
+    features: Vec<String>,
+    kernel_version: Option<(u32, u32)>,
+    kernel_version_cfg: String,
+    target_dir: PathBuf,
+    force_loop_unroll: bool,
+    cpu_version: Option<u8>,
+    opt_level: u8,
+    emit_remark_messages: bool,
+    strip_for_size: bool,
+    strict_strip: bool,
+    force: bool,
+    verify: bool,
+    message_format: MessageFormat,
+}
+
+impl ProbeJob {
+    // Build `probe` unless it is up to date
+    fn build(
+        &self,
+        probe: &str,
+        post_compile_hook: Option<&dyn Fn(&Path) -> Result<(), Error>>,
+    ) -> Result<BuildStatus, Error> {
+        let artifacts_dir = self.target_dir.join("bpf").join("programs").join(probe);
+        // what the post-compile hook does is unknown, so it is always run
+        let fingerprint = if post_compile_hook.is_none() {
+            Some(self.fingerprint(probe)?)
+        } else {
+            None
+        };
+        if let Some(fingerprint) = fingerprint.as_ref() {
+            if !self.force && is_fresh(&artifacts_dir, probe, fingerprint) {
+                return Ok(BuildStatus::Fresh);
+            }
+        }
+        build_probe(self, probe, post_compile_hook)?;
+        if let Some(fingerprint) = fingerprint {
+            fs::write(artifacts_dir.join(FINGERPRINT_FILE), fingerprint)?;
+        }
+        Ok(BuildStatus::Built)
+    }
+
+    fn fingerprint(&self, probe: &str) -> Result<String, Error> {
+        fingerprint(
+            &self.package,
+            &self.cargo,
+            probe,
+            &self.features,
+            &self.kernel_version_cfg,
+            &rustc_version_verbose(&self.cargo, self.toolchain.as_deref())?,
+            &FingerprintOptions {
+                force_loop_unroll: self.force_loop_unroll,
+                cpu_version: self.cpu_version,
+                strip_for_size: self.strip_for_size,
+                toolchain: self.toolchain.as_deref(),
+                opt_level: self.opt_level,
+                emit_remark_messages: self.emit_remark_messages,
+            },
+        )
+    }
+}
+
+/// Run `job` for each of `probes` on up to `jobs` threads
+///
+/// Every probe is run even if others fail. The results are returned in the
+/// order of `probes`.
+fn run_jobs<T, F>(probes: &[String], jobs: usize, job: F) -> Vec<(String, Result<T, Error>)>
+where
+    T: Send + 'static,
+    F: Fn(&str) -> Result<T, Error> + Send + Sync + 'static,
+{
+    let queue: VecDeque<(usize, String)> = probes.iter().cloned().enumerate().collect();
+    let queue = Arc::new(Mutex::new(queue));
+    let job = Arc::new(job);
+    let (sender, receiver) = mpsc::channel();
+    let workers: Vec<_> = (0..jobs.min(probes.len()))
+        .map(|_| {
+            let queue = queue.clone();
+            let job = job.clone();
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let next = queue.lock().unwrap().pop_front();
+                let (idx, probe) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let ret =
+                    panic::catch_unwind(AssertUnwindSafe(|| job(&probe))).unwrap_or_else(|_| {
+                        Err(Error::Compile(probe.clone(), Some("panicked".to_string())))
+                    });
+                if sender.send((idx, probe, ret)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+    let mut results: Vec<_> = receiver.iter().collect();
+    for worker in workers {
+        let _ = worker.join();
+    }
+    results.sort_by_key(|(idx, _, _)| *idx);
+    results
+        .into_iter()
+        .map(|(_, probe, ret)| (probe, ret))
+        .collect()
+}
+
+// Format the result of building `probe` as a line of `MessageFormat::Json`
+fn probe_message_json(probe: &str, ret: &Result<BuildStatus, Error>) -> String {
+    let status = match ret {
+        Ok(BuildStatus::Built) => "built",
+        Ok(BuildStatus::Fresh) => "fresh",
+        Err(_) => "failed",
+    };
+    let mut json = format!(
+        "{{\"probe\":{},\"status\":{}",
+        json_string(probe),
+        json_string(status)
+    );
+    if let Err(e) = ret {
+        // the message of the compiler is reported without the context
+        let message = match e {
+            Error::Compile(_, Some(msg)) => msg.clone(),
+            e => e.to_string(),
+        };
+        json.push_str(&format!(
+            ",\"kind\":{},\"message\":{}",
+            json_string(e.kind()),
+            json_string(&message)
+        ));
+    }
+    json.push('}');
+    json
+}
+
+// Format what the verifier said about a program of `probe` as a line of
+// `MessageFormat::Json`
+fn verify_message_json(probe: &str, result: &verify::VerifyResult) -> String {
+    let status = match result.error {
+        None => "verified",
+        Some(_) => "rejected",
+    };
+    let mut json = format!(
+        "{{\"probe\":{},\"program\":{},\"status\":{}",
+        json_string(probe),
+        json_string(&result.program),
+        json_string(status)
+    );
+    if let Some(e) = result.error.as_ref() {
+        json.push_str(&format!(",\"message\":{}", json_string(e)));
+    }
+    json.push('}');
+    json
+}
+
+// Print a line of human readable text to stdout, or to stderr if stdout is
+// kept for the lines of `MessageFormat::Json`
+fn print_human(message_format: MessageFormat, line: &str) {
+    match message_format {
+        MessageFormat::Human => println!("{}", line),
+        MessageFormat::Json => eprintln!("{}", line),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// The maximum length of the compiler diagnostics kept in `Error::Compile`
+const MAX_COMPILER_MESSAGE_LEN: usize = 8192;
+
+// Run `command` that compiles `probe`. If it fails, the diagnostics it
+// printed are returned in `Error::Compile`.
+fn run_compiler(
+    probe: &str,
+    command: &mut Command,
+    message_format: MessageFormat,
+) -> Result<(), Error> {
+    let output = command.output()?;
+    if !output.status.success() {
+        let mut diagnostics = output.stderr;
+        diagnostics.extend_from_slice(&output.stdout);
+        if let Some(msg) = toolchain_mismatch(&String::from_utf8_lossy(&diagnostics)) {
+            return Err(Error::ToolchainMismatch(format!(
+                "the `{}' program {}\n{}",
+                probe,
+                msg,
+                compiler_message(&diagnostics)
+            )));
+        }
+        return Err(Error::Compile(
+            probe.to_string(),
+            Some(compiler_message(&diagnostics)),
+        ));
+    }
+    // the progress of cargo and the warnings of rustc
+    io::stderr().write_all(&output.stderr)?;
+    match message_format {
+        MessageFormat::Human => io::stdout().write_all(&output.stdout)?,
+        MessageFormat::Json => io::stderr().write_all(&output.stdout)?,
+    }
+    Ok(())
+}
+
+// Return what the diagnostics of rustc say is missing if they are the errors
+// of using unstable features on a stable toolchain
+//
+// Only the errors that stable toolchains alone report tell a mismatch. The
+// hints to add `#![feature(..)]` that come with them name the features, but
+// nightly toolchains give the same hints for features that are not enabled.
+fn toolchain_mismatch(diagnostics: &str) -> Option<String> {
+    let stable = diagnostics.contains("error[E0554]")
+        || diagnostics.contains("is only accepted on the nightly compiler")
+        || diagnostics.contains("on the stable release channel");
+    if !stable {
+        return None;
+    }
+    const FEATURE_HINT: &str = "add `#![feature(";
+    let mut features: Vec<&str> = vec![];
+    for line in diagnostics.lines() {
+        if let Some(start) = line.find(FEATURE_HINT) {
+            let rest = &line[start + FEATURE_HINT.len()..];
+            if let Some(end) = rest.find(")]`") {
+                if !features.contains(&&rest[..end]) {
+                    features.push(&rest[..end]);
+                }
+            }
+        }
+    }
+    let suggestion = "Use a nightly toolchain, e.g., by `cargo +nightly bpf build`, a `rust-toolchain` file pinning the nightly the probes are developed with, or `toolchain` of `BuildOptions`";
+    if !features.is_empty() {
+        Some(format!(
+            "requires the unstable features {}, which are enabled by `#![feature(..)]` only on nightly toolchains. {}",
+            features
+                .iter()
+                .map(|feature| format!("`{}'", feature))
+                .collect::<Vec<_>>()
+                .join(", "),
+            suggestion
+        ))
+    } else {
+        Some(format!("requires a nightly toolchain. {}", suggestion))
+    }
+}
+
+// Keep the last lines of the diagnostics, where the errors are summarized
+fn compiler_message(diagnostics: &[u8]) -> String {
+    let message = String::from_utf8_lossy(diagnostics);
+    let message = message.trim_end();
+    if message.len() <= MAX_COMPILER_MESSAGE_LEN {
+        return message.to_string();
+    }
+    let mut start = message.len() - MAX_COMPILER_MESSAGE_LEN;
+    while !message.is_char_boundary(start) {
+        start += 1;
+    }
+    let tail = &message[start..];
+    let tail = match tail.find('\n') {
+        Some(newline) => &tail[newline + 1..],
+        None => tail,
+    };
+    format!("...\n{}", tail)
+}
+
+// The arguments of rustc to emit the bitcode of probes
+fn rustc_codegen_args(opt_level: u8) -> Result<Vec<String>, Error> {
+    if opt_level > 3 {
+        return Err(Error::InvalidOptLevel(opt_level));
+    }
+    // panic=abort is required because the verifier rejects unwinding
+    let args = format!(
+        "--emit=llvm-bc -C panic=abort -C lto -C opt-level={} -C linker=true", // /usr/bin/true or /bin/true
+        opt_level
+    );
+    Ok(args.split(' ').map(String::from).collect())
+}
+
+// Create the command of `cargo` that uses `toolchain` if it is set
+fn cargo_command(cargo: &Path, toolchain: Option<&str>) -> Command {
+    let mut command = Command::new(cargo);
+    if let Some(toolchain) = toolchain {
+        command.arg(format!("+{}", toolchain));
+    }
+    command
+}
+
+// Create the command of the rustc that `cargo` builds probes with
+//
+// Like cargo, `RUSTC` is used if it is set. Otherwise it is the rustc in the
+// directory of `cargo`, or the one in `PATH` if there is none there, e.g.,
+// when `cargo` is just `cargo`. `toolchain` is given to it by
+// `RUSTUP_TOOLCHAIN`, as the rustup proxy of cargo does, so that it is
+// ignored by a rustc that is not a rustup proxy.
+fn rustc_command(cargo: &Path, toolchain: Option<&str>) -> Command {
+    let rustc = match env::var_os("RUSTC") {
+        Some(rustc) if !rustc.is_empty() => PathBuf::from(rustc),
+        _ => cargo
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| dir.join(format!("rustc{}", env::consts::EXE_SUFFIX)))
+            .filter(|rustc| rustc.is_file())
+            .unwrap_or_else(|| PathBuf::from("rustc")),
+    };
+    let mut command = Command::new(rustc);
+    if let Some(toolchain) = toolchain {
+        command.env("RUSTUP_TOOLCHAIN", toolchain);
+    }
+    command
+}
+
+fn build_probe(
+    job: &ProbeJob,
+    probe: &str,
+    post_compile_hook: Option<&dyn Fn(&Path) -> Result<(), Error>>,
 ) -> Result<(), Error> {
+    let cargo = job.cargo.as_path();
+    let toolchain = job.toolchain.as_deref();
+    let package = job.package.as_path();
+    let target_dir = job.target_dir.as_path();
+    let features = &job.features;
+    let cpu_version = job.cpu_version;
+    let strip_for_size = job.strip_for_size;
+
     fs::create_dir_all(&target_dir)?;
     let target_dir = target_dir.canonicalize().unwrap().join("bpf");
     let artifacts_dir = target_dir.join("programs").join(probe);
@@ -136,56 +1009,22 @@ fn build_probe(
     fs::create_dir_all(&artifacts_dir)?;
 
     let (env_name, env_value) = create_rustflags();
-    let version = build_kernel_version()
-        .map(|mut v| {
-            if v.version >= 5 && v.patchlevel >= 7 {
-                v.patchlevel = 7;
-                v
-            } else {
-                v
-            }
-        })
-        .map(|v| format!(r#"kernel_version="{}.{}""#, v.version, v.patchlevel))
-        .unwrap_or_else(|_| r#"kernel_version="unknown""#.to_string());
-
-    // Compare the LLVM version[1] that rustc depends on currently and the LLVM
-    // version[2] `cargo-bpf` had been linked into.
-    //
-    // [2] must be greater than or equal to [1].
-    //
-    // Because the bitcode generated by [1] should be processed by [2].
-    //
-    // If [1] is newer than [2], [2] will fail to process the newer bitcode
-    // format.
+    check_rustflags(&env_name, &env::var(&env_name).unwrap_or_default())?;
+    let kernel_version = target_kernel_version(job.kernel_version);
+    let version = kernel_version_cfg(kernel_version.as_ref());
+
     let linked_llvm_version = Version::parse(env!("CARGO_BPF_LLVM_VERSION")).map_err(|_| {
         Error::InvalidLLVMVersion("Unknown LLVM version that cargo-bpf linked to".to_string())
     })?;
-    let llvm_version = rustc_version::version_meta()
-        .map_err(|e| {
-            Error::InvalidLLVMVersion(format!("Failed to get LLVM version of rustc: {}", e))
-        })?
-        .llvm_version
-        .ok_or_else(|| {
-            Error::InvalidLLVMVersion("Failed to get LLVM version of rustc".to_string())
-        })?;
-    if linked_llvm_version.major < llvm_version.major
-        || (linked_llvm_version.major == llvm_version.major
-            && linked_llvm_version.minor < llvm_version.minor)
-    {
-        return Err(Error::InvalidLLVMVersion(format!(
-            "LLVM version that cargo-bpf linked to ({}.{}) < LLVM version that rustc depends on ({}.{}). You should re-build cargo-bpf with LLVM version ({}.{}), or downgrade rustc that uses LLVM version ({}.{})",
-            linked_llvm_version.major,
-            linked_llvm_version.minor,
-            llvm_version.major,
-            llvm_version.minor,
-            llvm_version.major,
-            llvm_version.minor,
-            linked_llvm_version.major,
-            linked_llvm_version.minor,
-        )));
-    }
+    let rustc_meta = rustc_version::VersionMeta::for_command(rustc_command(cargo, toolchain));
+    let rustc_meta = rustc_meta.map_err(|e| {
+        Error::InvalidLLVMVersion(format!("Failed to get LLVM version of rustc: {}", e))
+    })?;
+    let codegen = check_llvm_version(&linked_llvm_version, &rustc_meta)?;
+    let cpu = cpu_name(cpu_version, &linked_llvm_version, kernel_version.as_ref())?;
 
-    if !Command::new(cargo)
+    let mut rustc = cargo_command(cargo, toolchain);
+    rustc
         .current_dir(package)
         .env(env_name, env_value)
         .args("rustc --release".split(' '))
@@ -197,15 +1036,11 @@ fn build_probe(
         .arg("--")
         .arg("--cfg")
         .arg(version)
-        .args("--emit=llvm-bc -C panic=abort -C lto -C opt-level=3 -C linker=true".split(' ')) // /usr/bin/true or /bin/true
+        .args(rustc_codegen_args(job.opt_level)?)
         .arg("-g") // To generate .BTF section
         .arg("-o")
-        .arg(artifacts_dir.join(probe).to_str().unwrap())
-        .status()?
-        .success()
-    {
-        return Err(Error::Compile(probe.to_string(), None));
-    }
+        .arg(artifacts_dir.join(probe).to_str().unwrap());
+    run_compiler(probe, &mut rustc, job.message_format)?;
 
     let mut bc_files: Vec<PathBuf> = fs::read_dir(artifacts_dir.clone())?
         .filter(|e| {
@@ -225,22 +1060,45 @@ fn build_probe(
     let bc_file = bc_files.drain(..).next().unwrap();
     let opt_bc_file = bc_file.with_extension("bc.opt");
     let target_tmp = artifacts_dir.join(format!("{}.elf.tmp", probe));
-    unsafe { llvm::compile(&bc_file, &target_tmp, Some(&opt_bc_file)) }.map_err(|msg| {
+    let remarks = artifacts_dir.join(format!("{}.remarks.yaml", probe));
+    let remarks = Some(remarks.as_path()).filter(|_| job.emit_remark_messages);
+    let compiled = unsafe {
+        llvm::compile(
+            &bc_file,
+            &target_tmp,
+            Some(&opt_bc_file),
+            &cpu,
+            codegen,
+            remarks,
+        )
+    };
+    compiled.map_err(|msg| {
         Error::Compile(
             probe.into(),
             Some(format!("couldn't process IR file: {}", msg)),
         )
     })?;
+    if let Some(hook) = post_compile_hook {
+        hook(&opt_bc_file)?;
+        unsafe { llvm::emit_object(&opt_bc_file, &target_tmp, &cpu) }.map_err(|msg| {
+            Error::Compile(
+                probe.into(),
+                Some(format!("couldn't process hooked IR file: {}", msg)),
+            )
+        })?;
+    }
 
     // stripping .debug sections, .text section and BTF sections is optional
     // process. So don't care about its failure.
-    let contains_tc = unsafe {
-        llvm::get_function_section_names(&bc_file)
-            .map_or_else(|_| vec![], |names| names)
-            .iter()
-            .find(|name| name.starts_with("tc_action/"))
-            .is_some()
+    let section_names = unsafe {
+        llvm::get_function_section_names(&bc_file).map_or_else(|_| vec![], |names| names)
     };
+    let contains_tc = section_names
+        .iter()
+        .find(|name| name.starts_with("tc_action/"))
+        .is_some();
+    // BPF global functions are placed in .text section
+    let contains_global_functions = section_names.iter().any(|name| name == ".text");
     if contains_tc {
         let elf_bytes = fs::read(&target_tmp).map_err(|e| Error::IOError(e))?;
         let binary = Elf::parse(&elf_bytes).map_err(|_| -> Error {
@@ -250,12 +1108,125 @@ fn build_probe(
         let fixed = btf::tc_legacy_fix_btf_section(elf_bytes.as_slice()).map_err(|_| Error::BTF)?;
         fs::write(&target_tmp, fixed).map_err(|e| Error::IOError(e))?;
     }
-    let _ = llvm::strip_unnecessary(&target_tmp, contains_tc);
+    if strip_for_size {
+        let before = fs::metadata(&target_tmp)?.len();
+        llvm::strip_for_size(&target_tmp, contains_tc, contains_global_functions)
+            .map_err(|e| Error::Compile(probe.into(), Some(e.to_string())))?;
+        let after = fs::metadata(&target_tmp)?.len();
+        print_human(
+            job.message_format,
+            &format!(
+                "stripped the `{}' program: {} bytes -> {} bytes",
+                probe, before, after
+            ),
+        );
+    } else {
+        strip_unnecessary(
+            &target_tmp,
+            contains_tc,
+            contains_global_functions,
+            job.strict_strip,
+        )?;
+    }
+    // finding unused maps and relocations is optional too
+    if let Ok(elf_bytes) = fs::read(&target_tmp) {
+        if let Ok(binary) = Elf::parse(&elf_bytes) {
+            for prefix in PROGRAM_SECTION_PREFIXES {
+                // relocations of tc_action sections are checked above
+                if *prefix == "tc_action/" {
+                    continue;
+                }
+                for (section, sym_section) in find_non_map_relocs(&binary, prefix) {
+                    eprintln!(
+                        "warning: `{}' section of the `{}' program has a relocation for `{}' section, which loaders that relocate only maps do not support. Did you use `map.get(&42)` instead of `map.get(&key)`?",
+                        section, probe, sym_section
+                    );
+                }
+            }
+            for map in unused_maps(&binary) {
+                eprintln!(
+                    "warning: the `{}' map of the `{}' program is never used",
+                    map, probe
+                );
+            }
+        }
+    }
+    if job.verify {
+        let elf_bytes = fs::read(&target_tmp)?;
+        let binary = Elf::parse(&elf_bytes).map_err(|_| -> Error {
+            Error::IllegalProgram(format!("{}: failed to parse ELF", probe))
+        })?;
+        check_program_sections(probe, &binary)?;
+    }
     let target = artifacts_dir.join(format!("{}.elf", probe));
     fs::rename(&target_tmp, &target).map_err(|e| Error::IOError(e))?;
     Ok(())
 }
 
+// Strip the debug sections and `.text` of `target`. Failures are returned
+// only if `strict` is true.
+fn strip_unnecessary(
+    target: &Path,
+    delete_btf: bool,
+    keep_text: bool,
+    strict: bool,
+) -> Result<(), Error> {
+    match llvm::strip_unnecessary(&target, delete_btf, keep_text) {
+        Err(e) if strict => Err(Error::StripFailed(format!("{:?}: {}", target, e))),
+        _ => Ok(()),
+    }
+}
+
+/// The prefixes of the names of the ELF sections that contain BPF programs
+///
+/// These are the sections generated by the attribute macros of
+/// `redbpf-macros`, e.g., `kprobe/do_sys_open` by `#[kprobe]`.
+pub const PROGRAM_SECTION_PREFIXES: &[&str] = &[
+    "kprobe/",
+    "kretprobe/",
+    "uprobe/",
+    "uretprobe/",
+    "xdp/",
+    "socketfilter/",
+    "streamparser/",
+    "streamverdict/",
+    "sk_lookup/",
+    "sockops/",
+    "cgroup/",
+    "sk_reuseport/",
+    "fentry/",
+    "fexit/",
+    "lsm/",
+    "raw_tracepoint/",
+    "perf_event/",
+    "task_iter/",
+    "struct_ops/",
+    "tc_action/",
+];
+
+/// Return an error if `binary` has no sections of BPF programs
+///
+/// A probe without any program compiles fine, e.g., if the attribute macros
+/// are not applied to its functions, but there is nothing to load.
+fn check_program_sections(probe: &str, binary: &Elf) -> Result<(), Error> {
+    let found = binary.section_headers.iter().any(|shdr| {
+        binary
+            .shdr_strtab
+            .get_at(shdr.sh_name)
+            .map(|name| {
+                PROGRAM_SECTION_PREFIXES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+            })
+            .unwrap_or(false)
+    });
+    if found {
+        Ok(())
+    } else {
+        Err(Error::NoRecognizableSections(probe.to_string()))
+    }
+}
+
 /// Check if the sections of tc_action program have relocation of data other
 /// than maps.
 ///
@@ -264,6 +1235,23 @@ fn build_probe(
 /// relocated properly before it is loaded into the Linux kernel. But tc
 /// utility does not support relocation other than maps.
 fn check_tc_action_relocs(binary: &Elf) -> Result<(), Error> {
+    match find_non_map_relocs(binary, "tc_action/").into_iter().next() {
+        Some((section, sym_section)) => Err(Error::IllegalProgram(format!("`{}` section has a relocation for `{}` section. But tc utility does not support relocation except for maps. Did you use `map.get(&42)` instead of `map.get(&key)`?", section, sym_section))),
+        None => Ok(()),
+    }
+}
+
+/// Find the relocations of the sections whose names start with
+/// `section_prefix` for data other than maps
+///
+/// The pairs of the name of the section with the relocation and the name of
+/// the section of the relocated symbol, e.g., `("kprobe/foo", ".rodata")`,
+/// are returned. Such relocations are made for constants such as `42` of
+/// `map.get(&42)`, which are stored in `.rodata`. Loaders that relocate only
+/// maps can not load the programs.
+pub fn find_non_map_relocs(binary: &Elf, section_prefix: &str) -> Vec<(String, String)> {
+    let rel_prefix = format!(".rel{}", section_prefix);
+    let mut found = vec![];
     for (shidx, relsec) in binary.shdr_relocs.iter() {
         let shdr = if let Some(shdr) = binary.section_headers.get(*shidx) {
             shdr
@@ -276,7 +1264,7 @@ fn check_tc_action_relocs(binary: &Elf) -> Result<(), Error> {
         } else {
             continue;
         };
-        if !hdr_name.starts_with(".reltc_action/") {
+        if !hdr_name.starts_with(&rel_prefix) {
             continue;
         }
         for reloc in relsec.iter() {
@@ -302,11 +1290,51 @@ fn check_tc_action_relocs(binary: &Elf) -> Result<(), Error> {
             // If `map.get(&42)` is written in the code, the 42 is stored at
             // `.rodata` section and st_type is `STT_SECTION`
             if sym.st_type() == STT_SECTION {
-                return Err(Error::IllegalProgram(format!("`{}` section has a relocation for `{}` section. But tc utility does not support relocation except for maps. Did you use `map.get(&42)` instead of `map.get(&key)`?", &hdr_name[4..], sym_hdr_name)));
+                found.push((hdr_name[4..].to_string(), sym_hdr_name.to_string()));
             }
         }
     }
-    Ok(())
+    found
+}
+
+/// Return the names of the maps that no program refers to
+///
+/// The maps are the symbols of the `maps` section and of `maps/<name>`
+/// sections. A map is used if a relocation section of any program, i.e., an
+/// executable section, has a relocation of its symbol, e.g., for
+/// `BPF_LD_MAP_FD`.
+fn unused_maps(binary: &Elf) -> Vec<String> {
+    let is_map_section = |shndx: usize| {
+        binary
+            .section_headers
+            .get(shndx)
+            .and_then(|shdr| binary.shdr_strtab.get_at(shdr.sh_name))
+            .map(|name| name == "maps" || name.starts_with("maps/"))
+            .unwrap_or(false)
+    };
+    let mut used = HashSet::new();
+    for (shidx, relsec) in binary.shdr_relocs.iter() {
+        let target_shndx = match binary.section_headers.get(*shidx) {
+            Some(shdr) => shdr.sh_info as usize,
+            None => continue,
+        };
+        match binary.section_headers.get(target_shndx) {
+            Some(shdr) if shdr.sh_flags & SHF_EXECINSTR as u64 != 0 => {}
+            _ => continue,
+        }
+        for reloc in relsec.iter() {
+            if let Some(sym) = binary.syms.get(reloc.r_sym) {
+                used.insert((sym.st_shndx, sym.st_value));
+            }
+        }
+    }
+    binary
+        .syms
+        .iter()
+        .filter(|sym| sym.st_type() != STT_SECTION && is_map_section(sym.st_shndx))
+        .filter(|sym| !used.contains(&(sym.st_shndx, sym.st_value)))
+        .filter_map(|sym| binary.strtab.get_at(sym.st_name).map(String::from))
+        .collect()
 }
 
 pub fn build(
@@ -335,10 +1363,15 @@ pub fn build_with_features(
     if !path.exists() {
         return Err(Error::MissingManifest(path));
     }
+    let package = &resolve_package(package, buildopt.package.as_deref())?;
 
     if probes.is_empty() {
         let doc = load_package(package)?;
         probes.extend(probe_names(&doc, &features)?);
+    } else if probes.iter().any(|probe| is_pattern(probe)) {
+        let doc = load_package(package)?;
+        let names = probe_names(&doc, &features)?;
+        *probes = select_probes(&names, probes)?;
     }
 
     unsafe {
@@ -346,15 +1379,161 @@ pub fn build_with_features(
         if buildopt.force_loop_unroll {
             llvm::force_loop_unroll();
         }
+        if buildopt.emit_remark_messages {
+            llvm::enable_remarks();
+        }
     }
 
-    for probe in probes {
-        build_probe(cargo, package, &buildopt.target_dir, &probe, &features)?;
+    rustc_codegen_args(buildopt.opt_level)?;
+    let kernel_version = target_kernel_version(buildopt.kernel_version);
+    let job = ProbeJob {
+        cargo: buildopt
+            .cargo_path
+            .as_deref()
+            .unwrap_or(cargo)
+            .to_path_buf(),
+        toolchain: buildopt.toolchain.clone(),
+        package: package.to_path_buf(),
+        features: features.clone(),
+        kernel_version: buildopt.kernel_version,
+        kernel_version_cfg: kernel_version_cfg(kernel_version.as_ref()),
+        target_dir: buildopt.target_dir.clone(),
+        force_loop_unroll: buildopt.force_loop_unroll,
+        cpu_version: buildopt.cpu_version,
+        opt_level: buildopt.opt_level,
+        emit_remark_messages: buildopt.emit_remark_messages,
+        strip_for_size: buildopt.strip_for_size,
+        strict_strip: buildopt.strict_strip,
+        force: buildopt.force,
+        verify: buildopt.verify,
+        message_format: buildopt.message_format,
+    };
+    // the hook is not shared with other threads
+    let results = match buildopt.post_compile_hook.as_deref() {
+        None if buildopt.jobs > 1 => {
+            run_jobs(probes, buildopt.jobs, move |probe| job.build(probe, None))
+        }
+        hook => probes
+            .iter()
+            .map(|probe| (probe.clone(), job.build(probe, hook)))
+            .collect(),
+    };
+    let mut failures = vec![];
+    for (probe, ret) in results {
+        match buildopt.message_format {
+            MessageFormat::Human => {
+                if let Ok(BuildStatus::Fresh) = ret {
+                    println!("the `{}' program is up to date", probe);
+                }
+            }
+            MessageFormat::Json => println!("{}", probe_message_json(&probe, &ret)),
+        }
+        if let Err(e) = ret {
+            failures.push((probe, e));
+        }
+    }
+    match failures.len() {
+        0 => {}
+        1 => return Err(failures.pop().unwrap().1),
+        _ => return Err(Error::Probes(failures)),
+    }
+
+    if buildopt.verify {
+        verify_probes(&buildopt.target_dir, probes, buildopt.message_format)?;
     }
 
     Ok(())
 }
 
+fn verify_probes(
+    target_dir: &Path,
+    probes: &[String],
+    message_format: MessageFormat,
+) -> Result<(), Error> {
+    let mut failed = vec![];
+    let mut passed = 0;
+    for probe in probes {
+        let elf = probe_elf_path(target_dir, probe);
+        let results = match verify::verify_probe(&elf) {
+            Ok(results) => results,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                failed.push(probe.clone());
+                continue;
+            }
+        };
+        for result in results {
+            if message_format == MessageFormat::Json {
+                println!("{}", verify_message_json(probe, &result));
+            }
+            match result.error {
+                None => {
+                    if message_format == MessageFormat::Human {
+                        println!(
+                            "verified `{}' of the `{}' program: ok",
+                            result.program, probe
+                        );
+                    }
+                    passed += 1;
+                }
+                Some(e) => {
+                    eprintln!(
+                        "verified `{}' of the `{}' program: FAILED: {}",
+                        result.program, probe, e
+                    );
+                    failed.push(format!("{}/{}", probe, result.program));
+                }
+            }
+        }
+    }
+    print_human(
+        message_format,
+        &format!(
+            "verifier smoke test: {} passed, {} failed",
+            passed,
+            failed.len()
+        ),
+    );
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Verify(failed))
+    }
+}
+
+/// Return the path of the ELF file that `cargo bpf build` produces for `probe`
+pub fn probe_elf_path(target_dir: &Path, probe: &str) -> PathBuf {
+    target_dir
+        .join("bpf")
+        .join("programs")
+        .join(probe)
+        .join(format!("{}.elf", probe))
+}
+
+/// Return the name and the ELF file of each of `probes` built in `target_dir`
+///
+/// Probes whose ELF file does not exist are left out. `build` fills in the
+/// names of all the probes of the package if it is given no names, so the
+/// same `probes` can be passed here after building, e.g.,
+///
+/// ```no_run
+/// # use std::path::Path;
+/// # use cargo_bpf_lib::{build, built_artifacts, BuildOptions};
+/// let buildopt = BuildOptions::default();
+/// let mut probes = Vec::new();
+/// build(Path::new("cargo"), Path::new("probes"), &mut probes, &buildopt).unwrap();
+/// for (probe, elf) in built_artifacts(&buildopt.target_dir, &probes) {
+///     println!("{}: {}", probe, elf.display());
+/// }
+/// ```
+pub fn built_artifacts(target_dir: &Path, probes: &[String]) -> Vec<(String, PathBuf)> {
+    probes
+        .iter()
+        .map(|probe| (probe.clone(), probe_elf_path(target_dir, probe)))
+        .filter(|(_, elf)| elf.is_file())
+        .collect()
+}
+
 pub fn cmd_build(mut programs: Vec<String>, buildopt: &BuildOptions) -> Result<(), CommandError> {
     let current_dir = std::env::current_dir().unwrap();
     Ok(build(
@@ -384,7 +1563,92 @@ fn load_package(package: &Path) -> Result<Document, Error> {
     Ok(data.parse::<Document>().unwrap())
 }
 
+/// Return the directory of the package to build programs of
+///
+/// If the manifest in `package` is of a workspace, the member named
+/// `selector` is returned, or the only member with `[[bin]]` targets if
+/// `selector` is `None`. The root package of the workspace, if any, is one of
+/// the members. Otherwise `package` itself is returned.
+fn resolve_package(package: &Path, selector: Option<&str>) -> Result<PathBuf, Error> {
+    let doc = load_package(package)?;
+    if doc["workspace"].is_none() {
+        return match selector {
+            Some(name) if package_name(&doc) != Some(name) => {
+                Err(Error::UnknownPackage(name.to_string()))
+            }
+            _ => Ok(package.to_path_buf()),
+        };
+    }
+
+    let mut members = vec![];
+    for dir in workspace_members(package, &doc)? {
+        let member = load_package(&dir)?;
+        members.push((dir, member));
+    }
+    if !doc["package"].is_none() {
+        members.insert(0, (package.to_path_buf(), doc));
+    }
+    if let Some(name) = selector {
+        return members
+            .into_iter()
+            .find(|(_, member)| package_name(member) == Some(name))
+            .map(|(dir, _)| dir)
+            .ok_or_else(|| Error::UnknownPackage(name.to_string()));
+    }
+    let mut with_bins: Vec<_> = members
+        .into_iter()
+        .filter(|(_, member)| member["bin"].is_array_of_tables())
+        .collect();
+    match with_bins.len() {
+        0 => Err(Error::NoPrograms),
+        1 => Ok(with_bins.pop().unwrap().0),
+        _ => Err(Error::AmbiguousPackage(
+            with_bins
+                .iter()
+                .filter_map(|(_, member)| package_name(member).map(String::from))
+                .collect(),
+        )),
+    }
+}
+
+// Expand the `members` of the workspace, which may be glob patterns relative
+// to the root, to the directories that are not `exclude`d
+fn workspace_members(root: &Path, doc: &Document) -> Result<Vec<PathBuf>, Error> {
+    let exclude: Vec<PathBuf> = doc["workspace"]["exclude"]
+        .as_array()
+        .map(|paths| {
+            paths
+                .iter()
+                .filter_map(|path| path.as_str())
+                .map(|path| root.join(path))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut dirs = vec![];
+    let patterns = match doc["workspace"]["members"].as_array() {
+        Some(patterns) => patterns,
+        None => return Ok(dirs),
+    };
+    for pattern in patterns.iter().filter_map(|pattern| pattern.as_str()) {
+        let paths = glob(&root.join(pattern).to_string_lossy()).map_err(Error::PatternError)?;
+        for dir in paths.filter_map(|path| path.ok()) {
+            if dir.join("Cargo.toml").is_file()
+                && !exclude.iter().any(|ex| dir.starts_with(ex))
+                && !dirs.contains(&dir)
+            {
+                dirs.push(dir);
+            }
+        }
+    }
+    Ok(dirs)
+}
+
+fn package_name(doc: &Document) -> Option<&str> {
+    doc["package"]["name"].as_str()
+}
+
 fn probe_names(doc: &Document, features: &Vec<String>) -> Result<Vec<String>, Error> {
+    let features = resolve_features(doc, features);
     match &doc["bin"] {
         Item::ArrayOfTables(aot) => {
             let mut names = vec![];
@@ -407,3 +1671,720 @@ fn probe_names(doc: &Document, features: &Vec<String>) -> Result<Vec<String>, Er
         _ => Err(Error::NoPrograms),
     }
 }
+
+// Expand `features` with the default features and the features they enable
+// transitively, as Cargo does since probes are built without
+// `--no-default-features`
+fn resolve_features(doc: &Document, features: &[String]) -> Vec<String> {
+    let mut resolved: Vec<String> = vec![];
+    let mut pending: Vec<String> = features.to_vec();
+    pending.push("default".to_string());
+    while let Some(feature) = pending.pop() {
+        if resolved.contains(&feature) {
+            continue;
+        }
+        if let Some(enabled) = doc["features"][feature.as_str()].as_array() {
+            for feat in enabled.iter().filter_map(|feat| feat.as_str()) {
+                // `dep:name` and `name?/feature` do not enable the feature of
+                // the optional dependency. `name/feature` does
+                if feat.starts_with("dep:") {
+                    continue;
+                }
+                match feat.split_once('/') {
+                    Some((dep, _)) if dep.ends_with('?') => {}
+                    Some((dep, _)) => pending.push(dep.to_string()),
+                    None => pending.push(feat.to_string()),
+                }
+            }
+        }
+        resolved.push(feature);
+    }
+    resolved
+}
+
+// Return true if `name` is a glob pattern rather than a probe name
+fn is_pattern(name: &str) -> bool {
+    name.contains(|c| c == '*' || c == '?' || c == '[')
+}
+
+/// Return the probes of `names` that match any of the glob `patterns`, e.g.,
+/// `xdp_*`, in the order of `names`
+///
+/// `Error::NoPrograms` is returned if a pattern matches no probes.
+fn select_probes(names: &[String], patterns: &[String]) -> Result<Vec<String>, Error> {
+    let patterns = patterns
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Error::PatternError)?;
+    for pattern in patterns.iter() {
+        if !names.iter().any(|name| pattern.matches(name)) {
+            eprintln!("error: no programs match `{}'", pattern);
+            return Err(Error::NoPrograms);
+        }
+    }
+    Ok(names
+        .iter()
+        .filter(|name| patterns.iter().any(|pattern| pattern.matches(name)))
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::elf_with_sections;
+    use rustc_version::{Channel, LlvmVersion, VersionMeta};
+    use tempfile::tempdir;
+
+    fn llvm(major: u64) -> Option<LlvmVersion> {
+        Some(LlvmVersion { major, minor: 0 })
+    }
+
+    fn rustc_meta(llvm_version: Option<LlvmVersion>) -> VersionMeta {
+        VersionMeta {
+            semver: Version::new(1, 75, 0),
+            commit_hash: None,
+            commit_date: None,
+            build_date: None,
+            channel: Channel::Stable,
+            host: "x86_64-unknown-linux-gnu".to_string(),
+            short_version_string: "rustc 1.75.0".to_string(),
+            llvm_version,
+        }
+    }
+
+    #[test]
+    fn test_check_llvm_version() {
+        let codegen = check_llvm_version(&Version::new(17, 0, 6), &rustc_meta(llvm(17))).unwrap();
+        assert!(codegen.opaque_pointers);
+        // minor versions do not matter
+        let codegen = check_llvm_version(&Version::new(18, 1, 0), &rustc_meta(llvm(17))).unwrap();
+        assert!(codegen.opaque_pointers);
+        let codegen = check_llvm_version(&Version::new(17, 0, 6), &rustc_meta(llvm(13))).unwrap();
+        assert!(!codegen.opaque_pointers);
+        assert!(check_llvm_version(&Version::new(17, 0, 6), &rustc_meta(None)).is_ok());
+
+        match check_llvm_version(&Version::new(13, 0, 1), &rustc_meta(llvm(17))) {
+            Err(Error::InvalidLLVMVersion(msg)) => assert!(msg.contains("LLVM 17.0")),
+            _ => panic!("LLVM 17 bitcode must be rejected by LLVM 13"),
+        }
+    }
+    #[test]
+    fn test_fingerprint() {
+        let package = tempdir().unwrap();
+        let src = package.path().join("src").join("probe");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("main.rs"), "fn main() {}").unwrap();
+        fs::write(package.path().join("Cargo.toml"), "[package]").unwrap();
+        let artifacts_dir = package.path().join("target/bpf/programs/probe");
+        fs::create_dir_all(&artifacts_dir).unwrap();
+        let options = FingerprintOptions {
+            opt_level: 3,
+            ..Default::default()
+        };
+        let features = vec!["probes".to_string()];
+        let cfg = kernel_version_cfg(None);
+        let cargo = Path::new("cargo");
+        let rustc = "rustc 1.60.0\nLLVM version: 14.0.0\n";
+        let fp = |cargo: &Path, features: &[String], rustc: &str, options: &FingerprintOptions| {
+            fingerprint(
+                package.path(),
+                cargo,
+                "probe",
+                features,
+                &cfg,
+                rustc,
+                options,
+            )
+            .unwrap()
+        };
+
+        let first = fp(cargo, &features, rustc, &options);
+        assert!(!is_fresh(&artifacts_dir, "probe", &first));
+        // the first build
+        fs::write(artifacts_dir.join("probe.elf"), "").unwrap();
+        fs::write(artifacts_dir.join(FINGERPRINT_FILE), &first).unwrap();
+
+        // the second build is a no-op
+        let second = fp(cargo, &features, rustc, &options);
+        assert!(is_fresh(&artifacts_dir, "probe", &second));
+
+        let other_features = vec!["probes".to_string(), "extra".to_string()];
+        let other = fp(cargo, &other_features, rustc, &options);
+        assert!(!is_fresh(&artifacts_dir, "probe", &other));
+
+        fs::write(src.join("main.rs"), "fn main() { }").unwrap();
+        let other = fp(cargo, &features, rustc, &options);
+        assert!(!is_fresh(&artifacts_dir, "probe", &other));
+
+        let other_rustc = "rustc 1.61.0\nLLVM version: 14.0.0\n";
+        let other = fp(cargo, &features, other_rustc, &options);
+        assert!(!is_fresh(&artifacts_dir, "probe", &other));
+
+        let nightly = FingerprintOptions {
+            toolchain: Some("nightly"),
+            ..options
+        };
+        let other = fp(cargo, &features, rustc, &nightly);
+        assert!(!is_fresh(&artifacts_dir, "probe", &other));
+
+        let opt_level = FingerprintOptions {
+            opt_level: 2,
+            ..options
+        };
+        let other = fp(cargo, &features, rustc, &opt_level);
+        assert!(!is_fresh(&artifacts_dir, "probe", &other));
+
+        let other = fp(Path::new("/opt/rust/bin/cargo"), &features, rustc, &options);
+        assert!(!is_fresh(&artifacts_dir, "probe", &other));
+
+        // the sources of path dependencies are part of the fingerprint
+        let dep = package.path().join("dep");
+        fs::create_dir_all(dep.join("src")).unwrap();
+        fs::write(dep.join("Cargo.toml"), "[package]").unwrap();
+        fs::write(dep.join("src").join("lib.rs"), "").unwrap();
+        fs::write(
+            package.path().join("Cargo.toml"),
+            "[package]\n[dependencies]\ndep = { path = \"dep\" }\n",
+        )
+        .unwrap();
+        let with_dep = fp(cargo, &features, rustc, &options);
+        fs::write(dep.join("src").join("lib.rs"), "pub fn f() {}").unwrap();
+        assert_ne!(with_dep, fp(cargo, &features, rustc, &options));
+    }
+    #[test]
+    fn test_run_jobs() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::Duration;
+
+        let target = tempdir().unwrap();
+        let artifacts = target.path().to_path_buf();
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (running2, peak2) = (running.clone(), peak.clone());
+        let probes = vec!["xdp".to_string(), "kprobe".to_string(), "bad".to_string()];
+        let results = run_jobs(&probes, 2, move |probe| {
+            let now = running2.fetch_add(1, Ordering::SeqCst) + 1;
+            peak2.fetch_max(now, Ordering::SeqCst);
+            let dir = artifacts.join(probe);
+            fs::create_dir_all(&dir)?;
+            for i in 0..5 {
+                fs::write(dir.join(format!("{}.{}", probe, i)), probe)?;
+                thread::sleep(Duration::from_millis(20));
+            }
+            running2.fetch_sub(1, Ordering::SeqCst);
+            if probe == "bad" {
+                return Err(Error::Compile(probe.to_string(), None));
+            }
+            Ok(())
+        });
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        let probes_run: Vec<_> = results.iter().map(|(probe, _)| probe).collect();
+        assert_eq!(probes_run, probes.iter().collect::<Vec<_>>());
+        let failures: Vec<_> = results.iter().filter(|(_, ret)| ret.is_err()).collect();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, "bad");
+        for probe in probes.iter() {
+            for entry in fs::read_dir(target.path().join(probe)).unwrap() {
+                let name = entry.unwrap().file_name().into_string().unwrap();
+                assert!(name.starts_with(&format!("{}.", probe)));
+                assert_eq!(
+                    fs::read_to_string(target.path().join(probe).join(&name)).unwrap(),
+                    *probe
+                );
+            }
+        }
+    }
+
+    // An ELF relocatable file whose `section` has a relocation for `.rodata`,
+    // as `map.get(&42)` generates
+    fn elf_with_rodata_reloc(section: &str) -> Vec<u8> {
+        let shstrtab = format!("\0.rel{}\0.rodata\0.symtab\0.strtab\0.shstrtab\0", section);
+        let shstrtab = shstrtab.as_bytes();
+        // the name of `section` is the suffix of the name of `.rel<section>`
+        let rodata_name = section.len() + 6;
+        let (text_off, rodata_off, rel_off, symtab_off, strtab_off, shstrtab_off) =
+            (64u64, 72u64, 80u64, 96u64, 144u64, 152u64);
+        let shoff = shstrtab_off + shstrtab.len() as u64;
+        let shoff = (shoff + 7) & !7;
+
+        let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+        elf.resize(16, 0);
+        elf.extend_from_slice(&1u16.to_le_bytes()); // ET_REL
+        elf.extend_from_slice(&247u16.to_le_bytes()); // EM_BPF
+        elf.extend_from_slice(&1u32.to_le_bytes());
+        elf.extend_from_slice(&0u64.to_le_bytes()); // entry
+        elf.extend_from_slice(&0u64.to_le_bytes()); // phoff
+        elf.extend_from_slice(&shoff.to_le_bytes());
+        elf.extend_from_slice(&0u32.to_le_bytes()); // flags
+        for half in [64u16, 0, 0, 64, 7, 6].iter() {
+            elf.extend_from_slice(&half.to_le_bytes());
+        }
+        // `section` and .rodata
+        elf.resize(rel_off as usize, 0);
+        // a relocation at offset 0 for the symbol 1
+        elf.extend_from_slice(&0u64.to_le_bytes());
+        elf.extend_from_slice(&((1u64 << 32) | 1).to_le_bytes());
+        // the null symbol and the STT_SECTION symbol of .rodata
+        elf.resize(symtab_off as usize + 24, 0);
+        elf.extend_from_slice(&0u32.to_le_bytes());
+        elf.push(STT_SECTION);
+        elf.push(0);
+        elf.extend_from_slice(&3u16.to_le_bytes());
+        elf.extend_from_slice(&[0; 16]);
+        elf.resize(strtab_off as usize + 1, 0);
+        elf.resize(shstrtab_off as usize, 0);
+        elf.extend_from_slice(shstrtab);
+        elf.resize(shoff as usize, 0);
+
+        // name, type, offset, size, link, info and entsize
+        let shdrs = [
+            (0, 0, 0, 0, 0, 0, 0),
+            (5, 1, text_off, 8, 0, 0, 0),
+            (1, 9, rel_off, 16, 4, 1, 16),
+            (rodata_name, 1, rodata_off, 8, 0, 0, 0),
+            (rodata_name + 8, 2, symtab_off, 48, 5, 1, 24),
+            (rodata_name + 16, 3, strtab_off, 1, 0, 0, 0),
+            (
+                rodata_name + 24,
+                3,
+                shstrtab_off,
+                shstrtab.len() as u64,
+                0,
+                0,
+                0,
+            ),
+        ];
+        for (name, ty, off, size, link, info, entsize) in shdrs.iter() {
+            elf.extend_from_slice(&(*name as u32).to_le_bytes());
+            elf.extend_from_slice(&(*ty as u32).to_le_bytes());
+            elf.extend_from_slice(&0u64.to_le_bytes()); // flags
+            elf.extend_from_slice(&0u64.to_le_bytes()); // addr
+            elf.extend_from_slice(&off.to_le_bytes());
+            elf.extend_from_slice(&size.to_le_bytes());
+            elf.extend_from_slice(&(*link as u32).to_le_bytes());
+            elf.extend_from_slice(&(*info as u32).to_le_bytes());
+            elf.extend_from_slice(&8u64.to_le_bytes()); // addralign
+            elf.extend_from_slice(&(*entsize as u64).to_le_bytes());
+        }
+        elf
+    }
+
+    #[test]
+    fn test_probe_message_json() {
+        let bytes = elf_with_rodata_reloc("tc_action/ingress");
+        let binary = Elf::parse(&bytes).unwrap();
+        let ret = check_tc_action_relocs(&binary).map(|_| BuildStatus::Built);
+        let json = probe_message_json("tc", &ret);
+        assert!(json.starts_with(r#"{"probe":"tc","status":"failed","kind":"IllegalProgram","#));
+        assert!(json.contains("`tc_action/ingress` section has a relocation for `.rodata` section"));
+        assert!(json.ends_with("\"}"));
+
+        let ret = Err(Error::Compile(
+            "kprobe".to_string(),
+            Some("error: \"x\"\n\tat line 1".to_string()),
+        ));
+        assert_eq!(
+            probe_message_json("kprobe", &ret),
+            r#"{"probe":"kprobe","status":"failed","kind":"Compile","message":"error: \"x\"\n\tat line 1"}"#
+        );
+        assert_eq!(
+            probe_message_json("xdp", &Ok(BuildStatus::Fresh)),
+            r#"{"probe":"xdp","status":"fresh"}"#
+        );
+    }
+
+    #[test]
+    fn test_verify_message_json() {
+        let result = verify::VerifyResult {
+            program: "kprobe/tcp_v4_connect".to_string(),
+            error: None,
+        };
+        assert_eq!(
+            verify_message_json("tcp", &result),
+            r#"{"probe":"tcp","program":"kprobe/tcp_v4_connect","status":"verified"}"#
+        );
+        let result = verify::VerifyResult {
+            program: "xdp/filter".to_string(),
+            error: Some("invalid mem access \"scalar\"".to_string()),
+        };
+        assert_eq!(
+            verify_message_json("xdp", &result),
+            r#"{"probe":"xdp","program":"xdp/filter","status":"rejected","message":"invalid mem access \"scalar\""}"#
+        );
+    }
+
+    #[test]
+    fn test_compiler_message() {
+        let long: String = (0..2000).map(|i| format!("line {}\n", i)).collect();
+        let message = compiler_message(long.as_bytes());
+        assert!(message.len() <= MAX_COMPILER_MESSAGE_LEN + 4);
+        assert!(message.starts_with("...\nline "));
+        assert!(message.ends_with("\nline 1999"));
+        assert_eq!(compiler_message(b"error: oops\n\n"), "error: oops");
+    }
+
+    #[test]
+    fn test_run_compiler() {
+        let package = tempdir().unwrap();
+        fs::write(
+            package.path().join("Cargo.toml"),
+            "[package]\nname = \"broken\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n[workspace]\n",
+        )
+        .unwrap();
+        fs::create_dir(package.path().join("src")).unwrap();
+        // E0585: a doc comment that documents nothing
+        fs::write(
+            package.path().join("src").join("main.rs"),
+            "fn main() {\n    /// nothing\n}\n",
+        )
+        .unwrap();
+        let mut cargo = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()));
+        cargo
+            .current_dir(package.path())
+            .args(&["rustc", "--offline", "--color=never", "--target-dir"])
+            .arg(package.path().join("target"));
+        match run_compiler("broken", &mut cargo, MessageFormat::Json) {
+            Err(Error::Compile(probe, Some(msg))) => {
+                assert_eq!(probe, "broken");
+                assert!(msg.contains("error[E0585]"), "{}", msg);
+            }
+            ret => panic!("unexpected result: {:?}", ret),
+        }
+    }
+
+    #[test]
+    fn test_cargo_command() {
+        let cargo = cargo_command(Path::new("/opt/rust/bin/cargo"), Some("nightly-2021-05-01"));
+        assert_eq!(cargo.get_program(), "/opt/rust/bin/cargo");
+        assert_eq!(
+            cargo.get_args().collect::<Vec<_>>(),
+            vec!["+nightly-2021-05-01"]
+        );
+
+        let cargo = cargo_command(Path::new("cargo"), None);
+        assert_eq!(cargo.get_program(), "cargo");
+        assert_eq!(cargo.get_args().count(), 0);
+    }
+
+    #[test]
+    fn test_rustc_command() {
+        // cargo uses `RUSTC` if it is set
+        if env::var_os("RUSTC").is_some() {
+            return;
+        }
+        let bin = tempdir().unwrap();
+        let rustc = bin.path().join(format!("rustc{}", env::consts::EXE_SUFFIX));
+        fs::write(&rustc, "").unwrap();
+        let command = rustc_command(&bin.path().join("cargo"), Some("nightly"));
+        assert_eq!(command.get_program(), rustc.as_os_str());
+        assert_eq!(command.get_args().count(), 0);
+        assert!(command
+            .get_envs()
+            .any(|(key, value)| key == "RUSTUP_TOOLCHAIN" && value == Some("nightly".as_ref())));
+
+        let command = rustc_command(Path::new("/nonexistent/bin/cargo"), None);
+        assert_eq!(command.get_program(), "rustc");
+        let command = rustc_command(Path::new("cargo"), None);
+        assert_eq!(command.get_program(), "rustc");
+        assert_eq!(command.get_envs().count(), 0);
+    }
+
+    #[test]
+    fn test_check_rustflags() {
+        for var in ["RUSTFLAGS", "CARGO_ENCODED_RUSTFLAGS"].iter() {
+            let encode = |flags: &[&str]| {
+                if *var == "RUSTFLAGS" {
+                    flags.join(" ")
+                } else {
+                    flags.join("\x1f")
+                }
+            };
+            assert!(check_rustflags(var, "").is_ok());
+            assert!(check_rustflags(var, &encode(&["-C", "embed-bitcode=yes"])).is_ok());
+            assert!(check_rustflags(var, &encode(&["-C", "lto"])).is_ok());
+            assert!(
+                check_rustflags(var, &encode(&["-C", "lto=fat", "-Ctarget-cpu=native"])).is_ok()
+            );
+            for conflict in [
+                &["-C", "embed-bitcode=no"][..],
+                &["-Cembed-bitcode=no"],
+                &["--codegen", "embed-bitcode=off"],
+                &["--cfg", "foo", "-C", "lto=off"],
+                &["--codegen=lto=off"],
+                &["-C", "lto=no"],
+                &["-Clto=n"],
+                &["--codegen", "lto=false"],
+            ]
+            .iter()
+            {
+                match check_rustflags(var, &encode(conflict)) {
+                    Err(Error::ConflictingFlags(msg)) => assert!(msg.contains(var)),
+                    ret => panic!("{:?} of {} is not rejected: {:?}", conflict, var, ret),
+                }
+            }
+        }
+        // a flag of the encoded form may contain spaces
+        assert!(check_rustflags("CARGO_ENCODED_RUSTFLAGS", "--cfg\x1ffeature=\"a b\"").is_ok());
+    }
+
+    #[test]
+    fn test_target_kernel_version() {
+        let cfg = kernel_version_cfg(target_kernel_version(Some((6, 1))).as_ref());
+        assert_eq!(cfg, r#"kernel_version="6.1""#);
+        let cfg = kernel_version_cfg(target_kernel_version(Some((5, 15))).as_ref());
+        assert_eq!(cfg, r#"kernel_version="5.7""#);
+        let cfg = kernel_version_cfg(target_kernel_version(Some((4, 19))).as_ref());
+        assert_eq!(cfg, r#"kernel_version="4.19""#);
+    }
+
+    #[test]
+    fn test_built_artifacts() {
+        let target = tempdir().unwrap();
+        let probes = vec!["built".to_string(), "missing".to_string()];
+        let artifacts_dir = target.path().join("bpf/programs/built");
+        fs::create_dir_all(&artifacts_dir).unwrap();
+        fs::write(artifacts_dir.join("built.elf"), "").unwrap();
+        fs::create_dir_all(target.path().join("bpf/programs/missing")).unwrap();
+
+        let artifacts = built_artifacts(target.path(), &probes);
+        assert_eq!(artifacts.len(), 1);
+        let (probe, elf) = &artifacts[0];
+        assert_eq!(probe, "built");
+        assert_eq!(elf.extension().unwrap(), "elf");
+        assert!(elf.exists());
+    }
+
+    #[test]
+    fn test_check_program_sections() {
+        let bytes = elf_with_sections(&[".text"]);
+        let binary = Elf::parse(&bytes).unwrap();
+        match check_program_sections("empty", &binary) {
+            Err(Error::NoRecognizableSections(probe)) => assert_eq!(probe, "empty"),
+            ret => panic!("unexpected result: {:?}", ret),
+        }
+
+        let bytes = elf_with_sections(&[".text", "kprobe/do_sys_open"]);
+        let binary = Elf::parse(&bytes).unwrap();
+        assert!(check_program_sections("kprobe", &binary).is_ok());
+
+        for prefix in PROGRAM_SECTION_PREFIXES {
+            let name = format!("{}prog", prefix);
+            let bytes = elf_with_sections(&[&name]);
+            let binary = Elf::parse(&bytes).unwrap();
+            assert!(check_program_sections("probe", &binary).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_find_non_map_relocs() {
+        let bytes = elf_with_rodata_reloc("kprobe/do_sys_open");
+        let binary = Elf::parse(&bytes).unwrap();
+        assert_eq!(
+            find_non_map_relocs(&binary, "kprobe/"),
+            vec![("kprobe/do_sys_open".to_string(), ".rodata".to_string())]
+        );
+        assert!(find_non_map_relocs(&binary, "tc_action/").is_empty());
+        assert!(check_tc_action_relocs(&binary).is_ok());
+    }
+
+    #[test]
+    fn test_strip_unnecessary() {
+        let dir = tempdir().unwrap();
+        let missing = dir.path().join("missing.elf");
+        match strip_unnecessary(&missing, false, false, true) {
+            Err(Error::StripFailed(msg)) => assert!(msg.contains("missing.elf"), "{}", msg),
+            ret => panic!("unexpected result: {:?}", ret),
+        }
+        assert!(strip_unnecessary(&missing, false, false, false).is_ok());
+    }
+
+    #[test]
+    fn test_rustc_codegen_args() {
+        let args = rustc_codegen_args(2).unwrap();
+        assert!(args.windows(2).any(|arg| arg == ["-C", "opt-level=2"]));
+        assert!(args.windows(2).any(|arg| arg == ["-C", "panic=abort"]));
+        assert!(!args.iter().any(|arg| arg == "opt-level=3"));
+        assert!(rustc_codegen_args(0).is_ok());
+        assert!(matches!(
+            rustc_codegen_args(4),
+            Err(Error::InvalidOptLevel(4))
+        ));
+    }
+
+    #[test]
+    fn test_select_probes() {
+        let names: Vec<String> = ["kprobe_foo", "xdp_drop", "kprobe_bar", "xdp_pass"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let patterns = vec!["xdp_*".to_string(), "kprobe_foo".to_string()];
+        assert!(is_pattern(&patterns[0]));
+        assert!(!is_pattern(&patterns[1]));
+        assert_eq!(
+            select_probes(&names, &patterns).unwrap(),
+            vec!["kprobe_foo", "xdp_drop", "xdp_pass"]
+        );
+
+        let patterns = vec!["xdp_*".to_string(), "tc_*".to_string()];
+        assert!(matches!(
+            select_probes(&names, &patterns),
+            Err(Error::NoPrograms)
+        ));
+    }
+
+    #[test]
+    fn test_probe_names_resolve_features() {
+        let doc = r#"
+[features]
+default = []
+core = []
+net = ["core", "libc/std", "dep:tracing"]
+
+[[bin]]
+name = "core_probe"
+required-features = ["core"]
+
+[[bin]]
+name = "net_probe"
+required-features = ["net"]
+
+[[bin]]
+name = "probe"
+"#
+        .parse::<Document>()
+        .unwrap();
+        assert_eq!(
+            probe_names(&doc, &vec!["net".to_string()]).unwrap(),
+            vec!["core_probe", "net_probe", "probe"]
+        );
+        assert_eq!(
+            probe_names(&doc, &vec!["core".to_string()]).unwrap(),
+            vec!["core_probe", "probe"]
+        );
+        assert_eq!(probe_names(&doc, &vec![]).unwrap(), vec!["probe"]);
+
+        let doc = r#"
+[features]
+default = ["net"]
+core = []
+net = ["core"]
+
+[[bin]]
+name = "core_probe"
+required-features = ["core"]
+"#
+        .parse::<Document>()
+        .unwrap();
+        assert_eq!(probe_names(&doc, &vec![]).unwrap(), vec!["core_probe"]);
+    }
+
+    #[test]
+    fn test_resolve_workspace_member() {
+        let root = tempdir().unwrap();
+        fs::write(
+            root.path().join("Cargo.toml"),
+            "[workspace]\nmembers = [\"probes\", \"crates/*\"]\nexclude = [\"crates/old\"]\n",
+        )
+        .unwrap();
+        let probes = root.path().join("probes");
+        fs::create_dir(&probes).unwrap();
+        fs::write(
+            probes.join("Cargo.toml"),
+            "[package]\nname = \"probes\"\n\n[[bin]]\nname = \"block_http\"\n\n[[bin]]\nname = \"tcp_lifetime\"\n",
+        )
+        .unwrap();
+        let userspace = root.path().join("crates/userspace");
+        fs::create_dir_all(&userspace).unwrap();
+        fs::write(
+            userspace.join("Cargo.toml"),
+            "[package]\nname = \"userspace\"\n",
+        )
+        .unwrap();
+        let old = root.path().join("crates/old");
+        fs::create_dir_all(&old).unwrap();
+        fs::write(
+            old.join("Cargo.toml"),
+            "[package]\nname = \"old\"\n\n[[bin]]\nname = \"old_probe\"\n",
+        )
+        .unwrap();
+
+        let package = resolve_package(root.path(), None).unwrap();
+        assert_eq!(package, probes);
+        let doc = load_package(&package).unwrap();
+        assert_eq!(
+            probe_names(&doc, &vec![]).unwrap(),
+            vec!["block_http", "tcp_lifetime"]
+        );
+        assert_eq!(
+            resolve_package(root.path(), Some("userspace")).unwrap(),
+            userspace
+        );
+        assert!(matches!(
+            resolve_package(root.path(), Some("old")),
+            Err(Error::UnknownPackage(_))
+        ));
+        assert_eq!(resolve_package(&probes, None).unwrap(), probes);
+        assert!(matches!(
+            resolve_package(&probes, Some("userspace")),
+            Err(Error::UnknownPackage(_))
+        ));
+
+        fs::write(
+            userspace.join("Cargo.toml"),
+            "[package]\nname = \"userspace\"\n\n[[bin]]\nname = \"cli\"\n",
+        )
+        .unwrap();
+        match resolve_package(root.path(), None) {
+            Err(Error::AmbiguousPackage(names)) => assert_eq!(names, vec!["probes", "userspace"]),
+            ret => panic!("unexpected result: {:?}", ret),
+        }
+    }
+
+    #[test]
+    fn test_toolchain_mismatch() {
+        let diagnostics = "\
+   Compiling probes v0.1.0 (/home/user/probes)
+error[E0554]: `#![feature]` may not be used on the stable release channel
+ --> src/lib.rs:1:1
+error[E0658]: use of unstable library feature 'asm'
+  --> src/xdp/main.rs:10:5
+   |
+10 |     asm!(\"r0 = 0\");
+   |     ^^^
+   |
+   = note: see issue #72016 <https://github.com/rust-lang/rust/issues/72016> for more information
+   = help: add `#![feature(asm)]` to the crate attributes to enable
+
+error[E0658]: use of unstable library feature 'asm'
+   = help: add `#![feature(asm)]` to the crate attributes to enable
+error[E0658]: `impl Trait` in type aliases is unstable
+   = help: add `#![feature(type_alias_impl_trait)]` to the crate attributes to enable
+";
+        let msg = toolchain_mismatch(diagnostics).unwrap();
+        assert!(msg.contains("`asm', `type_alias_impl_trait'"), "{}", msg);
+        assert!(msg.contains("nightly"));
+
+        let diagnostics = "\
+error[E0554]: `#![feature]` may not be used on the stable release channel
+ --> src/lib.rs:1:1
+";
+        assert!(toolchain_mismatch(diagnostics)
+            .unwrap()
+            .starts_with("requires a nightly toolchain"));
+
+        let diagnostics = "error[E0425]: cannot find value `x` in this scope";
+        assert_eq!(toolchain_mismatch(diagnostics), None);
+
+        // nightly toolchains hint at the features that are not enabled too
+        let diagnostics = "\
+error[E0658]: use of unstable library feature 'asm'
+   = help: add `#![feature(asm)]` to the crate attributes to enable
+";
+        assert_eq!(toolchain_mismatch(diagnostics), None);
+
+        let diagnostics = "error: the option `Z` is only accepted on the nightly compiler";
+        assert!(toolchain_mismatch(diagnostics).is_some());
+    }
+}
@@ -25,13 +25,23 @@ mod build;
 mod build_c;
 #[cfg(feature = "build")]
 mod llvm;
+#[cfg(feature = "build")]
+pub mod verify;
 
+#[cfg(feature = "command-line")]
+mod bench;
+#[cfg(feature = "command-line")]
+mod btf_dump;
 #[cfg(feature = "command-line")]
 mod load;
 #[cfg(feature = "command-line")]
 mod new;
 #[cfg(feature = "command-line")]
 mod new_program;
+#[cfg(feature = "command-line")]
+mod skeleton;
+#[cfg(all(test, any(feature = "build", feature = "command-line")))]
+mod test_util;
 
 pub struct CommandError(pub String);
 
@@ -41,13 +51,19 @@ impl std::convert::From<std::io::Error> for CommandError {
     }
 }
 
+#[cfg(feature = "command-line")]
+pub use bench::{bench, parse_duration, BenchOptions};
+#[cfg(feature = "command-line")]
+pub use btf_dump::btf_dump;
 #[cfg(feature = "build")]
 pub use build::*;
 #[cfg(feature = "build-c")]
 pub use build_c::*;
 #[cfg(feature = "command-line")]
-pub use load::load;
+pub use load::{load, parse_pid, EventFormat, LoadOptions};
 #[cfg(feature = "command-line")]
 pub use new::new;
 #[cfg(feature = "command-line")]
 pub use new_program::new_program;
+#[cfg(feature = "command-line")]
+pub use skeleton::skeleton;
@@ -37,14 +37,28 @@ use llvm_sys::target::*;
 use llvm_sys::target_machine::*;
 use llvm_sys::transforms::ipo::LLVMAddAlwaysInlinerPass;
 use llvm_sys::transforms::pass_manager_builder::*;
-use llvm_sys::{LLVMAttributeFunctionIndex, LLVMInlineAsmDialect::*};
+use llvm_sys::{LLVMAttributeFunctionIndex, LLVMDiagnosticSeverity, LLVMInlineAsmDialect::*};
 use llvm_sys::{LLVMOpcode, LLVMTypeKind, LLVMValueKind};
 use std::ffi::{CStr, CString};
-use std::os::raw::c_char;
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::raw::{c_char, c_void};
 use std::path::Path;
 use std::process::{Command, Stdio};
 use std::ptr;
 use std::slice;
+use std::sync::Once;
+
+/// rustc generates bitcode with opaque pointers since LLVM 15
+pub(crate) const OPAQUE_POINTERS_MIN_LLVM: u64 = 15;
+
+/// How the bitcode generated by rustc is processed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Codegen {
+    /// Pointers of the bitcode are opaque, i.e., they are all of type `ptr`
+    /// and do not tell the types they point to
+    pub opaque_pointers: bool,
+}
 
 pub(crate) unsafe fn init() {
     LLVM_InitializeAllTargets();
@@ -68,6 +82,95 @@ pub(crate) unsafe fn force_loop_unroll() {
     LLVMParseCommandLineOptions(args.len() as i32, args_ptrs.as_ptr(), overview.as_ptr());
 }
 
+/// Enable the optimization remarks of every pass
+///
+/// LLVM generates remarks, e.g., of loops not unrolled or functions not
+/// inlined, only if they are enabled. They are written to the file given to
+/// `compile`.
+///
+/// The options are global to the process and LLVM rejects options given
+/// more than once, so they are parsed only by the first call.
+pub(crate) unsafe fn enable_remarks() {
+    static ENABLE_REMARKS: Once = Once::new();
+    ENABLE_REMARKS.call_once(|| {
+        let mut args = Vec::new();
+        args.push(CString::new("cargo-bpf").unwrap());
+        args.push(CString::new("-pass-remarks=.*").unwrap());
+        args.push(CString::new("-pass-remarks-missed=.*").unwrap());
+        args.push(CString::new("-pass-remarks-analysis=.*").unwrap());
+        let args_ptrs = args.iter().map(|s| s.as_ptr()).collect::<Vec<_>>();
+        let overview = CString::new("what is this").unwrap();
+        LLVMParseCommandLineOptions(args.len() as i32, args_ptrs.as_ptr(), overview.as_ptr());
+    });
+}
+
+/// The state of the diagnostic handler that writes optimization remarks
+///
+/// Each remark is written as a `--- !Remark` YAML document whose `Message` is
+/// the description of the remark, including its source location. The C API
+/// of LLVM does not tell the pass or the kind of remarks, so this is not the
+/// optimization record format of LLVM.
+///
+/// Other diagnostics are kept in `diagnostics` to be reported by `compile`,
+/// since the handler replaces the default one that prints them. `has_error`
+/// is set if any of them is an error, which fails the compilation even if
+/// LLVM goes on generating the output.
+struct RemarkWriter {
+    file: File,
+    error: Option<io::Error>,
+    diagnostics: String,
+    has_error: bool,
+}
+
+extern "C" fn write_remark(info: LLVMDiagnosticInfoRef, writer: *mut c_void) {
+    let writer = unsafe { &mut *(writer as *mut RemarkWriter) };
+    let (severity, description) = unsafe {
+        let description = LLVMGetDiagInfoDescription(info);
+        let ret = (
+            LLVMGetDiagInfoSeverity(info),
+            CStr::from_ptr(description).to_string_lossy().into_owned(),
+        );
+        LLVMDisposeMessage(description);
+        ret
+    };
+    let kind = match severity {
+        LLVMDiagnosticSeverity::LLVMDSRemark => {
+            if writer.error.is_none() {
+                let remark = format!("--- !Remark\nMessage: {}\n...\n", yaml_string(&description));
+                writer.error = writer.file.write_all(remark.as_bytes()).err();
+            }
+            return;
+        }
+        LLVMDiagnosticSeverity::LLVMDSError => {
+            writer.has_error = true;
+            "error"
+        }
+        LLVMDiagnosticSeverity::LLVMDSWarning => "warning",
+        LLVMDiagnosticSeverity::LLVMDSNote => "note",
+    };
+    writer
+        .diagnostics
+        .push_str(&format!("{}: {}\n", kind, description));
+}
+
+// Quote `s` as a double-quoted YAML scalar
+fn yaml_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\x{:02x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
 unsafe fn load_module(context: LLVMContextRef, input: &Path) -> Result<LLVMModuleRef> {
     let mut message: *mut c_char = ptr::null_mut();
     let filename = CString::new(input.to_str().unwrap()).unwrap();
@@ -121,13 +224,20 @@ unsafe fn inject_exit_call(context: LLVMContextRef, func: LLVMValueRef, builder:
     let last = LLVMGetLastInstruction(block);
     LLVMPositionBuilderBefore(builder, last);
     let c_str = CString::new("").unwrap();
-    LLVMBuildCall(builder, exit, ptr::null_mut(), 0, c_str.as_ptr());
+    LLVMBuildCall2(builder, exit_sig, exit, ptr::null_mut(), 0, c_str.as_ptr());
 }
 
 /// Find debugger intrinsics handling methods of RedBPF maps. The the type
 /// string of methods such as `get`, `get_mut`, `get_val`, `set`, `delete` are
 /// returned.
-unsafe fn find_redbpf_map_method_type_str(dbg_var_inst: LLVMValueRef) -> Option<String> {
+///
+/// With opaque pointers, the type of the map is unknown, so only the method
+/// names such as `get_mut<u64, example_probes::vfsreadlat::VFSEvent>` are
+/// returned.
+unsafe fn find_redbpf_map_method_type_str(
+    dbg_var_inst: LLVMValueRef,
+    codegen: Codegen,
+) -> Option<String> {
     let called_val = LLVMGetCalledValue(dbg_var_inst);
     if called_val.is_null() {
         return None;
@@ -164,12 +274,17 @@ unsafe fn find_redbpf_map_method_type_str(dbg_var_inst: LLVMValueRef) -> Option<
     }
     let cname = LLVMPrintTypeToString(undef_type);
     let undef_type_str = CStr::from_ptr(cname).to_str().unwrap();
-    if !(undef_type_str.starts_with("%\"redbpf_probes::maps::") && undef_type_str.ends_with("\"*"))
-    {
-        return None;
-    }
-    // e.g., `redbpf_probes::maps::HashMap<u64, example_probes::vfsreadlat::VFSEvent>`
-    let map_type_str = &undef_type_str[2..undef_type_str.len() - 2];
+    let map_type_str = if codegen.opaque_pointers {
+        None
+    } else {
+        if !(undef_type_str.starts_with("%\"redbpf_probes::maps::")
+            && undef_type_str.ends_with("\"*"))
+        {
+            return None;
+        }
+        // e.g., `redbpf_probes::maps::HashMap<u64, example_probes::vfsreadlat::VFSEvent>`
+        Some(&undef_type_str[2..undef_type_str.len() - 2])
+    };
 
     // The second argument of llvm.dbg.value is DI local variable.
     let di_local_var = LLVMGetArgOperand(dbg_var_inst, 1);
@@ -191,12 +306,13 @@ unsafe fn find_redbpf_map_method_type_str(dbg_var_inst: LLVMValueRef) -> Option<
     let mut len: usize = 0;
     let cname = LLVMDITypeGetName(method_scope, &mut len as *mut _);
 
+    let method_str = String::from_utf8_lossy(slice::from_raw_parts(cname as *const _, len));
+
     // e.g., `redbpf_probes::maps::HashMap<u64, example_probes::vfsreadlat::VFSEvent>::get_mut<u64, example_probes::vfsreadlat::VFSEvent>`
-    Some(format!(
-        "{}::{}",
-        map_type_str,
-        String::from_utf8_lossy(slice::from_raw_parts(cname as *const _, len))
-    ))
+    match map_type_str {
+        Some(map_type_str) => Some(format!("{}::{}", map_type_str, method_str)),
+        None => Some(method_str.into_owned()),
+    }
 }
 
 unsafe fn find_map_calling_bpf_map_lookup_elem(call_inst: LLVMValueRef) -> Option<String> {
@@ -239,20 +355,21 @@ unsafe fn find_map_calling_bpf_map_lookup_elem(call_inst: LLVMValueRef) -> Optio
     }
     // The first argument of `bpf_map_lookup_elem` is a pointer to map def
     let map_def = LLVMGetArgOperand(call_inst, 0);
-    match LLVMGetValueKind(map_def) {
-        LLVMValueKind::LLVMConstantExprValueKind => {}
-        _ => {
-            return None;
-        }
-    }
-    match LLVMGetConstOpcode(map_def) {
-        LLVMOpcode::LLVMGetElementPtr => {}
+    let map = match LLVMGetValueKind(map_def) {
+        // Get a map that is the container of the map definition
+        LLVMValueKind::LLVMConstantExprValueKind => match LLVMGetConstOpcode(map_def) {
+            LLVMOpcode::LLVMGetElementPtr => LLVMGetOperand(map_def, 0),
+            _ => {
+                return None;
+            }
+        },
+        // With opaque pointers, the map definition at offset 0 is referred
+        // to by the pointer to the map without a GEP
+        LLVMValueKind::LLVMGlobalVariableValueKind => map_def,
         _ => {
             return None;
         }
-    }
-    // Get a map that is the container of the map definition
-    let map = LLVMGetOperand(map_def, 0);
+    };
     match LLVMGetValueKind(map) {
         LLVMValueKind::LLVMGlobalVariableValueKind => {}
         _ => {
@@ -270,7 +387,11 @@ unsafe fn find_map_calling_bpf_map_lookup_elem(call_inst: LLVMValueRef) -> Optio
 /// Check if the alignment of value of map exceeds 8 bytes and `get` or
 /// `get_mut` method is called to create a reference of the possibly misaligned
 /// value data.
-unsafe fn check_map_value_alignment(_context: LLVMContextRef, module: LLVMModuleRef) -> Result<()> {
+unsafe fn check_map_value_alignment(
+    _context: LLVMContextRef,
+    module: LLVMModuleRef,
+    codegen: Codegen,
+) -> Result<()> {
     let mut get_called_maps = Vec::<(String, String, String)>::new(); // (map_variable_name, calling_function_name, get_method_name)
     let mut func = LLVMGetFirstFunction(module);
     while !func.is_null() {
@@ -284,7 +405,7 @@ unsafe fn check_map_value_alignment(_context: LLVMContextRef, module: LLVMModule
                     // find debugger intrinsics of `get` or `get_mut` methods
                     // of RedBPF maps such as
                     // `redbpf_probes::maps::HashMap<u64, example_probes::vfsreadlat::VFSEvent>::get_mut<u64, example_probes::vfsreadlat::VFSEvent>`
-                    if let Some(name) = find_redbpf_map_method_type_str(inst) {
+                    if let Some(name) = find_redbpf_map_method_type_str(inst, codegen) {
                         map_method_type_str = Some(name);
                     }
                     // inspect normal function call
@@ -295,8 +416,10 @@ unsafe fn check_map_value_alignment(_context: LLVMContextRef, module: LLVMModule
                             // Don't care the `get_val` method because it does
                             // not create any reference of misaligned value
                             // data.
-                            if method_name.contains("::get<") || method_name.contains("::get_mut<")
-                            {
+                            if ["get<", "get_mut<"].iter().any(|get| {
+                                method_name.starts_with(get)
+                                    || method_name.contains(&format!("::{}", get))
+                            }) {
                                 let mut len: usize = 0;
                                 let cname = LLVMGetValueName2(func, &mut len as *mut _);
                                 let calling_func_name = String::from_utf8_lossy(
@@ -374,38 +497,116 @@ unsafe fn check_map_value_alignment(_context: LLVMContextRef, module: LLVMModule
     Ok(())
 }
 
-pub unsafe fn compile(input: &Path, output: &Path, bc_output: Option<&Path>) -> Result<()> {
-    let context = LLVMGetGlobalContext();
-    let module = load_module(context, input)?;
-    check_map_value_alignment(context, module)?;
-    process_ir(context, module)?;
-    let ret = compile_module(module, output, bc_output);
-    LLVMDisposeModule(module);
+pub unsafe fn compile(
+    input: &Path,
+    output: &Path,
+    bc_output: Option<&Path>,
+    cpu: &str,
+    codegen: Codegen,
+    remarks: Option<&Path>,
+) -> Result<()> {
+    with_context(|context| {
+        let module = load_module(context, input)?;
+        let mut writer = match remarks {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| anyhow!("failed to create {:?}: {}", path, e))?;
+                Some(Box::new(RemarkWriter {
+                    file,
+                    error: None,
+                    diagnostics: String::new(),
+                    has_error: false,
+                }))
+            }
+            None => None,
+        };
+        if let Some(writer) = writer.as_mut() {
+            let writer: *mut RemarkWriter = &mut **writer;
+            LLVMContextSetDiagnosticHandler(context, Some(write_remark), writer as *mut c_void);
+        }
+        let ret = check_map_value_alignment(context, module, codegen)
+            .and_then(|_| process_ir(context, module))
+            .and_then(|_| compile_module(module, output, bc_output, cpu));
+        LLVMDisposeModule(module);
+
+        if let Some(writer) = writer {
+            LLVMContextSetDiagnosticHandler(context, None, ptr::null_mut());
+            let diagnostics = writer.diagnostics.trim_end();
+            // the diagnostics end up in `Error::Compile` like the ones of
+            // rustc, or are printed like warnings of rustc
+            match &ret {
+                Err(e) if !diagnostics.is_empty() => {
+                    return Err(anyhow!("{}\n{}", e, diagnostics));
+                }
+                Ok(_) if writer.has_error => {
+                    return Err(anyhow!("LLVM reported errors\n{}", diagnostics));
+                }
+                Ok(_) if !diagnostics.is_empty() => {
+                    let _ = writeln!(io::stderr(), "{}", diagnostics);
+                }
+                _ => {}
+            }
+            if let (Ok(_), Some(e)) = (&ret, writer.error) {
+                return Err(anyhow!("failed to write remarks: {}", e));
+            }
+        }
+        ret
+    })
+}
 
+/// Run `f` with a new LLVM context
+///
+/// The global context of LLVM is not thread-safe, so each compilation uses
+/// its own context to let probes be compiled by multiple threads. Modules
+/// left in the context are disposed of with it.
+unsafe fn with_context<T>(f: impl FnOnce(LLVMContextRef) -> Result<T>) -> Result<T> {
+    let context = LLVMContextCreate();
+    let ret = f(context);
+    LLVMContextDispose(context);
     ret
 }
 
+/// Generate ELF relocatable file from optimized bitcode
+///
+/// No optimization passes are run. This is used to re-generate `output` after
+/// the bitcode written by `compile` is processed by a post-compile hook.
+pub unsafe fn emit_object(input: &Path, output: &Path, cpu: &str) -> Result<()> {
+    with_context(|context| {
+        let module = load_module(context, input)?;
+        let ret = create_target_machine(cpu).and_then(|tm| {
+            LLVMSetModuleDataLayout(module, LLVMCreateTargetDataLayout(tm));
+            let ret = emit_object_file(tm, module, output);
+            LLVMDisposeTargetMachine(tm);
+            ret
+        });
+        LLVMDisposeModule(module);
+
+        ret
+    })
+}
+
 /// Get section names of functions
 ///
 /// Only functions that do not belong to the default .text section have
 /// particular section names. So thd default `.text` won't be included in the
 /// result vector.
 pub(crate) unsafe fn get_function_section_names(bc: &Path) -> Result<Vec<String>> {
-    let mut section_names = vec![];
-    let context = LLVMGetGlobalContext();
-    let module = load_module(context, bc)?;
-    let mut func = LLVMGetFirstFunction(module);
-    while !func.is_null() {
-        let secptr = LLVMGetSection(func);
-        if !secptr.is_null() {
-            let secname = CStr::from_ptr(secptr).to_string_lossy().into_owned();
-            section_names.push(secname);
+    with_context(|context| {
+        let mut section_names = vec![];
+        let module = load_module(context, bc)?;
+        let mut func = LLVMGetFirstFunction(module);
+        while !func.is_null() {
+            let secptr = LLVMGetSection(func);
+            if !secptr.is_null() {
+                let secname = CStr::from_ptr(secptr).to_string_lossy().into_owned();
+                section_names.push(secname);
+            }
+            func = LLVMGetNextFunction(func);
         }
-        func = LLVMGetNextFunction(func);
-    }
-    LLVMDisposeModule(module);
+        LLVMDisposeModule(module);
 
-    Ok(section_names)
+        Ok(section_names)
+    })
 }
 
 fn find_available_command<'a>(candidates: &[&'a str]) -> Option<&'a str> {
@@ -432,9 +633,14 @@ fn find_available_command<'a>(candidates: &[&'a str]) -> Option<&'a str> {
 /// cf) `llvm_sys::debuginfo::LLVMStripModuleDebugInfo` removes BTF sections so
 /// do not call it.
 ///
-/// .text section is also removed.
+/// .text section is also removed unless `keep_text` is true, i.e., there are
+/// BPF global functions.
 ///
-pub(crate) fn strip_unnecessary(target: &impl AsRef<Path>, delete_btf: bool) -> Result<()> {
+pub(crate) fn strip_unnecessary(
+    target: &impl AsRef<Path>,
+    delete_btf: bool,
+    keep_text: bool,
+) -> Result<()> {
     let cmd = find_available_command(&[
         "llvm-strip",
         "llvm-strip-13",
@@ -443,12 +649,14 @@ pub(crate) fn strip_unnecessary(target: &impl AsRef<Path>, delete_btf: bool) ->
     ])
     .ok_or_else(|| anyhow!("llvm-strip command not found"))?;
 
-    Command::new(cmd)
+    let status = Command::new(cmd)
         .arg("--strip-debug")
         .arg(target.as_ref())
         .status()
-        .map(|_| ())
         .or_else(|e| Err(anyhow!("llvm-strip --strip-debug failed: {}", e)))?;
+    if !status.success() {
+        return Err(anyhow!("llvm-strip --strip-debug failed: {}", status));
+    }
 
     let mut cmd = Command::new(cmd);
     if delete_btf {
@@ -458,12 +666,74 @@ pub(crate) fn strip_unnecessary(target: &impl AsRef<Path>, delete_btf: bool) ->
     // section is created with zero size as a result of compilation. So it is
     // needed to remove it explictly. The .text section can cause a problem if
     // the resulting ELF relocatable file is passed to tc command.
-    cmd.args("--remove-section .text".split(' '))
+    if !keep_text {
+        cmd.args("--remove-section .text".split(' '));
+    }
+    let status = cmd
+        .arg("--no-strip-all")
+        .arg(target.as_ref())
+        .status()
+        .or_else(|e| Err(anyhow!("llvm-strip --remove-section .text failed: {}", e)))?;
+    if !status.success() {
+        return Err(anyhow!(
+            "llvm-strip --remove-section .text failed: {}",
+            status
+        ));
+    }
+    Ok(())
+}
+
+/// The sections that are not needed to load ELF relocatable files
+///
+/// `.rel.BTF` and `.rel.BTF.ext` are not applied by the loader because it
+/// fixes up BTF by itself. The others are left by the compiler for linkers
+/// and debuggers.
+const UNNEEDED_SECTIONS: [&str; 7] = [
+    ".rel.BTF",
+    ".rel.BTF.ext",
+    ".llvm_addrsig",
+    ".comment",
+    ".note.GNU-stack",
+    ".eh_frame",
+    ".rel.eh_frame",
+];
+
+/// Strip every section that is not needed to load the ELF relocatable file
+///
+/// In addition to what `strip_unnecessary` removes, this removes the sections
+/// of `UNNEEDED_SECTIONS` and the temporary local symbols. `.BTF` and
+/// `.BTF.ext` are kept, except for `.BTF.ext` if `delete_btf` is true, to
+/// provide the verifier with line info. `.symtab` and the relocation
+/// sections of programs are kept too because the loader looks up maps and
+/// programs by symbols and relocates map references at load time.
+pub(crate) fn strip_for_size(
+    target: &impl AsRef<Path>,
+    delete_btf: bool,
+    keep_text: bool,
+) -> Result<()> {
+    strip_unnecessary(target, delete_btf, keep_text)?;
+    let cmd = find_available_command(&[
+        "llvm-strip",
+        "llvm-strip-13",
+        "llvm-strip-12",
+        "llvm-strip-11",
+    ])
+    .ok_or_else(|| anyhow!("llvm-strip command not found"))?;
+
+    let mut cmd = Command::new(cmd);
+    for section in UNNEEDED_SECTIONS.iter() {
+        cmd.arg("--remove-section").arg(section);
+    }
+    let status = cmd
+        .arg("--discard-locals")
         .arg("--no-strip-all")
         .arg(target.as_ref())
         .status()
-        .map(|_| ())
-        .or_else(|e| Err(anyhow!("llvm-strip --remove-section .text failed: {}", e)))
+        .or_else(|e| Err(anyhow!("llvm-strip --remove-section failed: {}", e)))?;
+    if !status.success() {
+        return Err(anyhow!("llvm-strip --remove-section failed: {}", status));
+    }
+    Ok(())
 }
 
 pub unsafe fn process_ir(context: LLVMContextRef, module: LLVMModuleRef) -> Result<()> {
@@ -475,6 +745,7 @@ pub unsafe fn process_ir(context: LLVMContextRef, module: LLVMModuleRef) -> Resu
     let always_inline_kind =
         LLVMGetEnumAttributeKindForName(always_inline.as_ptr(), "alwaysinline".len());
     let always_inline_attr = LLVMCreateEnumAttribute(context, always_inline_kind, 0);
+    let no_inline_attr = LLVMCreateEnumAttribute(context, no_inline_kind, 0);
 
     let mut func = LLVMGetFirstFunction(module);
     while !func.is_null() {
@@ -482,7 +753,12 @@ pub unsafe fn process_ir(context: LLVMContextRef, module: LLVMModuleRef) -> Resu
         let name = CStr::from_ptr(LLVMGetValueName2(func, &mut size as *mut _))
             .to_str()
             .unwrap();
-        if !name.starts_with("llvm.") {
+        if is_global_function(func) {
+            // BPF global functions are verified independently of their
+            // callers so they must not be inlined
+            LLVMRemoveEnumAttributeAtIndex(func, LLVMAttributeFunctionIndex, always_inline_kind);
+            LLVMAddAttributeAtIndex(func, LLVMAttributeFunctionIndex, no_inline_attr);
+        } else if !name.starts_with("llvm.") {
             // make sure everything gets inlined as BPF can't do calls to
             // things other than helpers
             LLVMRemoveEnumAttributeAtIndex(func, LLVMAttributeFunctionIndex, no_inline_kind);
@@ -499,10 +775,21 @@ pub unsafe fn process_ir(context: LLVMContextRef, module: LLVMModuleRef) -> Resu
     Ok(())
 }
 
-unsafe fn create_target_machine() -> Result<LLVMTargetMachineRef> {
+/// Return true if `func` is a BPF global function
+///
+/// BPF global functions are defined with the `#[global_function]` attribute
+/// that places them in `.text` section explicitly.
+unsafe fn is_global_function(func: LLVMValueRef) -> bool {
+    let secptr = LLVMGetSection(func);
+    LLVMIsDeclaration(func) == 0
+        && !secptr.is_null()
+        && CStr::from_ptr(secptr).to_bytes() == b".text"
+}
+
+unsafe fn create_target_machine(cpu: &str) -> Result<LLVMTargetMachineRef> {
     let mut error = ptr::null_mut();
     let triple = CString::new("bpf").unwrap();
-    let cpu = CString::new("generic").unwrap(); // see llc -march=bpf -mcpu=help
+    let cpu = CString::new(cpu).unwrap(); // see llc -march=bpf -mcpu=help
     let features = CString::new("").unwrap(); // see llc -march=bpf -mcpu=help
 
     let mut target = ptr::null_mut();
@@ -534,8 +821,9 @@ unsafe fn compile_module(
     module: LLVMModuleRef,
     output: &Path,
     bc_output: Option<&Path>,
+    cpu: &str,
 ) -> Result<()> {
-    let tm = create_target_machine()?;
+    let tm = create_target_machine(cpu)?;
     let data_layout = LLVMCreateTargetDataLayout(tm);
     LLVMSetModuleDataLayout(module, data_layout);
 
@@ -592,6 +880,21 @@ unsafe fn compile_module(
     }
 
     // emit the code
+    emit_object_file(tm, module, output)?;
+
+    LLVMPassManagerBuilderDispose(pmb);
+    LLVMDisposePassManager(fpm);
+    LLVMDisposePassManager(mpm);
+    LLVMDisposeTargetMachine(tm);
+
+    Ok(())
+}
+
+unsafe fn emit_object_file(
+    tm: LLVMTargetMachineRef,
+    module: LLVMModuleRef,
+    output: &Path,
+) -> Result<()> {
     let mut error = ptr::null_mut();
     let file_ptr = CString::new(output.to_str().unwrap()).unwrap().into_raw();
     let ret = LLVMTargetMachineEmitToFile(
@@ -609,11 +912,6 @@ unsafe fn compile_module(
         ));
     }
 
-    LLVMPassManagerBuilderDispose(pmb);
-    LLVMDisposePassManager(fpm);
-    LLVMDisposePassManager(mpm);
-    LLVMDisposeTargetMachine(tm);
-
     Ok(())
 }
 
@@ -624,3 +922,52 @@ unsafe fn error_str(ptr: *mut c_char) -> String {
         CStr::from_ptr(ptr).to_string_lossy().into_owned()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    const PROBE_IR: &str = r#"
+target triple = "bpf"
+
+define internal i32 @add_one(i32 %n) {
+entry:
+  %sum = add i32 %n, 1
+  ret i32 %sum
+}
+
+define i32 @prog(i32 %n) section "kprobe/prog" {
+entry:
+  %sum = call i32 @add_one(i32 %n)
+  ret i32 %sum
+}
+"#;
+
+    #[test]
+    fn test_compile_remarks() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("probe.ll");
+        fs::write(&input, PROBE_IR).unwrap();
+        let output = dir.path().join("probe.elf");
+        let remarks = dir.path().join("probe.remarks.yaml");
+        let codegen = Codegen {
+            opaque_pointers: false,
+        };
+        unsafe {
+            init();
+            // every build enables remarks but they are parsed only once
+            enable_remarks();
+            enable_remarks();
+            compile(&input, &output, None, "generic", codegen, Some(&remarks)).unwrap();
+        }
+        assert!(output.exists());
+        // at least the remark of inlining `add_one` into `prog`
+        let remarks = fs::read_to_string(&remarks).unwrap();
+        assert!(remarks.starts_with("--- !Remark\nMessage: "), "{}", remarks);
+        assert!(remarks.contains("add_one"), "{}", remarks);
+
+        assert_eq!(yaml_string("a \"b\"\n"), r#""a \"b\"\n""#);
+    }
+}
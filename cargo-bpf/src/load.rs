@@ -5,85 +5,299 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use crate::CommandError;
+//! Loading a probe and tailing its events
+//!
+//! `cargo bpf load` loads every program of a built ELF file, attaches
+//! kprobes, tracepoints, raw tracepoints, uprobes and XDP programs, and
+//! prints the events of all perf maps until Ctrl-C is pressed. The programs
+//! are detached before the command exits.
+use std::ffi::CString;
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
 
-use futures::{future, stream::StreamExt};
+use futures::stream::StreamExt;
+use goblin::elf::Elf;
 use hexdump::hexdump;
-use redbpf::xdp;
+use redbpf::xdp::XdpFlags;
 use redbpf::{load::Loader, Program::*};
-use std::path::PathBuf;
 use tokio::runtime;
 use tokio::signal;
 
-pub fn load(
-    program: &PathBuf,
-    interface: Option<&str>,
-    uprobe_path: Option<&str>,
-    pid: Option<i32>,
-) -> Result<(), CommandError> {
+use crate::CommandError;
+
+pub struct LoadOptions {
+    /// The ELF file built by `cargo bpf build`
+    pub program: PathBuf,
+    /// The interface to attach XDP programs to
+    pub interface: Option<String>,
+    /// The library or binary to attach uprobes to
+    pub uprobe_path: Option<String>,
+    /// The process to attach uprobes to, all processes if `None`
+    pub pid: Option<i32>,
+    pub format: EventFormat,
+}
+
+/// How the events of perf maps are printed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventFormat {
+    /// A hexdump of each event
+    Hex,
+    /// Each event as a line of UTF-8 text
+    Utf8,
+}
+
+impl FromStr for EventFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(EventFormat::Hex),
+            "utf8" => Ok(EventFormat::Utf8),
+            _ => Err(format!("invalid event format: `{}'", s)),
+        }
+    }
+}
+
+/// Parse the PID that uprobes are attached to
+pub fn parse_pid(pid: &str) -> Result<i32, String> {
+    match pid.parse::<i32>() {
+        Ok(pid) if pid > 0 => Ok(pid),
+        _ => Err(format!("invalid PID: `{}'", pid)),
+    }
+}
+
+pub fn load(opts: &LoadOptions) -> Result<(), CommandError> {
+    let bytes = fs::read(&opts.program)
+        .map_err(|e| CommandError(format!("failed to read {:?}: {}", opts.program, e)))?;
+    let binary = Elf::parse(&bytes)
+        .map_err(|e| CommandError(format!("failed to parse {:?}: {}", opts.program, e)))?;
+    let section_names: Vec<&str> = binary
+        .section_headers
+        .iter()
+        .filter_map(|shdr| binary.shdr_strtab.get_at(shdr.sh_name))
+        .collect();
+    check_options(&section_names, opts)?;
+
     let rt = runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap();
-    rt.block_on(async {
-        // Load all the programs and maps included in the program
-        let mut loader = Loader::load_file(&program).expect("error loading file");
-
-        // attach the programs
-        for program in loader.module.programs.iter_mut() {
-            let name = program.name().to_string();
-            let ret = match program {
-                XDP(prog) => {
-                    let iface = match interface {
-                        Some(i) => i,
-                        None => {
-                            return Err(CommandError(
-                                "XDP program found, but no interface specified".to_string(),
-                            ))
-                        }
-                    };
-                    prog.attach_xdp(&iface, xdp::Flags::default())
-                }
-                KProbe(prog) | KRetProbe(prog) => prog.attach_kprobe(&name, 0),
-                UProbe(prog) | URetProbe(prog) => {
-                    let path = match uprobe_path {
-                        Some(p) => p,
-                        None => {
-                            return Err(CommandError(
-                                "uprobe program found, but no path specified".to_string(),
-                            ))
-                        }
-                    };
-                    prog.attach_uprobe(Some(&prog.name()), 0, path, pid)
+    rt.block_on(run(opts))
+}
+
+// Check the options needed to attach the programs of the sections before
+// anything is loaded
+fn check_options(section_names: &[&str], opts: &LoadOptions) -> Result<(), CommandError> {
+    let has_xdp = section_names.iter().any(|name| name.starts_with("xdp/"));
+    match opts.interface.as_deref() {
+        Some(interface) => check_interface(interface)?,
+        None if has_xdp => {
+            return Err(CommandError(
+                "XDP program found, but no interface specified".to_string(),
+            ))
+        }
+        None => {}
+    }
+    let has_uprobe = section_names
+        .iter()
+        .any(|name| name.starts_with("uprobe/") || name.starts_with("uretprobe/"));
+    if has_uprobe && opts.uprobe_path.is_none() {
+        return Err(CommandError(
+            "uprobe program found, but no path specified".to_string(),
+        ));
+    }
+    let invalid_tracepoint = section_names
+        .iter()
+        .find(|name| is_invalid_tracepoint_section(name));
+    if let Some(name) = invalid_tracepoint {
+        return Err(CommandError(format!(
+            "tracepoint section `{}' is not `tracepoint/<category>/<name>'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+// The tracepoint to attach to is given by the section name
+// `tracepoint/<category>/<name>`
+fn is_invalid_tracepoint_section(name: &str) -> bool {
+    match name.strip_prefix("tracepoint/") {
+        Some(tracepoint) => {
+            tracepoint.split('/').map(str::is_empty).collect::<Vec<_>>() != [false, false]
+        }
+        None => false,
+    }
+}
+
+fn check_interface(interface: &str) -> Result<(), CommandError> {
+    let ciface = CString::new(interface)
+        .map_err(|_| CommandError(format!("invalid interface name: `{}'", interface)))?;
+    if unsafe { libc::if_nametoindex(ciface.as_ptr()) } == 0 {
+        return Err(CommandError(format!("interface `{}' not found", interface)));
+    }
+    Ok(())
+}
+
+async fn run(opts: &LoadOptions) -> Result<(), CommandError> {
+    // Load all the programs and maps included in the program
+    let mut loader = Loader::load_file(&opts.program)
+        .map_err(|e| CommandError(format!("failed to load {:?}: {:?}", opts.program, e)))?;
+
+    // attach the programs
+    let mut links = vec![];
+    for program in loader.module.programs.iter_mut() {
+        let name = program.name().to_string();
+        let ret = match program {
+            XDP(prog) => {
+                // checked by check_options
+                let interface = opts.interface.as_deref().unwrap();
+                prog.attach_xdp_link(interface, XdpFlags::Default)
+                    .map(|link| links.push(link))
+            }
+            KProbe(prog) | KRetProbe(prog) => prog
+                .attach_kprobe_link(&name, 0)
+                .map(|link| links.push(link)),
+            TracePoint(prog) => match prog.tracepoint() {
+                Some((category, tracepoint)) => prog
+                    .attach_trace_point_link(category, tracepoint)
+                    .map(|link| links.push(link)),
+                None => {
+                    return Err(CommandError(format!(
+                        "failed to attach program {}: the tracepoint is unknown, use a \
+                         `tracepoint/<category>/<name>' section",
+                        name
+                    )))
                 }
-                _ => Ok(()),
-            };
-            if let Err(e) = ret {
-                return Err(CommandError(format!(
-                    "failed to attach program {}: {:?}",
-                    name, e
-                )));
+            },
+            RawTracePoint(prog) => match prog.tracepoint().map(String::from) {
+                Some(tracepoint) => prog
+                    .attach_raw_tracepoint_link(&tracepoint)
+                    .map(|link| links.push(link)),
+                None => Ok(()),
+            },
+            UProbe(prog) | URetProbe(prog) => {
+                let path = opts.uprobe_path.as_deref().unwrap();
+                prog.attach_uprobe(Some(&name), 0, path, opts.pid)
             }
+            _ => Ok(()),
+        };
+        if let Err(e) = ret {
+            return Err(CommandError(format!(
+                "failed to attach program {}: {:?}",
+                name, e
+            )));
         }
+    }
 
-        // dump all the generated events on stdout
-        tokio::spawn(async move {
-            while let Some((name, events)) = loader.events.next().await {
+    // dump all the generated events on stdout until Ctrl-C. If the program
+    // doesn't have any perf maps, the stream never yields
+    let format = opts.format;
+    let stream = &mut loader.events;
+    tokio::select! {
+        _ = async {
+            while let Some((name, events)) = stream.next().await {
                 for event in events {
-                    println!("-- Event: {} --", name);
-                    hexdump(&event);
+                    print_event(&name, &event, format);
                 }
             }
+            futures::future::pending::<()>().await
+        } => {}
+        _ = signal::ctrl_c() => {}
+    }
+    println!("exiting");
+
+    // detach the programs before they are unloaded
+    drop(links);
+    Ok(())
+}
+
+fn print_event(map: &str, event: &[u8], format: EventFormat) {
+    match format {
+        EventFormat::Hex => {
+            println!("-- Event: {} --", map);
+            hexdump(event);
+        }
+        EventFormat::Utf8 => println!("{}: {}", map, utf8_line(event)),
+    }
+}
+
+// Events are usually fixed size buffers, so the text ends at the first NUL
+fn utf8_line(event: &[u8]) -> String {
+    let end = event.iter().position(|b| *b == 0).unwrap_or(event.len());
+    String::from_utf8_lossy(&event[..end])
+        .trim_end_matches('\n')
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn options(interface: Option<&str>, uprobe_path: Option<&str>) -> LoadOptions {
+        LoadOptions {
+            program: PathBuf::from("probe.elf"),
+            interface: interface.map(String::from),
+            uprobe_path: uprobe_path.map(String::from),
+            pid: None,
+            format: EventFormat::Hex,
+        }
+    }
+
+    #[test]
+    fn test_parse_arguments() {
+        assert_eq!("hex".parse::<EventFormat>(), Ok(EventFormat::Hex));
+        assert_eq!("utf8".parse::<EventFormat>(), Ok(EventFormat::Utf8));
+        assert!("text".parse::<EventFormat>().is_err());
+        assert_eq!(parse_pid("1234"), Ok(1234));
+        assert!(parse_pid("0").is_err());
+        assert!(parse_pid("-1").is_err());
+        assert!(parse_pid("init").is_err());
+    }
+
+    #[test]
+    fn test_check_interface() {
+        let sections = ["xdp/block_http", "maps/EVENTS"];
+        let e = check_options(&sections, &options(None, None))
+            .err()
+            .unwrap();
+        assert!(e.0.contains("no interface"), "{}", e.0);
+        assert!(check_options(&sections, &options(Some("lo"), None)).is_ok());
+        let e = check_options(&sections, &options(Some("redbpf-none0"), None))
+            .err()
+            .unwrap();
+        assert!(e.0.contains("`redbpf-none0' not found"), "{}", e.0);
+        assert!(check_options(&sections, &options(Some("lo\0"), None)).is_err());
+
+        // the interface is only required by XDP programs
+        assert!(check_options(&["kprobe/do_sys_open"], &options(None, None)).is_ok());
+        assert!(check_options(&["uprobe/getaddrinfo"], &options(None, None)).is_err());
+        assert!(check_options(
+            &["uprobe/getaddrinfo"],
+            &options(None, Some("/lib/libc.so.6"))
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_check_tracepoint() {
+        let tracepoint = ["tracepoint/syscalls/sys_enter_openat"];
+        assert!(check_options(&tracepoint, &options(None, None)).is_ok());
+        for name in &[
+            "tracepoint/sys_enter_openat",
+            "tracepoint/syscalls/",
+            "tracepoint//sys_enter_openat",
+            "tracepoint/syscalls/sys_enter_openat/extra",
+        ] {
+            let e = check_options(&[name], &options(None, None)).err().unwrap();
+            assert!(e.0.contains("tracepoint/<category>/<name>"), "{}", e.0);
+        }
+    }
 
-            // If the program doesn't have any maps and therefore doesn't fire any events, we still
-            // need to keep `loader` alive here so that BPF programs are not dropped. The future
-            // below will never complete, meaning that the programs will keep running until Ctrl-C
-            future::pending::<()>().await;
-        });
-
-        // quit on SIGINT
-        let _ = signal::ctrl_c().await;
-        println!("exiting");
-        Ok(())
-    })
+    #[test]
+    fn test_utf8_line() {
+        assert_eq!(utf8_line(b"open /etc/passwd\n\0\0\0"), "open /etc/passwd");
+        assert_eq!(utf8_line(b"no nul"), "no nul");
+        assert_eq!(utf8_line(b"\xffbad"), "\u{fffd}bad");
+    }
 }
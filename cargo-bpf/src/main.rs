@@ -117,6 +117,14 @@ $ cargo bpf build block_http
 by `redbpf::Module` and will place it in
 `target/bpf/programs/block_http.elf`.
 
+In a Cargo workspace, `cargo bpf build` can be run at the root of the
+workspace. The programs of the only member with `[[bin]]` targets are built,
+or of the member selected with `--package`:
+
+```
+$ cargo bpf build --package probes block_http
+```
+
 # Loading a program during development
 
 `cargo bpf` includes a simple `load` subcommand that can be used during
@@ -130,11 +138,51 @@ Loading eBPF programs requires admin priviledges, so you'll have to run
 $ sudo cargo bpf load -i eth0 target/bpf/programs/block_http.elf
 ```
 
+Every program is loaded, and kprobes, tracepoints of
+`tracepoint/<category>/<name>` sections, raw tracepoints, uprobes and XDP
+programs are attached. The events of perf maps are printed as hexdumps, or as
+lines of text with `--format utf8`, until Ctrl-C is pressed, and the programs
+are detached before `load` exits.
+
+# Measuring map throughput
+
+`cargo bpf bench` attaches a program and counts the events it sends to a perf
+map, or samples the `u64` counter at index 0 of an array map, for a fixed
+duration while you run a workload. It prints the events per second, which
+helps to compare map designs:
+
+```
+$ sudo cargo bpf bench -i eth0 -d 30s target/bpf/programs/block_http/block_http.elf block_http EVENTS
+```
+
+# Inspecting BTF
+
+`cargo bpf btf dump` pretty-prints the types in the `.BTF` section of a built
+program, with the members, offsets and sizes of structures. It helps to debug
+map layouts and BTF related load failures:
+
+```
+$ cargo bpf btf dump --probe block_http
+```
+
+Pass `-o FILE` to also write the raw `.BTF` section to `FILE`.
+
+# Generating a skeleton
+
+`cargo bpf skeleton` generates a Rust module from the maps and programs of a
+built program. Its `Skeleton` borrows each map of a loaded module as a typed
+map, e.g., `HashMap<'a, Connection, u64>` with the key and value types derived
+from BTF, and each program as its concrete type, e.g., `&'a mut KProbe`:
+
+```
+$ cargo bpf skeleton --probe block_http -o src/skeleton.rs
+```
+
 */
 use clap::{self, crate_authors, crate_version, App, AppSettings, Arg, SubCommand};
 use std::path::PathBuf;
 
-use cargo_bpf::BuildOptions;
+use cargo_bpf::{BenchOptions, BuildOptions, LoadOptions, MessageFormat};
 use cargo_bpf_lib as cargo_bpf;
 
 fn main() {
@@ -189,8 +237,41 @@ fn main() {
                             .arg(Arg::with_name("FORCE_LOOP_UNROLL").long("force-loop-unroll").help(
                                 "Ensure every loop is unrolled"
                             ))
+                            .arg(Arg::with_name("CPU_VERSION").value_name("N").long("cpu-version").possible_values(&["1", "2", "3", "4"]).help(
+                                "The eBPF instruction set version to target (-mcpu=vN)"
+                            ))
+                            .arg(Arg::with_name("OPT_LEVEL").value_name("N").long("opt-level").possible_values(&["0", "1", "2", "3"]).help(
+                                "The optimization level of rustc (-C opt-level=N). Defaults to 3"
+                            ))
+                            .arg(Arg::with_name("EMIT_REMARK_MESSAGES").long("emit-remark-messages").help(
+                                "Write the messages of the optimization remarks of LLVM, e.g., of loops not unrolled, to <program>.remarks.yaml"
+                            ))
+                            .arg(Arg::with_name("STRIP_FOR_SIZE").long("strip-for-size").help(
+                                "Strip every section not needed to load the programs, keeping BTF"
+                            ))
+                            .arg(Arg::with_name("STRICT_STRIP").long("strict-strip").help(
+                                "Fail if stripping the debug sections of the programs fails"
+                            ))
+                            .arg(Arg::with_name("KERNEL_VERSION").value_name("X.Y").long("kernel-version").help(
+                                "The version of the kernel to build the programs for. Defaults to the version of the kernel headers"
+                            ))
+                            .arg(Arg::with_name("JOBS").value_name("N").short("j").long("jobs").help(
+                                "The number of programs built at the same time. Defaults to the number of CPUs"
+                            ))
+                            .arg(Arg::with_name("FORCE").long("force").help(
+                                "Build every program even if nothing changed since the last build"
+                            ))
+                            .arg(Arg::with_name("VERIFY").long("verify").help(
+                                "Load the built programs on the running kernel to check that the verifier accepts them. Requires privilege"
+                            ))
+                            .arg(Arg::with_name("PACKAGE").value_name("SPEC").short("p").long("package").help(
+                                "The package of the workspace to build the programs of. Defaults to the only member with [[bin]] targets"
+                            ))
+                            .arg(Arg::with_name("MESSAGE_FORMAT").value_name("FMT").long("message-format").possible_values(&["human", "json"]).default_value("human").help(
+                                "The format of the result of building each program. `json' prints a JSON object per line"
+                            ))
                             .arg(Arg::with_name("NAME").required(false).multiple(true).help(
-                                "The names of the programs to compile. When no names are specified, all the programs are built",
+                                "The names of the programs to compile, or glob patterns such as `xdp_*'. When no names are specified, all the programs are built",
                             ))
                     )
                     .subcommand(
@@ -205,9 +286,73 @@ fn main() {
                             .arg(Arg::with_name("PID").value_name("PID").short("p").long("pid").help(
                                 "Attach uprobes to the given PID"
                             ))
+                            .arg(Arg::with_name("FORMAT").value_name("FORMAT").short("f").long("format").possible_values(&["hex", "utf8"]).default_value("hex").help(
+                                "Print the events of perf maps as hexdumps or as lines of UTF-8 text"
+                            ))
                             .arg(Arg::with_name("PROGRAM").required(true).help(
                                 "Loads the specified eBPF program and outputs all the events generated",
                             ))
+                    )
+                    .subcommand(
+                        SubCommand::with_name("bench")
+                            .about("Measures the events per second that an eBPF program puts in a map")
+                            .arg(Arg::with_name("INTERFACE").value_name("INTERFACE").short("i").long("interface").help(
+                                "Binds XDP programs to the given interface"
+                            ))
+                            .arg(Arg::with_name("DURATION").value_name("DURATION").short("d").long("duration").default_value("10s").help(
+                                "How long to sample the map, e.g., 10s, 500ms or 2m"
+                            ))
+                            .arg(Arg::with_name("DRY_RUN").long("dry-run").help(
+                                "Checks that the ELF file has the program and the map without loading it"
+                            ))
+                            .arg(Arg::with_name("ELF").required(true).help(
+                                "The ELF file of the eBPF program"
+                            ))
+                            .arg(Arg::with_name("PROGRAM").required(true).help(
+                                "The name of the program to attach"
+                            ))
+                            .arg(Arg::with_name("MAP").required(true).help(
+                                "The name of the perf map, or the array map whose u64 counter at index 0 is sampled"
+                            ))
+                    )
+                    .subcommand(
+                        SubCommand::with_name("btf")
+                            .about("Inspects the BTF of eBPF programs")
+                            .settings(&[
+                                AppSettings::SubcommandRequiredElseHelp
+                            ])
+                            .subcommand(
+                                SubCommand::with_name("dump")
+                                    .about("Pretty-prints the .BTF section of a built eBPF program")
+                                    .arg(Arg::with_name("PROBE").value_name("NAME").long("probe").help(
+                                        "The name of the program built by `cargo bpf build`"
+                                    ))
+                                    .arg(Arg::with_name("TARGET_DIR").value_name("DIRECTORY").long("target-dir").help(
+                                        "Directory for all generated artifacts"
+                                    ))
+                                    .arg(Arg::with_name("OUTPUT").value_name("FILE").short("o").long("output").help(
+                                        "Writes the raw .BTF section to the given file"
+                                    ))
+                                    .arg(Arg::with_name("PROGRAM").required_unless("PROBE").conflicts_with("PROBE").help(
+                                        "The ELF file of the eBPF program"
+                                    ))
+                            )
+                    )
+                    .subcommand(
+                        SubCommand::with_name("skeleton")
+                            .about("Generates typed accessors of the maps and programs of an eBPF program")
+                            .arg(Arg::with_name("PROBE").value_name("NAME").long("probe").help(
+                                "The name of the program built by `cargo bpf build`"
+                            ))
+                            .arg(Arg::with_name("TARGET_DIR").value_name("DIRECTORY").long("target-dir").help(
+                                "Directory for all generated artifacts"
+                            ))
+                            .arg(Arg::with_name("OUTPUT").value_name("FILE").short("o").long("output").help(
+                                "Writes the generated module to the given file instead of stdout"
+                            ))
+                            .arg(Arg::with_name("PROGRAM").required_unless("PROBE").conflicts_with("PROBE").help(
+                                "The ELF file of the eBPF program"
+                            ))
                     ),
             )
             .get_matches();
@@ -240,6 +385,39 @@ fn main() {
             buildopt.target_dir = PathBuf::from(v);
         }
         buildopt.force_loop_unroll = m.is_present("FORCE_LOOP_UNROLL");
+        buildopt.cpu_version = m.value_of("CPU_VERSION").map(|v| v.parse().unwrap());
+        if let Some(v) = m.value_of("OPT_LEVEL") {
+            buildopt.opt_level = v.parse().unwrap();
+        }
+        buildopt.emit_remark_messages = m.is_present("EMIT_REMARK_MESSAGES");
+        buildopt.strip_for_size = m.is_present("STRIP_FOR_SIZE");
+        buildopt.strict_strip = m.is_present("STRICT_STRIP");
+        buildopt.verify = m.is_present("VERIFY");
+        buildopt.force = m.is_present("FORCE");
+        buildopt.package = m.value_of("PACKAGE").map(String::from);
+        if m.value_of("MESSAGE_FORMAT") == Some("json") {
+            buildopt.message_format = MessageFormat::Json;
+        }
+        if let Some(v) = m.value_of("KERNEL_VERSION") {
+            let mut parts = v.splitn(2, '.').map(|n| n.parse::<u32>());
+            buildopt.kernel_version = match (parts.next(), parts.next()) {
+                (Some(Ok(version)), Some(Ok(patchlevel))) => Some((version, patchlevel)),
+                _ => clap::Error::with_description(
+                    "the kernel version must be in the form of X.Y, e.g., 6.1",
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit(),
+            };
+        }
+        if let Some(v) = m.value_of("JOBS") {
+            buildopt.jobs = v.parse().unwrap_or_else(|_| {
+                clap::Error::with_description(
+                    "the number of jobs must be a positive integer",
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+        }
         let programs = m
             .values_of("NAME")
             .map(|i| i.map(String::from).collect())
@@ -249,11 +427,70 @@ fn main() {
         }
     }
     if let Some(m) = matches.subcommand_matches("load") {
-        let program = m.value_of("PROGRAM").map(PathBuf::from).unwrap();
-        let interface = m.value_of("INTERFACE");
-        let uprobe_path = m.value_of("UPROBE_PATH");
-        let uprobe_pid = m.value_of("PID").map(|p| p.parse::<i32>().unwrap());
-        if let Err(e) = cargo_bpf::load(&program, interface, uprobe_path, uprobe_pid) {
+        let pid = m.value_of("PID").map(|pid| {
+            cargo_bpf::parse_pid(pid).unwrap_or_else(|e| {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+            })
+        });
+        let opts = LoadOptions {
+            program: m.value_of("PROGRAM").map(PathBuf::from).unwrap(),
+            interface: m.value_of("INTERFACE").map(String::from),
+            uprobe_path: m.value_of("UPROBE_PATH").map(String::from),
+            pid,
+            format: m.value_of("FORMAT").unwrap().parse().unwrap(),
+        };
+        if let Err(e) = cargo_bpf::load(&opts) {
+            clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("bench") {
+        let duration =
+            cargo_bpf::parse_duration(m.value_of("DURATION").unwrap()).unwrap_or_else(|e| {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+            });
+        let opts = BenchOptions {
+            elf: m.value_of("ELF").map(PathBuf::from).unwrap(),
+            program: m.value_of("PROGRAM").unwrap().to_string(),
+            map: m.value_of("MAP").unwrap().to_string(),
+            duration,
+            interface: m.value_of("INTERFACE").map(String::from),
+            dry_run: m.is_present("DRY_RUN"),
+        };
+        if let Err(e) = cargo_bpf::bench(&opts) {
+            clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("btf") {
+        if let Some(m) = m.subcommand_matches("dump") {
+            let program = match m.value_of("PROBE") {
+                Some(probe) => {
+                    let target_dir = m
+                        .value_of("TARGET_DIR")
+                        .map(PathBuf::from)
+                        .unwrap_or_else(|| BuildOptions::default().target_dir);
+                    cargo_bpf::probe_elf_path(&target_dir, probe)
+                }
+                None => m.value_of("PROGRAM").map(PathBuf::from).unwrap(),
+            };
+            let output = m.value_of("OUTPUT").map(PathBuf::from);
+            if let Err(e) = cargo_bpf::btf_dump(&program, output.as_deref()) {
+                clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
+            }
+        }
+    }
+    if let Some(m) = matches.subcommand_matches("skeleton") {
+        let program = match m.value_of("PROBE") {
+            Some(probe) => {
+                let target_dir = m
+                    .value_of("TARGET_DIR")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| BuildOptions::default().target_dir);
+                cargo_bpf::probe_elf_path(&target_dir, probe)
+            }
+            None => m.value_of("PROGRAM").map(PathBuf::from).unwrap(),
+        };
+        let output = m.value_of("OUTPUT").map(PathBuf::from);
+        if let Err(e) = cargo_bpf::skeleton(&program, output.as_deref()) {
             clap::Error::with_description(&e.0, clap::ErrorKind::InvalidValue).exit()
         }
     }
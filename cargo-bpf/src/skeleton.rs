@@ -0,0 +1,619 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Generating typed accessors of the maps and programs of a probe
+//!
+//! `cargo bpf skeleton` reads the maps and programs of a built ELF file and
+//! generates a Rust module with a `Skeleton` structure. It borrows every map
+//! of a parsed module as its typed wrapper, e.g., `HashMap<'a, K, V>`, and
+//! every program as its concrete type, e.g., `&'a mut KProbe`, so that
+//! userspace code does not look maps and programs up by name. The key and
+//! value types of maps are derived from BTF, see
+//! [`redbpf::btf::map_rust_types`].
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+use goblin::elf::Elf;
+use redbpf::btf::{self, RustTypes};
+use redbpf::ProgramType;
+
+use crate::CommandError;
+
+// The variants of `redbpf::Program` that programs of `program_type` are
+// parsed to and the type the variants hold
+fn program_variants(program_type: ProgramType) -> (&'static [&'static str], &'static str) {
+    use ProgramType::*;
+
+    match program_type {
+        KProbe | KRetProbe => (&["KProbe", "KRetProbe"], "KProbe"),
+        UProbe | URetProbe => (&["UProbe", "URetProbe"], "UProbe"),
+        SocketFilter => (&["SocketFilter"], "SocketFilter"),
+        TracePoint => (&["TracePoint"], "TracePoint"),
+        XDP => (&["XDP"], "XDP"),
+        StreamParser => (&["StreamParser"], "StreamParser"),
+        StreamVerdict => (&["StreamVerdict"], "StreamVerdict"),
+        SkLookup => (&["SkLookup"], "SkLookup"),
+        SockOps => (&["SockOps"], "SockOps"),
+        CgroupSkb => (&["CgroupSkb"], "CgroupSkb"),
+        CgroupSock => (&["CgroupSock"], "CgroupSock"),
+        SkReuseport => (&["SkReuseport"], "SkReuseport"),
+        RawTracePoint => (&["RawTracePoint"], "RawTracePoint"),
+        PerfEvent => (&["PerfEvent"], "PerfEvent"),
+    }
+}
+
+// The kinds of sections of programs that are attached by BTF, which have no
+// `ProgramType`
+const BTF_PROGRAM_KINDS: &[(&str, &[&str], &str)] = &[
+    ("fentry", &["FEntry"], "FEntry"),
+    ("fexit", &["FExit"], "FEntry"),
+    ("lsm", &["Lsm"], "Lsm"),
+    ("task_iter", &["TaskIter"], "TaskIter"),
+    ("struct_ops", &["StructOps"], "StructOps"),
+];
+
+// The variants and the type of programs in sections of `kind`
+fn program_kind(kind: &str) -> Option<(&'static [&'static str], &'static str)> {
+    ProgramType::ALL
+        .iter()
+        .find(|program_type| program_type.section_kind() == kind)
+        .map(|program_type| program_variants(*program_type))
+        .or_else(|| {
+            BTF_PROGRAM_KINDS
+                .iter()
+                .find(|(k, ..)| *k == kind)
+                .map(|(_, variants, type_name)| (*variants, *type_name))
+        })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MapSection {
+    name: String,
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ProgramSection {
+    /// The name of the program once it is parsed by redbpf
+    name: String,
+    variants: &'static [&'static str],
+    type_name: &'static str,
+}
+
+/// Generate the skeleton of the ELF file `program`
+///
+/// The Rust module is written to `output`, or printed if `output` is
+/// `None`.
+pub fn skeleton(program: &Path, output: Option<&Path>) -> Result<(), CommandError> {
+    let bytes = fs::read(program)
+        .map_err(|e| CommandError(format!("failed to read {:?}: {}", program, e)))?;
+    let source = program
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let code = generate(&bytes, &source)?;
+    match output {
+        Some(output) => fs::write(output, code)?,
+        None => print!("{}", code),
+    }
+    Ok(())
+}
+
+fn generate(bytes: &[u8], source: &str) -> Result<String, CommandError> {
+    let object = Elf::parse(bytes)
+        .map_err(|e| CommandError(format!("failed to parse {}: {}", source, e)))?;
+    let (maps, programs) = parse_sections(&object, bytes)?;
+    // probes built without BTF get byte arrays of the sizes of the map
+    // definitions
+    let has_btf = section_names(&object).any(|(name, _)| name == ".BTF");
+    let types = if has_btf {
+        btf::map_rust_types(bytes)
+            .map_err(|e| CommandError(format!("failed to parse BTF of {}: {:?}", source, e)))?
+    } else {
+        RustTypes::default()
+    };
+
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "// Generated by `cargo bpf skeleton` from {}. Do not edit.",
+        source
+    );
+    for definition in types.definitions.iter() {
+        let _ = write!(out, "\n{}", definition);
+    }
+    write_maps(&mut out, &maps, &types);
+    write_programs(&mut out, &programs);
+    let _ = write!(
+        out,
+        r#"
+pub struct Skeleton<'a> {{
+    pub maps: Maps<'a>,
+    pub programs: Programs<'a>,
+}}
+
+impl<'a> Skeleton<'a> {{
+    pub fn new(module: &'a mut redbpf::Module) -> redbpf::Result<Skeleton<'a>> {{
+        Ok(Skeleton {{
+            maps: Maps::new(&module.maps)?,
+            programs: Programs::new(&mut module.programs)?,
+        }})
+    }}
+}}
+"#
+    );
+    Ok(out)
+}
+
+fn section_names<'o>(object: &'o Elf) -> impl Iterator<Item = (&'o str, usize)> + 'o {
+    object
+        .section_headers
+        .iter()
+        .enumerate()
+        .filter_map(move |(shndx, shdr)| {
+            object
+                .shdr_strtab
+                .get_at(shdr.sh_name)
+                .map(|name| (name, shndx))
+        })
+}
+
+fn parse_sections(
+    object: &Elf,
+    bytes: &[u8],
+) -> Result<(Vec<MapSection>, Vec<ProgramSection>), CommandError> {
+    let mut maps = Vec::new();
+    let mut programs = Vec::new();
+    for (section, shndx) in section_names(object) {
+        let shdr = &object.section_headers[shndx];
+        if section == "maps" {
+            // the maps section of C programs holds every map definition and
+            // the maps are named by their symbols
+            for sym in object.syms.iter().filter(|sym| sym.st_shndx == shndx) {
+                let name = object.strtab.get_at(sym.st_name).unwrap_or_default();
+                let offset = shdr.sh_offset as usize + sym.st_value as usize;
+                maps.push(map_section(name, bytes, offset, section)?);
+            }
+            continue;
+        }
+        let (kind, name) = match section.split_once('/') {
+            Some(kind_name) => kind_name,
+            None => continue,
+        };
+        if kind == "maps" {
+            maps.push(map_section(name, bytes, shdr.sh_offset as usize, section)?);
+            continue;
+        }
+
+        if let Some((kind, name)) = program_section(kind, name) {
+            if let Some((variants, type_name)) = program_kind(kind) {
+                programs.push(ProgramSection {
+                    name,
+                    variants,
+                    type_name,
+                });
+            }
+        }
+    }
+    maps.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((maps, programs))
+}
+
+fn map_section(
+    name: &str,
+    bytes: &[u8],
+    offset: usize,
+    section: &str,
+) -> Result<MapSection, CommandError> {
+    let def = bytes
+        .get(offset..offset + 3 * 4)
+        .ok_or_else(|| CommandError(format!("invalid map `{}' in section `{}'", name, section)))?;
+    let field = |i: usize| u32::from_ne_bytes(def[i * 4..i * 4 + 4].try_into().unwrap());
+    Ok(MapSection {
+        name: name.to_string(),
+        map_type: field(0),
+        key_size: field(1),
+        value_size: field(2),
+    })
+}
+
+// Return the kind of the program in the section `<kind>/<name>` and the name
+// redbpf gives it
+//
+// The name of tracepoint programs is <category>/<tracepoint>, that of raw
+// tracepoint programs is <tracepoint>/<program name> and that of cgroup
+// programs is <hook>/<program name>. Like redbpf, cgroup sections without a
+// program name, e.g., `cgroup/sock` or `cgroup_skb/ingress`, are named after
+// the section.
+fn program_section<'s>(kind: &'s str, name: &'s str) -> Option<(&'s str, String)> {
+    let last = || name.rsplit('/').next().unwrap().to_string();
+    Some(match (kind, name.split_once('/')) {
+        ("cgroup", Some(("skb", prog_name))) => ("cgroup_skb", prog_name.to_string()),
+        ("cgroup", Some((_, prog_name))) => ("cgroup_sock", prog_name.to_string()),
+        ("cgroup", None) if name == "skb" => ("cgroup_skb", format!("cgroup_{}", name)),
+        ("cgroup", None) => ("cgroup_sock", format!("cgroup_{}", name)),
+        ("cgroup_skb", None) if name == "ingress" || name == "egress" => {
+            ("cgroup_skb", format!("cgroup_skb_{}", name))
+        }
+        ("cgroup_skb", _) => return None,
+        ("raw_tracepoint", _) | ("raw_tp", _) => ("raw_tracepoint", last()),
+        ("tracepoint", _) | ("struct_ops", _) => (kind, last()),
+        _ => (kind, name.to_string()),
+    })
+}
+
+// Return the type of the field of a map and the expression that creates it
+// from `base`, an expression of `&redbpf::Map`
+fn map_field(map: &MapSection, types: &RustTypes, base: &str) -> (String, String) {
+    use libbpf_sys::*;
+
+    let (key, value) = match types.maps.iter().find(|types| types.name == map.name) {
+        Some(types) => (types.key.clone(), types.value.clone()),
+        None => (
+            format!("[u8; {}]", map.key_size),
+            format!("[u8; {}]", map.value_size),
+        ),
+    };
+    let key_value = format!("{}, {}", key, value);
+    let typed = |wrapper: &str, params: &str| {
+        (
+            format!("redbpf::{}<'a, {}>", wrapper, params),
+            format!("redbpf::{}::new({})?", wrapper, base),
+        )
+    };
+    match map.map_type {
+        BPF_MAP_TYPE_HASH => typed("HashMap", &key_value),
+        BPF_MAP_TYPE_LRU_HASH => typed("LruHashMap", &key_value),
+        BPF_MAP_TYPE_PERCPU_HASH => typed("PerCpuHashMap", &key_value),
+        BPF_MAP_TYPE_LRU_PERCPU_HASH => typed("LruPerCpuHashMap", &key_value),
+        BPF_MAP_TYPE_ARRAY => typed("Array", &value),
+        BPF_MAP_TYPE_PERCPU_ARRAY => typed("PerCpuArray", &value),
+        BPF_MAP_TYPE_QUEUE => typed("Queue", &value),
+        BPF_MAP_TYPE_STACK => typed("Stack", &value),
+        BPF_MAP_TYPE_STACK_TRACE => (
+            "redbpf::StackTrace<'a>".to_string(),
+            format!("redbpf::StackTrace::new({})", base),
+        ),
+        BPF_MAP_TYPE_SOCKMAP => (
+            "redbpf::SockMap<'a>".to_string(),
+            format!("redbpf::SockMap::new({})?", base),
+        ),
+        BPF_MAP_TYPE_PROG_ARRAY => (
+            "redbpf::ProgramArray<'a>".to_string(),
+            format!("redbpf::ProgramArray::new({})?", base),
+        ),
+        BPF_MAP_TYPE_DEVMAP => (
+            "redbpf::xdp::DevMap<'a>".to_string(),
+            format!("redbpf::xdp::DevMap::new({})?", base),
+        ),
+        // e.g., perf event arrays are consumed by `PerfMapStream`
+        _ => ("&'a redbpf::Map".to_string(), base.to_string()),
+    }
+}
+
+fn write_maps(out: &mut String, maps: &[MapSection], types: &RustTypes) {
+    let fields = field_names(maps.iter().map(|map| (map.name.as_str(), "map")));
+    let _ = writeln!(out, "\npub struct Maps<'a> {{");
+    for (map, field) in maps.iter().zip(fields.iter()) {
+        let (type_name, _) = map_field(map, types, "");
+        let _ = writeln!(out, "    pub {}: {},", field, type_name);
+    }
+    if maps.is_empty() {
+        let _ = writeln!(out, "    _maps: std::marker::PhantomData<&'a redbpf::Map>,");
+    }
+    let _ = writeln!(out, "}}");
+
+    let _ = writeln!(out, "\nimpl<'a> Maps<'a> {{");
+    if maps.is_empty() {
+        let _ = write!(
+            out,
+            r#"    pub fn new(_maps: &'a [redbpf::Map]) -> redbpf::Result<Maps<'a>> {{
+        Ok(Maps {{
+            _maps: std::marker::PhantomData,
+        }})
+    }}
+}}
+"#
+        );
+        return;
+    }
+    let _ = write!(
+        out,
+        r#"    pub fn new(maps: &'a [redbpf::Map]) -> redbpf::Result<Maps<'a>> {{
+        let find = |name: &str| {{
+            maps.iter()
+                .find(|map| map.name == name)
+                .ok_or(redbpf::Error::Map)
+        }};
+        Ok(Maps {{
+"#
+    );
+    for (map, field) in maps.iter().zip(fields.iter()) {
+        let (_, create) = map_field(map, types, &format!("find({:?})?", map.name));
+        let _ = writeln!(out, "            {}: {},", field, create);
+    }
+    let _ = writeln!(out, "        }})\n    }}\n}}");
+}
+
+fn write_programs(out: &mut String, programs: &[ProgramSection]) {
+    let fields = field_names(
+        programs
+            .iter()
+            .map(|prog| (prog.name.as_str(), prog.type_name)),
+    );
+    let _ = writeln!(out, "\npub struct Programs<'a> {{");
+    for (prog, field) in programs.iter().zip(fields.iter()) {
+        let _ = writeln!(
+            out,
+            "    pub {}: &'a mut redbpf::{},",
+            field, prog.type_name
+        );
+    }
+    if programs.is_empty() {
+        let _ = writeln!(
+            out,
+            "    _programs: std::marker::PhantomData<&'a mut redbpf::Program>,"
+        );
+    }
+    let _ = writeln!(out, "}}");
+
+    let _ = writeln!(out, "\nimpl<'a> Programs<'a> {{");
+    if programs.is_empty() {
+        let _ = write!(
+            out,
+            r#"    pub fn new(_programs: &'a mut [redbpf::Program]) -> redbpf::Result<Programs<'a>> {{
+        Ok(Programs {{
+            _programs: std::marker::PhantomData,
+        }})
+    }}
+}}
+"#
+        );
+        return;
+    }
+    let _ = writeln!(
+        out,
+        "    pub fn new(programs: &'a mut [redbpf::Program]) -> redbpf::Result<Programs<'a>> {{"
+    );
+    for field in fields.iter() {
+        let _ = writeln!(out, "        let mut {} = None;", field);
+    }
+    let _ = writeln!(out, "        for program in programs.iter_mut() {{");
+    let _ = writeln!(out, "            let name = program.name().to_string();");
+    let _ = writeln!(out, "            match (name.as_str(), program) {{");
+    for (i, (prog, field)) in programs.iter().zip(fields.iter()).enumerate() {
+        // programs of the same name and variant are matched in turn
+        let guard = if programs[..i].contains(prog) {
+            format!(" if {}.is_none()", field)
+        } else {
+            String::new()
+        };
+        let variants = prog
+            .variants
+            .iter()
+            .map(|variant| format!("redbpf::Program::{}(prog)", variant))
+            .collect::<Vec<String>>()
+            .join(" | ");
+        let _ = writeln!(
+            out,
+            "                ({:?}, {}){} => {} = Some(prog),",
+            prog.name, variants, guard, field
+        );
+    }
+    let _ = writeln!(out, "                _ => {{}}");
+    let _ = writeln!(out, "            }}");
+    let _ = writeln!(out, "        }}");
+    let _ = writeln!(out, "        Ok(Programs {{");
+    for (prog, field) in programs.iter().zip(fields.iter()) {
+        let _ = writeln!(
+            out,
+            "            {}: {}.ok_or_else(|| redbpf::Error::SymbolNotFound({:?}.to_string()))?,",
+            field, field, prog.name
+        );
+    }
+    let _ = writeln!(out, "        }})\n    }}\n}}");
+}
+
+// Turn names into unique field names. Keywords are suffixed with `_` and
+// names used more than once are suffixed with `suffix`, e.g., a kprobe and a
+// kretprobe of the same function.
+fn field_names<'n>(names: impl Iterator<Item = (&'n str, &'n str)>) -> Vec<String> {
+    let names = names
+        .map(|(name, suffix)| {
+            let mut field = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect::<String>();
+            if field.is_empty() || field.starts_with(|c: char| c.is_ascii_digit()) {
+                field.insert(0, '_');
+            }
+            if syn::parse_str::<syn::Ident>(&field).is_err() {
+                field.push('_');
+            }
+            (field, suffix)
+        })
+        .collect::<Vec<(String, &str)>>();
+    let mut counts = HashMap::new();
+    for (field, _) in names.iter() {
+        *counts.entry(field.clone()).or_insert(0) += 1;
+    }
+    names
+        .into_iter()
+        .enumerate()
+        .map(|(i, (field, suffix))| match counts[&field] {
+            1 => field,
+            _ => format!("{}_{}{}", field, suffix.to_lowercase(), i),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The skeleton of map_types.o is checked in so that it is compiled
+    // against redbpf
+    #[allow(dead_code)]
+    mod map_types {
+        include!("../testdata/map_types_skeleton.rs");
+    }
+
+    #[test]
+    fn test_generate_skeleton() {
+        // `connections: HashMap<Connection, u64>`, `counts: Array<u64>`,
+        // `tasks: LruHashMap<u32, Task>`, `events: PerfMap` without BTF,
+        // `kprobe/do_sys_open` and `xdp/pass`
+        let elf = include_bytes!("../../redbpf/testdata/map_types.o");
+        let code = generate(elf, "map_types.o").unwrap_or_else(|e| panic!("{}", e.0));
+        let file = syn::parse_file(&code).unwrap();
+
+        let fields = |name: &str| {
+            file.items
+                .iter()
+                .find_map(|item| match item {
+                    syn::Item::Struct(item) if item.ident == name => Some(&item.fields),
+                    _ => None,
+                })
+                .unwrap()
+                .iter()
+                .map(|field| {
+                    let ty = &field.ty;
+                    (
+                        field.ident.as_ref().unwrap().to_string(),
+                        quote::quote!(#ty).to_string().replace(' ', ""),
+                    )
+                })
+                .collect::<Vec<(String, String)>>()
+        };
+        let expected = |fields: &[(&str, &str)]| {
+            fields
+                .iter()
+                .map(|(name, ty)| (name.to_string(), ty.to_string()))
+                .collect::<Vec<(String, String)>>()
+        };
+        assert_eq!(
+            fields("Maps"),
+            expected(&[
+                ("connections", "redbpf::HashMap<'a,Connection,u64>"),
+                ("counts", "redbpf::Array<'a,u64>"),
+                ("events", "&'aredbpf::Map"),
+                ("tasks", "redbpf::LruHashMap<'a,u32,Task>"),
+            ])
+        );
+        assert_eq!(
+            fields("Programs"),
+            expected(&[
+                ("do_sys_open", "&'amutredbpf::KProbe"),
+                ("pass", "&'amutredbpf::XDP"),
+            ])
+        );
+        assert_eq!(
+            fields("Task"),
+            expected(&[
+                ("pid", "u32"),
+                ("_pad1", "[u8;4]"),
+                ("start", "u64"),
+                ("comm", "[u8;16]"),
+            ])
+        );
+        assert!(code.contains("counts: redbpf::Array::new(find(\"counts\")?)?,"));
+        assert!(
+            code == include_str!("../testdata/map_types_skeleton.rs"),
+            "testdata/map_types_skeleton.rs is outdated, write the skeleton of map_types.o to it"
+        );
+    }
+
+    #[test]
+    fn test_legacy_maps_section() {
+        // `counts` and `events` defined in the `maps` section without BTF
+        let elf = include_bytes!("../../redbpf/testdata/legacy_maps.o");
+        let object = Elf::parse(elf).unwrap();
+        let (maps, programs) = parse_sections(&object, elf).unwrap();
+        let map = |name: &str, map_type, key_size, value_size| MapSection {
+            name: name.to_string(),
+            map_type,
+            key_size,
+            value_size,
+        };
+        assert_eq!(
+            maps,
+            vec![
+                map("counts", libbpf_sys::BPF_MAP_TYPE_ARRAY, 4, 8),
+                map("events", libbpf_sys::BPF_MAP_TYPE_PERF_EVENT_ARRAY, 4, 4),
+            ]
+        );
+        assert_eq!(
+            programs,
+            vec![ProgramSection {
+                name: "accept".to_string(),
+                variants: &["SocketFilter"],
+                type_name: "SocketFilter",
+            }]
+        );
+        let code = generate(elf, "legacy_maps.o").unwrap_or_else(|e| panic!("{}", e.0));
+        assert!(code.contains("    pub counts: redbpf::Array<'a, [u8; 8]>,\n"));
+    }
+
+    #[test]
+    fn test_program_sections() {
+        let section = |section: &str| {
+            let (kind, name) = section.split_once('/').unwrap();
+            program_section(kind, name)
+                .map(|(kind, name)| (program_kind(kind).unwrap().1, name))
+                .map(|(type_name, name)| format!("{} {}", type_name, name))
+        };
+        let expected = |program: &str| Some(program.to_string());
+        assert_eq!(
+            section("kprobe/do_sys_open"),
+            expected("KProbe do_sys_open")
+        );
+        assert_eq!(
+            section("tracepoint/syscalls/sys_enter_openat"),
+            expected("TracePoint sys_enter_openat")
+        );
+        assert_eq!(
+            section("raw_tracepoint/sched_switch/on_switch"),
+            expected("RawTracePoint on_switch")
+        );
+        assert_eq!(
+            section("raw_tp/sys_enter"),
+            expected("RawTracePoint sys_enter")
+        );
+        assert_eq!(section("cgroup/skb/filter"), expected("CgroupSkb filter"));
+        assert_eq!(section("cgroup/skb"), expected("CgroupSkb cgroup_skb"));
+        assert_eq!(
+            section("cgroup/post_bind4/on_bind"),
+            expected("CgroupSock on_bind")
+        );
+        assert_eq!(section("cgroup/sock"), expected("CgroupSock cgroup_sock"));
+        assert_eq!(
+            section("cgroup_skb/ingress"),
+            expected("CgroupSkb cgroup_skb_ingress")
+        );
+        assert_eq!(section("cgroup_skb/filter"), None);
+        assert_eq!(section("fexit/vfs_read"), expected("FEntry vfs_read"));
+    }
+
+    #[test]
+    fn test_field_names() {
+        let names = [
+            ("open", "KProbe"),
+            ("open", "UProbe"),
+            ("type", "Map"),
+            ("sys-enter", "Map"),
+        ];
+        assert_eq!(
+            field_names(names.iter().copied()),
+            vec!["open_kprobe0", "open_uprobe1", "type_", "sys_enter"]
+        );
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Fixtures shared by the tests of the modules of cargo-bpf
+
+// An ELF relocatable file that has empty PROGBITS sections of `names`
+pub(crate) fn elf_with_sections(names: &[&str]) -> Vec<u8> {
+    let mut shstrtab = vec![0];
+    let mut name_offs = vec![];
+    for name in names.iter().chain([".shstrtab"].iter()) {
+        name_offs.push(shstrtab.len() as u32);
+        shstrtab.extend_from_slice(name.as_bytes());
+        shstrtab.push(0);
+    }
+    let shnum = names.len() as u16 + 2;
+    let shoff = (64 + shstrtab.len() as u64 + 7) & !7;
+
+    let mut elf = vec![0x7f, b'E', b'L', b'F', 2, 1, 1];
+    elf.resize(16, 0);
+    elf.extend_from_slice(&1u16.to_le_bytes()); // ET_REL
+    elf.extend_from_slice(&247u16.to_le_bytes()); // EM_BPF
+    elf.extend_from_slice(&1u32.to_le_bytes());
+    elf.extend_from_slice(&0u64.to_le_bytes()); // entry
+    elf.extend_from_slice(&0u64.to_le_bytes()); // phoff
+    elf.extend_from_slice(&shoff.to_le_bytes());
+    elf.extend_from_slice(&0u32.to_le_bytes()); // flags
+    for half in [64u16, 0, 0, 64, shnum, shnum - 1].iter() {
+        elf.extend_from_slice(&half.to_le_bytes());
+    }
+    elf.extend_from_slice(&shstrtab);
+    elf.resize(shoff as usize, 0);
+    // the null section header
+    elf.extend_from_slice(&[0; 64]);
+    for (i, name_off) in name_offs.iter().enumerate() {
+        let (ty, size) = if i < names.len() {
+            (1u32, 0u64)
+        } else {
+            (3, shstrtab.len() as u64)
+        };
+        elf.extend_from_slice(&name_off.to_le_bytes());
+        elf.extend_from_slice(&ty.to_le_bytes());
+        elf.extend_from_slice(&[0; 16]); // flags and addr
+        elf.extend_from_slice(&64u64.to_le_bytes());
+        elf.extend_from_slice(&size.to_le_bytes());
+        elf.extend_from_slice(&[0; 8]); // link and info
+        elf.extend_from_slice(&1u64.to_le_bytes()); // addralign
+        elf.extend_from_slice(&0u64.to_le_bytes()); // entsize
+    }
+    elf
+}
@@ -0,0 +1,63 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Verifier smoke test of built probes
+//!
+//! Every program of a probe is loaded on the running kernel and unloaded
+//! right away, so that programs rejected by the verifier are found at build
+//! time rather than when they are deployed. Nothing is attached. Loading
+//! needs `CAP_BPF` (or `CAP_SYS_ADMIN` before Linux 5.8) and the result only
+//! holds for kernels that verify programs like the running one does.
+use std::fs;
+use std::path::Path;
+
+use redbpf::Module;
+
+/// The outcome of loading a program of a probe
+#[derive(Debug)]
+pub struct VerifyResult {
+    pub program: String,
+    /// `None` if the verifier accepted the program, or the reason it was not
+    /// loaded
+    pub error: Option<String>,
+}
+
+/// Load every program of the ELF file `elf` built by `cargo bpf build`
+///
+/// The verifier log of a rejected program is logged by `redbpf` with
+/// `tracing`. An error is returned if the ELF file can not be parsed or its
+/// maps can not be created, e.g., for lack of privilege.
+pub fn verify_probe(elf: &Path) -> Result<Vec<VerifyResult>, String> {
+    let bytes = fs::read(elf).map_err(|e| format!("failed to read {:?}: {}", elf, e))?;
+    let mut module = Module::parse(&bytes).map_err(|e| {
+        format!(
+            "failed to parse {:?} or create its maps: {:?} (loading requires CAP_BPF or root)",
+            elf, e
+        )
+    })?;
+    let version = module.version;
+    let license = module.license.clone();
+    let failed_relocs = module.core_relocation_failures().len();
+    let results = module
+        .programs
+        .iter_mut()
+        .map(|program| {
+            let error = program.load(version, license.clone()).err().map(|e| {
+                if failed_relocs == 0 {
+                    format!("{:?}", e)
+                } else {
+                    format!("{:?} ({} CO-RE relocations failed)", e, failed_relocs)
+                }
+            });
+            VerifyResult {
+                program: program.name().to_string(),
+                error,
+            }
+        })
+        .collect();
+    Ok(results)
+}
@@ -0,0 +1,82 @@
+// Generated by `cargo bpf skeleton` from map_types.o. Do not edit.
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types, non_snake_case)]
+pub struct Connection {
+    pub saddr: u32,
+    pub daddr: u32,
+    pub sport: u16,
+    pub dport: u16,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+#[allow(non_camel_case_types, non_snake_case)]
+pub struct Task {
+    pub pid: u32,
+    pub _pad1: [u8; 4],
+    pub start: u64,
+    pub comm: [u8; 16],
+}
+
+pub struct Maps<'a> {
+    pub connections: redbpf::HashMap<'a, Connection, u64>,
+    pub counts: redbpf::Array<'a, u64>,
+    pub events: &'a redbpf::Map,
+    pub tasks: redbpf::LruHashMap<'a, u32, Task>,
+}
+
+impl<'a> Maps<'a> {
+    pub fn new(maps: &'a [redbpf::Map]) -> redbpf::Result<Maps<'a>> {
+        let find = |name: &str| {
+            maps.iter()
+                .find(|map| map.name == name)
+                .ok_or(redbpf::Error::Map)
+        };
+        Ok(Maps {
+            connections: redbpf::HashMap::new(find("connections")?)?,
+            counts: redbpf::Array::new(find("counts")?)?,
+            events: find("events")?,
+            tasks: redbpf::LruHashMap::new(find("tasks")?)?,
+        })
+    }
+}
+
+pub struct Programs<'a> {
+    pub do_sys_open: &'a mut redbpf::KProbe,
+    pub pass: &'a mut redbpf::XDP,
+}
+
+impl<'a> Programs<'a> {
+    pub fn new(programs: &'a mut [redbpf::Program]) -> redbpf::Result<Programs<'a>> {
+        let mut do_sys_open = None;
+        let mut pass = None;
+        for program in programs.iter_mut() {
+            let name = program.name().to_string();
+            match (name.as_str(), program) {
+                ("do_sys_open", redbpf::Program::KProbe(prog) | redbpf::Program::KRetProbe(prog)) => do_sys_open = Some(prog),
+                ("pass", redbpf::Program::XDP(prog)) => pass = Some(prog),
+                _ => {}
+            }
+        }
+        Ok(Programs {
+            do_sys_open: do_sys_open.ok_or_else(|| redbpf::Error::SymbolNotFound("do_sys_open".to_string()))?,
+            pass: pass.ok_or_else(|| redbpf::Error::SymbolNotFound("pass".to_string()))?,
+        })
+    }
+}
+
+pub struct Skeleton<'a> {
+    pub maps: Maps<'a>,
+    pub programs: Programs<'a>,
+}
+
+impl<'a> Skeleton<'a> {
+    pub fn new(module: &'a mut redbpf::Module) -> redbpf::Result<Skeleton<'a>> {
+        Ok(Skeleton {
+            maps: Maps::new(&module.maps)?,
+            programs: Programs::new(&mut module.programs)?,
+        })
+    }
+}
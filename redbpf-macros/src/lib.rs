@@ -48,15 +48,22 @@ use proc_macro::TokenStream;
 use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
 use quote::quote;
 use std::str;
-use syn::parse::{Parse, ParseStream};
+use syn::parse::{Parse, ParseStream, Parser};
 use syn::punctuated::Punctuated;
 use syn::token::Comma;
 use syn::{
-    parse_macro_input, parse_quote, AttributeArgs, Expr, ExprLit, GenericArgument, ItemFn,
-    ItemStatic, Lit, Meta, NestedMeta, PathArguments, Result, Type,
+    parse_macro_input, parse_quote, Expr, ExprLit, GenericArgument, ItemFn, ItemStatic, Lit, Meta,
+    NestedMeta, PathArguments, Result, ReturnType, Type,
 };
 use uuid::Uuid;
 
+// Flags of maps in `linux/bpf.h`
+const BPF_F_NO_PREALLOC: u32 = 1 << 0;
+const BPF_F_NO_COMMON_LRU: u32 = 1 << 1;
+const BPF_F_RDONLY_PROG: u32 = 1 << 7;
+const BPF_F_WRONLY_PROG: u32 = 1 << 8;
+const BPF_F_MMAPABLE: u32 = 1 << 10;
+
 fn inline_string_literal(e: &Expr) -> (TokenStream2, TokenStream2) {
     let bytes = match e {
         Expr::Lit(ExprLit {
@@ -110,7 +117,7 @@ pub fn program(input: TokenStream) -> TokenStream {
     let license = args.next().expect("no license");
     let (license_ty, license) = inline_string_literal(&license);
     let (panic_ty, panic_msg) = inline_bytes(b"panic".to_vec());
-    let tokens = quote! {
+    let mut tokens = quote! {
         #[no_mangle]
         #[link_section = "license"]
         pub static _license: #license_ty = #license;
@@ -118,7 +125,14 @@ pub fn program(input: TokenStream) -> TokenStream {
         #[no_mangle]
         #[link_section = "version"]
         pub static _version: u32 = #version;
+    };
 
+    // redbpf-probes defines the panic handler instead
+    if cfg!(feature = "no-panic-handler") {
+        return tokens.into();
+    }
+
+    tokens.extend(quote! {
         #[panic_handler]
         #[no_mangle]
         pub extern "C" fn rust_begin_panic(info: &::core::panic::PanicInfo) -> ! {
@@ -129,7 +143,7 @@ pub fn program(input: TokenStream) -> TokenStream {
 
             unsafe { core::hint::unreachable_unchecked() }
         }
-    };
+    });
 
     tokens.into()
 }
@@ -157,6 +171,27 @@ pub fn impl_network_buffer_array(_: TokenStream) -> TokenStream {
 /// require strict naming conventions use `#[map(link_section = "foo")]`
 /// which place the map into a section called `foo`.
 ///
+/// The access of BPF programs to the map can be restricted with
+/// `#[map(access = "read_only")]` or `#[map(access = "write_only")]`, which
+/// set `BPF_F_RDONLY_PROG` or `BPF_F_WRONLY_PROG` flag respectively. Then the
+/// verifier rejects programs that update a read-only map or look up a
+/// write-only map. Userspace keeps full access to the map. The flags are
+/// supported by hash maps and array maps.
+///
+/// Other flags of the map are set with `#[map(flags = "...")]`, which takes
+/// `no_prealloc`, `no_common_lru` and `mmapable` separated by `|`, or the
+/// value of the flags as an integer. `no_prealloc` sets `BPF_F_NO_PREALLOC`
+/// so that the elements of a large hash map are allocated on insert instead
+/// of all at once when the map is created. The kernel rejects it for LRU
+/// hash maps, which are always preallocated. `mmapable` sets
+/// `BPF_F_MMAPABLE` of array maps. The flags are supported by hash maps and
+/// array maps.
+///
+/// `#[map(pinned = "/sys/fs/bpf/...")]` pins the map to the file in BPF FS.
+/// The loader of redbpf reuses the pinned map if the file exists, and
+/// otherwise pins the map once it is created, so the map outlives the
+/// program and is shared by the next runs.
+///
 /// # Example
 ///
 /// ```no_run
@@ -169,14 +204,56 @@ pub fn impl_network_buffer_array(_: TokenStream) -> TokenStream {
 /// #[map(link_section = "dns_queries")]
 /// static mut queries: PerfMap<Query> = PerfMap::with_max_entries(1024);
 ///
+/// // Userspace writes the config, the program can only read it
+/// #[map(access = "read_only")]
+/// static mut config: Array<u64> = Array::with_max_entries(1);
+///
+/// // Elements are allocated on insert, and the map is kept in BPF FS
+/// #[map(flags = "no_prealloc", pinned = "/sys/fs/bpf/connections")]
+/// static mut connections: HashMap<u64, u64> = HashMap::with_max_entries(1 << 20);
+///
 /// struct Query {
 /// // ...
 /// }
 /// ```
 #[proc_macro_attribute]
 pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    map_impl(attrs.into(), item.into()).into()
+}
+
+// Parse `flags = "no_prealloc | mmapable"` or `flags = 1` of #[map]
+fn parse_map_flags(lit: &Lit) -> u32 {
+    match lit {
+        Lit::Int(int) => int
+            .base10_parse::<u32>()
+            .unwrap_or_else(|e| panic!("invalid map flags: {}", e)),
+        Lit::Str(names) => names
+            .value()
+            .split('|')
+            .map(|name| match name.trim() {
+                "no_prealloc" => BPF_F_NO_PREALLOC,
+                "no_common_lru" => BPF_F_NO_COMMON_LRU,
+                "mmapable" => BPF_F_MMAPABLE,
+                other => panic!(
+                    "expected `no_prealloc', `no_common_lru' or `mmapable' but got `{}'",
+                    other
+                ),
+            })
+            .fold(0, |flags, flag| flags | flag),
+        _ => panic!("expected #[map(flags = \"...\")]"),
+    }
+}
+
+fn map_impl(attrs: TokenStream2, item: TokenStream2) -> TokenStream2 {
     let mut link_section: Option<String> = None;
-    for attr in parse_macro_input!(attrs as AttributeArgs) {
+    let mut access_flags: Option<u32> = None;
+    let mut extra_flags: Option<u32> = None;
+    let mut pinned: Option<String> = None;
+    let attrs = match Punctuated::<NestedMeta, Comma>::parse_terminated.parse2(attrs) {
+        Ok(attrs) => attrs,
+        Err(e) => return e.to_compile_error(),
+    };
+    for attr in attrs {
         let mut allowed = false;
         match attr {
             NestedMeta::Meta(meta) => {
@@ -195,7 +272,48 @@ pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
                                     allowed = true;
                                 }
                             }
-                            _ => panic!("expected `link_section' as metadata of #[map]"),
+                            "access" => {
+                                if let Lit::Str(access) = mnv.lit {
+                                    if access_flags.is_some() {
+                                        panic!("#[map(access = \"...\")] is used more than once");
+                                    }
+                                    access_flags = Some(match access.value().as_str() {
+                                        "read_only" => BPF_F_RDONLY_PROG,
+                                        "write_only" => BPF_F_WRONLY_PROG,
+                                        other => panic!(
+                                            "expected `read_only' or `write_only' but got `{}'",
+                                            other
+                                        ),
+                                    });
+                                    allowed = true;
+                                }
+                            }
+                            "flags" => {
+                                if extra_flags.is_some() {
+                                    panic!("#[map(flags = \"...\")] is used more than once");
+                                }
+                                extra_flags = Some(parse_map_flags(&mnv.lit));
+                                allowed = true;
+                            }
+                            "pinned" => {
+                                if let Lit::Str(path) = mnv.lit {
+                                    if pinned.is_some() {
+                                        panic!("#[map(pinned = \"...\")] is used more than once");
+                                    }
+                                    let path = path.value();
+                                    if !path.starts_with('/') {
+                                        panic!(
+                                            "expected an absolute path in BPF FS but got `{}'",
+                                            path
+                                        );
+                                    }
+                                    pinned = Some(path);
+                                    allowed = true;
+                                }
+                            }
+                            _ => panic!(
+                                "expected `link_section', `access', `flags' or `pinned' as metadata of #[map]"
+                            ),
                         }
                     }
                 }
@@ -212,22 +330,52 @@ pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
             panic!("expected #[map(link_section = \"...\")]");
         }
     }
-    let static_item = {
-        let item = item.clone();
-        parse_macro_input!(item as ItemStatic)
+    let mut static_item = match syn::parse2::<ItemStatic>(item.clone()) {
+        Ok(item) => item,
+        Err(e) => return e.to_compile_error(),
     };
     let section_name = link_section.unwrap_or_else(|| {
         // In case of just #[map] without any metadata
         format!("maps/{}", static_item.ident.to_string())
     });
-    let mut tokens = {
-        let item = TokenStream2::from(item);
+    let map_flags = match (access_flags, extra_flags) {
+        (None, None) => None,
+        (access, extra) => Some(access.unwrap_or(0) | extra.unwrap_or(0)),
+    };
+    let mut tokens = if let Some(flags) = map_flags {
+        let expr = &static_item.expr;
+        let expr = parse_quote!((#expr).with_map_flags(#flags));
+        *static_item.expr = expr;
+        quote! {
+            #[no_mangle]
+            #[link_section = #section_name]
+            #static_item
+        }
+    } else {
         quote! {
             #[no_mangle]
             #[link_section = #section_name]
             #item
         }
     };
+    if let Some(path) = pinned {
+        // The loader of redbpf reads the path from `maps_pin/<map name>`.
+        // The map name is the part after `maps/` of the section name, or the
+        // symbol name for maps in the `maps` section.
+        let ident = static_item.ident.to_string();
+        let map_name = match section_name.split_once('/') {
+            Some((_, name)) => name.to_string(),
+            None => ident.clone(),
+        };
+        let pin_section = format!("maps_pin/{}", map_name);
+        let pin_ident = Ident::new(&format!("MAP_PIN_{}", ident), static_item.ident.span());
+        let (ty, array_lit) = inline_bytes(path.into_bytes());
+        tokens.extend(quote! {
+            #[no_mangle]
+            #[link_section = #pin_section]
+            static #pin_ident: #ty = #array_lit;
+        });
+    }
 
     let mut tc_compatible = false;
     let mut key_type: Option<GenericArgument> = None;
@@ -251,7 +399,7 @@ pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
                             value_type = Some(bracket.args.last().unwrap().clone());
                         }
                     }
-                    "PerfMap" => {}
+                    "PerfMap" | "RateLimiter" | "BloomFilter" => {}
                     _ => {
                         panic!("unknown map type name: {}", map_type_name);
                     }
@@ -263,7 +411,8 @@ pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
             } else {
                 // without generic types
                 match map_type_name.as_str() {
-                    "StackTrace" | "SockMap" | "ProgramArray" | "DevMap" | "XskMap" => {}
+                    "StackTrace" | "SockMap" | "ProgramArray" | "DevMap" | "XskMap"
+                    | "FlowGate" => {}
                     _ => {
                         panic!("unknown map type name: {}", map_type_name);
                     }
@@ -333,7 +482,7 @@ pub fn map(attrs: TokenStream, item: TokenStream) -> TokenStream {
             });
         }
     }
-    tokens.into()
+    tokens
 }
 
 fn probe_impl(ty: &str, attrs: TokenStream, item: ItemFn, mut name: String) -> TokenStream {
@@ -749,6 +898,232 @@ pub fn tc_action(attrs: TokenStream, item: TokenStream) -> TokenStream {
     probe_impl("tc_action", attrs, wrapper, name)
 }
 
+/// Attribute macro for defining [`sockops`](../../redbpf_probes/sockops/index.html)
+/// BPF programs.
+///
+/// `sockops` programs are attached to cgroups and called at TCP connection
+/// lifecycle events.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::sockops::prelude::*;
+///
+/// #[sockops]
+/// fn monitor_retrans(ctx: SockOpsContext) -> SockOpsResult {
+///     if let SockOp::ActiveEstablishedCb = ctx.op() {
+///         ctx.set_cb_flags(cb_flags::RETRANS)?;
+///     }
+///     Ok(())
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn sockops(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(ctx: *mut ::redbpf_probes::bindings::bpf_sock_ops) -> i32 {
+            let ctx = ::redbpf_probes::sockops::SockOpsContext { ctx };
+            return match #ident(ctx) {
+                Ok(_) => 1,
+                Err(_) => 0,
+            };
+
+            #item
+        }
+    };
+
+    probe_impl("sockops", attrs, wrapper, name)
+}
+
+/// Attribute macro for defining [`cgroup/skb`](../../redbpf_probes/cgroup/index.html)
+/// BPF programs.
+///
+/// `cgroup/skb` programs are attached to cgroups and called for the ingress
+/// or egress packets of the sockets of the cgroup. Returning
+/// `CgroupAction::Deny` or an error drops the packet.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::cgroup::prelude::*;
+///
+/// #[cgroup_skb]
+/// fn allow_ipv4(skb: SkBuff) -> CgroupSkbResult {
+///     let version: u8 = skb.load(0)?;
+///     if version >> 4 == 4 {
+///         Ok(CgroupAction::Allow)
+///     } else {
+///         Ok(CgroupAction::Deny)
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn cgroup_skb(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(skb: *const ::redbpf_probes::bindings::__sk_buff) -> i32 {
+            let skb = ::redbpf_probes::socket::SkBuff { skb };
+            return match unsafe { #ident(skb) } {
+                Ok(::redbpf_probes::cgroup::CgroupAction::Allow) => 1,
+                Ok(::redbpf_probes::cgroup::CgroupAction::Deny) | Err(_) => 0,
+            };
+
+            #item
+        }
+    };
+
+    probe_impl("cgroup/skb", attrs, wrapper, name)
+}
+
+/// Attribute macro for defining [`cgroup/sock`](../../redbpf_probes/cgroup/index.html)
+/// BPF programs.
+///
+/// The macro optionally takes the hook of the program, one of `sock_create`,
+/// `sock_release`, `post_bind4` and `post_bind6`. Socket creation is the
+/// default. Returning `CgroupAction::Deny` rejects the operation.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::cgroup::prelude::*;
+///
+/// #[cgroup_sock]
+/// fn deny_raw_sockets(ctx: CgroupSockContext) -> CgroupAction {
+///     if ctx.sock_type() == SOCK_RAW {
+///         CgroupAction::Deny
+///     } else {
+///         CgroupAction::Allow
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn cgroup_sock(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    cgroup_sock_impl(attrs.into(), item.into()).into()
+}
+
+fn cgroup_sock_impl(attrs: TokenStream2, item: TokenStream2) -> TokenStream2 {
+    let hook = if attrs.is_empty() {
+        "sock".to_string()
+    } else {
+        match syn::parse2::<Expr>(attrs) {
+            Ok(Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            })) => s.value(),
+            _ => panic!("expected the hook of the cgroup/sock program"),
+        }
+    };
+    match hook.as_str() {
+        "sock" | "sock_create" | "sock_release" | "post_bind4" | "post_bind6" => {}
+        _ => panic!(
+            "unsupported hook `{}', expected sock_create, sock_release, post_bind4 or post_bind6",
+            hook
+        ),
+    }
+    let item = match syn::parse2::<ItemFn>(item) {
+        Ok(item) => item,
+        Err(e) => return e.to_compile_error(),
+    };
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let section_name = format!("cgroup/{}/{}", hook, ident);
+    quote! {
+        #[no_mangle]
+        #[link_section = #section_name]
+        fn #outer_ident(sk: *mut ::redbpf_probes::bindings::bpf_sock) -> i32 {
+            let ctx = ::redbpf_probes::cgroup::CgroupSockContext { sk };
+            return match unsafe { #ident(ctx) } {
+                ::redbpf_probes::cgroup::CgroupAction::Allow => 1,
+                ::redbpf_probes::cgroup::CgroupAction::Deny => 0,
+            };
+
+            #item
+        }
+    }
+}
+
+/// Attribute macro for defining [`sk_reuseport`](../../redbpf_probes/sk_reuseport/index.html)
+/// BPF programs.
+///
+/// `sk_reuseport` programs select the socket of a `SO_REUSEPORT` group that
+/// receives a new connection or datagram. Returning an error drops the
+/// packet.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::sk_reuseport::prelude::*;
+///
+/// #[map]
+/// static mut SOCKETS: ReuseportSockArray = ReuseportSockArray::with_max_entries(4);
+///
+/// #[sk_reuseport]
+/// fn select_worker(ctx: SkReuseportContext) -> SkReuseportResult {
+///     unsafe { ctx.select_reuseport(&mut SOCKETS, ctx.hash() % 4)? };
+///     Ok(SkReuseportAction::Pass)
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn sk_reuseport(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(ctx: *mut ::redbpf_probes::bindings::sk_reuseport_md) -> i32 {
+            let ctx = ::redbpf_probes::sk_reuseport::SkReuseportContext { ctx };
+            return match unsafe { #ident(ctx) } {
+                Ok(::redbpf_probes::sk_reuseport::SkReuseportAction::Pass) => 1,
+                Ok(::redbpf_probes::sk_reuseport::SkReuseportAction::Drop) | Err(_) => 0,
+            };
+
+            #item
+        }
+    };
+
+    probe_impl("sk_reuseport", attrs, wrapper, name)
+}
+
+/// Attribute macro for defining [`perf_event`](../../redbpf_probes/perf_event/index.html)
+/// BPF programs.
+///
+/// `perf_event` programs are called whenever the perf event they are
+/// attached to overflows, e.g., when a sampling CPU clock fires.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::perf_event::prelude::*;
+///
+/// #[map]
+/// static mut SAMPLES: PerCpuArray<u64> = PerCpuArray::with_max_entries(1);
+///
+/// #[perf_event]
+/// fn count_samples(ctx: PerfEventContext) {
+///     if let Some(count) = unsafe { SAMPLES.get_mut(0) } {
+///         *count += 1;
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn perf_event(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    let name = item.sig.ident.to_string();
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let wrapper = parse_quote! {
+        fn #outer_ident(ctx: *mut ::redbpf_probes::bindings::bpf_perf_event_data) -> i32 {
+            let ctx = ::redbpf_probes::perf_event::PerfEventContext { ctx };
+            let _ = unsafe { #ident(ctx) };
+            return 0;
+
+            #item
+        }
+    };
+
+    probe_impl("perf_event", attrs, wrapper, name)
+}
+
 /// Attribute macro for defining a BPF iterator of `task`
 #[proc_macro_attribute]
 pub fn task_iter(attrs: TokenStream, item: TokenStream) -> TokenStream {
@@ -771,6 +1146,286 @@ pub fn task_iter(attrs: TokenStream, item: TokenStream) -> TokenStream {
     probe_impl("task_iter", attrs, wrapper, name)
 }
 
+/// Attribute macro for defining a callback of a `struct_ops` map
+///
+/// `struct_ops` programs implement the function pointers of a kernel
+/// structure such as `tcp_congestion_ops`. The macro takes the names of the
+/// structure and of the member implemented by the function. The arguments of
+/// the function are those of the member in the same order, and the function
+/// is registered with the kernel by `redbpf::StructOpsMap` in userspace.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::struct_ops::prelude::*;
+///
+/// #[struct_ops("tcp_congestion_ops", "ssthresh")]
+/// fn rust_ssthresh(sk: *mut sock) -> u32 {
+///     TCP_INFINITE_SSTHRESH
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn struct_ops(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let names: Vec<String> = parse_macro_input!(attrs as Args)
+        .0
+        .iter()
+        .map(|e| match e {
+            Expr::Lit(ExprLit {
+                lit: Lit::Str(s), ..
+            }) => s.value(),
+            _ => panic!("expected string literal"),
+        })
+        .collect();
+    if names.len() != 2 {
+        panic!("expected the names of the structure and its member");
+    }
+    let item = parse_macro_input!(item as ItemFn);
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    // the context is an array of the arguments of the callback
+    let args = (0..item.sig.inputs.len()).map(|i| quote!(*ctx.add(#i) as _));
+    let call = quote!(#ident(#(#args),*));
+    let ret = match item.sig.output {
+        ReturnType::Default => quote! {{
+            unsafe { #call };
+            0
+        }},
+        _ => quote!(unsafe { #call as u64 }),
+    };
+    let section_name = format!("struct_ops/{}/{}/{}", names[0], names[1], ident);
+    let tokens = quote! {
+        #[no_mangle]
+        #[link_section = #section_name]
+        fn #outer_ident(ctx: *const u64) -> u64 {
+            return #ret;
+
+            #item
+        }
+    };
+
+    tokens.into()
+}
+
+/// Attribute macro for defining a BPF program attached to the entry of a
+/// kernel function through a BPF trampoline
+///
+/// The macro takes the name of the kernel function. The arguments of the
+/// function are those of the kernel function in the same order. `fentry`
+/// programs require vmlinux BTF, so a `kprobe` program doing the same can be
+/// defined as a fallback, see `redbpf::Module::attach_preferred`.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::fentry::prelude::*;
+///
+/// #[fentry("do_unlinkat")]
+/// fn unlinkat(dfd: i32, name: *const c_void) {
+///     bpf_trace_printk(b"unlinkat\0");
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn fentry(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    trampoline_impl("fentry", attrs, item)
+}
+
+/// Attribute macro for defining a BPF program attached to the exit of a
+/// kernel function through a BPF trampoline
+///
+/// This is the same as `#[fentry]` except that the function takes the
+/// return value of the kernel function as its last argument.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::fentry::prelude::*;
+///
+/// #[fexit("do_unlinkat")]
+/// fn unlinkat_exit(dfd: i32, name: *const c_void, ret: i32) {
+///     bpf_trace_printk(b"unlinkat returned\0");
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn fexit(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    trampoline_impl("fexit", attrs, item)
+}
+
+/// Attribute macro for defining a BPF program attached to a raw tracepoint
+///
+/// The macro takes the name of the tracepoint, e.g., `sched_switch`. The
+/// function takes `RawTracePointArgs` to read the raw arguments of the
+/// tracepoint as `u64`s.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::raw_tracepoint::prelude::*;
+///
+/// #[raw_tracepoint("sys_enter")]
+/// fn sys_enter(args: RawTracePointArgs) {
+///     let id = args.arg(1);
+///     bpf_trace_printk(b"sys_enter\0");
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn raw_tracepoint(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    raw_tracepoint_impl(attrs.into(), item.into()).into()
+}
+
+fn raw_tracepoint_impl(attrs: TokenStream2, item: TokenStream2) -> TokenStream2 {
+    let tracepoint = match syn::parse2::<Expr>(attrs) {
+        Ok(Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        })) => s.value(),
+        _ => panic!("expected the name of the tracepoint"),
+    };
+    let item = match syn::parse2::<ItemFn>(item) {
+        Ok(item) => item,
+        Err(e) => return e.to_compile_error(),
+    };
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    let section_name = format!("raw_tracepoint/{}/{}", tracepoint, ident);
+    quote! {
+        #[no_mangle]
+        #[link_section = #section_name]
+        fn #outer_ident(ctx: *const u64) -> i32 {
+            let args = ::redbpf_probes::raw_tracepoint::RawTracePointArgs::from(ctx);
+            let _ = unsafe { #ident(args) };
+            return 0;
+
+            #item
+        }
+    }
+}
+
+/// Attribute macro for defining a BPF program attached to a Linux Security
+/// Module hook
+///
+/// The macro takes the name of the LSM hook, e.g., `file_open`. The
+/// arguments of the function are those of the hook in the same order,
+/// optionally followed by the return value of the previous program attached
+/// to the hook. The function returns an `i32`, 0 to allow the operation or a
+/// negative error number to deny it. `lsm` programs require a kernel built with
+/// `CONFIG_BPF_LSM` and `bpf` in the active LSMs.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::lsm::prelude::*;
+///
+/// #[lsm("file_open")]
+/// fn file_open(file: *const c_void, ret: i32) -> i32 {
+///     bpf_trace_printk(b"file_open\0");
+///     ret
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn lsm(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    lsm_impl(attrs.into(), item.into()).into()
+}
+
+fn lsm_impl(attrs: TokenStream2, item: TokenStream2) -> TokenStream2 {
+    let hook = match syn::parse2::<Expr>(attrs) {
+        Ok(Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        })) => s.value(),
+        _ => panic!("expected the name of the LSM hook"),
+    };
+    let item = match syn::parse2::<ItemFn>(item) {
+        Ok(item) => item,
+        Err(e) => return e.to_compile_error(),
+    };
+    if let ReturnType::Default = item.sig.output {
+        panic!("lsm programs must return 0 or a negative error number");
+    }
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    // the context is an array of the arguments of the hook followed by the
+    // return value of the previous program
+    let args = (0..item.sig.inputs.len()).map(|i| quote!(*ctx.add(#i) as _));
+    let section_name = format!("lsm/{}/{}", hook, ident);
+    quote! {
+        #[no_mangle]
+        #[link_section = #section_name]
+        fn #outer_ident(ctx: *const u64) -> i32 {
+            return unsafe { #ident(#(#args),*) };
+
+            #item
+        }
+    }
+}
+
+fn trampoline_impl(kind: &str, attrs: TokenStream, item: TokenStream) -> TokenStream {
+    let target = match parse_macro_input!(attrs as Expr) {
+        Expr::Lit(ExprLit {
+            lit: Lit::Str(s), ..
+        }) => s.value(),
+        _ => panic!("expected the name of the kernel function"),
+    };
+    let item = parse_macro_input!(item as ItemFn);
+    let ident = item.sig.ident.clone();
+    let outer_ident = Ident::new(&format!("outer_{}", ident), Span::call_site());
+    // the context is an array of the arguments of the kernel function
+    let args = (0..item.sig.inputs.len()).map(|i| quote!(*ctx.add(#i) as _));
+    let section_name = format!("{}/{}/{}", kind, target, ident);
+    let tokens = quote! {
+        #[no_mangle]
+        #[link_section = #section_name]
+        fn #outer_ident(ctx: *const u64) -> i32 {
+            unsafe { #ident(#(#args),*) };
+            return 0;
+
+            #item
+        }
+    };
+
+    tokens.into()
+}
+
+/// Attribute macro for defining a BPF global function
+///
+/// BPF global functions are not inlined into the programs calling them. The
+/// verifier verifies them once, independently of their callers, so they
+/// keep large programs under the complexity limit and they can be replaced
+/// by `freplace` programs. In return, their arguments must be scalars or
+/// pointers to the context and they must return a scalar.
+///
+/// The function gets the C ABI and is placed in `.text` section, which
+/// cargo-bpf keeps from being inlined and redBPF links to the programs
+/// calling it.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[global_function]
+/// fn is_small(count: u64) -> i32 {
+///     (count < 64) as i32
+/// }
+///
+/// #[kprobe]
+/// fn vfs_read(regs: Registers) {
+///     if is_small(regs.parm3()) != 0 {
+///         bpf_trace_printk(b"small read\0");
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn global_function(attrs: TokenStream, item: TokenStream) -> TokenStream {
+    if !attrs.is_empty() {
+        panic!("global_function does not take arguments");
+    }
+    let mut item = parse_macro_input!(item as ItemFn);
+    if let ReturnType::Default = item.sig.output {
+        panic!("BPF global functions must return a scalar");
+    }
+    item.sig.abi = Some(parse_quote!(extern "C"));
+    let tokens = quote! {
+        #[no_mangle]
+        #[inline(never)]
+        #[link_section = ".text"]
+        #item
+    };
+
+    tokens.into()
+}
+
 /// Safe wrapper for bpf_trace_printk helper.
 ///
 /// Maximum three arguments are accepted, only one of
@@ -904,3 +1559,146 @@ fn parse_format_string(fmt: &str) -> Vec<FmtPlaceholder> {
     }
     res
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn link_section(tokens: TokenStream2) -> String {
+        let outer = syn::parse2::<ItemFn>(tokens).unwrap();
+        let section = outer
+            .attrs
+            .iter()
+            .find(|attr| attr.path.is_ident("link_section"))
+            .and_then(|attr| match attr.parse_meta() {
+                Ok(Meta::NameValue(nv)) => Some(nv.lit),
+                _ => None,
+            });
+        match section {
+            Some(Lit::Str(s)) => s.value(),
+            _ => panic!("link_section not found"),
+        }
+    }
+
+    #[test]
+    fn test_lsm_section_name() {
+        let tokens = lsm_impl(
+            quote!("file_open"),
+            quote! {
+                fn deny_open(file: *const c_void, ret: i32) -> i32 {
+                    ret
+                }
+            },
+        );
+        assert_eq!(link_section(tokens), "lsm/file_open/deny_open");
+    }
+
+    #[test]
+    fn test_raw_tracepoint_section_name() {
+        let tokens = raw_tracepoint_impl(
+            quote!("sys_enter"),
+            quote! {
+                fn count_syscalls(args: RawTracePointArgs) {}
+            },
+        );
+        assert_eq!(
+            link_section(tokens),
+            "raw_tracepoint/sys_enter/count_syscalls"
+        );
+    }
+
+    #[test]
+    fn test_cgroup_sock_section_name() {
+        let item = quote! {
+            fn on_bind(ctx: CgroupSockContext) -> CgroupAction {
+                CgroupAction::Allow
+            }
+        };
+        assert_eq!(
+            link_section(cgroup_sock_impl(quote!(), item.clone())),
+            "cgroup/sock/on_bind"
+        );
+        assert_eq!(
+            link_section(cgroup_sock_impl(quote!("post_bind4"), item)),
+            "cgroup/post_bind4/on_bind"
+        );
+    }
+
+    // The top-level statics emitted by #[map]
+    fn map_statics(tokens: TokenStream2) -> Vec<ItemStatic> {
+        syn::parse2::<syn::File>(tokens)
+            .unwrap()
+            .items
+            .into_iter()
+            .filter_map(|item| match item {
+                syn::Item::Static(item) => Some(item),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn map_flags(item: &ItemStatic) -> u32 {
+        match &*item.expr {
+            Expr::MethodCall(call) if call.method == "with_map_flags" => match call.args.first() {
+                Some(Expr::Lit(ExprLit {
+                    lit: Lit::Int(int), ..
+                })) => int.base10_parse().unwrap(),
+                _ => panic!("flags are not an integer"),
+            },
+            _ => panic!("with_map_flags is not called"),
+        }
+    }
+
+    #[test]
+    fn test_map_flags_and_pinning() {
+        let tokens = map_impl(
+            quote!(flags = "no_prealloc | mmapable", access = "read_only"),
+            quote! {
+                static mut counts: HashMap<u32, u64> = HashMap::with_max_entries(1024);
+            },
+        );
+        let statics = map_statics(tokens);
+        assert_eq!(statics.len(), 1);
+        assert_eq!(
+            map_flags(&statics[0]),
+            BPF_F_NO_PREALLOC | BPF_F_MMAPABLE | BPF_F_RDONLY_PROG
+        );
+
+        let tokens = map_impl(
+            quote!(flags = 2, pinned = "/sys/fs/bpf/lru"),
+            quote! {
+                static mut lru: LruHashMap<u32, u64> = LruHashMap::with_max_entries(1024);
+            },
+        );
+        let statics = map_statics(tokens);
+        assert_eq!(statics.len(), 2);
+        assert_eq!(map_flags(&statics[0]), BPF_F_NO_COMMON_LRU);
+        let pin = &statics[1];
+        assert_eq!(pin.ident, "MAP_PIN_lru");
+        let section = pin
+            .attrs
+            .iter()
+            .find(|attr| attr.path.is_ident("link_section"))
+            .map(|attr| attr.parse_meta().unwrap());
+        match section {
+            Some(Meta::NameValue(nv)) => {
+                assert!(matches!(nv.lit, Lit::Str(s) if s.value() == "maps_pin/lru"))
+            }
+            _ => panic!("link_section not found"),
+        }
+        let expected = b"/sys/fs/bpf/lru\0";
+        let (_, array_lit) = inline_bytes(expected[..expected.len() - 1].to_vec());
+        let pin_expr = &pin.expr;
+        assert_eq!(quote!(#pin_expr).to_string(), array_lit.to_string());
+
+        // without flags, the map is emitted as it is
+        let tokens = map_impl(
+            quote!(),
+            quote! {
+                static mut counts: PerfMap<u64> = PerfMap::with_max_entries(1024);
+            },
+        );
+        let statics = map_statics(tokens);
+        assert!(matches!(&*statics[0].expr, Expr::Call(_)));
+    }
+}
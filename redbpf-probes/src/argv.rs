@@ -0,0 +1,185 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Reading the command line arguments of processes
+
+The arguments given to `execve(2)` live in user memory as an array of
+pointers to strings. [`Argv`](struct.Argv.html) walks the array with a loop
+of a fixed number of iterations, so that the verifier accepts it, and copies
+the strings into one buffer. The buffer is usually sent to userspace as the
+last field of a perf event, emitting only the used part of it with
+[`PerfMap::insert_with_len`](../maps/struct.PerfMap.html#method.insert_with_len).
+Userspace decodes it with
+[`redbpf::argv::Argv`](../../redbpf/argv/struct.Argv.html).
+
+# Example
+
+```no_run
+#![no_std]
+#![no_main]
+use core::mem;
+use redbpf_probes::argv::Argv;
+use redbpf_probes::raw_tracepoint::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+// the syscall number of execve(2) on x86_64
+const NR_EXECVE: u64 = 59;
+
+#[repr(C)]
+pub struct ExecEvent {
+    pid: u64,
+    // must be the last field to emit only the used part of it
+    argv: Argv<16, 1024>,
+}
+
+#[map]
+static mut SCRATCH: PerCpuArray<ExecEvent> = PerCpuArray::with_max_entries(1);
+
+#[map]
+static mut EXECS: PerfMap<ExecEvent> = PerfMap::with_max_entries(1024);
+
+// `sys_enter` is called with `struct pt_regs *regs, long id`
+#[raw_tracepoint("sys_enter")]
+fn sys_enter(args: RawTracePointArgs) {
+    if args.arg(1) != NR_EXECVE {
+        return;
+    }
+    // the event is too large for the stack of BPF programs
+    let event = match unsafe { SCRATCH.get_mut(0) } {
+        Some(event) => event,
+        None => return,
+    };
+    event.pid = bpf_get_current_pid_tgid() >> 32;
+    // `regs` are the registers of the user process saved in kernel memory,
+    // so they are read with a helper. argv is the second argument of
+    // execve(2), i.e., `si` on x86_64
+    let regs = args.arg(0) as *const pt_regs;
+    let argv = match unsafe { bpf_probe_read(&(*regs).si) } {
+        Ok(argv) => argv as *const *const c_char,
+        Err(_) => return,
+    };
+    unsafe { event.argv.read(argv) };
+    let len = mem::size_of::<ExecEvent>() - event.argv.unused();
+    unsafe { EXECS.insert_with_len(args.ctx as *mut u64, event, len) };
+}
+```
+*/
+use core::mem::size_of;
+use core::ptr;
+use cty::*;
+
+use crate::helpers::gen::{bpf_probe_read_user, bpf_probe_read_user_str};
+
+/// Command line arguments read from user memory
+///
+/// Up to `MAX_ARGS` arguments are stored in `buf` one after another, each
+/// terminated by NUL. `SIZE` must be a power of two. An argument is read
+/// only if it starts in the first half of `buf` and at most `SIZE / 2` bytes
+/// of it are read, so that the verifier can tell that every read is within
+/// `buf`. Arguments that do not fit are dropped or cut and `truncated` is set.
+///
+/// `Argv` is as large as `buf`, so it should be put in a map such as
+/// `PerCpuArray` rather than on the stack.
+#[repr(C)]
+pub struct Argv<const MAX_ARGS: usize, const SIZE: usize> {
+    /// The number of arguments in `buf`
+    pub argc: u32,
+    /// The number of bytes of `buf` in use
+    pub len: u32,
+    /// Non-zero if some arguments were dropped or cut
+    pub truncated: u32,
+    pub buf: [u8; SIZE],
+}
+
+impl<const MAX_ARGS: usize, const SIZE: usize> Argv<MAX_ARGS, SIZE> {
+    const ARG_MAX: usize = SIZE / 2;
+    // masking offsets with `ARG_MAX - 1` keeps them in `buf` only if `SIZE`
+    // is a power of two. Referring to it fails the build otherwise
+    const SIZE_IS_POWER_OF_TWO: () = assert!(
+        SIZE >= 2 && SIZE.is_power_of_two(),
+        "SIZE of Argv must be a power of two"
+    );
+
+    /// Read the arguments of the NULL terminated array `argv` in user memory
+    ///
+    /// The previous contents are overwritten. Arguments are read until a
+    /// NULL pointer, `MAX_ARGS` arguments or the end of `buf`, whichever
+    /// comes first. An argument that can not be read, e.g., because it is
+    /// paged out, ends the reading too.
+    ///
+    /// # Safety
+    ///
+    /// `argv` must be a pointer in user memory. It is only read with BPF
+    /// helpers, so an invalid pointer makes the reading stop.
+    #[inline(always)]
+    pub unsafe fn read(&mut self, argv: *const *const c_char) {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE_IS_POWER_OF_TWO;
+        self.argc = 0;
+        self.len = 0;
+        self.truncated = 0;
+        // the loop of a constant count is unrolled or bounded
+        for i in 0..MAX_ARGS {
+            let arg = match read_user_ptr(argv.add(i)) {
+                Some(arg) => arg,
+                None => return,
+            };
+            let off = self.len as usize;
+            if off >= Self::ARG_MAX {
+                self.truncated = 1;
+                return;
+            }
+            // masking tells the verifier that `off` is less than ARG_MAX
+            let dst = self.buf.as_mut_ptr().add(off & (Self::ARG_MAX - 1));
+            let ret = bpf_probe_read_user_str(
+                dst as *mut c_void,
+                Self::ARG_MAX as u32,
+                arg as *const c_void,
+            );
+            if ret <= 0 {
+                self.truncated = 1;
+                return;
+            }
+            // the argument may be cut if it fills the read size
+            if ret as usize == Self::ARG_MAX {
+                self.truncated = 1;
+            }
+            self.len += ret as u32;
+            self.argc += 1;
+        }
+        if read_user_ptr(argv.add(MAX_ARGS)).is_some() {
+            self.truncated = 1;
+        }
+    }
+
+    /// Return the number of bytes at the end of `buf` not in use
+    ///
+    /// Subtract it from the size of the event that ends with `Argv` to emit
+    /// only the used part.
+    #[inline]
+    pub fn unused(&self) -> usize {
+        SIZE - self.len as usize
+    }
+}
+
+// Read a non-NULL pointer from user memory
+#[inline(always)]
+unsafe fn read_user_ptr(src: *const *const c_char) -> Option<*const c_char> {
+    let mut p: *const c_char = ptr::null();
+    let ret = bpf_probe_read_user(
+        &mut p as *mut _ as *mut c_void,
+        size_of::<*const c_char>() as u32,
+        src as *const c_void,
+    );
+    if ret < 0 || p.is_null() {
+        None
+    } else {
+        Some(p)
+    }
+}
@@ -0,0 +1,131 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+cgroup socket and packet BPF programs
+
+`cgroup/skb` programs are attached to a cgroup and are called for each packet
+received or sent by the sockets of the processes of the cgroup. The packet is
+given as a [`SkBuff`](../socket/struct.SkBuff.html) that starts at the
+network header.
+
+`cgroup/sock` programs are attached to a cgroup and are called when the
+processes of the cgroup create, release or bind sockets. The hook is chosen
+by the argument of the macro, e.g., `#[cgroup_sock("post_bind4")]`, and
+socket creation is the default.
+
+Both kinds of programs return a [`CgroupAction`](enum.CgroupAction.html) to
+allow or deny the packet or the operation.
+
+# Example
+
+Drop the packets sent to TCP port 23 by the processes of the cgroup:
+
+```no_run
+#![no_std]
+#![no_main]
+use memoffset::offset_of;
+use redbpf_probes::cgroup::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[cgroup_skb]
+fn block_telnet(skb: SkBuff) -> CgroupSkbResult {
+    let version: u8 = skb.load(0)?;
+    let ip_proto: u8 = skb.load(offset_of!(iphdr, protocol))?;
+    if version >> 4 != 4 || ip_proto as u32 != IPPROTO_TCP {
+        return Ok(CgroupAction::Allow);
+    }
+    let ihl = ((version & 0xf) as usize) * 4;
+    let dport: u16 = skb.load(ihl + offset_of!(tcphdr, dest))?;
+    if dport == 23 {
+        return Ok(CgroupAction::Deny);
+    }
+    Ok(CgroupAction::Allow)
+}
+```
+*/
+pub mod prelude;
+
+use crate::bindings::*;
+use crate::socket::SocketError;
+
+/// The verdict of cgroup programs
+pub enum CgroupAction {
+    /// Let the packet or the operation through
+    Allow,
+    /// Drop the packet or reject the operation with `EPERM`
+    Deny,
+}
+
+/// Result type of `cgroup/skb` programs
+///
+/// The packet is dropped when a program returns an error.
+pub type CgroupSkbResult = Result<CgroupAction, SocketError>;
+
+/// Context object provided to `cgroup/sock` programs
+pub struct CgroupSockContext {
+    /// The low level `bpf_sock` instance
+    pub sk: *mut bpf_sock,
+}
+
+impl CgroupSockContext {
+    /// Returns the address family of the socket, e.g., `AF_INET`
+    #[inline]
+    pub fn family(&self) -> u32 {
+        unsafe { (*self.sk).family }
+    }
+
+    /// Returns the type of the socket, e.g., `SOCK_STREAM`
+    #[inline]
+    pub fn sock_type(&self) -> u32 {
+        unsafe { (*self.sk).type_ }
+    }
+
+    /// Returns the protocol of the socket, e.g., `IPPROTO_TCP`
+    #[inline]
+    pub fn protocol(&self) -> u32 {
+        unsafe { (*self.sk).protocol }
+    }
+
+    /// Returns the mark of the socket
+    #[inline]
+    pub fn mark(&self) -> u32 {
+        unsafe { (*self.sk).mark }
+    }
+
+    /// Sets the mark of the socket. Only allowed at socket creation.
+    #[inline]
+    pub fn set_mark(&self, mark: u32) {
+        unsafe { (*self.sk).mark = mark }
+    }
+
+    /// Returns the index of the interface the socket is bound to
+    #[inline]
+    pub fn bound_dev_if(&self) -> u32 {
+        unsafe { (*self.sk).bound_dev_if }
+    }
+
+    /// Binds the socket to the interface at `ifindex`. Only allowed at socket
+    /// creation.
+    #[inline]
+    pub fn set_bound_dev_if(&self, ifindex: u32) {
+        unsafe { (*self.sk).bound_dev_if = ifindex }
+    }
+
+    /// Returns the bound IPv4 address in network byte order. Only available
+    /// after bind.
+    #[inline]
+    pub fn src_ip4(&self) -> u32 {
+        unsafe { (*self.sk).src_ip4 }
+    }
+
+    /// Returns the bound port in host byte order. Only available after bind.
+    #[inline]
+    pub fn src_port(&self) -> u32 {
+        unsafe { (*self.sk).src_port }
+    }
+}
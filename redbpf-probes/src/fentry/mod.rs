@@ -0,0 +1,54 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+Tracing kernel functions through BPF trampolines
+
+`fentry` and `fexit` programs are attached to the entry and the exit of a
+kernel function like kprobes, but the kernel calls them directly through a
+BPF trampoline, which has much less overhead. They take the arguments of the
+kernel function instead of registers, and `fexit` programs also take its
+return value as the last argument.
+
+They require vmlinux BTF and Linux 5.5 or newer. Tools running on older
+kernels can define a `kprobe` program doing the same in the same ELF file and
+let `redbpf::Module::attach_preferred` pick the one the kernel supports.
+
+# Example
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::fentry::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map]
+static mut UNLINKS: HashMap<u32, u64> = HashMap::with_max_entries(1024);
+
+#[fentry("do_unlinkat")]
+fn fentry_unlinkat(_dfd: i32, _name: *const c_void) {
+    count_unlink();
+}
+
+#[kprobe("do_unlinkat")]
+fn kprobe_unlinkat(_regs: Registers) {
+    count_unlink();
+}
+
+#[inline(always)]
+fn count_unlink() {
+    let tgid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    unsafe {
+        match UNLINKS.get_mut(&tgid) {
+            Some(count) => *count += 1,
+            None => UNLINKS.set(&tgid, &1),
+        }
+    }
+}
+```
+*/
+pub mod prelude;
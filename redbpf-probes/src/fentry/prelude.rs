@@ -0,0 +1,20 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+//! The fentry Prelude
+//!
+//! The purpose of this module is to alleviate imports of the common fentry
+//! and fexit types by adding a glob import to the top of fentry programs:
+//!
+//! ```
+//! use redbpf_probes::fentry::prelude::*;
+//! ```
+pub use crate::bindings::*;
+pub use crate::helpers::*;
+pub use crate::maps::*;
+pub use crate::registers::*;
+pub use cty::*;
+pub use redbpf_macros::{fentry, fexit, global_function, kprobe, kretprobe, map, printk, program};
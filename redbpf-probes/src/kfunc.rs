@@ -0,0 +1,57 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+/*!
+Kernel functions callable from BPF programs
+
+kfuncs are kernel functions that are exported to BPF programs. Unlike BPF
+helpers, they have no fixed ids. A call to a kfunc is compiled as a call to an
+undefined external function, and the loader of redBPF patches the call with
+the BTF id of the function in the BTF of the running kernel. So a kernel with
+`CONFIG_DEBUG_INFO_BTF` is required.
+
+kfuncs are declared by [`kfuncs!`](macro.kfuncs.html) with the signatures
+that the kernel defines. The kernel verifies the arguments against the BTF of
+the function when the program is loaded, and it only allows calling kfuncs
+that are registered for the type of the program.
+
+Calling kfuncs requires Linux 5.13 or later, the BPF JIT compiler and a GPL
+compatible license. kfuncs of loadable kernel modules are not supported.
+
+# Example
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::kfunc::kfuncs;
+use redbpf_probes::bindings::task_struct;
+
+kfuncs! {
+    fn bpf_task_acquire(p: *mut task_struct) -> *mut task_struct;
+    fn bpf_task_release(p: *mut task_struct);
+}
+```
+ */
+
+/// Declare kfuncs
+///
+/// Each function is declared as an `extern "C"` function so that calling it
+/// leaves a relocation against the undefined symbol of the function, which
+/// the loader resolves to the BTF id of the kernel function. Calling kfuncs
+/// is `unsafe` as calling any other external function.
+#[macro_export]
+macro_rules! kfuncs {
+    ($($(#[$attr:meta])* $vis:vis fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?;)*) => {
+        extern "C" {
+            $(
+                $(#[$attr])*
+                $vis fn $name($($arg: $ty),*) $(-> $ret)?;
+            )*
+        }
+    };
+}
+
+pub use crate::kfuncs;
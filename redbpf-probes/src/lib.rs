@@ -104,16 +104,28 @@ tries to probe vmlinux from the well-known system paths and uses it
 */
 #![deny(clippy::all)]
 #![no_std]
+pub mod argv;
 pub mod bindings;
 pub mod bpf_iter;
+pub mod cgroup;
+pub mod fentry;
 pub mod helpers;
+pub mod kfunc;
 pub mod kprobe;
+pub mod lsm;
 pub mod maps;
 pub mod net;
+#[cfg(feature = "panic-handler")]
+pub mod panic;
+pub mod perf_event;
+pub mod raw_tracepoint;
 pub mod registers;
+pub mod sk_reuseport;
 pub mod socket;
 pub mod socket_filter;
 pub mod sockmap;
+pub mod sockops;
+pub mod struct_ops;
 pub mod tc;
 pub mod uprobe;
 pub mod xdp;
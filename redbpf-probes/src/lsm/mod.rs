@@ -0,0 +1,44 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+Enforcing security policies with Linux Security Module hooks
+
+`lsm` programs are attached to the LSM hooks of the kernel, e.g.,
+`file_open`, through a BPF trampoline like `fentry` programs. They take the
+arguments of the hook followed by the return value of the previous program
+attached to the hook, and return 0 to allow the operation or a negative error
+number to deny it.
+
+They require vmlinux BTF and Linux 5.7 or newer built with `CONFIG_BPF_LSM`,
+and `bpf` must be one of the active LSMs given by the `lsm=` boot parameter.
+
+# Example
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::lsm::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map]
+static mut DENIED_TGIDS: HashMap<u32, u8> = HashMap::with_max_entries(1024);
+
+#[lsm("file_open")]
+fn deny_file_open(_file: *const c_void, ret: i32) -> i32 {
+    if ret != 0 {
+        return ret;
+    }
+    let tgid = (bpf_get_current_pid_tgid() >> 32) as u32;
+    match unsafe { DENIED_TGIDS.get(&tgid) } {
+        Some(_) => -1, // -EPERM
+        None => 0,
+    }
+}
+```
+*/
+pub mod prelude;
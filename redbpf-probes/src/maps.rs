@@ -21,6 +21,9 @@ use cty::*;
 use crate::bindings::*;
 use crate::helpers::*;
 
+/// `BPF_F_NO_PREALLOC` map flag
+const BPF_F_NO_PREALLOC: u32 = 1 << 0;
+
 macro_rules! define_hashmap {
     ($(#[$attr:meta])* $name:ident, $map_type:expr) => {
         $(#[$attr])*
@@ -46,6 +49,38 @@ macro_rules! define_hashmap {
                     _v: PhantomData,
                 }
             }
+
+            /// Adds `flags` to the flags of the map definition.
+            ///
+            /// This is used by `#[map(access = "...")]` to set
+            /// `BPF_F_RDONLY_PROG` or `BPF_F_WRONLY_PROG`.
+            pub const fn with_map_flags(mut self, flags: u32) -> Self {
+                self.def.map_flags |= flags;
+                self
+            }
+
+            /// Sets whether all elements of the map are allocated when the
+            /// map is created.
+            ///
+            /// Hash maps are preallocated by default. A preallocated map
+            /// takes memory for `max_entries` elements even if it is nearly
+            /// empty, but inserting never allocates memory, so it is fast and
+            /// does not fail for lack of memory. `with_prealloc(false)` sets
+            /// `BPF_F_NO_PREALLOC` so that elements are allocated on insert,
+            /// which saves memory for large and sparse maps at the cost of
+            /// slower inserts.
+            ///
+            /// LRU hash maps are always preallocated. The kernel and the
+            /// loader of redbpf reject LRU hash maps with `with_prealloc(false)`.
+            pub const fn with_prealloc(mut self, prealloc: bool) -> Self {
+                if prealloc {
+                    self.def.map_flags &= !BPF_F_NO_PREALLOC;
+                } else {
+                    self.def.map_flags |= BPF_F_NO_PREALLOC;
+                }
+                self
+            }
+
             /// Returns a reference to the value corresponding to the key.
             ///
             /// **CUATION** The value that the returned reference refers to is
@@ -172,6 +207,15 @@ macro_rules! define_array {
                 }
             }
 
+            /// Adds `flags` to the flags of the map definition.
+            ///
+            /// This is used by `#[map(access = "...")]` to set
+            /// `BPF_F_RDONLY_PROG` or `BPF_F_WRONLY_PROG`.
+            pub const fn with_map_flags(mut self, flags: u32) -> Self {
+                self.def.map_flags |= flags;
+                self
+            }
+
             /// Returns a reference to the value at `index`.
             #[inline]
             pub fn get(&mut self, index: u32) -> Option<&T> {
@@ -245,6 +289,15 @@ define_hashmap!(
     ///
     /// High level API of BPF_MAP_TYPE_LRU_HASH maps for BPF programs.
     ///
+    /// Unlike [`HashMap`](struct.HashMap.html), inserting into a full LRU
+    /// hash map does not fail. The kernel evicts the least recently used
+    /// entries to make room instead. Entries can also be evicted before the
+    /// map is full because the kernel keeps free entries in per-cpu lists
+    /// and steals entries from the global LRU list when the list of the
+    /// current CPU runs out. So `get` may return `None` even for a key that
+    /// was inserted a moment ago. Use this map for bounded caches where
+    /// losing old entries is acceptable, never as the only copy of data.
+    ///
     /// If you are looking for userspace API, see
     /// [`redbpf::LruHashMap`](../../redbpf/struct.LruHashMap.html) instead.
     LruHashMap,
@@ -255,6 +308,9 @@ define_hashmap!(
     ///
     /// High level API of BPF_MAP_TYPE_LRU_PERCPU_HASH maps for BPF programs.
     ///
+    /// Entries are evicted in the same way as
+    /// [`LruHashMap`](struct.LruHashMap.html).
+    ///
     /// If you are looking for userspace API, see
     /// [`redbpf::LruPerCpuHashMap`](../../redbpf/struct.LruPerCpuHashMap.html)
     /// instead.
@@ -262,6 +318,33 @@ define_hashmap!(
     bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH
 );
 
+/// `BPF_F_NO_COMMON_LRU` map flag
+const BPF_F_NO_COMMON_LRU: u32 = 1 << 1;
+
+impl<K, V> LruHashMap<K, V> {
+    /// Creates an LRU hash map that keeps a separate LRU list for each CPU.
+    ///
+    /// It sets `BPF_F_NO_COMMON_LRU` flag. Without this flag, all CPUs share
+    /// one LRU list which is protected by a lock. With this flag, there is
+    /// no lock contention, but each CPU can only evict entries from its own
+    /// list. So an entry can be evicted even when the map is far from full
+    /// if a CPU inserts many entries, and `max_entries` is effectively
+    /// divided among CPUs.
+    pub const fn with_max_entries_per_cpu_lru(max_entries: u32) -> Self {
+        Self::with_max_entries(max_entries).with_map_flags(BPF_F_NO_COMMON_LRU)
+    }
+}
+
+impl<K, V> LruPerCpuHashMap<K, V> {
+    /// Creates an LRU per-cpu hash map that keeps a separate LRU list for
+    /// each CPU.
+    ///
+    /// See [`LruHashMap::with_max_entries_per_cpu_lru`](struct.LruHashMap.html#method.with_max_entries_per_cpu_lru).
+    pub const fn with_max_entries_per_cpu_lru(max_entries: u32) -> Self {
+        Self::with_max_entries(max_entries).with_map_flags(BPF_F_NO_COMMON_LRU)
+    }
+}
+
 define_array!(
     /// BPF array map for BPF programs
     ///
@@ -390,6 +473,546 @@ impl<T> PerfMap<T> {
             mem::size_of::<T>() as u64,
         );
     }
+
+    /// Insert the first `len` bytes of a new event keyed by the current CPU
+    /// number.
+    ///
+    /// This emits events of variable length, e.g., an event that ends with a
+    /// buffer of which only a part is used. `len` is limited to the size of
+    /// `T`. Userspace receives `len` bytes, so it must not read the event as
+    /// `T` as it is.
+    #[inline]
+    pub fn insert_with_len<C>(&mut self, ctx: *mut C, data: &T, len: usize) {
+        let len = if len > mem::size_of::<T>() {
+            mem::size_of::<T>()
+        } else {
+            len
+        };
+        bpf_perf_event_output(
+            ctx as *mut _ as *mut c_void,
+            &mut self.def as *mut _ as *mut c_void,
+            PerfMapFlags::default().into(),
+            data as *const _ as *const c_void,
+            len as u64,
+        );
+    }
+
+    /// Insert a new event if `gate` allows emission.
+    ///
+    /// Returns `true` if the event is inserted. Otherwise the event is counted
+    /// as suppressed by `gate` and `false` is returned. See
+    /// [`FlowGate`](struct.FlowGate.html).
+    #[inline]
+    pub fn insert_gated<C>(&mut self, ctx: *mut C, data: &T, gate: &mut FlowGate) -> bool {
+        if !gate.should_emit() {
+            return false;
+        }
+        self.insert(ctx, data);
+        true
+    }
+}
+
+const FLOW_GATE_LEVEL: u32 = 0;
+const FLOW_GATE_SUPPRESSED: u32 = 1;
+
+/// Throttling level at which a [`FlowGate`](struct.FlowGate.html) suppresses
+/// all events.
+pub const FLOW_LEVEL_PAUSED: u64 = 32;
+
+/// Emission gate controlled by the userspace consumer of a perf map.
+///
+/// When the consumer can't keep up with the events, the kernel drops them
+/// silently. A `FlowGate` lets the consumer apply backpressure instead: it
+/// reports how far behind it is by setting a throttling level, and the BPF
+/// program checks the gate before emitting each event.
+///
+/// - level `0` emits all events
+/// - level `n` in `1..32` emits one out of `2^n` events on average
+/// - level [`FLOW_LEVEL_PAUSED`](constant.FLOW_LEVEL_PAUSED.html) suppresses
+///   all events
+///
+/// Suppressed events are counted per CPU so the consumer knows how many
+/// events it did not receive.
+///
+/// This is a `BPF_MAP_TYPE_PERCPU_ARRAY` with two `u64` entries: the level at
+/// index 0, which is written by userspace for every CPU, and the number of
+/// suppressed events at index 1, which is written by BPF programs only. So no
+/// atomic operation is needed.
+///
+/// For userspace API, see
+/// [`redbpf::backpressure::Backpressure`](../../redbpf/backpressure/struct.Backpressure.html)
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[map]
+/// static mut EVENTS: PerfMap<u64> = PerfMap::with_max_entries(1024);
+/// #[map]
+/// static mut EVENTS_GATE: FlowGate = FlowGate::new();
+///
+/// #[kprobe]
+/// fn vfs_read(regs: Registers) {
+///     let ts = bpf_ktime_get_ns();
+///     unsafe {
+///         EVENTS.insert_gated(regs.ctx, &ts, &mut EVENTS_GATE);
+///     }
+/// }
+/// ```
+#[repr(transparent)]
+pub struct FlowGate {
+    def: bpf_map_def,
+}
+
+impl FlowGate {
+    /// Creates a flow gate that initially emits all events.
+    pub const fn new() -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_PERCPU_ARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u64>() as u32,
+                max_entries: 2,
+                map_flags: 0,
+            },
+        }
+    }
+
+    #[inline]
+    fn entry(&mut self, index: u32) -> Option<&mut u64> {
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                &index as *const _ as *const c_void,
+            );
+            if value.is_null() {
+                None
+            } else {
+                Some(&mut *(value as *mut u64))
+            }
+        }
+    }
+
+    /// Returns the current throttling level.
+    #[inline]
+    pub fn level(&mut self) -> u64 {
+        self.entry(FLOW_GATE_LEVEL).map(|level| *level).unwrap_or(0)
+    }
+
+    /// Decides whether the next event should be emitted.
+    ///
+    /// If it returns `false`, the event is counted as suppressed.
+    #[inline]
+    pub fn should_emit(&mut self) -> bool {
+        let level = self.level();
+        let emit = if level == 0 {
+            true
+        } else if level >= FLOW_LEVEL_PAUSED {
+            false
+        } else {
+            let mask = (1u64 << level) - 1;
+            (bpf_get_prandom_u32() as u64) & mask == 0
+        };
+        if !emit {
+            if let Some(suppressed) = self.entry(FLOW_GATE_SUPPRESSED) {
+                *suppressed += 1;
+            }
+        }
+        emit
+    }
+}
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// Rate of a [`RateLimiter`](struct.RateLimiter.html)
+///
+/// `rate` events per second are allowed on average, and up to `burst` events
+/// are allowed at once after a quiet period.
+///
+/// This is usually stored in an [`Array`](struct.Array.html) map so that
+/// userspace can configure it, see
+/// [`redbpf::ratelimit::RateLimitConfig`](../../redbpf/ratelimit/struct.RateLimitConfig.html).
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct RateLimit {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+/// State of the token bucket of a key
+///
+/// Tokens are counted in nano-tokens, i.e., one event costs `10^9` units, so
+/// that refilling `rate` tokens per second is an integer multiplication per
+/// nanosecond.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct TokenBucket {
+    tokens: u64,
+    last_ns: u64,
+}
+
+impl TokenBucket {
+    /// Refill the bucket up to `now_ns` and take a token if there is one
+    ///
+    /// There are no loops and all arithmetic is saturating or bounded so the
+    /// verifier accepts it and it never overflows.
+    #[inline]
+    fn take(&mut self, now_ns: u64, limit: &RateLimit) -> bool {
+        let cap = limit.burst.saturating_mul(NSEC_PER_SEC);
+        // A clock going backwards, e.g. timestamps taken on another CPU
+        // before this one, refills nothing and does not move `last_ns` back,
+        // so the same period is never refilled twice.
+        let elapsed = now_ns.saturating_sub(self.last_ns);
+        if now_ns > self.last_ns {
+            self.last_ns = now_ns;
+        }
+        if limit.rate != 0 {
+            // Time to refill the whole bucket. Comparing elapsed time with it
+            // first keeps `elapsed * rate` below `cap`.
+            let fill_ns = cap / limit.rate;
+            if elapsed >= fill_ns {
+                self.tokens = cap;
+            } else {
+                self.tokens = self.tokens.saturating_add(elapsed * limit.rate);
+                if self.tokens > cap {
+                    self.tokens = cap;
+                }
+            }
+        }
+        if self.tokens >= NSEC_PER_SEC {
+            self.tokens -= NSEC_PER_SEC;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Token bucket rate limiter keyed by `K`
+///
+/// Each key has its own token bucket in an LRU hash map, so keys that have
+/// not been seen for a while are evicted when the map is full. An evicted key
+/// starts over with a full bucket.
+///
+/// A limiter created by [`with_max_entries`](#method.with_max_entries) shares
+/// a bucket per key between CPUs. Buckets are updated without locks, so
+/// events on different CPUs at the same moment can be allowed slightly more
+/// than the rate. A limiter created by
+/// [`per_cpu_with_max_entries`](#method.per_cpu_with_max_entries) has a
+/// bucket per key and CPU, which is exact but limits the rate of each CPU
+/// separately.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[map]
+/// static mut LIMIT: Array<RateLimit> = Array::with_max_entries(1);
+/// #[map]
+/// static mut LIMITER: RateLimiter<u32> = RateLimiter::with_max_entries(1024);
+/// #[map]
+/// static mut EVENTS: PerfMap<u32> = PerfMap::with_max_entries(1024);
+///
+/// #[kprobe]
+/// fn tcp_v4_connect(regs: Registers) {
+///     let pid = (bpf_get_current_pid_tgid() >> 32) as u32;
+///     unsafe {
+///         if let Some(limit) = LIMIT.get(0) {
+///             if LIMITER.allow(&pid, limit) {
+///                 EVENTS.insert(regs.ctx, &pid);
+///             }
+///         }
+///     }
+/// }
+/// ```
+#[repr(transparent)]
+pub struct RateLimiter<K> {
+    def: bpf_map_def,
+    _k: PhantomData<K>,
+}
+
+impl<K> RateLimiter<K> {
+    /// Creates a rate limiter that shares buckets between CPUs.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_LRU_HASH,
+                key_size: mem::size_of::<K>() as u32,
+                value_size: mem::size_of::<TokenBucket>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _k: PhantomData,
+        }
+    }
+
+    /// Creates a rate limiter that has buckets for each CPU.
+    pub const fn per_cpu_with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_LRU_PERCPU_HASH,
+                key_size: mem::size_of::<K>() as u32,
+                value_size: mem::size_of::<TokenBucket>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            _k: PhantomData,
+        }
+    }
+
+    /// Returns `true` if an event of `key` is allowed under `limit`.
+    ///
+    /// A token is taken from the bucket of `key` if the event is allowed.
+    #[inline]
+    pub fn allow(&mut self, key: &K, limit: &RateLimit) -> bool {
+        let now_ns = bpf_ktime_get_ns();
+        unsafe {
+            let value = bpf_map_lookup_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+            );
+            if !value.is_null() {
+                return (*(value as *mut TokenBucket)).take(now_ns, limit);
+            }
+            if limit.burst == 0 {
+                return false;
+            }
+            // a new key starts with a full bucket minus this event
+            let bucket = TokenBucket {
+                tokens: (limit.burst - 1).saturating_mul(NSEC_PER_SEC),
+                last_ns: now_ns,
+            };
+            bpf_map_update_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                key as *const _ as *const c_void,
+                &bucket as *const _ as *const c_void,
+                BPF_ANY.into(),
+            );
+            true
+        }
+    }
+}
+
+// Bloom filter maps were introduced at Linux 5.16
+const BPF_MAP_TYPE_BLOOM_FILTER: u32 = 30;
+
+/// Bloom filter map.
+///
+/// A probabilistic set of values without keys. [`peek`](#method.peek) may
+/// return false positives but never false negatives, so it is useful as a
+/// fast pre-filter before an expensive exact lookup. Values can be pushed but
+/// not removed. Requires Linux 5.16 or later.
+///
+/// The number of hash functions is set by
+/// [`with_nr_hashes`](#method.with_nr_hashes). More hash functions lower the
+/// false positive rate but make each operation slower.
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::xdp::prelude::*;
+///
+/// #[map]
+/// static mut BLOCKLIST: BloomFilter<u32> = BloomFilter::with_max_entries(10240).with_nr_hashes(3);
+/// #[map]
+/// static mut BLOCKED: HashMap<u32, u8> = HashMap::with_max_entries(10240);
+///
+/// #[xdp]
+/// fn block(ctx: XdpContext) -> XdpResult {
+///     let ip = unsafe { (*ctx.ip()?).saddr };
+///     unsafe {
+///         // skip the hash map lookup if the address is definitely not blocked
+///         if BLOCKLIST.peek(&ip) && BLOCKED.get(&ip).is_some() {
+///             return Ok(XdpAction::Drop);
+///         }
+///     }
+///     Ok(XdpAction::Pass)
+/// }
+/// ```
+#[repr(C)]
+pub struct BloomFilter<V> {
+    def: bpf_map_def,
+    // read by the loader of redbpf and passed to the kernel as `map_extra`
+    nr_hashes: u32,
+    _v: PhantomData<V>,
+}
+
+impl<V> BloomFilter<V> {
+    /// Creates a Bloom filter sized for `max_entries` values.
+    ///
+    /// The kernel uses 5 hash functions unless
+    /// [`with_nr_hashes`](#method.with_nr_hashes) is called.
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: BPF_MAP_TYPE_BLOOM_FILTER,
+                key_size: 0,
+                value_size: mem::size_of::<V>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            nr_hashes: 0,
+            _v: PhantomData,
+        }
+    }
+
+    /// Sets the number of hash functions, from 1 to 15.
+    pub const fn with_nr_hashes(mut self, nr_hashes: u32) -> Self {
+        self.nr_hashes = nr_hashes;
+        self
+    }
+
+    /// Adds `value` to the Bloom filter.
+    ///
+    /// Returns `false` if `value` could not be added.
+    #[inline]
+    pub fn push(&mut self, value: &V) -> bool {
+        unsafe {
+            bpf_map_push_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                value as *const _ as *const c_void,
+                BPF_ANY.into(),
+            ) == 0
+        }
+    }
+
+    /// Returns `true` if `value` may be in the Bloom filter.
+    ///
+    /// `false` is returned only if `value` has definitely not been pushed.
+    #[inline]
+    pub fn peek(&mut self, value: &V) -> bool {
+        unsafe {
+            bpf_map_peek_elem(
+                &mut self.def as *mut _ as *mut c_void,
+                value as *const _ as *mut c_void,
+            ) == 0
+        }
+    }
+}
+
+/// Ring buffer map.
+///
+/// A buffer shared by all CPUs that BPF programs write events to and
+/// userspace reads them from in the order they were committed. It costs less
+/// than `PerfMap` and needs no buffer per CPU. This is a wrapper for
+/// `BPF_MAP_TYPE_RINGBUF`, introduced at Linux 5.8.
+///
+/// For userspace API, see [`redbpf::RingBuf`](../../redbpf/struct.RingBuf.html)
+///
+/// # Example
+/// ```no_run
+/// use redbpf_probes::kprobe::prelude::*;
+///
+/// #[map]
+/// static mut EVENTS: RingBuf<u64> = RingBuf::with_max_entries(4096);
+///
+/// #[kprobe]
+/// fn do_sys_open(regs: Registers) {
+///     let pid_tgid = bpf_get_current_pid_tgid();
+///     unsafe { EVENTS.output(&pid_tgid) };
+/// }
+/// ```
+#[repr(transparent)]
+pub struct RingBuf<T> {
+    def: bpf_map_def,
+    _event: PhantomData<T>,
+}
+
+impl<T> RingBuf<T> {
+    /// Creates a ring buffer of `size` bytes.
+    ///
+    /// `size` must be a power of 2 multiple of the page size.
+    pub const fn with_max_entries(size: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_RINGBUF,
+                key_size: 0,
+                value_size: 0,
+                max_entries: size,
+                map_flags: 0,
+            },
+            _event: PhantomData,
+        }
+    }
+
+    /// Copies `data` to the ring buffer as a new record.
+    ///
+    /// Returns `false` if the buffer has no space left for it.
+    #[inline]
+    pub fn output(&mut self, data: &T) -> bool {
+        unsafe {
+            bpf_ringbuf_output(
+                &mut self.def as *mut _ as *mut c_void,
+                data as *const _ as *mut c_void,
+                mem::size_of::<T>() as u64,
+                0,
+            ) == 0
+        }
+    }
+
+    /// Reserves space of a new record to fill in place.
+    ///
+    /// The record is read by userspace after it is
+    /// [`submit`](struct.RingBufRecord.html#method.submit)ted. Dropping it
+    /// without submitting discards it. Returns `None` if the buffer has no
+    /// space left.
+    #[inline]
+    pub fn reserve(&mut self) -> Option<RingBufRecord<T>> {
+        let data = unsafe {
+            bpf_ringbuf_reserve(
+                &mut self.def as *mut _ as *mut c_void,
+                mem::size_of::<T>() as u64,
+                0,
+            )
+        };
+        if data.is_null() {
+            None
+        } else {
+            Some(RingBufRecord {
+                data: data as *mut T,
+            })
+        }
+    }
+}
+
+/// A record reserved in a [`RingBuf`](struct.RingBuf.html).
+pub struct RingBufRecord<T> {
+    data: *mut T,
+}
+
+impl<T> RingBufRecord<T> {
+    /// Commits the record so that userspace can read it.
+    #[inline]
+    pub fn submit(self) {
+        unsafe { bpf_ringbuf_submit(self.data as *mut c_void, 0) };
+        mem::forget(self);
+    }
+
+    /// Discards the record. Userspace skips it.
+    #[inline]
+    pub fn discard(self) {
+        unsafe { bpf_ringbuf_discard(self.data as *mut c_void, 0) };
+        mem::forget(self);
+    }
+}
+
+impl<T> core::ops::Deref for RingBufRecord<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<T> core::ops::DerefMut for RingBufRecord<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<T> Drop for RingBufRecord<T> {
+    fn drop(&mut self) {
+        unsafe { bpf_ringbuf_discard(self.data as *mut c_void, 0) };
+    }
 }
 
 // TODO Use PERF_MAX_STACK_DEPTH
@@ -546,3 +1169,108 @@ impl SockMap {
         }
     }
 }
+
+/// Socket array of `SO_REUSEPORT` groups.
+///
+/// The sockets of a `SO_REUSEPORT` group are put in the map by userspace and
+/// `sk_reuseport` programs select one of them to receive a new connection or
+/// datagram with
+/// [`SkReuseportContext::select_reuseport`](../sk_reuseport/struct.SkReuseportContext.html#method.select_reuseport).
+///
+/// The counterpart which is used by userspace is:
+/// [`redbpf::ReuseportSockArray`](../../redbpf/struct.ReuseportSockArray.html).
+pub struct ReuseportSockArray {
+    def: bpf_map_def,
+}
+
+impl ReuseportSockArray {
+    pub const fn with_max_entries(max_entries: u32) -> Self {
+        Self {
+            def: bpf_map_def {
+                type_: bpf_map_type_BPF_MAP_TYPE_REUSEPORT_SOCKARRAY,
+                key_size: mem::size_of::<u32>() as u32,
+                // 64-bit values let userspace read the socket cookies back
+                value_size: mem::size_of::<u64>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+        }
+    }
+
+    /// Select the socket at index `key` to receive the packet of `reuse`.
+    ///
+    /// The helper error code is returned if no socket is at `key` or the
+    /// socket does not belong to the `SO_REUSEPORT` group of the packet.
+    pub fn select(&mut self, reuse: *mut sk_reuseport_md, mut key: u32) -> Result<(), i64> {
+        let ret = unsafe {
+            bpf_sk_select_reuseport(
+                reuse,
+                &mut self.def as *mut _ as *mut c_void,
+                &mut key as *mut _ as *mut c_void,
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(ret as i64);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MSEC: u64 = 1_000_000;
+
+    #[test]
+    fn test_token_bucket_burst() {
+        let limit = RateLimit { rate: 10, burst: 3 };
+        let mut bucket = TokenBucket {
+            tokens: limit.burst * NSEC_PER_SEC,
+            last_ns: 0,
+        };
+        for _ in 0..3 {
+            assert!(bucket.take(0, &limit));
+        }
+        assert!(!bucket.take(0, &limit));
+    }
+
+    #[test]
+    fn test_token_bucket_refill() {
+        let limit = RateLimit { rate: 10, burst: 3 };
+        let mut bucket = TokenBucket {
+            tokens: 0,
+            last_ns: 0,
+        };
+        // a token is refilled every 100ms
+        assert!(!bucket.take(50 * MSEC, &limit));
+        assert!(bucket.take(100 * MSEC, &limit));
+        assert!(!bucket.take(150 * MSEC, &limit));
+
+        // a long quiet period refills no more than the burst
+        let now_ns = 3600 * 1000 * MSEC;
+        for _ in 0..3 {
+            assert!(bucket.take(now_ns, &limit));
+        }
+        assert!(!bucket.take(now_ns, &limit));
+
+        // the clock going backwards refills nothing, neither does catching
+        // up with the latest time again
+        assert!(!bucket.take(now_ns - 1000 * MSEC, &limit));
+        assert!(!bucket.take(now_ns, &limit));
+        assert!(!bucket.take(now_ns + 50 * MSEC, &limit));
+        assert!(bucket.take(now_ns + 100 * MSEC, &limit));
+    }
+
+    #[test]
+    fn test_token_bucket_zero_rate() {
+        let limit = RateLimit { rate: 0, burst: 1 };
+        let mut bucket = TokenBucket {
+            tokens: NSEC_PER_SEC,
+            last_ns: 0,
+        };
+        assert!(bucket.take(0, &limit));
+        assert!(!bucket.take(u64::MAX, &limit));
+    }
+}
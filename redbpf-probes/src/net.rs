@@ -57,6 +57,14 @@ pub enum NetworkError {
 
 pub type NetworkResult<T> = Result<T, NetworkError>;
 
+// the seed of `NetworkBuffer::flow_hash`
+const FLOW_HASH_SEED: u32 = 0x5bd1_e995;
+// offsets of the addresses in `ipv6hdr`
+const IPV6_SADDR_OFFSET: usize = 8;
+const IPV6_DADDR_OFFSET: usize = 24;
+// fragment offset bits of `iphdr.frag_off`
+const IP_OFFSET_MASK: u16 = 0x1fff;
+
 pub trait NetworkBuffer
 where
     Self: Clone + Sized,
@@ -153,6 +161,63 @@ where
         }
     }
 
+    /// Returns a hash of the packet's 5-tuple.
+    ///
+    /// The source and destination addresses, the protocol and, for TCP and
+    /// UDP, the ports are hashed, so all the packets of a flow get the same
+    /// hash. This is useful to pick a backend consistently in load balancers.
+    /// Both IPv4 and IPv6 are supported. The ports of other protocols, of
+    /// IPv4 fragments but the first one and of IPv6 packets with extension
+    /// headers are taken as 0.
+    ///
+    /// The hash is computed with a fixed number of steps, so it passes the
+    /// verifier. `NetworkError::NoIPHeader` is returned for non-IP packets.
+    #[inline]
+    fn flow_hash(&self) -> NetworkResult<u32> {
+        unsafe {
+            let eth = self.eth()?;
+            if (*eth).h_proto == u16::from_be(ETH_P_IP as u16) {
+                let ip: *const iphdr = self.ptr_after(eth)?;
+                let protocol = (*ip).protocol as u32;
+                let is_first_fragment = u16::from_be((*ip).frag_off) & IP_OFFSET_MASK == 0;
+                let ports = match protocol {
+                    IPPROTO_TCP | IPPROTO_UDP if is_first_fragment => {
+                        let addr = ip as usize + ((*ip).ihl() * 4) as usize;
+                        let ports: *const u32 = self.ptr_at(addr)?;
+                        ports.read_unaligned()
+                    }
+                    _ => 0,
+                };
+                Ok(hash_words(
+                    &[(*ip).saddr, (*ip).daddr, ports, protocol],
+                    FLOW_HASH_SEED,
+                ))
+            } else if (*eth).h_proto == u16::from_be(ETH_P_IPV6 as u16) {
+                let ip6: *const ipv6hdr = self.ptr_after(eth)?;
+                let protocol = (*ip6).nexthdr as u32;
+                let ports = match protocol {
+                    IPPROTO_TCP | IPPROTO_UDP => {
+                        let ports: *const u32 = self.ptr_after(ip6)?;
+                        ports.read_unaligned()
+                    }
+                    _ => 0,
+                };
+                let saddr = (ip6 as usize + IPV6_SADDR_OFFSET) as *const [u32; 4];
+                let daddr = (ip6 as usize + IPV6_DADDR_OFFSET) as *const [u32; 4];
+                let (saddr, daddr) = (saddr.read_unaligned(), daddr.read_unaligned());
+                Ok(hash_words(
+                    &[
+                        saddr[0], saddr[1], saddr[2], saddr[3], daddr[0], daddr[1], daddr[2],
+                        daddr[3], ports, protocol,
+                    ],
+                    FLOW_HASH_SEED,
+                ))
+            } else {
+                Err(NetworkError::NoIPHeader)
+            }
+        }
+    }
+
     /// Returns the packet's data starting after the transport headers.
     #[inline]
     fn data(&self) -> NetworkResult<Data<Self>> {
@@ -175,6 +240,26 @@ where
     }
 }
 
+// MurmurHash3 of 32-bit words
+#[inline(always)]
+fn hash_words(words: &[u32], seed: u32) -> u32 {
+    let mut h = seed;
+    for word in words {
+        let k = word
+            .wrapping_mul(0xcc9e_2d51)
+            .rotate_left(15)
+            .wrapping_mul(0x1b87_3593);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    h ^= (words.len() * 4) as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
+}
+
 /// Data type returned by calling `NetworkBuffer::data()`
 pub struct Data<T: NetworkBuffer> {
     ctx: T,
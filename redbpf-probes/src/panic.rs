@@ -0,0 +1,51 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+Panic handler that reports the panic location
+
+This module is enabled by the `panic-handler` feature. Then the
+[`program!`](../../redbpf_macros/macro.program.html) macro does not define
+its own panic handler and the handler of this module is used instead.
+
+When a BPF program panics, the handler prints the line and the column where
+the panic occurred to `/sys/kernel/debug/tracing/trace_pipe`, e.g.,
+
+```text
+redbpf panic at line 42 column 13
+```
+
+The handler must pass the BPF verifier so it can not loop, neither waiting
+forever nor walking the file name whose length is unknown to the verifier.
+That is why only the line and the column are printed. After printing, the
+program ends up in unreachable code which the compiler turns into the end of
+the program.
+*/
+use core::panic::PanicInfo;
+
+use crate::helpers::bpf_trace_printk_raw;
+
+#[panic_handler]
+#[no_mangle]
+pub fn rust_begin_panic(info: &PanicInfo) -> ! {
+    match info.location() {
+        Some(location) => {
+            let fmt = *b"redbpf panic at line %u column %u\n\0";
+            let _ = bpf_trace_printk_raw(
+                &fmt,
+                location.line() as u64,
+                location.column() as u64,
+                0,
+            );
+        }
+        None => {
+            let fmt = *b"redbpf panic\n\0";
+            let _ = bpf_trace_printk_raw(&fmt, 0, 0, 0);
+        }
+    }
+
+    unsafe { core::hint::unreachable_unchecked() }
+}
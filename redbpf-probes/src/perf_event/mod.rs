@@ -0,0 +1,80 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+Perf event sampling
+
+`perf_event` programs are called whenever a perf event overflows, e.g., every
+time a CPU clock sampling at 99Hz fires on a CPU. They take the registers of
+the interrupted context, so sampling profilers use them to collect the stack
+traces that flamegraphs are built from.
+
+The event is chosen by userspace when the program is attached, see
+[`redbpf::PerfEvent::attach_sampler`](../../redbpf/struct.PerfEvent.html#method.attach_sampler).
+
+# Example
+
+Count the kernel stacks that CPUs are sampled in:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::perf_event::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map]
+static mut STACKS: StackTrace = StackTrace::with_max_entries(10240);
+
+#[map]
+static mut COUNTS: HashMap<i64, u64> = HashMap::with_max_entries(10240);
+
+#[perf_event]
+fn sample_stack(ctx: PerfEventContext) {
+    if let Ok(stack_id) = unsafe { STACKS.stack_id(ctx.ctx as _, 0) } {
+        unsafe {
+            match COUNTS.get_mut(&stack_id) {
+                Some(count) => *count += 1,
+                None => COUNTS.set(&stack_id, &1),
+            }
+        }
+    }
+}
+```
+*/
+pub mod prelude;
+
+use crate::bindings::*;
+use crate::registers::Registers;
+
+/// Context object provided to `perf_event` programs
+pub struct PerfEventContext {
+    /// The low level `bpf_perf_event_data` instance
+    pub ctx: *mut bpf_perf_event_data,
+}
+
+impl PerfEventContext {
+    /// Return the registers of the context the event interrupted
+    #[inline]
+    pub fn regs(&self) -> Registers {
+        // `regs` is the first member of `bpf_perf_event_data`
+        Registers::from(self.ctx as *mut cty::c_void)
+    }
+
+    /// Return the sample period, i.e., how many times the event occurred
+    /// since the last sample
+    #[inline]
+    pub fn sample_period(&self) -> u64 {
+        unsafe { (*self.ctx).sample_period }
+    }
+
+    /// Return the address of the sample if the event has one, e.g., the
+    /// data address of a memory access
+    #[inline]
+    pub fn addr(&self) -> u64 {
+        unsafe { (*self.ctx).addr }
+    }
+}
@@ -0,0 +1,69 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+Raw tracepoints
+
+Raw tracepoint programs are attached to kernel tracepoints like `tracepoint`
+programs, but they take the raw arguments the tracepoint is called with
+instead of the formatted tracepoint record. This saves formatting the record
+on every event, which matters for hot tracepoints such as `sched_switch` or
+`sys_enter`.
+
+The arguments are those of the `TP_PROTO` of the tracepoint, e.g.,
+`struct pt_regs *regs, long id` for `sys_enter`. They are read with
+[`RawTracePointArgs::arg`](struct.RawTracePointArgs.html#method.arg). Raw
+tracepoints require Linux 4.17 or newer.
+
+# Example
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::raw_tracepoint::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[map]
+static mut SYSCALLS: HashMap<u64, u64> = HashMap::with_max_entries(1024);
+
+#[raw_tracepoint("sys_enter")]
+fn sys_enter(args: RawTracePointArgs) {
+    let id = args.arg(1);
+    unsafe {
+        match SYSCALLS.get_mut(&id) {
+            Some(count) => *count += 1,
+            None => SYSCALLS.set(&id, &1),
+        }
+    }
+}
+```
+*/
+pub mod prelude;
+
+/// The raw arguments of a tracepoint
+#[derive(Copy, Clone)]
+pub struct RawTracePointArgs {
+    pub ctx: *const u64,
+}
+
+impl From<*const u64> for RawTracePointArgs {
+    #[inline]
+    fn from(ctx: *const u64) -> RawTracePointArgs {
+        RawTracePointArgs { ctx }
+    }
+}
+
+impl RawTracePointArgs {
+    /// Return the `n`th argument of the tracepoint
+    ///
+    /// The verifier rejects reading beyond the number of the arguments of
+    /// the tracepoint when the program is attached.
+    #[inline(always)]
+    pub fn arg(&self, n: usize) -> u64 {
+        unsafe { *self.ctx.add(n) }
+    }
+}
@@ -10,6 +10,14 @@
 use crate::bindings::*;
 use cty::*;
 
+/// The number of function parameters passed in registers
+#[cfg(target_arch = "x86_64")]
+pub const MAX_REG_ARGS: usize = 6;
+
+/// The number of function parameters passed in registers
+#[cfg(target_arch = "aarch64")]
+pub const MAX_REG_ARGS: usize = 8;
+
 #[derive(Copy, Clone)]
 pub struct Registers {
     pub ctx: *mut pt_regs,
@@ -102,6 +110,68 @@ impl Registers {
         }
     }
 
+    /// Sixth parameter to the function
+    #[inline]
+    pub fn parm6(&self) -> u64 {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            (*self.ctx).r9
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            (*self.ctx).__bindgen_anon_1.user_regs.regs[5]
+        }
+    }
+
+    /// The `n`th parameter to the function, counting from zero
+    ///
+    /// This maps `n` to the register of the calling convention of the target
+    /// architecture, so probes using it are portable:
+    ///
+    /// | n | x86_64 | aarch64 |
+    /// |---|--------|---------|
+    /// | 0 | `di`   | `x0`    |
+    /// | 1 | `si`   | `x1`    |
+    /// | 2 | `dx`   | `x2`    |
+    /// | 3 | `cx`   | `x3`    |
+    /// | 4 | `r8`   | `x4`    |
+    /// | 5 | `r9`   | `x5`    |
+    /// | 6 |        | `x6`    |
+    /// | 7 |        | `x7`    |
+    ///
+    /// Returns `None` if the parameter is not passed in a register, i.e. `n`
+    /// is greater than or equal to [`MAX_REG_ARGS`](constant.MAX_REG_ARGS.html).
+    /// Such parameters are passed on the stack.
+    ///
+    /// The return value of a function is read by [`rc`](#method.rc) in
+    /// kretprobes.
+    #[inline]
+    pub fn arg(&self, n: usize) -> Option<u64> {
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            let regs = &*self.ctx;
+            match n {
+                0 => Some(regs.di),
+                1 => Some(regs.si),
+                2 => Some(regs.dx),
+                3 => Some(regs.cx),
+                4 => Some(regs.r8),
+                5 => Some(regs.r9),
+                _ => None,
+            }
+        }
+
+        #[cfg(target_arch = "aarch64")]
+        unsafe {
+            if n < MAX_REG_ARGS {
+                Some((*self.ctx).__bindgen_anon_1.user_regs.regs[n])
+            } else {
+                None
+            }
+        }
+    }
+
     /// Procedure link pointer (return to this IP)
     #[inline]
     pub fn ret(&self) -> u64 {
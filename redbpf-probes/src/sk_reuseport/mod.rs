@@ -0,0 +1,114 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+`SO_REUSEPORT` socket selection BPF programs
+
+`sk_reuseport` programs are attached to a group of sockets bound to the same
+address with `SO_REUSEPORT`. They are called for each new connection or
+datagram of the group and select the socket that receives it from a
+[`ReuseportSockArray`](../maps/struct.ReuseportSockArray.html), e.g., to
+steer the packets of a flow to the same worker process. If no socket is
+selected, the kernel picks one by hash as usual.
+
+# Example
+
+Steer packets to the sockets of workers by the flow hash:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::sk_reuseport::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+const WORKERS: u32 = 4;
+
+#[map]
+static mut WORKER_SOCKETS: ReuseportSockArray = ReuseportSockArray::with_max_entries(WORKERS);
+
+#[sk_reuseport]
+fn select_worker(ctx: SkReuseportContext) -> SkReuseportResult {
+    let index = ctx.hash() % WORKERS;
+    unsafe { ctx.select_reuseport(&mut WORKER_SOCKETS, index)? };
+    Ok(SkReuseportAction::Pass)
+}
+```
+*/
+pub mod prelude;
+
+use crate::bindings::*;
+use crate::maps::ReuseportSockArray;
+
+/// The verdict of `sk_reuseport` programs
+pub enum SkReuseportAction {
+    /// Deliver the packet to the selected socket, or to the socket picked by
+    /// the kernel if none is selected
+    Pass,
+    /// Drop the packet
+    Drop,
+}
+
+/// Errors in `sk_reuseport` programs
+///
+/// The packet is dropped when a program returns an error.
+pub enum SkReuseportError {
+    /// A BPF helper returned the error code
+    Helper(i64),
+}
+
+/// Result type of `sk_reuseport` programs
+pub type SkReuseportResult = Result<SkReuseportAction, SkReuseportError>;
+
+/// Context object provided to `sk_reuseport` programs
+pub struct SkReuseportContext {
+    /// The low level `sk_reuseport_md` instance
+    pub ctx: *mut sk_reuseport_md,
+}
+
+impl SkReuseportContext {
+    /// Returns the length of the packet
+    #[inline]
+    pub fn len(&self) -> u32 {
+        unsafe { (*self.ctx).len }
+    }
+
+    /// Returns the ethernet protocol of the packet in network byte order
+    #[inline]
+    pub fn eth_protocol(&self) -> u32 {
+        unsafe { (*self.ctx).eth_protocol }
+    }
+
+    /// Returns the IP protocol of the packet, e.g., `IPPROTO_TCP`
+    #[inline]
+    pub fn ip_protocol(&self) -> u32 {
+        unsafe { (*self.ctx).ip_protocol }
+    }
+
+    /// Returns whether the sockets of the group are bound to a wildcard
+    /// address
+    #[inline]
+    pub fn bind_inany(&self) -> bool {
+        unsafe { (*self.ctx).bind_inany != 0 }
+    }
+
+    /// Returns the hash of the packet computed by the kernel
+    #[inline]
+    pub fn hash(&self) -> u32 {
+        unsafe { (*self.ctx).hash }
+    }
+
+    /// Selects the socket at `index` of `map` to receive the packet
+    #[inline]
+    pub fn select_reuseport(
+        &self,
+        map: &mut ReuseportSockArray,
+        index: u32,
+    ) -> Result<(), SkReuseportError> {
+        map.select(self.ctx, index)
+            .map_err(SkReuseportError::Helper)
+    }
+}
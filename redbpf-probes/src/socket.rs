@@ -1,7 +1,11 @@
 //! Socket related type and functions
 
 use crate::bindings::*;
-use crate::helpers::bpf_skb_load_bytes;
+use crate::helpers::{
+    bpf_get_hash_recalc, bpf_skb_load_bytes, bpf_skb_pull_data, bpf_skb_vlan_pop, bpf_skb_vlan_push,
+};
+use crate::net::{NetworkBuffer, NetworkError, NetworkResult, Transport};
+use core::cmp;
 use core::mem::{size_of, MaybeUninit};
 
 pub trait FromBe {
@@ -26,9 +30,14 @@ impl_from_be!(u32);
 pub enum SocketError {
     /// Loading data from the socket buffer failed.
     LoadFailed,
+    /// Pulling non-linear data into the linear portion of the socket buffer
+    /// failed.
+    PullFailed,
     /// Error in parsing inside a stream parser. The TCP stream is
     /// unrecoverable.
     ParserError,
+    /// Pushing or popping a VLAN tag failed.
+    VlanFailed,
 }
 
 /// SkAction is returned by verdict eBPF programs.
@@ -38,6 +47,7 @@ pub enum SkAction {
 }
 
 /// Context object provided to Socket-related programs.
+#[derive(Clone)]
 pub struct SkBuff {
     /// The low level skb instance.
     pub skb: *const __sk_buff,
@@ -84,4 +94,150 @@ impl SkBuff {
             Ok(data.assume_init().from_be())
         }
     }
+
+    /// Returns the index of the interface the packet was received on.
+    ///
+    /// It is 0 for packets that are being transmitted, e.g., in tc egress
+    /// programs. Unlike packet data, the index is in host byte order.
+    #[inline]
+    pub fn ingress_ifindex(&self) -> u32 {
+        unsafe { (*self.skb).ingress_ifindex }
+    }
+
+    /// Returns the kernel's flow hash of the packet.
+    ///
+    /// The hash is recalculated by `bpf_get_hash_recalc` if it is not set
+    /// yet, e.g., by the NIC. Unlike
+    /// [`NetworkBuffer::flow_hash`](../net/trait.NetworkBuffer.html#method.flow_hash),
+    /// it does not depend on the linear portion of the packet.
+    #[inline]
+    pub fn hash_recalc(&self) -> u32 {
+        unsafe { bpf_get_hash_recalc(self.skb as *mut _) as u32 }
+    }
+
+    /// Returns true if the packet carries a VLAN tag that was stripped into
+    /// the socket buffer's metadata, e.g., by hardware offload.
+    ///
+    /// Such a tag is not part of the packet data, so it is read by
+    /// [`vlan_tci`](#method.vlan_tci) instead of parsing the ethernet
+    /// header.
+    #[inline]
+    pub fn vlan_present(&self) -> bool {
+        unsafe { (*self.skb).vlan_present != 0 }
+    }
+
+    /// Returns the tag control information of the VLAN tag in the socket
+    /// buffer's metadata, in host byte order.
+    ///
+    /// The lower 12 bits are the VLAN id. It is meaningful only if
+    /// [`vlan_present`](#method.vlan_present) returns true.
+    #[inline]
+    pub fn vlan_tci(&self) -> u16 {
+        unsafe { (*self.skb).vlan_tci as u16 }
+    }
+
+    /// Pushes a VLAN tag onto the packet.
+    ///
+    /// `vlan_proto` is the protocol of the tag, either `ETH_P_8021Q` or
+    /// `ETH_P_8021AD`, and `vlan_tci` is its tag control information. Both
+    /// are in host byte order and converted to network byte order here.
+    ///
+    /// Like [`pull_data`](#method.pull_data), this invalidates all pointers
+    /// into the packet obtained before. This is available to tc programs
+    /// only.
+    #[inline]
+    pub fn vlan_push(&self, vlan_proto: u16, vlan_tci: u16) -> Result<(), SocketError> {
+        let ret = unsafe { bpf_skb_vlan_push(self.skb as *mut _, vlan_proto.to_be(), vlan_tci) };
+        if ret < 0 {
+            return Err(SocketError::VlanFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Pops the outermost VLAN tag of the packet.
+    ///
+    /// Like [`pull_data`](#method.pull_data), this invalidates all pointers
+    /// into the packet obtained before. This is available to tc programs
+    /// only.
+    #[inline]
+    pub fn vlan_pop(&self) -> Result<(), SocketError> {
+        let ret = unsafe { bpf_skb_vlan_pop(self.skb as *mut _) };
+        if ret < 0 {
+            return Err(SocketError::VlanFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Pulls non-linear data of the socket buffer into its linear portion.
+    ///
+    /// Packets that are fragmented or aggregated by GRO can have only a part
+    /// of their data in the linear portion, and direct packet access beyond
+    /// it fails the bounds checks. This calls `bpf_skb_pull_data` so that the
+    /// first `len` bytes become directly accessible. If `len` is 0, the
+    /// whole packet is pulled.
+    ///
+    /// Pulling invalidates all pointers into the packet obtained before. So
+    /// pointers returned by [`NetworkBuffer`](../net/trait.NetworkBuffer.html)
+    /// methods must be acquired again after calling this method.
+    ///
+    /// This is available to tc and `sk_skb` programs only.
+    #[inline]
+    pub fn pull_data(&self, len: u32) -> Result<(), SocketError> {
+        let ret = unsafe { bpf_skb_pull_data(self.skb as *mut _, len) };
+        if ret < 0 {
+            return Err(SocketError::PullFailed);
+        }
+
+        Ok(())
+    }
+
+    /// Makes sure that the first `len` bytes of the packet are in the linear
+    /// portion.
+    ///
+    /// `bpf_skb_pull_data` is called only when the linear portion is shorter
+    /// than `len`. `len` is capped to the length of the packet.
+    #[inline]
+    pub fn ensure_linear(&self, len: usize) -> Result<(), SocketError> {
+        let len = cmp::min(len, unsafe { (*self.skb).len } as usize);
+        if NetworkBuffer::len(self) >= len {
+            return Ok(());
+        }
+
+        self.pull_data(len as u32)
+    }
+
+    /// Returns the packet's transport header after pulling all of the
+    /// headers into the linear portion.
+    ///
+    /// This is the same as
+    /// [`NetworkBuffer::transport`](../net/trait.NetworkBuffer.html#method.transport)
+    /// except that it works on non-linear socket buffers too.
+    #[inline]
+    pub fn transport_linear(&self) -> NetworkResult<Transport> {
+        // ethernet header + maximum IP header + maximum TCP header
+        const MAX_HEADERS_LEN: usize = size_of::<ethhdr>() + 60 + 60;
+        self.ensure_linear(MAX_HEADERS_LEN)
+            .map_err(|_| NetworkError::Other)?;
+        // data pointers are read from `__sk_buff` again here
+        self.transport()
+    }
+}
+
+/// Direct packet access to socket buffers.
+///
+/// It is available to tc and `sk_skb` programs but not to socket filters.
+/// Only the linear portion of the socket buffer is accessible, see
+/// [`SkBuff::pull_data`](struct.SkBuff.html#method.pull_data).
+impl NetworkBuffer for SkBuff {
+    #[inline]
+    fn data_start(&self) -> usize {
+        unsafe { (*self.skb).data as usize }
+    }
+
+    #[inline]
+    fn data_end(&self) -> usize {
+        unsafe { (*self.skb).data_end as usize }
+    }
 }
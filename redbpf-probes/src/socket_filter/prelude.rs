@@ -18,4 +18,4 @@ pub use crate::maps::*;
 pub use crate::socket::*;
 pub use crate::socket_filter::*;
 pub use cty::*;
-pub use redbpf_macros::{map, printk, program, socket_filter};
+pub use redbpf_macros::{global_function, map, printk, program, socket_filter};
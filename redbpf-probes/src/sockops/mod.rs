@@ -0,0 +1,285 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+Socket operations BPF programs
+
+`sockops` programs are attached to a cgroup and are called at various points
+of the lifecycle of TCP connections made by the processes of the cgroup. The
+operation that triggers the call is given by
+[`SockOpsContext::op`](struct.SockOpsContext.html#method.op). Programs can
+tune the connection by replying to the operation or by setting socket
+options.
+
+# Example
+
+Set the initial congestion window of connections to 40 segments:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::sockops::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[sockops]
+fn tune_iw(ctx: SockOpsContext) -> SockOpsResult {
+    match ctx.op() {
+        SockOp::ActiveEstablishedCb | SockOp::PassiveEstablishedCb => {
+            let mut iw: i32 = 40;
+            ctx.setsockopt(SOL_TCP, TCP_BPF_IW, &mut iw)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+```
+*/
+pub mod prelude;
+
+use core::mem::{size_of, MaybeUninit};
+use cty::*;
+
+use crate::bindings::*;
+use crate::helpers::{bpf_getsockopt, bpf_setsockopt, bpf_sock_ops_cb_flags_set};
+
+/// `SOL_TCP` level of socket options
+pub const SOL_TCP: c_int = 6;
+/// Set the initial congestion window. It is accepted only before any data is
+/// sent.
+pub const TCP_BPF_IW: c_int = 1001;
+/// Clamp the send congestion window (`snd_cwnd`) of the socket. It also sets
+/// the slow start threshold to the clamp.
+pub const TCP_BPF_SNDCWND_CLAMP: c_int = 1002;
+
+/// Operation that triggered a `sockops` program
+///
+/// The values correspond to `BPF_SOCK_OPS_*` of the kernel.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SockOp {
+    /// Should never be seen
+    Void,
+    /// Asks for the SYN retransmission timeout in jiffies
+    TimeoutInit,
+    /// Asks for the initial advertised window in packets
+    RwndInit,
+    /// Called before an active connection is initialized
+    TcpConnectCb,
+    /// Called when an active connection is established
+    ActiveEstablishedCb,
+    /// Called when a passive connection is established
+    PassiveEstablishedCb,
+    /// Asks whether the connection should use ECN
+    NeedsEcn,
+    /// Asks for the base RTT
+    BaseRtt,
+    /// Called when a RTO fires. Enabled by `BPF_SOCK_OPS_RTO_CB_FLAG`
+    RtoCb,
+    /// Called when a packet is retransmitted. Enabled by
+    /// `BPF_SOCK_OPS_RETRANS_CB_FLAG`
+    RetransCb,
+    /// Called when the TCP state changes. Enabled by
+    /// `BPF_SOCK_OPS_STATE_CB_FLAG`
+    StateCb,
+    /// Called on `listen(2)`
+    TcpListenCb,
+    /// Called on every RTT. Enabled by `BPF_SOCK_OPS_RTT_CB_FLAG`
+    RttCb,
+    /// Called to parse TCP header options
+    ParseHdrOptCb,
+    /// Asks for the length of TCP header options to reserve
+    HdrOptLenCb,
+    /// Called to write TCP header options
+    WriteHdrOptCb,
+    /// An operation unknown to redBPF
+    Unknown(u32),
+}
+
+impl From<u32> for SockOp {
+    fn from(op: u32) -> SockOp {
+        use SockOp::*;
+        match op {
+            0 => Void,
+            1 => TimeoutInit,
+            2 => RwndInit,
+            3 => TcpConnectCb,
+            4 => ActiveEstablishedCb,
+            5 => PassiveEstablishedCb,
+            6 => NeedsEcn,
+            7 => BaseRtt,
+            8 => RtoCb,
+            9 => RetransCb,
+            10 => StateCb,
+            11 => TcpListenCb,
+            12 => RttCb,
+            13 => ParseHdrOptCb,
+            14 => HdrOptLenCb,
+            15 => WriteHdrOptCb,
+            _ => Unknown(op),
+        }
+    }
+}
+
+/// Flags for [`SockOpsContext::set_cb_flags`](struct.SockOpsContext.html#method.set_cb_flags)
+pub mod cb_flags {
+    pub const RTO: u32 = 1 << 0;
+    pub const RETRANS: u32 = 1 << 1;
+    pub const STATE: u32 = 1 << 2;
+    pub const RTT: u32 = 1 << 3;
+    pub const PARSE_ALL_HDR_OPT: u32 = 1 << 4;
+    pub const PARSE_UNKNOWN_HDR_OPT: u32 = 1 << 5;
+    pub const WRITE_HDR_OPT: u32 = 1 << 6;
+}
+
+/// Errors in `sockops` programs
+pub enum SockOpsError {
+    /// A BPF helper returned the error code
+    Helper(i64),
+}
+
+/// Result type of `sockops` programs
+pub type SockOpsResult = Result<(), SockOpsError>;
+
+/// Context object provided to `sockops` programs
+pub struct SockOpsContext {
+    /// The low level `bpf_sock_ops` instance
+    pub ctx: *mut bpf_sock_ops,
+}
+
+impl SockOpsContext {
+    /// Returns the operation that triggered the program
+    #[inline]
+    pub fn op(&self) -> SockOp {
+        SockOp::from(unsafe { (*self.ctx).op })
+    }
+
+    /// Returns the arguments of the operation. Their meanings depend on the
+    /// operation, e.g., old and new states for `SockOp::StateCb`.
+    #[inline]
+    pub fn args(&self) -> [u32; 4] {
+        unsafe { (*self.ctx).__bindgen_anon_1.args }
+    }
+
+    /// Sets the reply to the operation, e.g., the timeout for
+    /// `SockOp::TimeoutInit`.
+    #[inline]
+    pub fn set_reply(&self, reply: u32) {
+        unsafe {
+            (*self.ctx).__bindgen_anon_1.reply = reply;
+        }
+    }
+
+    /// Returns the address family of the socket
+    #[inline]
+    pub fn family(&self) -> u32 {
+        unsafe { (*self.ctx).family }
+    }
+
+    /// Returns the remote IPv4 address in network byte order
+    #[inline]
+    pub fn remote_ip4(&self) -> u32 {
+        unsafe { (*self.ctx).remote_ip4 }
+    }
+
+    /// Returns the local IPv4 address in network byte order
+    #[inline]
+    pub fn local_ip4(&self) -> u32 {
+        unsafe { (*self.ctx).local_ip4 }
+    }
+
+    /// Returns the remote port in host byte order
+    #[inline]
+    pub fn remote_port(&self) -> u16 {
+        // remote_port is stored in network byte order in the upper 16 bits
+        u16::from_be((unsafe { (*self.ctx).remote_port } >> 16) as u16)
+    }
+
+    /// Returns the local port in host byte order
+    #[inline]
+    pub fn local_port(&self) -> u16 {
+        unsafe { (*self.ctx).local_port as u16 }
+    }
+
+    /// Returns the TCP state of the socket
+    #[inline]
+    pub fn state(&self) -> u32 {
+        unsafe { (*self.ctx).state }
+    }
+
+    /// Returns the smoothed RTT in microseconds, shifted left by 3
+    #[inline]
+    pub fn srtt_us(&self) -> u32 {
+        unsafe { (*self.ctx).srtt_us }
+    }
+
+    /// Returns the congestion window
+    #[inline]
+    pub fn snd_cwnd(&self) -> u32 {
+        unsafe { (*self.ctx).snd_cwnd }
+    }
+
+    /// Returns the number of retransmitted segments of the connection
+    #[inline]
+    pub fn total_retrans(&self) -> u32 {
+        unsafe { (*self.ctx).total_retrans }
+    }
+
+    /// Returns the callback flags currently enabled on the socket
+    #[inline]
+    pub fn cb_flags(&self) -> u32 {
+        unsafe { (*self.ctx).bpf_sock_ops_cb_flags }
+    }
+
+    /// Enables the callbacks given by `flags` for the socket
+    ///
+    /// `flags` is a combination of [`cb_flags`](cb_flags/index.html). It
+    /// replaces the currently enabled flags.
+    #[inline]
+    pub fn set_cb_flags(&self, flags: u32) -> SockOpsResult {
+        let ret = unsafe { bpf_sock_ops_cb_flags_set(self.ctx, flags as c_int) };
+        if ret < 0 {
+            return Err(SockOpsError::Helper(ret as i64));
+        }
+        Ok(())
+    }
+
+    /// Sets the socket option `optname` at `level` to `value`
+    #[inline]
+    pub fn setsockopt<T>(&self, level: c_int, optname: c_int, value: &mut T) -> SockOpsResult {
+        let ret = unsafe {
+            bpf_setsockopt(
+                self.ctx as *mut c_void,
+                level,
+                optname,
+                value as *mut T as *mut c_void,
+                size_of::<T>() as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(SockOpsError::Helper(ret as i64));
+        }
+        Ok(())
+    }
+
+    /// Gets the socket option `optname` at `level`
+    #[inline]
+    pub fn getsockopt<T>(&self, level: c_int, optname: c_int) -> Result<T, SockOpsError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let ret = unsafe {
+            bpf_getsockopt(
+                self.ctx as *mut c_void,
+                level,
+                optname,
+                value.as_mut_ptr() as *mut c_void,
+                size_of::<T>() as c_int,
+            )
+        };
+        if ret < 0 {
+            return Err(SockOpsError::Helper(ret as i64));
+        }
+        Ok(unsafe { value.assume_init() })
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright 2021 Authors of redBPF
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+/*!
+Implementing kernel structures of function pointers
+
+`struct_ops` programs implement the callbacks of a kernel structure such as
+`tcp_congestion_ops`. Each callback is a function annotated with
+`#[struct_ops(structure, member)]` that takes the arguments of the member.
+In userspace, `redbpf::StructOpsMap` fills the structure with the loaded
+programs and registers it, e.g., as a TCP congestion control algorithm that
+can be selected with the `TCP_CONGESTION` socket option.
+
+# Example
+
+A congestion control algorithm that halves the window on loss and grows it
+by one segment per ACK:
+
+```no_run
+#![no_std]
+#![no_main]
+use redbpf_probes::struct_ops::prelude::*;
+
+program!(0xFFFFFFFE, "GPL");
+
+#[struct_ops("tcp_congestion_ops", "ssthresh")]
+fn rust_ssthresh(sk: *mut sock) -> u32 {
+    let tp = sk as *const tcp_sock;
+    let cwnd = unsafe { (*tp).snd_cwnd };
+    if cwnd / 2 > 2 { cwnd / 2 } else { 2 }
+}
+
+#[struct_ops("tcp_congestion_ops", "cong_avoid")]
+fn rust_cong_avoid(sk: *mut sock, _ack: u32, _acked: u32) {
+    let tp = sk as *mut tcp_sock;
+    unsafe { (*tp).snd_cwnd += 1 };
+}
+
+#[struct_ops("tcp_congestion_ops", "undo_cwnd")]
+fn rust_undo_cwnd(sk: *mut sock) -> u32 {
+    let tp = sk as *const tcp_sock;
+    unsafe { (*tp).snd_cwnd }
+}
+```
+*/
+pub mod prelude;
+
+/// The slow start threshold of connections that have not seen a loss yet
+pub const TCP_INFINITE_SSTHRESH: u32 = 0x7fffffff;
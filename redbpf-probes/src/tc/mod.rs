@@ -83,5 +83,5 @@ pub mod prelude {
     pub use crate::helpers::*;
     pub use crate::maps::*;
     pub use crate::socket::*;
-    pub use redbpf_macros::{program, tc_action};
+    pub use redbpf_macros::{global_function, program, tc_action};
 }
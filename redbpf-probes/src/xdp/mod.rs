@@ -86,6 +86,23 @@ impl XdpContext {
     pub fn inner(&self) -> *mut xdp_md {
         self.ctx
     }
+
+    /// Returns the index of the interface the packet was received on.
+    ///
+    /// Unlike packet data, the index is in host byte order.
+    #[inline]
+    pub fn ingress_ifindex(&self) -> u32 {
+        unsafe { (*self.ctx).ingress_ifindex }
+    }
+
+    /// Returns the index of the RX queue the packet was received on.
+    ///
+    /// This is useful to look up `XSKMAP` entries, which are usually indexed
+    /// by queue.
+    #[inline]
+    pub fn rx_queue_index(&self) -> u32 {
+        unsafe { (*self.ctx).rx_queue_index }
+    }
 }
 
 impl NetworkBuffer for XdpContext {
@@ -0,0 +1,105 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Decoding the command line arguments read by BPF programs
+//!
+//! BPF programs read the arguments of processes with
+//! [`redbpf_probes::argv::Argv`](../../redbpf_probes/argv/struct.Argv.html)
+//! and emit only the used part of it at the end of their events. So the
+//! events are shorter than their types and they can not be read as the types
+//! as they are. [`Argv::parse`] decodes the arguments from the bytes of an
+//! event starting at the offset of the `Argv` field.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::argv::Argv;
+//!
+//! # let event: &[u8] = &[];
+//! // the event starts with a `u64` pid followed by `Argv`
+//! let argv = Argv::parse(&event[8..]).expect("short event");
+//! println!("{}{}", argv.to_strings().join(" "), if argv.truncated { " ..." } else { "" });
+//! ```
+use std::convert::TryInto;
+
+// argc, len and truncated
+const HEADER_LEN: usize = 12;
+
+/// The command line arguments emitted by a BPF program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Argv {
+    /// The arguments without the terminating NUL
+    pub args: Vec<Vec<u8>>,
+    /// True if the BPF program dropped or cut some arguments
+    pub truncated: bool,
+}
+
+impl Argv {
+    /// Decode the arguments from `bytes` that start with the `Argv` of
+    /// `redbpf_probes`
+    ///
+    /// `None` is returned if `bytes` is shorter than the length that the
+    /// header says.
+    pub fn parse(bytes: &[u8]) -> Option<Argv> {
+        if bytes.len() < HEADER_LEN {
+            return None;
+        }
+        let u32_at = |off: usize| u32::from_ne_bytes(bytes[off..off + 4].try_into().unwrap());
+        let argc = u32_at(0) as usize;
+        let len = u32_at(4) as usize;
+        let truncated = u32_at(8) != 0;
+        let buf = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+        let mut args: Vec<Vec<u8>> = buf
+            .split(|b| *b == 0)
+            .take(argc)
+            .map(|arg| arg.to_vec())
+            .collect();
+        args.resize(argc, vec![]);
+        Some(Argv { args, truncated })
+    }
+
+    /// Return the arguments as strings, replacing invalid UTF-8
+    pub fn to_strings(&self) -> Vec<String> {
+        self.args
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(argc: u32, truncated: u32, buf: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend_from_slice(&argc.to_ne_bytes());
+        bytes.extend_from_slice(&(buf.len() as u32).to_ne_bytes());
+        bytes.extend_from_slice(&truncated.to_ne_bytes());
+        bytes.extend_from_slice(buf);
+        bytes
+    }
+
+    #[test]
+    fn test_parse() {
+        let argv = Argv::parse(&event(3, 0, b"ls\0-l\0/tmp\0")).unwrap();
+        assert_eq!(argv.to_strings(), vec!["ls", "-l", "/tmp"]);
+        assert!(!argv.truncated);
+
+        // the last argument is cut without NUL
+        let argv = Argv::parse(&event(2, 1, b"cat\0/very/lo")).unwrap();
+        assert_eq!(argv.to_strings(), vec!["cat", "/very/lo"]);
+        assert!(argv.truncated);
+
+        let argv = Argv::parse(&event(0, 0, b"")).unwrap();
+        assert!(argv.args.is_empty());
+
+        let mut short = event(1, 0, b"ls\0");
+        short.pop();
+        assert_eq!(Argv::parse(&short), None);
+        assert_eq!(Argv::parse(&[0; 4]), None);
+    }
+}
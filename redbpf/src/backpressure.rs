@@ -0,0 +1,197 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Backpressure for perf event consumers
+//!
+//! When a consumer can't keep up with the events emitted by BPF programs, the
+//! kernel drops samples silently once the perf ring buffer is full. For tools
+//! that prefer throttling to random loss, BPF programs can check a
+//! `FlowGate` map (see
+//! [`redbpf_probes::maps::FlowGate`](../../redbpf_probes/maps/struct.FlowGate.html))
+//! before emitting each event, and the consumer feeds its queue depth back
+//! into the gate through [`Backpressure`].
+//!
+//! The feedback protocol works as follows:
+//!
+//! 1. The consumer calls [`Backpressure::report`] with its queue depth and
+//!    capacity, e.g., [`PerfMap::pending_bytes`](../struct.PerfMap.html#method.pending_bytes)
+//!    and [`PerfMap::buffer_size`](../struct.PerfMap.html#method.buffer_size),
+//!    or the length of its own queue of decoded events.
+//! 2. If the queue is filled above the high watermark, the throttling level
+//!    is raised by one, so BPF programs emit half as many events as before.
+//!    Once the level reaches [`FLOW_LEVEL_PAUSED`], emission is paused.
+//! 3. If the queue is filled below the low watermark, the level is lowered
+//!    by one until all events are emitted again.
+//! 4. Between the watermarks the level is kept as it is, so the level does
+//!    not oscillate.
+//!
+//! Events suppressed by the gate are counted and can be read by
+//! [`Backpressure::suppressed`], so the loss is never silent.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::backpressure::Backpressure;
+//! use redbpf::{Map, PerfMap};
+//!
+//! let mut events = Map::load("EVENTS", &vec![]).unwrap();
+//! let gate = Map::load("EVENTS_GATE", &vec![]).unwrap();
+//! let perfmap = PerfMap::bind(&mut events, -1, 0, 16, -1, 0).unwrap();
+//! let mut backpressure = Backpressure::new(&gate).unwrap();
+//! loop {
+//!     while let Some(_event) = perfmap.read() {
+//!         // handle the event
+//!     }
+//!     backpressure
+//!         .report(perfmap.pending_bytes(), perfmap.buffer_size())
+//!         .unwrap();
+//! }
+//! ```
+use tracing::{debug, error};
+
+use crate::{Error, Map, PerCpuArray, PerCpuValues, Result};
+
+const FLOW_GATE_LEVEL: u32 = 0;
+const FLOW_GATE_SUPPRESSED: u32 = 1;
+
+/// Throttling level at which BPF programs suppress all events
+///
+/// It should be equal to `redbpf_probes::maps::FLOW_LEVEL_PAUSED`.
+pub const FLOW_LEVEL_PAUSED: u64 = 32;
+
+/// Watermarks of the queue fill ratio
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watermarks {
+    /// Raise the throttling level when the queue is filled above this ratio
+    pub high: f64,
+    /// Lower the throttling level when the queue is filled below this ratio
+    pub low: f64,
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Watermarks {
+            high: 0.75,
+            low: 0.25,
+        }
+    }
+}
+
+/// Userspace side of a `FlowGate` map
+///
+/// See the [module level documentation](index.html) for the feedback
+/// protocol.
+pub struct Backpressure<'a> {
+    gate: PerCpuArray<'a, u64>,
+    watermarks: Watermarks,
+    level: u64,
+}
+
+impl<'a> Backpressure<'a> {
+    /// Create `Backpressure` from the `FlowGate` map `base` with the default
+    /// watermarks
+    ///
+    /// The throttling level is reset to `0`.
+    pub fn new(base: &'a Map) -> Result<Backpressure<'a>> {
+        Backpressure::with_watermarks(base, Watermarks::default())
+    }
+
+    /// Create `Backpressure` from the `FlowGate` map `base` with `watermarks`
+    pub fn with_watermarks(base: &'a Map, watermarks: Watermarks) -> Result<Backpressure<'a>> {
+        if base.config.max_entries != 2 {
+            error!("`{}' is not a FlowGate map", base.name);
+            return Err(Error::Map);
+        }
+        let mut bp = Backpressure {
+            gate: PerCpuArray::new(base)?,
+            watermarks,
+            level: 0,
+        };
+        bp.set_level(0)?;
+        Ok(bp)
+    }
+
+    /// Return the current throttling level
+    pub fn level(&self) -> u64 {
+        self.level
+    }
+
+    /// Set the throttling level of BPF programs
+    ///
+    /// Levels above [`FLOW_LEVEL_PAUSED`] are clamped.
+    pub fn set_level(&mut self, level: u64) -> Result<()> {
+        let level = level.min(FLOW_LEVEL_PAUSED);
+        self.gate.set(FLOW_GATE_LEVEL, &PerCpuValues::new(level))?;
+        if level != self.level {
+            debug!("flow gate level: {} -> {}", self.level, level);
+        }
+        self.level = level;
+        Ok(())
+    }
+
+    /// Suppress all events until [`resume`](#method.resume) is called
+    pub fn pause(&mut self) -> Result<()> {
+        self.set_level(FLOW_LEVEL_PAUSED)
+    }
+
+    /// Emit all events again
+    pub fn resume(&mut self) -> Result<()> {
+        self.set_level(0)
+    }
+
+    /// Report the queue depth of the consumer and adjust the throttling level
+    ///
+    /// `depth` and `capacity` can be in any unit as long as they are in the
+    /// same unit. Returns the new throttling level.
+    pub fn report(&mut self, depth: usize, capacity: usize) -> Result<u64> {
+        let level = next_level(self.level, depth, capacity, &self.watermarks);
+        if level != self.level {
+            self.set_level(level)?;
+        }
+        Ok(self.level)
+    }
+
+    /// Return the total number of events suppressed by BPF programs
+    pub fn suppressed(&self) -> u64 {
+        self.gate
+            .get(FLOW_GATE_SUPPRESSED)
+            .map(|values| values.iter().sum())
+            .unwrap_or(0)
+    }
+}
+
+fn next_level(level: u64, depth: usize, capacity: usize, watermarks: &Watermarks) -> u64 {
+    if capacity == 0 {
+        return level;
+    }
+    let ratio = depth as f64 / capacity as f64;
+    if ratio > watermarks.high {
+        (level + 1).min(FLOW_LEVEL_PAUSED)
+    } else if ratio < watermarks.low {
+        level.saturating_sub(1)
+    } else {
+        level
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_next_level() {
+        let wm = Watermarks::default();
+        assert_eq!(next_level(0, 80, 100, &wm), 1);
+        assert_eq!(next_level(3, 50, 100, &wm), 3);
+        assert_eq!(next_level(3, 10, 100, &wm), 2);
+        assert_eq!(next_level(0, 0, 100, &wm), 0);
+        assert_eq!(
+            next_level(FLOW_LEVEL_PAUSED, 100, 100, &wm),
+            FLOW_LEVEL_PAUSED
+        );
+        assert_eq!(next_level(2, 10, 0, &wm), 2);
+    }
+}
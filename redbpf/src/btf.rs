@@ -15,8 +15,10 @@ use std::fs;
 use std::io;
 use std::mem;
 use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::slice;
+use std::sync::Mutex;
 use tracing::{debug, error, warn};
 
 use libbpf_sys::{
@@ -28,8 +30,18 @@ use libbpf_sys::{
 };
 
 use crate::error::{Error, Result};
+use crate::uname;
 
 const BTF_SECTION_NAME: &str = ".BTF";
+const VMLINUX_BTF_PATH: &str = "/sys/kernel/btf/vmlinux";
+
+/// The environment variable that names a vmlinux BTF file to use instead of
+/// `/sys/kernel/btf/vmlinux`
+pub const VMLINUX_BTF_ENV: &str = "REDBPF_VMLINUX_BTF";
+
+lazy_static! {
+    static ref VMLINUX_BTF_OVERRIDE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
 
 pub(crate) struct BTF {
     types: Vec<(u32, BtfType)>,
@@ -46,7 +58,7 @@ struct BtfTypeCommon {
     name_raw: String,
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum BtfKind {
     Unknown,
     Integer,
@@ -93,6 +105,86 @@ struct BtfMember {
     name: String,
 }
 
+/// A member of a structure resolved from BTF
+#[derive(Debug)]
+pub(crate) struct StructMember {
+    pub(crate) name: String,
+    /// Offset in bytes from the start of the structure
+    pub(crate) offset: u32,
+    pub(crate) size: u32,
+    pub(crate) is_func_ptr: bool,
+}
+
+/// A field of a Rust structure to check against BTF
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtfField {
+    pub name: &'static str,
+    /// Offset in bytes from the start of the structure
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// Rust structures whose field layout can be checked against BTF
+///
+/// Implement it with [`btf_layout!`](../macro.btf_layout.html) for
+/// `#[repr(C)]` structures shared with BPF programs, e.g., to call
+/// [`HashMap::get_checked_layout`](../struct.HashMap.html#method.get_checked_layout).
+pub trait BtfLayout {
+    /// Return the fields to check
+    fn btf_fields() -> Vec<BtfField>;
+}
+
+/// Implement [`BtfLayout`](btf/trait.BtfLayout.html) for a structure with
+/// the listed fields
+///
+/// # Example
+/// ```
+/// use redbpf::btf_layout;
+///
+/// #[repr(C)]
+/// #[derive(Clone)]
+/// struct Connection {
+///     pid: u32,
+///     bytes: u64,
+/// }
+///
+/// btf_layout!(Connection { pid, bytes });
+/// ```
+#[macro_export]
+macro_rules! btf_layout {
+    ($ty:ty { $($field:ident),* $(,)? }) => {
+        impl $crate::btf::BtfLayout for $ty {
+            fn btf_fields() -> Vec<$crate::btf::BtfField> {
+                let uninit = ::std::mem::MaybeUninit::<$ty>::uninit();
+                let base = uninit.as_ptr();
+                vec![$({
+                    let field = unsafe { ::std::ptr::addr_of!((*base).$field) };
+                    $crate::btf::BtfField {
+                        name: stringify!($field),
+                        offset: field as usize - base as usize,
+                        size: $crate::btf::size_of_pointee(field),
+                    }
+                }),*]
+            }
+        }
+    };
+}
+
+#[doc(hidden)]
+pub fn size_of_pointee<T>(_: *const T) -> usize {
+    mem::size_of::<T>()
+}
+
+/// A step of a CO-RE access string resolved with the BTF of the program
+///
+/// Members are matched by name in the target BTF, so anonymous members are
+/// not steps but looked through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AccessStep {
+    Member(String),
+    Index(u32),
+}
+
 /// A structure representing BTF of a map
 ///
 /// `btf_fd` is a file descriptor of successfully loaded BTF
@@ -107,16 +199,319 @@ pub(crate) struct MapBtfTypeId {
     pub(crate) value_type_id: u32,
 }
 
+// Parse the BTF loaded by the running kernel
+//
+// The type ids of this BTF are the ids the kernel knows, so it is used for
+// the attach targets of fentry, fexit, lsm, task_iter and struct_ops
+// programs and the value types of struct_ops maps.
+// `set_vmlinux_btf_path` does not affect it.
 pub(crate) fn parse_vmlinux_btf() -> Result<BTF> {
-    let bytes = fs::read("/sys/kernel/btf/vmlinux").or_else(|e| Err(Error::IO(e)))?;
+    let bytes = fs::read(VMLINUX_BTF_PATH).map_err(|e| {
+        error!("error reading vmlinux BTF {}: {}", VMLINUX_BTF_PATH, e);
+        Error::IO(e)
+    })?;
     BTF::parse_raw(&bytes)
 }
 
+// Parse the vmlinux BTF at `vmlinux_btf_path()` for CO-RE relocations
+//
+// Only the layouts of types are looked up for CO-RE, so BTF of the same
+// kernel release that is not loaded by the kernel, e.g., from BTFHub, can be
+// used.
+pub(crate) fn parse_core_btf() -> Result<BTF> {
+    let bytes = read_vmlinux_btf()?;
+    BTF::parse_raw(&bytes)
+}
+
+/// Use the vmlinux BTF file at `path` instead of `/sys/kernel/btf/vmlinux`
+/// for CO-RE relocations
+///
+/// This is for environments where `/sys/kernel/btf` is not accessible, e.g.,
+/// containers, but BTF of the running kernel is shipped with the
+/// application, e.g., from BTFHub. The path set by this function takes
+/// precedence over the `REDBPF_VMLINUX_BTF` environment variable.
+///
+/// Only the offsets and sizes of fields are taken from this file. The type
+/// ids of the kernel, e.g., of the functions fentry programs attach to, of
+/// struct_ops types and of kfuncs, are always read from
+/// `/sys/kernel/btf/vmlinux` since the ids of other BTF files differ from the
+/// ids the running kernel knows even if the kernel release is the same.
+pub fn set_vmlinux_btf_path(path: impl AsRef<Path>) {
+    *VMLINUX_BTF_OVERRIDE.lock().unwrap() = Some(path.as_ref().to_path_buf());
+}
+
+/// Return the path of the vmlinux BTF file used for CO-RE relocations
+///
+/// The path set by [`set_vmlinux_btf_path`](fn.set_vmlinux_btf_path.html)
+/// comes first, then the path in the `REDBPF_VMLINUX_BTF` environment
+/// variable, then `/sys/kernel/btf/vmlinux`.
+pub fn vmlinux_btf_path() -> PathBuf {
+    if let Some(path) = VMLINUX_BTF_OVERRIDE.lock().unwrap().as_ref() {
+        return path.clone();
+    }
+    match std::env::var_os(VMLINUX_BTF_ENV) {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => PathBuf::from(VMLINUX_BTF_PATH),
+    }
+}
+
+fn read_vmlinux_btf() -> Result<Vec<u8>> {
+    let path = vmlinux_btf_path();
+    if path != Path::new(VMLINUX_BTF_PATH) {
+        check_btf_release(&path)?;
+    }
+    fs::read(&path).map_err(|e| {
+        error!("error reading vmlinux BTF {:?}: {}", path, e);
+        Error::IO(e)
+    })
+}
+
+// BTF does not record the kernel release it describes, but BTFHub names
+// files after it, e.g., `5.4.0-1045-aws.btf`. Reject files named after
+// another release since their offsets do not match the running kernel.
+fn check_btf_release(path: &Path) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("");
+    let file_release = file_name.strip_suffix(".btf").unwrap_or(file_name);
+    if !file_release.starts_with(|c: char| c.is_ascii_digit()) {
+        debug!(
+            "release of vmlinux BTF {:?} is unknown. assume it matches",
+            path
+        );
+        return Ok(());
+    }
+    let uts = uname::uname().map_err(|_| Error::IO(io::Error::last_os_error()))?;
+    let release = uname::to_str(&uts.release);
+    if file_release != release {
+        error!(
+            "vmlinux BTF {:?} is for kernel {} but the running kernel is {}",
+            path, file_release, release
+        );
+        return Err(Error::BTF(format!(
+            "vmlinux BTF of kernel {} does not match the running kernel",
+            file_release
+        )));
+    }
+    Ok(())
+}
+
+/// Parse BTF loaded into the kernel of which id is `btf_id`, e.g., BTF of
+/// a loaded program
+pub(crate) fn parse_kernel_btf_by_id(btf_id: u32) -> Result<BTF> {
+    unsafe {
+        let fd = libbpf_sys::bpf_btf_get_fd_by_id(btf_id);
+        if fd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        let mut info = mem::zeroed::<libbpf_sys::bpf_btf_info>();
+        let mut info_len = mem::size_of_val(&info) as u32;
+        // the first call only gets the size of the raw BTF data
+        let mut ret =
+            libbpf_sys::bpf_obj_get_info_by_fd(fd, &mut info as *mut _ as *mut _, &mut info_len);
+        let mut bytes = vec![0u8; info.btf_size as usize];
+        if ret == 0 {
+            let btf_size = info.btf_size;
+            info = mem::zeroed();
+            info.btf = bytes.as_mut_ptr() as u64;
+            info.btf_size = btf_size;
+            ret = libbpf_sys::bpf_obj_get_info_by_fd(
+                fd,
+                &mut info as *mut _ as *mut _,
+                &mut info_len,
+            );
+        }
+        let err = io::Error::last_os_error();
+        libc::close(fd);
+        if ret != 0 {
+            return Err(Error::IO(err));
+        }
+        BTF::parse_raw(&bytes)
+    }
+}
+
+/// Check the value of the map `map_fd` against its BTF
+///
+/// The size of the value type of BTF must be `value_size`. If `fields` is
+/// given, the value type must be a structure that has all of the fields at
+/// the same offsets with the same sizes.
+pub(crate) fn check_map_value_layout(
+    map_fd: RawFd,
+    value_size: usize,
+    fields: Option<&[BtfField]>,
+) -> Result<()> {
+    let info = crate::map_info_by_fd(map_fd)?;
+    if info.btf_id == 0 || info.btf_value_type_id == 0 {
+        error!("map has no BTF to check its value against");
+        return Err(Error::BTF("map has no BTF".to_string()));
+    }
+    let btf = parse_kernel_btf_by_id(info.btf_id)?;
+    let type_id = info.btf_value_type_id;
+    let type_name = btf.name_of(type_id).unwrap_or("").to_string();
+    match btf.size_of(type_id) {
+        Some(size) if size as usize == value_size => {}
+        size => {
+            error!(
+                "size of value type `{}' is {:?} in BTF but {} in Rust",
+                type_name, size, value_size
+            );
+            return Err(Error::BTF(format!("size mismatch of `{}'", type_name)));
+        }
+    }
+    let fields = match fields {
+        Some(fields) => fields,
+        None => return Ok(()),
+    };
+    let members = btf.struct_members(type_id).ok_or_else(|| {
+        error!("value type `{}' is not a structure in BTF", type_name);
+        Error::BTF(format!("`{}' is not a structure", type_name))
+    })?;
+    for field in fields {
+        match members.iter().find(|memb| memb.name == field.name) {
+            Some(memb)
+                if memb.offset as usize == field.offset && memb.size as usize == field.size => {}
+            Some(memb) => {
+                error!(
+                    "field `{}.{}' is at offset {} with size {} in BTF but at offset {} with size {} in Rust",
+                    type_name, field.name, memb.offset, memb.size, field.offset, field.size
+                );
+                return Err(Error::BTF(format!(
+                    "layout mismatch of `{}.{}'",
+                    type_name, field.name
+                )));
+            }
+            None => {
+                error!("field `{}.{}' not found in BTF", type_name, field.name);
+                return Err(Error::BTF(format!(
+                    "field `{}.{}' not found",
+                    type_name, field.name
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Return the sizes of the key and value types of BTF of the map `map_fd`
+///
+/// A size is `None` if the map has no BTF, its BTF can not be fetched or it
+/// has no BTF type for the key or the value.
+pub(crate) fn map_type_sizes(map_fd: RawFd) -> Result<(Option<u32>, Option<u32>)> {
+    let info = crate::map_info_by_fd(map_fd)?;
+    if info.btf_id == 0 {
+        return Ok((None, None));
+    }
+    // the BTF of a map can be out of reach, e.g., `EPERM` without
+    // CAP_SYS_ADMIN, while the map itself is, so fall back to the sizes of
+    // the map definition
+    let btf = match parse_kernel_btf_by_id(info.btf_id) {
+        Ok(btf) => btf,
+        Err(e) => {
+            warn!("error on fetching BTF of map: {:?}", e);
+            return Ok((None, None));
+        }
+    };
+    let size_of = |type_id| match type_id {
+        0 => None,
+        type_id => btf.size_of(type_id),
+    };
+    Ok((
+        size_of(info.btf_key_type_id),
+        size_of(info.btf_value_type_id),
+    ))
+}
+
+// Kinds that are newer than the BTF parser. They are only skipped over by
+// `kernel_func_btf_ids`.
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+
+/// Return the type ids of all `BTF_KIND_FUNC` types of vmlinux BTF by name
+///
+/// These are the BTF ids that calls of kfuncs refer to. Unlike
+/// `parse_vmlinux_btf`, the type records are not parsed but only walked, so
+/// it also works with BTF kinds that are unknown to the BTF parser.
+///
+/// Like `parse_vmlinux_btf`, the ids are read from `/sys/kernel/btf/vmlinux`
+/// regardless of [`vmlinux_btf_path`](fn.vmlinux_btf_path.html), or the
+/// verifier would call another function.
+pub(crate) fn kernel_func_btf_ids() -> Result<RSHashMap<String, u32>> {
+    let bytes = fs::read(VMLINUX_BTF_PATH).map_err(|e| {
+        error!("error reading vmlinux BTF {}: {}", VMLINUX_BTF_PATH, e);
+        Error::IO(e)
+    })?;
+    func_btf_ids(&bytes)
+}
+
+fn func_btf_ids(bytes: &[u8]) -> Result<RSHashMap<String, u32>> {
+    if mem::size_of::<btf_header>() > bytes.len() {
+        return Err(Error::BTF("BTF section data size is too small".to_string()));
+    }
+    let btf_hdr = unsafe { ptr::read_unaligned::<btf_header>(bytes.as_ptr() as *const _) };
+    if btf_hdr.magic != BTF_MAGIC as u16 {
+        return Err(Error::BTF(
+            "illegal magic. not a valid BTF section".to_string(),
+        ));
+    }
+    let type_start = btf_hdr.hdr_len as usize + btf_hdr.type_off as usize;
+    let type_end = type_start + btf_hdr.type_len as usize;
+    let str_start = btf_hdr.hdr_len as usize + btf_hdr.str_off as usize;
+    let str_end = str_start + btf_hdr.str_len as usize;
+    if type_end > bytes.len() || str_end > bytes.len() {
+        return Err(Error::BTF("invalid binary data length".to_string()));
+    }
+    let str_bytes = &bytes[str_start..str_end];
+
+    let mut ids = RSHashMap::new();
+    let mut type_id: u32 = 1;
+    let mut remain = &bytes[type_start..type_end];
+    while remain.len() >= mem::size_of::<btf_type>() {
+        let type_ = unsafe { ptr::read_unaligned(remain.as_ptr() as *const btf_type) };
+        let kind = (type_.info >> 24) & 0x1f;
+        let vlen = (type_.info & 0xffff) as usize;
+        let extra = match kind {
+            BTF_KIND_INT | BTF_KIND_VAR | BTF_KIND_DECL_TAG => mem::size_of::<u32>(),
+            BTF_KIND_PTR | BTF_KIND_FWD | BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST
+            | BTF_KIND_RESTRICT | BTF_KIND_FUNC | BTF_KIND_FLOAT | BTF_KIND_TYPE_TAG => 0,
+            BTF_KIND_ARRAY => mem::size_of::<btf_array>(),
+            BTF_KIND_STRUCT | BTF_KIND_UNION => vlen * mem::size_of::<btf_member>(),
+            BTF_KIND_ENUM => vlen * mem::size_of::<btf_enum>(),
+            BTF_KIND_FUNC_PROTO => vlen * mem::size_of::<btf_param>(),
+            BTF_KIND_DATASEC => vlen * mem::size_of::<btf_var_secinfo>(),
+            // struct btf_enum64 { name_off, val_lo32, val_hi32 }
+            BTF_KIND_ENUM64 => vlen * 3 * mem::size_of::<u32>(),
+            _ => {
+                return Err(Error::BTF(format!(
+                    "Unknown BTF kind {} of type_id={}",
+                    kind, type_id
+                )))
+            }
+        };
+        if kind == BTF_KIND_FUNC {
+            ids.insert(get_type_name(str_bytes, type_.name_off)?, type_id);
+        }
+        let sz = mem::size_of::<btf_type>() + extra;
+        if sz > remain.len() {
+            return Err(Error::BTF("truncated BTF type data".to_string()));
+        }
+        remain = &remain[sz..];
+        type_id += 1;
+    }
+    Ok(ids)
+}
+
 impl BTF {
     fn is_loaded(&self) -> bool {
         self.fd.is_some()
     }
 
+    /// The file descriptor of BTF loaded to the kernel
+    pub(crate) fn fd(&self) -> Option<RawFd> {
+        self.fd
+    }
+
     /// Load BTF raw data to the Linux kernel and save `fd` of the data
     pub(crate) fn load(&mut self) -> Result<()> {
         if self.is_loaded() {
@@ -218,20 +613,22 @@ impl BTF {
                 btf_hdr.version
             )));
         }
-        if (btf_hdr.hdr_len + btf_hdr.str_off + btf_hdr.str_len) as usize != bytes.len() {
+        // offsets of the header are not trusted since BTF is read from files
+        let sub_slice = |off: u32, len: u32| {
+            let start = btf_hdr.hdr_len as usize + off as usize;
+            bytes.get(start..start.checked_add(len as usize)?)
+        };
+        if btf_hdr.hdr_len as usize + btf_hdr.str_off as usize + btf_hdr.str_len as usize
+            != bytes.len()
+        {
             return Err(Error::BTF("invalid binary data length".to_string()));
         }
 
-        let raw_type_enc = {
-            let start = (btf_hdr.hdr_len + btf_hdr.type_off) as usize;
-            let end = start + btf_hdr.type_len as usize;
-            &bytes[start..end]
-        };
-        let mut raw_str_enc = {
-            let start = (btf_hdr.hdr_len + btf_hdr.str_off) as usize;
-            let end = start + btf_hdr.str_len as usize;
-            (&bytes[start..end]).to_vec()
-        };
+        let raw_type_enc = sub_slice(btf_hdr.type_off, btf_hdr.type_len)
+            .ok_or_else(|| Error::BTF("type section is out of BTF data".to_string()))?;
+        let mut raw_str_enc = sub_slice(btf_hdr.str_off, btf_hdr.str_len)
+            .ok_or_else(|| Error::BTF("string section is out of BTF data".to_string()))?
+            .to_vec();
         let types = Self::parse_types(&raw_type_enc, &mut raw_str_enc)?;
         Ok(BTF {
             types,
@@ -249,8 +646,11 @@ impl BTF {
     pub(crate) fn parse_elf(object: &Elf, bytes: &[u8]) -> Result<BTF> {
         let shdr = get_section_header_by_name(object, BTF_SECTION_NAME)
             .ok_or_else(|| Error::BTF("section not found".to_string()))?;
-        let btf_bytes = &bytes[shdr.sh_offset as usize..(shdr.sh_offset + shdr.sh_size) as usize];
-        let mut btf = Self::parse_raw(&btf_bytes)?;
+        let btf_bytes = (shdr.sh_offset as usize)
+            .checked_add(shdr.sh_size as usize)
+            .and_then(|end| bytes.get(shdr.sh_offset as usize..end))
+            .ok_or_else(|| Error::BTF("BTF section is out of ELF data".to_string()))?;
+        let mut btf = Self::parse_raw(btf_bytes)?;
         btf.fix_datasection(object)?;
         for (type_id, type_) in btf.types.iter() {
             debug!("[{}] {:?}", type_id, type_);
@@ -360,6 +760,18 @@ impl BTF {
         if !self.is_loaded() {
             return Err(Error::BTF("BTF is not loaded yet".to_string()));
         }
+        let (key_type_id, value_type_id) = self.map_key_value_type_ids(map_sym_name)?;
+        Ok(MapBtfTypeId {
+            // self.is_loaded ensures that unwrap returns fd
+            btf_fd: self.fd.unwrap(),
+            key_type_id,
+            value_type_id,
+        })
+    }
+
+    /// Return the type ids of the key and the value of the map `map_sym_name`
+    /// from the fields of its `MAP_BTF_<map_sym_name>` variable
+    fn map_key_value_type_ids(&self, map_sym_name: &str) -> Result<(u32, u32)> {
         let map_btf_sym_name = format!("MAP_BTF_{}", map_sym_name);
         use BtfType::*;
         let map_btf_type = self
@@ -419,12 +831,7 @@ impl BTF {
                         Error::BTF(msg)
                     })?;
 
-                Ok(MapBtfTypeId {
-                    // self.is_loaded ensures that unwrap returns fd
-                    btf_fd: self.fd.unwrap(),
-                    key_type_id,
-                    value_type_id,
-                })
+                Ok((key_type_id, value_type_id))
             }
             _ => {
                 let msg = format!("`{}` must be a struct type but it isn't", map_btf_sym_name);
@@ -649,6 +1056,568 @@ impl BTF {
     }
 }
 
+// Limit of recursion while walking the type graph. Well-formed BTF does not
+// have cycles except through pointers, but a malformed section may.
+const MAX_TYPE_DEPTH: usize = 32;
+
+impl BTF {
+    /// Return the C-like name of the type `type_id`
+    ///
+    /// Referenced types are walked, e.g., a pointer to a const struct `foo`
+    /// is named `const struct foo *`.
+    fn type_name(&self, type_id: u32, depth: usize) -> String {
+        use BtfType::*;
+        if type_id == 0 {
+            return "void".to_string();
+        }
+        if depth > MAX_TYPE_DEPTH {
+            return "...".to_string();
+        }
+        let type_ = if let Some(type_) = self.get_type_by_id(type_id) {
+            type_
+        } else {
+            return format!("<invalid type_id={}>", type_id);
+        };
+        let name = |comm: &BtfTypeCommon| {
+            if comm.name_raw.is_empty() {
+                "(anon)".to_string()
+            } else {
+                comm.name_raw.clone()
+            }
+        };
+        match type_ {
+            Integer(comm, _)
+            | FloatingPoint(comm)
+            | TypeDef(comm)
+            | Function(comm)
+            | Variable(comm, _)
+            | DataSection(comm, _) => name(comm),
+            Structure(comm, _) => format!("struct {}", name(comm)),
+            Union(comm, _) => format!("union {}", name(comm)),
+            Enumeration(comm, _) => format!("enum {}", name(comm)),
+            Forward(comm) => {
+                if comm.kind_flag() {
+                    format!("union {}", name(comm))
+                } else {
+                    format!("struct {}", name(comm))
+                }
+            }
+            Pointer(comm) => format!("{} *", self.type_name(comm.type_id(), depth + 1)),
+            Constant(comm) => format!("const {}", self.type_name(comm.type_id(), depth + 1)),
+            Volatile(comm) => format!("volatile {}", self.type_name(comm.type_id(), depth + 1)),
+            Restrict(comm) => format!("{} restrict", self.type_name(comm.type_id(), depth + 1)),
+            Array(_, arr) => format!("{}[{}]", self.type_name(arr.type_, depth + 1), arr.nelems),
+            FunctionProtocol(comm, params) => {
+                let params = params
+                    .iter()
+                    .map(|param| {
+                        if param.type_ == 0 {
+                            // the last parameter of variadic functions
+                            "...".to_string()
+                        } else {
+                            self.type_name(param.type_, depth + 1)
+                        }
+                    })
+                    .collect::<Vec<String>>();
+                format!(
+                    "fn({}) -> {}",
+                    params.join(", "),
+                    self.type_name(comm.type_id(), depth + 1)
+                )
+            }
+        }
+    }
+
+    /// Return the size in bytes of the type `type_id`
+    ///
+    /// Typedefs, modifiers and variables are resolved to the size of the
+    /// types they refer to. `None` is returned for types without size, e.g.,
+    /// functions and forward declarations.
+    fn type_size(&self, type_id: u32, depth: usize) -> Option<u32> {
+        use BtfType::*;
+        if type_id == 0 || depth > MAX_TYPE_DEPTH {
+            return None;
+        }
+        match self.get_type_by_id(type_id)? {
+            Pointer(..) => Some(mem::size_of::<u64>() as u32),
+            TypeDef(comm)
+            | Constant(comm)
+            | Volatile(comm)
+            | Restrict(comm)
+            | Variable(comm, _) => self.type_size(comm.type_id(), depth + 1),
+            Array(_, arr) => Some(arr.nelems * self.type_size(arr.type_, depth + 1)?),
+            type_ => type_.size(),
+        }
+    }
+
+    /// Return the size in bytes of the type `type_id`
+    pub(crate) fn size_of(&self, type_id: u32) -> Option<u32> {
+        self.type_size(type_id, 0)
+    }
+
+    /// Return the members of the structure `type_id` in declaration order
+    ///
+    /// Typedefs and modifiers are resolved. `None` is returned if `type_id`
+    /// is not a structure.
+    pub(crate) fn struct_members(&self, type_id: u32) -> Option<Vec<StructMember>> {
+        match self.resolve_type(type_id)? {
+            BtfType::Structure(_, members) => Some(
+                members
+                    .iter()
+                    .map(|memb| StructMember {
+                        name: memb.name.clone(),
+                        offset: memb.bit_offset() / 8,
+                        size: self.type_size(memb.type_id(), 0).unwrap_or(0),
+                        is_func_ptr: self.is_func_ptr(memb.type_id()),
+                    })
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Return the name of the type `type_id`
+    pub(crate) fn name_of(&self, type_id: u32) -> Option<&str> {
+        self.get_type_by_id(type_id)
+            .map(|type_| type_.common().name_raw.as_str())
+    }
+
+    /// Return the string at `offset` of the string section
+    pub(crate) fn string_at(&self, offset: u32) -> Result<String> {
+        get_type_name(&self.raw_str_enc, offset)
+    }
+
+    /// Return the name and the kind of the type `type_id` for CO-RE
+    ///
+    /// The flavor suffix of the name, e.g., `___old` of `task_struct___old`,
+    /// is stripped so that the type matches the type of the kernel.
+    pub(crate) fn core_type(&self, type_id: u32) -> Option<(String, BtfKind)> {
+        let comm = self.get_type_by_id(type_id)?.common();
+        Some((essential_name(&comm.name_raw).to_string(), comm.kind()))
+    }
+
+    /// Convert the CO-RE access string `access` of the type `type_id` to
+    /// access steps
+    ///
+    /// The first index of `access` indexes `type_id` as an array and the
+    /// others index members of structures and unions or elements of arrays.
+    pub(crate) fn core_access_steps(
+        &self,
+        type_id: u32,
+        access: &[u32],
+    ) -> std::result::Result<Vec<AccessStep>, String> {
+        use BtfType::*;
+        let (first, rest) = access
+            .split_first()
+            .ok_or_else(|| "empty access string".to_string())?;
+        let mut steps = vec![AccessStep::Index(*first)];
+        let mut type_id = type_id;
+        for idx in rest {
+            match self.resolve_type(type_id) {
+                Some(Structure(_, members)) | Some(Union(_, members)) => {
+                    let memb = members
+                        .get(*idx as usize)
+                        .ok_or_else(|| format!("member index {} is out of range", idx))?;
+                    if !memb.name.is_empty() {
+                        steps.push(AccessStep::Member(memb.name.clone()));
+                    }
+                    type_id = memb.type_id();
+                }
+                Some(Array(_, arr)) => {
+                    steps.push(AccessStep::Index(*idx));
+                    type_id = arr.type_;
+                }
+                _ => return Err(format!("index {} of non-composite type", idx)),
+            }
+        }
+        Ok(steps)
+    }
+
+    /// Return the byte offset and the size of the field reached from the
+    /// type `type_id` by `steps`
+    pub(crate) fn core_field(
+        &self,
+        type_id: u32,
+        steps: &[AccessStep],
+    ) -> std::result::Result<(u32, u32), String> {
+        use BtfType::*;
+        let (first, steps) = match steps.split_first() {
+            Some((AccessStep::Index(idx), steps)) => (*idx, steps),
+            _ => return Err("access string does not start with an index".to_string()),
+        };
+        let mut type_id = type_id;
+        let mut bit_offset = match first {
+            0 => 0,
+            idx => {
+                let size = self
+                    .type_size(type_id, 0)
+                    .ok_or_else(|| format!("size of {} is unknown", self.type_name(type_id, 0)))?;
+                idx * size * 8
+            }
+        };
+        for step in steps.iter() {
+            match step {
+                AccessStep::Member(name) => {
+                    let (offset, memb_type_id, is_bitfield) =
+                        self.find_member(type_id, name, 0).ok_or_else(|| {
+                            format!("{} has no member {}", self.type_name(type_id, 0), name)
+                        })?;
+                    if is_bitfield {
+                        return Err(format!("bitfield {} is not supported", name));
+                    }
+                    bit_offset += offset;
+                    type_id = memb_type_id;
+                }
+                AccessStep::Index(idx) => match self.resolve_type(type_id) {
+                    Some(Array(_, arr)) => {
+                        if arr.nelems != 0 && *idx >= arr.nelems {
+                            return Err(format!("index {} is out of array bounds", idx));
+                        }
+                        let elem_size = self
+                            .type_size(arr.type_, 0)
+                            .ok_or_else(|| "array element has no size".to_string())?;
+                        bit_offset += idx * elem_size * 8;
+                        type_id = arr.type_;
+                    }
+                    _ => return Err(format!("{} is not an array", self.type_name(type_id, 0))),
+                },
+            }
+        }
+        let size = self
+            .type_size(type_id, 0)
+            .ok_or_else(|| format!("size of {} is unknown", self.type_name(type_id, 0)))?;
+        Ok((bit_offset / 8, size))
+    }
+
+    // Find the member `name` of the structure or union `type_id`, looking
+    // through anonymous members. Return its bit offset, type id and whether
+    // it is a bitfield.
+    fn find_member(&self, type_id: u32, name: &str, depth: usize) -> Option<(u32, u32, bool)> {
+        use BtfType::*;
+        if depth > MAX_TYPE_DEPTH {
+            return None;
+        }
+        let (comm, members) = match self.resolve_type(type_id)? {
+            Structure(comm, members) | Union(comm, members) => (comm, members),
+            _ => return None,
+        };
+        members.iter().find_map(|memb| {
+            let is_bitfield = comm.kind_flag() && memb.bitfield_size() != 0;
+            if memb.name == name {
+                Some((memb.bit_offset(), memb.type_id(), is_bitfield))
+            } else if memb.name.is_empty() {
+                self.find_member(memb.type_id(), name, depth + 1).map(
+                    |(offset, type_id, is_bitfield)| {
+                        (memb.bit_offset() + offset, type_id, is_bitfield)
+                    },
+                )
+            } else {
+                None
+            }
+        })
+    }
+
+    // Resolve typedefs and modifiers to the type they refer to
+    fn resolve_type(&self, mut type_id: u32) -> Option<&BtfType> {
+        use BtfType::*;
+        for _ in 0..MAX_TYPE_DEPTH {
+            match self.get_type_by_id(type_id)? {
+                TypeDef(comm) | Constant(comm) | Volatile(comm) | Restrict(comm) => {
+                    type_id = comm.type_id()
+                }
+                type_ => return Some(type_),
+            }
+        }
+        None
+    }
+
+    fn is_func_ptr(&self, type_id: u32) -> bool {
+        match self.resolve_type(type_id) {
+            Some(BtfType::Pointer(comm)) => matches!(
+                self.resolve_type(comm.type_id()),
+                Some(BtfType::FunctionProtocol(..))
+            ),
+            _ => false,
+        }
+    }
+
+    /// Pretty-print all types in C-like notation
+    ///
+    /// Each type is printed with its type id, kind, name and size. Members of
+    /// structures and unions are printed with their types, offsets and sizes
+    /// so the layout of maps and CO-RE relocations can be inspected.
+    fn pretty_print(&self) -> String {
+        use fmt::Write;
+        use BtfType::*;
+        let mut out = String::new();
+        let size_str = |size: Option<u32>| {
+            size.map(|sz| sz.to_string())
+                .unwrap_or_else(|| "?".to_string())
+        };
+        for (type_id, type_) in self.types.iter() {
+            let _ = write!(out, "[{}] {} ", type_id, type_.type_str());
+            let _ = match type_ {
+                Integer(comm, enc) => writeln!(
+                    out,
+                    "'{}' size={} bits={}",
+                    comm.name_raw,
+                    comm.size(),
+                    btf_int_bits(*enc)
+                ),
+                Structure(comm, membs) | Union(comm, membs) => {
+                    let _ = writeln!(
+                        out,
+                        "'{}' size={} vlen={}",
+                        self.type_name(*type_id, 0),
+                        comm.size(),
+                        membs.len()
+                    );
+                    for memb in membs.iter() {
+                        let _ = write!(
+                            out,
+                            "\t'{}' {} offset={}",
+                            memb.name,
+                            self.type_name(memb.type_id(), 0),
+                            memb.bit_offset() / 8
+                        );
+                        if memb.bit_offset() % 8 != 0 {
+                            let _ = write!(out, "+{}bits", memb.bit_offset() % 8);
+                        }
+                        let _ = if comm.kind_flag() && memb.bitfield_size() != 0 {
+                            writeln!(out, " bitfield_size={}", memb.bitfield_size())
+                        } else {
+                            writeln!(out, " size={}", size_str(self.type_size(memb.type_id(), 0)))
+                        };
+                    }
+                    Ok(())
+                }
+                Enumeration(comm, enums) => {
+                    let _ = writeln!(
+                        out,
+                        "'{}' size={} vlen={}",
+                        self.type_name(*type_id, 0),
+                        comm.size(),
+                        enums.len()
+                    );
+                    for enu in enums.iter() {
+                        let name = get_type_name(&self.raw_str_enc, enu.name_off)
+                            .unwrap_or_else(|_| "(invalid)".to_string());
+                        let _ = writeln!(out, "\t'{}' = {}", name, enu.val);
+                    }
+                    Ok(())
+                }
+                FunctionProtocol(_, params) => {
+                    let _ = writeln!(out, "'{}'", self.type_name(*type_id, 0));
+                    for param in params.iter() {
+                        let name = get_type_name(&self.raw_str_enc, param.name_off)
+                            .unwrap_or_else(|_| "(invalid)".to_string());
+                        let _ = writeln!(out, "\t'{}' {}", name, self.type_name(param.type_, 0));
+                    }
+                    Ok(())
+                }
+                DataSection(comm, vsis) => {
+                    let _ = writeln!(
+                        out,
+                        "'{}' size={} vlen={}",
+                        comm.name_raw,
+                        comm.size(),
+                        vsis.len()
+                    );
+                    for vsi in vsis.iter() {
+                        let _ = writeln!(
+                            out,
+                            "\t{} offset={} size={}",
+                            self.type_name(vsi.type_, 0),
+                            vsi.offset,
+                            vsi.size
+                        );
+                    }
+                    Ok(())
+                }
+                Variable(comm, _) => writeln!(
+                    out,
+                    "'{}' {} size={}",
+                    comm.name_raw,
+                    self.type_name(comm.type_id(), 0),
+                    size_str(self.type_size(*type_id, 0))
+                ),
+                Function(comm) => writeln!(
+                    out,
+                    "'{}' {}",
+                    comm.name_raw,
+                    self.type_name(comm.type_id(), 0)
+                ),
+                TypeDef(comm) => writeln!(
+                    out,
+                    "'{}' {} size={}",
+                    comm.name_raw,
+                    self.type_name(comm.type_id(), 0),
+                    size_str(self.type_size(*type_id, 0))
+                ),
+                _ => writeln!(
+                    out,
+                    "'{}' size={}",
+                    self.type_name(*type_id, 0),
+                    size_str(self.type_size(*type_id, 0))
+                ),
+            };
+        }
+        out
+    }
+}
+
+// Renders BTF types as Rust types of the same layout, see `map_rust_types`
+struct RustTypeRenderer<'b> {
+    btf: &'b BTF,
+    // type id of a structure => its Rust name and alignment
+    structs: RSHashMap<u32, (String, u32)>,
+    definitions: Vec<String>,
+}
+
+impl<'b> RustTypeRenderer<'b> {
+    fn new(btf: &'b BTF) -> Self {
+        RustTypeRenderer {
+            btf,
+            structs: RSHashMap::new(),
+            definitions: Vec::new(),
+        }
+    }
+
+    /// Return the Rust type of `type_id` and its alignment
+    fn render(&mut self, type_id: u32, depth: usize) -> Result<(String, u32)> {
+        use BtfType::*;
+        if depth > MAX_TYPE_DEPTH {
+            return Err(Error::BTF(format!(
+                "type_id={} is nested too deep",
+                type_id
+            )));
+        }
+        let type_ = self
+            .btf
+            .get_type_by_id(type_id)
+            .ok_or_else(|| Error::BTF(format!("invalid type_id={}", type_id)))?;
+        match type_ {
+            TypeDef(comm) | Constant(comm) | Volatile(comm) | Restrict(comm) => {
+                self.render(comm.type_id(), depth + 1)
+            }
+            Integer(comm, enc) => {
+                let size = comm.size();
+                if btf_int_offset(*enc) != 0 || btf_int_bits(*enc) != size * 8 {
+                    return self.bytes(type_id);
+                }
+                // booleans are not guaranteed to be 0 or 1, so they are
+                // rendered as integers too
+                let signed = btf_int_encoding(*enc) & BTF_INT_SIGNED != 0;
+                match (size, signed) {
+                    (1 | 2 | 4 | 8, true) => Ok((format!("i{}", size * 8), size)),
+                    (1 | 2 | 4 | 8, false) => Ok((format!("u{}", size * 8), size)),
+                    _ => self.bytes(type_id),
+                }
+            }
+            FloatingPoint(comm) => match comm.size() {
+                4 => Ok(("f32".to_string(), 4)),
+                8 => Ok(("f64".to_string(), 8)),
+                _ => self.bytes(type_id),
+            },
+            Enumeration(comm, _) => match comm.size() {
+                size @ (1 | 2 | 4 | 8) => Ok((format!("u{}", size * 8), size)),
+                _ => self.bytes(type_id),
+            },
+            Pointer(_) => Ok(("u64".to_string(), mem::size_of::<u64>() as u32)),
+            Array(_, arr) => {
+                let (elem, align) = self.render(arr.type_, depth + 1)?;
+                Ok((format!("[{}; {}]", elem, arr.nelems), align))
+            }
+            Structure(comm, members) => self.structure(type_id, comm, members, depth),
+            Union(..) => self.bytes(type_id),
+            _ => Err(Error::BTF(format!(
+                "`{}` has no Rust counterpart",
+                self.btf.type_name(type_id, 0)
+            ))),
+        }
+    }
+
+    // Types without a Rust counterpart are rendered as byte arrays
+    fn bytes(&self, type_id: u32) -> Result<(String, u32)> {
+        let size = self.btf.type_size(type_id, 0).ok_or_else(|| {
+            Error::BTF(format!(
+                "size of `{}` is unknown",
+                self.btf.type_name(type_id, 0)
+            ))
+        })?;
+        Ok((format!("[u8; {}]", size), 1))
+    }
+
+    fn structure(
+        &mut self,
+        type_id: u32,
+        comm: &BtfTypeCommon,
+        members: &[BtfMember],
+        depth: usize,
+    ) -> Result<(String, u32)> {
+        use fmt::Write;
+        if let Some(rendered) = self.structs.get(&type_id) {
+            return Ok(rendered.clone());
+        }
+        let mut fields = Vec::new();
+        let mut align = 1;
+        let mut offset = 0;
+        for (i, memb) in members.iter().enumerate() {
+            if (comm.kind_flag() && memb.bitfield_size() != 0) || memb.bit_offset() % 8 != 0 {
+                return self.bytes(type_id);
+            }
+            let memb_offset = memb.bit_offset() / 8;
+            let (type_name, memb_align) = self.render(memb.type_id(), depth + 1)?;
+            // packed structures can not be laid out by #[repr(C)]
+            if memb_offset < offset || memb_offset % memb_align != 0 {
+                return self.bytes(type_id);
+            }
+            if memb_offset > offset {
+                fields.push((
+                    format!("_pad{}", fields.len()),
+                    format!("[u8; {}]", memb_offset - offset),
+                ));
+            }
+            let name = if memb.name.is_empty() {
+                format!("_anon{}", i)
+            } else {
+                rust_identifier(&memb.name)
+            };
+            fields.push((name, type_name));
+            align = align.max(memb_align);
+            offset = memb_offset + self.btf.type_size(memb.type_id(), 0).unwrap_or(0);
+        }
+        if comm.size() > offset {
+            fields.push((
+                format!("_pad{}", fields.len()),
+                format!("[u8; {}]", comm.size() - offset),
+            ));
+        }
+
+        let mut name = if comm.name_raw.is_empty() {
+            format!("Anon{}", type_id)
+        } else {
+            fix_btf_identifier(comm.name_raw.rsplit("::").next().unwrap())
+        };
+        if self.structs.values().any(|(other, _)| *other == name) {
+            name = format!("{}{}", name, type_id);
+        }
+        self.structs.insert(type_id, (name.clone(), align));
+
+        let mut definition = String::new();
+        let _ = writeln!(definition, "#[repr(C)]");
+        let _ = writeln!(definition, "#[derive(Debug, Clone, Copy)]");
+        let _ = writeln!(definition, "#[allow(non_camel_case_types, non_snake_case)]");
+        let _ = writeln!(definition, "pub struct {} {{", name);
+        for (field, type_name) in fields.iter() {
+            let _ = writeln!(definition, "    pub {}: {},", field, type_name);
+        }
+        let _ = writeln!(definition, "}}");
+        self.definitions.push(definition);
+        Ok((name, align))
+    }
+}
+
 impl Drop for BTF {
     fn drop(&mut self) {
         if let Some(fd) = self.fd {
@@ -857,6 +1826,28 @@ impl BtfType {
         Ok(type_)
     }
 
+    fn common(&self) -> &BtfTypeCommon {
+        use BtfType::*;
+        match self {
+            Integer(comm, _)
+            | Pointer(comm)
+            | Array(comm, _)
+            | Structure(comm, _)
+            | Union(comm, _)
+            | Enumeration(comm, _)
+            | Forward(comm)
+            | TypeDef(comm)
+            | Volatile(comm)
+            | Constant(comm)
+            | Restrict(comm)
+            | Function(comm)
+            | FunctionProtocol(comm, _)
+            | Variable(comm, _)
+            | DataSection(comm, _)
+            | FloatingPoint(comm) => comm,
+        }
+    }
+
     fn type_str(&self) -> String {
         use BtfType::*;
         match self {
@@ -1238,6 +2229,22 @@ fn fix_btf_identifier(btf_id: &str) -> String {
         .collect()
 }
 
+// Keywords can not be used as field names, so they are suffixed with `_`
+fn rust_identifier(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else",
+        "enum", "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod",
+        "move", "mut", "pub", "ref", "return", "self", "Self", "static", "struct", "super",
+        "trait", "true", "try", "type", "unsafe", "use", "where", "while", "yield",
+    ];
+    let name = fix_btf_identifier(name);
+    if KEYWORDS.contains(&name.as_str()) {
+        format!("{}_", name)
+    } else {
+        name
+    }
+}
+
 fn fix_btf_name(btf_name: &str) -> String {
     btf_name
         .chars()
@@ -1266,7 +2273,10 @@ fn get_section_name<'o>(object: &'o Elf, shdr: &SectionHeader) -> Option<&'o str
     object.shdr_strtab.get_unsafe(shdr.sh_name)
 }
 
-fn get_section_header_by_name<'o>(object: &'o Elf, name: &str) -> Option<&'o SectionHeader> {
+pub(crate) fn get_section_header_by_name<'o>(
+    object: &'o Elf,
+    name: &str,
+) -> Option<&'o SectionHeader> {
     object.section_headers.iter().find(|shdr| {
         if let Some(n) = get_section_name(object, shdr) {
             if n == name {
@@ -1277,6 +2287,17 @@ fn get_section_header_by_name<'o>(object: &'o Elf, name: &str) -> Option<&'o Sec
     })
 }
 
+// Strip the flavor suffix of a CO-RE type name, e.g., `___old` of
+// `task_struct___old`. Like libbpf, the last `___` that is not part of a
+// longer run of underscores starts the suffix.
+fn essential_name(name: &str) -> &str {
+    let bytes = name.as_bytes();
+    (0..bytes.len().saturating_sub(4))
+        .rev()
+        .find(|&i| bytes[i] != b'_' && &bytes[i + 1..i + 4] == b"___" && bytes[i + 4] != b'_')
+        .map_or(name, |i| &name[..i + 1])
+}
+
 fn get_type_name(str_bytes: &[u8], name_off: u32) -> Result<String> {
     if name_off as usize >= str_bytes.len() {
         return Err(Error::BTF("name offset is out of string data".to_string()));
@@ -1288,6 +2309,96 @@ fn get_type_name(str_bytes: &[u8], name_off: u32) -> Result<String> {
     })
 }
 
+/// Extract the raw `.BTF` section of the ELF relocatable file `elf_bytes`
+///
+/// The section is validated by parsing it but it is returned as it is in the
+/// ELF file, so it can be examined or transformed by other BTF tools.
+pub fn extract_btf_section(elf_bytes: &[u8]) -> Result<Vec<u8>> {
+    let object = Elf::parse(elf_bytes)?;
+    let shdr = get_section_header_by_name(&object, BTF_SECTION_NAME)
+        .ok_or_else(|| Error::BTF("section not found".to_string()))?;
+    let raw_btf = (shdr.sh_offset as usize)
+        .checked_add(shdr.sh_size as usize)
+        .and_then(|end| elf_bytes.get(shdr.sh_offset as usize..end))
+        .ok_or_else(|| Error::BTF("BTF section is out of ELF data".to_string()))?;
+    BTF::parse_raw(raw_btf)?;
+    Ok(raw_btf.to_vec())
+}
+
+/// Pretty-print the types of the `.BTF` section of the ELF relocatable file
+/// `elf_bytes`
+///
+/// Each line shows a type id, the kind and the C-like name of the type and
+/// its size. Members of structures and unions follow their parent type,
+/// indented by a tab, with their types, byte offsets and sizes.
+pub fn dump_btf_section(elf_bytes: &[u8]) -> Result<String> {
+    let object = Elf::parse(elf_bytes)?;
+    let btf = BTF::parse_elf(&object, elf_bytes)?;
+    Ok(btf.pretty_print())
+}
+
+/// The key and value types of a map rendered as Rust types
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapRustTypes {
+    /// The name of the map
+    pub name: String,
+    /// The Rust type of the keys, e.g., `u32` or `[u8; 16]`
+    pub key: String,
+    /// The Rust type of the values
+    pub value: String,
+}
+
+/// The Rust types of the maps of an ELF relocatable file, see
+/// [`map_rust_types`]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RustTypes {
+    /// The maps whose key and value types are described by BTF, sorted by
+    /// name
+    pub maps: Vec<MapRustTypes>,
+    /// The definitions of the structures the key and value types refer to
+    pub definitions: Vec<String>,
+}
+
+/// Render the key and value types of the maps of the ELF relocatable file
+/// `elf_bytes` as Rust types
+///
+/// The types are read from the `MAP_BTF_<map name>` variables that `#[map]`
+/// emits. Integers and floats are rendered as Rust primitives, pointers as
+/// `u64` and structures as `#[repr(C)]` structures with explicit padding, so
+/// they have the same layout as the BPF program sees. Types without a Rust
+/// counterpart, e.g., unions and structures with bitfields, are rendered as
+/// byte arrays of the same size.
+pub fn map_rust_types(elf_bytes: &[u8]) -> Result<RustTypes> {
+    let object = Elf::parse(elf_bytes)?;
+    let btf = BTF::parse_elf(&object, elf_bytes)?;
+    let mut names = btf
+        .types
+        .iter()
+        .filter_map(|(_, type_)| match type_ {
+            BtfType::Variable(comm, _) => comm.name_raw.strip_prefix("MAP_BTF_"),
+            _ => None,
+        })
+        .collect::<Vec<&str>>();
+    names.sort_unstable();
+
+    let mut renderer = RustTypeRenderer::new(&btf);
+    let mut maps = Vec::new();
+    for name in names {
+        let (key_type_id, value_type_id) = btf.map_key_value_type_ids(name)?;
+        let (key, _) = renderer.render(key_type_id, 0)?;
+        let (value, _) = renderer.render(value_type_id, 0)?;
+        maps.push(MapRustTypes {
+            name: name.to_string(),
+            key,
+            value,
+        });
+    }
+    Ok(RustTypes {
+        maps,
+        definitions: renderer.definitions,
+    })
+}
+
 /// Fix .BTF section for `tc` command that depends on legacy BPF
 ///
 /// BTF types generated by rustc contain invalid characters from the point of
@@ -1341,3 +2452,95 @@ pub fn tc_legacy_fix_btf_section(elf_bytes: &[u8]) -> Result<Vec<u8>> {
     fixed.extend(&elf_bytes[end..]);
     Ok(fixed)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Build raw BTF data of `types` and `strings` following btf_header
+    fn raw_btf(types: &[u32], strings: &[u8]) -> Vec<u8> {
+        let hdr_len = mem::size_of::<btf_header>() as u32;
+        let type_len = (types.len() * 4) as u32;
+        let mut raw = vec![];
+        raw.extend(&(BTF_MAGIC as u16).to_ne_bytes());
+        raw.extend(&[1u8, 0u8]);
+        for field in &[hdr_len, 0, type_len, type_len, strings.len() as u32] {
+            raw.extend(&field.to_ne_bytes());
+        }
+        for word in types {
+            raw.extend(&word.to_ne_bytes());
+        }
+        raw.extend(strings);
+        raw
+    }
+
+    #[test]
+    fn test_essential_name() {
+        assert_eq!(essential_name("task_struct"), "task_struct");
+        assert_eq!(essential_name("task_struct___old"), "task_struct");
+        assert_eq!(essential_name("foo___bar___v2"), "foo___bar");
+        assert_eq!(essential_name("__u32"), "__u32");
+        assert_eq!(essential_name("x____y"), "x____y");
+    }
+
+    #[test]
+    fn test_pretty_print() {
+        let strings = b"\0int\0pair\0a\0b\0";
+        let types = [
+            // [1] int, signed 32 bits
+            1,
+            BTF_KIND_INT << 24,
+            4,
+            (BTF_INT_SIGNED << 24) | 32,
+            // [2] struct pair { int a; int b; }
+            5,
+            (BTF_KIND_STRUCT << 24) | 2,
+            8,
+            10,
+            1,
+            0,
+            12,
+            1,
+            32,
+        ];
+        let btf = BTF::parse_raw(&raw_btf(&types, strings)).unwrap();
+        assert_eq!(
+            btf.pretty_print(),
+            "[1] Integer 'int' size=4 bits=32\n\
+             [2] Structure 'struct pair' size=8 vlen=2\n\
+             \t'a' int offset=0 size=4\n\
+             \t'b' int offset=4 size=4\n"
+        );
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_vmlinux_btf_path_is_only_for_core() {
+        let find = |btf: &BTF| btf.find_type_id("task_struct", BtfKind::Structure);
+        let kernel_id = find(&parse_vmlinux_btf().unwrap());
+        assert!(kernel_id.is_some());
+
+        // BTF of another build of the kernel has other type ids
+        let strings = b"\0task_struct\0";
+        let types = [1, BTF_KIND_STRUCT << 24, 0];
+        let path = std::env::temp_dir().join(format!("redbpf_test_{}.btf", std::process::id()));
+        fs::write(&path, raw_btf(&types, strings)).unwrap();
+        set_vmlinux_btf_path(&path);
+        let core_id = parse_core_btf().map(|btf| find(&btf));
+        let attach_id = parse_vmlinux_btf().map(|btf| find(&btf));
+        *VMLINUX_BTF_OVERRIDE.lock().unwrap() = None;
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(core_id.unwrap(), Some(1));
+        assert_eq!(attach_id.unwrap(), kernel_id);
+    }
+
+    #[test]
+    fn test_parse_raw_rejects_out_of_bounds() {
+        let mut raw = raw_btf(&[1, BTF_KIND_INT << 24, 4, 32], b"\0int\0");
+        // type_off beyond the data
+        raw[8..12].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert!(BTF::parse_raw(&raw).is_err());
+        assert!(BTF::parse_raw(&raw[..10]).is_err());
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Resolution of cgroup ids to cgroup paths
+//!
+//! BPF programs identify cgroups by the 64 bit ids returned by
+//! `bpf_get_current_cgroup_id`. These are the ids of the cgroup v2
+//! directories, so userspace maps an id back to a cgroup path, e.g., the
+//! cgroup of a container, by walking the cgroup v2 hierarchy.
+//!
+//! Walking the hierarchy is expensive, so resolved ids are cached. The
+//! hierarchy is walked again only when an id is not found in the cache, e.g.
+//! for cgroups created after the last walk.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::cgroup;
+//!
+//! let cgroup_id = 1234u64; // emitted by a BPF program
+//! match cgroup::id_to_path(cgroup_id) {
+//!     Some(path) => println!("{}", path.display()),
+//!     None => println!("unknown cgroup {}", cgroup_id),
+//! }
+//! ```
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use tracing::debug;
+
+// the unified hierarchy of the hybrid mode comes first
+const CGROUP2_ROOTS: [&str; 2] = ["/sys/fs/cgroup/unified", "/sys/fs/cgroup"];
+
+lazy_static! {
+    static ref CGROUP_PATHS: Mutex<HashMap<u64, PathBuf>> = Mutex::new(HashMap::new());
+}
+
+/// `struct file_handle` with room for the 8 byte handle of kernfs
+#[repr(C)]
+struct CgroupFileHandle {
+    handle_bytes: u32,
+    handle_type: i32,
+    f_handle: [u8; 8],
+}
+
+/// Return the root directory of the cgroup v2 hierarchy
+pub fn cgroup2_root() -> Option<&'static Path> {
+    CGROUP2_ROOTS
+        .iter()
+        .map(Path::new)
+        .find(|root| root.join("cgroup.controllers").exists())
+}
+
+/// Return the id of the cgroup at `path`
+///
+/// The id is read from the file handle of the directory by
+/// `name_to_handle_at(2)`, which is how the kernel exports kernfs node
+/// ids. If file handles are not supported, the inode number is used since it
+/// is the same as the id on 64 bit architectures.
+pub fn path_to_id(path: impl AsRef<Path>) -> io::Result<u64> {
+    let path = path.as_ref();
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+    let mut handle = CgroupFileHandle {
+        handle_bytes: 8,
+        handle_type: 0,
+        f_handle: [0; 8],
+    };
+    let mut mount_id: libc::c_int = 0;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_name_to_handle_at,
+            libc::AT_FDCWD,
+            cpath.as_ptr(),
+            &mut handle as *mut _,
+            &mut mount_id as *mut _,
+            0,
+        )
+    };
+    if ret == 0 && handle.handle_bytes == 8 {
+        return Ok(u64::from_ne_bytes(handle.f_handle));
+    }
+    Ok(fs::metadata(path)?.ino())
+}
+
+/// Return the path of the cgroup whose id is `id`
+///
+/// The path is absolute, e.g.,
+/// `/sys/fs/cgroup/system.slice/docker-<container id>.scope`. `None` is
+/// returned if there is no cgroup v2 hierarchy or no cgroup has the id, e.g.,
+/// the cgroup has been removed.
+pub fn id_to_path(id: u64) -> Option<PathBuf> {
+    let mut paths = CGROUP_PATHS.lock().unwrap();
+    if let Some(path) = paths.get(&id) {
+        return Some(path.clone());
+    }
+    let root = cgroup2_root()?;
+    paths.clear();
+    walk(root, &mut paths);
+    debug!("{} cgroups found under {}", paths.len(), root.display());
+    paths.get(&id).cloned()
+}
+
+/// Forget all cached cgroup paths
+pub fn clear_cache() {
+    CGROUP_PATHS.lock().unwrap().clear();
+}
+
+fn walk(dir: &Path, paths: &mut HashMap<u64, PathBuf>) {
+    if let Ok(id) = path_to_id(dir) {
+        paths.insert(id, dir.to_path_buf());
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        // cgroups can be removed while walking
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        // `file_type` does not follow symlinks so no cycle is possible
+        if entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false) {
+            walk(&entry.path(), paths);
+        }
+    }
+}
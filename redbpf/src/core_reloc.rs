@@ -0,0 +1,629 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! CO-RE relocations of BPF programs
+//!
+//! BPF programs compiled by clang with `__builtin_preserve_access_index`, or
+//! with `bpf_core_read` and `bpf_core_field_exists` of libbpf, record the
+//! fields they access in the `.BTF.ext` section. Before the programs are
+//! loaded, the offsets and sizes of these fields are looked up in the BTF of
+//! the running kernel and the instructions are patched with them.
+//!
+//! A relocation that can not be resolved, e.g., because the field was
+//! renamed in the running kernel, does not fail the whole module. The
+//! instruction is poisoned instead so that the verifier rejects the program
+//! only if the instruction is reachable, which lets programs guard the
+//! access with `bpf_core_field_exists`. The failures are logged and
+//! returned by
+//! [`Module::core_relocation_failures`](../struct.Module.html#method.core_relocation_failures)
+//! so that the reason can be reported when a program fails to load.
+//!
+//! The func_info of `.BTF.ext`, which describes the functions of programs,
+//! is parsed here too.
+use std::collections::HashMap as RSHashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::mem;
+
+use goblin::elf::Elf;
+use libbpf_sys::{bpf_func_info, bpf_insn};
+use tracing::{debug, error, warn};
+
+use crate::btf::{self, AccessStep, BtfKind, BTF};
+use crate::insn::{
+    call, ja, BPF_ALU, BPF_ALU64, BPF_DW, BPF_IMM, BPF_LD, BPF_LDX, BPF_ST, BPF_STX,
+};
+use crate::{Error, Result};
+
+const BTF_EXT_SECTION_NAME: &str = ".BTF.ext";
+const BTF_EXT_MAGIC: u16 = 0xeb9f;
+// the offsets of func_info_off and core_relo_off in the header
+const FUNC_INFO_FIELD_OFF: usize = 8;
+const CORE_RELO_FIELD_OFF: usize = 24;
+const FUNC_INFO_RECORD_LEN: usize = 8;
+const CORE_RELO_RECORD_LEN: usize = 16;
+// the helper call that poisons an instruction, the same as libbpf
+const POISON_HELPER: u32 = 0xbad2310;
+
+/// Kinds of CO-RE relocations
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CoreRelocationKind {
+    FieldByteOffset,
+    FieldByteSize,
+    FieldExists,
+    FieldSigned,
+    FieldLShiftU64,
+    FieldRShiftU64,
+    TypeIdLocal,
+    TypeIdTarget,
+    TypeExists,
+    TypeSize,
+    EnumValueExists,
+    EnumValue,
+    Unknown(u32),
+}
+
+/// A CO-RE relocation that could not be applied
+///
+/// The instruction of the relocation is poisoned so the verifier rejects
+/// the program if the instruction is reachable.
+#[derive(Debug, Clone)]
+pub struct CoreRelocationFailure {
+    /// The section of the program, e.g., `kprobe/tcp_connect`
+    pub section: String,
+    /// The index of the relocated instruction
+    pub insn_idx: usize,
+    pub kind: CoreRelocationKind,
+    /// The relocated field or type, e.g., `task_struct.pid`
+    pub target: String,
+    pub reason: String,
+}
+
+// A relocation with its access string resolved with the BTF of the program
+#[derive(Debug)]
+pub(crate) struct CoreRelocation {
+    insn_idx: usize,
+    kind: CoreRelocationKind,
+    // name and kind of the root type. None if the type is anonymous
+    root: Option<(String, BtfKind)>,
+    steps: std::result::Result<Vec<AccessStep>, String>,
+    target: String,
+}
+
+// The relocations of the program in section `shndx`
+#[derive(Debug)]
+pub(crate) struct CoreRelocationSection {
+    pub(crate) shndx: usize,
+    pub(crate) section: String,
+    relocations: Vec<CoreRelocation>,
+}
+
+impl From<u32> for CoreRelocationKind {
+    fn from(kind: u32) -> Self {
+        use CoreRelocationKind::*;
+        match kind {
+            0 => FieldByteOffset,
+            1 => FieldByteSize,
+            2 => FieldExists,
+            3 => FieldSigned,
+            4 => FieldLShiftU64,
+            5 => FieldRShiftU64,
+            6 => TypeIdLocal,
+            7 => TypeIdTarget,
+            8 => TypeExists,
+            9 => TypeSize,
+            10 => EnumValueExists,
+            11 => EnumValue,
+            _ => Unknown(kind),
+        }
+    }
+}
+
+impl CoreRelocationKind {
+    fn is_field_based(&self) -> bool {
+        use CoreRelocationKind::*;
+        matches!(
+            self,
+            FieldByteOffset
+                | FieldByteSize
+                | FieldExists
+                | FieldSigned
+                | FieldLShiftU64
+                | FieldRShiftU64
+        )
+    }
+}
+
+impl fmt::Display for CoreRelocationKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use CoreRelocationKind::*;
+        match self {
+            FieldByteOffset => write!(f, "byte_off"),
+            FieldByteSize => write!(f, "byte_sz"),
+            FieldExists => write!(f, "field_exists"),
+            FieldSigned => write!(f, "signed"),
+            FieldLShiftU64 => write!(f, "lshift_u64"),
+            FieldRShiftU64 => write!(f, "rshift_u64"),
+            TypeIdLocal => write!(f, "local_type_id"),
+            TypeIdTarget => write!(f, "target_type_id"),
+            TypeExists => write!(f, "type_exists"),
+            TypeSize => write!(f, "type_size"),
+            EnumValueExists => write!(f, "enumval_exists"),
+            EnumValue => write!(f, "enumval_value"),
+            Unknown(kind) => write!(f, "unknown({})", kind),
+        }
+    }
+}
+
+impl fmt::Display for CoreRelocationFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} insn #{}: {} relocation of {} failed: {}",
+            self.section, self.insn_idx, self.kind, self.target, self.reason
+        )
+    }
+}
+
+/// Parse the CO-RE relocations of `.BTF.ext` of the ELF relocatable file
+///
+/// `btf` is the BTF of the same file. An empty vector is returned if there
+/// is no `.BTF.ext` section.
+pub(crate) fn parse_btf_ext(
+    object: &Elf,
+    bytes: &[u8],
+    btf: &BTF,
+) -> Result<Vec<CoreRelocationSection>> {
+    let data = match btf_ext_section(object, bytes)? {
+        Some(data) => data,
+        None => return Ok(vec![]),
+    };
+    let relo_data = btf_ext_data(data, CORE_RELO_FIELD_OFF)?;
+    let mut sections = vec![];
+    for (sec_name_off, records) in parse_core_relo_data(relo_data)? {
+        let section = btf.string_at(sec_name_off)?;
+        let shndx = object
+            .section_headers
+            .iter()
+            .position(|shdr| object.shdr_strtab.get_at(shdr.sh_name) == Some(section.as_str()))
+            .ok_or_else(|| {
+                error!("section `{}' of CO-RE relocations not found", section);
+                Error::Section(section.clone())
+            })?;
+        let mut relocations = vec![];
+        for record in records {
+            let access = btf.string_at(record.access_str_off)?;
+            relocations.push(CoreRelocation::resolve(btf, &record, &access));
+        }
+        debug!(
+            "{} CO-RE relocations in section `{}'",
+            relocations.len(),
+            section
+        );
+        sections.push(CoreRelocationSection {
+            shndx,
+            section,
+            relocations,
+        });
+    }
+    Ok(sections)
+}
+
+/// Parse the func_info of `.BTF.ext` of the ELF relocatable file
+///
+/// The func_info of each section is returned by the section index. The
+/// offsets of the records are converted from bytes to instructions as the
+/// kernel expects. An empty map is returned if there is no `.BTF.ext`
+/// section.
+pub(crate) fn parse_func_info(
+    object: &Elf,
+    bytes: &[u8],
+    btf: &BTF,
+) -> Result<RSHashMap<usize, Vec<bpf_func_info>>> {
+    let mut func_info = RSHashMap::new();
+    let data = match btf_ext_section(object, bytes)? {
+        Some(data) => data,
+        None => return Ok(func_info),
+    };
+    let data = btf_ext_data(data, FUNC_INFO_FIELD_OFF)?;
+    for (sec_name_off, records) in parse_btf_ext_records(data, FUNC_INFO_RECORD_LEN)? {
+        let section = btf.string_at(sec_name_off)?;
+        let shndx = match object
+            .section_headers
+            .iter()
+            .position(|shdr| object.shdr_strtab.get_at(shdr.sh_name) == Some(section.as_str()))
+        {
+            Some(shndx) => shndx,
+            None => continue,
+        };
+        let records = records
+            .into_iter()
+            .map(|rec| bpf_func_info {
+                insn_off: read_u32(rec, 0) / mem::size_of::<bpf_insn>() as u32,
+                type_id: read_u32(rec, 4),
+            })
+            .collect();
+        func_info.insert(shndx, records);
+    }
+    Ok(func_info)
+}
+
+/// Apply the CO-RE relocations of `section` to `code` with `target_btf`
+///
+/// The relocations that can not be applied are returned and their
+/// instructions are poisoned.
+pub(crate) fn apply(
+    code: &mut [bpf_insn],
+    section: &CoreRelocationSection,
+    target_btf: &BTF,
+) -> Vec<CoreRelocationFailure> {
+    let mut failures = vec![];
+    for relo in section.relocations.iter() {
+        let result = relo
+            .target_value(target_btf)
+            .and_then(|value| patch_insn(code, relo.insn_idx, value));
+        if let Err(reason) = result {
+            if relo.insn_idx < code.len() {
+                poison_insn(code, relo.insn_idx);
+            }
+            let failure = CoreRelocationFailure {
+                section: section.section.clone(),
+                insn_idx: relo.insn_idx,
+                kind: relo.kind,
+                target: relo.target.clone(),
+                reason,
+            };
+            warn!("{}", failure);
+            failures.push(failure);
+        }
+    }
+    failures
+}
+
+#[derive(Debug)]
+struct CoreReloRecord {
+    insn_off: u32,
+    type_id: u32,
+    access_str_off: u32,
+    kind: u32,
+}
+
+// Return the contents of the `.BTF.ext` section of the ELF file, or None if
+// there is no such section
+fn btf_ext_section<'a>(object: &Elf, bytes: &'a [u8]) -> Result<Option<&'a [u8]>> {
+    let shdr = match btf::get_section_header_by_name(object, BTF_EXT_SECTION_NAME) {
+        Some(shdr) => shdr,
+        None => return Ok(None),
+    };
+    let start = shdr.sh_offset as usize;
+    match start
+        .checked_add(shdr.sh_size as usize)
+        .and_then(|end| bytes.get(start..end))
+    {
+        Some(data) => Ok(Some(data)),
+        None => {
+            error!("{} section is out of the ELF file", BTF_EXT_SECTION_NAME);
+            Err(Error::BTF("invalid .BTF.ext section".to_string()))
+        }
+    }
+}
+
+// Return the subsection of the `.BTF.ext` section `data` of which offset and
+// length are at `field_off` of the header, e.g., core_relo data
+fn btf_ext_data(data: &[u8], field_off: usize) -> Result<&[u8]> {
+    let invalid = |msg: &str| {
+        error!("invalid {} section: {}", BTF_EXT_SECTION_NAME, msg);
+        Err(Error::BTF(msg.to_string()))
+    };
+    if data.len() < 8 {
+        return invalid("too short header");
+    }
+    if read_u16(data, 0) != BTF_EXT_MAGIC {
+        return invalid("bad magic");
+    }
+    let hdr_len = read_u32(data, 4) as usize;
+    // old compilers emit the header without core_relo_off and core_relo_len
+    if hdr_len < field_off + 8 {
+        return Ok(&[]);
+    }
+    if data.len() < hdr_len {
+        return invalid("too short header");
+    }
+    let start = hdr_len.checked_add(read_u32(data, field_off) as usize);
+    let len = read_u32(data, field_off + 4) as usize;
+    match start
+        .and_then(|start| Some(start..start.checked_add(len)?))
+        .and_then(|range| data.get(range))
+    {
+        Some(subsection) => Ok(subsection),
+        None => invalid("subsection is out of the section"),
+    }
+}
+
+// The records of each section of a `.BTF.ext` subsection, by the offset of
+// the section name
+type BtfExtRecords<'a> = Vec<(u32, Vec<&'a [u8]>)>;
+
+// Parse a subsection of `.BTF.ext` that consists of the record size and the
+// sections of records. The records are returned as byte slices
+fn parse_btf_ext_records(data: &[u8], min_record_len: usize) -> Result<BtfExtRecords> {
+    let mut sections = vec![];
+    if data.is_empty() {
+        return Ok(sections);
+    }
+    let invalid = |msg: &str| {
+        error!("invalid {} records: {}", BTF_EXT_SECTION_NAME, msg);
+        Err(Error::BTF(msg.to_string()))
+    };
+    if data.len() < 4 {
+        return invalid("no record size");
+    }
+    let record_len = read_u32(data, 0) as usize;
+    if record_len < min_record_len {
+        return invalid("too small record size");
+    }
+    let mut off = 4;
+    while off < data.len() {
+        if data.len() - off < 8 {
+            return invalid("truncated section header");
+        }
+        let sec_name_off = read_u32(data, off);
+        let num_info = read_u32(data, off + 4) as usize;
+        off += 8;
+        let records_len = match num_info
+            .checked_mul(record_len)
+            .filter(|&len| len <= data.len() - off)
+        {
+            Some(len) => len,
+            None => return invalid("truncated records"),
+        };
+        let records = data[off..off + records_len]
+            .chunks_exact(record_len)
+            .collect();
+        off += records_len;
+        sections.push((sec_name_off, records));
+    }
+    Ok(sections)
+}
+
+// Parse core_relo data into the records of each section
+fn parse_core_relo_data(data: &[u8]) -> Result<Vec<(u32, Vec<CoreReloRecord>)>> {
+    Ok(parse_btf_ext_records(data, CORE_RELO_RECORD_LEN)?
+        .into_iter()
+        .map(|(sec_name_off, records)| {
+            let records = records
+                .into_iter()
+                .map(|rec| CoreReloRecord {
+                    insn_off: read_u32(rec, 0),
+                    type_id: read_u32(rec, 4),
+                    access_str_off: read_u32(rec, 8),
+                    kind: read_u32(rec, 12),
+                })
+                .collect();
+            (sec_name_off, records)
+        })
+        .collect())
+}
+
+fn read_u16(data: &[u8], off: usize) -> u16 {
+    u16::from_ne_bytes(data[off..off + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], off: usize) -> u32 {
+    u32::from_ne_bytes(data[off..off + 4].try_into().unwrap())
+}
+
+fn parse_access_string(access: &str) -> std::result::Result<Vec<u32>, String> {
+    access
+        .split(':')
+        .map(|idx| {
+            idx.parse()
+                .map_err(|_| format!("invalid access string `{}'", access))
+        })
+        .collect()
+}
+
+impl CoreRelocation {
+    fn resolve(btf: &BTF, record: &CoreReloRecord, access: &str) -> Self {
+        let kind = CoreRelocationKind::from(record.kind);
+        let root = btf
+            .core_type(record.type_id)
+            .filter(|(name, _)| !name.is_empty());
+        let steps = if kind.is_field_based() {
+            parse_access_string(access)
+                .and_then(|access| btf.core_access_steps(record.type_id, &access))
+        } else {
+            Ok(vec![])
+        };
+        let mut target = root
+            .as_ref()
+            .map_or("<anon>".to_string(), |(name, _)| name.clone());
+        if let Ok(ref steps) = steps {
+            describe_steps(&mut target, steps);
+        }
+        CoreRelocation {
+            insn_idx: record.insn_off as usize / mem::size_of::<bpf_insn>(),
+            kind,
+            root,
+            steps,
+            target,
+        }
+    }
+
+    // Compute the value of the relocation with the BTF of the target kernel
+    fn target_value(&self, target_btf: &BTF) -> std::result::Result<u32, String> {
+        use CoreRelocationKind::*;
+        let (name, btf_kind) = self
+            .root
+            .as_ref()
+            .ok_or_else(|| "anonymous types can not be relocated".to_string())?;
+        let target_type_id = target_btf.find_type_id(name, *btf_kind);
+        match self.kind {
+            FieldByteOffset | FieldByteSize | FieldExists => {
+                let steps = self.steps.as_ref().map_err(|e| e.clone())?;
+                let field = target_type_id
+                    .ok_or_else(|| format!("{:?} {} not found in kernel BTF", btf_kind, name))
+                    .and_then(|type_id| target_btf.core_field(type_id, steps));
+                match (self.kind, field) {
+                    (FieldExists, Ok(_)) => Ok(1),
+                    (FieldExists, Err(e)) => {
+                        debug!("{} does not exist: {}", self.target, e);
+                        Ok(0)
+                    }
+                    (FieldByteOffset, Ok((offset, _))) => Ok(offset),
+                    (_, Ok((_, size))) => Ok(size),
+                    (_, Err(e)) => Err(e),
+                }
+            }
+            TypeExists => Ok(target_type_id.is_some() as u32),
+            TypeSize => target_type_id
+                .and_then(|type_id| target_btf.size_of(type_id))
+                .ok_or_else(|| format!("size of {:?} {} not found in kernel BTF", btf_kind, name)),
+            kind => Err(format!("{} relocations are not supported", kind)),
+        }
+    }
+}
+
+// Append the steps to the name of the root type, e.g., `task_struct.comm[0]`
+fn describe_steps(target: &mut String, steps: &[AccessStep]) {
+    for (i, step) in steps.iter().enumerate() {
+        match step {
+            AccessStep::Index(0) if i == 0 => {}
+            AccessStep::Index(idx) => target.push_str(&format!("[{}]", idx)),
+            AccessStep::Member(name) => {
+                target.push('.');
+                target.push_str(name);
+            }
+        }
+    }
+}
+
+// Patch the instruction `insn_idx` with `value`
+fn patch_insn(
+    code: &mut [bpf_insn],
+    insn_idx: usize,
+    value: u32,
+) -> std::result::Result<(), String> {
+    let insn = code
+        .get_mut(insn_idx)
+        .ok_or_else(|| format!("instruction #{} is out of the program", insn_idx))?;
+    match insn.code & 0x07 {
+        BPF_ALU | BPF_ALU64 if insn.code & 0x08 == 0 => {
+            insn.imm = value as i32;
+        }
+        BPF_LDX | BPF_ST | BPF_STX => {
+            if value > i16::MAX as u32 {
+                return Err(format!("offset {} does not fit in the instruction", value));
+            }
+            insn.off = value as i16;
+        }
+        BPF_LD if insn.code == BPF_LD | BPF_IMM | BPF_DW => {
+            insn.imm = value as i32;
+            match code.get_mut(insn_idx + 1) {
+                Some(next) => next.imm = 0,
+                None => return Err("truncated ld_imm64 instruction".to_string()),
+            }
+        }
+        _ => {
+            return Err(format!(
+                "instruction #{} of code {:#x} can not be relocated",
+                insn_idx, insn.code
+            ))
+        }
+    }
+    Ok(())
+}
+
+// Replace the instruction `insn_idx` with a call to an invalid helper so that
+// the verifier rejects the program if it is reachable
+fn poison_insn(code: &mut [bpf_insn], insn_idx: usize) {
+    let is_ld_imm64 = code[insn_idx].code == BPF_LD | BPF_IMM | BPF_DW;
+    code[insn_idx] = call(POISON_HELPER);
+    if is_ld_imm64 {
+        if let Some(next) = code.get_mut(insn_idx + 1) {
+            *next = ja(0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::insn::{ld_imm64, ldx_mem, mov64_imm, R0, R1};
+
+    #[test]
+    fn test_patch_insn() {
+        let mut code = vec![ldx_mem(BPF_DW, R0, R1, 0), mov64_imm(R0, 0)];
+        code.extend_from_slice(&ld_imm64(R0, 0xdead_beef_0000));
+        patch_insn(&mut code, 0, 16).unwrap();
+        assert_eq!(code[0].off, 16);
+        patch_insn(&mut code, 1, 1).unwrap();
+        assert_eq!(code[1].imm, 1);
+        patch_insn(&mut code, 2, 8).unwrap();
+        assert_eq!((code[2].imm, code[3].imm), (8, 0));
+        assert!(patch_insn(&mut code, 0, 0x10000).is_err());
+        assert!(patch_insn(&mut code, 4, 0).is_err());
+    }
+
+    #[test]
+    fn test_poison_insn() {
+        let mut code = ld_imm64(R0, 0).to_vec();
+        poison_insn(&mut code, 0);
+        assert_eq!(code[0].imm, POISON_HELPER as i32);
+        assert_eq!(code[1].code, ja(0).code);
+    }
+
+    #[test]
+    fn test_parse_core_relo_data() {
+        let mut data = vec![];
+        for val in &[16u32, 7, 1, 8, 3, 12, 0] {
+            data.extend_from_slice(&val.to_ne_bytes());
+        }
+        let sections = parse_core_relo_data(&data).unwrap();
+        assert_eq!(sections.len(), 1);
+        let (sec_name_off, records) = &sections[0];
+        assert_eq!(*sec_name_off, 7);
+        assert_eq!(records[0].insn_off, 8);
+        assert_eq!(records[0].type_id, 3);
+        assert_eq!(records[0].access_str_off, 12);
+        assert_eq!(
+            CoreRelocationKind::from(records[0].kind),
+            CoreRelocationKind::FieldByteOffset
+        );
+        assert!(parse_core_relo_data(&data[..20]).is_err());
+
+        // the records must not overflow
+        data[8..12].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert!(parse_core_relo_data(&data).is_err());
+    }
+
+    #[test]
+    fn test_btf_ext_data_out_of_bounds() {
+        let mut data = vec![];
+        data.extend_from_slice(&BTF_EXT_MAGIC.to_ne_bytes());
+        data.extend_from_slice(&[1, 0]);
+        // hdr_len, func_info_off/len, line_info_off/len, core_relo_off/len
+        for val in &[32u32, 0, 4, 4, 0, 8, 4] {
+            data.extend_from_slice(&val.to_ne_bytes());
+        }
+        data.extend_from_slice(&[0; 12]);
+        assert_eq!(btf_ext_data(&data, FUNC_INFO_FIELD_OFF).unwrap().len(), 4);
+        assert_eq!(btf_ext_data(&data, CORE_RELO_FIELD_OFF).unwrap().len(), 4);
+
+        data[24..28].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert!(btf_ext_data(&data, CORE_RELO_FIELD_OFF).is_err());
+        data[24..28].copy_from_slice(&8u32.to_ne_bytes());
+        data[28..32].copy_from_slice(&u32::MAX.to_ne_bytes());
+        assert!(btf_ext_data(&data, CORE_RELO_FIELD_OFF).is_err());
+    }
+
+    #[test]
+    fn test_parse_access_string() {
+        assert_eq!(parse_access_string("0:1:2").unwrap(), vec![0, 1, 2]);
+        assert!(parse_access_string("0:x").is_err());
+    }
+}
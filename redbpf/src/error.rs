@@ -20,9 +20,39 @@ pub enum Error {
     SymbolNotFound(String),
     ProgramAlreadyLoaded,
     ProgramNotLoaded,
+    /// Loading a program failed with `error`. `verifier_log` is the log of
+    /// the verifier, which points out the instruction it rejected, or `None`
+    /// if the kernel did not write a log.
+    ProgramLoad {
+        error: ::std::io::Error,
+        verifier_log: Option<String>,
+    },
     ProgramAlreadyLinked,
+    /// The program is not attached where it is expected to be, e.g., to the
+    /// cgroup whose program should be replaced.
+    ProgramNotAttached,
     ElfError,
     BTF(String),
+    /// The size of a Rust type differs from the size of the key or value of
+    /// a map, as declared by its BTF or its definition.
+    TypeMismatch(String),
+    /// The kernel can not run LSM programs. It is built without
+    /// `CONFIG_BPF_LSM` or `bpf` is not one of the active LSMs.
+    LsmUnsupported(String),
+    /// The driver of `interface` does not support attaching XDP programs in
+    /// `mode`.
+    XdpModeUnsupported {
+        interface: String,
+        mode: crate::xdp::XdpFlags,
+    },
+    /// Attaching an XDP program to multiple interfaces failed at `interface`
+    /// with `error`. The program was detached from the interfaces attached
+    /// before it, except `not_rolled_back` which failed to detach.
+    XdpMultiAttach {
+        interface: String,
+        error: Box<Error>,
+        not_rolled_back: Vec<String>,
+    },
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -0,0 +1,171 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Metadata of loaded programs and maps
+//!
+//! [`ProgramInfo`] and [`MapInfo`] mirror `struct bpf_prog_info` and
+//! `struct bpf_map_info` of the kernel, which are returned by
+//! `BPF_OBJ_GET_INFO_BY_FD`. They are returned by
+//! [`Program::info`](../enum.Program.html#method.info) and
+//! [`Map::info`](../struct.Map.html#method.info) for introspection, e.g., to
+//! monitor how often programs run and how long they take.
+//!
+//! `run_cnt` and `run_time_ns` of programs are only counted while
+//! `kernel.bpf_stats_enabled` is on, see
+//! [`sysctl::set_stats_enabled`](../sysctl/fn.set_stats_enabled.html).
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::{load::Loader, sysctl};
+//!
+//! sysctl::set_stats_enabled(true).expect("error enabling BPF stats");
+//! let loaded = Loader::load_file("file.elf").expect("error loading probe");
+//! for program in loaded.module.programs.iter() {
+//!     let info = program.info().expect("error getting program info");
+//!     println!(
+//!         "{} id={} runs={} time={}ns",
+//!         info.name, info.id, info.run_cnt, info.run_time_ns
+//!     );
+//! }
+//! ```
+use std::os::raw::c_char;
+
+use libbpf_sys::{bpf_map_info, bpf_prog_info};
+
+/// The information of a loaded program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProgramInfo {
+    /// `BPF_PROG_TYPE_*` of the program
+    pub prog_type: u32,
+    pub id: u32,
+    /// The hash of the instructions of the program
+    pub tag: [u8; 8],
+    pub jited_prog_len: u32,
+    pub xlated_prog_len: u32,
+    /// When the program was loaded, in nanoseconds since boot
+    pub load_time: u64,
+    pub created_by_uid: u32,
+    /// The ids of the maps used by the program
+    pub map_ids: Vec<u32>,
+    pub name: String,
+    /// The interface the program is offloaded to, 0 if it is not offloaded
+    pub ifindex: u32,
+    pub gpl_compatible: bool,
+    pub netns_dev: u64,
+    pub netns_ino: u64,
+    pub btf_id: u32,
+    /// The total run time in nanoseconds while `kernel.bpf_stats_enabled` is
+    /// on
+    pub run_time_ns: u64,
+    /// The number of runs while `kernel.bpf_stats_enabled` is on
+    pub run_cnt: u64,
+    /// The number of runs skipped because the program was already running
+    /// on the same CPU
+    pub recursion_misses: u64,
+    /// The number of instructions the verifier processed
+    pub verified_insns: u32,
+}
+
+impl ProgramInfo {
+    pub(crate) fn new(info: &bpf_prog_info, map_ids: Vec<u32>) -> ProgramInfo {
+        ProgramInfo {
+            prog_type: info.type_,
+            id: info.id,
+            tag: info.tag,
+            jited_prog_len: info.jited_prog_len,
+            xlated_prog_len: info.xlated_prog_len,
+            load_time: info.load_time,
+            created_by_uid: info.created_by_uid,
+            map_ids,
+            name: name_of(&info.name),
+            ifindex: info.ifindex,
+            gpl_compatible: info.gpl_compatible() != 0,
+            netns_dev: info.netns_dev,
+            netns_ino: info.netns_ino,
+            btf_id: info.btf_id,
+            run_time_ns: info.run_time_ns,
+            run_cnt: info.run_cnt,
+            recursion_misses: info.recursion_misses,
+            verified_insns: info.verified_insns,
+        }
+    }
+}
+
+/// The information of a map
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapInfo {
+    /// `BPF_MAP_TYPE_*` of the map
+    pub map_type: u32,
+    pub id: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub name: String,
+    /// The interface the map is offloaded to, 0 if it is not offloaded
+    pub ifindex: u32,
+    pub btf_vmlinux_value_type_id: u32,
+    pub netns_dev: u64,
+    pub netns_ino: u64,
+    pub btf_id: u32,
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+    pub map_extra: u64,
+}
+
+impl From<&bpf_map_info> for MapInfo {
+    fn from(info: &bpf_map_info) -> MapInfo {
+        MapInfo {
+            map_type: info.type_,
+            id: info.id,
+            key_size: info.key_size,
+            value_size: info.value_size,
+            max_entries: info.max_entries,
+            map_flags: info.map_flags,
+            name: name_of(&info.name),
+            ifindex: info.ifindex,
+            btf_vmlinux_value_type_id: info.btf_vmlinux_value_type_id,
+            netns_dev: info.netns_dev,
+            netns_ino: info.netns_ino,
+            btf_id: info.btf_id,
+            btf_key_type_id: info.btf_key_type_id,
+            btf_value_type_id: info.btf_value_type_id,
+            map_extra: info.map_extra,
+        }
+    }
+}
+
+// Names are NUL-terminated by the kernel but stop at the end of the array
+// anyway
+fn name_of(name: &[c_char]) -> String {
+    let bytes = name
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8)
+        .collect::<Vec<u8>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn test_map_info_from_raw() {
+        let mut raw = unsafe { mem::zeroed::<bpf_map_info>() };
+        raw.id = 42;
+        raw.max_entries = 1024;
+        for (dst, src) in raw.name.iter_mut().zip(b"counts") {
+            *dst = *src as c_char;
+        }
+        let info = MapInfo::from(&raw);
+        assert_eq!(info.id, 42);
+        assert_eq!(info.max_entries, 1024);
+        assert_eq!(info.name, "counts");
+    }
+}
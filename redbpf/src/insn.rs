@@ -0,0 +1,361 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Assembling BPF programs from raw instructions
+//!
+//! Programs are normally written in Rust and compiled by `cargo bpf`. This
+//! module is for programs generated at runtime, e.g., a filter compiled from
+//! user input, or for testing specific instruction sequences. The functions
+//! of this module build `bpf_insn` instructions, named after the macros of
+//! the kernel's `filter.h`, and [`ProgramBuilder`] loads them.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::insn::*;
+//! use redbpf::{Map, ProgramType};
+//!
+//! // count the packets seen by a socket filter in an array map
+//! let counter = Map::create("counter", 2, 4, 8, 1, 0).unwrap();
+//! let mut builder = ProgramBuilder::new("count", ProgramType::SocketFilter);
+//! builder
+//!     .insn(st_mem(BPF_W, R10, -4, 0)) // key = 0
+//!     .ld_map(R1, "counter")
+//!     .insn(mov64_reg(R2, R10))
+//!     .insn(alu64_imm(BPF_ADD, R2, -4))
+//!     .insn(call(1)) // bpf_map_lookup_elem
+//!     .insn(jmp_imm(BPF_JEQ, R0, 0, 3))
+//!     .insn(mov64_imm(R1, 1))
+//!     .insn(atomic_add(BPF_DW, R0, R1, 0))
+//!     .insn(mov64_imm(R0, 0))
+//!     .insn(exit())
+//!     .map("counter", &counter);
+//! let program = builder.load().unwrap();
+//! ```
+use std::collections::HashMap as RSHashMap;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use tracing::error;
+
+use crate::uname::get_kernel_internal_version;
+use crate::{Error, Map, Program, ProgramData, ProgramType, Result};
+use libbpf_sys::bpf_insn;
+
+// instruction classes
+pub const BPF_LD: u8 = 0x00;
+pub const BPF_LDX: u8 = 0x01;
+pub const BPF_ST: u8 = 0x02;
+pub const BPF_STX: u8 = 0x03;
+pub const BPF_ALU: u8 = 0x04;
+pub const BPF_JMP: u8 = 0x05;
+pub const BPF_JMP32: u8 = 0x06;
+pub const BPF_ALU64: u8 = 0x07;
+
+// sizes of load and store instructions
+pub const BPF_W: u8 = 0x00;
+pub const BPF_H: u8 = 0x08;
+pub const BPF_B: u8 = 0x10;
+pub const BPF_DW: u8 = 0x18;
+
+// modes of load and store instructions
+pub const BPF_IMM: u8 = 0x00;
+pub const BPF_MEM: u8 = 0x60;
+pub const BPF_ATOMIC: u8 = 0xc0;
+
+// ALU operations
+pub const BPF_ADD: u8 = 0x00;
+pub const BPF_SUB: u8 = 0x10;
+pub const BPF_MUL: u8 = 0x20;
+pub const BPF_DIV: u8 = 0x30;
+pub const BPF_OR: u8 = 0x40;
+pub const BPF_AND: u8 = 0x50;
+pub const BPF_LSH: u8 = 0x60;
+pub const BPF_RSH: u8 = 0x70;
+pub const BPF_NEG: u8 = 0x80;
+pub const BPF_MOD: u8 = 0x90;
+pub const BPF_XOR: u8 = 0xa0;
+pub const BPF_MOV: u8 = 0xb0;
+pub const BPF_ARSH: u8 = 0xc0;
+
+// jump operations
+pub const BPF_JA: u8 = 0x00;
+pub const BPF_JEQ: u8 = 0x10;
+pub const BPF_JGT: u8 = 0x20;
+pub const BPF_JGE: u8 = 0x30;
+pub const BPF_JSET: u8 = 0x40;
+pub const BPF_JNE: u8 = 0x50;
+pub const BPF_JSGT: u8 = 0x60;
+pub const BPF_JSGE: u8 = 0x70;
+pub const BPF_CALL: u8 = 0x80;
+pub const BPF_EXIT: u8 = 0x90;
+pub const BPF_JLT: u8 = 0xa0;
+pub const BPF_JLE: u8 = 0xb0;
+pub const BPF_JSLT: u8 = 0xc0;
+pub const BPF_JSLE: u8 = 0xd0;
+
+// operand sources
+pub const BPF_K: u8 = 0x00;
+pub const BPF_X: u8 = 0x08;
+
+// registers
+pub const R0: u8 = 0;
+pub const R1: u8 = 1;
+pub const R2: u8 = 2;
+pub const R3: u8 = 3;
+pub const R4: u8 = 4;
+pub const R5: u8 = 5;
+pub const R6: u8 = 6;
+pub const R7: u8 = 7;
+pub const R8: u8 = 8;
+pub const R9: u8 = 9;
+/// The read-only frame pointer
+pub const R10: u8 = 10;
+
+/// Build an instruction from its fields
+pub fn insn(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> bpf_insn {
+    let mut insn = unsafe { mem::zeroed::<bpf_insn>() };
+    insn.code = code;
+    insn.set_dst_reg(dst);
+    insn.set_src_reg(src);
+    insn.off = off;
+    insn.imm = imm;
+    insn
+}
+
+/// `dst op= imm` on 64 bit registers
+pub fn alu64_imm(op: u8, dst: u8, imm: i32) -> bpf_insn {
+    insn(BPF_ALU64 | op | BPF_K, dst, 0, 0, imm)
+}
+
+/// `dst op= src` on 64 bit registers
+pub fn alu64_reg(op: u8, dst: u8, src: u8) -> bpf_insn {
+    insn(BPF_ALU64 | op | BPF_X, dst, src, 0, 0)
+}
+
+/// `dst op= imm` on 32 bit subregisters
+pub fn alu32_imm(op: u8, dst: u8, imm: i32) -> bpf_insn {
+    insn(BPF_ALU | op | BPF_K, dst, 0, 0, imm)
+}
+
+/// `dst op= src` on 32 bit subregisters
+pub fn alu32_reg(op: u8, dst: u8, src: u8) -> bpf_insn {
+    insn(BPF_ALU | op | BPF_X, dst, src, 0, 0)
+}
+
+/// `dst = imm`
+pub fn mov64_imm(dst: u8, imm: i32) -> bpf_insn {
+    alu64_imm(BPF_MOV, dst, imm)
+}
+
+/// `dst = src`
+pub fn mov64_reg(dst: u8, src: u8) -> bpf_insn {
+    alu64_reg(BPF_MOV, dst, src)
+}
+
+/// `dst = imm` of 64 bits. It takes two instructions
+pub fn ld_imm64(dst: u8, imm: u64) -> [bpf_insn; 2] {
+    ld_imm64_raw(dst, 0, imm)
+}
+
+/// `dst = map`, loading the map referred to by `map_fd`. It takes two
+/// instructions
+pub fn ld_map_fd(dst: u8, map_fd: RawFd) -> [bpf_insn; 2] {
+    ld_imm64_raw(
+        dst,
+        libbpf_sys::BPF_PSEUDO_MAP_FD as u8,
+        map_fd as u32 as u64,
+    )
+}
+
+fn ld_imm64_raw(dst: u8, src: u8, imm: u64) -> [bpf_insn; 2] {
+    [
+        insn(BPF_LD | BPF_DW | BPF_IMM, dst, src, 0, imm as u32 as i32),
+        insn(0, 0, 0, 0, (imm >> 32) as u32 as i32),
+    ]
+}
+
+/// `dst = *(size *)(src + off)`
+pub fn ldx_mem(size: u8, dst: u8, src: u8, off: i16) -> bpf_insn {
+    insn(BPF_LDX | size | BPF_MEM, dst, src, off, 0)
+}
+
+/// `*(size *)(dst + off) = src`
+pub fn stx_mem(size: u8, dst: u8, src: u8, off: i16) -> bpf_insn {
+    insn(BPF_STX | size | BPF_MEM, dst, src, off, 0)
+}
+
+/// `*(size *)(dst + off) = imm`
+pub fn st_mem(size: u8, dst: u8, off: i16, imm: i32) -> bpf_insn {
+    insn(BPF_ST | size | BPF_MEM, dst, 0, off, imm)
+}
+
+/// `*(size *)(dst + off) += src` atomically. `size` is `BPF_W` or `BPF_DW`
+pub fn atomic_add(size: u8, dst: u8, src: u8, off: i16) -> bpf_insn {
+    insn(BPF_STX | size | BPF_ATOMIC, dst, src, off, BPF_ADD as i32)
+}
+
+/// `if (dst op imm) goto pc + off`
+pub fn jmp_imm(op: u8, dst: u8, imm: i32, off: i16) -> bpf_insn {
+    insn(BPF_JMP | op | BPF_K, dst, 0, off, imm)
+}
+
+/// `if (dst op src) goto pc + off`
+pub fn jmp_reg(op: u8, dst: u8, src: u8, off: i16) -> bpf_insn {
+    insn(BPF_JMP | op | BPF_X, dst, src, off, 0)
+}
+
+/// `goto pc + off`
+pub fn ja(off: i16) -> bpf_insn {
+    insn(BPF_JMP | BPF_JA, 0, 0, off, 0)
+}
+
+/// Call the helper function whose id is `helper`
+pub fn call(helper: u32) -> bpf_insn {
+    insn(BPF_JMP | BPF_CALL, 0, 0, 0, helper as i32)
+}
+
+/// Return `R0`
+pub fn exit() -> bpf_insn {
+    insn(BPF_JMP | BPF_EXIT, 0, 0, 0, 0)
+}
+
+/// Builder of a BPF program from raw instructions
+///
+/// Maps can be referred to by name with [`ld_map`](#method.ld_map) and given
+/// later with [`map`](#method.map). The `BPF_LD_MAP_FD` instructions are
+/// relocated to the file descriptors of the maps when the program is
+/// loaded. The loaded program is a [`Program`](../enum.Program.html) of
+/// `prog_type`, so it is attached like the programs of modules.
+pub struct ProgramBuilder<'a> {
+    name: String,
+    prog_type: ProgramType,
+    license: String,
+    insns: Vec<bpf_insn>,
+    // index of `BPF_LD_MAP_FD` instruction => map name
+    map_relocs: Vec<(usize, String)>,
+    maps: RSHashMap<String, &'a Map>,
+}
+
+impl<'a> ProgramBuilder<'a> {
+    /// Create a builder of a program named `name` of type `prog_type`
+    ///
+    /// The license of the program is `GPL` unless
+    /// [`license`](#method.license) is called.
+    pub fn new(name: &str, prog_type: ProgramType) -> Self {
+        ProgramBuilder {
+            name: name.to_string(),
+            prog_type,
+            license: "GPL".to_string(),
+            insns: Vec::new(),
+            map_relocs: Vec::new(),
+            maps: RSHashMap::new(),
+        }
+    }
+
+    /// Set the license of the program
+    pub fn license(&mut self, license: &str) -> &mut Self {
+        self.license = license.to_string();
+        self
+    }
+
+    /// Append an instruction
+    pub fn insn(&mut self, insn: bpf_insn) -> &mut Self {
+        self.insns.push(insn);
+        self
+    }
+
+    /// Append instructions
+    pub fn insns(&mut self, insns: &[bpf_insn]) -> &mut Self {
+        self.insns.extend_from_slice(insns);
+        self
+    }
+
+    /// Append the two instructions loading the map `map_name` into `dst`
+    ///
+    /// The map should be given by [`map`](#method.map) before the program
+    /// is loaded.
+    pub fn ld_map(&mut self, dst: u8, map_name: &str) -> &mut Self {
+        self.map_relocs
+            .push((self.insns.len(), map_name.to_string()));
+        self.insns(&ld_map_fd(dst, -1))
+    }
+
+    /// Give the map referred to as `name` by [`ld_map`](#method.ld_map)
+    pub fn map(&mut self, name: &str, map: &'a Map) -> &mut Self {
+        self.maps.insert(name.to_string(), map);
+        self
+    }
+
+    /// Return the instructions with maps relocated
+    pub fn build(&self) -> Result<Vec<bpf_insn>> {
+        let mut code = self.insns.clone();
+        for (insn_idx, name) in self.map_relocs.iter() {
+            let map = self.maps.get(name).ok_or_else(|| {
+                error!("map `{}' used by `{}' is not given", name, self.name);
+                Error::Reloc
+            })?;
+            code[*insn_idx].imm = map.fd;
+        }
+        Ok(code)
+    }
+
+    /// Load the program into the kernel
+    ///
+    /// This fails if a map is not given or the BPF verifier rejects the
+    /// program. The log of the verifier is printed in the latter case.
+    pub fn load(&self) -> Result<Program> {
+        let code = self.build()?;
+        let mut program = Program::with_data(
+            self.prog_type.section_kind(),
+            ProgramData {
+                name: self.name.clone(),
+                code,
+                fd: None,
+                license: String::new(),
+                func_info: Vec::new(),
+                btf_fd: None,
+            },
+        )?;
+        program.load(
+            get_kernel_internal_version().unwrap_or(0),
+            self.license.clone(),
+        )?;
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let mov = mov64_imm(R0, 1);
+        assert_eq!((mov.code, mov.dst_reg(), mov.imm), (0xb7, 0, 1));
+        let ld = ld_imm64(R1, 0x1_0000_0002);
+        assert_eq!((ld[0].code, ld[0].imm, ld[1].imm), (0x18, 2, 1));
+        let stx = stx_mem(BPF_DW, R10, R1, -8);
+        assert_eq!((stx.code, stx.dst_reg(), stx.src_reg()), (0x7b, 10, 1));
+        assert_eq!(stx.off, -8);
+        assert_eq!(exit().code, 0x95);
+    }
+
+    #[test]
+    fn test_map_reloc() {
+        let mut builder = ProgramBuilder::new("test", ProgramType::SocketFilter);
+        builder
+            .insn(mov64_imm(R0, 0))
+            .ld_map(R1, "missing")
+            .insn(exit());
+        assert_eq!(builder.insns.len(), 4);
+        assert_eq!(
+            builder.insns[1].src_reg(),
+            libbpf_sys::BPF_PSEUDO_MAP_FD as u8
+        );
+        assert!(builder.build().is_err());
+    }
+}
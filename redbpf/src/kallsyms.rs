@@ -0,0 +1,220 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Kernel symbol resolution with `/proc/kallsyms`
+//!
+//! `/proc/kallsyms` is parsed once on first use and kept sorted by address,
+//! so both resolving a symbol name to its address and symbolizing an address
+//! (e.g., an instruction pointer of a kernel stack trace) are cheap.
+//!
+//! If `kernel.kptr_restrict` hides kernel addresses from the current process,
+//! e.g., the process lacks `CAP_SYSLOG`, all addresses in `/proc/kallsyms`
+//! read as zero. In that case nothing can be resolved and
+//! [`is_restricted`](fn.is_restricted.html) returns `true`.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::kallsyms;
+//!
+//! let addr = kallsyms::resolve("vfs_read").expect("vfs_read not found");
+//! let (name, offset) = kallsyms::symbolize(addr + 4).unwrap();
+//! assert_eq!(name, "vfs_read");
+//! assert_eq!(offset, 4);
+//! ```
+use std::collections::HashMap;
+use std::fs;
+
+use tracing::warn;
+
+const PROC_KALLSYMS: &str = "/proc/kallsyms";
+
+lazy_static! {
+    static ref KALLSYMS: KernelSymbols = KernelSymbols::load();
+}
+
+/// A symbol of the kernel or a kernel module
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelSymbol {
+    pub address: u64,
+    pub name: String,
+    /// The kernel module that defines the symbol. `None` for vmlinux
+    pub module: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct KernelSymbols {
+    // sorted by address
+    symbols: Vec<KernelSymbol>,
+    // symbol name => index of `symbols`
+    by_name: HashMap<String, usize>,
+    restricted: bool,
+}
+
+impl KernelSymbols {
+    fn load() -> KernelSymbols {
+        match fs::read_to_string(PROC_KALLSYMS) {
+            Ok(contents) => {
+                let syms = KernelSymbols::parse(&contents);
+                if syms.restricted {
+                    warn!("kernel addresses are hidden by kptr_restrict");
+                }
+                syms
+            }
+            Err(e) => {
+                warn!("error reading {}: {}", PROC_KALLSYMS, e);
+                KernelSymbols::default()
+            }
+        }
+    }
+
+    fn parse(contents: &str) -> KernelSymbols {
+        let mut symbols: Vec<KernelSymbol> = contents
+            .lines()
+            .filter_map(|line| {
+                // <address> <type> <name> [<module>]
+                let mut parts = line.split_whitespace();
+                let address = u64::from_str_radix(parts.next()?, 16).ok()?;
+                let _type = parts.next()?;
+                let name = parts.next()?.to_string();
+                let module = parts
+                    .next()
+                    .map(|m| m.trim_start_matches('[').trim_end_matches(']').to_string());
+                Some(KernelSymbol {
+                    address,
+                    name,
+                    module,
+                })
+            })
+            .collect();
+
+        let restricted = !symbols.is_empty() && symbols.iter().all(|sym| sym.address == 0);
+        if restricted {
+            return KernelSymbols {
+                restricted,
+                ..Default::default()
+            };
+        }
+
+        symbols.sort_by_key(|sym| sym.address);
+        let mut by_name = HashMap::with_capacity(symbols.len());
+        for (i, sym) in symbols.iter().enumerate() {
+            // keep the first one if a name is defined more than once
+            by_name.entry(sym.name.clone()).or_insert(i);
+        }
+        KernelSymbols {
+            symbols,
+            by_name,
+            restricted,
+        }
+    }
+
+    fn resolve(&self, name: &str) -> Option<&KernelSymbol> {
+        self.by_name.get(name).map(|i| &self.symbols[*i])
+    }
+
+    fn symbolize(&self, addr: u64) -> Option<(&KernelSymbol, u64)> {
+        // index of the first symbol whose address is greater than addr
+        let idx = self.symbols.partition_point(|sym| sym.address <= addr);
+        let sym = self.symbols.get(idx.checked_sub(1)?)?;
+        Some((sym, addr - sym.address))
+    }
+
+    fn contains(&self, name: &str, offset: u64) -> Option<bool> {
+        let sym = self.resolve(name)?;
+        let (containing, _) = self.symbolize(sym.address.checked_add(offset)?)?;
+        // aliases of `name` start at the same address
+        Some(containing.address == sym.address)
+    }
+}
+
+/// Return the address of the kernel symbol `name`
+pub fn resolve(name: &str) -> Option<u64> {
+    KALLSYMS.resolve(name).map(|sym| sym.address)
+}
+
+/// Return the kernel symbol `name`
+pub fn resolve_symbol(name: &str) -> Option<&'static KernelSymbol> {
+    KALLSYMS.resolve(name)
+}
+
+/// Return the name of the symbol containing `addr` and the offset of `addr`
+/// from the start of the symbol
+pub fn symbolize(addr: u64) -> Option<(String, u64)> {
+    KALLSYMS
+        .symbolize(addr)
+        .map(|(sym, offset)| (sym.name.clone(), offset))
+}
+
+/// Return the symbol containing `addr` and the offset of `addr` from the
+/// start of the symbol
+pub fn symbolize_symbol(addr: u64) -> Option<(&'static KernelSymbol, u64)> {
+    KALLSYMS.symbolize(addr)
+}
+
+/// Return whether `offset` bytes from the start of the kernel symbol `name`
+/// is still inside the symbol, i.e., before the start of the next symbol
+///
+/// `None` is returned if `name` can not be resolved.
+pub fn contains(name: &str, offset: u64) -> Option<bool> {
+    KALLSYMS.contains(name, offset)
+}
+
+/// Return true if kernel addresses are hidden from this process by
+/// `kernel.kptr_restrict`
+pub fn is_restricted() -> bool {
+    KALLSYMS.restricted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KALLSYMS_SAMPLE: &str = "\
+ffffffff81000000 T _stext
+ffffffff81200100 T vfs_write
+ffffffff81200000 T vfs_read
+ffffffffc0a01000 t e1000_probe\t[e1000]
+";
+
+    #[test]
+    fn test_resolve() {
+        let syms = KernelSymbols::parse(KALLSYMS_SAMPLE);
+        assert_eq!(syms.resolve("vfs_read").unwrap().address, 0xffffffff81200000);
+        let e1000 = syms.resolve("e1000_probe").unwrap();
+        assert_eq!(e1000.module.as_deref(), Some("e1000"));
+        assert!(syms.resolve("vfs_readv").is_none());
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let syms = KernelSymbols::parse(KALLSYMS_SAMPLE);
+        let (sym, offset) = syms.symbolize(0xffffffff81200010).unwrap();
+        assert_eq!((sym.name.as_str(), offset), ("vfs_read", 0x10));
+        let (sym, offset) = syms.symbolize(0xffffffff81200100).unwrap();
+        assert_eq!((sym.name.as_str(), offset), ("vfs_write", 0));
+        assert!(syms.symbolize(0x1000).is_none());
+    }
+
+    #[test]
+    fn test_contains() {
+        let syms = KernelSymbols::parse(KALLSYMS_SAMPLE);
+        assert_eq!(syms.contains("vfs_read", 0), Some(true));
+        assert_eq!(syms.contains("vfs_read", 0xff), Some(true));
+        assert_eq!(syms.contains("vfs_read", 0x100), Some(false));
+        assert_eq!(syms.contains("vfs_readv", 0), None);
+    }
+
+    #[test]
+    fn test_restricted() {
+        let syms = KernelSymbols::parse(
+            "0000000000000000 T _stext\n0000000000000000 T vfs_read\n",
+        );
+        assert!(syms.restricted);
+        assert!(syms.resolve("vfs_read").is_none());
+        assert!(syms.symbolize(0).is_none());
+    }
+}
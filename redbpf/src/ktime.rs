@@ -0,0 +1,139 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Converting kernel timestamps to wall-clock time
+//!
+//! Timestamps taken by BPF programs are nanoseconds since boot, not since the
+//! UNIX epoch. `bpf_ktime_get_ns` reads `CLOCK_MONOTONIC`, which stops while
+//! the system is suspended, and `bpf_ktime_get_boot_ns` reads
+//! `CLOCK_BOOTTIME`, which does not. [`ClockOffset`] captures the offset of
+//! the clock the probe used from `CLOCK_REALTIME` once, and converts event
+//! timestamps to [`SystemTime`] with it.
+//!
+//! The offset of `CLOCK_MONOTONIC` changes when the system resumes from
+//! suspend, and the offset of both clocks changes when the wall clock is
+//! set, so long running tools should capture it again from time to time.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::ktime::{ClockOffset, KtimeClock};
+//!
+//! let offset = ClockOffset::new(KtimeClock::Boottime).unwrap();
+//! # let ts = 0;
+//! // `ts` was read by `bpf_ktime_get_boot_ns` in the probe
+//! println!("{:?}", offset.to_system_time(ts));
+//! ```
+use std::io;
+use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tracing::error;
+
+use crate::{Error, Result};
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// The clock of timestamps taken by BPF programs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KtimeClock {
+    /// `CLOCK_MONOTONIC`, read by `bpf_ktime_get_ns`
+    Monotonic,
+    /// `CLOCK_BOOTTIME`, read by `bpf_ktime_get_boot_ns`
+    Boottime,
+}
+
+impl KtimeClock {
+    fn clock_id(&self) -> libc::clockid_t {
+        match self {
+            KtimeClock::Monotonic => libc::CLOCK_MONOTONIC,
+            KtimeClock::Boottime => libc::CLOCK_BOOTTIME,
+        }
+    }
+}
+
+/// The offset of a kernel clock from the wall clock
+#[derive(Debug, Clone, Copy)]
+pub struct ClockOffset {
+    clock: KtimeClock,
+    // the wall-clock time when the kernel clock was 0
+    base: SystemTime,
+}
+
+impl ClockOffset {
+    /// Capture the offset of `clock` from `CLOCK_REALTIME`
+    ///
+    /// The wall clock is read between two readings of `clock` and compared
+    /// with their midpoint, so the offset is not skewed by the time it takes
+    /// to read the clocks.
+    pub fn new(clock: KtimeClock) -> Result<ClockOffset> {
+        let before = clock_gettime_ns(clock.clock_id())?;
+        let realtime = clock_gettime_ns(libc::CLOCK_REALTIME)?;
+        let after = clock_gettime_ns(clock.clock_id())?;
+        Ok(ClockOffset {
+            clock,
+            base: base_time(realtime, before + (after - before) / 2),
+        })
+    }
+
+    /// The clock of which offset is captured
+    pub fn clock(&self) -> KtimeClock {
+        self.clock
+    }
+
+    /// Convert `ktime_ns`, a timestamp of the clock, to wall-clock time
+    pub fn to_system_time(&self, ktime_ns: u64) -> SystemTime {
+        self.base + Duration::from_nanos(ktime_ns)
+    }
+}
+
+fn base_time(realtime_ns: u64, ktime_ns: u64) -> SystemTime {
+    if realtime_ns >= ktime_ns {
+        UNIX_EPOCH + Duration::from_nanos(realtime_ns - ktime_ns)
+    } else {
+        // the wall clock is set before the boot time
+        UNIX_EPOCH - Duration::from_nanos(ktime_ns - realtime_ns)
+    }
+}
+
+fn clock_gettime_ns(clock_id: libc::clockid_t) -> Result<u64> {
+    unsafe {
+        let mut ts = mem::zeroed::<libc::timespec>();
+        if libc::clock_gettime(clock_id, &mut ts) != 0 {
+            error!("error on clock_gettime: {}", io::Error::last_os_error());
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        Ok(ts.tv_sec as u64 * NSEC_PER_SEC + ts.tv_nsec as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_base_time() {
+        assert_eq!(
+            base_time(5 * NSEC_PER_SEC, 2 * NSEC_PER_SEC),
+            UNIX_EPOCH + Duration::from_secs(3)
+        );
+        assert_eq!(
+            base_time(NSEC_PER_SEC, 2 * NSEC_PER_SEC),
+            UNIX_EPOCH - Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_to_system_time() {
+        let offset = ClockOffset::new(KtimeClock::Boottime).unwrap();
+        let now = clock_gettime_ns(libc::CLOCK_BOOTTIME).unwrap();
+        let diff = match SystemTime::now().duration_since(offset.to_system_time(now)) {
+            Ok(d) => d,
+            Err(e) => e.duration(),
+        };
+        assert!(diff < Duration::from_secs(1));
+    }
+}
@@ -44,24 +44,43 @@ for kprobe in loader.kprobes_mut() {
 #[macro_use]
 extern crate lazy_static;
 
+pub mod argv;
+pub mod backpressure;
 pub mod btf;
+pub mod cgroup;
+pub mod core_reloc;
 pub mod cpus;
 mod error;
+pub mod info;
+pub mod insn;
+pub mod kallsyms;
+pub mod ktime;
+pub mod link;
 #[cfg(feature = "load")]
 pub mod load;
+pub mod map_type;
 mod perf;
+pub mod perf_record;
+#[cfg(feature = "async")]
+mod perf_stream;
+pub mod pinning;
+pub mod ratelimit;
+mod ringbuf;
 mod symbols;
 pub mod sys;
+pub mod sysctl;
+pub mod system;
 pub mod xdp;
 
 pub use bpf_sys::uname;
-use goblin::elf::{reloc::RelocSection, section_header as hdr, Elf, SectionHeader, Sym};
+use goblin::elf::{reloc::RelocSection, section_header as hdr, sym, Elf, SectionHeader, Sym};
 use libbpf_sys::{
     bpf_create_map_attr, bpf_create_map_xattr, bpf_insn, bpf_iter_create, bpf_link_create,
-    bpf_load_program_xattr, bpf_map_def, bpf_map_info, bpf_prog_type, BPF_ANY, BPF_MAP_TYPE_ARRAY,
-    BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_LRU_HASH, BPF_MAP_TYPE_LRU_PERCPU_HASH,
+    bpf_load_program_xattr, bpf_map_def, bpf_map_info, bpf_prog_type, BPF_ANY, BPF_LSM_MAC,
+    BPF_MAP_TYPE_ARRAY, BPF_MAP_TYPE_HASH, BPF_MAP_TYPE_LRU_HASH, BPF_MAP_TYPE_LRU_PERCPU_HASH,
     BPF_MAP_TYPE_PERCPU_ARRAY, BPF_MAP_TYPE_PERCPU_HASH, BPF_MAP_TYPE_PERF_EVENT_ARRAY,
-    BPF_SK_LOOKUP, BPF_SK_SKB_STREAM_PARSER, BPF_SK_SKB_STREAM_VERDICT, BPF_TRACE_ITER,
+    BPF_SK_LOOKUP, BPF_SK_REUSEPORT_SELECT, BPF_SK_SKB_STREAM_PARSER, BPF_SK_SKB_STREAM_VERDICT,
+    BPF_TRACE_FENTRY, BPF_TRACE_FEXIT, BPF_TRACE_ITER,
 };
 
 use libc::{self, pid_t};
@@ -71,14 +90,20 @@ use std::fs::{self, File};
 use std::io::{self, BufReader, ErrorKind, Read};
 use std::marker::PhantomData;
 use std::mem::{self, MaybeUninit};
-use std::ops::{Deref, DerefMut};
+use std::ops::{Add, Deref, DerefMut};
 use std::os::unix::io::{FromRawFd, RawFd};
 use std::path::{Path, PathBuf};
 use std::ptr;
 
-use crate::btf::{BtfKind, MapBtfTypeId, BTF};
+use crate::btf::{BtfKind, MapBtfTypeId, StructMember, BTF};
+use crate::core_reloc::{CoreRelocationFailure, CoreRelocationSection};
 pub use crate::error::{Error, Result};
+use crate::info::{MapInfo, ProgramInfo};
+use crate::link::LinkGuard;
 pub use crate::perf::*;
+#[cfg(feature = "async")]
+pub use crate::perf_stream::PerfMapStream;
+pub use crate::ringbuf::RingBuf;
 use crate::symbols::*;
 use crate::uname::get_kernel_internal_version;
 
@@ -99,6 +124,20 @@ pub struct Module {
     pub maps: Vec<Map>,
     pub license: String,
     pub version: u32,
+    // outer map name => inner map set by `swap_map`
+    inner_maps: RSHashMap<String, Map>,
+    core_relocation_failures: Vec<CoreRelocationFailure>,
+    // variable name => its location, see `set_global_var`
+    global_vars: RSHashMap<String, GlobalVar>,
+}
+
+// A global variable of programs in a global data section, e.g., `.rodata`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct GlobalVar {
+    // the name of the section, which is also the name of its map
+    section: String,
+    offset: usize,
+    size: usize,
 }
 
 /// A builder of [Module](struct.Module.html)
@@ -131,6 +170,13 @@ pub struct ModuleBuilder<'a> {
     // BTF should survive until all maps are created with it. So keep it
     #[allow(dead_code)]
     btf: Option<BTF>,
+    core_relocations: Vec<CoreRelocationSection>,
+    // the index and code of `.text` section that BPF global functions live in
+    text: Option<(usize, Vec<bpf_insn>)>,
+    func_info: RSHashMap<usize, Vec<libbpf_sys::bpf_func_info>>,
+    // map name => file in BPF FS, set by `#[map(pinned = "...")]`
+    pin_files: RSHashMap<String, PathBuf>,
+    global_vars: RSHashMap<String, GlobalVar>,
 }
 
 enum ProbeAttachType {
@@ -151,17 +197,157 @@ pub enum Program {
     StreamVerdict(StreamVerdict),
     TaskIter(TaskIter),
     SkLookup(SkLookup),
+    SockOps(SockOps),
+    CgroupSkb(CgroupSkb),
+    CgroupSock(CgroupSock),
+    SkReuseport(SkReuseport),
+    RawTracePoint(RawTracePoint),
+    PerfEvent(PerfEvent),
+    StructOps(StructOps),
+    FEntry(FEntry),
+    FExit(FEntry),
+    Lsm(Lsm),
+}
+
+/// The type of a BPF program, used to override the type derived from the
+/// section name of the program. See
+/// [`ModuleBuilder::load_as`](struct.ModuleBuilder.html#method.load_as).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramType {
+    KProbe,
+    KRetProbe,
+    UProbe,
+    URetProbe,
+    SocketFilter,
+    TracePoint,
+    XDP,
+    StreamParser,
+    StreamVerdict,
+    SkLookup,
+    SockOps,
+    CgroupSkb,
+    CgroupSock,
+    SkReuseport,
+    RawTracePoint,
+    PerfEvent,
+}
+
+impl ProgramType {
+    /// Every program type
+    pub const ALL: &'static [ProgramType] = &[
+        ProgramType::KProbe,
+        ProgramType::KRetProbe,
+        ProgramType::UProbe,
+        ProgramType::URetProbe,
+        ProgramType::SocketFilter,
+        ProgramType::TracePoint,
+        ProgramType::XDP,
+        ProgramType::StreamParser,
+        ProgramType::StreamVerdict,
+        ProgramType::SkLookup,
+        ProgramType::SockOps,
+        ProgramType::CgroupSkb,
+        ProgramType::CgroupSock,
+        ProgramType::SkReuseport,
+        ProgramType::RawTracePoint,
+        ProgramType::PerfEvent,
+    ];
+
+    /// Return the kind part of the section name of programs of this type,
+    /// e.g., `kprobe` of `kprobe/do_sys_open`
+    pub fn section_kind(&self) -> &'static str {
+        use ProgramType::*;
+
+        match self {
+            KProbe => "kprobe",
+            KRetProbe => "kretprobe",
+            UProbe => "uprobe",
+            URetProbe => "uretprobe",
+            SocketFilter => "socketfilter",
+            TracePoint => "tracepoint",
+            XDP => "xdp",
+            StreamParser => "streamparser",
+            StreamVerdict => "streamverdict",
+            SkLookup => "sk_lookup",
+            SockOps => "sockops",
+            CgroupSkb => "cgroup_skb",
+            CgroupSock => "cgroup_sock",
+            SkReuseport => "sk_reuseport",
+            RawTracePoint => "raw_tracepoint",
+            PerfEvent => "perf_event",
+        }
+    }
+
+    fn to_prog_type(self) -> bpf_prog_type {
+        use ProgramType::*;
+
+        match self {
+            KProbe | KRetProbe | UProbe | URetProbe => libbpf_sys::BPF_PROG_TYPE_KPROBE,
+            XDP => libbpf_sys::BPF_PROG_TYPE_XDP,
+            SocketFilter => libbpf_sys::BPF_PROG_TYPE_SOCKET_FILTER,
+            TracePoint => libbpf_sys::BPF_PROG_TYPE_TRACEPOINT,
+            StreamParser | StreamVerdict => libbpf_sys::BPF_PROG_TYPE_SK_SKB,
+            SkLookup => libbpf_sys::BPF_PROG_TYPE_SK_LOOKUP,
+            SockOps => libbpf_sys::BPF_PROG_TYPE_SOCK_OPS,
+            CgroupSkb => libbpf_sys::BPF_PROG_TYPE_CGROUP_SKB,
+            CgroupSock => libbpf_sys::BPF_PROG_TYPE_CGROUP_SOCK,
+            SkReuseport => libbpf_sys::BPF_PROG_TYPE_SK_REUSEPORT,
+            RawTracePoint => libbpf_sys::BPF_PROG_TYPE_RAW_TRACEPOINT,
+            PerfEvent => libbpf_sys::BPF_PROG_TYPE_PERF_EVENT,
+        }
+    }
+}
+
+/// A subprogram of a loaded program, see
+/// [`Program::subprograms`](enum.Program.html#method.subprograms)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubprogInfo {
+    /// The name of the function
+    pub name: String,
+    /// The index of the first instruction in the xlated program
+    pub insn_off: u32,
+    /// The number of instructions after the verifier rewrote them
+    pub insn_cnt: u32,
 }
 
 struct ProgramData {
     pub name: String,
     code: Vec<bpf_insn>,
     fd: Option<RawFd>,
+    license: String,
+    // func_info and BTF of programs calling BPF global functions
+    func_info: Vec<libbpf_sys::bpf_func_info>,
+    btf_fd: Option<RawFd>,
 }
 
 struct KProbeAttachmentPoint {
     fn_name: String,
     offset: u64,
+    // kernel address of the probe attached by `KProbe::attach_kprobe_addr`
+    addr: Option<u64>,
+    pfd: RawFd, // file descriptor of perf event
+    // true if attached by the fallback of `KProbe::attach_kprobe_multi`
+    multi: bool,
+    // name of the event created through `kprobe_events` for module symbols
+    event: Option<String>,
+}
+
+struct KProbeMultiLink {
+    fn_names: Vec<String>,
+    lfd: RawFd, // file descriptor of BPF link
+}
+
+// A kprobe target remembered by `KProbe::detach` to be attached again by
+// `KProbe::reattach`
+enum KProbeTarget {
+    Single(String, u64),
+    Address(u64),
+    Multi(Vec<String>),
+}
+
+struct TracePointAttachmentPoint {
+    category: String,
+    name: String,
     pfd: RawFd, // file descriptor of perf event
 }
 
@@ -173,11 +359,20 @@ struct UProbeAttachmentPoint {
     pfd: RawFd, // file descriptor of perf event
 }
 
+struct PerfEventAttachmentPoint {
+    pfd: RawFd, // file descriptor of perf event of a CPU
+}
+
 /// Type to work with `kprobes` or `kretprobes`.
 pub struct KProbe {
     common: ProgramData,
     attach_type: ProbeAttachType,
     attachment_points: Vec<KProbeAttachmentPoint>,
+    // fd of the copy of the program loaded with `BPF_TRACE_KPROBE_MULTI`
+    multi_fd: Option<RawFd>,
+    multi_links: Vec<KProbeMultiLink>,
+    // targets detached by `detach` and not yet reattached
+    detached: Vec<KProbeTarget>,
 }
 
 /// Type to work with `uprobes` or `uretprobes`.
@@ -192,13 +387,70 @@ pub struct SocketFilter {
     common: ProgramData,
 }
 
+/// Type to work with `tracepoint` BPF programs.
+///
+/// The program in the section `tracepoint/<category>/<name>` is named `<name>`
+/// and [`attach`](#method.attach) attaches it to `<category>:<name>`.
 pub struct TracePoint {
     common: ProgramData,
+    tracepoint: Option<(String, String)>,
+    attachment_points: Vec<TracePointAttachmentPoint>,
+    detached: Vec<(String, String)>,
+}
+
+/// Type to work with `raw_tracepoint` BPF programs.
+///
+/// Raw tracepoint programs take the raw arguments of the tracepoint as an
+/// array of `u64` instead of the formatted tracepoint record, which is
+/// cheaper than `tracepoint` programs. The tracepoint is given by
+/// `#[raw_tracepoint]` of `redbpf-probes`. They require Linux 4.17 or newer.
+pub struct RawTracePoint {
+    common: ProgramData,
+    // the tracepoint given by `#[raw_tracepoint]`
+    tracepoint: Option<String>,
+    link_fds: Vec<RawFd>,
+}
+
+/// Type to work with `perf_event` BPF programs.
+///
+/// `perf_event` programs are called whenever a perf event overflows, e.g.,
+/// every time a CPU clock sampling at 99Hz fires. They take the registers
+/// of the interrupted context, so sampling profilers use them to collect
+/// stack traces. See [`attach_sampler`](#method.attach_sampler).
+///
+/// # Example
+/// ```no_run
+/// # static PROFILER: &[u8] = &[];
+/// use redbpf::{load::Loader, PerfEventType};
+///
+/// let mut loaded = Loader::load(PROFILER).unwrap();
+/// loaded
+///     .perf_event_mut("sample_stack")
+///     .unwrap()
+///     .attach_sampler(PerfEventType::CPU_CLOCK, 99)
+///     .unwrap();
+/// ```
+pub struct PerfEvent {
+    common: ProgramData,
+    // one perf event per CPU
+    attachment_points: Vec<PerfEventAttachmentPoint>,
+}
+
+/// The event a [`PerfEvent`](struct.PerfEvent.html) program samples
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfEventType {
+    /// A software event, one of `PERF_COUNT_SW_*`
+    Software(u32),
+    /// A generalized hardware event, one of `PERF_COUNT_HW_*`. Virtual
+    /// machines often do not provide hardware events.
+    Hardware(u32),
 }
 /// Type to work with `XDP` programs.
 pub struct XDP {
     common: ProgramData,
-    interfaces: Vec<String>,
+    // interfaces and `XDP_FLAGS_*` bits
+    interfaces: Vec<(String, u32)>,
+    detached: Vec<(String, u32)>,
 }
 
 /// Type to work with `stream_parser` BPF programs.
@@ -237,6 +489,57 @@ pub struct TaskIter {
     link_fd: Option<RawFd>,
 }
 
+/// Type to work with `struct_ops` BPF programs.
+///
+/// A `struct_ops` program implements a function pointer member of a kernel
+/// structure, e.g., the `ssthresh` callback of `tcp_congestion_ops`. The
+/// structure and the member are given by `#[struct_ops]` of
+/// `redbpf-probes`. Loaded programs take effect once they are set to a
+/// [`StructOpsMap`](struct.StructOpsMap.html) and the map is registered.
+pub struct StructOps {
+    common: ProgramData,
+    struct_name: String,
+    member: String,
+    // type id of `struct_name` in vmlinux BTF
+    attach_btf_id: u32,
+    member_idx: u32,
+}
+
+/// Type to work with `fentry` and `fexit` BPF programs.
+///
+/// `fentry` and `fexit` programs are attached to the entry and the exit of a
+/// kernel function through a BPF trampoline, which is cheaper than kprobes,
+/// and read the arguments of the function with their BTF types. They
+/// require vmlinux BTF and Linux 5.5 or newer. The target function is given
+/// by `#[fentry]` or `#[fexit]` of `redbpf-probes`. See
+/// [`Module::attach_preferred`](struct.Module.html#method.attach_preferred)
+/// to fall back to kprobes on kernels that do not support them.
+pub struct FEntry {
+    common: ProgramData,
+    target: String,
+    // type id of `target` in vmlinux BTF. None if vmlinux BTF or the
+    // function is not found
+    attach_btf_id: Option<u32>,
+    link_fd: Option<RawFd>,
+}
+
+/// Type to work with `lsm` BPF programs.
+///
+/// `lsm` programs are attached to a Linux Security Module hook, e.g.,
+/// `file_open`, and take the arguments of the hook. A program returning a
+/// negative error number denies the operation. They require vmlinux BTF and
+/// Linux 5.7 or newer built with `CONFIG_BPF_LSM`, and `bpf` must be one of
+/// the active LSMs given by the `lsm=` boot parameter. The hook is given by
+/// `#[lsm]` of `redbpf-probes`.
+pub struct Lsm {
+    common: ProgramData,
+    hook: String,
+    // type id of `bpf_lsm_<hook>` in vmlinux BTF. None if vmlinux BTF or the
+    // hook is not found
+    attach_btf_id: Option<u32>,
+    link_fd: Option<RawFd>,
+}
+
 /// Type to work with [`sk_lookup`] BPF programs.
 ///
 /// `sk_lookup` programs were introduced with Linux 5.9 and make it possible to
@@ -292,6 +595,127 @@ pub struct SkLookup {
     link: Option<(RawFd, RawFd)>,
 }
 
+/// Type to work with `sockops` BPF programs.
+///
+/// `sockops` programs are called at various points of the lifecycle of TCP
+/// connections. They take effect on the connections made by processes that
+/// belong to the cgroup the program is attached to. See
+/// [`attach_cgroup`](#method.attach_cgroup).
+///
+/// # Example
+/// ```no_run
+/// # static SOCKOPS: &[u8] = &[];
+/// use redbpf::load::Loader;
+///
+/// let mut loaded = Loader::load(SOCKOPS).unwrap();
+/// loaded
+///     .sockops_mut("tune_iw")
+///     .unwrap()
+///     .attach_cgroup("/sys/fs/cgroup/unified")
+///     .unwrap();
+/// ```
+pub struct SockOps {
+    common: ProgramData,
+    cgroups: Vec<CgroupAttachment>,
+}
+
+/// Type to work with `cgroup/skb` BPF programs.
+///
+/// `cgroup/skb` programs are called for the packets received or sent by the
+/// sockets of the processes that belong to the cgroup the program is attached
+/// to. Packets are dropped when the program returns 0. See
+/// [`attach_cgroup`](#method.attach_cgroup). Besides `cgroup/skb/<name>`,
+/// the libbpf sections `cgroup_skb/ingress` and `cgroup_skb/egress` are
+/// loaded as programs named `cgroup_skb_ingress` and `cgroup_skb_egress`.
+///
+/// # Example
+/// ```no_run
+/// # static CGROUP_SKB: &[u8] = &[];
+/// use redbpf::{load::Loader, CgroupSkbAttachType};
+///
+/// let mut loaded = Loader::load(CGROUP_SKB).unwrap();
+/// loaded
+///     .cgroup_skb_mut("block_port")
+///     .unwrap()
+///     .attach_cgroup("/sys/fs/cgroup/my-service", CgroupSkbAttachType::Ingress)
+///     .unwrap();
+/// ```
+pub struct CgroupSkb {
+    common: ProgramData,
+    cgroups: Vec<CgroupAttachment>,
+}
+
+/// The direction of the packets a `cgroup/skb` program is called for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupSkbAttachType {
+    Ingress,
+    Egress,
+}
+
+/// Type to work with `cgroup/sock` BPF programs.
+///
+/// `cgroup/sock` programs are called when the processes of the cgroup the
+/// program is attached to create, release or bind sockets. Returning 0
+/// rejects the operation. The hook is given by the section name of the
+/// program, e.g., `cgroup/sock/<name>` for socket creation or
+/// `cgroup/post_bind4/<name>`, because the kernel requires it when the
+/// program is loaded. See [`CgroupSockAttachType`](enum.CgroupSockAttachType.html).
+/// A program in a section without a name like `cgroup/sock` is named after
+/// the section, e.g., `cgroup_sock`.
+pub struct CgroupSock {
+    common: ProgramData,
+    attach_type: CgroupSockAttachType,
+    cgroups: Vec<CgroupAttachment>,
+}
+
+/// The hook of a `cgroup/sock` program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgroupSockAttachType {
+    /// `cgroup/sock` or `cgroup/sock_create`
+    SockCreate,
+    /// `cgroup/sock_release`, Linux 5.9 or later
+    SockRelease,
+    /// `cgroup/post_bind4`
+    PostBind4,
+    /// `cgroup/post_bind6`
+    PostBind6,
+}
+
+/// Type to work with `sk_reuseport` BPF programs.
+///
+/// `sk_reuseport` programs select the socket of a `SO_REUSEPORT` group that
+/// receives a new connection or datagram, e.g., from a
+/// `BPF_MAP_TYPE_REUSEPORT_SOCKARRAY` map of the sockets of worker
+/// processes. See [`ReuseportSockArray`](struct.ReuseportSockArray.html).
+///
+/// # Example
+/// ```no_run
+/// # static SK_REUSEPORT: &[u8] = &[];
+/// use std::net::UdpSocket;
+/// use std::os::unix::io::AsRawFd;
+/// use redbpf::load::Loader;
+///
+/// # let socket = UdpSocket::bind("0.0.0.0:5353").unwrap();
+/// // `socket` is bound with SO_REUSEPORT
+/// let mut loaded = Loader::load(SK_REUSEPORT).unwrap();
+/// loaded
+///     .sk_reuseport_mut("select_worker")
+///     .unwrap()
+///     .attach_reuseport(socket.as_raw_fd())
+///     .unwrap();
+/// ```
+pub struct SkReuseport {
+    common: ProgramData,
+}
+
+// A BPF program attached to a cgroup. It is detached when dropped.
+struct CgroupAttachment {
+    path: PathBuf,
+    cgroup_fd: RawFd,
+    prog_fd: RawFd,
+    attach_type: libbpf_sys::bpf_attach_type,
+}
+
 /// A base BPF map data structure
 ///
 /// It is a base data structure that contains a map definition and auxiliary
@@ -317,6 +741,9 @@ enum MapBuilder<'a> {
         name: String,
         def: bpf_map_def,
         btf_type_id: Option<MapBtfTypeId>,
+        // `map_extra` of `BPF_MAP_CREATE`, e.g., the number of hash functions
+        // of Bloom filter maps
+        map_extra: u64,
     },
     SectionData {
         name: String,
@@ -339,6 +766,18 @@ pub struct HashMap<'a, K: Clone, V: Clone> {
 ///
 /// This provides higher level API for BPF maps whose type is
 /// `BPF_MAP_TYPE_LRU_HASH`
+///
+/// The kernel evicts the least recently used entries of LRU hash maps to make
+/// room for new entries, so inserting never fails because the map is full
+/// but entries disappear without being deleted. Eviction can happen before
+/// the map is full too because free entries are kept in per-cpu lists. Do not
+/// expect that a key set by [`set`](#method.set) is found by
+/// [`get`](#method.get) later. Use [`HashMap`](struct.HashMap.html) if all
+/// entries must persist.
+///
+/// If the map is created with `BPF_F_NO_COMMON_LRU` flag, each CPU has its
+/// own LRU list and evicts entries from it only. See
+/// [`has_common_lru`](#method.has_common_lru).
 pub struct LruHashMap<'a, K: Clone, V: Clone> {
     base: &'a Map,
     _k: PhantomData<K>,
@@ -387,6 +826,20 @@ pub struct SockMap<'a> {
     base: &'a Map,
 }
 
+/// Socket array of `SO_REUSEPORT` groups corresponding to
+/// BPF_MAP_TYPE_REUSEPORT_SOCKARRAY
+///
+/// Userspace programs put the sockets of a `SO_REUSEPORT` group in the map
+/// and [`SkReuseport`](struct.SkReuseport.html) programs select one of them
+/// by index. The sockets must be bound with `SO_REUSEPORT` and, for TCP,
+/// listening. All sockets of the map must belong to the same group.
+///
+/// The counterpart which is used by BPF program is:
+/// [`redbpf_probes::maps::ReuseportSockArray`](../redbpf_probes/maps/struct.ReuseportSockArray.html).
+pub struct ReuseportSockArray<'a> {
+    base: &'a Map,
+}
+
 /// Array map corresponding to BPF_MAP_TYPE_ARRAY
 ///
 /// # Example
@@ -426,11 +879,161 @@ pub struct PerCpuArray<'a, T: Clone> {
 const BPF_MAX_STACK_DEPTH: usize = 127;
 const BPF_FS_MAGIC: i64 = 0xcafe4a11;
 
+// BPF_JMP | BPF_CALL
+const BPF_JMP_CALL: u8 = 0x85;
+// `src_reg` of the call instructions that call BPF functions
+const BPF_PSEUDO_CALL: u8 = 1;
+// `src_reg` of the call instructions that call kfuncs
+const BPF_PSEUDO_KFUNC_CALL: u8 = 2;
+const SHN_UNDEF: u32 = 0;
+// The maximum number of programs attached to a cgroup hook
+const BPF_CGROUP_MAX_PROGS: usize = 64;
+// The socket options to attach BPF programs to `SO_REUSEPORT` groups
+const SO_ATTACH_REUSEPORT_EBPF: libc::c_int = 52;
+const SO_DETACH_REUSEPORT_BPF: libc::c_int = 68;
+// The largest verifier log buffer accepted by kernels before v5.2, which
+// reject `log_size` above `UINT_MAX >> 8`
+const VERIFIER_LOG_MAX_SIZE: usize = (u32::MAX >> 8) as usize;
+
+// `BPF_TRACE_KPROBE_MULTI` link was introduced at Linux 5.18
+const KPROBE_MULTI_MIN_VERSION: u32 = 5 << 16 | 18 << 8;
+const BPF_TRACE_KPROBE_MULTI: u32 = 42;
+const BPF_F_KPROBE_MULTI_RETURN: u32 = 1;
+
+// The `link_create` member of `union bpf_attr` with `kprobe_multi`. libbpf-sys
+// is older than kprobe multi links so the layout is defined here.
+#[repr(C)]
+struct KProbeMultiLinkCreateAttr {
+    prog_fd: u32,
+    target_fd: u32,
+    attach_type: u32,
+    flags: u32,
+    kprobe_multi_flags: u32,
+    cnt: u32,
+    syms: u64,
+    addrs: u64,
+    cookies: u64,
+}
+
+// Bloom filter maps were introduced at Linux 5.16
+const BPF_MAP_TYPE_BLOOM_FILTER: u32 = 30;
+// `nr_hashes` is stored in the lower 4 bits of `map_extra`
+const BLOOM_FILTER_MAX_NR_HASHES: u32 = 15;
+
+// The `map_create` member of `union bpf_attr` up to `map_extra`. libbpf-sys is
+// older than `map_extra` so the layout is defined here.
+#[repr(C)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+    inner_map_fd: u32,
+    numa_node: u32,
+    map_name: [u8; 16],
+    map_ifindex: u32,
+    btf_fd: u32,
+    btf_key_type_id: u32,
+    btf_value_type_id: u32,
+    btf_vmlinux_value_type_id: u32,
+    map_extra: u64,
+}
+
+impl MapCreateAttr {
+    fn new(name: &str, config: &bpf_map_def) -> MapCreateAttr {
+        let mut attr = unsafe { mem::zeroed::<MapCreateAttr>() };
+        attr.map_type = config.type_;
+        attr.key_size = config.key_size;
+        attr.value_size = config.value_size;
+        attr.max_entries = config.max_entries;
+        attr.map_flags = config.map_flags;
+        // the name is truncated to 15 bytes and NUL-terminated like libbpf
+        let len = name.len().min(attr.map_name.len() - 1);
+        attr.map_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        attr
+    }
+}
+
 #[repr(C)]
 pub struct BpfStackFrames {
     pub ip: [u64; BPF_MAX_STACK_DEPTH],
 }
 
+/// Bloom filter map.
+///
+/// A probabilistic set of values. Testing whether a value is in the set may
+/// return false positives but never false negatives, so it is useful as a
+/// fast pre-filter before an exact lookup. Values can be added but not
+/// removed.
+///
+/// To use this from eBPF code, see
+/// [`redbpf_probes::maps::BloomFilter`](../redbpf_probes/maps/struct.BloomFilter.html).
+pub struct BloomFilter<'a, V: Clone> {
+    base: &'a Map,
+    _v: PhantomData<V>,
+}
+
+/// Queue map.
+///
+/// A FIFO list of values without keys. Values pushed by userspace or BPF
+/// programs are popped in the order they were pushed, which makes it useful
+/// for passing a work list between them. Requires Linux 4.20 or newer.
+pub struct Queue<'a, V: Clone> {
+    base: &'a Map,
+    _v: PhantomData<V>,
+}
+
+/// Stack map.
+///
+/// Same as [`Queue`](./struct.Queue.html) except that values are popped in
+/// LIFO order.
+pub struct Stack<'a, V: Clone> {
+    base: &'a Map,
+    _v: PhantomData<V>,
+}
+
+/// Map-in-map.
+///
+/// A `BPF_MAP_TYPE_ARRAY_OF_MAPS` or `BPF_MAP_TYPE_HASH_OF_MAPS` map whose
+/// values are other maps, e.g., a hash map per network interface referenced
+/// from an outer array. BPF programs look up an inner map in the outer map
+/// and then look up the inner map. The outer map is created by
+/// [`Map::create_map_in_map`](./struct.Map.html#method.create_map_in_map)
+/// from a template inner map, so the template must be created first. Only
+/// inner maps with the same definition as the template can be set.
+///
+/// # Example
+/// ```no_run
+/// use redbpf::{Map, OuterMap};
+/// use libbpf_sys::{BPF_MAP_TYPE_ARRAY_OF_MAPS, BPF_MAP_TYPE_HASH};
+///
+/// let eth0 = Map::create("eth0_rules", BPF_MAP_TYPE_HASH, 4, 4, 1024, 0).unwrap();
+/// let eth1 = Map::create("eth1_rules", BPF_MAP_TYPE_HASH, 4, 4, 1024, 0).unwrap();
+/// let rules = Map::create_map_in_map("rules", BPF_MAP_TYPE_ARRAY_OF_MAPS, 2, &eth0).unwrap();
+/// let outer = OuterMap::<u32>::new(&rules).unwrap();
+/// outer.set_inner(&0, &eth0).unwrap();
+/// outer.set_inner(&1, &eth1).unwrap();
+/// ```
+pub struct OuterMap<'a, K: Clone> {
+    base: &'a Map,
+    _k: PhantomData<K>,
+}
+
+/// A map whose key and value types are checked.
+///
+/// It is created by [`Map::open_typed`](./struct.Map.html#method.open_typed)
+/// which checks the sizes of `K` and `V` against the BTF of the map, so that
+/// a Rust type which drifted from the type of the BPF program is caught
+/// before values are corrupted. Elements are accessed like any regular map
+/// so per-CPU maps and maps that can not be looked up, like perf event arrays
+/// and queues, are not supported.
+pub struct TypedMap<'a, K: Clone, V: Clone> {
+    base: &'a Map,
+    _k: PhantomData<K>,
+    _v: PhantomData<V>,
+}
+
 /// Program array map.
 ///
 /// An array of eBPF programs that can be used as a jump table.
@@ -456,25 +1059,71 @@ trait MapIterable<K: Clone, V: Clone> {
 impl Program {
     #[allow(clippy::unnecessary_wraps)]
     fn new(kind: &str, name: &str, code: &[u8]) -> Result<Program> {
-        let code = unsafe { zero::read_array_unsafe(code) }.to_vec();
-        let name = name.to_string();
+        let code = read_insns(code);
+        // the name of tracepoint programs is <category>/<tracepoint>, that of
+        // raw tracepoint programs is <tracepoint>/<program name>
+        // and that of cgroup programs is <hook>/<program name>. A libbpf
+        // style raw_tracepoint/<tracepoint> section has no program name so
+        // the tracepoint doubles as the name.
+        let mut names = name.rsplitn(2, '/');
+        let (name, target) = match kind {
+            "raw_tracepoint" | "raw_tp" => {
+                let name = names.next().unwrap();
+                (name.to_string(), names.next().or(Some(name)))
+            }
+            "cgroup_skb" | "cgroup_sock" => (names.next().unwrap().to_string(), names.next()),
+            "tracepoint" => match name.split_once('/') {
+                Some((_, tracepoint)) => (tracepoint.to_string(), Some(name)),
+                None => (name.to_string(), None),
+            },
+            _ => (name.to_string(), None),
+        };
 
         let common = ProgramData {
             name,
             code,
             fd: None,
+            license: String::new(),
+            func_info: Vec::new(),
+            btf_fd: None,
         };
 
+        let mut program = Program::with_data(kind, common)?;
+        match &mut program {
+            Program::TracePoint(tp) => {
+                tp.tracepoint = target
+                    .and_then(|target| target.split_once('/'))
+                    .map(|(category, name)| (category.to_string(), name.to_string()))
+            }
+            Program::RawTracePoint(raw_tp) => raw_tp.tracepoint = target.map(String::from),
+            Program::CgroupSock(cgroup_sock) => {
+                if let Some(hook) = target {
+                    cgroup_sock.attach_type = CgroupSockAttachType::from_hook(hook)
+                        .ok_or_else(|| Error::Section(format!("cgroup/{}", hook)))?;
+                }
+            }
+            _ => {}
+        }
+        Ok(program)
+    }
+
+    fn with_data(kind: &str, common: ProgramData) -> Result<Program> {
         Ok(match kind {
             "kprobe" => Program::KProbe(KProbe {
                 common,
                 attach_type: ProbeAttachType::Entry,
                 attachment_points: Vec::new(),
+                multi_fd: None,
+                multi_links: Vec::new(),
+                detached: Vec::new(),
             }),
             "kretprobe" => Program::KProbe(KProbe {
                 common,
                 attach_type: ProbeAttachType::Return,
                 attachment_points: Vec::new(),
+                multi_fd: None,
+                multi_links: Vec::new(),
+                detached: Vec::new(),
             }),
             "uprobe" => Program::UProbe(UProbe {
                 common,
@@ -486,27 +1135,59 @@ impl Program {
                 attach_type: ProbeAttachType::Return,
                 attachment_points: Vec::new(),
             }),
-            "tracepoint" => Program::TracePoint(TracePoint { common }),
+            "tracepoint" => Program::TracePoint(TracePoint {
+                common,
+                tracepoint: None,
+                attachment_points: Vec::new(),
+                detached: Vec::new(),
+            }),
             "socketfilter" => Program::SocketFilter(SocketFilter { common }),
             "xdp" => Program::XDP(XDP {
                 common,
                 interfaces: Vec::new(),
+                detached: Vec::new(),
             }),
             "streamparser" => Program::StreamParser(StreamParser { common }),
             "streamverdict" => Program::StreamVerdict(StreamVerdict { common }),
             "sk_lookup" => Program::SkLookup(SkLookup { common, link: None }),
+            "sockops" => Program::SockOps(SockOps {
+                common,
+                cgroups: Vec::new(),
+            }),
+            "cgroup_skb" => Program::CgroupSkb(CgroupSkb {
+                common,
+                cgroups: Vec::new(),
+            }),
+            "cgroup_sock" => Program::CgroupSock(CgroupSock {
+                common,
+                attach_type: CgroupSockAttachType::SockCreate,
+                cgroups: Vec::new(),
+            }),
+            "sk_reuseport" => Program::SkReuseport(SkReuseport { common }),
+            "raw_tracepoint" | "raw_tp" => Program::RawTracePoint(RawTracePoint {
+                common,
+                tracepoint: None,
+                link_fds: Vec::new(),
+            }),
+            "perf_event" => Program::PerfEvent(PerfEvent {
+                common,
+                attachment_points: Vec::new(),
+            }),
             _ => return Err(Error::Section(kind.to_string())),
         })
     }
 
     fn with_btf(kind: &str, name: &str, code: &[u8], btf: &BTF) -> Result<Program> {
-        let code = unsafe { zero::read_array_unsafe(code) }.to_vec();
+        let code = read_insns(code);
         let name = name.to_string();
 
-        let common = ProgramData {
+        let mut common = ProgramData {
             name,
             code,
             fd: None,
+            license: String::new(),
+            func_info: Vec::new(),
+            btf_fd: None,
         };
 
         Ok(match kind {
@@ -521,6 +1202,91 @@ impl Program {
                     link_fd: None,
                 })
             }
+            "struct_ops" => {
+                // the name is <struct name>/<member>/<program name>
+                let mut names = common.name.splitn(3, '/');
+                let (struct_name, member, prog_name) =
+                    match (names.next(), names.next(), names.next()) {
+                        (Some(struct_name), Some(member), Some(prog_name)) => (
+                            struct_name.to_string(),
+                            member.to_string(),
+                            prog_name.to_string(),
+                        ),
+                        _ => return Err(Error::Section(format!("{}/{}", kind, common.name))),
+                    };
+                let btf_id = btf
+                    .find_type_id(&struct_name, BtfKind::Structure)
+                    .ok_or_else(|| {
+                        Error::BTF(format!("type id of struct {} not found", struct_name))
+                    })?;
+                let member_idx = btf
+                    .struct_members(btf_id)
+                    .and_then(|members| members.iter().position(|memb| memb.name == member))
+                    .ok_or_else(|| {
+                        Error::BTF(format!(
+                            "member {} of struct {} not found",
+                            member, struct_name
+                        ))
+                    })?;
+                common.name = prog_name;
+                Program::StructOps(StructOps {
+                    common,
+                    struct_name,
+                    member,
+                    attach_btf_id: btf_id,
+                    member_idx: member_idx as u32,
+                })
+            }
+            _ => return Err(Error::Section(kind.to_string())),
+        })
+    }
+
+    // Unlike `with_btf`, a missing vmlinux BTF or target function does not
+    // fail parsing but loading, so that modules can fall back to kprobes
+    fn with_target(kind: &str, name: &str, code: &[u8], btf: Option<&BTF>) -> Result<Program> {
+        let code = read_insns(code);
+        // the name is <target function or LSM hook>/<program name>
+        let mut names = name.splitn(2, '/');
+        let (target, prog_name) = match (names.next(), names.next()) {
+            (Some(target), Some(prog_name)) => (target.to_string(), prog_name.to_string()),
+            _ => return Err(Error::Section(format!("{}/{}", kind, name))),
+        };
+        // LSM programs are attached to the `bpf_lsm_<hook>` function that
+        // the kernel defines for every hook with CONFIG_BPF_LSM
+        let btf_name = match kind {
+            "lsm" => format!("bpf_lsm_{}", target),
+            _ => target.clone(),
+        };
+        let attach_btf_id = btf.and_then(|btf| btf.find_type_id(&btf_name, BtfKind::Function));
+        debug!("btf_id of {}: {:?}", btf_name, attach_btf_id);
+        let common = ProgramData {
+            name: prog_name,
+            code,
+            fd: None,
+            license: String::new(),
+            func_info: Vec::new(),
+            btf_fd: None,
+        };
+        Ok(match kind {
+            "fentry" | "fexit" => {
+                let fentry = FEntry {
+                    common,
+                    target,
+                    attach_btf_id,
+                    link_fd: None,
+                };
+                if kind == "fentry" {
+                    Program::FEntry(fentry)
+                } else {
+                    Program::FExit(fentry)
+                }
+            }
+            "lsm" => Program::Lsm(Lsm {
+                common,
+                hook: target,
+                attach_btf_id,
+                link_fd: None,
+            }),
             _ => return Err(Error::Section(kind.to_string())),
         })
     }
@@ -534,8 +1300,16 @@ impl Program {
             SocketFilter(_) => libbpf_sys::BPF_PROG_TYPE_SOCKET_FILTER,
             TracePoint(_) => libbpf_sys::BPF_PROG_TYPE_TRACEPOINT,
             StreamParser(_) | StreamVerdict(_) => libbpf_sys::BPF_PROG_TYPE_SK_SKB,
-            TaskIter(_) => libbpf_sys::BPF_PROG_TYPE_TRACING,
+            TaskIter(_) | FEntry(_) | FExit(_) => libbpf_sys::BPF_PROG_TYPE_TRACING,
             SkLookup(_) => libbpf_sys::BPF_PROG_TYPE_SK_LOOKUP,
+            SockOps(_) => libbpf_sys::BPF_PROG_TYPE_SOCK_OPS,
+            CgroupSkb(_) => libbpf_sys::BPF_PROG_TYPE_CGROUP_SKB,
+            CgroupSock(_) => libbpf_sys::BPF_PROG_TYPE_CGROUP_SOCK,
+            SkReuseport(_) => libbpf_sys::BPF_PROG_TYPE_SK_REUSEPORT,
+            RawTracePoint(_) => libbpf_sys::BPF_PROG_TYPE_RAW_TRACEPOINT,
+            PerfEvent(_) => libbpf_sys::BPF_PROG_TYPE_PERF_EVENT,
+            StructOps(_) => libbpf_sys::BPF_PROG_TYPE_STRUCT_OPS,
+            Lsm(_) => libbpf_sys::BPF_PROG_TYPE_LSM,
         }
     }
 
@@ -552,6 +1326,15 @@ impl Program {
             StreamVerdict(p) => &p.common,
             TaskIter(p) => &p.common,
             SkLookup(p) => &p.common,
+            SockOps(p) => &p.common,
+            CgroupSkb(p) => &p.common,
+            CgroupSock(p) => &p.common,
+            SkReuseport(p) => &p.common,
+            RawTracePoint(p) => &p.common,
+            PerfEvent(p) => &p.common,
+            StructOps(p) => &p.common,
+            FEntry(p) | FExit(p) => &p.common,
+            Lsm(p) => &p.common,
         }
     }
 
@@ -568,6 +1351,15 @@ impl Program {
             StreamVerdict(p) => &mut p.common,
             TaskIter(p) => &mut p.common,
             SkLookup(p) => &mut p.common,
+            SockOps(p) => &mut p.common,
+            CgroupSkb(p) => &mut p.common,
+            CgroupSock(p) => &mut p.common,
+            SkReuseport(p) => &mut p.common,
+            RawTracePoint(p) => &mut p.common,
+            PerfEvent(p) => &mut p.common,
+            StructOps(p) => &mut p.common,
+            FEntry(p) | FExit(p) => &mut p.common,
+            Lsm(p) => &mut p.common,
         }
     }
 
@@ -579,37 +1371,259 @@ impl Program {
         &self.data().fd
     }
 
-    /// Load the BPF program.
+    /// Pin the loaded program to BPF FS
     ///
-    /// BPF programs need to be loaded before they can be attached. Loading will fail if the BPF verifier rejects the code.
+    /// The program stays loaded after the process exits as long as `file`
+    /// exists. Remove `file` to unpin it.
     ///
     /// # Example
+    /// ```no_run
+    /// use redbpf::load::Loader;
+    /// let loaded = Loader::load_file("file.elf").expect("error loading probe");
+    /// let program = loaded.program("filter").expect("program not found");
+    /// program.pin("/sys/fs/bpf/filter").expect("error on pinning");
+    /// ```
+    pub fn pin(&self, file: impl AsRef<Path>) -> Result<()> {
+        let fd = self.fd().ok_or(Error::ProgramNotLoaded)?;
+        pin_bpf_obj(fd, file)
+    }
+
+    /// Create `Program` from a file which represents a pinned program
     ///
+    /// The kernel does not tell some types of programs apart, e.g., kprobes
+    /// and kretprobes, so `program_type` tells which program is expected.
+    /// It fails if the pinned program is of another type. The program is
+    /// loaded already and can be attached as is.
+    ///
+    /// # Example
     /// ```no_run
-    /// use redbpf::Module;
-    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
-    /// for program in module.programs.iter_mut() {
-    ///     program
-    ///         .load(module.version, module.license.clone()).unwrap()
-    /// }
+    /// use redbpf::{Program, ProgramType};
+    /// let mut program = Program::from_pin_file("/sys/fs/bpf/filter", ProgramType::XDP)
+    ///     .expect("error on Program::from_pin_file");
     /// ```
-    pub fn load(&mut self, kernel_version: u32, license: String) -> Result<()> {
-        if self.fd().is_some() {
-            return Err(Error::ProgramAlreadyLoaded);
+    pub fn from_pin_file(file: impl AsRef<Path>, program_type: ProgramType) -> Result<Program> {
+        let file = file.as_ref();
+        let fd = unsafe {
+            let cpathname = CString::new(file.to_str().unwrap())?;
+            libbpf_sys::bpf_obj_get(cpathname.as_ptr())
+        };
+        if fd < 0 {
+            error!("error on bpf_obj_get: {}", io::Error::last_os_error());
+            return Err(Error::IO(io::Error::last_os_error()));
         }
-        // Should bind CString to local variable not to make a dangling pointer
-        // with .as_ptr() method
-        let cname = CString::new(self.name().clone())?;
-        let clicense = CString::new(license)?;
+        // The fd is closed by `ProgramData` from now on
+        let common = ProgramData {
+            name: String::new(),
+            code: Vec::new(),
+            fd: Some(fd),
+            license: String::new(),
+            func_info: Vec::new(),
+            btf_fd: None,
+        };
+        let mut program = Program::with_data(program_type.section_kind(), common)?;
+        let info = prog_info_by_fd(fd)?;
+        if info.type_ != program_type.to_prog_type() {
+            error!(
+                "pinned program {:?} is not of type {:?}",
+                file, program_type
+            );
+            return Err(Error::BPF);
+        }
+        program.data_mut().name = unsafe {
+            CStr::from_ptr(&info.name as *const _)
+                .to_string_lossy()
+                .into_owned()
+        };
+        Ok(program)
+    }
 
-        let mut attr = unsafe { mem::zeroed::<libbpf_sys::bpf_load_program_attr>() };
+    /// Return the information of the loaded program
+    ///
+    /// The id of the program does not change while it is loaded. Its run
+    /// count and run time are only counted while `kernel.bpf_stats_enabled`
+    /// is on. See the [`info`](info/index.html) module.
+    pub fn info(&self) -> Result<ProgramInfo> {
+        let fd = self.fd().ok_or(Error::ProgramNotLoaded)?;
+        let info = prog_info_by_fd(fd)?;
+        let map_ids = prog_map_ids_by_fd(fd, info.nr_map_ids)?;
+        Ok(ProgramInfo::new(&info, map_ids))
+    }
+
+    /// Return true if the kernel compiled the loaded program into native
+    /// code
+    ///
+    /// Programs that are not JIT-compiled run in the BPF interpreter, which
+    /// is much slower. Programs are JIT-compiled only if the JIT was enabled
+    /// when they were loaded, see [`is_jit_enabled`](fn.is_jit_enabled.html).
+    pub fn is_jited(&self) -> Result<bool> {
+        Ok(self.info()?.jited_prog_len > 0)
+    }
 
-        attr.prog_type = self.to_prog_type();
-        attr.name = cname.as_ptr();
+    /// Return the size in bytes of the native code of the loaded program
+    ///
+    /// It is 0 if the program is not JIT-compiled.
+    pub fn jited_size(&self) -> Result<u32> {
+        Ok(self.info()?.jited_prog_len)
+    }
+
+    /// Return the size in bytes of the loaded program after the verifier
+    /// rewrote its instructions
+    pub fn xlated_size(&self) -> Result<u32> {
+        Ok(self.info()?.xlated_prog_len)
+    }
+
+    /// Return the subprograms of the loaded program
+    ///
+    /// Programs with BPF-to-BPF calls consist of the main program and the
+    /// functions it calls, and the verifier checks each of them against its
+    /// limits separately. The subprograms are found by the func_info of the
+    /// program and named after their BTF. A program loaded without func_info
+    /// is returned as a single subprogram.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let program = module.program_mut("trace_vfs").unwrap();
+    /// for subprog in program.subprograms().unwrap() {
+    ///     println!("{}: {} insns", subprog.name, subprog.insn_cnt);
+    /// }
+    /// ```
+    pub fn subprograms(&self) -> Result<Vec<SubprogInfo>> {
+        let fd = self.fd().ok_or(Error::ProgramNotLoaded)?;
+        let info = prog_info_by_fd(fd)?;
+        let insn_cnt = info.xlated_prog_len / mem::size_of::<bpf_insn>() as u32;
+        if info.nr_func_info == 0 {
+            return Ok(vec![SubprogInfo {
+                name: self.name().to_string(),
+                insn_off: 0,
+                insn_cnt,
+            }]);
+        }
+        let func_info = prog_func_info_by_fd(fd, info.nr_func_info)?;
+        // name subprograms like the verifier log does if BTF is unavailable
+        let btf = btf::parse_kernel_btf_by_id(info.btf_id)
+            .map_err(|e| warn!("error parsing BTF of `{}': {:?}", self.name(), e))
+            .ok();
+        let subprogs = func_info
+            .iter()
+            .enumerate()
+            .map(|(i, finfo)| {
+                let end = func_info.get(i + 1).map_or(insn_cnt, |next| next.insn_off);
+                let name = btf
+                    .as_ref()
+                    .and_then(|btf| btf.name_of(finfo.type_id))
+                    .map_or_else(|| format!("func#{}", i), |name| name.to_string());
+                SubprogInfo {
+                    name,
+                    insn_off: finfo.insn_off,
+                    insn_cnt: end.saturating_sub(finfo.insn_off),
+                }
+            })
+            .collect();
+        Ok(subprogs)
+    }
+
+    /// Detach the program from everything it is attached to, remembering
+    /// where it was attached.
+    ///
+    /// The program stays loaded, so its maps and their contents are kept
+    /// intact. Call [`reattach`](#method.reattach) to attach the program
+    /// again to the same targets. This is supported by `kprobe`,
+    /// `kretprobe`, `tracepoint` and `XDP` programs.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let program = module.program_mut("trace_vfs").unwrap();
+    /// // pause tracing
+    /// program.detach().unwrap();
+    /// // ... and resume it
+    /// program.reattach().unwrap();
+    /// ```
+    pub fn detach(&mut self) -> Result<()> {
+        use Program::*;
+
+        match self {
+            KProbe(p) | KRetProbe(p) => p.detach(),
+            TracePoint(p) => p.detach(),
+            XDP(p) => p.detach(),
+            _ => {
+                error!("detaching `{}' is not supported", self.name());
+                Err(Error::BPF)
+            }
+        }
+    }
+
+    /// Attach the program again to the targets it was detached from by
+    /// [`detach`](#method.detach).
+    pub fn reattach(&mut self) -> Result<()> {
+        use Program::*;
+
+        match self {
+            KProbe(p) | KRetProbe(p) => p.reattach(),
+            TracePoint(p) => p.reattach(),
+            XDP(p) => p.reattach(),
+            _ => {
+                error!("reattaching `{}' is not supported", self.name());
+                Err(Error::BPF)
+            }
+        }
+    }
+
+    /// Return true if the program was detached by [`detach`](#method.detach)
+    /// and has not been reattached yet
+    pub fn is_detached(&self) -> bool {
+        use Program::*;
+
+        match self {
+            KProbe(p) | KRetProbe(p) => !p.detached.is_empty(),
+            TracePoint(p) => !p.detached.is_empty(),
+            XDP(p) => !p.detached.is_empty(),
+            _ => false,
+        }
+    }
+
+    /// Load the BPF program.
+    ///
+    /// BPF programs need to be loaded before they can be attached. Loading will fail if the BPF verifier rejects the code.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// for program in module.programs.iter_mut() {
+    ///     program
+    ///         .load(module.version, module.license.clone()).unwrap()
+    /// }
+    /// ```
+    pub fn load(&mut self, kernel_version: u32, license: String) -> Result<()> {
+        if self.fd().is_some() {
+            return Err(Error::ProgramAlreadyLoaded);
+        }
+        // Should bind CString to local variable not to make a dangling pointer
+        // with .as_ptr() method
+        let cname = CString::new(self.name().clone())?;
+        let clicense = CString::new(license.clone())?;
+        self.data_mut().license = license;
+
+        let mut attr = unsafe { mem::zeroed::<libbpf_sys::bpf_load_program_attr>() };
+
+        attr.prog_type = self.to_prog_type();
+        attr.name = cname.as_ptr();
         attr.insns = self.data().code.as_ptr();
         attr.insns_cnt = self.data().code.len() as u64;
         attr.license = clicense.as_ptr();
         attr.log_level = 0;
+        if let Some(btf_fd) = self.data().btf_fd {
+            let func_info = &self.data().func_info;
+            attr.prog_btf_fd = btf_fd as u32;
+            attr.func_info = func_info.as_ptr() as *const _;
+            attr.func_info_cnt = func_info.len() as u32;
+            attr.func_info_rec_size = mem::size_of::<libbpf_sys::bpf_func_info>() as u32;
+        }
 
         match self {
             Program::TaskIter(bpf_iter) => {
@@ -620,6 +1634,32 @@ impl Program {
                 attr.expected_attach_type = BPF_SK_LOOKUP;
                 attr.__bindgen_anon_1.kern_version = kernel_version;
             }
+            Program::SkReuseport(_) => {
+                attr.expected_attach_type = BPF_SK_REUSEPORT_SELECT;
+                attr.__bindgen_anon_1.kern_version = kernel_version;
+            }
+            Program::CgroupSock(cgroup_sock) => {
+                // the kernel rejects attaching to other hooks than this one
+                attr.expected_attach_type = cgroup_sock.attach_type.to_attach_type();
+                attr.__bindgen_anon_1.kern_version = kernel_version;
+            }
+            Program::StructOps(struct_ops) => {
+                // the index of the member implemented by the program
+                attr.expected_attach_type = struct_ops.member_idx;
+                attr.__bindgen_anon_2.attach_btf_id = struct_ops.attach_btf_id;
+            }
+            Program::FEntry(fentry) => {
+                attr.expected_attach_type = BPF_TRACE_FENTRY;
+                attr.__bindgen_anon_2.attach_btf_id = fentry.attach_btf_id()?;
+            }
+            Program::FExit(fexit) => {
+                attr.expected_attach_type = BPF_TRACE_FEXIT;
+                attr.__bindgen_anon_2.attach_btf_id = fexit.attach_btf_id()?;
+            }
+            Program::Lsm(lsm) => {
+                attr.expected_attach_type = BPF_LSM_MAC;
+                attr.__bindgen_anon_2.attach_btf_id = lsm.attach_btf_id()?;
+            }
             _ => {
                 attr.expected_attach_type = 0;
                 attr.__bindgen_anon_1.kern_version = kernel_version;
@@ -658,14 +1698,14 @@ impl Program {
             }
         }
 
-        // unknown error. print log from bpf verifier and give up loading BPF program
+        // unknown error. load the program again with a log buffer to get the
+        // log of the verifier and give up loading BPF program
         attr.log_level = 1;
-        let mut printed = false;
         let mut vec_len = 64 * 1024;
-        while !printed {
+        loop {
             let mut buf_vec = vec![0; vec_len];
             let log_buffer: MutDataPtr = buf_vec.as_mut_ptr();
-            let buf_size = buf_vec.capacity() * mem::size_of_val(unsafe { &*log_buffer });
+            let buf_size = buf_vec.len() * mem::size_of_val(unsafe { &*log_buffer });
             let fd =
                 unsafe { libbpf_sys::bpf_load_program_xattr(&attr, log_buffer, buf_size as u64) };
             if fd >= 0 {
@@ -675,26 +1715,103 @@ impl Program {
                 self.data_mut().fd = Some(fd);
                 return Ok(());
             }
-            if let Some(libc::ENOSPC) = io::Error::last_os_error().raw_os_error() {
-                // If the size of the buffer is not large
-                // enough to store all verifier messages, errno
-                // is set to ENOSPC. So, pass the bigger log
-                // buffer.
-                vec_len *= 2;
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(libc::ENOSPC) && vec_len < VERIFIER_LOG_MAX_SIZE {
+                // If the size of the buffer is not large enough to store all
+                // verifier messages, errno is set to ENOSPC. So, pass the
+                // bigger log buffer. At the limit, the truncated log is
+                // returned.
+                vec_len = (vec_len * 2).min(VERIFIER_LOG_MAX_SIZE);
                 continue;
             }
 
-            let cstr = unsafe { CStr::from_ptr(log_buffer) };
+            let log_len = buf_vec.iter().position(|&c| c == 0).unwrap_or(vec_len);
+            let log = buf_vec[..log_len]
+                .iter()
+                .map(|&c| c as u8)
+                .collect::<Vec<u8>>();
+            let log = String::from_utf8_lossy(&log).into_owned();
             error!(
                 "error loading BPF program `{}' with bpf_load_program_xattr. ret={} os error={}: {}",
                 self.name(),
-                fd, io::Error::last_os_error(),
-                cstr.to_str().unwrap()
+                fd,
+                error,
+                log
             );
-            printed = true;
+            return Err(Error::ProgramLoad {
+                error,
+                verifier_log: if log.is_empty() { None } else { Some(log) },
+            });
         }
+    }
+}
 
-        Err(Error::BPF)
+/// Return true if the BPF JIT compiler is enabled system-wide
+///
+/// It reads `net.core.bpf_jit_enable`, which is `1`, or `2` with debugging
+/// output, if programs are JIT-compiled on load. The sysctl does not exist if
+/// the kernel is built without the JIT, in which case `false` is returned.
+/// See the [`sysctl`](sysctl/index.html) module for the other JIT sysctls.
+pub fn is_jit_enabled() -> bool {
+    sysctl::jit_enable()
+        .map(|value| value != sysctl::JitEnable::Disabled)
+        .unwrap_or(false)
+}
+
+// Fill `info`, a `bpf_prog_info` or `bpf_map_info`, with the information of
+// the object referred to by `fd`
+unsafe fn obj_info_by_fd<T>(fd: RawFd, info: &mut T) -> Result<()> {
+    let mut info_len = mem::size_of::<T>() as u32;
+    if libbpf_sys::bpf_obj_get_info_by_fd(fd, info as *mut _ as *mut _, &mut info_len) != 0 {
+        let err = io::Error::last_os_error();
+        error!("error on bpf_obj_get_info_by_fd: {}", err);
+        return Err(Error::IO(err));
+    }
+    Ok(())
+}
+
+fn prog_info_by_fd(fd: RawFd) -> Result<libbpf_sys::bpf_prog_info> {
+    unsafe {
+        let mut info = mem::zeroed::<libbpf_sys::bpf_prog_info>();
+        obj_info_by_fd(fd, &mut info)?;
+        Ok(info)
+    }
+}
+
+fn prog_map_ids_by_fd(fd: RawFd, nr_map_ids: u32) -> Result<Vec<u32>> {
+    if nr_map_ids == 0 {
+        return Ok(Vec::new());
+    }
+    unsafe {
+        let mut map_ids = vec![0u32; nr_map_ids as usize];
+        let mut info = mem::zeroed::<libbpf_sys::bpf_prog_info>();
+        info.nr_map_ids = nr_map_ids;
+        info.map_ids = map_ids.as_mut_ptr() as u64;
+        obj_info_by_fd(fd, &mut info)?;
+        // maps can be added to the program in the meantime, e.g., by
+        // BPF_PROG_BIND_MAP
+        map_ids.truncate(info.nr_map_ids.min(nr_map_ids) as usize);
+        Ok(map_ids)
+    }
+}
+
+pub(crate) fn map_info_by_fd(fd: RawFd) -> Result<libbpf_sys::bpf_map_info> {
+    unsafe {
+        let mut info = mem::zeroed::<libbpf_sys::bpf_map_info>();
+        obj_info_by_fd(fd, &mut info)?;
+        Ok(info)
+    }
+}
+
+fn prog_func_info_by_fd(fd: RawFd, nr_func_info: u32) -> Result<Vec<libbpf_sys::bpf_func_info>> {
+    unsafe {
+        let mut func_info = vec![mem::zeroed::<libbpf_sys::bpf_func_info>(); nr_func_info as usize];
+        let mut info = mem::zeroed::<libbpf_sys::bpf_prog_info>();
+        info.nr_func_info = nr_func_info;
+        info.func_info_rec_size = mem::size_of::<libbpf_sys::bpf_func_info>() as u32;
+        info.func_info = func_info.as_mut_ptr() as u64;
+        obj_info_by_fd(fd, &mut info)?;
+        Ok(func_info)
     }
 }
 
@@ -705,6 +1822,11 @@ impl Drop for ProgramData {
                 let _ = libc::close(self.fd.unwrap());
             }
         }
+        if let Some(btf_fd) = self.btf_fd {
+            unsafe {
+                let _ = libc::close(btf_fd);
+            }
+        }
     }
 }
 
@@ -718,9 +1840,24 @@ fn pin_bpf_obj(fd: RawFd, file: impl AsRef<Path>) -> Result<()> {
         file = Path::new(".").join(file);
     }
     let dir = file.parent().unwrap();
+    check_bpf_fs(dir)?;
+    fs::create_dir_all(dir)?;
+    unsafe {
+        let cpathname = CString::new(file.to_str().unwrap())?;
+        if libbpf_sys::bpf_obj_pin(fd, cpathname.as_ptr()) != 0 {
+            error!("error on bpf_obj_pin: {}", io::Error::last_os_error());
+            Err(Error::IO(io::Error::last_os_error()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Check that `dir` or its nearest existing ancestor is on BPF FS
+fn check_bpf_fs(dir: &Path) -> Result<()> {
     let existing_ancestor: Option<&Path> = dir.ancestors().find(|x| x.exists());
     if existing_ancestor.is_none() {
-        if file.is_absolute() {
+        if dir.is_absolute() {
             error!("root directory does not exist");
         } else {
             error!("current working directory does not exist");
@@ -740,16 +1877,7 @@ fn pin_bpf_obj(fd: RawFd, file: impl AsRef<Path>) -> Result<()> {
             return Err(Error::IO(io::Error::from(ErrorKind::PermissionDenied)));
         }
     };
-    fs::create_dir_all(dir)?;
-    unsafe {
-        let cpathname = CString::new(file.to_str().unwrap())?;
-        if libbpf_sys::bpf_obj_pin(fd, cpathname.as_ptr()) != 0 {
-            error!("error on bpf_obj_pin: {}", io::Error::last_os_error());
-            Err(Error::IO(io::Error::last_os_error()))
-        } else {
-            Ok(())
-        }
-    }
+    Ok(())
 }
 
 fn unpin_bpf_obj(file: impl AsRef<Path>) -> Result<()> {
@@ -758,6 +1886,59 @@ fn unpin_bpf_obj(file: impl AsRef<Path>) -> Result<()> {
 }
 
 impl Drop for KProbeAttachmentPoint {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = perf::detach_perf_event(self.pfd);
+            let _ = libc::close(self.pfd);
+        }
+        // the event can be removed only after its perf event is closed
+        if let Some(event) = self.event.take() {
+            if let Err(e) = perf::remove_kprobe_event(&event) {
+                warn!("error removing kprobe event {}: {:?}", event, e);
+            }
+        }
+    }
+}
+
+impl Drop for KProbeMultiLink {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = libc::close(self.lfd);
+        }
+    }
+}
+
+impl Drop for KProbe {
+    fn drop(&mut self) {
+        // links must be closed before the program they refer to
+        self.multi_links.clear();
+        if let Some(fd) = self.multi_fd.take() {
+            unsafe {
+                let _ = libc::close(fd);
+            }
+        }
+    }
+}
+
+/// Return the name of the kprobe event created for `module:symbol`
+///
+/// Names of events may only contain alphanumeric characters and
+/// underscores. The process id keeps events of concurrent processes apart.
+fn kprobe_event_name(module: &str, symbol: &str, offset: u64, retprobe: bool) -> String {
+    let name = format!(
+        "{}_{}_{}_{}_{}",
+        if retprobe { 'r' } else { 'p' },
+        module,
+        symbol,
+        offset,
+        std::process::id()
+    );
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+impl Drop for TracePointAttachmentPoint {
     fn drop(&mut self) {
         unsafe {
             let _ = perf::detach_perf_event(self.pfd);
@@ -775,6 +1956,15 @@ impl Drop for UProbeAttachmentPoint {
     }
 }
 
+impl Drop for PerfEventAttachmentPoint {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = perf::detach_perf_event(self.pfd);
+            let _ = libc::close(self.pfd);
+        }
+    }
+}
+
 impl KProbe {
     /// Attach the `kprobe` or `kretprobe`.
     ///
@@ -782,6 +1972,12 @@ impl KProbe {
     /// is given, the probe will be attached at that byte offset inside the
     /// function.
     ///
+    /// Functions of loadable kernel modules are specified in the
+    /// `module:function` form, e.g., `e1000:e1000_xmit_frame`. The module
+    /// should be loaded before attaching. The probe is created through the
+    /// `kprobe_events` file of tracefs and it is removed when the probe is
+    /// detached.
+    ///
     /// # Example
     /// ```no_run
     /// use redbpf::Module;
@@ -791,27 +1987,125 @@ impl KProbe {
     /// }
     /// ```
     pub fn attach_kprobe(&mut self, fn_name: &str, offset: u64) -> Result<()> {
+        let ap = self.kprobe_attachment_point(fn_name, offset)?;
+        self.attachment_points.push(ap);
+        Ok(())
+    }
+
+    /// Attach the `kprobe` or `kretprobe` like
+    /// [`attach_kprobe`](#method.attach_kprobe) but return a guard that
+    /// detaches it on drop
+    ///
+    /// The probe is not remembered by `KProbe`, so it is not affected by
+    /// [`detach`](#method.detach) and it stays attached while the guard
+    /// lives.
+    pub fn attach_kprobe_link(&self, fn_name: &str, offset: u64) -> Result<LinkGuard> {
+        let ap = self.kprobe_attachment_point(fn_name, offset)?;
+        Ok(LinkGuard::kprobe(ap))
+    }
+
+    fn kprobe_attachment_point(&self, fn_name: &str, offset: u64) -> Result<KProbeAttachmentPoint> {
         let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let (module, symbol) = symbols::parse_kprobe_target(fn_name).ok_or_else(|| {
+            error!("invalid kprobe target: `{}'", fn_name);
+            Error::SymbolNotFound(fn_name.to_string())
+        })?;
+        if module.is_none() && offset != 0 && kallsyms::contains(symbol, offset) == Some(false) {
+            error!("offset {:#x} is beyond the end of `{}'", offset, symbol);
+            return Err(Error::SymbolNotFound(format!("{}+{:#x}", symbol, offset)));
+        }
+        let retprobe = matches!(self.attach_type, ProbeAttachType::Return);
+        let mut event = None;
         unsafe {
-            let pfd = match self.attach_type {
-                ProbeAttachType::Entry => perf::open_kprobe_perf_event(fn_name, offset)?,
+            let pfd = match module {
+                None if retprobe => perf::open_kretprobe_perf_event(symbol, offset)?,
+                None => perf::open_kprobe_perf_event(symbol, offset)?,
+                Some(module) => {
+                    if !Path::new("/sys/module").join(&module).exists() {
+                        error!("kernel module `{}' is not loaded", module);
+                        return Err(Error::SymbolNotFound(fn_name.to_string()));
+                    }
+                    let name = kprobe_event_name(&module, symbol, offset, retprobe);
+                    let target = format!("{}:{}", module, symbol);
+                    perf::create_kprobe_event(&name, &target, offset, retprobe)?;
+                    match perf::open_kprobe_event_perf_event(&name) {
+                        Ok(pfd) => {
+                            event = Some(name);
+                            pfd
+                        }
+                        Err(e) => {
+                            let _ = perf::remove_kprobe_event(&name);
+                            return Err(e);
+                        }
+                    }
+                }
+            };
+            // `KProbeAttachmentPoint` closes `pfd` and removes `event` on
+            // drop even if attaching fails
+            let ap = KProbeAttachmentPoint {
+                fn_name: fn_name.to_owned(),
+                offset,
+                addr: None,
+                pfd,
+                multi: false,
+                event,
+            };
+            perf::attach_perf_event(fd, pfd)?;
+            Ok(ap)
+        }
+    }
 
-                ProbeAttachType::Return => perf::open_kretprobe_perf_event(fn_name, offset)?,
+    /// Attach the `kprobe` or `kretprobe` at the kernel address `addr`.
+    ///
+    /// This probes code that can not be named by a symbol, e.g., a function
+    /// inlined into another one or a static function whose name is ambiguous.
+    /// The address is usually computed from
+    /// [`kallsyms::resolve`](kallsyms/fn.resolve.html).
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::{kallsyms, Module};
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let addr = kallsyms::resolve("vfs_read").expect("vfs_read not found");
+    /// for kprobe in module.kprobes_mut() {
+    ///     kprobe.attach_kprobe_addr(addr).unwrap();
+    /// }
+    /// ```
+    pub fn attach_kprobe_addr(&mut self, addr: u64) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        unsafe {
+            let pfd = if matches!(self.attach_type, ProbeAttachType::Return) {
+                perf::open_kretprobe_addr_perf_event(addr)?
+            } else {
+                perf::open_kprobe_addr_perf_event(addr)?
+            };
+            let ap = KProbeAttachmentPoint {
+                fn_name: format!("{:#x}", addr),
+                offset: 0,
+                addr: Some(addr),
+                pfd,
+                multi: false,
+                event: None,
             };
             let ret = perf::attach_perf_event(fd, pfd);
             if ret.is_ok() {
-                self.attachment_points.push(KProbeAttachmentPoint {
-                    fn_name: fn_name.to_owned(),
-                    offset,
-                    pfd,
-                });
-            } else {
-                libc::close(pfd);
+                self.attachment_points.push(ap);
             }
             ret
         }
     }
 
+    /// Detach the `kprobe` or `kretprobe` attached at `addr` by
+    /// [`attach_kprobe_addr`](#method.attach_kprobe_addr)
+    pub fn detach_kprobe_addr(&mut self, addr: u64) -> Result<()> {
+        self.attachment_points.retain(|ap| ap.addr != Some(addr));
+        self.detached.retain(|target| match target {
+            KProbeTarget::Address(a) => *a != addr,
+            _ => true,
+        });
+        Ok(())
+    }
+
     /// Detach the `kprobe` or `kretprobe`
     ///
     /// This method is not needed to be called manually because all attachment
@@ -833,48 +2127,276 @@ impl KProbe {
         // bpf program is detached from perf event and the perf event is closed
         // by dropping KProbeAttachmentPoint
         self.attachment_points
-            .retain(|ap| !(ap.fn_name == fn_name && ap.offset == offset));
+            .retain(|ap| !(ap.addr.is_none() && ap.fn_name == fn_name && ap.offset == offset));
+        self.detached.retain(|target| match target {
+            KProbeTarget::Single(name, off) => !(name == fn_name && *off == offset),
+            KProbeTarget::Address(_) | KProbeTarget::Multi(_) => true,
+        });
         Ok(())
     }
 
-    pub fn name(&self) -> String {
-        self.common.name.to_string()
-    }
+    /// Attach the `kprobe` or `kretprobe` to multiple kernel functions at once.
+    ///
+    /// On Linux 5.18 or later, a single `BPF_TRACE_KPROBE_MULTI` link is
+    /// created for all of `fn_names`, which is much faster than attaching to
+    /// each function one by one. On older kernels, or if the link can not be
+    /// created, this falls back to calling
+    /// [`attach_kprobe`](#method.attach_kprobe) for each function.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let kprobe = module.kprobe_mut("trace_vfs").expect("bpf program not found");
+    /// kprobe.attach_kprobe_multi(&["vfs_read", "vfs_write", "vfs_open"]).unwrap();
+    /// ```
+    pub fn attach_kprobe_multi(&mut self, fn_names: &[&str]) -> Result<()> {
+        self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        if fn_names.is_empty() {
+            return Ok(());
+        }
 
-    pub fn attach_type_str(&self) -> &'static str {
-        match self.attach_type {
-            ProbeAttachType::Entry => "Kprobe",
-            ProbeAttachType::Return => "Kretprobe",
+        let supported = get_kernel_internal_version()
+            .map(|version| version >= KPROBE_MULTI_MIN_VERSION)
+            .unwrap_or(false);
+        if supported {
+            match self.create_kprobe_multi_link(fn_names) {
+                Ok(lfd) => {
+                    self.multi_links.push(KProbeMultiLink {
+                        fn_names: fn_names.iter().map(|name| name.to_string()).collect(),
+                        lfd,
+                    });
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!(
+                        "error creating kprobe multi link for `{}': {:?}. Fall back to attaching one by one",
+                        self.common.name, e
+                    );
+                }
+            }
+        }
+
+        // all or nothing like the link. The probes attached so far are
+        // detached by dropping them if a later function fails
+        let mut attachment_points = Vec::with_capacity(fn_names.len());
+        for fn_name in fn_names {
+            let mut ap = self.kprobe_attachment_point(fn_name, 0).map_err(|e| {
+                error!(
+                    "error attaching `{}' to {}: {:?}",
+                    self.common.name, fn_name, e
+                );
+                e
+            })?;
+            ap.multi = true;
+            attachment_points.push(ap);
         }
+        self.attachment_points.extend(attachment_points);
+        Ok(())
     }
-}
 
-impl UProbe {
-    /// Attach the `uprobe` or `uretprobe`.
-    ///
-    /// Attach the probe to the function `fn_name` defined in the library or
-    /// binary at `path`. If an `offset` is given, the probe will be attached at
-    /// that byte offset inside the function. If `fn_name` is `None`, then
-    /// `offset` is treated as an absolute address.
+    /// Attach the `kprobe` or `kretprobe` to all kernel functions matching
+    /// `pattern`.
     ///
-    /// If a `pid` is passed, only the corresponding process is traced.
+    /// `pattern` is a glob pattern that may contain `*` and `?` wildcards. It
+    /// is matched against the functions listed in
+    /// `available_filter_functions` of tracefs. See
+    /// [`attach_kprobe_multi`](#method.attach_kprobe_multi) for how the
+    /// functions are attached.
     ///
     /// # Example
     /// ```no_run
     /// use redbpf::Module;
     /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
-    /// for uprobe in module.uprobes_mut() {
-    ///     uprobe.attach_uprobe(Some(&uprobe.name()), 0, "/lib/x86_64-linux-gnu/libc-2.30.so", None).unwrap();
-    /// }
+    /// let kprobe = module.kprobe_mut("trace_tcp").expect("bpf program not found");
+    /// kprobe.attach_kprobe_multi_pattern("tcp_v4_*").unwrap();
     /// ```
-    pub fn attach_uprobe(
-        &mut self,
-        fn_name: Option<&str>,
-        offset: u64,
-        target: &str,
-        pid: Option<pid_t>,
-    ) -> Result<()> {
-        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+    pub fn attach_kprobe_multi_pattern(&mut self, pattern: &str) -> Result<()> {
+        let fn_names = kprobe_functions_matching(pattern)?;
+        if fn_names.is_empty() {
+            error!("no kernel function matches the pattern `{}'", pattern);
+            return Err(Error::SymbolNotFound(pattern.to_string()));
+        }
+        let fn_names: Vec<&str> = fn_names.iter().map(String::as_str).collect();
+        self.attach_kprobe_multi(&fn_names)
+    }
+
+    /// Detach all functions attached by
+    /// [`attach_kprobe_multi`](#method.attach_kprobe_multi) or
+    /// [`attach_kprobe_multi_pattern`](#method.attach_kprobe_multi_pattern)
+    pub fn detach_kprobe_multi(&mut self) -> Result<()> {
+        // BPF links and perf events are closed by dropping KProbeMultiLink
+        // and KProbeAttachmentPoint
+        self.multi_links.clear();
+        self.attachment_points.retain(|ap| !ap.multi);
+        self.detached
+            .retain(|target| !matches!(target, KProbeTarget::Multi(_)));
+        Ok(())
+    }
+
+    /// Detach the `kprobe` or `kretprobe` from all functions it is attached
+    /// to, keeping the program loaded.
+    ///
+    /// The functions are remembered so that [`reattach`](#method.reattach)
+    /// can attach the probe to them again.
+    pub fn detach(&mut self) -> Result<()> {
+        let mut multi_fallback = Vec::new();
+        // perf events and BPF links are closed by dropping them
+        for ap in self.attachment_points.drain(..) {
+            if ap.multi {
+                multi_fallback.push(ap.fn_name.clone());
+            } else if let Some(addr) = ap.addr {
+                self.detached.push(KProbeTarget::Address(addr));
+            } else {
+                self.detached
+                    .push(KProbeTarget::Single(ap.fn_name.clone(), ap.offset));
+            }
+        }
+        if !multi_fallback.is_empty() {
+            self.detached.push(KProbeTarget::Multi(multi_fallback));
+        }
+        for link in self.multi_links.drain(..) {
+            self.detached
+                .push(KProbeTarget::Multi(link.fn_names.clone()));
+        }
+        Ok(())
+    }
+
+    /// Attach the `kprobe` or `kretprobe` again to the functions it was
+    /// detached from by [`detach`](#method.detach).
+    ///
+    /// If attaching to a function fails, the functions that are not
+    /// reattached yet are kept so that `reattach` can be retried.
+    pub fn reattach(&mut self) -> Result<()> {
+        self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let mut targets = mem::take(&mut self.detached).into_iter();
+        while let Some(target) = targets.next() {
+            let ret = match &target {
+                KProbeTarget::Single(fn_name, offset) => self.attach_kprobe(fn_name, *offset),
+                KProbeTarget::Address(addr) => self.attach_kprobe_addr(*addr),
+                KProbeTarget::Multi(fn_names) => {
+                    let fn_names: Vec<&str> = fn_names.iter().map(String::as_str).collect();
+                    self.attach_kprobe_multi(&fn_names)
+                }
+            };
+            if let Err(e) = ret {
+                self.detached.push(target);
+                self.detached.extend(targets);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the names of kernel functions attached by kprobe multi links
+    pub fn multi_attached_functions(&self) -> impl Iterator<Item = &str> {
+        self.multi_links
+            .iter()
+            .flat_map(|link| link.fn_names.iter().map(String::as_str))
+    }
+
+    fn load_kprobe_multi(&mut self) -> Result<RawFd> {
+        if let Some(fd) = self.multi_fd {
+            return Ok(fd);
+        }
+        let cname = CString::new(self.common.name.clone())?;
+        let clicense = CString::new(self.common.license.clone())?;
+        let mut attr = unsafe { mem::zeroed::<libbpf_sys::bpf_load_program_attr>() };
+        attr.prog_type = libbpf_sys::BPF_PROG_TYPE_KPROBE;
+        attr.expected_attach_type = BPF_TRACE_KPROBE_MULTI;
+        attr.name = cname.as_ptr();
+        attr.insns = self.common.code.as_ptr();
+        attr.insns_cnt = self.common.code.len() as u64;
+        attr.license = clicense.as_ptr();
+
+        let fd = unsafe { bpf_load_program_xattr(&attr, ptr::null_mut(), 0) };
+        if fd < 0 {
+            error!(
+                "error loading `{}' as kprobe multi program: {}",
+                self.common.name,
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        self.multi_fd = Some(fd);
+        Ok(fd)
+    }
+
+    fn create_kprobe_multi_link(&mut self, fn_names: &[&str]) -> Result<RawFd> {
+        let prog_fd = self.load_kprobe_multi()?;
+        let cnames = fn_names
+            .iter()
+            .map(|name| CString::new(*name))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let syms: Vec<*const libc::c_char> = cnames.iter().map(|name| name.as_ptr()).collect();
+
+        let mut attr = unsafe { mem::zeroed::<KProbeMultiLinkCreateAttr>() };
+        attr.prog_fd = prog_fd as u32;
+        attr.attach_type = BPF_TRACE_KPROBE_MULTI;
+        attr.kprobe_multi_flags = match self.attach_type {
+            ProbeAttachType::Entry => 0,
+            ProbeAttachType::Return => BPF_F_KPROBE_MULTI_RETURN,
+        };
+        attr.cnt = syms.len() as u32;
+        attr.syms = syms.as_ptr() as u64;
+
+        let lfd = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                libbpf_sys::BPF_LINK_CREATE,
+                &attr as *const _,
+                mem::size_of_val(&attr) as u32,
+            )
+        };
+        if lfd < 0 {
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        Ok(lfd as RawFd)
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+
+    pub fn attach_type_str(&self) -> &'static str {
+        match self.attach_type {
+            ProbeAttachType::Entry => "Kprobe",
+            ProbeAttachType::Return => "Kretprobe",
+        }
+    }
+}
+
+impl UProbe {
+    /// Attach the `uprobe` or `uretprobe`.
+    ///
+    /// Attach the probe to the function `fn_name` defined in the library or
+    /// binary at `path`. If an `offset` is given, the probe will be attached at
+    /// that byte offset inside the function. If `fn_name` is `None`, then
+    /// `offset` is treated as an absolute address.
+    ///
+    /// `fn_name` is looked up in the dynamic and the static symbol tables of
+    /// the ELF file, by its mangled name or by its demangled path without
+    /// the hash of Rust symbols and the parameter types of C++ functions,
+    /// e.g., `mycrate::parser::parse`.
+    ///
+    /// If a `pid` is passed, only the corresponding process is traced.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// for uprobe in module.uprobes_mut() {
+    ///     uprobe.attach_uprobe(Some(&uprobe.name()), 0, "/lib/x86_64-linux-gnu/libc-2.30.so", None).unwrap();
+    /// }
+    /// ```
+    pub fn attach_uprobe(
+        &mut self,
+        fn_name: Option<&str>,
+        offset: u64,
+        target: &str,
+        pid: Option<pid_t>,
+    ) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
 
         let path = if let Some(pid) = pid {
             resolve_proc_maps_lib(pid, target).unwrap_or_else(|| target.to_string())
@@ -887,10 +2409,10 @@ impl UProbe {
         let sym_offset = if let Some(fn_name) = fn_name {
             let data = fs::read(&path)?;
             let parser = ElfSymbols::parse(&data)?;
-            parser
-                .resolve(fn_name)
-                .ok_or_else(|| Error::SymbolNotFound(fn_name.to_string()))?
-                .st_value
+            parser.file_offset(fn_name).ok_or_else(|| {
+                error!("symbol `{}' not found in `{}'", fn_name, path);
+                Error::SymbolNotFound(fn_name.to_string())
+            })?
         } else {
             0
         };
@@ -919,6 +2441,30 @@ impl UProbe {
         }
     }
 
+    /// Attach the `uprobe` or `uretprobe` to the function `fn_name` of the
+    /// executable or shared library `target`
+    ///
+    /// It is a shorthand for [`attach_uprobe`](#method.attach_uprobe) with no
+    /// offset. The file offset of `fn_name` is computed from the symbol
+    /// tables of `target`, so it works for PIE and non-PIE executables as well
+    /// as shared libraries.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let uprobe = module.uprobe_mut("trace_parse").expect("bpf program not found");
+    /// uprobe.attach_uprobe_symbol("/usr/bin/myapp", "myapp::parser::parse", None).unwrap();
+    /// ```
+    pub fn attach_uprobe_symbol(
+        &mut self,
+        target: &str,
+        fn_name: &str,
+        pid: Option<pid_t>,
+    ) -> Result<()> {
+        self.attach_uprobe(Some(fn_name), 0, target, pid)
+    }
+
     /// Detach the `uprobe` or `uretprobe`
     ///
     /// This method is not needed to be called manually because all attachment
@@ -959,15 +2505,245 @@ impl UProbe {
 }
 
 impl TracePoint {
+    /// Attach the program to the tracepoint of its section
+    /// `tracepoint/<category>/<name>`
+    pub fn attach(&mut self) -> Result<()> {
+        let (category, name) = self.tracepoint.clone().ok_or_else(|| {
+            error!("tracepoint of `{}' is not given", self.common.name);
+            Error::Section(self.common.name.clone())
+        })?;
+        self.attach_trace_point(&category, &name)
+    }
+
     pub fn attach_trace_point(&mut self, category: &str, name: &str) -> Result<()> {
+        let ap = self.trace_point_attachment_point(category, name)?;
+        self.attachment_points.push(ap);
+        Ok(())
+    }
+
+    /// Attach the tracepoint program to `category:name` but return a guard
+    /// that detaches it on drop
+    ///
+    /// The tracepoint is not remembered by `TracePoint`, so it is not
+    /// affected by [`detach`](#method.detach).
+    pub fn attach_trace_point_link(&self, category: &str, name: &str) -> Result<LinkGuard> {
+        let ap = self.trace_point_attachment_point(category, name)?;
+        Ok(LinkGuard::trace_point(ap))
+    }
+
+    fn trace_point_attachment_point(
+        &self,
+        category: &str,
+        name: &str,
+    ) -> Result<TracePointAttachmentPoint> {
         let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
         // TODO Check this works correctly
         unsafe {
             let pfd = perf::open_tracepoint_perf_event(category, name)?;
-            perf::attach_perf_event(fd, pfd)
+            // `TracePointAttachmentPoint` closes `pfd` on drop even if
+            // attaching fails
+            let ap = TracePointAttachmentPoint {
+                category: category.to_owned(),
+                name: name.to_owned(),
+                pfd,
+            };
+            perf::attach_perf_event(fd, pfd)?;
+            Ok(ap)
         }
     }
 
+    /// Detach the tracepoint program from `category:name`
+    pub fn detach_trace_point(&mut self, category: &str, name: &str) -> Result<()> {
+        // the perf event is closed by dropping TracePointAttachmentPoint
+        self.attachment_points
+            .retain(|ap| !(ap.category == category && ap.name == name));
+        self.detached
+            .retain(|(cat, n)| !(cat == category && n == name));
+        Ok(())
+    }
+
+    /// Detach the tracepoint program from all tracepoints it is attached to,
+    /// keeping the program loaded.
+    ///
+    /// The tracepoints are remembered so that
+    /// [`reattach`](#method.reattach) can attach the program to them again.
+    pub fn detach(&mut self) -> Result<()> {
+        for ap in self.attachment_points.drain(..) {
+            self.detached.push((ap.category.clone(), ap.name.clone()));
+        }
+        Ok(())
+    }
+
+    /// Attach the tracepoint program again to the tracepoints it was
+    /// detached from by [`detach`](#method.detach).
+    pub fn reattach(&mut self) -> Result<()> {
+        self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let mut targets = mem::take(&mut self.detached).into_iter();
+        while let Some((category, name)) = targets.next() {
+            if let Err(e) = self.attach_trace_point(&category, &name) {
+                self.detached.push((category, name));
+                self.detached.extend(targets);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the category and the name of the tracepoint of the section
+    /// `tracepoint/<category>/<name>`
+    pub fn tracepoint(&self) -> Option<(&str, &str)> {
+        self.tracepoint
+            .as_ref()
+            .map(|(category, name)| (category.as_str(), name.as_str()))
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl RawTracePoint {
+    /// Attach the program to the tracepoint given by `#[raw_tracepoint]`
+    pub fn attach(&mut self) -> Result<()> {
+        let tracepoint = self.tracepoint.clone().ok_or_else(|| {
+            error!("tracepoint of `{}' is not given", self.common.name);
+            Error::Section(self.common.name.clone())
+        })?;
+        self.attach_raw_tracepoint(&tracepoint)
+    }
+
+    /// Attach the program to the raw tracepoint `tracepoint`, e.g.,
+    /// `sys_enter` or `sched_switch`
+    ///
+    /// The program stays attached until it is dropped.
+    pub fn attach_raw_tracepoint(&mut self, tracepoint: &str) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let ctracepoint = CString::new(tracepoint)?;
+        let link_fd = unsafe { libbpf_sys::bpf_raw_tracepoint_open(ctracepoint.as_ptr(), fd) };
+        if link_fd < 0 {
+            error!(
+                "error attaching `{}' to raw tracepoint {}: {}",
+                self.common.name,
+                tracepoint,
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        self.link_fds.push(link_fd);
+        Ok(())
+    }
+
+    /// Attach the program to the raw tracepoint `tracepoint` but return a
+    /// guard that detaches it on drop
+    pub fn attach_raw_tracepoint_link(&self, tracepoint: &str) -> Result<LinkGuard> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let ctracepoint = CString::new(tracepoint)?;
+        let link_fd = unsafe { libbpf_sys::bpf_raw_tracepoint_open(ctracepoint.as_ptr(), fd) };
+        if link_fd < 0 {
+            error!(
+                "error attaching `{}' to raw tracepoint {}: {}",
+                self.common.name,
+                tracepoint,
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        LinkGuard::from_link_fd(link_fd)
+    }
+
+    /// Return the tracepoint given by `#[raw_tracepoint]`
+    pub fn tracepoint(&self) -> Option<&str> {
+        self.tracepoint.as_deref()
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl Drop for RawTracePoint {
+    fn drop(&mut self) {
+        for link_fd in self.link_fds.drain(..) {
+            unsafe {
+                let _ = libc::close(link_fd);
+            }
+        }
+    }
+}
+
+impl PerfEventType {
+    /// The CPU clock, which is available without hardware counters
+    pub const CPU_CLOCK: PerfEventType =
+        PerfEventType::Software(sys::perf::perf_sw_ids_PERF_COUNT_SW_CPU_CLOCK);
+    /// The CPU cycles counted by hardware
+    pub const CPU_CYCLES: PerfEventType =
+        PerfEventType::Hardware(sys::perf::perf_hw_id_PERF_COUNT_HW_CPU_CYCLES);
+}
+
+impl PerfEvent {
+    /// Attach the program to `event` on every online CPU, sampling it
+    /// `sample_freq` times a second
+    ///
+    /// An odd frequency like 99Hz keeps samples from running in lockstep
+    /// with periodic activities of the system. The program stays attached
+    /// until it is dropped or [`detach`](#method.detach) is called.
+    pub fn attach_sampler(&mut self, event: PerfEventType, sample_freq: u64) -> Result<()> {
+        let mut attr = unsafe { mem::zeroed::<sys::perf::perf_event_attr>() };
+        let (type_, config) = match event {
+            PerfEventType::Software(config) => (sys::perf::perf_type_id_PERF_TYPE_SOFTWARE, config),
+            PerfEventType::Hardware(config) => (sys::perf::perf_type_id_PERF_TYPE_HARDWARE, config),
+        };
+        attr.type_ = type_;
+        attr.config = config as u64;
+        attr.size = mem::size_of::<sys::perf::perf_event_attr>() as u32;
+        attr.set_freq(1);
+        attr.__bindgen_anon_1.sample_freq = sample_freq;
+        // enabled once the program is set
+        attr.set_disabled(1);
+        self.attach_perf_event(&attr)
+    }
+
+    /// Attach the program to the perf event described by `attr` on every
+    /// online CPU
+    ///
+    /// The event is opened for all processes on each CPU. If it can not be
+    /// opened or the program can not be set on any CPU, the events opened
+    /// for the other CPUs are closed and an error is returned.
+    pub fn attach_perf_event(&mut self, attr: &sys::perf::perf_event_attr) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let mut attachment_points = vec![];
+        for cpu in cpus::get_online()? {
+            let ap = unsafe {
+                let pfd = perf::open_cpu_perf_event(attr, cpu).map_err(|e| {
+                    error!("error opening perf event on cpu {}: {:?}", cpu, e);
+                    e
+                })?;
+                // `PerfEventAttachmentPoint` closes `pfd` on drop even if
+                // attaching fails
+                let ap = PerfEventAttachmentPoint { pfd };
+                perf::attach_perf_event(fd, pfd).map_err(|e| {
+                    error!(
+                        "error attaching `{}' to perf event on cpu {}: {:?}",
+                        self.common.name, cpu, e
+                    );
+                    e
+                })?;
+                ap
+            };
+            attachment_points.push(ap);
+        }
+        self.attachment_points.extend(attachment_points);
+        Ok(())
+    }
+
+    /// Detach the program from all the perf events it is attached to,
+    /// keeping the program loaded
+    pub fn detach(&mut self) -> Result<()> {
+        // the perf events are closed by dropping PerfEventAttachmentPoint
+        self.attachment_points.clear();
+        Ok(())
+    }
+
     pub fn name(&self) -> String {
         self.common.name.to_string()
     }
@@ -987,16 +2763,168 @@ impl XDP {
     /// # }
     /// ```
     pub fn attach_xdp(&mut self, interface: &str, flags: xdp::Flags) -> Result<()> {
+        self.attach_xdp_bits(interface, flags as u32)
+    }
+
+    /// Attach the XDP program to the given network interface in `mode`.
+    ///
+    /// Unless `replace` is true, attaching fails if a program is already
+    /// attached to the interface. `Error::XdpModeUnsupported` is returned if
+    /// the driver of the interface does not support `mode`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use redbpf::{Module, xdp::XdpFlags};
+    /// # let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// # for prog in module.xdps_mut() {
+    /// prog.attach_xdp_mode("eth0", XdpFlags::Native, false).unwrap();
+    /// # }
+    /// ```
+    pub fn attach_xdp_mode(
+        &mut self,
+        interface: &str,
+        mode: xdp::XdpFlags,
+        replace: bool,
+    ) -> Result<()> {
+        self.attach_xdp_bits(interface, mode.bits(replace))
+    }
+
+    /// Attach the XDP program to the given network interface in `mode` and
+    /// return a guard that detaches it on drop
+    ///
+    /// The program is attached through a BPF link on Linux 5.9 or later, so
+    /// the kernel detaches it even if the process is killed. Otherwise it
+    /// falls back to netlink and the guard detaches the program only if it
+    /// is still the one attached to the interface. Attaching fails if a
+    /// program is already attached to the interface. The interface is not
+    /// remembered by `XDP`, so it is not affected by
+    /// [`detach`](#method.detach).
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use redbpf::{Module, xdp::XdpFlags};
+    /// # let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// # for prog in module.xdps_mut() {
+    /// let link = prog.attach_xdp_link("eth0", XdpFlags::Native).unwrap();
+    /// // the program is detached from eth0 here
+    /// drop(link);
+    /// # }
+    /// ```
+    pub fn attach_xdp_link(&self, interface: &str, mode: xdp::XdpFlags) -> Result<LinkGuard> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let ciface = CString::new(interface)?;
+        let ifindex = unsafe { libc::if_nametoindex(ciface.as_ptr()) };
+        if ifindex == 0 {
+            error!("interface {} not found", interface);
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        // BPF links do not take XDP_FLAGS_UPDATE_IF_NOEXIST, they never
+        // replace programs
+        let flags = mode.bits(true);
+        let mut opts = unsafe { mem::zeroed::<libbpf_sys::bpf_link_create_opts>() };
+        opts.sz = mem::size_of_val(&opts) as _;
+        opts.flags = flags;
+        let link_fd =
+            unsafe { libbpf_sys::bpf_link_create(fd, ifindex as i32, libbpf_sys::BPF_XDP, &opts) };
+        if link_fd >= 0 {
+            return LinkGuard::from_link_fd(link_fd);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            error!("error attaching xdp to interface {}: {}", interface, err);
+            return Err(Error::IO(err));
+        }
+
+        // BPF links of XDP are not supported by the kernel
+        let prog_id = prog_info_by_fd(fd)?.id;
+        match unsafe { attach_xdp(interface, fd, mode.bits(false)) } {
+            Ok(_) => Ok(LinkGuard::xdp(interface, flags, prog_id)),
+            Err(e) => {
+                if let Error::IO(ref oserr) = e {
+                    error!("error attaching xdp to interface {}: {}", interface, oserr);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn attach_xdp_bits(&mut self, interface: &str, flags: u32) -> Result<()> {
         let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
-        self.interfaces.push(interface.to_string());
-        if let Err(e) = unsafe { attach_xdp(interface, fd, flags as u32) } {
-            if let Error::IO(oserr) = e {
-                error!("error attaching xdp to interface {}: {}", interface, oserr);
+        match unsafe { attach_xdp(interface, fd, flags) } {
+            Ok(_) => {
+                self.interfaces.push((interface.to_string(), flags));
+                Ok(())
             }
-            Err(Error::BPF)
-        } else {
-            Ok(())
+            Err(Error::IO(oserr)) if oserr.raw_os_error() == Some(libc::EOPNOTSUPP) => {
+                let mode = xdp::XdpFlags::from_bits(flags);
+                error!(
+                    "driver of interface {} does not support xdp in {:?} mode",
+                    interface, mode
+                );
+                Err(Error::XdpModeUnsupported {
+                    interface: interface.to_string(),
+                    mode,
+                })
+            }
+            Err(e) => {
+                if let Error::IO(oserr) = e {
+                    error!("error attaching xdp to interface {}: {}", interface, oserr);
+                }
+                Err(Error::BPF)
+            }
+        }
+    }
+
+    /// Attach the XDP program to all of `interfaces`, or to none of them.
+    ///
+    /// The interfaces are attached in order. If attaching to one of them
+    /// fails, the program is detached from the interfaces that this call
+    /// attached it to and `Error::XdpMultiAttach` is returned. The error
+    /// tells the interface that failed and the interfaces that could not be
+    /// detached during the rollback, if any.
+    ///
+    /// `XDP_FLAGS_UPDATE_IF_NOEXIST` is always added to `flags`, so attaching
+    /// fails on an interface that already has an XDP program rather than
+    /// replacing it. Otherwise the rollback would leave that interface
+    /// without any program.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use redbpf::{Module, xdp};
+    /// # let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// # for prog in module.xdps_mut() {
+    /// prog.attach_xdp_multi(&["eth0", "eth1"], xdp::Flags::default()).unwrap();
+    /// # }
+    /// ```
+    pub fn attach_xdp_multi(&mut self, interfaces: &[&str], flags: xdp::Flags) -> Result<()> {
+        self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let flags = flags as u32 | libbpf_sys::XDP_FLAGS_UPDATE_IF_NOEXIST;
+        for (i, interface) in interfaces.iter().enumerate() {
+            let error = match self.attach_xdp_bits(interface, flags) {
+                Ok(_) => continue,
+                Err(e) => e,
+            };
+            // all interfaces before `i` were attached by this call since
+            // programs are never replaced
+            let mut not_rolled_back = vec![];
+            for attached in interfaces[..i].iter().rev() {
+                if self.detach_xdp(attached).is_err() {
+                    not_rolled_back.push(attached.to_string());
+                }
+            }
+            if !not_rolled_back.is_empty() {
+                error!(
+                    "xdp is still attached to {:?} after failing to attach to {}",
+                    not_rolled_back, interface
+                );
+            }
+            return Err(Error::XdpMultiAttach {
+                interface: interface.to_string(),
+                error: Box::new(error),
+                not_rolled_back,
+            });
         }
+        Ok(())
     }
 
     /// Detach the XDP program.
@@ -1014,13 +2942,13 @@ impl XDP {
     /// ```
     pub fn detach_xdp(&mut self, interface: &str) -> Result<()> {
         // The linear search here isn't great, but self.interfaces will almost always be short.
-        let index = self
+        let (index, flags) = self
             .interfaces
             .iter()
             .enumerate()
-            .find_map(|(i, v)| (v.as_str() == interface).then(|| i))
+            .find_map(|(i, (v, flags))| (v.as_str() == interface).then_some((i, *flags)))
             .ok_or(Error::ProgramNotLoaded)?;
-        if let Err(e) = unsafe { detach_xdp(interface) } {
+        if let Err(e) = unsafe { detach_xdp(interface, flags) } {
             if let Error::IO(ref oserr) = e {
                 error!(
                     "error detaching xdp from interface {}: {}",
@@ -1033,6 +2961,43 @@ impl XDP {
         Ok(())
     }
 
+    /// Detach the XDP program from all network interfaces it is attached to,
+    /// keeping the program loaded.
+    ///
+    /// The interfaces and the flags used to attach are remembered so that
+    /// [`reattach`](#method.reattach) can attach the program to them again.
+    pub fn detach(&mut self) -> Result<()> {
+        while let Some((interface, flags)) = self.interfaces.pop() {
+            if let Err(e) = unsafe { detach_xdp(&interface, flags) } {
+                if let Error::IO(ref oserr) = e {
+                    error!(
+                        "error detaching xdp from interface {}: {}",
+                        interface, oserr
+                    );
+                }
+                self.interfaces.push((interface, flags));
+                return Err(e);
+            }
+            self.detached.push((interface, flags));
+        }
+        Ok(())
+    }
+
+    /// Attach the XDP program again to the network interfaces it was
+    /// detached from by [`detach`](#method.detach).
+    pub fn reattach(&mut self) -> Result<()> {
+        self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let mut targets = mem::take(&mut self.detached).into_iter();
+        while let Some((interface, flags)) = targets.next() {
+            if let Err(e) = self.attach_xdp_bits(&interface, flags) {
+                self.detached.push((interface, flags));
+                self.detached.extend(targets);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
     pub fn name(&self) -> String {
         self.common.name.to_string()
     }
@@ -1040,20 +3005,20 @@ impl XDP {
 
 impl Drop for XDP {
     fn drop(&mut self) {
-        for interface in self.interfaces.iter() {
-            let _ = unsafe { detach_xdp(interface) };
+        for (interface, flags) in self.interfaces.iter() {
+            let _ = unsafe { detach_xdp(interface, *flags) };
         }
     }
 }
 
-unsafe fn open_raw_sock(name: &str) -> Result<RawFd> {
+unsafe fn open_raw_sock(name: &str) -> io::Result<RawFd> {
     let sock = libc::socket(
         libc::PF_PACKET,
         libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
         (libc::ETH_P_ALL as u16).to_be().into(),
     );
     if sock < 0 {
-        return Err(Error::IO(io::Error::last_os_error()));
+        return Err(io::Error::last_os_error());
     }
 
     // Do not bind on empty interface names
@@ -1063,11 +3028,16 @@ unsafe fn open_raw_sock(name: &str) -> Result<RawFd> {
 
     let mut sll = mem::zeroed::<libc::sockaddr_ll>();
     sll.sll_family = libc::AF_PACKET as u16;
-    let ciface = CString::new(name).unwrap();
-    sll.sll_ifindex = libc::if_nametoindex(ciface.as_ptr()) as i32;
+    sll.sll_ifindex = match CString::new(name) {
+        Ok(ciface) => libc::if_nametoindex(ciface.as_ptr()) as i32,
+        Err(_) => 0,
+    };
     if sll.sll_ifindex == 0 {
         libc::close(sock);
-        return Err(Error::IO(io::Error::last_os_error()));
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("interface `{}' not found", name),
+        ));
     }
 
     sll.sll_protocol = (libc::ETH_P_ALL as u16).to_be() as u16;
@@ -1077,13 +3047,33 @@ unsafe fn open_raw_sock(name: &str) -> Result<RawFd> {
         mem::size_of_val(&sll) as u32,
     ) < 0
     {
+        let e = io::Error::last_os_error();
         libc::close(sock);
-        return Err(Error::IO(io::Error::last_os_error()));
+        return Err(e);
     }
 
     Ok(sock)
 }
 
+// Open a packet socket bound to `interface` and install the socket filter
+// program of `prog_fd` on it
+unsafe fn open_filtered_sock(prog_fd: RawFd, interface: &str) -> io::Result<RawFd> {
+    let sfd = open_raw_sock(interface)?;
+    if libc::setsockopt(
+        sfd,
+        libc::SOL_SOCKET,
+        libc::SO_ATTACH_BPF,
+        &prog_fd as *const _ as *const _,
+        mem::size_of_val(&prog_fd) as u32,
+    ) < 0
+    {
+        let e = io::Error::last_os_error();
+        libc::close(sfd);
+        return Err(e);
+    }
+    Ok(sfd)
+}
+
 unsafe fn attach_xdp(dev_name: &str, progfd: libc::c_int, flags: libc::c_uint) -> Result<()> {
     let ciface = CString::new(dev_name).unwrap();
     let ifindex = libc::if_nametoindex(ciface.as_ptr()) as i32;
@@ -1091,14 +3081,35 @@ unsafe fn attach_xdp(dev_name: &str, progfd: libc::c_int, flags: libc::c_uint) -
         return Err(Error::IO(io::Error::last_os_error()));
     }
 
-    if libbpf_sys::bpf_set_link_xdp_fd(ifindex, progfd, flags) != 0 {
-        return Err(Error::IO(io::Error::last_os_error()));
+    // it returns a negative errno of the netlink request
+    let ret = libbpf_sys::bpf_set_link_xdp_fd(ifindex, progfd, flags);
+    if ret != 0 {
+        return Err(Error::IO(io::Error::from_raw_os_error(-ret)));
     }
     Ok(())
 }
 
-unsafe fn detach_xdp(dev_name: &str) -> Result<()> {
-    attach_xdp(dev_name, -1, 0)
+// `flags` are the flags used to attach. The program is detached in the same
+// mode.
+unsafe fn detach_xdp(dev_name: &str, flags: libc::c_uint) -> Result<()> {
+    attach_xdp(dev_name, -1, flags & libbpf_sys::XDP_FLAGS_MODES)
+}
+
+// Return the id of the program attached to `dev_name` in the mode of
+// `flags`, 0 if none is attached
+unsafe fn xdp_prog_id(dev_name: &str, flags: libc::c_uint) -> Result<u32> {
+    let ciface = CString::new(dev_name)?;
+    let ifindex = libc::if_nametoindex(ciface.as_ptr()) as i32;
+    if ifindex == 0 {
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+    let mut prog_id = 0;
+    let ret =
+        libbpf_sys::bpf_get_link_xdp_id(ifindex, &mut prog_id, flags & libbpf_sys::XDP_FLAGS_MODES);
+    if ret != 0 {
+        return Err(Error::IO(io::Error::from_raw_os_error(-ret)));
+    }
+    Ok(prog_id)
 }
 
 impl SocketFilter {
@@ -1116,22 +3127,7 @@ impl SocketFilter {
     /// ```
     pub fn attach_socket_filter(&mut self, interface: &str) -> Result<RawFd> {
         let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
-        unsafe {
-            let sfd = open_raw_sock(interface)?;
-            if libc::setsockopt(
-                sfd,
-                libc::SOL_SOCKET,
-                libc::SO_ATTACH_BPF,
-                &fd as *const _ as *const _,
-                mem::size_of_val(&fd) as u32,
-            ) < 0
-            {
-                libc::close(sfd);
-                Err(Error::IO(io::Error::last_os_error()))
-            } else {
-                Ok(sfd)
-            }
-        }
+        unsafe { open_filtered_sock(fd, interface).map_err(Error::IO) }
     }
 
     pub fn name(&self) -> String {
@@ -1139,6 +3135,41 @@ impl SocketFilter {
     }
 }
 
+/// Open a packet socket on `interface` that is filtered by the socket filter
+/// `program`
+///
+/// The raw `AF_PACKET` socket receives the packets of every protocol. It is
+/// bound to `interface`, the program is installed with `SO_ATTACH_BPF` and
+/// the socket is returned ready for `recv`. The socket is non-blocking and
+/// is owned by the caller, who closes it. An error of
+/// `io::ErrorKind::NotFound` is returned if the interface does not exist,
+/// and of `io::ErrorKind::InvalidInput` if `program` is not a loaded socket
+/// filter.
+///
+/// # Example
+/// ```no_run
+/// use redbpf::{attach_socket_filter, load::Loader};
+/// let loaded = Loader::load_file("file.elf").expect("error loading probe");
+/// for program in loaded.module.programs.iter() {
+///     let sock = attach_socket_filter(program, "eth0").expect("error attaching filter");
+///     println!("{}: socket {}", program.name(), sock);
+/// }
+/// ```
+pub fn attach_socket_filter(program: &Program, interface: &str) -> io::Result<RawFd> {
+    let fd = match program {
+        Program::SocketFilter(sf) => sf.common.fd.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "the program is not loaded")
+        })?,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{}' is not a socket filter program", program.name()),
+            ))
+        }
+    };
+    unsafe { open_filtered_sock(fd, interface) }
+}
+
 impl SkLookup {
     /// Attach the `sk_lookup` to the given network namespace.
     ///
@@ -1148,47 +3179,663 @@ impl SkLookup {
             return Err(Error::ProgramAlreadyLinked);
         }
 
-        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        unsafe {
+            let namespace = CString::new(namespace)?;
+            let nfd = libc::open(namespace.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC);
+            if nfd < 0 {
+                return Err(Error::IO(io::Error::last_os_error()));
+            }
+
+            let lfd = bpf_link_create(fd, nfd, BPF_SK_LOOKUP, ptr::null());
+            if lfd < 0 {
+                libc::close(nfd);
+                return Err(Error::IO(io::Error::last_os_error()));
+            }
+
+            self.link = Some((nfd, lfd));
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for SkLookup {
+    fn drop(&mut self) {
+        if let Some((nfd, lfd)) = self.link.take() {
+            unsafe {
+                libc::close(lfd);
+                libc::close(nfd);
+            }
+        }
+    }
+}
+
+impl SockOps {
+    /// Attach the `sockops` program to the cgroup at `cgroup_path`
+    ///
+    /// The path is a directory of cgroup v2 hierarchy, e.g.,
+    /// `/sys/fs/cgroup/unified/my-service`. The program is detached when
+    /// `SockOps` is dropped or [`detach_cgroup`](#method.detach_cgroup) is
+    /// called.
+    pub fn attach_cgroup(&mut self, cgroup_path: impl AsRef<Path>) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        CgroupAttachment::attach_to(
+            &mut self.cgroups,
+            fd,
+            cgroup_path.as_ref(),
+            libbpf_sys::BPF_CGROUP_SOCK_OPS,
+        )
+    }
+
+    /// Atomically replace `old` attached to the cgroup at `cgroup_path` with
+    /// this `sockops` program
+    ///
+    /// The hook runs either `old` or this program at any moment, so there is
+    /// no window in which connections of the cgroup are not handled. Once
+    /// replaced, the attachment is owned by this program and `old` is no
+    /// longer attached to the cgroup. `Error::ProgramNotAttached` is
+    /// returned if `old` is not attached to the cgroup at `cgroup_path`.
+    pub fn replace_cgroup(
+        &mut self,
+        cgroup_path: impl AsRef<Path>,
+        old: &mut SockOps,
+    ) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        CgroupAttachment::replace_in(
+            &mut old.cgroups,
+            &mut self.cgroups,
+            fd,
+            cgroup_path.as_ref(),
+            libbpf_sys::BPF_CGROUP_SOCK_OPS,
+        )
+    }
+
+    /// Detach the `sockops` program from the cgroup at `cgroup_path`
+    pub fn detach_cgroup(&mut self, cgroup_path: impl AsRef<Path>) -> Result<()> {
+        CgroupAttachment::detach_from(
+            &mut self.cgroups,
+            cgroup_path.as_ref(),
+            libbpf_sys::BPF_CGROUP_SOCK_OPS,
+        );
+        Ok(())
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl CgroupSkb {
+    /// Attach the `cgroup/skb` program to the cgroup at `cgroup_path`
+    ///
+    /// The path is a directory of cgroup v2 hierarchy. `attach_type` selects
+    /// whether the program is called for ingress or egress packets. The
+    /// program is detached when `CgroupSkb` is dropped or
+    /// [`detach_cgroup`](#method.detach_cgroup) is called.
+    pub fn attach_cgroup(
+        &mut self,
+        cgroup_path: impl AsRef<Path>,
+        attach_type: CgroupSkbAttachType,
+    ) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        CgroupAttachment::attach_to(
+            &mut self.cgroups,
+            fd,
+            cgroup_path.as_ref(),
+            attach_type.to_attach_type(),
+        )
+    }
+
+    /// Atomically replace `old` attached to the `attach_type` hook of the
+    /// cgroup at `cgroup_path` with this `cgroup/skb` program
+    ///
+    /// See [`SockOps::replace_cgroup`](struct.SockOps.html#method.replace_cgroup).
+    pub fn replace_cgroup(
+        &mut self,
+        cgroup_path: impl AsRef<Path>,
+        attach_type: CgroupSkbAttachType,
+        old: &mut CgroupSkb,
+    ) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        CgroupAttachment::replace_in(
+            &mut old.cgroups,
+            &mut self.cgroups,
+            fd,
+            cgroup_path.as_ref(),
+            attach_type.to_attach_type(),
+        )
+    }
+
+    /// Detach the `cgroup/skb` program from the `attach_type` hook of the
+    /// cgroup at `cgroup_path`
+    pub fn detach_cgroup(
+        &mut self,
+        cgroup_path: impl AsRef<Path>,
+        attach_type: CgroupSkbAttachType,
+    ) -> Result<()> {
+        CgroupAttachment::detach_from(
+            &mut self.cgroups,
+            cgroup_path.as_ref(),
+            attach_type.to_attach_type(),
+        );
+        Ok(())
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl CgroupSkbAttachType {
+    fn to_attach_type(self) -> libbpf_sys::bpf_attach_type {
+        match self {
+            CgroupSkbAttachType::Ingress => libbpf_sys::BPF_CGROUP_INET_INGRESS,
+            CgroupSkbAttachType::Egress => libbpf_sys::BPF_CGROUP_INET_EGRESS,
+        }
+    }
+}
+
+impl CgroupSockAttachType {
+    fn from_hook(hook: &str) -> Option<CgroupSockAttachType> {
+        use CgroupSockAttachType::*;
+
+        match hook {
+            "sock" | "sock_create" => Some(SockCreate),
+            "sock_release" => Some(SockRelease),
+            "post_bind4" => Some(PostBind4),
+            "post_bind6" => Some(PostBind6),
+            _ => None,
+        }
+    }
+
+    fn to_attach_type(self) -> libbpf_sys::bpf_attach_type {
+        use CgroupSockAttachType::*;
+
+        match self {
+            SockCreate => libbpf_sys::BPF_CGROUP_INET_SOCK_CREATE,
+            SockRelease => libbpf_sys::BPF_CGROUP_INET_SOCK_RELEASE,
+            PostBind4 => libbpf_sys::BPF_CGROUP_INET4_POST_BIND,
+            PostBind6 => libbpf_sys::BPF_CGROUP_INET6_POST_BIND,
+        }
+    }
+}
+
+impl CgroupSock {
+    /// Attach the `cgroup/sock` program to its hook of the cgroup at
+    /// `cgroup_path`
+    ///
+    /// The path is a directory of cgroup v2 hierarchy. The program is
+    /// detached when `CgroupSock` is dropped or
+    /// [`detach_cgroup`](#method.detach_cgroup) is called.
+    pub fn attach_cgroup(&mut self, cgroup_path: impl AsRef<Path>) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        CgroupAttachment::attach_to(
+            &mut self.cgroups,
+            fd,
+            cgroup_path.as_ref(),
+            self.attach_type.to_attach_type(),
+        )
+    }
+
+    /// Atomically replace `old` attached to the cgroup at `cgroup_path` with
+    /// this `cgroup/sock` program
+    ///
+    /// Both programs must be of the same hook. See
+    /// [`SockOps::replace_cgroup`](struct.SockOps.html#method.replace_cgroup).
+    pub fn replace_cgroup(
+        &mut self,
+        cgroup_path: impl AsRef<Path>,
+        old: &mut CgroupSock,
+    ) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        if old.attach_type != self.attach_type {
+            error!(
+                "can not replace program `{}' of hook {:?} with a program of hook {:?}",
+                old.common.name, old.attach_type, self.attach_type
+            );
+            return Err(Error::ProgramNotAttached);
+        }
+        CgroupAttachment::replace_in(
+            &mut old.cgroups,
+            &mut self.cgroups,
+            fd,
+            cgroup_path.as_ref(),
+            self.attach_type.to_attach_type(),
+        )
+    }
+
+    /// Detach the `cgroup/sock` program from the cgroup at `cgroup_path`
+    pub fn detach_cgroup(&mut self, cgroup_path: impl AsRef<Path>) -> Result<()> {
+        CgroupAttachment::detach_from(
+            &mut self.cgroups,
+            cgroup_path.as_ref(),
+            self.attach_type.to_attach_type(),
+        );
+        Ok(())
+    }
+
+    /// Return the hook the program is attached to
+    pub fn attach_type(&self) -> CgroupSockAttachType {
+        self.attach_type
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl SkReuseport {
+    /// Attach the `sk_reuseport` program to the `SO_REUSEPORT` group of
+    /// `socket`
+    ///
+    /// The program is attached to the group, not to `socket`, so it selects
+    /// sockets for the whole group. It stays attached while any socket of the
+    /// group is open, even after `SkReuseport` is dropped, until another
+    /// program is attached or [`detach_reuseport`](#method.detach_reuseport)
+    /// is called.
+    pub fn attach_reuseport(&mut self, socket: RawFd) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket,
+                libc::SOL_SOCKET,
+                SO_ATTACH_REUSEPORT_EBPF,
+                &fd as *const _ as *const _,
+                mem::size_of_val(&fd) as u32,
+            )
+        };
+        if ret < 0 {
+            error!(
+                "error on setsockopt SO_ATTACH_REUSEPORT_EBPF: {}",
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Detach the BPF program from the `SO_REUSEPORT` group of `socket`
+    ///
+    /// Sockets of the group are selected by hash again. This requires Linux
+    /// 5.8 or later.
+    pub fn detach_reuseport(&mut self, socket: RawFd) -> Result<()> {
+        let dummy: libc::c_int = 0;
+        let ret = unsafe {
+            libc::setsockopt(
+                socket,
+                libc::SOL_SOCKET,
+                SO_DETACH_REUSEPORT_BPF,
+                &dummy as *const _ as *const _,
+                mem::size_of_val(&dummy) as u32,
+            )
+        };
+        if ret < 0 {
+            error!(
+                "error on setsockopt SO_DETACH_REUSEPORT_BPF: {}",
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+}
+
+impl CgroupAttachment {
+    fn attach(
+        prog_fd: RawFd,
+        path: &Path,
+        attach_type: libbpf_sys::bpf_attach_type,
+    ) -> Result<CgroupAttachment> {
+        let cpath = CString::new(path.to_str().ok_or(Error::StringConversion)?)?;
+        unsafe {
+            let cgroup_fd = libc::open(
+                cpath.as_ptr(),
+                libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+            );
+            if cgroup_fd < 0 {
+                let err = io::Error::last_os_error();
+                error!("error opening cgroup {:?}: {}", path, err);
+                return Err(Error::IO(err));
+            }
+            if libbpf_sys::bpf_prog_attach(prog_fd, cgroup_fd, attach_type, 0) < 0 {
+                let err = io::Error::last_os_error();
+                error!("error attaching BPF program to cgroup {:?}: {}", path, err);
+                libc::close(cgroup_fd);
+                return Err(Error::IO(err));
+            }
+            Ok(CgroupAttachment {
+                path: path.to_path_buf(),
+                cgroup_fd,
+                prog_fd,
+                attach_type,
+            })
+        }
+    }
+
+    // Attach `prog_fd` to the cgroup at `path` and keep the attachment in
+    // `cgroups`
+    fn attach_to(
+        cgroups: &mut Vec<CgroupAttachment>,
+        prog_fd: RawFd,
+        path: &Path,
+        attach_type: libbpf_sys::bpf_attach_type,
+    ) -> Result<()> {
+        let attachment = CgroupAttachment::attach(prog_fd, path, attach_type)?;
+        cgroups.push(attachment);
+        Ok(())
+    }
+
+    // Move the attachment to the cgroup at `path` from `old` to `new` by
+    // replacing its program with `new_prog_fd`
+    fn replace_in(
+        old: &mut Vec<CgroupAttachment>,
+        new: &mut Vec<CgroupAttachment>,
+        new_prog_fd: RawFd,
+        path: &Path,
+        attach_type: libbpf_sys::bpf_attach_type,
+    ) -> Result<()> {
+        let idx = old
+            .iter()
+            .position(|cg| cg.path == path && cg.attach_type == attach_type)
+            .ok_or_else(|| {
+                error!("program is not attached to cgroup {:?}", path);
+                Error::ProgramNotAttached
+            })?;
+        let attachment = old[idx].replace(new_prog_fd)?;
+        old.remove(idx).forget();
+        new.push(attachment);
+        Ok(())
+    }
+
+    // Detach the program from the cgroup at `path` by dropping the attachment
+    fn detach_from(
+        cgroups: &mut Vec<CgroupAttachment>,
+        path: &Path,
+        attach_type: libbpf_sys::bpf_attach_type,
+    ) {
+        cgroups.retain(|cg| cg.path != path || cg.attach_type != attach_type);
+    }
+
+    // Attach `new_prog_fd` in place of the program of this attachment. The
+    // returned attachment shares the cgroup fd, so this attachment must be
+    // forgotten afterwards.
+    fn replace(&self, new_prog_fd: RawFd) -> Result<CgroupAttachment> {
+        let old_id = prog_info_by_fd(self.prog_fd)?.id;
+        let mut attach_flags = 0u32;
+        let mut prog_ids = [0u32; BPF_CGROUP_MAX_PROGS];
+        let mut prog_cnt = prog_ids.len() as u32;
         unsafe {
-            let namespace = CString::new(namespace)?;
-            let nfd = libc::open(namespace.as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC);
-            if nfd < 0 {
-                return Err(Error::IO(io::Error::last_os_error()));
+            if libbpf_sys::bpf_prog_query(
+                self.cgroup_fd,
+                self.attach_type,
+                0,
+                &mut attach_flags,
+                prog_ids.as_mut_ptr(),
+                &mut prog_cnt,
+            ) < 0
+            {
+                let err = io::Error::last_os_error();
+                error!("error querying programs of cgroup {:?}: {}", self.path, err);
+                return Err(Error::IO(err));
             }
+        }
+        // make sure that the program being replaced is still attached
+        if !prog_ids[..prog_cnt as usize].contains(&old_id) {
+            error!(
+                "program id {} is not attached to cgroup {:?}",
+                old_id, self.path
+            );
+            return Err(Error::ProgramNotAttached);
+        }
 
-            let lfd = bpf_link_create(fd, nfd, BPF_SK_LOOKUP, ptr::null());
-            if lfd < 0 {
-                libc::close(nfd);
-                return Err(Error::IO(io::Error::last_os_error()));
+        // Without BPF_F_ALLOW_MULTI, the cgroup has only one program and
+        // attaching with the same flags replaces it atomically. Otherwise the
+        // program to replace is specified by BPF_F_REPLACE.
+        let mut opts = unsafe { mem::zeroed::<libbpf_sys::bpf_prog_attach_opts>() };
+        opts.sz = mem::size_of_val(&opts) as _;
+        opts.flags = attach_flags;
+        if attach_flags & libbpf_sys::BPF_F_ALLOW_MULTI != 0 {
+            opts.flags |= libbpf_sys::BPF_F_REPLACE;
+            opts.replace_prog_fd = self.prog_fd;
+        }
+        unsafe {
+            if libbpf_sys::bpf_prog_attach_xattr(
+                new_prog_fd,
+                self.cgroup_fd,
+                self.attach_type,
+                &opts,
+            ) < 0
+            {
+                let err = io::Error::last_os_error();
+                error!(
+                    "error replacing BPF program of cgroup {:?}: {}",
+                    self.path, err
+                );
+                return Err(Error::IO(err));
             }
-
-            self.link = Some((nfd, lfd));
         }
+        Ok(CgroupAttachment {
+            path: self.path.clone(),
+            cgroup_fd: self.cgroup_fd,
+            prog_fd: new_prog_fd,
+            attach_type: self.attach_type,
+        })
+    }
 
-        Ok(())
+    // Drop the attachment without detaching the program or closing the
+    // cgroup fd
+    fn forget(mut self) {
+        self.cgroup_fd = -1;
     }
 }
 
-impl Drop for SkLookup {
+impl Drop for CgroupAttachment {
     fn drop(&mut self) {
-        if let Some((nfd, lfd)) = self.link.take() {
-            unsafe {
-                libc::close(lfd);
-                libc::close(nfd);
-            }
+        if self.cgroup_fd < 0 {
+            return;
+        }
+        unsafe {
+            let _ = libbpf_sys::bpf_prog_detach2(self.prog_fd, self.cgroup_fd, self.attach_type);
+            let _ = libc::close(self.cgroup_fd);
         }
     }
 }
 
 impl Module {
+    /// Parse the programs and maps of an ELF relocatable file in memory
+    ///
+    /// The sections are parsed in the same way as
+    /// [`Loader::load_file`](./load/struct.Loader.html#method.load_file)
+    /// does but the filesystem is not touched, so ELF files embedded with
+    /// `include_bytes!` or received over the network can be used as they
+    /// are. Programs are not loaded into the kernel yet.
     pub fn parse(bytes: &[u8]) -> Result<Module> {
         ModuleBuilder::parse(bytes)?.to_module()
     }
 
+    /// Parse the programs and maps of an ELF relocatable file in memory,
+    /// recognizing additional sections of programs
+    ///
+    /// Each key of `section_map` is the kind part of section names, e.g.,
+    /// `custom_xdp` of `custom_xdp/<name>`, and its sections are parsed as
+    /// programs of the mapped type before the built-in kinds are considered.
+    /// See
+    /// [`ModuleBuilder::parse_with_section_map`](struct.ModuleBuilder.html#method.parse_with_section_map).
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::{Module, ProgramType};
+    /// use std::collections::HashMap;
+    ///
+    /// let mut section_map = HashMap::new();
+    /// section_map.insert("custom_xdp".to_string(), ProgramType::XDP);
+    /// let module = Module::with_section_map(&std::fs::read("file.elf").unwrap(), &section_map)
+    ///     .expect("error parsing probe");
+    /// ```
+    pub fn with_section_map(
+        bytes: &[u8],
+        section_map: &RSHashMap<String, ProgramType>,
+    ) -> Result<Module> {
+        ModuleBuilder::parse_with_section_map(bytes, section_map)?.to_module()
+    }
+
+    /// Return the CO-RE relocations that could not be applied
+    ///
+    /// A failed relocation does not fail parsing the module. Its instruction
+    /// is poisoned instead, so loading a program that reaches it fails. The
+    /// failures tell which field or type could not be relocated in the
+    /// running kernel and why, e.g., to report them when a program fails to
+    /// load.
+    pub fn core_relocation_failures(&self) -> &[CoreRelocationFailure] {
+        &self.core_relocation_failures
+    }
+
+    /// Atomically replace the inner map of the map-in-map `outer_name`
+    ///
+    /// BPF programs can not be reloaded without a gap in which no program
+    /// runs, e.g., XDP programs drop or pass packets unfiltered. But a map
+    /// can be replaced atomically if programs look it up through an outer
+    /// `BPF_MAP_TYPE_ARRAY_OF_MAPS` map. This method stores `new` at index 0
+    /// of the outer map with a single update so programs see either the old
+    /// map or the new one, never a missing map. This is useful for resizing
+    /// a map or for replacing all rules of a filter at once.
+    ///
+    /// Only arrays of maps are accepted since the inner map is always stored
+    /// at index 0. Use [`OuterMap`](./struct.OuterMap.html) to set inner
+    /// maps of `BPF_MAP_TYPE_HASH_OF_MAPS` or other indices.
+    ///
+    /// The previous inner map set by this method is returned. Programs may
+    /// still be running with the previous map when this returns, so it
+    /// should be dropped only after they finish.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # static ELF: [u8; 128] = [0u8; 128];
+    /// use redbpf::{HashMap, Map, ModuleBuilder};
+    /// use libbpf_sys::{BPF_MAP_TYPE_ARRAY_OF_MAPS, BPF_MAP_TYPE_HASH};
+    ///
+    /// let rules = Map::create("rules", BPF_MAP_TYPE_HASH, 4, 4, 1024, 0).unwrap();
+    /// let outer = Map::create_map_in_map("rules_outer", BPF_MAP_TYPE_ARRAY_OF_MAPS, 1, &rules).unwrap();
+    /// let mut builder = ModuleBuilder::parse(&ELF).unwrap();
+    /// builder.replace_map("rules_outer", outer).unwrap();
+    /// let mut module = builder.to_module().unwrap();
+    /// module.swap_map("rules_outer", rules).unwrap();
+    ///
+    /// // later, grow the rule table without reloading programs
+    /// let bigger = Map::create("rules", BPF_MAP_TYPE_HASH, 4, 4, 65536, 0).unwrap();
+    /// HashMap::<u32, u32>::new(&bigger).unwrap().set(0x0100007f, 1);
+    /// let _old = module.swap_map("rules_outer", bigger).unwrap();
+    /// ```
+    pub fn swap_map(&mut self, outer_name: &str, new: Map) -> Result<Option<Map>> {
+        let outer = self.map(outer_name).ok_or_else(|| {
+            error!("map of which name is `{}' not found", outer_name);
+            Error::Map
+        })?;
+        if outer.config.type_ != libbpf_sys::BPF_MAP_TYPE_ARRAY_OF_MAPS {
+            error!("map `{}' is not an array of maps", outer_name);
+            return Err(Error::Map);
+        }
+        OuterMap::<u32>::new(outer)?.set_inner(&0, &new)?;
+        Ok(self.inner_maps.insert(outer_name.to_string(), new))
+    }
+
+    /// Return the inner map set by [`swap_map`](#method.swap_map)
+    pub fn inner_map(&self, outer_name: &str) -> Option<&Map> {
+        self.inner_maps.get(outer_name)
+    }
+
     pub fn map(&self, name: &str) -> Option<&Map> {
         self.maps.iter().find(|m| m.name == name)
     }
 
+    /// Return the maps of the global data sections, i.e., `.rodata`, `.data`
+    /// and `.bss`
+    ///
+    /// Each map is an array of a single element that holds the whole
+    /// section. The global variables of programs live in these maps, see
+    /// [`set_global_var`](#method.set_global_var).
+    pub fn global_data_maps(&self) -> impl Iterator<Item = &Map> {
+        self.maps.iter().filter(|m| m.section_data)
+    }
+
+    /// Set the global variable `name` of programs to `value`
+    ///
+    /// This configures programs without recompiling them, e.g., with a PID
+    /// or a port to filter. The offset of the variable in its section comes
+    /// from the symbol table of the ELF file and `T` must have the size of
+    /// the variable.
+    ///
+    /// Configuration usually lives in `.rodata`, i.e., `const volatile`
+    /// globals in C or statics read with `core::ptr::read_volatile` in Rust.
+    /// It can only be set until the `.rodata` map is frozen. Programs loaded
+    /// after that see the variables as constants, so the verifier prunes
+    /// the branches they disable. See [`Map::freeze`](struct.Map.html#method.freeze).
+    /// [`LoaderBuilder`](./load/struct.LoaderBuilder.html) freezes it right
+    /// before it loads programs.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// module
+    ///     .set_global_var("TARGET_PID", 1234u32)
+    ///     .expect("error setting TARGET_PID");
+    /// module.map(".rodata").unwrap().freeze().unwrap();
+    /// for program in module.programs.iter_mut() {
+    ///     program.load(module.version, module.license.clone()).unwrap();
+    /// }
+    /// ```
+    pub fn set_global_var<T>(&mut self, name: &str, value: T) -> Result<()> {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(&value as *const T as *const u8, mem::size_of::<T>())
+        };
+        self.set_global_bytes(name, bytes)
+    }
+
+    /// Return the value of the global variable `name` of programs
+    ///
+    /// Programs can change variables of `.data` and `.bss`, so this reads
+    /// their current values, e.g., counters or the state of programs.
+    pub fn global_var<T>(&self, name: &str) -> Result<T> {
+        let (map, offset) = self.global_var_location(name, mem::size_of::<T>())?;
+        let data = map.section_bytes()?;
+        Ok(unsafe { ptr::read_unaligned(data[offset..].as_ptr() as *const T) })
+    }
+
+    pub(crate) fn set_global_bytes(&self, name: &str, bytes: &[u8]) -> Result<()> {
+        let (map, offset) = self.global_var_location(name, bytes.len())?;
+        let mut data = map.section_bytes()?;
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        map.set_section_bytes(&data)
+    }
+
+    fn global_var_location(&self, name: &str, size: usize) -> Result<(&Map, usize)> {
+        let var = self.global_vars.get(name).ok_or_else(|| {
+            error!("global variable `{}' not found", name);
+            Error::SymbolNotFound(name.to_string())
+        })?;
+        if var.size != size {
+            error!(
+                "global variable `{}' is {} bytes but {} bytes in Rust",
+                name, var.size, size
+            );
+            return Err(Error::TypeMismatch(format!(
+                "global variable `{}' is {} bytes but {} bytes in Rust",
+                name, var.size, size
+            )));
+        }
+        let map = self.map(&var.section).ok_or_else(|| {
+            error!("map of section `{}' not found", var.section);
+            Error::Map
+        })?;
+        Ok((map, var.offset))
+    }
+
     pub fn map_mut(&mut self, name: &str) -> Option<&mut Map> {
         self.maps.iter_mut().find(|m| m.name == name)
     }
@@ -1301,6 +3948,26 @@ impl Module {
         self.trace_points_mut().find(|p| p.common.name == name)
     }
 
+    pub fn raw_trace_points(&self) -> impl Iterator<Item = &RawTracePoint> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            RawTracePoint(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn raw_trace_points_mut(&mut self) -> impl Iterator<Item = &mut RawTracePoint> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            RawTracePoint(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn raw_trace_point_mut(&mut self, name: &str) -> Option<&mut RawTracePoint> {
+        self.raw_trace_points_mut().find(|p| p.common.name == name)
+    }
+
     pub fn stream_parsers(&self) -> impl Iterator<Item = &StreamParser> {
         use Program::*;
         self.programs.iter().filter_map(|prog| match prog {
@@ -1361,6 +4028,106 @@ impl Module {
         self.sk_lookups_mut().find(|p| p.common.name == name)
     }
 
+    pub fn sockops(&self) -> impl Iterator<Item = &SockOps> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            SockOps(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn sockops_iter_mut(&mut self) -> impl Iterator<Item = &mut SockOps> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            SockOps(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn sockops_mut(&mut self, name: &str) -> Option<&mut SockOps> {
+        self.sockops_iter_mut().find(|p| p.common.name == name)
+    }
+
+    pub fn cgroup_skbs(&self) -> impl Iterator<Item = &CgroupSkb> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            CgroupSkb(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn cgroup_skbs_mut(&mut self) -> impl Iterator<Item = &mut CgroupSkb> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            CgroupSkb(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn cgroup_skb_mut(&mut self, name: &str) -> Option<&mut CgroupSkb> {
+        self.cgroup_skbs_mut().find(|p| p.common.name == name)
+    }
+
+    pub fn cgroup_socks(&self) -> impl Iterator<Item = &CgroupSock> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            CgroupSock(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn cgroup_socks_mut(&mut self) -> impl Iterator<Item = &mut CgroupSock> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            CgroupSock(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn cgroup_sock_mut(&mut self, name: &str) -> Option<&mut CgroupSock> {
+        self.cgroup_socks_mut().find(|p| p.common.name == name)
+    }
+
+    pub fn sk_reuseports(&self) -> impl Iterator<Item = &SkReuseport> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            SkReuseport(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn sk_reuseports_mut(&mut self) -> impl Iterator<Item = &mut SkReuseport> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            SkReuseport(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn sk_reuseport_mut(&mut self, name: &str) -> Option<&mut SkReuseport> {
+        self.sk_reuseports_mut().find(|p| p.common.name == name)
+    }
+
+    pub fn perf_events(&self) -> impl Iterator<Item = &PerfEvent> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            PerfEvent(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn perf_events_mut(&mut self) -> impl Iterator<Item = &mut PerfEvent> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            PerfEvent(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn perf_event_mut(&mut self, name: &str) -> Option<&mut PerfEvent> {
+        self.perf_events_mut().find(|p| p.common.name == name)
+    }
+
     pub fn task_iters(&self) -> impl Iterator<Item = &TaskIter> {
         use Program::*;
         self.programs.iter().filter_map(|prog| match prog {
@@ -1380,6 +4147,134 @@ impl Module {
     pub fn task_iter_mut(&mut self, name: &str) -> Option<&mut TaskIter> {
         self.task_iters_mut().find(|p| p.common.name == name)
     }
+
+    pub fn struct_ops(&self) -> impl Iterator<Item = &StructOps> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            StructOps(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn struct_ops_iter_mut(&mut self) -> impl Iterator<Item = &mut StructOps> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            StructOps(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn struct_ops_mut(&mut self, name: &str) -> Option<&mut StructOps> {
+        self.struct_ops_iter_mut().find(|p| p.common.name == name)
+    }
+
+    pub fn fentries(&self) -> impl Iterator<Item = &FEntry> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            FEntry(p) | FExit(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn fentries_mut(&mut self) -> impl Iterator<Item = &mut FEntry> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            FEntry(p) | FExit(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn fentry_mut(&mut self, name: &str) -> Option<&mut FEntry> {
+        self.fentries_mut().find(|p| p.common.name == name)
+    }
+
+    pub fn lsms(&self) -> impl Iterator<Item = &Lsm> {
+        use Program::*;
+        self.programs.iter().filter_map(|prog| match prog {
+            Lsm(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn lsms_mut(&mut self) -> impl Iterator<Item = &mut Lsm> {
+        use Program::*;
+        self.programs.iter_mut().filter_map(|prog| match prog {
+            Lsm(p) => Some(p),
+            _ => None,
+        })
+    }
+
+    pub fn lsm_mut(&mut self, name: &str) -> Option<&mut Lsm> {
+        self.lsms_mut().find(|p| p.common.name == name)
+    }
+
+    /// Load and attach the most preferred of the programs `candidates` that
+    /// the running kernel supports
+    ///
+    /// This is for modules that implement the same probe in several ways to
+    /// run on many kernel versions, e.g., an `fentry` program and a `kprobe`
+    /// program. `fentry` and `fexit` programs are preferred since they are
+    /// cheaper, then `kprobe` and `kretprobe` programs. Candidates of the
+    /// same kind are tried in the given order. Kprobes are attached to
+    /// `fn_name` while `fentry` and `fexit` programs are attached to the
+    /// function given by `#[fentry]` or `#[fexit]`.
+    ///
+    /// A candidate that fails to load or attach is skipped with a warning.
+    /// The name of the attached program is returned.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::Module;
+    /// let mut module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let chosen = module
+    ///     .attach_preferred(&["fentry_unlinkat", "kprobe_unlinkat"], "do_unlinkat")
+    ///     .expect("error on Module::attach_preferred");
+    /// println!("attached {}", chosen);
+    /// ```
+    pub fn attach_preferred(&mut self, candidates: &[&str], fn_name: &str) -> Result<String> {
+        let mut ranked = candidates
+            .iter()
+            .map(|name| match self.program(name) {
+                Some(Program::FEntry(_)) | Some(Program::FExit(_)) => Ok((0, *name)),
+                Some(Program::KProbe(_)) | Some(Program::KRetProbe(_)) => Ok((1, *name)),
+                Some(_) => {
+                    error!("program `{}' is neither fentry, fexit nor kprobe", name);
+                    Err(Error::BPF)
+                }
+                None => {
+                    error!("program `{}' not found", name);
+                    Err(Error::Section(name.to_string()))
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // stable sort keeps the given order of the candidates of a kind
+        ranked.sort_by_key(|(rank, _)| *rank);
+
+        let (version, license) = (self.version, self.license.clone());
+        for (_, name) in ranked {
+            let prog = self.program_mut(name).unwrap();
+            if prog.fd().is_none() {
+                if let Err(e) = prog.load(version, license.clone()) {
+                    warn!("can not load `{}': {:?}. try the next candidate", name, e);
+                    continue;
+                }
+            }
+            let result = match prog {
+                Program::FEntry(p) | Program::FExit(p) => p.attach(),
+                Program::KProbe(p) | Program::KRetProbe(p) => p.attach_kprobe(fn_name, 0),
+                _ => unreachable!(),
+            };
+            match result {
+                Ok(_) => {
+                    debug!("`{}' is attached among {:?}", name, candidates);
+                    return Ok(name.to_string());
+                }
+                Err(e) => warn!("can not attach `{}': {:?}. try the next candidate", name, e),
+            }
+        }
+        error!("none of {:?} can be attached", candidates);
+        Err(Error::BPF)
+    }
 }
 
 impl<'a> ModuleBuilder<'a> {
@@ -1393,6 +4288,71 @@ impl<'a> ModuleBuilder<'a> {
     /// let mut builder = ModuleBuilder::parse(probe_code()).expect("error on ModuleBuilder::parse");
     /// ```
     pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        Self::parse_with_map_sections(bytes, &[])
+    }
+
+    /// Parse binary data of ELF relocatable file, recognizing additional
+    /// sections of maps
+    ///
+    /// Maps are found in sections named `maps` or `maps/<name>`. ELF files
+    /// built by other toolchains may put maps in sections of other names,
+    /// e.g., `legacy_maps/<name>`. Each of `map_sections` is such a name,
+    /// without the `/<name>` part, and its sections are parsed as if they
+    /// were `maps` sections.
+    ///
+    /// Sections that look like map sections but are not recognized are
+    /// skipped with a warning.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # static ELF_BINARY: [u8; 128] = [0u8; 128];
+    /// # fn probe_code() -> &'static [u8] { &ELF_BINARY }
+    /// use redbpf::ModuleBuilder;
+    /// let mut builder = ModuleBuilder::parse_with_map_sections(probe_code(), &["legacy_maps"])
+    ///     .expect("error on ModuleBuilder::parse_with_map_sections");
+    /// ```
+    pub fn parse_with_map_sections(bytes: &'a [u8], map_sections: &[&str]) -> Result<Self> {
+        Self::parse_sections(bytes, map_sections, &RSHashMap::new())
+    }
+
+    /// Parse binary data of ELF relocatable file, recognizing additional
+    /// sections of programs
+    ///
+    /// Programs are found in sections named `<kind>/<name>`, e.g.,
+    /// `xdp/<name>`. ELF files built by other toolchains or from hand-written
+    /// assembly may put programs in sections of other kinds. Each key of
+    /// `section_map` is such a kind, and its sections are parsed as programs
+    /// of the mapped type. A section named just the kind, without the
+    /// `/<name>` part, is parsed as a program of that name.
+    ///
+    /// The kinds of `section_map` take precedence over the built-in ones, so
+    /// a built-in kind can be remapped. Sections of other kinds are parsed as
+    /// [`ModuleBuilder::parse`](#method.parse) does.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # static ELF_BINARY: [u8; 128] = [0u8; 128];
+    /// # fn probe_code() -> &'static [u8] { &ELF_BINARY }
+    /// use redbpf::{ModuleBuilder, ProgramType};
+    /// use std::collections::HashMap;
+    ///
+    /// let mut section_map = HashMap::new();
+    /// section_map.insert("custom_xdp".to_string(), ProgramType::XDP);
+    /// let mut builder = ModuleBuilder::parse_with_section_map(probe_code(), &section_map)
+    ///     .expect("error on ModuleBuilder::parse_with_section_map");
+    /// ```
+    pub fn parse_with_section_map(
+        bytes: &'a [u8],
+        section_map: &RSHashMap<String, ProgramType>,
+    ) -> Result<Self> {
+        Self::parse_sections(bytes, &[], section_map)
+    }
+
+    fn parse_sections(
+        bytes: &'a [u8],
+        map_sections: &[&str],
+        section_map: &RSHashMap<String, ProgramType>,
+    ) -> Result<Self> {
         let object = Elf::parse(bytes)?;
         let strtab = &object.strtab;
         let symtab = object.syms.to_vec();
@@ -1408,21 +4368,53 @@ impl<'a> ModuleBuilder<'a> {
         let mut license = String::new();
         let mut version = 0u32;
         // BTF is optional
-        let btf: Option<BTF> = BTF::parse_elf(&object, bytes)
-            .and_then(|mut btf| btf.load().map(|_| btf))
-            .or_else(|e| {
+        let btf = BTF::parse_elf(&object, bytes);
+        // CO-RE relocations are resolved with BTF of the ELF file even if
+        // the kernel can not load it
+        let core_relocations = match btf {
+            Ok(ref btf) => core_reloc::parse_btf_ext(&object, bytes, btf).unwrap_or_else(|e| {
+                warn!("Failed to parse CO-RE relocations, ignore them: {:?}", e);
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        // func_info is needed only by programs calling BPF global functions
+        let func_info = match btf {
+            Ok(ref btf) => core_reloc::parse_func_info(&object, bytes, btf).unwrap_or_else(|e| {
+                warn!("Failed to parse func_info but it is optional: {:?}", e);
+                RSHashMap::new()
+            }),
+            Err(_) => RSHashMap::new(),
+        };
+        let mut text = None;
+        let mut pin_files = RSHashMap::new();
+        let btf: Option<BTF> = match btf.and_then(|mut btf| btf.load().map(|_| btf)) {
+            Ok(btf) => Some(btf),
+            Err(_) => {
                 warn!("Failed to load BTF but BTF is optional. Ignore it");
-                Err(e)
-            })
-            .ok();
+                None
+            }
+        };
         let mut vmlinux_btf = None;
         for (shndx, shdr) in object.section_headers.iter().enumerate() {
             let (kind, name) = get_split_section_name(&object, &shdr, shndx)?;
+            let kind = kind.map(|kind| {
+                if map_sections.contains(&kind) {
+                    "maps"
+                } else {
+                    kind
+                }
+            });
 
             let section_type = shdr.sh_type;
             let content = data(&bytes, &shdr);
             match (section_type, kind, name) {
                 (hdr::SHT_REL, _, _) => add_relocation(&mut rels, shndx, &shdr, shdr_relocs),
+                (hdr::SHT_PROGBITS, Some(kind), name) if section_map.contains_key(kind) => {
+                    let name = name.unwrap_or(kind);
+                    let prog = Program::new(section_map[kind].section_kind(), name, content)?;
+                    programs.insert(shndx, prog);
+                }
                 (hdr::SHT_PROGBITS, Some("version"), _) => version = get_version(&content),
                 (hdr::SHT_PROGBITS, Some("license"), _) => {
                     license = zero::read_str(content).to_string()
@@ -1483,6 +4475,10 @@ impl<'a> ModuleBuilder<'a> {
                         symval_to_map_builders.insert(sym.st_value, map_builder);
                     }
                 }
+                (hdr::SHT_PROGBITS, Some("maps_pin"), Some(name)) => {
+                    let file = zero::read_str(content);
+                    pin_files.insert(name.to_string(), PathBuf::from(file));
+                }
                 (hdr::SHT_PROGBITS, Some(kind @ "kprobe"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "kretprobe"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "uprobe"), Some(name))
@@ -1491,14 +4487,42 @@ impl<'a> ModuleBuilder<'a> {
                 | (hdr::SHT_PROGBITS, Some(kind @ "socketfilter"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "streamparser"), Some(name))
                 | (hdr::SHT_PROGBITS, Some(kind @ "streamverdict"), Some(name))
-                | (hdr::SHT_PROGBITS, Some(kind @ "sk_lookup"), Some(name)) => {
+                | (hdr::SHT_PROGBITS, Some(kind @ "sk_lookup"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "sockops"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "sk_reuseport"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "tracepoint"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "raw_tracepoint"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "raw_tp"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "perf_event"), Some(name)) => {
                     let prog = Program::new(kind, name, &content)?;
                     programs.insert(shndx, prog);
                 }
-                (hdr::SHT_PROGBITS, Some(kind @ "task_iter"), Some(name)) => {
+                (hdr::SHT_PROGBITS, Some(section @ "cgroup"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(section @ "cgroup_skb"), Some(name)) => {
+                    if let Some((kind, name)) = cgroup_section(section, name) {
+                        let prog = Program::new(kind, &name, content)?;
+                        programs.insert(shndx, prog);
+                    } else {
+                        warn!("section `{}/{}' is not supported. skip it", section, name);
+                    }
+                }
+                (hdr::SHT_PROGBITS, Some(kind @ "fentry"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "fexit"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "lsm"), Some(name)) => {
+                    if vmlinux_btf.is_none() {
+                        vmlinux_btf = btf::parse_vmlinux_btf()
+                            .map_err(|e| warn!("fentry, fexit and lsm can not be loaded: {:?}", e))
+                            .ok();
+                    }
+                    let prog = Program::with_target(kind, name, content, vmlinux_btf.as_ref())?;
+                    programs.insert(shndx, prog);
+                }
+                (hdr::SHT_PROGBITS, Some(kind @ "task_iter"), Some(name))
+                | (hdr::SHT_PROGBITS, Some(kind @ "struct_ops"), Some(name)) => {
                     if vmlinux_btf.is_none() {
                         vmlinux_btf = Some(btf::parse_vmlinux_btf().map_err(|e| {
-                            // Raise an error because BPF iter programs can not run without BTF support.
+                            // Raise an error because BPF iter and struct_ops
+                            // programs can not run without BTF support.
                             error!("error on btf::parse_vmlinux_btf: {:?}", e);
                             e
                         })?);
@@ -1512,10 +4536,37 @@ impl<'a> ModuleBuilder<'a> {
                             })?;
                     programs.insert(shndx, prog);
                 }
+                (hdr::SHT_PROGBITS, Some(".text"), None) if !content.is_empty() => {
+                    let code = read_insns(content);
+                    text = Some((shndx, code));
+                }
+                (hdr::SHT_PROGBITS, Some(kind), _) if kind.to_lowercase().contains("map") => {
+                    warn!(
+                        "section `{}' looks like a map section but it is not recognized. skip it",
+                        get_section_name(&object, shdr)?
+                    );
+                }
                 _ => {}
             }
         }
 
+        // global variables live in the maps of `.rodata`, `.data` and `.bss`
+        let mut global_vars = RSHashMap::new();
+        for sym in symtab.iter().filter(|sym| sym.st_type() == sym::STT_OBJECT) {
+            if let (Some(MapBuilder::SectionData { name: section, .. }), Some(name)) =
+                (map_builders.get(&sym.st_shndx), strtab.get_at(sym.st_name))
+            {
+                global_vars.insert(
+                    name.to_string(),
+                    GlobalVar {
+                        section: section.clone(),
+                        offset: sym.st_value as usize,
+                        size: sym.st_size as usize,
+                    },
+                );
+            }
+        }
+
         Ok(ModuleBuilder {
             object,
             programs,
@@ -1525,6 +4576,11 @@ impl<'a> ModuleBuilder<'a> {
             license,
             version,
             btf,
+            core_relocations,
+            text,
+            func_info,
+            pin_files,
+            global_vars,
         })
     }
 
@@ -1542,21 +4598,101 @@ impl<'a> ModuleBuilder<'a> {
     /// ```
     pub fn to_module(mut self) -> Result<Module> {
         let symtab = self.object.syms.to_vec();
+        // Maps declared with `#[map(pinned = "...")]` reuse the pinned map
+        // if it exists, otherwise they are pinned once created
+        let pin_files = mem::take(&mut self.pin_files);
+        for (name, file) in pin_files.iter() {
+            if file.exists() {
+                self.replace_map(name, Map::from_pin_file(file)?)?;
+            }
+        }
+
         let mut maps = RSHashMap::new();
-        for (shndx, map_builder) in self.map_builders.into_iter() {
+        for (shndx, map_builder) in self.map_builders.drain() {
             let map = map_builder.to_map()?;
             maps.insert(shndx, map);
         }
 
         let mut symval_to_maps = RSHashMap::new();
-        for (symval, map_builder) in self.symval_to_map_builders.into_iter() {
+        for (symval, map_builder) in self.symval_to_map_builders.drain() {
             let map = map_builder.to_map()?;
             symval_to_maps.insert(symval, map);
         }
 
+        for map in maps.values_mut().chain(symval_to_maps.values_mut()) {
+            match pin_files.get(&map.name) {
+                Some(file) if map.pin_file.is_none() => map.pin(file)?,
+                _ => {}
+            }
+        }
+
+        // Link `.text` section, i.e., BPF global functions, to the end of
+        // the programs calling them. The relocations of `.text` are copied
+        // for each of the programs to be applied to the linked instructions.
+        let mut text_offsets = RSHashMap::new();
+        let mut rels = vec![];
+        let text = self.text.take();
+        let text_shndx = text.as_ref().map(|(shndx, _)| *shndx);
+        if let Some((text_shndx, text)) = text {
+            for rel in self.rels.iter() {
+                if !rel.is_subprog_call(&self.programs, &symtab, text_shndx) {
+                    continue;
+                }
+                let code = &mut self
+                    .programs
+                    .get_mut(&rel.target_sec_idx)
+                    .ok_or(Error::Reloc)?
+                    .data_mut()
+                    .code;
+                text_offsets.entry(rel.target_sec_idx).or_insert_with(|| {
+                    let text_off = code.len();
+                    code.extend_from_slice(&text);
+                    text_off
+                });
+            }
+            for (shndx, text_off) in text_offsets.iter() {
+                rels.extend(
+                    self.rels
+                        .iter()
+                        .filter(|rel| rel.target_sec_idx == text_shndx)
+                        .map(|rel| RelocationInfo {
+                            target_sec_idx: *shndx,
+                            offset: rel.offset + (*text_off * mem::size_of::<bpf_insn>()) as u64,
+                            sym_idx: rel.sym_idx,
+                        }),
+                );
+                self.set_func_info(*shndx, text_shndx, *text_off);
+            }
+        }
+
+        // BTF ids of kernel functions are loaded only if any program calls kfuncs
+        let mut kfunc_ids = None;
         // Rewrite programs with relocation data
-        for rel in self.rels.iter() {
+        for rel in self.rels.iter().chain(rels.iter()) {
             if self.programs.contains_key(&rel.target_sec_idx) {
+                if let (Some(text_off), Some(text_shndx)) =
+                    (text_offsets.get(&rel.target_sec_idx), text_shndx)
+                {
+                    if rel.is_subprog_call(&self.programs, &symtab, text_shndx) {
+                        rel.apply_subprog_call(&mut self.programs, &symtab, *text_off)?;
+                        continue;
+                    }
+                }
+                if rel.is_kfunc_call(&self.programs, &symtab) {
+                    let sym_name = self
+                        .object
+                        .strtab
+                        .get_at(symtab[rel.sym_idx].st_name)
+                        .ok_or(Error::Reloc)?;
+                    if kfunc_ids.is_none() {
+                        kfunc_ids = Some(btf::kernel_func_btf_ids().map_err(|e| {
+                            error!("kfuncs can not be resolved without vmlinux BTF: {:?}", e);
+                            e
+                        })?);
+                    }
+                    rel.apply_kfunc(&mut self.programs, sym_name, kfunc_ids.as_ref().unwrap())?;
+                    continue;
+                }
                 if let Err(_) = rel.apply(&mut self.programs, &maps, &symtab) {
                     // means that not normal case, we should rely on symbol value instead of section header index
                     rel.apply_with_symmap(&mut self.programs, &symval_to_maps, &symtab)
@@ -1568,6 +4704,20 @@ impl<'a> ModuleBuilder<'a> {
             }
         }
 
+        let mut core_relocation_failures = vec![];
+        if !self.core_relocations.is_empty() {
+            let vmlinux_btf = btf::parse_core_btf().map_err(|e| {
+                error!("error on btf::parse_core_btf: {:?}", e);
+                e
+            })?;
+            for section in self.core_relocations.iter() {
+                if let Some(prog) = self.programs.get_mut(&section.shndx) {
+                    let code = &mut prog.data_mut().code;
+                    core_relocation_failures.extend(core_reloc::apply(code, section, &vmlinux_btf));
+                }
+            }
+        }
+
         let programs = self.programs.drain().map(|(_, v)| v).collect();
         let mut maps: Vec<Map> = maps.drain().map(|(_, v)| v).collect();
         maps.extend(symval_to_maps.drain().map(|(_, v)| v));
@@ -1577,9 +4727,45 @@ impl<'a> ModuleBuilder<'a> {
             maps,
             license: self.license,
             version: self.version,
+            inner_maps: RSHashMap::new(),
+            core_relocation_failures,
+            global_vars: self.global_vars,
         })
     }
 
+    // Set func_info of the program in section `shndx` that `.text` is linked
+    // to at `text_off`, so that the verifier verifies BPF global functions
+    // independently of the program
+    fn set_func_info(&mut self, shndx: usize, text_shndx: usize, text_off: usize) {
+        let (btf_fd, prog_func_info) = match (
+            self.btf.as_ref().and_then(|btf| btf.fd()),
+            self.func_info.get(&shndx),
+        ) {
+            (Some(btf_fd), Some(prog_func_info)) => (btf_fd, prog_func_info),
+            _ => {
+                warn!("no func_info or BTF for section #{}. BPF global functions are verified as static functions", shndx);
+                return;
+            }
+        };
+        let mut func_info = prog_func_info.clone();
+        if let Some(text_func_info) = self.func_info.get(&text_shndx) {
+            func_info.extend(text_func_info.iter().map(|info| libbpf_sys::bpf_func_info {
+                insn_off: info.insn_off + text_off as u32,
+                type_id: info.type_id,
+            }));
+        }
+        let btf_fd = unsafe { libc::dup(btf_fd) };
+        if btf_fd < 0 {
+            warn!("error on dup: {}", io::Error::last_os_error());
+            return;
+        }
+        if let Some(prog) = self.programs.get_mut(&shndx) {
+            let data = prog.data_mut();
+            data.func_info = func_info;
+            data.btf_fd = Some(btf_fd);
+        }
+    }
+
     /// Replace a map whose name is `map_name` with a `new` [`Map`](struct.Map.html)
     ///
     /// This method can fail if there does not exist a map whose name is
@@ -1597,13 +4783,14 @@ impl<'a> ModuleBuilder<'a> {
     /// let mut module = builder.to_module().expect("error on ModuleBuilder::to_module");
     /// ```
     pub fn replace_map(&mut self, map_name: &str, new: Map) -> Result<&mut Self> {
-        for (_, map_builder) in self.map_builders.iter_mut() {
+        // maps of `maps/<name>` sections and of the `maps` section
+        let map_builders = self
+            .map_builders
+            .values_mut()
+            .chain(self.symval_to_map_builders.values_mut());
+        for map_builder in map_builders {
             match map_builder {
-                MapBuilder::Normal {
-                    name,
-                    def,
-                    btf_type_id: _,
-                } => {
+                MapBuilder::Normal { name, def, .. } => {
                     if name == map_name {
                         if !(def.type_ == new.config.type_
                             && def.key_size == new.config.key_size
@@ -1646,6 +4833,76 @@ impl<'a> ModuleBuilder<'a> {
         error!("map of which name is `{}' not found", map_name);
         Err(Error::Map)
     }
+
+    /// Load the program `name` as `prog_type` instead of the type derived
+    /// from its section name
+    ///
+    /// This is useful for a program whose code is valid for several program
+    /// types, e.g., a packet parser that works as both a socket filter and a
+    /// stream parser. `name` is the name of the program or its whole section
+    /// name, e.g., `socketfilter/parse`. The `prog_type` and
+    /// `expected_attach_type` passed to the kernel when the program is
+    /// loaded are derived from `prog_type`.
+    ///
+    /// This method fails if no program is named `name` or if the context
+    /// of `prog_type` differs from the context of the program, e.g., a
+    /// kprobe can be loaded as a uprobe but not as an XDP program.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # let arr = [0u8; 128];
+    /// # let bytes = &arr;
+    /// use redbpf::{ModuleBuilder, ProgramType};
+    /// let mut builder = ModuleBuilder::parse(bytes).expect("error on ModuleBuilder::parse");
+    /// builder.load_as("socketfilter/parse", ProgramType::StreamParser).expect("error on ModuleBuilder::load_as");
+    /// let mut module = builder.to_module().expect("error on ModuleBuilder::to_module");
+    /// ```
+    pub fn load_as(&mut self, name: &str, prog_type: ProgramType) -> Result<&mut Self> {
+        let prog_name = name.splitn(2, '/').last().unwrap();
+        let program = self
+            .programs
+            .values_mut()
+            .find(|prog| prog.name() == prog_name)
+            .ok_or_else(|| {
+                error!("program of which name is `{}' not found", name);
+                Error::Section(name.to_string())
+            })?;
+        if program.fd().is_some() {
+            return Err(Error::ProgramAlreadyLoaded);
+        }
+        if !same_context(program.to_prog_type(), prog_type.to_prog_type()) {
+            error!(
+                "program `{}' can not be loaded as {:?} since their contexts differ",
+                name, prog_type
+            );
+            return Err(Error::Section(name.to_string()));
+        }
+        let common = mem::replace(
+            program.data_mut(),
+            ProgramData {
+                name: String::new(),
+                code: Vec::new(),
+                fd: None,
+                license: String::new(),
+                func_info: Vec::new(),
+                btf_fd: None,
+            },
+        );
+        *program = Program::with_data(prog_type.section_kind(), common)?;
+        Ok(self)
+    }
+}
+
+// Whether programs of both types get the same context so that one can be
+// loaded as the other
+fn same_context(a: bpf_prog_type, b: bpf_prog_type) -> bool {
+    // they all get `struct __sk_buff`
+    let is_skb = |t| {
+        t == libbpf_sys::BPF_PROG_TYPE_SOCKET_FILTER
+            || t == libbpf_sys::BPF_PROG_TYPE_SK_SKB
+            || t == libbpf_sys::BPF_PROG_TYPE_CGROUP_SKB
+    };
+    a == b || (is_skb(a) && is_skb(b))
 }
 
 fn get_section_name<'o>(object: &'o Elf, shdr: &SectionHeader) -> Result<&'o str> {
@@ -1656,6 +4913,30 @@ fn get_section_name<'o>(object: &'o Elf, shdr: &SectionHeader) -> Result<&'o str
     Ok(name)
 }
 
+/// Return the program kind and the `<hook>/<program name>` of a cgroup
+/// program in the section `<section>/<name>`
+///
+/// The sections are `cgroup/skb/<program name>`, `cgroup/<hook>/<program
+/// name>` and libbpf's `cgroup_skb/ingress` and `cgroup_skb/egress`. Sections
+/// without a program name, e.g., `cgroup/sock`, are named after the section
+/// with `/` replaced by `_` since the kernel rejects `/` in program names.
+fn cgroup_section(section: &str, name: &str) -> Option<(&'static str, String)> {
+    let (kind, hook, prog_name) = match (section, name.split_once('/')) {
+        ("cgroup", Some(("skb", prog_name))) => ("cgroup_skb", "skb", prog_name.to_string()),
+        ("cgroup", Some((hook, prog_name))) => ("cgroup_sock", hook, prog_name.to_string()),
+        ("cgroup", None) if name == "skb" => ("cgroup_skb", name, format!("cgroup_{}", name)),
+        ("cgroup", None) => ("cgroup_sock", name, format!("cgroup_{}", name)),
+        ("cgroup_skb", None) if name == "ingress" || name == "egress" => {
+            ("cgroup_skb", name, format!("cgroup_skb_{}", name))
+        }
+        _ => return None,
+    };
+    if kind == "cgroup_sock" && CgroupSockAttachType::from_hook(hook).is_none() {
+        return None;
+    }
+    Some((kind, format!("{}/{}", hook, prog_name)))
+}
+
 #[inline]
 fn get_split_section_name<'o>(
     object: &'o Elf<'_>,
@@ -1701,6 +4982,94 @@ impl RelocationInfo {
         Ok(())
     }
 
+    /// Return true if the relocation is of a call to a BPF function of `.text`
+    fn is_subprog_call(
+        &self,
+        programs: &RSHashMap<usize, Program>,
+        symtab: &[Sym],
+        text_shndx: usize,
+    ) -> bool {
+        if symtab[self.sym_idx].st_shndx != text_shndx {
+            return false;
+        }
+        let insn_idx = (self.offset / std::mem::size_of::<bpf_insn>() as u64) as usize;
+        programs
+            .get(&self.target_sec_idx)
+            .and_then(|prog| prog.data().code.get(insn_idx))
+            .map(|insn| insn.code == BPF_JMP_CALL && insn.src_reg() == BPF_PSEUDO_CALL)
+            .unwrap_or(false)
+    }
+
+    /// Patch the call instruction to call the function of `.text` that is
+    /// linked at `text_off` of the program
+    ///
+    /// The callee is at `imm + 1` instructions from the symbol, the same as
+    /// libbpf. The immediate of the call is the offset of the callee from the
+    /// next instruction.
+    fn apply_subprog_call(
+        &self,
+        programs: &mut RSHashMap<usize, Program>,
+        symtab: &[Sym],
+        text_off: usize,
+    ) -> Result<()> {
+        let prog = programs.get_mut(&self.target_sec_idx).ok_or(Error::Reloc)?;
+        let sym = symtab[self.sym_idx];
+        let insn_idx = (self.offset / std::mem::size_of::<bpf_insn>() as u64) as usize;
+        let code = &mut prog.data_mut().code;
+        let callee = text_off as i64
+            + (sym.st_value / std::mem::size_of::<bpf_insn>() as u64) as i64
+            + code[insn_idx].imm as i64
+            + 1;
+        if callee < text_off as i64 || callee >= code.len() as i64 {
+            error!("call to BPF function is out of `.text' section");
+            return Err(Error::Reloc);
+        }
+        code[insn_idx].imm = (callee - insn_idx as i64 - 1) as i32;
+        Ok(())
+    }
+
+    /// Return true if the relocation is of a call to an undefined function
+    ///
+    /// Functions that are not defined in the ELF object are kfuncs, i.e.,
+    /// kernel functions that BPF programs can call.
+    fn is_kfunc_call(&self, programs: &RSHashMap<usize, Program>, symtab: &[Sym]) -> bool {
+        let sym = symtab[self.sym_idx];
+        if sym.st_shndx != SHN_UNDEF as usize {
+            return false;
+        }
+        let insn_idx = (self.offset / std::mem::size_of::<bpf_insn>() as u64) as usize;
+        programs
+            .get(&self.target_sec_idx)
+            .and_then(|prog| prog.data().code.get(insn_idx))
+            .map(|insn| insn.code == BPF_JMP_CALL)
+            .unwrap_or(false)
+    }
+
+    /// Patch the call instruction to call the kfunc `name`
+    ///
+    /// The immediate of a kfunc call is the BTF id of the function in vmlinux
+    /// BTF.
+    fn apply_kfunc(
+        &self,
+        programs: &mut RSHashMap<usize, Program>,
+        name: &str,
+        kfunc_ids: &RSHashMap<String, u32>,
+    ) -> Result<()> {
+        let prog = programs.get_mut(&self.target_sec_idx).ok_or(Error::Reloc)?;
+        let btf_id = *kfunc_ids.get(name).ok_or_else(|| {
+            error!("kfunc `{}' not found in vmlinux BTF", name);
+            Error::SymbolNotFound(name.to_string())
+        })?;
+        let insn_idx = (self.offset / std::mem::size_of::<bpf_insn>() as u64) as usize;
+        let code = &mut prog.data_mut().code;
+        code[insn_idx].set_src_reg(BPF_PSEUDO_KFUNC_CALL);
+        // kfuncs of vmlinux. `off` designates BTF of kernel modules
+        code[insn_idx].off = 0;
+        code[insn_idx].imm = btf_id as i32;
+        debug!("kfunc `{}' is resolved to BTF id {}", name, btf_id);
+        Ok(())
+    }
+
     #[inline]
     fn apply_with_symmap(
         &self,
@@ -1721,8 +5090,8 @@ impl RelocationInfo {
 
 impl Map {
     pub fn load(name: &str, code: &[u8]) -> Result<Map> {
-        let config: bpf_map_def = *unsafe { zero::read_unsafe(code) };
-        Map::with_map_def(name, config, None)
+        let config = unsafe { ptr::read_unaligned(code.as_ptr() as *const bpf_map_def) };
+        Map::with_map_def(name, config, None, None)
     }
 
     fn with_section_data(name: &str, data: &[u8], flags: u32) -> Result<Map> {
@@ -1736,6 +5105,7 @@ impl Map {
                 map_flags: flags,
             },
             None,
+            None,
         )?;
         map.section_data = true;
         // for BSS we don't need to copy the data, it's already 0-initialized
@@ -1755,11 +5125,182 @@ impl Map {
         Ok(map)
     }
 
+    /// Create a new BPF map from userspace
+    ///
+    /// The map is not bound to any BPF program. It can be passed to BPF
+    /// programs by
+    /// [`ModuleBuilder::replace_map`](struct.ModuleBuilder.html#method.replace_map)
+    /// or used as an inner map of map-in-map, see
+    /// [`Module::swap_map`](struct.Module.html#method.swap_map).
+    pub fn create(
+        name: &str,
+        map_type: u32,
+        key_size: u32,
+        value_size: u32,
+        max_entries: u32,
+        map_flags: u32,
+    ) -> Result<Map> {
+        Map::with_map_def(
+            name,
+            bpf_map_def {
+                type_: map_type,
+                key_size,
+                value_size,
+                max_entries,
+                map_flags,
+            },
+            None,
+            None,
+        )
+    }
+
+    /// Create a new map-in-map from userspace
+    ///
+    /// `map_type` should be `BPF_MAP_TYPE_ARRAY_OF_MAPS` or
+    /// `BPF_MAP_TYPE_HASH_OF_MAPS`. `inner` is used as the template of the
+    /// inner maps. The kernel only accepts inner maps whose definitions
+    /// match `inner`.
+    pub fn create_map_in_map(
+        name: &str,
+        map_type: u32,
+        max_entries: u32,
+        inner: &Map,
+    ) -> Result<Map> {
+        if map_type != libbpf_sys::BPF_MAP_TYPE_ARRAY_OF_MAPS
+            && map_type != libbpf_sys::BPF_MAP_TYPE_HASH_OF_MAPS
+        {
+            error!("map type {} is not map-in-map", map_type);
+            return Err(Error::Map);
+        }
+        Map::with_map_def(
+            name,
+            bpf_map_def {
+                type_: map_type,
+                key_size: mem::size_of::<u32>() as u32,
+                value_size: mem::size_of::<u32>() as u32,
+                max_entries,
+                map_flags: 0,
+            },
+            None,
+            Some(inner.fd),
+        )
+    }
+
+    /// Create a new program array map from userspace
+    ///
+    /// The keys and values are `u32` indices and program fds, as required
+    /// by [`ProgramArray`](./struct.ProgramArray.html).
+    pub fn create_program_array(name: &str, max_entries: u32) -> Result<Map> {
+        Map::create(
+            name,
+            libbpf_sys::BPF_MAP_TYPE_PROG_ARRAY,
+            mem::size_of::<u32>() as u32,
+            mem::size_of::<RawFd>() as u32,
+            max_entries,
+            0,
+        )
+    }
+
+    /// Create a new Bloom filter map from userspace
+    ///
+    /// Bloom filter maps have no keys. `value_size` is the size of values
+    /// added to the filter and `max_entries` is the expected number of
+    /// values. `nr_hashes` is the number of hash functions, from 1 to 15. If
+    /// it is 0, the kernel uses 5 hash functions. Bloom filter maps require
+    /// Linux 5.16 or later.
+    pub fn create_bloom_filter(
+        name: &str,
+        value_size: u32,
+        max_entries: u32,
+        nr_hashes: u32,
+    ) -> Result<Map> {
+        if nr_hashes > BLOOM_FILTER_MAX_NR_HASHES {
+            error!(
+                "nr_hashes of bloom filter should be at most {}",
+                BLOOM_FILTER_MAX_NR_HASHES
+            );
+            return Err(Error::Map);
+        }
+        Map::with_map_extra(
+            name,
+            bpf_map_def {
+                type_: BPF_MAP_TYPE_BLOOM_FILTER,
+                key_size: 0,
+                value_size,
+                max_entries,
+                map_flags: 0,
+            },
+            nr_hashes as u64,
+        )
+    }
+
+    fn with_map_extra(name: &str, config: bpf_map_def, map_extra: u64) -> Result<Map> {
+        let mut attr = MapCreateAttr::new(name, &config);
+        attr.map_extra = map_extra;
+        Map::with_create_attr(name, config, &attr)
+    }
+
+    // Create a `struct_ops` map of which value is the vmlinux BTF type
+    // `btf_vmlinux_value_type_id`
+    fn create_struct_ops(
+        name: &str,
+        value_size: u32,
+        btf_vmlinux_value_type_id: u32,
+    ) -> Result<Map> {
+        let config = bpf_map_def {
+            type_: libbpf_sys::BPF_MAP_TYPE_STRUCT_OPS,
+            key_size: mem::size_of::<u32>() as u32,
+            value_size,
+            max_entries: 1,
+            map_flags: 0,
+        };
+        let mut attr = MapCreateAttr::new(name, &config);
+        attr.btf_vmlinux_value_type_id = btf_vmlinux_value_type_id;
+        Map::with_create_attr(name, config, &attr)
+    }
+
+    // Create a map by calling `BPF_MAP_CREATE` directly since
+    // `bpf_create_map_xattr` of libbpf-sys does not support all attributes
+    fn with_create_attr(name: &str, config: bpf_map_def, attr: &MapCreateAttr) -> Result<Map> {
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_bpf,
+                libbpf_sys::BPF_MAP_CREATE,
+                attr as *const _,
+                mem::size_of_val(attr) as u32,
+            )
+        };
+        if fd < 0 {
+            error!(
+                "error on BPF_MAP_CREATE. failed to load map `{}`: {}",
+                name,
+                io::Error::last_os_error()
+            );
+            return Err(Error::Map);
+        }
+        Ok(Map {
+            name: name.to_string(),
+            kind: config.type_,
+            fd: fd as RawFd,
+            config,
+            section_data: false,
+            pin_file: None,
+        })
+    }
+
     fn with_map_def(
         name: &str,
         config: bpf_map_def,
         btf_type_id: Option<MapBtfTypeId>,
+        inner_map_fd: Option<RawFd>,
     ) -> Result<Map> {
+        if let Some(map_type) = map_type::MapType::from_u32(config.type_) {
+            let flags = map_type::MapFlags::from_bits(config.map_flags);
+            if let Err(msg) = map_type.check_flags(flags) {
+                error!("illegal flags of map `{}`: {}", name, msg);
+                return Err(Error::Map);
+            }
+        }
         let cname = CString::new(name)?;
         let attr = unsafe {
             let mut attr_uninit = MaybeUninit::<bpf_create_map_attr>::zeroed();
@@ -1770,6 +5311,9 @@ impl Map {
             (*attr_ptr).key_size = config.key_size;
             (*attr_ptr).value_size = config.value_size;
             (*attr_ptr).max_entries = config.max_entries;
+            if let Some(inner_map_fd) = inner_map_fd {
+                (*attr_ptr).__bindgen_anon_1.inner_map_fd = inner_map_fd as u32;
+            }
             if let Some(type_id) = btf_type_id {
                 (*attr_ptr).btf_fd = type_id.btf_fd as u32;
                 (*attr_ptr).btf_key_type_id = type_id.key_type_id;
@@ -1817,8 +5361,33 @@ impl Map {
         }
     }
 
+    /// Return the decoded type of this map
+    ///
+    /// `None` is returned if the map type is unknown to redbpf.
+    pub fn map_type(&self) -> Option<map_type::MapType> {
+        map_type::MapType::from_u32(self.kind)
+    }
+
+    /// Return the flags this map was created with
+    pub fn flags(&self) -> map_type::MapFlags {
+        map_type::MapFlags::from_bits(self.config.map_flags)
+    }
+
+    /// Return true if all elements of this map were allocated on creation
+    ///
+    /// See [`MapType::is_preallocated`](map_type/enum.MapType.html#method.is_preallocated).
+    pub fn is_preallocated(&self) -> bool {
+        self.map_type()
+            .map(|map_type| map_type.is_preallocated(self.flags()))
+            .unwrap_or(true)
+    }
+
     /// Create `Map` from a file which represents pinned map
     ///
+    /// The type, key/value sizes and flags of the map are read back from the
+    /// kernel, so typed wrappers such as `HashMap` check them against their
+    /// key and value types as for maps created by a module.
+    ///
     /// # Example
     /// ```no_run
     /// use redbpf::{Array, Map};
@@ -1841,33 +5410,149 @@ impl Map {
             if libbpf_sys::bpf_obj_get_info_by_fd(fd, &mut info as *mut _ as *mut _, &mut info_len)
                 != 0
             {
+                let e = io::Error::last_os_error();
+                error!("error on bpf_obj_get_info_by_fd: {}", e);
+                let _ = libc::close(fd);
+                return Err(Error::IO(e));
+            }
+            info
+        };
+
+        let name = unsafe {
+            CStr::from_ptr(&map_info.name as *const _)
+                .to_string_lossy()
+                .into_owned()
+        };
+        Ok(Map {
+            name,
+            kind: map_info.type_,
+            fd,
+            config: bpf_map_def {
+                type_: map_info.type_,
+                key_size: map_info.key_size,
+                value_size: map_info.value_size,
+                max_entries: map_info.max_entries,
+                map_flags: map_info.map_flags,
+            },
+            section_data: false,
+            pin_file: Some(Box::from(file)),
+        })
+    }
+
+    /// Return the information of the map
+    ///
+    /// See the [`info`](info/index.html) module.
+    pub fn info(&self) -> Result<MapInfo> {
+        Ok(MapInfo::from(&map_info_by_fd(self.fd)?))
+    }
+
+    /// Return the number of entries in the map
+    ///
+    /// The kernel does not keep the count, so it walks all keys with
+    /// `BPF_MAP_GET_NEXT_KEY`. It is not exact if BPF programs add or delete
+    /// entries meanwhile. Arrays always have `max_entries` entries, and maps
+    /// without keys, e.g., queues, have none.
+    pub fn entry_count(&self) -> usize {
+        let mut key = vec![0u8; self.config.key_size as usize];
+        let mut next_key = key.clone();
+        let mut count = 0;
+        let mut prev_key = ptr::null();
+        while unsafe {
+            libbpf_sys::bpf_map_get_next_key(self.fd, prev_key, next_key.as_mut_ptr() as *mut _)
+        } == 0
+        {
+            count += 1;
+            mem::swap(&mut key, &mut next_key);
+            prev_key = key.as_ptr() as *const _;
+        }
+        count
+    }
+
+    /// Return a [`TypedMap`](./struct.TypedMap.html) of the map after
+    /// checking the sizes of `K` and `V`
+    ///
+    /// The sizes are checked against the key and value types of the BTF of
+    /// the map. If the map has no BTF, e.g., it is defined without BTF or
+    /// created by [`create`](#method.create), they are checked against the
+    /// key and value sizes of the map instead. `Error::TypeMismatch` is
+    /// returned if a size differs.
+    ///
+    /// `Error::Map` is returned for per-CPU maps, whose values hold an element
+    /// for each CPU, and for perf event arrays, ring buffers, queues and stacks
+    /// whose elements can not be looked up by key.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::load::Loader;
+    ///
+    /// #[derive(Clone)]
+    /// #[repr(C)]
+    /// struct Connection {
+    ///     pid: u32,
+    ///     bytes: u64,
+    /// }
+    ///
+    /// let loaded = Loader::load_file("file.elf").expect("error loading probe");
+    /// let conns = loaded
+    ///     .map("conns")
+    ///     .expect("map not found")
+    ///     .open_typed::<u32, Connection>()
+    ///     .expect("Connection does not match the BPF program");
+    /// let conn = conns.get(&1);
+    /// ```
+    pub fn open_typed<K: Clone, V: Clone>(&self) -> Result<TypedMap<'_, K, V>> {
+        if matches!(
+            self.config.type_,
+            BPF_MAP_TYPE_PERCPU_HASH
+                | BPF_MAP_TYPE_PERCPU_ARRAY
+                | BPF_MAP_TYPE_LRU_PERCPU_HASH
+                | libbpf_sys::BPF_MAP_TYPE_PERCPU_CGROUP_STORAGE
+                | BPF_MAP_TYPE_PERF_EVENT_ARRAY
+                | libbpf_sys::BPF_MAP_TYPE_RINGBUF
+                | libbpf_sys::BPF_MAP_TYPE_QUEUE
+                | libbpf_sys::BPF_MAP_TYPE_STACK
+        ) {
+            error!(
+                "map `{}' of type {} can not be opened as a typed map",
+                self.name, self.config.type_
+            );
+            return Err(Error::Map);
+        }
+        let (btf_key_size, btf_value_size) = btf::map_type_sizes(self.fd)?;
+        for (what, btf_size, map_size, rust_size) in [
+            (
+                "key",
+                btf_key_size,
+                self.config.key_size,
+                mem::size_of::<K>(),
+            ),
+            (
+                "value",
+                btf_value_size,
+                self.config.value_size,
+                mem::size_of::<V>(),
+            ),
+        ] {
+            let (size, source) = match btf_size {
+                Some(size) => (size, "BTF"),
+                None => (map_size, "the map definition"),
+            };
+            if size as usize != rust_size {
                 error!(
-                    "error on bpf_obj_get_info_by_fd: {}",
-                    io::Error::last_os_error()
+                    "{} of map `{}' is {} bytes in {} but {} bytes in Rust",
+                    what, self.name, size, source, rust_size
                 );
-                return Err(Error::IO(io::Error::last_os_error()));
+                return Err(Error::TypeMismatch(format!(
+                    "{} of map `{}' is {} bytes but {} bytes in Rust",
+                    what, self.name, size, rust_size
+                )));
             }
-            info
-        };
+        }
 
-        let name = unsafe {
-            CStr::from_ptr(&map_info.name as *const _)
-                .to_string_lossy()
-                .into_owned()
-        };
-        Ok(Map {
-            name,
-            kind: map_info.type_,
-            fd,
-            config: bpf_map_def {
-                type_: map_info.type_,
-                key_size: map_info.key_size,
-                value_size: map_info.value_size,
-                max_entries: map_info.max_entries,
-                map_flags: map_info.map_flags,
-            },
-            section_data: false,
-            pin_file: Some(Box::from(file)),
+        Ok(TypedMap {
+            base: self,
+            _k: PhantomData,
+            _v: PhantomData,
         })
     }
 
@@ -1912,6 +5597,57 @@ impl Map {
         self.pin_file = None;
         Ok(())
     }
+
+    /// Freeze the map so that userspace can not update it any more
+    ///
+    /// BPF programs can still update the map unless it is created with
+    /// `BPF_F_RDONLY_PROG`, e.g., the map of `.rodata`. Programs loaded
+    /// after such a map is frozen see its contents as constants.
+    pub fn freeze(&self) -> Result<()> {
+        if unsafe { libbpf_sys::bpf_map_freeze(self.fd) } < 0 {
+            let e = io::Error::last_os_error();
+            error!("error on bpf_map_freeze of `{}': {}", self.name, e);
+            return Err(Error::IO(e));
+        }
+        Ok(())
+    }
+
+    // The contents of a global data section, i.e., the value at index 0 of
+    // its map
+    fn section_bytes(&self) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; self.config.value_size as usize];
+        let ret = unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                self.fd,
+                &0u32 as *const u32 as *const _,
+                data.as_mut_ptr() as *mut _,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            error!("error on bpf_map_lookup_elem of `{}': {}", self.name, e);
+            return Err(Error::IO(e));
+        }
+        Ok(data)
+    }
+
+    fn set_section_bytes(&self, data: &[u8]) -> Result<()> {
+        let ret = unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.fd,
+                &0u32 as *const u32 as *const _,
+                data.as_ptr() as *const _,
+                BPF_ANY.into(),
+            )
+        };
+        if ret < 0 {
+            // EPERM once the map is frozen
+            let e = io::Error::last_os_error();
+            error!("error on bpf_map_update_elem of `{}': {}", self.name, e);
+            return Err(Error::IO(e));
+        }
+        Ok(())
+    }
 }
 
 impl Drop for Map {
@@ -1925,10 +5661,21 @@ impl Drop for Map {
 impl<'a> MapBuilder<'a> {
     fn parse(name: &str, bytes: &[u8]) -> Result<Self> {
         let def = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const bpf_map_def) };
+        // `redbpf_probes::maps::BloomFilter` stores the number of hash
+        // functions right after the map definition
+        let def_size = mem::size_of::<bpf_map_def>();
+        let map_extra = if def.type_ == BPF_MAP_TYPE_BLOOM_FILTER
+            && bytes.len() >= def_size + mem::size_of::<u32>()
+        {
+            unsafe { ptr::read_unaligned(bytes[def_size..].as_ptr() as *const u32) as u64 }
+        } else {
+            0
+        };
         Ok(MapBuilder::Normal {
             def,
             name: name.to_string(),
             btf_type_id: None,
+            map_extra,
         })
     }
 
@@ -1962,11 +5709,18 @@ impl<'a> MapBuilder<'a> {
 
     fn to_map(self) -> Result<Map> {
         match self {
+            MapBuilder::Normal {
+                name,
+                def,
+                map_extra,
+                ..
+            } if map_extra != 0 => Map::with_map_extra(name.as_ref(), def, map_extra),
             MapBuilder::Normal {
                 name,
                 def,
                 btf_type_id,
-            } => Map::with_map_def(name.as_ref(), def, btf_type_id),
+                ..
+            } => Map::with_map_def(name.as_ref(), def, btf_type_id, None),
             MapBuilder::SectionData { name, bytes } => Map::with_section_data(
                 name.as_ref(),
                 bytes,
@@ -2010,16 +5764,123 @@ impl<'base, K: Clone, V: Clone> HashMap<'base, K, V> {
         bpf_map_get(self.base.fd, key)
     }
 
+    /// Get the value of `key` after checking `V` against the BTF of the map
+    ///
+    /// `Error::BTF` is returned if the size of `V` differs from the size of
+    /// the value type of BTF, e.g., because the structure changed in the
+    /// BPF program but not in userspace, or if the map has no BTF. BTF is
+    /// read from the kernel on every call, so use `get` in hot paths after
+    /// checking once.
+    pub fn get_checked(&self, key: K) -> Result<Option<V>> {
+        btf::check_map_value_layout(self.base.fd, mem::size_of::<V>(), None)?;
+        Ok(self.get(key))
+    }
+
+    /// Get the value of `key` after checking the fields of `V` against the
+    /// BTF of the map
+    ///
+    /// In addition to the size checked by `get_checked`, every field of `V`
+    /// must be at the same offset with the same size as the field of the
+    /// same name of the value type of BTF.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::{btf_layout, HashMap, Module};
+    ///
+    /// #[repr(C)]
+    /// #[derive(Clone)]
+    /// struct Connection {
+    ///     pid: u32,
+    ///     bytes: u64,
+    /// }
+    /// btf_layout!(Connection { pid, bytes });
+    ///
+    /// # let module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let conns = HashMap::<u32, Connection>::new(module.map("conns").unwrap()).unwrap();
+    /// let conn = conns.get_checked_layout(1).expect("layout of Connection drifted");
+    /// ```
+    pub fn get_checked_layout(&self, key: K) -> Result<Option<V>>
+    where
+        V: btf::BtfLayout,
+    {
+        let fields = V::btf_fields();
+        btf::check_map_value_layout(self.base.fd, mem::size_of::<V>(), Some(&fields))?;
+        Ok(self.get(key))
+    }
+
     pub fn delete(&self, key: K) {
         let _ = bpf_map_delete(self.base.fd, key);
     }
 
     /// Return an iterator over all items in the map
+    ///
+    /// Items are visited in the order of `BPF_MAP_GET_NEXT_KEY`. The key
+    /// after an item is got before the item is yielded, so the yielded item
+    /// can be deleted while iterating, e.g., to drain the map. The map can
+    /// also be modified by BPF programs while it is iterated: items deleted
+    /// before they are looked up are skipped, but if an item is deleted
+    /// right after its key is got, the kernel restarts the iteration from
+    /// the first key so some items can be yielded twice.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::{HashMap, Module};
+    /// # let module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let flows = HashMap::<u64, u64>::new(module.map("flows").unwrap()).unwrap();
+    /// for (flow, bytes) in flows.iter() {
+    ///     println!("{:x}: {}", flow, bytes);
+    /// }
+    /// ```
     pub fn iter<'a>(&'a self) -> MapIter<'a, K, V> {
-        MapIter {
-            iterable: self,
-            last_key: None,
-        }
+        MapIter::new(self)
+    }
+
+    /// Look up `count` items in a batch, starting from `cursor`
+    ///
+    /// The items are read with the `BPF_MAP_LOOKUP_BATCH` command, which is
+    /// much faster than calling `get` for each key. Pass the returned cursor
+    /// to the next call until it reaches the end. Kernels older than 5.6 do
+    /// not support batched operations, so the items are read key by key
+    /// instead.
+    ///
+    /// Hash maps are read a bucket at a time, so more than `count` items are
+    /// returned if a bucket has more items than `count`.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::{BatchCursor, HashMap, Module};
+    /// # let module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let flows = HashMap::<u64, u64>::new(module.map("flows").unwrap()).unwrap();
+    /// let mut cursor = BatchCursor::default();
+    /// while !cursor.is_end() {
+    ///     let (items, next) = flows.lookup_batch(cursor, 1024).unwrap();
+    ///     for (flow, bytes) in items {
+    ///         println!("{:x}: {}", flow, bytes);
+    ///     }
+    ///     cursor = next;
+    /// }
+    /// ```
+    pub fn lookup_batch(&self, cursor: BatchCursor<K>, count: usize) -> Batch<K, V> {
+        bpf_map_lookup_batch(self.base.fd, cursor, count, false)
+    }
+
+    /// Look up and delete at most `count` items in a batch
+    ///
+    /// Same as `lookup_batch` except that the items read are deleted from
+    /// the map, using the `BPF_MAP_LOOKUP_AND_DELETE_BATCH` command.
+    pub fn lookup_and_delete_batch(&self, cursor: BatchCursor<K>, count: usize) -> Batch<K, V> {
+        bpf_map_lookup_batch(self.base.fd, cursor, count, true)
+    }
+
+    /// Insert or update `items` in a batch
+    ///
+    /// The items are written with the `BPF_MAP_UPDATE_BATCH` command, or key
+    /// by key if the kernel does not support it. Either way, the items are
+    /// written in order and an error stops at the item that failed, leaving
+    /// the items before it written. How many were written is logged with the
+    /// error.
+    pub fn update_batch(&self, items: &[(K, V)]) -> Result<()> {
+        bpf_map_update_batch(self.base.fd, items)
     }
 }
 
@@ -2052,6 +5913,15 @@ impl<'base, K: Clone, V: Clone> LruHashMap<'base, K, V> {
         })
     }
 
+    /// Return true if all CPUs share one LRU list
+    ///
+    /// It returns false if the map is created with `BPF_F_NO_COMMON_LRU`
+    /// flag. Then entries inserted on one CPU are evicted only when that CPU
+    /// runs out of its share of the map.
+    pub fn has_common_lru(&self) -> bool {
+        self.base.config.map_flags & libbpf_sys::BPF_F_NO_COMMON_LRU == 0
+    }
+
     pub fn set(&self, key: K, value: V) {
         let _ = bpf_map_set(self.base.fd, key, value);
     }
@@ -2066,10 +5936,28 @@ impl<'base, K: Clone, V: Clone> LruHashMap<'base, K, V> {
 
     /// Return an iterator over all items in the map
     pub fn iter<'a>(&'a self) -> MapIter<'a, K, V> {
-        MapIter {
-            iterable: self,
-            last_key: None,
-        }
+        MapIter::new(self)
+    }
+
+    /// Look up at most `count` items in a batch, starting from `cursor`
+    ///
+    /// See [`HashMap::lookup_batch`](struct.HashMap.html#method.lookup_batch).
+    pub fn lookup_batch(&self, cursor: BatchCursor<K>, count: usize) -> Batch<K, V> {
+        bpf_map_lookup_batch(self.base.fd, cursor, count, false)
+    }
+
+    /// Look up and delete at most `count` items in a batch
+    ///
+    /// See [`HashMap::lookup_and_delete_batch`](struct.HashMap.html#method.lookup_and_delete_batch).
+    pub fn lookup_and_delete_batch(&self, cursor: BatchCursor<K>, count: usize) -> Batch<K, V> {
+        bpf_map_lookup_batch(self.base.fd, cursor, count, true)
+    }
+
+    /// Insert or update `items` in a batch
+    ///
+    /// See [`HashMap::update_batch`](struct.HashMap.html#method.update_batch).
+    pub fn update_batch(&self, items: &[(K, V)]) -> Result<()> {
+        bpf_map_update_batch(self.base.fd, items)
     }
 }
 
@@ -2128,10 +6016,23 @@ impl<'base, K: Clone, V: Clone> PerCpuHashMap<'base, K, V> {
 
     /// Return an iterator over all items in the map
     pub fn iter<'a>(&'a self) -> MapIter<'a, K, PerCpuValues<V>> {
-        MapIter {
-            iterable: self,
-            last_key: None,
-        }
+        MapIter::new(self)
+    }
+}
+
+impl<'base, K: Clone, V: Clone + Default + Add<Output = V>> PerCpuHashMap<'base, K, V> {
+    /// Read the per-cpu values corresponding to the `key`, sum them up and
+    /// reset them
+    ///
+    /// On Linux 5.14 or later the entry is looked up and deleted atomically,
+    /// so no increment made by BPF programs is lost. The next update by a
+    /// BPF program creates the entry again. On older kernels the values are
+    /// read and then overwritten with `V::default()`, which is best-effort
+    /// since increments made between reading and writing are lost.
+    ///
+    /// `Ok(None)` is returned if `key` is not found.
+    pub fn read_and_reset(&self, key: K) -> Result<Option<V>> {
+        bpf_percpu_map_read_and_reset(self.base.fd, key)
     }
 }
 
@@ -2190,10 +6091,23 @@ impl<'base, K: Clone, V: Clone> LruPerCpuHashMap<'base, K, V> {
 
     /// Return an iterator over all items in the map
     pub fn iter<'a>(&'a self) -> MapIter<'a, K, PerCpuValues<V>> {
-        MapIter {
-            iterable: self,
-            last_key: None,
-        }
+        MapIter::new(self)
+    }
+}
+
+impl<'base, K: Clone, V: Clone + Default + Add<Output = V>> LruPerCpuHashMap<'base, K, V> {
+    /// Read the per-cpu values corresponding to the `key`, sum them up and
+    /// reset them
+    ///
+    /// On Linux 5.14 or later the entry is looked up and deleted atomically,
+    /// so no increment made by BPF programs is lost. The next update by a
+    /// BPF program creates the entry again. On older kernels the values are
+    /// read and then overwritten with `V::default()`, which is best-effort
+    /// since increments made between reading and writing are lost.
+    ///
+    /// `Ok(None)` is returned if `key` is not found.
+    pub fn read_and_reset(&self, key: K) -> Result<Option<V>> {
+        bpf_percpu_map_read_and_reset(self.base.fd, key)
     }
 }
 
@@ -2306,6 +6220,27 @@ impl<T: Clone> PerCpuValues<T> {
     }
 }
 
+impl<T: Clone + Default + Add<Output = T>> PerCpuValues<T> {
+    /// Return the sum of the values of all CPUs
+    ///
+    /// This is how per-cpu counters incremented by BPF programs are
+    /// aggregated.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use redbpf::{Module, PerCpuArray};
+    /// # let module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let counters = PerCpuArray::<u64>::new(module.map("counters").unwrap()).unwrap();
+    /// let total = counters.get(0).unwrap().sum();
+    /// ```
+    pub fn sum(&self) -> T {
+        self.0
+            .iter()
+            .cloned()
+            .fold(T::default(), |acc, value| acc + value)
+    }
+}
+
 impl<T: Clone> From<Box<[T]>> for PerCpuValues<T> {
     fn from(values: Box<[T]>) -> Self {
         Self(values)
@@ -2424,12 +6359,242 @@ impl<'base, T: Clone> PerCpuArray<'base, T> {
             }
         }
 
-        Some(values.into())
+        Some(values.into())
+    }
+
+    /// Get length of array map
+    pub fn len(&self) -> usize {
+        self.base.config.max_entries as usize
+    }
+}
+
+impl<'base, T: Clone + Default + Add<Output = T>> PerCpuArray<'base, T> {
+    /// Read the per-cpu values at `index`, sum them up and reset them to
+    /// `T::default()`
+    ///
+    /// This is useful for scraping per-cpu counters periodically. Resetting
+    /// is best-effort: elements of array maps can not be deleted, so the
+    /// zeroed values are written back after reading. Increments made by BPF
+    /// programs between reading and writing back are lost.
+    ///
+    /// # Example
+    /// ```no_run
+    /// # use redbpf::{Module, PerCpuArray};
+    /// # let module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+    /// let counters = PerCpuArray::<u64>::new(module.map("counters").unwrap()).unwrap();
+    /// let total = counters.read_and_reset(0).unwrap();
+    /// ```
+    pub fn read_and_reset(&self, index: u32) -> Result<T> {
+        let values = self.get(index).ok_or_else(|| {
+            error!("error reading index {} of per-cpu array", index);
+            Error::Map
+        })?;
+        self.set(index, &PerCpuValues::new(T::default()))?;
+        Ok(values.sum())
+    }
+}
+
+impl<'base, V: Clone> BloomFilter<'base, V> {
+    pub fn new(base: &Map) -> Result<BloomFilter<V>> {
+        if mem::size_of::<V>() != base.config.value_size as usize
+            || BPF_MAP_TYPE_BLOOM_FILTER != base.config.type_
+        {
+            error!(
+                "map definitions (size of value, map type) of base `Map' and `BloomFilter' do not match"
+            );
+            return Err(Error::Map);
+        }
+
+        Ok(BloomFilter {
+            base,
+            _v: PhantomData,
+        })
+    }
+
+    /// Add `value` to the Bloom filter
+    pub fn add(&self, mut value: V) -> Result<()> {
+        if unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.base.fd,
+                ptr::null(),
+                &mut value as *mut _ as *mut _,
+                BPF_ANY.into(),
+            )
+        } < 0
+        {
+            error!(
+                "error adding value to bloom filter: {}",
+                io::Error::last_os_error()
+            );
+            Err(Error::Map)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return `true` if `value` may be in the Bloom filter
+    ///
+    /// `false` is returned only if `value` has definitely not been added.
+    pub fn contains(&self, mut value: V) -> bool {
+        unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                self.base.fd,
+                ptr::null(),
+                &mut value as *mut _ as *mut _,
+            ) == 0
+        }
+    }
+}
+
+impl<'base, V: Clone> Queue<'base, V> {
+    pub fn new(base: &Map) -> Result<Queue<V>> {
+        if mem::size_of::<V>() != base.config.value_size as usize
+            || libbpf_sys::BPF_MAP_TYPE_QUEUE != base.config.type_
+        {
+            error!(
+                "map definitions (size of value, map type) of base `Map' and `Queue' do not match"
+            );
+            return Err(Error::Map);
+        }
+
+        Ok(Queue {
+            base,
+            _v: PhantomData,
+        })
+    }
+
+    /// Push `value` to the back of the queue
+    ///
+    /// `Err(Error::IO)` of `E2BIG` is returned if the queue is full.
+    pub fn push(&self, value: &V) -> Result<()> {
+        bpf_map_push(self.base.fd, value)
+    }
+
+    /// Pop the value at the front of the queue
+    ///
+    /// `None` is returned if the queue is empty.
+    pub fn pop(&self) -> Option<V> {
+        bpf_map_pop(self.base.fd)
+    }
+}
+
+impl<'base, V: Clone> Stack<'base, V> {
+    pub fn new(base: &Map) -> Result<Stack<V>> {
+        if mem::size_of::<V>() != base.config.value_size as usize
+            || libbpf_sys::BPF_MAP_TYPE_STACK != base.config.type_
+        {
+            error!(
+                "map definitions (size of value, map type) of base `Map' and `Stack' do not match"
+            );
+            return Err(Error::Map);
+        }
+
+        Ok(Stack {
+            base,
+            _v: PhantomData,
+        })
+    }
+
+    /// Push `value` to the top of the stack
+    ///
+    /// `Err(Error::IO)` of `E2BIG` is returned if the stack is full.
+    pub fn push(&self, value: &V) -> Result<()> {
+        bpf_map_push(self.base.fd, value)
+    }
+
+    /// Pop the value at the top of the stack
+    ///
+    /// `None` is returned if the stack is empty.
+    pub fn pop(&self) -> Option<V> {
+        bpf_map_pop(self.base.fd)
+    }
+}
+
+impl<'base, K: Clone> OuterMap<'base, K> {
+    pub fn new(base: &Map) -> Result<OuterMap<K>> {
+        if mem::size_of::<K>() != base.config.key_size as usize
+            || (libbpf_sys::BPF_MAP_TYPE_ARRAY_OF_MAPS != base.config.type_
+                && libbpf_sys::BPF_MAP_TYPE_HASH_OF_MAPS != base.config.type_)
+        {
+            error!(
+                "map definitions (size of key, map type) of base `Map' and `OuterMap' do not match"
+            );
+            return Err(Error::Map);
+        }
+
+        Ok(OuterMap {
+            base,
+            _k: PhantomData,
+        })
+    }
+
+    /// Set `inner` at `key` of the outer map
+    ///
+    /// The previous inner map at `key` is replaced atomically. The kernel
+    /// rejects `inner` with `Err(Error::IO)` of `EINVAL` if its definition
+    /// does not match the template inner map of the outer map.
+    pub fn set_inner(&self, key: &K, inner: &Map) -> Result<()> {
+        let mut key = key.clone();
+        let mut inner_fd = inner.fd;
+        if unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.base.fd,
+                &mut key as *mut _ as *mut _,
+                &mut inner_fd as *mut _ as *mut _,
+                BPF_ANY.into(),
+            )
+        } < 0
+        {
+            let e = io::Error::last_os_error();
+            error!(
+                "error setting inner map `{}' of `{}': {}",
+                inner.name, self.base.name, e
+            );
+            return Err(Error::IO(e));
+        }
+        Ok(())
+    }
+
+    /// Return the id of the inner map at `key`
+    ///
+    /// Looking up an outer map from userspace returns the id of the inner
+    /// map instead of its fd. `None` is returned if no map is set at `key`.
+    pub fn get_inner_id(&self, key: &K) -> Option<u32> {
+        let mut key = key.clone();
+        let mut id = 0u32;
+        if unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                self.base.fd,
+                &mut key as *mut _ as *mut _,
+                &mut id as *mut _ as *mut _,
+            )
+        } < 0
+        {
+            return None;
+        }
+        Some(id)
+    }
+
+    /// Delete the inner map at `key` of the outer map
+    pub fn delete(&self, key: &K) {
+        let _ = bpf_map_delete(self.base.fd, key.clone());
+    }
+}
+
+impl<'base, K: Clone, V: Clone> TypedMap<'base, K, V> {
+    /// Return the value of `key`
+    pub fn get(&self, key: &K) -> Option<V> {
+        bpf_map_get(self.base.fd, key.clone())
     }
 
-    /// Get length of array map
-    pub fn len(&self) -> usize {
-        self.base.config.max_entries as usize
+    /// Set the value of `key`
+    pub fn set(&self, key: &K, value: &V) -> Result<()> {
+        bpf_map_set(self.base.fd, key.clone(), value.clone())
+    }
+
+    /// Delete `key`
+    pub fn delete(&self, key: &K) -> Result<()> {
+        bpf_map_delete(self.base.fd, key.clone())
     }
 }
 
@@ -2497,23 +6662,126 @@ impl<'base> ProgramArray<'base> {
 
         Ok(())
     }
+
+    /// Install `program` at the `index` entry
+    ///
+    /// `Error::ProgramNotLoaded` is returned if `program` is not loaded yet.
+    /// The kernel rejects programs of a type other than the type of the
+    /// programs that tail call through the array.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::{load::Loader, ProgramArray};
+    /// let loaded = Loader::load_file("pipeline.elf").expect("error loading probe");
+    /// let programs = ProgramArray::new(loaded.map("stages").unwrap()).unwrap();
+    /// programs
+    ///     .set_program(0, loaded.program("parse_ip").unwrap())
+    ///     .unwrap();
+    /// ```
+    pub fn set_program(&self, mut index: u32, program: &Program) -> Result<()> {
+        let mut fd = program.fd().ok_or_else(|| {
+            error!(
+                "program `{}' is not loaded to install in `{}'",
+                program.name(),
+                self.base.name
+            );
+            Error::ProgramNotLoaded
+        })?;
+        if unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.base.fd,
+                &mut index as *mut _ as *mut _,
+                &mut fd as *mut _ as *mut _,
+                BPF_ANY.into(),
+            )
+        } < 0
+        {
+            error!(
+                "error installing program `{}' at {} of `{}': {}",
+                program.name(),
+                index,
+                self.base.name,
+                io::Error::last_os_error()
+            );
+            return Err(Error::Map);
+        }
+        Ok(())
+    }
+
+    /// Remove the program at the `index` entry
+    pub fn delete(&self, index: u32) -> Result<()> {
+        bpf_map_delete(self.base.fd, index)
+    }
 }
 
 pub struct MapIter<'a, K: Clone, V: Clone> {
     iterable: &'a dyn MapIterable<K, V>,
-    last_key: Option<K>,
+    next_key: Option<K>,
+}
+
+impl<'a, K: Clone, V: Clone> MapIter<'a, K, V> {
+    fn new(iterable: &'a dyn MapIterable<K, V>) -> MapIter<'a, K, V> {
+        MapIter {
+            iterable,
+            next_key: iterable.next_key(None),
+        }
+    }
 }
 
 impl<K: Clone, V: Clone> Iterator for MapIter<'_, K, V> {
     type Item = (K, V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let key = self.last_key.take();
-        self.last_key = self.iterable.next_key(key);
-        Some((
-            self.last_key.as_ref()?.clone(),
-            self.iterable.get(self.last_key.as_ref()?.clone())?,
-        ))
+        loop {
+            let key = self.next_key.take()?;
+            // The next key is got while `key` still exists, since
+            // BPF_MAP_GET_NEXT_KEY of a deleted key restarts from the first
+            // key of hash maps. So the yielded item can be deleted.
+            self.next_key = self.iterable.next_key(Some(key.clone()));
+            // The entry can be deleted by BPF programs after its key is got.
+            // Skip it instead of ending the iteration
+            if let Some(value) = self.iterable.get(key.clone()) {
+                return Some((key, value));
+            }
+        }
+    }
+}
+
+/// The position that batched operations of a map continue from
+///
+/// Start from `BatchCursor::default()` and pass the cursor returned by each
+/// batched operation to the next one until `is_end` returns `true`.
+#[derive(Clone, Debug)]
+pub struct BatchCursor<K> {
+    state: BatchState<K>,
+}
+
+#[derive(Clone, Debug)]
+enum BatchState<K> {
+    Start,
+    // the opaque position returned by the kernel
+    Batch(Vec<u8>),
+    // the last key visited by the per-key fallback
+    Key(K),
+    End,
+}
+
+/// The items read by a batched operation of a map and the cursor to pass to
+/// the next one
+pub type Batch<K, V> = Result<(Vec<(K, V)>, BatchCursor<K>)>;
+
+impl<K> Default for BatchCursor<K> {
+    fn default() -> Self {
+        BatchCursor {
+            state: BatchState::Start,
+        }
+    }
+}
+
+impl<K> BatchCursor<K> {
+    /// Return `true` if all items of the map have been visited
+    pub fn is_end(&self) -> bool {
+        matches!(self.state, BatchState::End)
     }
 }
 
@@ -2640,6 +6908,67 @@ impl<'a> SockMap<'a> {
     }
 }
 
+impl<'a> ReuseportSockArray<'a> {
+    pub fn new(map: &'a Map) -> Result<ReuseportSockArray<'a>> {
+        if map.config.type_ != libbpf_sys::BPF_MAP_TYPE_REUSEPORT_SOCKARRAY {
+            error!("map type of base `Map' is not BPF_MAP_TYPE_REUSEPORT_SOCKARRAY");
+            return Err(Error::Map);
+        }
+        Ok(ReuseportSockArray { base: map })
+    }
+
+    /// Put `socket` in the map at `idx`
+    pub fn set(&mut self, mut idx: u32, socket: RawFd) -> Result<()> {
+        let mut fd = socket as u64;
+        let ret = unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.base.fd,
+                &mut idx as *mut _ as *mut _,
+                &mut fd as *mut _ as *mut _,
+                BPF_ANY.into(),
+            )
+        };
+        if ret < 0 {
+            error!(
+                "error on updating reuseport sockarray: {:?}",
+                io::Error::last_os_error()
+            );
+            Err(Error::Map)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return the socket cookie of the socket at `idx`
+    ///
+    /// The value size of the map must be 8 bytes to read socket cookies.
+    pub fn get_cookie(&self, mut idx: u32) -> Option<u64> {
+        let mut cookie = 0u64;
+        let ret = unsafe {
+            libbpf_sys::bpf_map_lookup_elem(
+                self.base.fd,
+                &mut idx as *mut _ as *mut _,
+                &mut cookie as *mut _ as *mut _,
+            )
+        };
+        if ret < 0 {
+            None
+        } else {
+            Some(cookie)
+        }
+    }
+
+    pub fn delete(&mut self, mut idx: u32) -> Result<()> {
+        let ret =
+            unsafe { libbpf_sys::bpf_map_delete_elem(self.base.fd, &mut idx as *mut _ as *mut _) };
+        if ret < 0 {
+            Err(Error::Map)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 /// A structure for reading data from BPF iterators
 ///
 /// The data read by this structure is written by BPF iterators from the kernel
@@ -2709,22 +7038,365 @@ impl TaskIter {
             self.create_link()?;
         }
 
-        let iter_fd = unsafe { bpf_iter_create(self.link_fd.clone().unwrap()) };
-        if iter_fd < 0 {
-            error!("Error on bpf_iter_create");
-            return Err(Error::BPF);
+        let iter_fd = unsafe { bpf_iter_create(self.link_fd.clone().unwrap()) };
+        if iter_fd < 0 {
+            error!("Error on bpf_iter_create");
+            return Err(Error::BPF);
+        }
+
+        Ok(BPFIter::from(iter_fd)?)
+    }
+}
+
+impl Drop for TaskIter {
+    fn drop(&mut self) {
+        if let Some(link_fd) = self.link_fd {
+            unsafe {
+                let _ = libc::close(link_fd);
+            }
+        }
+    }
+}
+
+impl StructOps {
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+
+    /// Return the name of the kernel structure the program implements a
+    /// member of, e.g., `tcp_congestion_ops`
+    pub fn struct_name(&self) -> &str {
+        &self.struct_name
+    }
+
+    /// Return the name of the member implemented by the program
+    pub fn member(&self) -> &str {
+        &self.member
+    }
+}
+
+impl FEntry {
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+
+    /// Return the name of the kernel function the program is attached to
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    fn attach_btf_id(&self) -> Result<u32> {
+        self.attach_btf_id.ok_or_else(|| {
+            error!(
+                "function `{}' of `{}' not found in vmlinux BTF",
+                self.target, self.common.name
+            );
+            Error::BTF(format!("type id of {} not found", self.target))
+        })
+    }
+
+    /// Attach the program to its target function
+    ///
+    /// The program stays attached until it is dropped.
+    pub fn attach(&mut self) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        if self.link_fd.is_some() {
+            return Ok(());
+        }
+        let link_fd = unsafe { libbpf_sys::bpf_raw_tracepoint_open(ptr::null(), fd) };
+        if link_fd < 0 {
+            error!(
+                "error attaching `{}' to {}: {}",
+                self.common.name,
+                self.target,
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        self.link_fd = Some(link_fd);
+        Ok(())
+    }
+}
+
+impl Drop for FEntry {
+    fn drop(&mut self) {
+        if let Some(link_fd) = self.link_fd {
+            unsafe {
+                let _ = libc::close(link_fd);
+            }
+        }
+    }
+}
+
+impl Lsm {
+    pub fn name(&self) -> String {
+        self.common.name.to_string()
+    }
+
+    /// Return the name of the LSM hook the program is attached to
+    pub fn hook(&self) -> &str {
+        &self.hook
+    }
+
+    fn attach_btf_id(&self) -> Result<u32> {
+        self.attach_btf_id.ok_or_else(|| {
+            error!(
+                "LSM hook `{}' of `{}' not found in vmlinux BTF",
+                self.hook, self.common.name
+            );
+            Error::LsmUnsupported(format!(
+                "bpf_lsm_{} not found in vmlinux BTF. the kernel is built without CONFIG_BPF_LSM \
+                 or {} is not an LSM hook",
+                self.hook, self.hook
+            ))
+        })
+    }
+
+    /// Attach the program to its LSM hook
+    ///
+    /// This creates a BPF link and the program stays attached until it is
+    /// dropped. `Error::LsmUnsupported` is returned if the kernel is built
+    /// without `CONFIG_BPF_LSM` or `bpf` is not one of the active LSMs.
+    pub fn attach(&mut self) -> Result<()> {
+        let fd = self.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        if self.link_fd.is_some() {
+            return Ok(());
+        }
+        self.attach_btf_id()?;
+        check_bpf_lsm_active()?;
+        let link_fd = unsafe { libbpf_sys::bpf_raw_tracepoint_open(ptr::null(), fd) };
+        if link_fd < 0 {
+            error!(
+                "error attaching `{}' to LSM hook {}: {}",
+                self.common.name,
+                self.hook,
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        self.link_fd = Some(link_fd);
+        Ok(())
+    }
+}
+
+impl Drop for Lsm {
+    fn drop(&mut self) {
+        if let Some(link_fd) = self.link_fd {
+            unsafe {
+                let _ = libc::close(link_fd);
+            }
+        }
+    }
+}
+
+// The BPF LSM only runs the programs attached to it when it is one of the
+// active LSMs, which are listed in securityfs. Attaching succeeds even if it
+// is not, so check it beforehand.
+fn check_bpf_lsm_active() -> Result<()> {
+    const ACTIVE_LSMS: &str = "/sys/kernel/security/lsm";
+    let lsms = match fs::read_to_string(ACTIVE_LSMS) {
+        Ok(lsms) => lsms,
+        Err(e) => {
+            warn!(
+                "can not read {}: {}. assume bpf LSM is active",
+                ACTIVE_LSMS, e
+            );
+            return Ok(());
+        }
+    };
+    if lsms.trim().split(',').any(|lsm| lsm == "bpf") {
+        return Ok(());
+    }
+    error!("bpf is not one of the active LSMs: {}", lsms.trim());
+    Err(Error::LsmUnsupported(format!(
+        "bpf is not one of the active LSMs `{}'. add bpf to the lsm= boot parameter",
+        lsms.trim()
+    )))
+}
+
+/// A `struct_ops` map that registers a kernel structure implemented by BPF
+/// programs
+///
+/// The value of the map is a kernel structure, e.g., `tcp_congestion_ops`.
+/// Its function pointers are set to loaded
+/// [`StructOps`](struct.StructOps.html) programs and its other members are
+/// set to raw bytes. Updating the map with the value registers the
+/// structure with the kernel, e.g., as a TCP congestion control algorithm.
+/// The structure is unregistered when the map is dropped.
+///
+/// `struct_ops` maps require Linux 5.6 or later and vmlinux BTF.
+///
+/// # Example
+/// ```no_run
+/// # static CC: &[u8] = &[];
+/// use redbpf::load::Loader;
+/// use redbpf::StructOpsMap;
+///
+/// let loaded = Loader::load(CC).unwrap();
+/// let mut ops = StructOpsMap::new("tcp_congestion_ops").unwrap();
+/// for prog in loaded.struct_ops() {
+///     ops.set_program(prog).unwrap();
+/// }
+/// ops.set_data("name", b"rust_cc\0").unwrap();
+/// ops.register().unwrap();
+/// ```
+pub struct StructOpsMap {
+    map: Map,
+    struct_name: String,
+    // offset of the `data` member of `struct bpf_struct_ops_<struct_name>`
+    data_offset: u32,
+    members: Vec<StructMember>,
+    value: Vec<u8>,
+    registered: bool,
+}
+
+impl StructOpsMap {
+    /// Create a `struct_ops` map of the kernel structure `struct_name`
+    ///
+    /// This fails if the kernel does not support implementing
+    /// `struct_name` by BPF programs.
+    pub fn new(struct_name: &str) -> Result<StructOpsMap> {
+        let btf = btf::parse_vmlinux_btf()?;
+        // the kernel wraps the structure in `bpf_struct_ops_<struct_name>`
+        let value_name = format!("bpf_struct_ops_{}", struct_name);
+        let value_type_id = btf
+            .find_type_id(&value_name, BtfKind::Structure)
+            .ok_or_else(|| {
+                error!(
+                    "struct_ops of `{}' is not supported by the kernel",
+                    struct_name
+                );
+                Error::BTF(format!("type id of struct {} not found", value_name))
+            })?;
+        let value_size = btf
+            .size_of(value_type_id)
+            .ok_or_else(|| Error::BTF(format!("size of struct {} not found", value_name)))?;
+        let data_offset = btf
+            .struct_members(value_type_id)
+            .and_then(|members| members.into_iter().find(|memb| memb.name == "data"))
+            .map(|memb| memb.offset)
+            .ok_or_else(|| Error::BTF(format!("member data of struct {} not found", value_name)))?;
+        let struct_id = btf
+            .find_type_id(struct_name, BtfKind::Structure)
+            .ok_or_else(|| Error::BTF(format!("type id of struct {} not found", struct_name)))?;
+        let members = btf.struct_members(struct_id).unwrap_or_default();
+
+        let map = Map::create_struct_ops(struct_name, value_size, value_type_id)?;
+        Ok(StructOpsMap {
+            map,
+            struct_name: struct_name.to_string(),
+            data_offset,
+            members,
+            value: vec![0; value_size as usize],
+            registered: false,
+        })
+    }
+
+    /// Set the member implemented by the loaded `program` to the program
+    pub fn set_program(&mut self, program: &StructOps) -> Result<&mut Self> {
+        if program.struct_name != self.struct_name {
+            error!(
+                "program `{}' implements {} but not {}",
+                program.common.name, program.struct_name, self.struct_name
+            );
+            return Err(Error::BPF);
+        }
+        let fd = program.common.fd.ok_or(Error::ProgramNotLoaded)?;
+        let (offset, memb) = self.member(&program.member)?;
+        if !memb.is_func_ptr {
+            error!(
+                "{}::{} is not a function pointer",
+                self.struct_name, memb.name
+            );
+            return Err(Error::BPF);
+        }
+        // the kernel reads the fd of the program from the function pointer
+        self.value[offset..offset + mem::size_of::<u64>()]
+            .copy_from_slice(&(fd as u64).to_ne_bytes());
+        Ok(self)
+    }
+
+    /// Set the data member `name` to `bytes`, e.g., `name` of
+    /// `tcp_congestion_ops` to the NUL-terminated name of the algorithm
+    ///
+    /// The rest of the member is zeroed if `bytes` is shorter than it.
+    pub fn set_data(&mut self, name: &str, bytes: &[u8]) -> Result<&mut Self> {
+        let (offset, memb) = self.member(name)?;
+        if memb.is_func_ptr || bytes.len() > memb.size as usize {
+            error!(
+                "{}::{} is not a data member of {} bytes or more",
+                self.struct_name,
+                name,
+                bytes.len()
+            );
+            return Err(Error::BPF);
+        }
+        let end = offset + memb.size as usize;
+        self.value[offset..end].iter_mut().for_each(|b| *b = 0);
+        self.value[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(self)
+    }
+
+    // Return the offset of the member `name` in the value of the map
+    fn member(&self, name: &str) -> Result<(usize, &StructMember)> {
+        let memb = self
+            .members
+            .iter()
+            .find(|memb| memb.name == name)
+            .ok_or_else(|| {
+                error!("{} has no member {}", self.struct_name, name);
+                Error::BPF
+            })?;
+        Ok(((self.data_offset + memb.offset) as usize, memb))
+    }
+
+    /// Register the structure with the kernel
+    ///
+    /// The kernel checks that the members required by the structure are
+    /// set.
+    pub fn register(&mut self) -> Result<()> {
+        let key = 0u32;
+        if unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                self.map.fd,
+                &key as *const _ as *const _,
+                self.value.as_ptr() as *const _,
+                0,
+            )
+        } < 0
+        {
+            error!(
+                "error registering {}: {}",
+                self.struct_name,
+                io::Error::last_os_error()
+            );
+            return Err(Error::Map);
+        }
+        self.registered = true;
+        Ok(())
+    }
+
+    /// Unregister the structure registered by [`register`](#method.register)
+    pub fn unregister(&mut self) -> Result<()> {
+        let key = 0u32;
+        if unsafe { libbpf_sys::bpf_map_delete_elem(self.map.fd, &key as *const _ as *const _) } < 0
+        {
+            error!(
+                "error unregistering {}: {}",
+                self.struct_name,
+                io::Error::last_os_error()
+            );
+            return Err(Error::Map);
         }
-
-        Ok(BPFIter::from(iter_fd)?)
+        self.registered = false;
+        Ok(())
     }
 }
 
-impl Drop for TaskIter {
+impl Drop for StructOpsMap {
     fn drop(&mut self) {
-        if let Some(link_fd) = self.link_fd {
-            unsafe {
-                let _ = libc::close(link_fd);
-            }
+        if self.registered {
+            let _ = self.unregister();
         }
     }
 }
@@ -2745,12 +7417,22 @@ fn add_relocation(
     }));
 }
 
+// Copy the instructions of a section. Section data is not necessarily
+// aligned, e.g., of ELF files embedded with `include_bytes!`
+fn read_insns(bytes: &[u8]) -> Vec<bpf_insn> {
+    bytes
+        .chunks_exact(mem::size_of::<bpf_insn>())
+        .map(|insn| unsafe { ptr::read_unaligned(insn.as_ptr() as *const bpf_insn) })
+        .collect()
+}
+
 #[inline]
 fn get_version(bytes: &[u8]) -> u32 {
-    let version = zero::read::<u32>(bytes);
+    assert!(bytes.len() >= mem::size_of::<u32>());
+    let version = unsafe { ptr::read_unaligned(bytes.as_ptr() as *const u32) };
     match version {
         0xFFFF_FFFE => get_kernel_internal_version().unwrap(),
-        _ => *version,
+        _ => version,
     }
 }
 
@@ -2801,6 +7483,192 @@ fn bpf_map_delete<K: Clone>(fd: RawFd, mut key: K) -> Result<()> {
     }
 }
 
+fn batch_opts() -> libbpf_sys::bpf_map_batch_opts {
+    libbpf_sys::bpf_map_batch_opts {
+        sz: mem::size_of::<libbpf_sys::bpf_map_batch_opts>() as _,
+        elem_flags: 0,
+        flags: 0,
+    }
+}
+
+fn bpf_map_lookup_batch<K: Clone, V: Clone>(
+    fd: RawFd,
+    cursor: BatchCursor<K>,
+    count: usize,
+    delete: bool,
+) -> Batch<K, V> {
+    let mut in_batch = match cursor.state {
+        BatchState::End => return Ok((vec![], cursor)),
+        BatchState::Key(_) => return bpf_map_lookup_per_key(fd, cursor, count, delete),
+        BatchState::Start => None,
+        BatchState::Batch(batch) => Some(batch),
+    };
+    if count == 0 {
+        let state = in_batch.map_or(BatchState::Start, BatchState::Batch);
+        return Ok((vec![], BatchCursor { state }));
+    }
+    // hash maps return the index of a bucket and array maps return a key
+    let mut out_batch = vec![0u8; mem::size_of::<K>().max(mem::size_of::<u32>())];
+    let mut keys = Vec::<K>::new();
+    let mut values = Vec::<V>::new();
+    let mut batch_size = count;
+    let opts = batch_opts();
+    let in_ptr = in_batch
+        .as_mut()
+        .map_or(ptr::null_mut(), |batch| batch.as_mut_ptr() as *mut _);
+    let mut end = false;
+    let n = loop {
+        keys.reserve(batch_size);
+        values.reserve(batch_size);
+        let mut n = batch_size as u32;
+        let ret = unsafe {
+            let batch_fn = if delete {
+                libbpf_sys::bpf_map_lookup_and_delete_batch
+            } else {
+                libbpf_sys::bpf_map_lookup_batch
+            };
+            batch_fn(
+                fd,
+                in_ptr,
+                out_batch.as_mut_ptr() as *mut _,
+                keys.as_mut_ptr() as *mut _,
+                values.as_mut_ptr() as *mut _,
+                &mut n,
+                &opts,
+            )
+        };
+        if ret >= 0 {
+            break n;
+        }
+        let e = io::Error::last_os_error();
+        match e.raw_os_error() {
+            // `n` items are read before reaching the end
+            Some(libc::ENOENT) => {
+                end = true;
+                break n;
+            }
+            Some(libc::EINVAL) if in_batch.is_none() => {
+                debug!("batched map operations are not supported. fall back to per-key operations");
+                return bpf_map_lookup_per_key(fd, BatchCursor::default(), count, delete);
+            }
+            // A bucket of a hash map is read as a whole, and the next bucket
+            // has more items than the batch. Nothing is read, so retry with
+            // a batch large enough for the bucket
+            Some(libc::ENOSPC) if batch_size < u32::MAX as usize / 2 => {
+                batch_size *= 2;
+                debug!(
+                    "a bucket of the map does not fit in the batch. retry with {} items",
+                    batch_size
+                );
+            }
+            _ => {
+                error!("error on batched lookup of map: {}", e);
+                return Err(Error::IO(e));
+            }
+        }
+    };
+    unsafe {
+        keys.set_len(n as usize);
+        values.set_len(n as usize);
+    }
+    let state = if end {
+        BatchState::End
+    } else {
+        BatchState::Batch(out_batch)
+    };
+    Ok((
+        keys.into_iter().zip(values).collect(),
+        BatchCursor { state },
+    ))
+}
+
+fn bpf_map_lookup_per_key<K: Clone, V: Clone>(
+    fd: RawFd,
+    cursor: BatchCursor<K>,
+    count: usize,
+    delete: bool,
+) -> Batch<K, V> {
+    let mut last_key = match cursor.state {
+        BatchState::Key(key) => Some(key),
+        _ => None,
+    };
+    let mut items = Vec::with_capacity(count);
+    while items.len() < count {
+        // Deleted keys can not tell where to continue, so start over from
+        // the first key that is left
+        let prev_key = if delete { None } else { last_key.take() };
+        let key = match bpf_map_get_next_key(fd, prev_key) {
+            Some(key) => key,
+            None => {
+                let state = BatchState::End;
+                return Ok((items, BatchCursor { state }));
+            }
+        };
+        if let Some(value) = bpf_map_get(fd, key.clone()) {
+            if delete {
+                let _ = bpf_map_delete(fd, key.clone());
+            }
+            items.push((key.clone(), value));
+        }
+        last_key = Some(key);
+    }
+    let state = last_key.map_or(BatchState::Start, BatchState::Key);
+    Ok((items, BatchCursor { state }))
+}
+
+fn bpf_map_update_batch<K: Clone, V: Clone>(fd: RawFd, items: &[(K, V)]) -> Result<()> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let mut keys = items.iter().map(|(k, _)| k.clone()).collect::<Vec<K>>();
+    let mut values = items.iter().map(|(_, v)| v.clone()).collect::<Vec<V>>();
+    let mut n = items.len() as u32;
+    let opts = batch_opts();
+    let ret = unsafe {
+        libbpf_sys::bpf_map_update_batch(
+            fd,
+            keys.as_mut_ptr() as *mut _,
+            values.as_mut_ptr() as *mut _,
+            &mut n,
+            &opts,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let e = io::Error::last_os_error();
+    if e.raw_os_error() != Some(libc::EINVAL) {
+        // the kernel sets `n` to the number of items written
+        return Err(update_batch_error(n as usize, items.len(), e));
+    }
+    debug!("batched map operations are not supported. fall back to per-key operations");
+    for (written, (key, value)) in items.iter().enumerate() {
+        let mut key = key.clone();
+        let mut value = value.clone();
+        let ret = unsafe {
+            libbpf_sys::bpf_map_update_elem(
+                fd,
+                &mut key as *mut _ as *mut _,
+                &mut value as *mut _ as *mut _,
+                0,
+            )
+        };
+        if ret < 0 {
+            let e = io::Error::last_os_error();
+            return Err(update_batch_error(written, items.len(), e));
+        }
+    }
+    Ok(())
+}
+
+fn update_batch_error(written: usize, total: usize, e: io::Error) -> Error {
+    error!(
+        "error on batched update of map after {} of {} items: {}",
+        written, total, e
+    );
+    Error::IO(e)
+}
+
 fn bpf_map_get_next_key<K: Clone>(fd: RawFd, key: Option<K>) -> Option<K> {
     if let Some(mut key) = key {
         let mut next_key = MaybeUninit::<K>::zeroed();
@@ -2862,6 +7730,90 @@ fn bpf_percpu_map_set<K: Clone, V: Clone>(
     }
 }
 
+// Push `value` to a queue or stack map. Without BPF_EXIST the kernel does not
+// overwrite the oldest value of a full map but fails with E2BIG.
+fn bpf_map_push<V: Clone>(fd: RawFd, value: &V) -> Result<()> {
+    let mut value = value.clone();
+    if unsafe {
+        libbpf_sys::bpf_map_update_elem(
+            fd,
+            ptr::null(),
+            &mut value as *mut _ as *mut _,
+            BPF_ANY.into(),
+        )
+    } < 0
+    {
+        let err = io::Error::last_os_error();
+        error!("error pushing value to map: {}", err);
+        return Err(Error::IO(err));
+    }
+    Ok(())
+}
+
+// Pop a value from a queue or stack map. Queue and stack maps have no keys.
+fn bpf_map_pop<V: Clone>(fd: RawFd) -> Option<V> {
+    let mut value = MaybeUninit::<V>::zeroed();
+    if unsafe {
+        libbpf_sys::bpf_map_lookup_and_delete_elem(fd, ptr::null(), value.as_mut_ptr() as *mut _)
+    } < 0
+    {
+        return None;
+    }
+    Some(unsafe { value.assume_init() })
+}
+
+// Look up and delete `key` atomically. `Err` is returned if the kernel does
+// not support BPF_MAP_LOOKUP_AND_DELETE_ELEM for the map.
+fn bpf_percpu_map_lookup_and_delete<K: Clone, V: Clone>(
+    fd: RawFd,
+    mut key: K,
+) -> Result<Option<PerCpuValues<V>>> {
+    let value_size = round_up::<V>(8);
+    let count = cpus::get_possible_num();
+    let alloc_size = value_size * count;
+    let mut alloc = vec![0u8; alloc_size];
+    let data = alloc.as_mut_ptr();
+    if unsafe {
+        libbpf_sys::bpf_map_lookup_and_delete_elem(fd, &mut key as *mut _ as *mut _, data as *mut _)
+    } < 0
+    {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOENT) => Ok(None),
+            _ => Err(Error::IO(err)),
+        };
+    }
+
+    let mut values = Vec::with_capacity(count);
+    for i in 0..count {
+        unsafe {
+            let elem_ptr = data.add(value_size * i) as *const V;
+            values.push(ptr::read_unaligned(elem_ptr));
+        }
+    }
+
+    Ok(Some(values.into()))
+}
+
+fn bpf_percpu_map_read_and_reset<K: Clone, V: Clone + Default + Add<Output = V>>(
+    fd: RawFd,
+    key: K,
+) -> Result<Option<V>> {
+    match bpf_percpu_map_lookup_and_delete::<K, V>(fd, key.clone()) {
+        Ok(values) => return Ok(values.map(|values| values.sum())),
+        Err(e) => debug!(
+            "lookup and delete is not available, fall back to reset: {:?}",
+            e
+        ),
+    }
+    let values = match bpf_percpu_map_get::<K, V>(fd, key.clone()) {
+        Some(values) => values,
+        None => return Ok(None),
+    };
+    bpf_percpu_map_set(fd, key, PerCpuValues::new(V::default()))?;
+    Ok(Some(values.sum()))
+}
+
 fn bpf_percpu_map_get<K: Clone, V: Clone>(fd: RawFd, mut key: K) -> Option<PerCpuValues<V>> {
     // It is needed to round up the value size to 8*N
     // cf., https://elixir.bootlin.com/linux/v5.8/source/kernel/bpf/syscall.c#L1035
@@ -2886,3 +7838,1258 @@ fn bpf_percpu_map_get<K: Clone, V: Clone>(fd: RawFd, mut key: K) -> Option<PerCp
 
     Some(values.into())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::BTreeMap;
+
+    // The bytes of `insns` as they are in the section of a program
+    fn section_data(insns: &[bpf_insn]) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(insns.as_ptr() as *const u8, mem::size_of_val(insns)) }
+    }
+
+    // A program that counts its runs at index 0 of the array `counter` and
+    // returns 0. The code is valid for any program type
+    #[cfg(feature = "kernel_tests")]
+    fn counter_program(counter: &Map) -> Vec<bpf_insn> {
+        use crate::insn::*;
+
+        let mut insns = vec![st_mem(BPF_W, R10, -4, 0)];
+        insns.extend_from_slice(&ld_map_fd(R1, counter.fd));
+        insns.extend_from_slice(&[
+            mov64_reg(R2, R10),
+            alu64_imm(BPF_ADD, R2, -4),
+            call(libbpf_sys::BPF_FUNC_map_lookup_elem),
+            jmp_imm(BPF_JEQ, R0, 0, 2),
+            mov64_imm(R1, 1),
+            atomic_add(BPF_DW, R0, R1, 0),
+            mov64_imm(R0, 0),
+            exit(),
+        ]);
+        insns
+    }
+
+    // A map that drops the entry of `delete_on_lookup` right before it is
+    // looked up, like a BPF program deleting it concurrently
+    struct FakeMap {
+        entries: RefCell<BTreeMap<u32, u64>>,
+        delete_on_lookup: Option<u32>,
+    }
+
+    impl MapIterable<u32, u64> for FakeMap {
+        fn get(&self, key: u32) -> Option<u64> {
+            if self.delete_on_lookup == Some(key) {
+                self.entries.borrow_mut().remove(&key);
+            }
+            self.entries.borrow().get(&key).cloned()
+        }
+
+        // a missing key restarts from the first key like hash maps do
+        fn next_key(&self, key: Option<u32>) -> Option<u32> {
+            let entries = self.entries.borrow();
+            match key {
+                Some(key) if entries.contains_key(&key) => {
+                    entries.range(key + 1..).next().map(|(k, _)| *k)
+                }
+                _ => entries.keys().next().cloned(),
+            }
+        }
+    }
+
+    fn fake_map(delete_on_lookup: Option<u32>) -> FakeMap {
+        FakeMap {
+            entries: RefCell::new((1..=4).map(|k| (k, k as u64 * 100)).collect()),
+            delete_on_lookup,
+        }
+    }
+
+    #[test]
+    fn test_map_iter() {
+        let map = fake_map(None);
+        let iter = MapIter::new(&map);
+        assert_eq!(
+            iter.collect::<Vec<_>>(),
+            vec![(1, 100), (2, 200), (3, 300), (4, 400)]
+        );
+
+        let map = fake_map(Some(2));
+        let iter = MapIter::new(&map);
+        assert_eq!(iter.collect::<Vec<_>>(), vec![(1, 100), (3, 300), (4, 400)]);
+
+        // the yielded items are deleted while iterating
+        let map = fake_map(None);
+        let mut drained = vec![];
+        for (key, value) in MapIter::new(&map) {
+            map.entries.borrow_mut().remove(&key);
+            drained.push((key, value));
+        }
+        assert_eq!(drained, vec![(1, 100), (2, 200), (3, 300), (4, 400)]);
+        assert!(map.entries.borrow().is_empty());
+
+        let map = fake_map(None);
+        map.entries.borrow_mut().clear();
+        let mut iter = MapIter::new(&map);
+        assert!(iter.next().is_none());
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_hash_map_iter_delete() {
+        let map = Map::create("flows", BPF_MAP_TYPE_HASH, 4, 8, 1024, 0).unwrap();
+        let flows = HashMap::<u32, u64>::new(&map).unwrap();
+        for key in 0..512 {
+            flows.set(key, key as u64 * 10);
+        }
+
+        let mut drained = vec![];
+        for (key, value) in flows.iter() {
+            assert_eq!(value, key as u64 * 10);
+            flows.delete(key);
+            drained.push(key);
+        }
+        drained.sort_unstable();
+        assert_eq!(drained, (0..512).collect::<Vec<_>>());
+        assert!(flows.iter().next().is_none());
+    }
+
+    #[test]
+    fn test_parse_in_memory() {
+        let module = Module::parse(include_bytes!("../testdata/programs.o")).unwrap();
+        let mut names = module
+            .programs
+            .iter()
+            .map(|prog| prog.name())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, vec!["do_sys_open", "pass"]);
+        assert!(matches!(
+            module.program("do_sys_open"),
+            Some(Program::KProbe(_))
+        ));
+        assert!(matches!(module.program("pass"), Some(Program::XDP(_))));
+        assert!(module.maps.is_empty());
+        assert_eq!(module.license, "GPL");
+        assert_eq!(module.version, 0x050a00);
+    }
+
+    #[test]
+    fn test_program_type_section_kinds() {
+        let code = [insn::mov64_imm(insn::R0, 0), insn::exit()];
+        for program_type in ProgramType::ALL {
+            let program = Program::new(program_type.section_kind(), "prog", section_data(&code));
+            assert!(program.is_ok(), "{:?}", program_type);
+        }
+    }
+
+    #[test]
+    fn test_parse_with_section_map() {
+        // programs.o with `xdp/pass` renamed to `custom_xdp/pass`
+        let elf = include_bytes!("../testdata/custom_sections.o");
+        let module = Module::parse(elf).unwrap();
+        assert!(module.program("pass").is_none());
+
+        let mut section_map = RSHashMap::new();
+        section_map.insert("custom_xdp".to_string(), ProgramType::XDP);
+        let module = Module::with_section_map(elf, &section_map).unwrap();
+        assert!(matches!(module.program("pass"), Some(Program::XDP(_))));
+        assert!(matches!(
+            module.program("do_sys_open"),
+            Some(Program::KProbe(_))
+        ));
+
+        // the mapping takes precedence over the built-in kinds
+        section_map.insert("kprobe".to_string(), ProgramType::SocketFilter);
+        let module = Module::with_section_map(elf, &section_map).unwrap();
+        assert!(matches!(
+            module.program("do_sys_open"),
+            Some(Program::SocketFilter(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_pinned_map() {
+        // `counts` declared with #[map(pinned = "/sys/fs/bpf/redbpf_test_pinned_counts")]
+        let builder = ModuleBuilder::parse(include_bytes!("../testdata/pinned_map.o")).unwrap();
+        assert_eq!(
+            builder.pin_files.get("counts").map(PathBuf::as_path),
+            Some(Path::new("/sys/fs/bpf/redbpf_test_pinned_counts"))
+        );
+    }
+
+    #[test]
+    fn test_map_rust_types() {
+        // `connections: HashMap<Connection, u64>`, `counts: Array<u64>`,
+        // `tasks: LruHashMap<u32, Task>` and `events: PerfMap` without BTF
+        let types = btf::map_rust_types(include_bytes!("../testdata/map_types.o")).unwrap();
+        let maps = types
+            .maps
+            .iter()
+            .map(|map| (map.name.as_str(), map.key.as_str(), map.value.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            maps,
+            vec![
+                ("connections", "Connection", "u64"),
+                ("counts", "u32", "u64"),
+                ("tasks", "u32", "Task"),
+            ]
+        );
+        assert_eq!(types.definitions.len(), 2);
+        assert!(types.definitions[0].contains("pub struct Connection {"));
+        // `start: u64` is aligned by explicit padding
+        assert!(types.definitions[1].contains(
+            "    pub pid: u32,\n    pub _pad1: [u8; 4],\n    pub start: u64,\n    pub comm: [u8; 16],\n"
+        ));
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_pinned_map_is_reused() {
+        let elf = include_bytes!("../testdata/pinned_map.o");
+        let file = Path::new("/sys/fs/bpf/redbpf_test_pinned_counts");
+        let _ = fs::remove_file(file);
+
+        let module = Module::parse(elf).unwrap();
+        assert!(file.exists());
+        HashMap::<u32, u64>::new(module.map("counts").unwrap())
+            .unwrap()
+            .set(1, 10);
+        drop(module);
+
+        // the next run reuses the pinned map with its entries
+        let module = Module::parse(elf).unwrap();
+        let counts = HashMap::<u32, u64>::new(module.map("counts").unwrap()).unwrap();
+        assert_eq!(counts.get(1), Some(10));
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn test_parse_global_vars() {
+        // `LIMIT: u64` and `VERDICT: u32` in `.rodata`
+        let builder = ModuleBuilder::parse(include_bytes!("../testdata/global_config.o")).unwrap();
+        let var = |section: &str, offset, size| GlobalVar {
+            section: section.to_string(),
+            offset,
+            size,
+        };
+        assert_eq!(
+            builder.global_vars.get("LIMIT"),
+            Some(&var(".rodata", 0, 8))
+        );
+        assert_eq!(
+            builder.global_vars.get("VERDICT"),
+            Some(&var(".rodata", 8, 4))
+        );
+        assert_eq!(builder.global_vars.len(), 2);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_set_global_var() {
+        // `socketfilter/verdict` returns `VERDICT`
+        let mut module = Module::parse(include_bytes!("../testdata/global_config.o")).unwrap();
+        assert_eq!(module.global_var::<u32>("VERDICT").unwrap(), 1);
+        module.set_global_var("VERDICT", 7u32).unwrap();
+        assert_eq!(module.global_var::<u32>("VERDICT").unwrap(), 7);
+        assert!(matches!(
+            module.set_global_var("VERDICT", 7u64),
+            Err(Error::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            module.set_global_var("UNKNOWN", 7u32),
+            Err(Error::SymbolNotFound(_))
+        ));
+
+        module.map(".rodata").unwrap().freeze().unwrap();
+        assert!(module.set_global_var("VERDICT", 8u32).is_err());
+        let (version, license) = (module.version, module.license.clone());
+        let program = module.program_mut("verdict").unwrap();
+        program.load(version, license).unwrap();
+
+        let packet = [0u8; 64];
+        let (mut retval, mut duration) = (0, 0);
+        let ret = unsafe {
+            libbpf_sys::bpf_prog_test_run(
+                program.fd().unwrap(),
+                1,
+                packet.as_ptr() as *mut _,
+                packet.len() as u32,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut retval,
+                &mut duration,
+            )
+        };
+        assert_eq!(ret, 0);
+        assert_eq!(retval, 7);
+    }
+
+    // Collect the items of all batches in the order of keys
+    #[cfg(feature = "kernel_tests")]
+    fn collect_batches<F>(mut lookup: F) -> Vec<(u32, u64)>
+    where
+        F: FnMut(BatchCursor<u32>) -> Batch<u32, u64>,
+    {
+        let mut items = vec![];
+        let mut cursor = BatchCursor::default();
+        while !cursor.is_end() {
+            let (batch, next) = lookup(cursor).unwrap();
+            assert!(batch.len() <= 16);
+            items.extend(batch);
+            cursor = next;
+        }
+        items.sort_unstable();
+        items
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_map_batch() {
+        let base = Map::create("batch", BPF_MAP_TYPE_HASH, 4, 8, 64, 0).unwrap();
+        let map = HashMap::<u32, u64>::new(&base).unwrap();
+        let items = (0..40u32).map(|k| (k, k as u64 * 10)).collect::<Vec<_>>();
+        map.update_batch(&items).unwrap();
+
+        assert_eq!(
+            collect_batches(|cursor| map.lookup_batch(cursor, 16)),
+            items
+        );
+        // some of the 64 buckets have more than one of the 40 items, which
+        // do not fit in a batch of one item
+        assert_eq!(collect_batches(|cursor| map.lookup_batch(cursor, 1)), items);
+        // the fallback of kernels without batched operations
+        assert_eq!(
+            collect_batches(|cursor| bpf_map_lookup_per_key(base.fd, cursor, 16, false)),
+            items
+        );
+
+        let (mut drained, cursor) =
+            bpf_map_lookup_per_key::<u32, u64>(base.fd, BatchCursor::default(), 8, true).unwrap();
+        assert_eq!(drained.len(), 8);
+        assert!(!cursor.is_end());
+        drained.extend(collect_batches(|cursor| {
+            map.lookup_and_delete_batch(cursor, 16)
+        }));
+        drained.sort_unstable();
+        assert_eq!(drained, items);
+        assert!(map.iter().next().is_none());
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_pin_map() {
+        let file = format!("/sys/fs/bpf/redbpf_test_pin_map_{}", std::process::id());
+        {
+            let mut base = Map::create("pinned", BPF_MAP_TYPE_HASH, 4, 8, 16, 0).unwrap();
+            HashMap::<u32, u64>::new(&base).unwrap().set(7, 42);
+            base.pin(&file).unwrap();
+        }
+        let mut base = Map::from_pin_file(&file).unwrap();
+        assert_eq!((base.config.key_size, base.config.value_size), (4, 8));
+        assert!(HashMap::<u64, u64>::new(&base).is_err());
+        assert_eq!(HashMap::<u32, u64>::new(&base).unwrap().get(7), Some(42));
+        base.unpin().unwrap();
+        assert!(Map::from_pin_file(&file).is_err());
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_pin_program() {
+        use crate::insn::*;
+
+        let file = format!("/sys/fs/bpf/redbpf_test_pin_prog_{}", std::process::id());
+        let mut builder = ProgramBuilder::new("pinned", ProgramType::SocketFilter);
+        builder.insn(mov64_imm(R0, 0)).insn(exit());
+        builder.load().unwrap().pin(&file).unwrap();
+        assert!(Program::from_pin_file(&file, ProgramType::XDP).is_err());
+        let program = Program::from_pin_file(&file, ProgramType::SocketFilter).unwrap();
+        assert!(matches!(program, Program::SocketFilter(_)));
+        assert_eq!(program.name(), "pinned");
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_fexit_fires() {
+        // count the calls of the target in an array map
+        let counter = Map::create("counter", BPF_MAP_TYPE_ARRAY, 4, 8, 1, 0).unwrap();
+        let vmlinux_btf = btf::parse_vmlinux_btf().unwrap();
+        let mut program = Program::with_target(
+            "fexit",
+            "do_unlinkat/count",
+            section_data(&[]),
+            Some(&vmlinux_btf),
+        )
+        .unwrap();
+        program.data_mut().code = counter_program(&counter);
+        program
+            .load(get_kernel_internal_version().unwrap(), "GPL".to_string())
+            .unwrap();
+        match program {
+            Program::FExit(ref mut fexit) => fexit.attach().unwrap(),
+            _ => panic!("not an fexit program"),
+        }
+
+        // unlink(2) calls do_unlinkat even if the file does not exist
+        let _ = fs::remove_file("/nonexistent/redbpf_test_fexit");
+        let count = Array::<u64>::new(&counter).unwrap().get(0).unwrap();
+        assert!(count >= 1);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    #[ignore = "needs a kernel booted with `bpf` in the active LSMs"]
+    fn test_lsm_fires() {
+        // count the calls of the hook in an array map and allow the operation
+        let counter = Map::create("counter", BPF_MAP_TYPE_ARRAY, 4, 8, 1, 0).unwrap();
+        let vmlinux_btf = btf::parse_vmlinux_btf().unwrap();
+        let mut program = Program::with_target(
+            "lsm",
+            "file_open/count",
+            section_data(&[]),
+            Some(&vmlinux_btf),
+        )
+        .unwrap();
+        program.data_mut().code = counter_program(&counter);
+        program
+            .load(get_kernel_internal_version().unwrap(), "GPL".to_string())
+            .unwrap();
+        match program {
+            Program::Lsm(ref mut lsm) => lsm.attach().unwrap(),
+            _ => panic!("not an lsm program"),
+        }
+
+        File::open("/proc/self/stat").unwrap();
+        let count = Array::<u64>::new(&counter).unwrap().get(0).unwrap();
+        assert!(count >= 1);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_percpu_counter_sum() {
+        use crate::insn::*;
+
+        // increment the counter of the current cpu
+        let counter = Map::create("counter", BPF_MAP_TYPE_PERCPU_ARRAY, 4, 8, 1, 0).unwrap();
+        let program = ProgramBuilder::new("count", ProgramType::SocketFilter)
+            .insns(&counter_program(&counter))
+            .load()
+            .unwrap();
+        let prog_fd = program.fd().unwrap();
+
+        // run the program twice on every online cpu
+        let online = cpus::get_online().unwrap();
+        for &cpu in online.iter() {
+            std::thread::spawn(move || unsafe {
+                let mut set = mem::zeroed::<libc::cpu_set_t>();
+                libc::CPU_SET(cpu as usize, &mut set);
+                assert_eq!(
+                    libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &set),
+                    0
+                );
+                let packet = [0u8; 64];
+                let (mut retval, mut duration) = (0, 0);
+                let ret = libbpf_sys::bpf_prog_test_run(
+                    prog_fd,
+                    2,
+                    packet.as_ptr() as *mut _,
+                    packet.len() as u32,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut retval,
+                    &mut duration,
+                );
+                assert_eq!(ret, 0);
+            })
+            .join()
+            .unwrap();
+        }
+
+        let counters = PerCpuArray::<u64>::new(&counter).unwrap();
+        let values = counters.get(0).unwrap();
+        assert_eq!(values.len(), cpus::get_possible_num());
+        for &cpu in online.iter() {
+            assert_eq!(values[cpu as usize], 2);
+        }
+        assert_eq!(values.sum(), 2 * online.len() as u64);
+
+        counters.set(0, &PerCpuValues::new(1)).unwrap();
+        assert!(counters.set(0, &vec![1].into()).is_err());
+        assert_eq!(
+            counters.get(0).unwrap().sum(),
+            cpus::get_possible_num() as u64
+        );
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_queue_fifo() {
+        let map = Map::create("queue", libbpf_sys::BPF_MAP_TYPE_QUEUE, 0, 8, 3, 0).unwrap();
+        let queue = Queue::<u64>::new(&map).unwrap();
+        assert_eq!(queue.pop(), None);
+        for value in 1..=3 {
+            queue.push(&value).unwrap();
+        }
+        match queue.push(&4) {
+            Err(Error::IO(e)) => assert_eq!(e.raw_os_error(), Some(libc::E2BIG)),
+            _ => panic!("pushing to a full queue should fail with E2BIG"),
+        }
+        assert_eq!(queue.pop(), Some(1));
+        queue.push(&4).unwrap();
+        let values = std::iter::from_fn(|| queue.pop()).collect::<Vec<_>>();
+        assert_eq!(values, vec![2, 3, 4]);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_stack_lifo() {
+        let map = Map::create("stack", libbpf_sys::BPF_MAP_TYPE_STACK, 0, 8, 3, 0).unwrap();
+        let stack = Stack::<u64>::new(&map).unwrap();
+        assert_eq!(stack.pop(), None);
+        for value in 1..=3 {
+            stack.push(&value).unwrap();
+        }
+        match stack.push(&4) {
+            Err(Error::IO(e)) => assert_eq!(e.raw_os_error(), Some(libc::E2BIG)),
+            _ => panic!("pushing to a full stack should fail with E2BIG"),
+        }
+        assert_eq!(stack.pop(), Some(3));
+        stack.push(&4).unwrap();
+        let values = std::iter::from_fn(|| stack.pop()).collect::<Vec<_>>();
+        assert_eq!(values, vec![4, 2, 1]);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_outer_map_swap() {
+        let map_id = |map: &Map| unsafe {
+            let mut info = mem::zeroed::<bpf_map_info>();
+            let mut info_len = mem::size_of_val(&info) as u32;
+            assert_eq!(
+                libbpf_sys::bpf_obj_get_info_by_fd(
+                    map.fd,
+                    &mut info as *mut _ as *mut _,
+                    &mut info_len
+                ),
+                0
+            );
+            info.id
+        };
+
+        let first = Map::create("first", BPF_MAP_TYPE_HASH, 4, 4, 16, 0).unwrap();
+        let second = Map::create("second", BPF_MAP_TYPE_HASH, 4, 4, 16, 0).unwrap();
+        // the template inner map is created before the outer map
+        let outer_map =
+            Map::create_map_in_map("outer", libbpf_sys::BPF_MAP_TYPE_ARRAY_OF_MAPS, 1, &first)
+                .unwrap();
+        let outer = OuterMap::<u32>::new(&outer_map).unwrap();
+        assert_eq!(outer.get_inner_id(&0), None);
+
+        outer.set_inner(&0, &first).unwrap();
+        assert_eq!(outer.get_inner_id(&0), Some(map_id(&first)));
+        outer.set_inner(&0, &second).unwrap();
+        assert_eq!(outer.get_inner_id(&0), Some(map_id(&second)));
+
+        // inner maps not matching the template are rejected
+        let other = Map::create("other", BPF_MAP_TYPE_ARRAY, 4, 8, 16, 0).unwrap();
+        match outer.set_inner(&0, &other) {
+            Err(Error::IO(e)) => assert_eq!(e.raw_os_error(), Some(libc::EINVAL)),
+            ret => panic!("unexpected result: {:?}", ret),
+        }
+        assert_eq!(outer.get_inner_id(&0), Some(map_id(&second)));
+    }
+
+    #[test]
+    fn test_load_as() {
+        let mut builder = ModuleBuilder::parse(include_bytes!("../testdata/programs.o")).unwrap();
+        assert!(builder
+            .load_as("kprobe/do_sys_open", ProgramType::XDP)
+            .is_err());
+        assert!(builder.load_as("pass", ProgramType::SocketFilter).is_err());
+        builder
+            .load_as("kprobe/do_sys_open", ProgramType::UProbe)
+            .unwrap();
+        assert!(builder
+            .programs
+            .values()
+            .any(|prog| matches!(prog, Program::UProbe(p) if p.common.name == "do_sys_open")));
+
+        let mut builder =
+            ModuleBuilder::parse(include_bytes!("../testdata/global_config.o")).unwrap();
+        builder
+            .load_as("verdict", ProgramType::StreamParser)
+            .unwrap();
+        assert!(builder
+            .programs
+            .values()
+            .any(|prog| matches!(prog, Program::StreamParser(_))));
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_module_swap_map() {
+        let first = Map::create("first", BPF_MAP_TYPE_HASH, 4, 4, 16, 0).unwrap();
+        let second = Map::create("second", BPF_MAP_TYPE_HASH, 4, 4, 16, 0).unwrap();
+        let first_id = first.info().unwrap().id;
+        let second_id = second.info().unwrap().id;
+        let outer_map =
+            Map::create_map_in_map("outer", libbpf_sys::BPF_MAP_TYPE_ARRAY_OF_MAPS, 1, &first)
+                .unwrap();
+        let hash_outer_map = Map::create_map_in_map(
+            "hash_outer",
+            libbpf_sys::BPF_MAP_TYPE_HASH_OF_MAPS,
+            1,
+            &first,
+        )
+        .unwrap();
+        let mut module = Module {
+            programs: vec![],
+            maps: vec![outer_map, hash_outer_map],
+            license: String::new(),
+            version: 0,
+            inner_maps: RSHashMap::new(),
+            core_relocation_failures: vec![],
+            global_vars: RSHashMap::new(),
+        };
+
+        assert!(module.swap_map("outer", first).unwrap().is_none());
+        let outer = OuterMap::<u32>::new(module.map("outer").unwrap()).unwrap();
+        assert_eq!(outer.get_inner_id(&0), Some(first_id));
+
+        let old = module.swap_map("outer", second).unwrap().unwrap();
+        assert_eq!(old.info().unwrap().id, first_id);
+        let outer = OuterMap::<u32>::new(module.map("outer").unwrap()).unwrap();
+        assert_eq!(outer.get_inner_id(&0), Some(second_id));
+        assert_eq!(
+            module.inner_map("outer").unwrap().info().unwrap().id,
+            second_id
+        );
+
+        // hash of maps is not keyed by index 0
+        assert!(module.swap_map("hash_outer", old).is_err());
+        assert!(module.inner_map("hash_outer").is_none());
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_raw_tracepoint_sys_enter() {
+        use crate::insn::*;
+
+        // count the syscalls with the first argument of sys_enter, which is
+        // struct pt_regs *, not NULL
+        let counter = Map::create("counter", BPF_MAP_TYPE_ARRAY, 4, 8, 1, 0).unwrap();
+        let count = counter_program(&counter);
+        let mut program = ProgramBuilder::new("count", ProgramType::RawTracePoint)
+            .insn(ldx_mem(BPF_DW, R6, R1, 0))
+            // skip to `r0 = 0` at the end
+            .insn(jmp_imm(BPF_JEQ, R6, 0, count.len() as i16 - 2))
+            .insns(&count)
+            .load()
+            .unwrap();
+        match program {
+            Program::RawTracePoint(ref mut raw_tp) => {
+                assert!(raw_tp.attach().is_err());
+                raw_tp.attach_raw_tracepoint("sys_enter").unwrap();
+            }
+            _ => panic!("not a raw tracepoint program"),
+        }
+
+        let _ = fs::metadata("/nonexistent/redbpf_test_raw_tracepoint");
+        let count = Array::<u64>::new(&counter).unwrap().get(0).unwrap();
+        assert!(count >= 1);
+    }
+
+    #[test]
+    fn test_raw_tracepoint_section() {
+        let code = [insn::mov64_imm(insn::R0, 0), insn::exit()];
+        let program = Program::new(
+            "raw_tracepoint",
+            "sched_switch/on_switch",
+            section_data(&code),
+        )
+        .unwrap();
+        match program {
+            Program::RawTracePoint(raw_tp) => {
+                assert_eq!(raw_tp.name(), "on_switch");
+                assert_eq!(raw_tp.tracepoint(), Some("sched_switch"));
+            }
+            _ => panic!("not a raw tracepoint program"),
+        }
+        for kind in &["raw_tracepoint", "raw_tp"] {
+            match Program::new(kind, "sys_enter", section_data(&code)).unwrap() {
+                Program::RawTracePoint(raw_tp) => {
+                    assert_eq!(raw_tp.name(), "sys_enter");
+                    assert_eq!(raw_tp.tracepoint(), Some("sys_enter"));
+                }
+                _ => panic!("not a raw tracepoint program"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_tracepoint_section() {
+        let code = [insn::mov64_imm(insn::R0, 0), insn::exit()];
+        let program = Program::new(
+            "tracepoint",
+            "syscalls/sys_enter_openat",
+            section_data(&code),
+        )
+        .unwrap();
+        match program {
+            Program::TracePoint(tp) => {
+                assert_eq!(tp.name(), "sys_enter_openat");
+                assert_eq!(tp.tracepoint(), Some(("syscalls", "sys_enter_openat")));
+            }
+            _ => panic!("not a tracepoint program"),
+        }
+        match Program::new("tracepoint", "on_open", section_data(&code)).unwrap() {
+            Program::TracePoint(tp) => {
+                assert_eq!(tp.name(), "on_open");
+                assert_eq!(tp.tracepoint(), None);
+            }
+            _ => panic!("not a tracepoint program"),
+        }
+    }
+
+    #[test]
+    fn test_perf_event_section() {
+        let code = [insn::mov64_imm(insn::R0, 0), insn::exit()];
+        match Program::new("perf_event", "sample_stack", section_data(&code)).unwrap() {
+            Program::PerfEvent(mut perf_event) => {
+                assert_eq!(perf_event.name(), "sample_stack");
+                assert!(matches!(
+                    perf_event.attach_sampler(PerfEventType::CPU_CLOCK, 99),
+                    Err(Error::ProgramNotLoaded)
+                ));
+            }
+            _ => panic!("not a perf_event program"),
+        }
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_perf_event_sampler_fires() {
+        use crate::insn::*;
+
+        // count the samples
+        let counter = Map::create("counter", BPF_MAP_TYPE_ARRAY, 4, 8, 1, 0).unwrap();
+        let mut program = ProgramBuilder::new("count", ProgramType::PerfEvent)
+            .insns(&counter_program(&counter))
+            .load()
+            .unwrap();
+        let perf_event = match program {
+            Program::PerfEvent(ref mut perf_event) => perf_event,
+            _ => panic!("not a perf_event program"),
+        };
+        perf_event
+            .attach_sampler(PerfEventType::CPU_CLOCK, 99)
+            .unwrap();
+
+        // keep a CPU busy for a while so that the clock samples it
+        let counts = Array::<u64>::new(&counter).unwrap();
+        let start = std::time::Instant::now();
+        while counts.get(0).unwrap() == 0 && start.elapsed().as_secs() < 5 {
+            std::hint::spin_loop();
+        }
+        assert!(counts.get(0).unwrap() >= 1);
+
+        perf_event.detach().unwrap();
+        let count = counts.get(0).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(counts.get(0).unwrap(), count);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_verifier_log_on_load_failure() {
+        use crate::insn::*;
+
+        let mut builder = ProgramBuilder::new("oob", ProgramType::SocketFilter);
+        builder
+            // read above the top of the stack
+            .insn(ldx_mem(BPF_DW, R0, R10, 8))
+            .insn(exit());
+        match builder.load() {
+            Err(Error::ProgramLoad { verifier_log, .. }) => {
+                assert!(!verifier_log.unwrap().is_empty());
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("the verifier accepted an out of bounds access"),
+        }
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_kprobe_attach_offset_and_addr() {
+        use crate::insn::*;
+
+        let mut builder = ProgramBuilder::new("probe", ProgramType::KProbe);
+        builder.license("GPL").insn(mov64_imm(R0, 0)).insn(exit());
+        let mut program = builder.load().unwrap();
+        let kprobe = match &mut program {
+            Program::KProbe(kprobe) => kprobe,
+            _ => panic!("not a kprobe program"),
+        };
+        // the first instructions of vfs_read depend on the kernel config, so
+        // try offsets until one is at an instruction boundary
+        assert!((1..16).any(|offset| kprobe.attach_kprobe("vfs_read", offset).is_ok()));
+        assert!(kprobe.attach_kprobe("vfs_read", 1 << 24).is_err());
+
+        let addr = kallsyms::resolve("vfs_read").unwrap();
+        kprobe.attach_kprobe_addr(addr).unwrap();
+        kprobe.detach().unwrap();
+        kprobe.reattach().unwrap();
+        kprobe.detach_kprobe_addr(addr).unwrap();
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_kprobe_multi_rolls_back() {
+        use crate::insn::*;
+
+        let mut builder = ProgramBuilder::new("probe", ProgramType::KProbe);
+        builder.license("GPL").insn(mov64_imm(R0, 0)).insn(exit());
+        let mut program = builder.load().unwrap();
+        let kprobe = match &mut program {
+            Program::KProbe(kprobe) => kprobe,
+            _ => panic!("not a kprobe program"),
+        };
+        // the link can not be created for the unknown function, so the
+        // fallback attaches vfs_read and then fails
+        assert!(kprobe
+            .attach_kprobe_multi(&["vfs_read", "redbpf_no_such_function"])
+            .is_err());
+        assert!(kprobe.attachment_points.is_empty());
+        assert!(kprobe.multi_links.is_empty());
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_open_typed_size_mismatch() {
+        let map = Map::create("typed", libbpf_sys::BPF_MAP_TYPE_HASH, 4, 8, 16, 0).unwrap();
+        assert!(matches!(
+            map.open_typed::<u32, u32>(),
+            Err(Error::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            map.open_typed::<u64, u64>(),
+            Err(Error::TypeMismatch(_))
+        ));
+        let typed = map.open_typed::<u32, u64>().unwrap();
+        typed.set(&1, &42).unwrap();
+        assert_eq!(typed.get(&1), Some(42));
+        typed.delete(&1).unwrap();
+        assert_eq!(typed.get(&1), None);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_open_typed_btf() {
+        #[derive(Clone)]
+        #[repr(C)]
+        struct Connection {
+            saddr: u32,
+            daddr: u32,
+            sport: u16,
+            dport: u16,
+        }
+
+        // `connections: HashMap<Connection, u64>` has BTF and `events` is a
+        // perf event array
+        let module = Module::parse(include_bytes!("../testdata/map_types.o")).unwrap();
+        let connections = module.map("connections").unwrap();
+        assert!(matches!(
+            connections.open_typed::<u64, u64>(),
+            Err(Error::TypeMismatch(_))
+        ));
+        let typed = connections.open_typed::<Connection, u64>().unwrap();
+        let conn = Connection {
+            saddr: 0x0100007f,
+            daddr: 0x0100007f,
+            sport: 1234,
+            dport: 80,
+        };
+        typed.set(&conn, &42).unwrap();
+        assert_eq!(typed.get(&conn), Some(42));
+        assert!(matches!(
+            module.map("events").unwrap().open_typed::<u32, u32>(),
+            Err(Error::Map)
+        ));
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_open_typed_rejects_percpu_map() {
+        let map = Map::create("percpu", BPF_MAP_TYPE_PERCPU_HASH, 4, 8, 16, 0).unwrap();
+        assert!(matches!(map.open_typed::<u32, u64>(), Err(Error::Map)));
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_program_and_map_info() {
+        use crate::insn::*;
+
+        let map = Map::create("info", libbpf_sys::BPF_MAP_TYPE_HASH, 4, 8, 16, 0).unwrap();
+        let mut builder = ProgramBuilder::new("info", ProgramType::SocketFilter);
+        builder
+            .ld_map(R1, "info")
+            .insn(mov64_imm(R0, 0))
+            .insn(exit())
+            .map("info", &map);
+        let program = builder.load().unwrap();
+
+        let info = program.info().unwrap();
+        assert_ne!(info.id, 0);
+        assert_eq!(info.name, "info");
+        assert_eq!(info.prog_type, libbpf_sys::BPF_PROG_TYPE_SOCKET_FILTER);
+        assert_eq!(program.info().unwrap().id, info.id);
+
+        let map_info = map.info().unwrap();
+        assert_ne!(map_info.id, 0);
+        assert_eq!(map_info.max_entries, 16);
+        assert_eq!(info.map_ids, vec![map_info.id]);
+
+        assert_eq!(map.entry_count(), 0);
+        let hash = HashMap::<u32, u64>::new(&map).unwrap();
+        hash.set(1, 10);
+        hash.set(2, 20);
+        assert_eq!(map.entry_count(), 2);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_program_array_tail_call() {
+        use crate::insn::*;
+
+        let map = Map::create_program_array("jmp_table", 1).unwrap();
+        let programs = ProgramArray::new(&map).unwrap();
+
+        let unloaded = Program::new(
+            ProgramType::SocketFilter.section_kind(),
+            "unloaded",
+            section_data(&[]),
+        )
+        .unwrap();
+        assert!(matches!(
+            programs.set_program(0, &unloaded),
+            Err(Error::ProgramNotLoaded)
+        ));
+
+        let mut builder = ProgramBuilder::new("second", ProgramType::SocketFilter);
+        builder.insn(mov64_imm(R0, 7)).insn(exit());
+        let second = builder.load().unwrap();
+        let mut builder = ProgramBuilder::new("first", ProgramType::SocketFilter);
+        builder
+            // tail call the program at index 0, or return 1 without it
+            .ld_map(R2, "jmp_table")
+            .insn(mov64_imm(R3, 0))
+            .insn(call(libbpf_sys::BPF_FUNC_tail_call))
+            .insn(mov64_imm(R0, 1))
+            .insn(exit())
+            .map("jmp_table", &map);
+        let first = builder.load().unwrap();
+
+        let run = || {
+            let packet = [0u8; 64];
+            let (mut retval, mut duration) = (0, 0);
+            let ret = unsafe {
+                libbpf_sys::bpf_prog_test_run(
+                    first.fd().unwrap(),
+                    1,
+                    packet.as_ptr() as *mut _,
+                    packet.len() as u32,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut retval,
+                    &mut duration,
+                )
+            };
+            assert_eq!(ret, 0);
+            retval
+        };
+        assert_eq!(run(), 1);
+        programs.set_program(0, &second).unwrap();
+        assert_eq!(run(), 7);
+        programs.delete(0).unwrap();
+        assert_eq!(run(), 1);
+    }
+
+    #[test]
+    fn test_attach_socket_filter_checks_program() {
+        let code = [insn::mov64_imm(insn::R0, 0), insn::exit()];
+        let unloaded = Program::new("socketfilter", "unloaded", section_data(&code)).unwrap();
+        let e = attach_socket_filter(&unloaded, "lo").err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        let kprobe = Program::new("kprobe", "do_sys_open", section_data(&code)).unwrap();
+        let e = attach_socket_filter(&kprobe, "lo").err().unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_attach_socket_filter_captures_loopback() {
+        use crate::insn::*;
+        use std::net::UdpSocket;
+        use std::thread;
+        use std::time::Duration;
+
+        // keep the whole of every packet
+        let mut builder = ProgramBuilder::new("capture", ProgramType::SocketFilter);
+        builder.insn(mov64_imm(R0, -1)).insn(exit());
+        let program = builder.load().unwrap();
+
+        let e = attach_socket_filter(&program, "redbpf-none0")
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+
+        let sock = attach_socket_filter(&program, "lo").unwrap();
+        let payload = b"redbpf socket filter";
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp.send_to(payload, udp.local_addr().unwrap()).unwrap();
+
+        // the socket is non-blocking
+        let mut buf = [0u8; 2048];
+        let mut captured = false;
+        for _ in 0..100 {
+            let n = unsafe { libc::recv(sock, buf.as_mut_ptr() as *mut _, buf.len(), 0) };
+            if n > 0
+                && buf[..n as usize]
+                    .windows(payload.len())
+                    .any(|w| w == payload)
+            {
+                captured = true;
+                break;
+            }
+            if n < 0 {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        unsafe { libc::close(sock) };
+        assert!(captured);
+    }
+
+    #[test]
+    fn test_cgroup_sections() {
+        let code = [insn::mov64_imm(insn::R0, 1), insn::exit()];
+        match Program::new("cgroup_skb", "skb/filter", section_data(&code)).unwrap() {
+            Program::CgroupSkb(cgroup_skb) => assert_eq!(cgroup_skb.name(), "filter"),
+            _ => panic!("not a cgroup/skb program"),
+        }
+        match Program::new("cgroup_sock", "post_bind4/on_bind", section_data(&code)).unwrap() {
+            Program::CgroupSock(cgroup_sock) => {
+                assert_eq!(cgroup_sock.name(), "on_bind");
+                assert_eq!(cgroup_sock.attach_type(), CgroupSockAttachType::PostBind4);
+            }
+            _ => panic!("not a cgroup/sock program"),
+        }
+        assert!(Program::new("cgroup_sock", "connect4/on_connect", section_data(&code)).is_err());
+    }
+
+    #[test]
+    fn test_cgroup_section_names() {
+        assert_eq!(
+            cgroup_section("cgroup", "skb/filter"),
+            Some(("cgroup_skb", "skb/filter".to_string()))
+        );
+        assert_eq!(
+            cgroup_section("cgroup", "post_bind4/on_bind"),
+            Some(("cgroup_sock", "post_bind4/on_bind".to_string()))
+        );
+        // sections without a program name
+        assert_eq!(
+            cgroup_section("cgroup", "skb"),
+            Some(("cgroup_skb", "skb/cgroup_skb".to_string()))
+        );
+        assert_eq!(
+            cgroup_section("cgroup", "sock"),
+            Some(("cgroup_sock", "sock/cgroup_sock".to_string()))
+        );
+        assert_eq!(
+            cgroup_section("cgroup_skb", "ingress"),
+            Some(("cgroup_skb", "ingress/cgroup_skb_ingress".to_string()))
+        );
+        assert_eq!(
+            cgroup_section("cgroup_skb", "egress"),
+            Some(("cgroup_skb", "egress/cgroup_skb_egress".to_string()))
+        );
+        assert_eq!(cgroup_section("cgroup", "connect4"), None);
+        assert_eq!(cgroup_section("cgroup_skb", "ingress/filter"), None);
+
+        let code = [insn::mov64_imm(insn::R0, 1), insn::exit()];
+        let (kind, name) = cgroup_section("cgroup", "sock").unwrap();
+        match Program::new(kind, &name, section_data(&code)).unwrap() {
+            Program::CgroupSock(cgroup_sock) => {
+                assert_eq!(cgroup_sock.name(), "cgroup_sock");
+                assert_eq!(cgroup_sock.attach_type(), CgroupSockAttachType::SockCreate);
+            }
+            _ => panic!("not a cgroup/sock program"),
+        }
+        let (kind, name) = cgroup_section("cgroup_skb", "ingress").unwrap();
+        match Program::new(kind, &name, section_data(&code)).unwrap() {
+            Program::CgroupSkb(cgroup_skb) => assert_eq!(cgroup_skb.name(), "cgroup_skb_ingress"),
+            _ => panic!("not a cgroup/skb program"),
+        }
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_cgroup_skb_attach_detach() {
+        use crate::insn::*;
+        use std::os::unix::io::AsRawFd;
+
+        let cgroup_path = cgroup::cgroup2_root()
+            .unwrap()
+            .join("redbpf_test_cgroup_skb");
+        let _ = fs::create_dir(&cgroup_path);
+        let query = |attach_type| {
+            let cgroup = fs::File::open(&cgroup_path).unwrap();
+            let mut attach_flags = 0u32;
+            let mut prog_ids = [0u32; BPF_CGROUP_MAX_PROGS];
+            let mut prog_cnt = prog_ids.len() as u32;
+            let ret = unsafe {
+                libbpf_sys::bpf_prog_query(
+                    cgroup.as_raw_fd(),
+                    attach_type,
+                    0,
+                    &mut attach_flags,
+                    prog_ids.as_mut_ptr(),
+                    &mut prog_cnt,
+                )
+            };
+            assert_eq!(ret, 0);
+            prog_ids[..prog_cnt as usize].to_vec()
+        };
+
+        let mut builder = ProgramBuilder::new("allow_all", ProgramType::CgroupSkb);
+        builder.insn(mov64_imm(R0, 1)).insn(exit());
+        let mut program = builder.load().unwrap();
+        let cgroup_skb = match &mut program {
+            Program::CgroupSkb(cgroup_skb) => cgroup_skb,
+            _ => panic!("not a cgroup/skb program"),
+        };
+        cgroup_skb
+            .attach_cgroup(&cgroup_path, CgroupSkbAttachType::Ingress)
+            .unwrap();
+        cgroup_skb
+            .attach_cgroup(&cgroup_path, CgroupSkbAttachType::Egress)
+            .unwrap();
+        assert_eq!(query(libbpf_sys::BPF_CGROUP_INET_INGRESS).len(), 1);
+        assert_eq!(query(libbpf_sys::BPF_CGROUP_INET_EGRESS).len(), 1);
+
+        cgroup_skb
+            .detach_cgroup(&cgroup_path, CgroupSkbAttachType::Ingress)
+            .unwrap();
+        assert!(query(libbpf_sys::BPF_CGROUP_INET_INGRESS).is_empty());
+        assert_eq!(query(libbpf_sys::BPF_CGROUP_INET_EGRESS).len(), 1);
+
+        // the egress attachment moves to the replacement
+        let mut replacement = builder.load().unwrap();
+        let replacement_id = replacement.info().unwrap().id;
+        let new_skb = match &mut replacement {
+            Program::CgroupSkb(cgroup_skb) => cgroup_skb,
+            _ => panic!("not a cgroup/skb program"),
+        };
+        let cgroup_skb = match &mut program {
+            Program::CgroupSkb(cgroup_skb) => cgroup_skb,
+            _ => panic!("not a cgroup/skb program"),
+        };
+        assert!(matches!(
+            new_skb.replace_cgroup(&cgroup_path, CgroupSkbAttachType::Ingress, cgroup_skb),
+            Err(Error::ProgramNotAttached)
+        ));
+        new_skb
+            .replace_cgroup(&cgroup_path, CgroupSkbAttachType::Egress, cgroup_skb)
+            .unwrap();
+        drop(program);
+        assert_eq!(
+            query(libbpf_sys::BPF_CGROUP_INET_EGRESS),
+            vec![replacement_id]
+        );
+        drop(replacement);
+        assert!(query(libbpf_sys::BPF_CGROUP_INET_EGRESS).is_empty());
+        fs::remove_dir(&cgroup_path).unwrap();
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_xdp_multi_rolls_back() {
+        use crate::insn::*;
+
+        let load = || {
+            let mut builder = ProgramBuilder::new("pass", ProgramType::XDP);
+            builder
+                .insn(mov64_imm(R0, libbpf_sys::XDP_PASS as i32))
+                .insn(exit());
+            builder.load().unwrap()
+        };
+        let mut first = load();
+        let mut second = load();
+        let first_id = first.info().unwrap().id;
+        let second_id = second.info().unwrap().id;
+        let (first, second) = match (&mut first, &mut second) {
+            (Program::XDP(first), Program::XDP(second)) => (first, second),
+            _ => panic!("not xdp programs"),
+        };
+        let skb_mode = xdp::Flags::SkbMode as u32;
+
+        // an interface that is not found fails after lo is attached
+        let err = second
+            .attach_xdp_multi(&["lo", "redbpf_no_such_if"], xdp::Flags::SkbMode)
+            .unwrap_err();
+        match err {
+            Error::XdpMultiAttach {
+                interface,
+                not_rolled_back,
+                ..
+            } => {
+                assert_eq!(interface, "redbpf_no_such_if");
+                assert!(not_rolled_back.is_empty());
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(unsafe { xdp_prog_id("lo", skb_mode) }.unwrap(), 0);
+        assert!(second.interfaces.is_empty());
+
+        // the program attached to lo before is not replaced nor detached
+        first.attach_xdp("lo", xdp::Flags::SkbMode).unwrap();
+        assert!(second
+            .attach_xdp_multi(&["lo"], xdp::Flags::SkbMode)
+            .is_err());
+        assert_eq!(unsafe { xdp_prog_id("lo", skb_mode) }.unwrap(), first_id);
+        assert!(second.interfaces.is_empty());
+
+        first.detach_xdp("lo").unwrap();
+        second
+            .attach_xdp_multi(&["lo"], xdp::Flags::SkbMode)
+            .unwrap();
+        assert_eq!(unsafe { xdp_prog_id("lo", skb_mode) }.unwrap(), second_id);
+        second.detach_xdp("lo").unwrap();
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_xdp_link_guard_detaches_on_drop() {
+        use crate::insn::*;
+
+        let mut builder = ProgramBuilder::new("pass", ProgramType::XDP);
+        builder
+            .insn(mov64_imm(R0, libbpf_sys::XDP_PASS as i32))
+            .insn(exit());
+        let program = builder.load().unwrap();
+        let prog_id = program.info().unwrap().id;
+        let xdp = match &program {
+            Program::XDP(xdp) => xdp,
+            _ => panic!("not an xdp program"),
+        };
+        let skb_mode = xdp::XdpFlags::Generic.bits(true);
+
+        let link = xdp.attach_xdp_link("lo", xdp::XdpFlags::Generic).unwrap();
+        assert_eq!(unsafe { xdp_prog_id("lo", skb_mode) }.unwrap(), prog_id);
+        assert!(xdp.attach_xdp_link("lo", xdp::XdpFlags::Generic).is_err());
+        drop(link);
+        assert_eq!(unsafe { xdp_prog_id("lo", skb_mode) }.unwrap(), 0);
+    }
+}
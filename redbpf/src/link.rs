@@ -0,0 +1,404 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Inspecting the BPF links of the system
+//!
+//! A BPF link keeps a program attached for as long as the link exists. Links
+//! pinned to BPF FS outlive the process that created them, so a restarting
+//! tool can look up the links it created before with [`links`] or
+//! [`Link::from_pin_file`] and find out what they attach which program to,
+//! instead of attaching its programs again.
+//!
+//! Programs attached by the `*_link` methods, e.g.,
+//! [`XDP::attach_xdp_link`](../struct.XDP.html#method.attach_xdp_link),
+//! are owned by a [`LinkGuard`] instead of the program. The guard detaches
+//! the program when it is dropped, so a program never stays attached to an
+//! interface or a probe by accident.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::link::{self, LinkTarget};
+//!
+//! for info in link::links().expect("error listing links") {
+//!     if let LinkTarget::Xdp { ifindex } = info.target {
+//!         println!("program {} is attached to ifindex {}", info.prog_id, ifindex);
+//!     }
+//! }
+//! ```
+use std::ffi::{CStr, CString};
+use std::io;
+use std::mem;
+use std::os::raw::c_char;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+
+use libbpf_sys::bpf_link_info;
+use tracing::{error, warn};
+
+use crate::{Error, KProbeAttachmentPoint, Result, TracePointAttachmentPoint};
+
+const BPF_LINK_TYPE_RAW_TRACEPOINT: u32 = 1;
+const BPF_LINK_TYPE_TRACING: u32 = 2;
+const BPF_LINK_TYPE_CGROUP: u32 = 3;
+const BPF_LINK_TYPE_ITER: u32 = 4;
+const BPF_LINK_TYPE_NETNS: u32 = 5;
+const BPF_LINK_TYPE_XDP: u32 = 6;
+const BPF_LINK_TYPE_PERF_EVENT: u32 = 7;
+
+/// What a link attaches its program to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkTarget {
+    /// A raw tracepoint of the name
+    RawTracepoint(String),
+    /// A kernel function or a BPF program, e.g., by `fentry` programs
+    Tracing {
+        attach_type: u32,
+        /// The id of the BTF object of the target, 0 for vmlinux BTF
+        target_obj_id: u32,
+        target_btf_id: u32,
+    },
+    /// A cgroup of the id
+    Cgroup { cgroup_id: u64, attach_type: u32 },
+    /// A BPF iterator
+    Iter,
+    /// A network namespace of the inode number, e.g., by `sk_lookup`
+    /// programs
+    Netns { netns_ino: u32, attach_type: u32 },
+    /// A network interface
+    Xdp { ifindex: u32 },
+    /// A perf event, e.g., a kprobe or a tracepoint
+    PerfEvent,
+    /// A link type unknown to redBPF
+    Unknown(u32),
+}
+
+/// The information of a BPF link
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub id: u32,
+    /// The id of the attached program
+    pub prog_id: u32,
+    pub target: LinkTarget,
+}
+
+/// An open BPF link
+///
+/// The link stays in the kernel after `Link` is dropped as long as it is
+/// pinned or referenced by another file descriptor.
+pub struct Link {
+    fd: RawFd,
+    info: LinkInfo,
+}
+
+impl Link {
+    /// Open the link of which id is `id`
+    pub fn from_id(id: u32) -> Result<Link> {
+        let fd = unsafe { libbpf_sys::bpf_link_get_fd_by_id(id) };
+        if fd < 0 {
+            error!(
+                "error on bpf_link_get_fd_by_id: {}",
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        Link::from_fd(fd)
+    }
+
+    /// Open the link pinned at `file` of BPF FS
+    pub fn from_pin_file(file: impl AsRef<Path>) -> Result<Link> {
+        let file = file.as_ref();
+        let cpathname = CString::new(file.to_str().unwrap())?;
+        let fd = unsafe { libbpf_sys::bpf_obj_get(cpathname.as_ptr()) };
+        if fd < 0 {
+            error!("error on bpf_obj_get: {}", io::Error::last_os_error());
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        Link::from_fd(fd)
+    }
+
+    fn from_fd(fd: RawFd) -> Result<Link> {
+        match link_info_by_fd(fd) {
+            Ok(info) => Ok(Link { fd, info }),
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    pub fn info(&self) -> &LinkInfo {
+        &self.info
+    }
+
+    /// Pin the link to `file` of BPF FS so that it outlives the process
+    pub fn pin(&self, file: impl AsRef<Path>) -> Result<()> {
+        let cpathname = CString::new(file.as_ref().to_str().unwrap())?;
+        if unsafe { libbpf_sys::bpf_obj_pin(self.fd, cpathname.as_ptr()) } != 0 {
+            error!("error on bpf_obj_pin: {}", io::Error::last_os_error());
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
+    /// Detach the program of the link
+    ///
+    /// The link is detached even if it is pinned or referenced by other file
+    /// descriptors. It is not removed but it stays defunct until it is
+    /// unpinned and closed.
+    pub fn detach(&self) -> Result<()> {
+        if unsafe { libbpf_sys::bpf_link_detach(self.fd) } != 0 {
+            error!("error on bpf_link_detach: {}", io::Error::last_os_error());
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Link {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = libc::close(self.fd);
+        }
+    }
+}
+
+/// A guard of an attached program
+///
+/// The program is detached when the guard is dropped: BPF links are
+/// detached and closed, perf events of kprobes and tracepoints are closed,
+/// and XDP programs attached through netlink are removed from the
+/// interface. Call [`forget`](#method.forget) to leave the program attached
+/// instead, e.g., after pinning the link with [`Link::pin`].
+pub struct LinkGuard {
+    inner: Option<GuardInner>,
+}
+
+enum GuardInner {
+    Link(Link),
+    KProbe(KProbeAttachmentPoint),
+    TracePoint(TracePointAttachmentPoint),
+    // attached through netlink since XDP links need Linux 5.9
+    Xdp {
+        interface: String,
+        flags: u32,
+        prog_id: u32,
+    },
+}
+
+impl LinkGuard {
+    pub(crate) fn from_link_fd(fd: RawFd) -> Result<LinkGuard> {
+        Ok(LinkGuard {
+            inner: Some(GuardInner::Link(Link::from_fd(fd)?)),
+        })
+    }
+
+    pub(crate) fn kprobe(ap: KProbeAttachmentPoint) -> LinkGuard {
+        LinkGuard {
+            inner: Some(GuardInner::KProbe(ap)),
+        }
+    }
+
+    pub(crate) fn trace_point(ap: TracePointAttachmentPoint) -> LinkGuard {
+        LinkGuard {
+            inner: Some(GuardInner::TracePoint(ap)),
+        }
+    }
+
+    pub(crate) fn xdp(interface: &str, flags: u32, prog_id: u32) -> LinkGuard {
+        LinkGuard {
+            inner: Some(GuardInner::Xdp {
+                interface: interface.to_string(),
+                flags,
+                prog_id,
+            }),
+        }
+    }
+
+    /// Return the BPF link of the attachment, `None` if the program is not
+    /// attached through a BPF link
+    pub fn link(&self) -> Option<&Link> {
+        match &self.inner {
+            Some(GuardInner::Link(link)) => Some(link),
+            _ => None,
+        }
+    }
+
+    /// Drop the guard without detaching the program
+    ///
+    /// BPF links are closed, so they stay attached only if they are pinned.
+    /// Perf events are leaked and stay attached until the process exits.
+    /// XDP programs attached through netlink stay attached until they are
+    /// removed from the interface.
+    pub fn forget(mut self) {
+        match self.inner.take() {
+            Some(GuardInner::Link(link)) => drop(link),
+            Some(inner) => mem::forget(inner),
+            None => {}
+        }
+    }
+}
+
+impl Drop for LinkGuard {
+    fn drop(&mut self) {
+        match self.inner.take() {
+            Some(GuardInner::Link(link)) => {
+                // detach even if the link is pinned or its fd is shared. It
+                // fails before Linux 5.9 where closing the last fd detaches
+                let _ = unsafe { libbpf_sys::bpf_link_detach(link.fd) };
+            }
+            Some(GuardInner::Xdp {
+                interface,
+                flags,
+                prog_id,
+            }) => {
+                // do not detach another program that replaced this one
+                match unsafe { crate::xdp_prog_id(&interface, flags) } {
+                    Ok(id) if id == prog_id => {
+                        if let Err(e) = unsafe { crate::detach_xdp(&interface, flags) } {
+                            warn!("error detaching xdp from interface {}: {:?}", interface, e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("error querying xdp of interface {}: {:?}", interface, e),
+                }
+            }
+            // the perf events are closed by dropping the attachment points
+            Some(GuardInner::KProbe(ap)) => drop(ap),
+            Some(GuardInner::TracePoint(ap)) => drop(ap),
+            None => {}
+        }
+    }
+}
+
+/// Return the information of all BPF links of the system
+///
+/// Links that are removed while they are enumerated are skipped. This
+/// requires `CAP_SYS_ADMIN`.
+pub fn links() -> Result<Vec<LinkInfo>> {
+    let mut infos = vec![];
+    let mut id = 0;
+    loop {
+        let mut next_id = 0;
+        if unsafe { libbpf_sys::bpf_link_get_next_id(id, &mut next_id) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::ENOENT) {
+                break;
+            }
+            error!("error on bpf_link_get_next_id: {}", err);
+            return Err(Error::IO(err));
+        }
+        id = next_id;
+        match Link::from_id(id) {
+            Ok(link) => infos.push(link.info().clone()),
+            Err(Error::IO(e)) if e.raw_os_error() == Some(libc::ENOENT) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(infos)
+}
+
+fn link_info_by_fd(fd: RawFd) -> Result<LinkInfo> {
+    unsafe {
+        let mut info = mem::zeroed::<bpf_link_info>();
+        get_info_by_fd(fd, &mut info)?;
+        // the name of the raw tracepoint is read with a buffer of its length
+        let mut tp_name = vec![];
+        if info.type_ == BPF_LINK_TYPE_RAW_TRACEPOINT {
+            let tp_name_len = info.__bindgen_anon_1.raw_tracepoint.tp_name_len;
+            if tp_name_len > 0 {
+                tp_name = vec![0 as c_char; tp_name_len as usize];
+                info = mem::zeroed();
+                info.__bindgen_anon_1.raw_tracepoint.tp_name = tp_name.as_mut_ptr() as u64;
+                info.__bindgen_anon_1.raw_tracepoint.tp_name_len = tp_name_len;
+                get_info_by_fd(fd, &mut info)?;
+            }
+        }
+        let target = match info.type_ {
+            BPF_LINK_TYPE_RAW_TRACEPOINT if !tp_name.is_empty() => LinkTarget::RawTracepoint(
+                CStr::from_ptr(tp_name.as_ptr())
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            BPF_LINK_TYPE_RAW_TRACEPOINT => LinkTarget::RawTracepoint(String::new()),
+            type_ => link_target(type_, &info),
+        };
+        Ok(LinkInfo {
+            id: info.id,
+            prog_id: info.prog_id,
+            target,
+        })
+    }
+}
+
+unsafe fn get_info_by_fd(fd: RawFd, info: &mut bpf_link_info) -> Result<()> {
+    let mut info_len = mem::size_of_val(info) as u32;
+    if libbpf_sys::bpf_obj_get_info_by_fd(fd, info as *mut _ as *mut _, &mut info_len) != 0 {
+        error!(
+            "error on bpf_obj_get_info_by_fd: {}",
+            io::Error::last_os_error()
+        );
+        return Err(Error::IO(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+// Decode the target of links other than raw tracepoints
+fn link_target(type_: u32, info: &bpf_link_info) -> LinkTarget {
+    let anon = &info.__bindgen_anon_1;
+    unsafe {
+        match type_ {
+            BPF_LINK_TYPE_TRACING => LinkTarget::Tracing {
+                attach_type: anon.tracing.attach_type,
+                target_obj_id: anon.tracing.target_obj_id,
+                target_btf_id: anon.tracing.target_btf_id,
+            },
+            BPF_LINK_TYPE_CGROUP => LinkTarget::Cgroup {
+                cgroup_id: anon.cgroup.cgroup_id,
+                attach_type: anon.cgroup.attach_type,
+            },
+            BPF_LINK_TYPE_ITER => LinkTarget::Iter,
+            BPF_LINK_TYPE_NETNS => LinkTarget::Netns {
+                netns_ino: anon.netns.netns_ino,
+                attach_type: anon.netns.attach_type,
+            },
+            BPF_LINK_TYPE_XDP => LinkTarget::Xdp {
+                ifindex: anon.xdp.ifindex,
+            },
+            BPF_LINK_TYPE_PERF_EVENT => LinkTarget::PerfEvent,
+            _ => LinkTarget::Unknown(type_),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_link_target() {
+        let mut info = unsafe { mem::zeroed::<bpf_link_info>() };
+        info.__bindgen_anon_1.xdp.ifindex = 2;
+        assert_eq!(
+            link_target(BPF_LINK_TYPE_XDP, &info),
+            LinkTarget::Xdp { ifindex: 2 }
+        );
+        info.__bindgen_anon_1.cgroup.cgroup_id = 7;
+        info.__bindgen_anon_1.cgroup.attach_type = libbpf_sys::BPF_CGROUP_SOCK_OPS;
+        assert_eq!(
+            link_target(BPF_LINK_TYPE_CGROUP, &info),
+            LinkTarget::Cgroup {
+                cgroup_id: 7,
+                attach_type: libbpf_sys::BPF_CGROUP_SOCK_OPS
+            }
+        );
+        assert_eq!(link_target(100, &info), LinkTarget::Unknown(100));
+    }
+}
@@ -10,13 +10,20 @@ use futures::prelude::*;
 use std::convert::AsRef;
 use std::fs;
 use std::io;
+use std::mem;
 use std::path::Path;
+use std::slice;
 
-use crate::load::map_io::PerfMessageStream;
+use libbpf_sys::BPF_ANY;
+use tracing::error;
+
+use crate::load::map_io::{PerfMessageStream, RingBufMessageStream};
+use crate::map_type::MapType;
 use crate::{cpus, Program};
 use crate::{
-    Error, KProbe, Map, Module, PerfMap, SkLookup, SocketFilter, StreamParser, StreamVerdict,
-    TaskIter, UProbe, XDP,
+    CgroupSkb, CgroupSock, Error, FEntry, KProbe, Lsm, Map, Module, PerfEvent, PerfMap, RingBuf,
+    SkLookup, SkReuseport, SockOps, SocketFilter, StreamParser, StreamVerdict, StructOps, TaskIter,
+    UProbe, XDP,
 };
 
 #[derive(Debug)]
@@ -24,6 +31,8 @@ pub enum LoaderError {
     FileError(io::Error),
     ParseError(Error),
     LoadError(String, Error),
+    MapInitError(String, Error),
+    GlobalVarError(String, Error),
 }
 
 /// High level API to load bpf programs.
@@ -35,7 +44,115 @@ impl Loader {
     /// This will parse `data` with `Module::parse()` and load all the programs
     /// present in the module.
     pub fn load(data: &[u8]) -> Result<Loaded, LoaderError> {
-        let mut module = Module::parse(&data).map_err(LoaderError::ParseError)?;
+        LoaderBuilder::new(data).load()
+    }
+
+    /// Loads the BPF programs included in `file`.
+    ///
+    /// See `load()`.
+    pub fn load_file<P: AsRef<Path>>(file: P) -> Result<Loaded, LoaderError> {
+        Loader::load(&fs::read(file).map_err(LoaderError::FileError)?)
+    }
+}
+
+// The entries to put in a map before programs are loaded
+struct MapInit {
+    name: String,
+    key_size: usize,
+    value_size: usize,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Builder to load BPF programs with maps initialized in advance.
+///
+/// Maps are populated right after they are created and before any program
+/// is loaded, so programs never see partially populated lookup tables.
+/// Maps that are read-only for programs, i.e., created with
+/// `BPF_F_RDONLY_PROG`, are frozen after they are populated. So is the map of
+/// `.rodata` after global variables are set.
+///
+/// # Example
+///
+/// ```no_run
+/// use redbpf::load::LoaderBuilder;
+/// let data = std::fs::read("probe.elf").unwrap();
+/// let loaded = LoaderBuilder::new(&data)
+///     .map_init("ports", vec![(80u16, 1u8), (443u16, 1u8)])
+///     .global_var("TARGET_PID", 1234u32)
+///     .load()
+///     .unwrap();
+/// ```
+pub struct LoaderBuilder<'a> {
+    data: &'a [u8],
+    map_inits: Vec<MapInit>,
+    // variable name => the bytes of its value
+    global_vars: Vec<(String, Vec<u8>)>,
+}
+
+impl<'a> LoaderBuilder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        LoaderBuilder {
+            data,
+            map_inits: Vec::new(),
+            global_vars: Vec::new(),
+        }
+    }
+
+    /// Sets the global variable `name` of programs to `value` before
+    /// programs are loaded.
+    ///
+    /// See [`Module::set_global_var`](../struct.Module.html#method.set_global_var).
+    pub fn global_var<T>(&mut self, name: &str, value: T) -> &mut Self {
+        self.global_vars.push((name.to_string(), to_bytes(&value)));
+        self
+    }
+
+    /// Puts `entries` in the map `name` before programs are loaded.
+    ///
+    /// Per-cpu maps can not be initialized this way since they hold a value
+    /// per CPU. Loading fails if the map `name` is a per-cpu map.
+    pub fn map_init<K: Clone, V: Clone>(
+        &mut self,
+        name: &str,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> &mut Self {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (to_bytes(&key), to_bytes(&value)))
+            .collect();
+        self.map_inits.push(MapInit {
+            name: name.to_string(),
+            key_size: mem::size_of::<K>(),
+            value_size: mem::size_of::<V>(),
+            entries,
+        });
+        self
+    }
+
+    /// Creates the maps, initializes them and loads all the programs.
+    pub fn load(&self) -> Result<Loaded, LoaderError> {
+        let mut module = Module::parse(self.data).map_err(LoaderError::ParseError)?;
+        for init in self.map_inits.iter() {
+            let map = module
+                .map(&init.name)
+                .ok_or_else(|| LoaderError::MapInitError(init.name.clone(), Error::Map))?;
+            init.apply(map)
+                .map_err(|e| LoaderError::MapInitError(init.name.clone(), e))?;
+        }
+        for (name, bytes) in self.global_vars.iter() {
+            module
+                .set_global_bytes(name, bytes)
+                .map_err(|e| LoaderError::GlobalVarError(name.clone(), e))?;
+        }
+        // programs see `.rodata` as constants only if it is frozen. The maps
+        // initialized by `map_init` are already frozen
+        for map in module.global_data_maps().filter(|m| {
+            m.config.map_flags & libbpf_sys::BPF_F_RDONLY_PROG != 0
+                && !self.map_inits.iter().any(|init| init.name == m.name)
+        }) {
+            map.freeze()
+                .map_err(|e| LoaderError::MapInitError(map.name.clone(), e))?;
+        }
         for program in module.programs.iter_mut() {
             program
                 .load(module.version, module.license.clone())
@@ -58,21 +175,71 @@ impl Loader {
                 tokio::spawn(fut);
             }
         }
+        // A ring buffer is shared by all CPUs so one stream reads all events
+        for m in module
+            .maps
+            .iter()
+            .filter(|m| m.kind == libbpf_sys::BPF_MAP_TYPE_RINGBUF)
+        {
+            let name = m.name.clone();
+            let ringbuf =
+                RingBuf::new(m).map_err(|e| LoaderError::MapInitError(name.clone(), e))?;
+            let stream = RingBufMessageStream::new(ringbuf);
+            let mut s = sender.clone();
+            let fut = stream.for_each(move |events| {
+                if !events.is_empty() {
+                    s.start_send((name.clone(), events)).unwrap();
+                }
+                future::ready(())
+            });
+            tokio::spawn(fut);
+        }
 
         Ok(Loaded {
             module,
             events: receiver,
         })
     }
+}
 
-    /// Loads the BPF programs included in `file`.
-    ///
-    /// See `load()`.
-    pub fn load_file<P: AsRef<Path>>(file: P) -> Result<Loaded, LoaderError> {
-        Loader::load(&fs::read(file).map_err(LoaderError::FileError)?)
+impl MapInit {
+    fn apply(&self, map: &Map) -> Result<(), Error> {
+        if matches!(MapType::from_u32(map.config.type_), Some(map_type) if map_type.is_per_cpu()) {
+            error!("per-cpu map `{}' can not be initialized", map.name);
+            return Err(Error::Map);
+        }
+        if self.key_size != map.config.key_size as usize
+            || self.value_size != map.config.value_size as usize
+        {
+            error!("key/value sizes of the entries and the map do not match");
+            return Err(Error::Map);
+        }
+        for (key, value) in self.entries.iter() {
+            let ret = unsafe {
+                libbpf_sys::bpf_map_update_elem(
+                    map.fd,
+                    key.as_ptr() as *const _,
+                    value.as_ptr() as *const _,
+                    BPF_ANY.into(),
+                )
+            };
+            if ret < 0 {
+                let err = io::Error::last_os_error();
+                error!("error on bpf_map_update_elem: {}", err);
+                return Err(Error::IO(err));
+            }
+        }
+        if map.config.map_flags & libbpf_sys::BPF_F_RDONLY_PROG != 0 {
+            map.freeze()?;
+        }
+        Ok(())
     }
 }
 
+fn to_bytes<T>(val: &T) -> Vec<u8> {
+    unsafe { slice::from_raw_parts(val as *const T as *const u8, mem::size_of::<T>()) }.to_vec()
+}
+
 /// The `Loaded` object returned by `load()`.
 pub struct Loaded {
     pub module: Module,
@@ -177,6 +344,66 @@ impl Loaded {
         self.module.sk_lookup_mut(name)
     }
 
+    pub fn sockops(&self) -> impl Iterator<Item = &SockOps> {
+        self.module.sockops()
+    }
+
+    pub fn sockops_iter_mut(&mut self) -> impl Iterator<Item = &mut SockOps> {
+        self.module.sockops_iter_mut()
+    }
+
+    pub fn sockops_mut(&mut self, name: &str) -> Option<&mut SockOps> {
+        self.module.sockops_mut(name)
+    }
+
+    pub fn cgroup_skbs(&self) -> impl Iterator<Item = &CgroupSkb> {
+        self.module.cgroup_skbs()
+    }
+
+    pub fn cgroup_skbs_mut(&mut self) -> impl Iterator<Item = &mut CgroupSkb> {
+        self.module.cgroup_skbs_mut()
+    }
+
+    pub fn cgroup_skb_mut(&mut self, name: &str) -> Option<&mut CgroupSkb> {
+        self.module.cgroup_skb_mut(name)
+    }
+
+    pub fn cgroup_socks(&self) -> impl Iterator<Item = &CgroupSock> {
+        self.module.cgroup_socks()
+    }
+
+    pub fn cgroup_socks_mut(&mut self) -> impl Iterator<Item = &mut CgroupSock> {
+        self.module.cgroup_socks_mut()
+    }
+
+    pub fn cgroup_sock_mut(&mut self, name: &str) -> Option<&mut CgroupSock> {
+        self.module.cgroup_sock_mut(name)
+    }
+
+    pub fn sk_reuseports(&self) -> impl Iterator<Item = &SkReuseport> {
+        self.module.sk_reuseports()
+    }
+
+    pub fn sk_reuseports_mut(&mut self) -> impl Iterator<Item = &mut SkReuseport> {
+        self.module.sk_reuseports_mut()
+    }
+
+    pub fn sk_reuseport_mut(&mut self, name: &str) -> Option<&mut SkReuseport> {
+        self.module.sk_reuseport_mut(name)
+    }
+
+    pub fn perf_events(&self) -> impl Iterator<Item = &PerfEvent> {
+        self.module.perf_events()
+    }
+
+    pub fn perf_events_mut(&mut self) -> impl Iterator<Item = &mut PerfEvent> {
+        self.module.perf_events_mut()
+    }
+
+    pub fn perf_event_mut(&mut self, name: &str) -> Option<&mut PerfEvent> {
+        self.module.perf_event_mut(name)
+    }
+
     pub fn task_iters(&self) -> impl Iterator<Item = &TaskIter> {
         self.module.task_iters()
     }
@@ -188,4 +415,40 @@ impl Loaded {
     pub fn task_iter_mut(&mut self, name: &str) -> Option<&mut TaskIter> {
         self.module.task_iter_mut(name)
     }
+
+    pub fn struct_ops(&self) -> impl Iterator<Item = &StructOps> {
+        self.module.struct_ops()
+    }
+
+    pub fn struct_ops_iter_mut(&mut self) -> impl Iterator<Item = &mut StructOps> {
+        self.module.struct_ops_iter_mut()
+    }
+
+    pub fn struct_ops_mut(&mut self, name: &str) -> Option<&mut StructOps> {
+        self.module.struct_ops_mut(name)
+    }
+
+    pub fn fentries(&self) -> impl Iterator<Item = &FEntry> {
+        self.module.fentries()
+    }
+
+    pub fn fentries_mut(&mut self) -> impl Iterator<Item = &mut FEntry> {
+        self.module.fentries_mut()
+    }
+
+    pub fn fentry_mut(&mut self, name: &str) -> Option<&mut FEntry> {
+        self.module.fentry_mut(name)
+    }
+
+    pub fn lsms(&self) -> impl Iterator<Item = &Lsm> {
+        self.module.lsms()
+    }
+
+    pub fn lsms_mut(&mut self) -> impl Iterator<Item = &mut Lsm> {
+        self.module.lsms_mut()
+    }
+
+    pub fn lsm_mut(&mut self, name: &str) -> Option<&mut Lsm> {
+        self.module.lsm_mut(name)
+    }
 }
@@ -8,64 +8,79 @@
 use futures::prelude::*;
 use std::os::unix::io::RawFd;
 use std::pin::Pin;
-use std::slice;
 use std::task::{Context, Poll};
 use tokio::io::unix::AsyncFd;
 use tokio::io::Interest;
 use tracing::error;
 
-use crate::{Event, PerfMap};
+use crate::perf_stream::PerfBufferReader;
+use crate::{PerfMap, RingBuf};
 
 pub struct PerfMessageStream {
-    poll: AsyncFd<RawFd>,
-    map: PerfMap,
+    reader: PerfBufferReader,
     name: String,
 }
 
 impl PerfMessageStream {
     pub fn new(name: String, map: PerfMap) -> Self {
-        let poll = AsyncFd::with_interest(map.fd, Interest::READABLE).unwrap();
-        PerfMessageStream { poll, map, name }
+        let reader = PerfBufferReader::new(map).unwrap();
+        PerfMessageStream { reader, name }
     }
+}
 
-    // Note that all messages should be consumed. Because ready flag is
-    // cleared, the remaining messages will not be read soon.
-    fn read_messages(&mut self) -> Vec<Box<[u8]>> {
-        let mut ret = Vec::new();
-        while let Some(ev) = self.map.read() {
-            match ev {
-                Event::Lost(lost) => {
-                    error!("Possibly lost {} samples for {}", lost.count, &self.name);
-                }
-                Event::Sample(sample) => {
-                    let msg = unsafe {
-                        slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize)
-                            .to_vec()
-                            .into_boxed_slice()
-                    };
-                    ret.push(msg);
+impl Stream for PerfMessageStream {
+    type Item = Vec<Box<[u8]>>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let mut messages = Vec::new();
+        match this.reader.poll_read(cx, |sample| {
+            messages.push(sample.to_vec().into_boxed_slice())
+        }) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(Err(e)) => {
+                // it should never happen
+                error!("PerfMessageStream error: {:?}", e);
+                Poll::Ready(None)
+            }
+            Poll::Ready(Ok(lost)) => {
+                if lost > 0 {
+                    error!("Possibly lost {} samples for {}", lost, &this.name);
                 }
-            };
+                Poll::Ready(Some(messages))
+            }
         }
+    }
+}
 
-        ret
+pub struct RingBufMessageStream {
+    poll: AsyncFd<RawFd>,
+    ringbuf: RingBuf,
+}
+
+impl RingBufMessageStream {
+    pub fn new(ringbuf: RingBuf) -> Self {
+        let poll = AsyncFd::with_interest(ringbuf.fd, Interest::READABLE).unwrap();
+        RingBufMessageStream { poll, ringbuf }
     }
 }
 
-impl Stream for PerfMessageStream {
+impl Stream for RingBufMessageStream {
     type Item = Vec<Box<[u8]>>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         match self.poll.poll_read_ready(cx) {
             Poll::Pending => return Poll::Pending,
             Poll::Ready(Err(e)) => {
                 // it should never happen
-                error!("PerfMessageStream error: {:?}", e);
+                error!("RingBufMessageStream error: {:?}", e);
                 return Poll::Ready(None);
             }
             Poll::Ready(Ok(mut rg)) => rg.clear_ready(),
         };
-        // Must read all messages because AsyncFdReadyGuard::clear_ready is
-        // already called.
-        Some(self.read_messages()).into()
+        // Records committed after the ready flag is cleared wake up the
+        // stream again, so reading all records now loses none.
+        let mut messages = Vec::new();
+        self.ringbuf
+            .consume(|record| messages.push(record.to_vec().into_boxed_slice()));
+        Some(messages).into()
     }
 }
@@ -0,0 +1,294 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Human readable representation of BPF map types and map flags
+//!
+//! The kernel reports map types and map flags as raw integers. [`MapType`]
+//! and [`MapFlags`] decode them and render them with the names used by the
+//! kernel UAPI headers.
+//!
+//! ```
+//! use redbpf::map_type::{MapFlags, MapType};
+//!
+//! assert_eq!(MapType::from_u32(9).unwrap().to_string(), "BPF_MAP_TYPE_LRU_HASH");
+//! assert_eq!(
+//!     MapFlags::from_bits(1 | 1 << 10).to_string(),
+//!     "BPF_F_NO_PREALLOC | BPF_F_MMAPABLE"
+//! );
+//! ```
+use std::fmt;
+
+macro_rules! map_types {
+    ($($variant:ident = $value:expr => $name:expr,)*) => {
+        /// The type of a BPF map, `enum bpf_map_type` of the kernel
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        #[repr(u32)]
+        pub enum MapType {
+            $($variant = $value,)*
+        }
+
+        impl MapType {
+            /// Decode the raw map type. `None` is returned if `value` is
+            /// not a known map type.
+            pub fn from_u32(value: u32) -> Option<MapType> {
+                match value {
+                    $($value => Some(MapType::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// The name of the map type defined by the kernel
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(MapType::$variant => $name,)*
+                }
+            }
+        }
+    };
+}
+
+map_types! {
+    Unspec = 0 => "BPF_MAP_TYPE_UNSPEC",
+    Hash = 1 => "BPF_MAP_TYPE_HASH",
+    Array = 2 => "BPF_MAP_TYPE_ARRAY",
+    ProgArray = 3 => "BPF_MAP_TYPE_PROG_ARRAY",
+    PerfEventArray = 4 => "BPF_MAP_TYPE_PERF_EVENT_ARRAY",
+    PerCpuHash = 5 => "BPF_MAP_TYPE_PERCPU_HASH",
+    PerCpuArray = 6 => "BPF_MAP_TYPE_PERCPU_ARRAY",
+    StackTrace = 7 => "BPF_MAP_TYPE_STACK_TRACE",
+    CgroupArray = 8 => "BPF_MAP_TYPE_CGROUP_ARRAY",
+    LruHash = 9 => "BPF_MAP_TYPE_LRU_HASH",
+    LruPerCpuHash = 10 => "BPF_MAP_TYPE_LRU_PERCPU_HASH",
+    LpmTrie = 11 => "BPF_MAP_TYPE_LPM_TRIE",
+    ArrayOfMaps = 12 => "BPF_MAP_TYPE_ARRAY_OF_MAPS",
+    HashOfMaps = 13 => "BPF_MAP_TYPE_HASH_OF_MAPS",
+    DevMap = 14 => "BPF_MAP_TYPE_DEVMAP",
+    SockMap = 15 => "BPF_MAP_TYPE_SOCKMAP",
+    CpuMap = 16 => "BPF_MAP_TYPE_CPUMAP",
+    XskMap = 17 => "BPF_MAP_TYPE_XSKMAP",
+    SockHash = 18 => "BPF_MAP_TYPE_SOCKHASH",
+    CgroupStorage = 19 => "BPF_MAP_TYPE_CGROUP_STORAGE",
+    ReuseportSockArray = 20 => "BPF_MAP_TYPE_REUSEPORT_SOCKARRAY",
+    PerCpuCgroupStorage = 21 => "BPF_MAP_TYPE_PERCPU_CGROUP_STORAGE",
+    Queue = 22 => "BPF_MAP_TYPE_QUEUE",
+    Stack = 23 => "BPF_MAP_TYPE_STACK",
+    SkStorage = 24 => "BPF_MAP_TYPE_SK_STORAGE",
+    DevMapHash = 25 => "BPF_MAP_TYPE_DEVMAP_HASH",
+    StructOps = 26 => "BPF_MAP_TYPE_STRUCT_OPS",
+    RingBuf = 27 => "BPF_MAP_TYPE_RINGBUF",
+    InodeStorage = 28 => "BPF_MAP_TYPE_INODE_STORAGE",
+    TaskStorage = 29 => "BPF_MAP_TYPE_TASK_STORAGE",
+    BloomFilter = 30 => "BPF_MAP_TYPE_BLOOM_FILTER",
+    UserRingBuf = 31 => "BPF_MAP_TYPE_USER_RINGBUF",
+    CgrpStorage = 32 => "BPF_MAP_TYPE_CGRP_STORAGE",
+    Arena = 33 => "BPF_MAP_TYPE_ARENA",
+}
+
+impl MapType {
+    /// Return true if maps of this type with `flags` allocate all of their
+    /// elements on creation
+    ///
+    /// Hash maps are preallocated unless `BPF_F_NO_PREALLOC` is set. LRU
+    /// hash maps and array-like maps are always preallocated. LPM tries and
+    /// local storages are never preallocated.
+    pub fn is_preallocated(&self, flags: MapFlags) -> bool {
+        use MapType::*;
+
+        match self {
+            Hash | PerCpuHash | HashOfMaps => !flags.contains(MapFlags::NO_PREALLOC),
+            LpmTrie | SkStorage | InodeStorage | TaskStorage | CgrpStorage => false,
+            _ => true,
+        }
+    }
+
+    /// Return true if maps of this type hold a value per possible CPU
+    ///
+    /// Lookups and updates of these maps from userspace transfer the values
+    /// of all CPUs at once, each rounded up to 8 bytes.
+    pub fn is_per_cpu(&self) -> bool {
+        use MapType::*;
+
+        matches!(
+            self,
+            PerCpuHash | PerCpuArray | LruPerCpuHash | PerCpuCgroupStorage
+        )
+    }
+
+    /// Check the preallocation flags of maps of this type
+    ///
+    /// `Err` describes a combination of the type and `flags` that the kernel
+    /// rejects, which is otherwise reported by the kernel as a bare
+    /// `EINVAL` or `ENOTSUPP`.
+    pub fn check_flags(&self, flags: MapFlags) -> Result<(), &'static str> {
+        use MapType::*;
+
+        let no_prealloc = flags.contains(MapFlags::NO_PREALLOC);
+        match self {
+            LruHash | LruPerCpuHash if no_prealloc => {
+                Err("LRU hash maps are always preallocated, BPF_F_NO_PREALLOC is not allowed")
+            }
+            Array | PerCpuArray if no_prealloc => {
+                Err("array maps are always preallocated, BPF_F_NO_PREALLOC is not allowed")
+            }
+            LpmTrie if !no_prealloc => Err("LPM trie maps require BPF_F_NO_PREALLOC"),
+            LruHash | LruPerCpuHash => Ok(()),
+            _ if flags.contains(MapFlags::NO_COMMON_LRU) => {
+                Err("BPF_F_NO_COMMON_LRU is only allowed for LRU hash maps")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for MapType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Flags of a BPF map, `map_flags` of `union bpf_attr`
+///
+/// Unknown bits are preserved and displayed as a hexadecimal number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct MapFlags(u32);
+
+const MAP_FLAG_NAMES: [(u32, &str); 19] = [
+    (1 << 0, "BPF_F_NO_PREALLOC"),
+    (1 << 1, "BPF_F_NO_COMMON_LRU"),
+    (1 << 2, "BPF_F_NUMA_NODE"),
+    (1 << 3, "BPF_F_RDONLY"),
+    (1 << 4, "BPF_F_WRONLY"),
+    (1 << 5, "BPF_F_STACK_BUILD_ID"),
+    (1 << 6, "BPF_F_ZERO_SEED"),
+    (1 << 7, "BPF_F_RDONLY_PROG"),
+    (1 << 8, "BPF_F_WRONLY_PROG"),
+    (1 << 9, "BPF_F_CLONE"),
+    (1 << 10, "BPF_F_MMAPABLE"),
+    (1 << 11, "BPF_F_PRESERVE_ELEMS"),
+    (1 << 12, "BPF_F_INNER_MAP"),
+    (1 << 13, "BPF_F_LINK"),
+    (1 << 14, "BPF_F_PATH_FD"),
+    (1 << 15, "BPF_F_VTYPE_BTF_OBJ_FD"),
+    (1 << 16, "BPF_F_TOKEN_FD"),
+    (1 << 17, "BPF_F_SEGV_ON_FAULT"),
+    (1 << 18, "BPF_F_NO_USER_CONV"),
+];
+
+impl MapFlags {
+    pub const NO_PREALLOC: MapFlags = MapFlags(1 << 0);
+    pub const NO_COMMON_LRU: MapFlags = MapFlags(1 << 1);
+    pub const NUMA_NODE: MapFlags = MapFlags(1 << 2);
+    pub const RDONLY: MapFlags = MapFlags(1 << 3);
+    pub const WRONLY: MapFlags = MapFlags(1 << 4);
+    pub const STACK_BUILD_ID: MapFlags = MapFlags(1 << 5);
+    pub const ZERO_SEED: MapFlags = MapFlags(1 << 6);
+    pub const RDONLY_PROG: MapFlags = MapFlags(1 << 7);
+    pub const WRONLY_PROG: MapFlags = MapFlags(1 << 8);
+    pub const CLONE: MapFlags = MapFlags(1 << 9);
+    pub const MMAPABLE: MapFlags = MapFlags(1 << 10);
+    pub const PRESERVE_ELEMS: MapFlags = MapFlags(1 << 11);
+    pub const INNER_MAP: MapFlags = MapFlags(1 << 12);
+
+    /// Create `MapFlags` from raw `map_flags`
+    pub fn from_bits(bits: u32) -> MapFlags {
+        MapFlags(bits)
+    }
+
+    /// Return raw `map_flags`
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Return true if all bits of `other` are set
+    pub fn contains(&self, other: MapFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Return the names of the known flags that are set
+    pub fn names(&self) -> impl Iterator<Item = &'static str> + '_ {
+        MAP_FLAG_NAMES
+            .iter()
+            .filter(move |(bit, _)| self.0 & bit != 0)
+            .map(|(_, name)| *name)
+    }
+}
+
+impl std::ops::BitOr for MapFlags {
+    type Output = MapFlags;
+
+    fn bitor(self, rhs: MapFlags) -> MapFlags {
+        MapFlags(self.0 | rhs.0)
+    }
+}
+
+impl fmt::Display for MapFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("0");
+        }
+        let known = MAP_FLAG_NAMES.iter().fold(0, |acc, (bit, _)| acc | bit);
+        let mut first = true;
+        for name in self.names() {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            f.write_str(name)?;
+            first = false;
+        }
+        let unknown = self.0 & !known;
+        if unknown != 0 {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{:#x}", unknown)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_map_type() {
+        assert_eq!(MapType::from_u32(1), Some(MapType::Hash));
+        assert_eq!(MapType::from_u32(27), Some(MapType::RingBuf));
+        assert_eq!(MapType::from_u32(1000), None);
+        assert_eq!(MapType::LruHash.to_string(), "BPF_MAP_TYPE_LRU_HASH");
+        assert!(MapType::LruPerCpuHash.is_per_cpu());
+        assert!(!MapType::LruHash.is_per_cpu());
+    }
+
+    #[test]
+    fn test_prealloc_flags() {
+        assert!(MapType::Hash.is_preallocated(MapFlags::default()));
+        assert!(!MapType::Hash.is_preallocated(MapFlags::NO_PREALLOC));
+        assert!(MapType::LruHash.is_preallocated(MapFlags::default()));
+        assert!(MapType::Hash.check_flags(MapFlags::NO_PREALLOC).is_ok());
+        assert!(MapType::LruHash.check_flags(MapFlags::NO_PREALLOC).is_err());
+        assert!(MapType::LruHash
+            .check_flags(MapFlags::NO_COMMON_LRU)
+            .is_ok());
+        assert!(MapType::Hash.check_flags(MapFlags::NO_COMMON_LRU).is_err());
+        assert!(MapType::Array.check_flags(MapFlags::NO_PREALLOC).is_err());
+        assert!(MapType::LpmTrie.check_flags(MapFlags::default()).is_err());
+    }
+
+    #[test]
+    fn test_map_flags() {
+        assert_eq!(MapFlags::from_bits(0).to_string(), "0");
+        assert_eq!(
+            (MapFlags::NO_PREALLOC | MapFlags::MMAPABLE).to_string(),
+            "BPF_F_NO_PREALLOC | BPF_F_MMAPABLE"
+        );
+        assert_eq!(
+            MapFlags::from_bits(1 << 7 | 1 << 31).to_string(),
+            "BPF_F_RDONLY_PROG | 0x80000000"
+        );
+    }
+}
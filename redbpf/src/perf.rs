@@ -53,11 +53,14 @@
 use crate::{Error, HashMap, Map, Result};
 use std::cell::RefCell;
 use std::ffi::CString;
+use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Write};
+use std::marker::PhantomData;
 use std::mem;
 use std::os::unix::io::RawFd;
-use std::ptr::null_mut;
+use std::path::Path;
+use std::ptr::{self, null_mut};
 use std::slice;
 use std::sync::atomic::{self, AtomicPtr, Ordering};
 
@@ -68,7 +71,35 @@ use libc::{
 
 use crate::sys::perf::*;
 
-unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> Result<RawFd> {
+/// When the kernel wakes up the consumer of a [`PerfMap`](struct.PerfMap.html)
+///
+/// Waking up the consumer on every event costs a wakeup and a `poll` per
+/// event. Under high throughput, waking it up after a batch of events costs
+/// less at the expense of latency. Events written since the last wakeup are
+/// not delivered until the next one, so a consumer that is woken up should
+/// read all available events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Wakeup {
+    /// Wake up the consumer after every `n` events
+    Events(u32),
+    /// Wake up the consumer once `n` bytes are written to the buffer. It
+    /// should be less than the size of the buffer.
+    Watermark(u32),
+}
+
+impl Default for Wakeup {
+    fn default() -> Self {
+        Wakeup::Events(1)
+    }
+}
+
+unsafe fn open_perf_buffer(
+    pid: i32,
+    cpu: i32,
+    group: RawFd,
+    flags: u32,
+    wakeup: Wakeup,
+) -> Result<RawFd> {
     let mut attr = mem::zeroed::<perf_event_attr>();
 
     attr.config = perf_sw_ids_PERF_COUNT_SW_BPF_OUTPUT as u64;
@@ -76,7 +107,13 @@ unsafe fn open_perf_buffer(pid: i32, cpu: i32, group: RawFd, flags: u32) -> Resu
     attr.type_ = perf_type_id_PERF_TYPE_SOFTWARE;
     attr.sample_type = perf_event_sample_format_PERF_SAMPLE_RAW as u64;
     attr.__bindgen_anon_1.sample_period = 1;
-    attr.__bindgen_anon_2.wakeup_events = 1;
+    match wakeup {
+        Wakeup::Events(n) => attr.__bindgen_anon_2.wakeup_events = n,
+        Wakeup::Watermark(n) => {
+            attr.set_watermark(1);
+            attr.__bindgen_anon_2.wakeup_watermark = n;
+        }
+    }
 
     let pfd = syscall(
         SYS_perf_event_open,
@@ -112,7 +149,8 @@ pub(crate) unsafe fn detach_perf_event(pfd: RawFd) -> Result<()> {
     Ok(())
 }
 
-unsafe fn perf_event_open_kprobe(name: &str, offset: u64, retprobe: bool) -> Result<RawFd> {
+// If `name` is `None`, `offset` is the kernel address to probe
+unsafe fn perf_event_open_kprobe(name: Option<&str>, offset: u64, retprobe: bool) -> Result<RawFd> {
     let mut attr = mem::zeroed::<perf_event_attr>();
     let type_ = fs::read_to_string("/sys/bus/event_source/devices/kprobe/type")
         .expect("Cannot read /sys/bus/event_source/devices/kprobe/type")
@@ -129,8 +167,8 @@ unsafe fn perf_event_open_kprobe(name: &str, offset: u64, retprobe: bool) -> Res
     }
     attr.size = mem::size_of_val(&attr) as u32;
     attr.type_ = type_;
-    let cname = CString::new(name)?;
-    attr.__bindgen_anon_3.config1 = cname.as_ptr() as u64;
+    let cname = name.map(CString::new).transpose()?;
+    attr.__bindgen_anon_3.config1 = cname.as_ref().map_or(0, |cname| cname.as_ptr() as u64);
     attr.__bindgen_anon_4.config2 = offset;
 
     let pfd = syscall(
@@ -149,11 +187,19 @@ unsafe fn perf_event_open_kprobe(name: &str, offset: u64, retprobe: bool) -> Res
 }
 
 pub(crate) unsafe fn open_kprobe_perf_event(name: &str, offset: u64) -> Result<RawFd> {
-    perf_event_open_kprobe(name, offset, false)
+    perf_event_open_kprobe(Some(name), offset, false)
 }
 
 pub(crate) unsafe fn open_kretprobe_perf_event(name: &str, offset: u64) -> Result<RawFd> {
-    perf_event_open_kprobe(name, offset, true)
+    perf_event_open_kprobe(Some(name), offset, true)
+}
+
+pub(crate) unsafe fn open_kprobe_addr_perf_event(addr: u64) -> Result<RawFd> {
+    perf_event_open_kprobe(None, addr, false)
+}
+
+pub(crate) unsafe fn open_kretprobe_addr_perf_event(addr: u64) -> Result<RawFd> {
+    perf_event_open_kprobe(None, addr, true)
 }
 
 unsafe fn perf_event_open_uprobe(
@@ -222,10 +268,80 @@ pub(crate) unsafe fn open_tracepoint_perf_event(category: &str, name: &str) -> R
     if tp_id < 0 {
         return Err(Error::BPF);
     }
+    open_tracepoint_id_perf_event(tp_id as u64)
+}
+
+const TRACEFS_ROOTS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+// group of kprobe events created by redbpf through `kprobe_events`
+const KPROBE_EVENT_GROUP: &str = "redbpf";
+
+fn tracefs_root() -> Result<&'static str> {
+    TRACEFS_ROOTS
+        .iter()
+        .find(|root| Path::new(root).join("kprobe_events").exists())
+        .copied()
+        .ok_or_else(|| Error::IO(io::Error::from(io::ErrorKind::NotFound)))
+}
+
+/// Create a kprobe event named `event` through the `kprobe_events` file of
+/// tracefs
+///
+/// `target` is a symbol name that may be qualified by a module name, e.g.,
+/// `e1000:e1000_xmit_frame`.
+pub(crate) fn create_kprobe_event(
+    event: &str,
+    target: &str,
+    offset: u64,
+    retprobe: bool,
+) -> Result<()> {
+    let root = tracefs_root()?;
+    let def = format!(
+        "{}:{}/{} {}+{}\n",
+        if retprobe { 'r' } else { 'p' },
+        KPROBE_EVENT_GROUP,
+        event,
+        target,
+        offset
+    );
+    // Each write to `kprobe_events` is a single command so the file must be
+    // appended to. Truncating it would remove all existing kprobe events.
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(Path::new(root).join("kprobe_events"))?;
+    file.write_all(def.as_bytes())?;
+    Ok(())
+}
+
+/// Remove the kprobe event created by `create_kprobe_event`
+pub(crate) fn remove_kprobe_event(event: &str) -> Result<()> {
+    let root = tracefs_root()?;
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(Path::new(root).join("kprobe_events"))?;
+    file.write_all(format!("-:{}/{}\n", KPROBE_EVENT_GROUP, event).as_bytes())?;
+    Ok(())
+}
+
+/// Open a perf event of the kprobe event created by `create_kprobe_event`
+pub(crate) unsafe fn open_kprobe_event_perf_event(event: &str) -> Result<RawFd> {
+    let root = tracefs_root()?;
+    let file = Path::new(root)
+        .join("events")
+        .join(KPROBE_EVENT_GROUP)
+        .join(event)
+        .join("id");
+    let tp_id = fs::read_to_string(&file)?
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| Error::IO(io::Error::from(io::ErrorKind::InvalidData)))?;
+    open_tracepoint_id_perf_event(tp_id)
+}
+
+unsafe fn open_tracepoint_id_perf_event(tp_id: u64) -> Result<RawFd> {
     let mut attr = mem::zeroed::<perf_event_attr>();
     attr.type_ = perf_type_id_PERF_TYPE_TRACEPOINT;
     attr.size = mem::size_of_val(&attr) as u32;
-    attr.config = tp_id as u64;
+    attr.config = tp_id;
 
     let pfd = syscall(
         SYS_perf_event_open,
@@ -242,6 +358,23 @@ pub(crate) unsafe fn open_tracepoint_perf_event(category: &str, name: &str) -> R
     }
 }
 
+/// Open the perf event described by `attr` for all processes on `cpu`
+pub(crate) unsafe fn open_cpu_perf_event(attr: &perf_event_attr, cpu: i32) -> Result<RawFd> {
+    let pfd = syscall(
+        SYS_perf_event_open,
+        attr as *const perf_event_attr,
+        -1, // pid
+        cpu,
+        -1, // group_fd
+        PERF_FLAG_FD_CLOEXEC,
+    );
+    if pfd < 0 {
+        Err(Error::IO(io::Error::last_os_error()))
+    } else {
+        Ok(pfd as RawFd)
+    }
+}
+
 #[repr(C)]
 pub struct Sample {
     header: perf_event_header,
@@ -261,6 +394,116 @@ pub enum Event<'a> {
     Lost(&'a LostSamples),
 }
 
+impl<'a> Event<'a> {
+    /// Interpret `buf` holding a whole perf record as an event. `None` is
+    /// returned if the record is neither a sample nor lost samples.
+    ///
+    /// # Safety
+    /// `buf` should start with a `perf_event_header` whose `size` is the
+    /// length of `buf`.
+    pub(crate) unsafe fn from_record(buf: &'a [u8]) -> Option<Event<'a>> {
+        let header = &*(buf.as_ptr() as *const perf_event_header);
+        match header.type_ {
+            perf_event_type_PERF_RECORD_SAMPLE => {
+                Some(Event::Sample(&*(buf.as_ptr() as *const Sample)))
+            }
+            perf_event_type_PERF_RECORD_LOST => {
+                Some(Event::Lost(&*(buf.as_ptr() as *const LostSamples)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the whole perf record of the event including its header
+    pub(crate) fn record(&self) -> &'a [u8] {
+        let header = match self {
+            Event::Sample(sample) => &sample.header,
+            Event::Lost(lost) => &lost.header,
+        };
+        unsafe { slice::from_raw_parts(header as *const _ as *const u8, header.size as usize) }
+    }
+}
+
+/// A source of perf events
+///
+/// This is implemented by [`PerfMap`](struct.PerfMap.html), which reads
+/// events written by BPF programs, and by
+/// [`PerfMapReplayer`](perf_record/struct.PerfMapReplayer.html), which replays
+/// recorded events. Code processing events can be written against this
+/// trait to be tested without the kernel.
+pub trait PerfEventSource {
+    /// Return the next event or `None` if no more events are available
+    fn read(&self) -> Option<Event<'_>>;
+
+    /// Return an iterator that decodes the available events into `T`
+    ///
+    /// See [`PerfMap::events`](struct.PerfMap.html#method.events).
+    fn events<T: Copy>(&self) -> PerfEvents<'_, T>
+    where
+        Self: Sized,
+    {
+        PerfEvents {
+            source: self,
+            _t: PhantomData,
+        }
+    }
+}
+
+/// Errors of decoding perf events into typed values
+///
+/// See [`PerfMap::events`](struct.PerfMap.html#method.events).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The sample is shorter than the type it is decoded into
+    TooShort { expected: usize, actual: usize },
+    /// The kernel dropped `count` samples because the buffer was full
+    Lost { count: u64 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort { expected, actual } => write!(
+                f,
+                "sample is too short: expected at least {} bytes but got {} bytes",
+                expected, actual
+            ),
+            DecodeError::Lost { count } => write!(f, "lost {} samples", count),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// An iterator that decodes perf events into values of `T`
+///
+/// It is created by [`PerfMap::events`](struct.PerfMap.html#method.events).
+pub struct PerfEvents<'a, T> {
+    source: &'a dyn PerfEventSource,
+    _t: PhantomData<T>,
+}
+
+impl<T: Copy> Iterator for PerfEvents<'_, T> {
+    type Item = std::result::Result<T, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(match self.source.read()? {
+            Event::Lost(lost) => Err(DecodeError::Lost { count: lost.count }),
+            Event::Sample(sample) => decode_sample(sample),
+        })
+    }
+}
+
+fn decode_sample<T: Copy>(sample: &Sample) -> std::result::Result<T, DecodeError> {
+    let expected = mem::size_of::<T>();
+    let actual = sample.size as usize;
+    if actual < expected {
+        return Err(DecodeError::TooShort { expected, actual });
+    }
+    // The size is checked above. Samples are not guaranteed to be aligned.
+    Ok(unsafe { ptr::read_unaligned(sample.data.as_ptr() as *const T) })
+}
+
 pub struct PerfMap {
     base_ptr: AtomicPtr<perf_event_mmap_page>,
     page_cnt: usize,
@@ -279,9 +522,57 @@ impl PerfMap {
         group: RawFd,
         flags: u32,
     ) -> Result<PerfMap> {
+        PerfMap::bind_with_wakeup(map, pid, cpu, page_cnt, group, flags, Wakeup::default())
+    }
+
+    /// Same as [`bind`](#method.bind) but the consumer is woken up as
+    /// specified by `wakeup` instead of on every event
+    ///
+    /// Consumers polling the fd of the `PerfMap` should read all events
+    /// available when woken up, e.g., until [`read`](#method.read) returns
+    /// `None`, since events left behind are not notified again.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::{Map, PerfMap, Wakeup};
+    ///
+    /// let mut map = Map::load("events", &vec![]).unwrap();
+    /// // wake up once 64 KiB of the 256 KiB buffer are filled
+    /// let perfmap =
+    ///     PerfMap::bind_with_wakeup(&mut map, -1, 0, 64, -1, 0, Wakeup::Watermark(64 * 1024))
+    ///         .unwrap();
+    /// ```
+    pub fn bind_with_wakeup(
+        map: &mut Map,
+        pid: i32,
+        cpu: i32,
+        page_cnt: usize,
+        group: RawFd,
+        flags: u32,
+        wakeup: Wakeup,
+    ) -> Result<PerfMap> {
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) as usize };
+        match wakeup {
+            Wakeup::Events(0) => {
+                return Err(Error::IO(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "wakeup events must be greater than 0",
+                )))
+            }
+            Wakeup::Watermark(n) if n == 0 || n as usize >= page_cnt * page_size => {
+                return Err(Error::IO(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "wakeup watermark {} is out of the buffer size {}",
+                        n,
+                        page_cnt * page_size
+                    ),
+                )))
+            }
+            _ => {}
+        }
         unsafe {
-            let fd = open_perf_buffer(pid, cpu, group, flags)?;
-            let page_size = sysconf(_SC_PAGESIZE) as usize;
+            let fd = open_perf_buffer(pid, cpu, group, flags, wakeup)?;
             let mmap_size = page_size * (page_cnt + 1);
             let base_ptr = mmap(
                 null_mut(),
@@ -314,6 +605,63 @@ impl PerfMap {
         }
     }
 
+    /// Return an iterator that decodes the available events into `T`
+    ///
+    /// Each sample is checked to be at least `size_of::<T>()` bytes long
+    /// before it is read as `T`. Lost samples are reported by
+    /// `DecodeError::Lost` so the iteration goes on after them. The
+    /// iterator ends when no more events are available.
+    ///
+    /// `T` should be a `#[repr(C)]` plain data type which is valid for any
+    /// bit pattern and matches the type that the BPF program writes.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use redbpf::{DecodeError, Map, PerfMap};
+    ///
+    /// #[repr(C)]
+    /// #[derive(Clone, Copy)]
+    /// struct Connection {
+    ///     pid: u32,
+    ///     port: u16,
+    /// }
+    ///
+    /// let mut map = Map::load("connections", &vec![]).unwrap();
+    /// let perfmap = PerfMap::bind(&mut map, -1, 0, 16, -1, 0).unwrap();
+    /// for event in perfmap.events::<Connection>() {
+    ///     match event {
+    ///         Ok(conn) => println!("{} connected to {}", conn.pid, conn.port),
+    ///         Err(DecodeError::Lost { count }) => eprintln!("lost {} events", count),
+    ///         Err(e) => eprintln!("{}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn events<T: Copy>(&self) -> PerfEvents<'_, T> {
+        PerfEvents {
+            source: self,
+            _t: PhantomData,
+        }
+    }
+
+    /// Return the size of the ring buffer in bytes
+    pub fn buffer_size(&self) -> usize {
+        self.page_cnt * self.page_size
+    }
+
+    /// Return the number of bytes written by the kernel but not read yet
+    ///
+    /// Comparing this with [`buffer_size`](#method.buffer_size) tells how far
+    /// the consumer is behind. The kernel drops samples once the pending
+    /// bytes reach the buffer size.
+    pub fn pending_bytes(&self) -> usize {
+        unsafe {
+            let header = self.base_ptr.load(Ordering::SeqCst);
+            let data_head = ptr::read_volatile(&(*header).data_head);
+            atomic::fence(Ordering::Acquire);
+            data_head.wrapping_sub((*header).data_tail) as usize
+        }
+    }
+
     pub fn read(&self) -> Option<Event<'_>> {
         unsafe {
             let header = self.base_ptr.load(Ordering::SeqCst);
@@ -350,19 +698,17 @@ impl PerfMap {
             atomic::fence(Ordering::SeqCst);
             (*header).data_tail += (*event).size as u64;
 
-            match (*event).type_ {
-                perf_event_type_PERF_RECORD_SAMPLE => {
-                    Some(Event::Sample(&*(buf.as_ptr() as *const Sample)))
-                }
-                perf_event_type_PERF_RECORD_LOST => {
-                    Some(Event::Lost(&*(buf.as_ptr() as *const LostSamples)))
-                }
-                _ => None,
-            }
+            Event::from_record(slice::from_raw_parts(buf.as_ptr(), buf.len()))
         }
     }
 }
 
+impl PerfEventSource for PerfMap {
+    fn read(&self) -> Option<Event<'_>> {
+        PerfMap::read(self)
+    }
+}
+
 impl Drop for PerfMap {
     fn drop(&mut self) {
         unsafe {
@@ -0,0 +1,337 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Recording and replaying perf event streams
+//!
+//! [`PerfMapRecorder`] writes the events read from a
+//! [`PerfMap`](../struct.PerfMap.html) to a file and [`PerfMapReplayer`]
+//! reads them back. Both the `PerfMap` and the replayer implement
+//! [`PerfEventSource`](../trait.PerfEventSource.html), so event handlers
+//! written against that trait can be tested deterministically with recorded
+//! events and without the kernel.
+//!
+//! A recording starts with the magic bytes `RBPFPERF` and a little-endian
+//! `u32` format version. Each record follows as a little-endian `u64`
+//! timestamp in nanoseconds since the UNIX epoch, a little-endian `u32`
+//! length and the raw perf record of that length, including its
+//! `perf_event_header`. Raw perf records are in host byte order, so
+//! recordings can only be replayed on hosts of the same endianness.
+//!
+//! # Example
+//! ```no_run
+//! use std::fs::File;
+//! use redbpf::perf_record::{PerfMapRecorder, PerfMapReplayer};
+//! use redbpf::{Map, PerfEventSource, PerfMap};
+//!
+//! fn count_events(source: &impl PerfEventSource) -> usize {
+//!     source.events::<u64>().filter(|event| event.is_ok()).count()
+//! }
+//!
+//! let mut map = Map::load("events", &vec![]).unwrap();
+//! let perfmap = PerfMap::bind(&mut map, -1, 0, 16, -1, 0).unwrap();
+//! // record the events while processing them
+//! let recorder = PerfMapRecorder::new(File::create("events.rec").unwrap()).unwrap();
+//! let recording = recorder.tee(&perfmap);
+//! count_events(&recording);
+//!
+//! // replay them later, e.g., in a test
+//! let replayer = PerfMapReplayer::open("events.rec").unwrap();
+//! count_events(&replayer);
+//! ```
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind, Read, Write};
+use std::mem;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tracing::error;
+
+use crate::sys::perf::perf_event_header;
+use crate::{Event, PerfEventSource};
+
+const MAGIC: &[u8; 8] = b"RBPFPERF";
+const FORMAT_VERSION: u32 = 1;
+
+/// Writes perf events to a recording
+pub struct PerfMapRecorder<W: Write> {
+    writer: W,
+    count: usize,
+}
+
+impl<W: Write> PerfMapRecorder<W> {
+    /// Start a recording written to `writer`
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        Ok(PerfMapRecorder { writer, count: 0 })
+    }
+
+    /// Write `event` with the current time as its timestamp
+    pub fn record(&mut self, event: &Event<'_>) -> io::Result<()> {
+        let timestamp_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        self.record_at(event, timestamp_ns)
+    }
+
+    /// Write `event` with `timestamp_ns` as its timestamp
+    pub fn record_at(&mut self, event: &Event<'_>, timestamp_ns: u64) -> io::Result<()> {
+        let record = event.record();
+        self.writer.write_all(&timestamp_ns.to_le_bytes())?;
+        self.writer
+            .write_all(&(record.len() as u32).to_le_bytes())?;
+        self.writer.write_all(record)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Return the number of recorded events
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Return an event source that records every event read from `source`
+    ///
+    /// Events are passed through unchanged. Errors writing the recording are
+    /// logged and do not interrupt reading.
+    pub fn tee<S: PerfEventSource>(self, source: &S) -> Recording<'_, S, W> {
+        Recording {
+            source,
+            recorder: RefCell::new(self),
+        }
+    }
+
+    /// Flush the recording and return the underlying writer
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// An event source that records the events of another source
+///
+/// It is created by [`PerfMapRecorder::tee`](struct.PerfMapRecorder.html#method.tee).
+pub struct Recording<'a, S: PerfEventSource, W: Write> {
+    source: &'a S,
+    recorder: RefCell<PerfMapRecorder<W>>,
+}
+
+impl<S: PerfEventSource, W: Write> Recording<'_, S, W> {
+    /// Stop recording and return the recorder
+    pub fn into_recorder(self) -> PerfMapRecorder<W> {
+        self.recorder.into_inner()
+    }
+}
+
+impl<S: PerfEventSource, W: Write> PerfEventSource for Recording<'_, S, W> {
+    fn read(&self) -> Option<Event<'_>> {
+        let event = self.source.read()?;
+        if let Err(e) = self.recorder.borrow_mut().record(&event) {
+            error!("error recording perf event: {}", e);
+        }
+        Some(event)
+    }
+}
+
+struct Record {
+    timestamp_ns: u64,
+    data: Vec<u8>,
+}
+
+/// Replays the perf events of a recording
+///
+/// Events are returned in the order they were recorded. Once all events are
+/// read, [`read`](../trait.PerfEventSource.html#tymethod.read) returns `None`
+/// until [`rewind`](#method.rewind) is called.
+pub struct PerfMapReplayer {
+    records: Vec<Record>,
+    next: Cell<usize>,
+}
+
+impl PerfMapReplayer {
+    /// Read a recording from `reader`
+    ///
+    /// An error of `ErrorKind::InvalidData` is returned if the recording is
+    /// malformed.
+    pub fn from_reader<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a perf event recording"));
+        }
+        let version = read_u32(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported recording format version {}",
+                version
+            )));
+        }
+
+        let mut records = vec![];
+        loop {
+            let mut timestamp = [0u8; 8];
+            match reader.read_exact(&mut timestamp) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let len = read_u32(&mut reader)? as usize;
+            if len < mem::size_of::<perf_event_header>() {
+                return Err(invalid_data("perf record is shorter than its header"));
+            }
+            let mut data = vec![0u8; len];
+            reader.read_exact(&mut data)?;
+            let header = unsafe { &*(data.as_ptr() as *const perf_event_header) };
+            if header.size as usize != len {
+                return Err(invalid_data("perf record size does not match its header"));
+            }
+            records.push(Record {
+                timestamp_ns: u64::from_le_bytes(timestamp),
+                data,
+            });
+        }
+
+        Ok(PerfMapReplayer {
+            records,
+            next: Cell::new(0),
+        })
+    }
+
+    /// Read the recording stored in `file`
+    pub fn open(file: impl AsRef<Path>) -> io::Result<Self> {
+        PerfMapReplayer::from_reader(BufReader::new(File::open(file)?))
+    }
+
+    /// Return the number of recorded events
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Return true if the recording has no events
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Return the timestamp of the event returned last, in nanoseconds since
+    /// the UNIX epoch
+    pub fn timestamp(&self) -> Option<u64> {
+        let next = self.next.get();
+        next.checked_sub(1)
+            .and_then(|i| self.records.get(i))
+            .map(|record| record.timestamp_ns)
+    }
+
+    /// Replay the recording from the start again
+    pub fn rewind(&self) {
+        self.next.set(0);
+    }
+}
+
+impl PerfEventSource for PerfMapReplayer {
+    fn read(&self) -> Option<Event<'_>> {
+        loop {
+            let record = self.records.get(self.next.get())?;
+            self.next.set(self.next.get() + 1);
+
+            // records are never modified once read, so the event borrows the
+            // record itself and stays valid across reads. `from_reader`
+            // checked that the record matches its header
+            let event = unsafe { Event::from_record(&record.data) };
+            // skip records of unknown types like `PerfMap` does
+            if event.is_some() {
+                return event;
+            }
+        }
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::sys::perf::perf_event_type_PERF_RECORD_SAMPLE;
+    use crate::DecodeError;
+    use std::ptr;
+
+    // a PERF_RECORD_SAMPLE carrying `value` as its raw data
+    fn sample_record(value: u32) -> Vec<u8> {
+        let size = (mem::size_of::<perf_event_header>() + 8) as u16;
+        let mut record = vec![];
+        record.extend_from_slice(&perf_event_type_PERF_RECORD_SAMPLE.to_ne_bytes());
+        record.extend_from_slice(&0u16.to_ne_bytes());
+        record.extend_from_slice(&size.to_ne_bytes());
+        record.extend_from_slice(&4u32.to_ne_bytes());
+        record.extend_from_slice(&value.to_ne_bytes());
+        record
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut recorder = PerfMapRecorder::new(vec![]).unwrap();
+        for (i, value) in [7u32, 42].iter().enumerate() {
+            let record = sample_record(*value);
+            let event = unsafe { Event::from_record(&record) }.unwrap();
+            recorder.record_at(&event, 100 + i as u64).unwrap();
+        }
+        assert_eq!(recorder.count(), 2);
+        let bytes = recorder.into_inner().unwrap();
+
+        let replayer = PerfMapReplayer::from_reader(&bytes[..]).unwrap();
+        assert_eq!(replayer.len(), 2);
+        let values: Vec<std::result::Result<u32, DecodeError>> = replayer.events::<u32>().collect();
+        assert_eq!(values, vec![Ok(7), Ok(42)]);
+        assert_eq!(replayer.timestamp(), Some(101));
+
+        replayer.rewind();
+        assert!(replayer.events::<u64>().all(|e| e
+            == Err(DecodeError::TooShort {
+                expected: 8,
+                actual: 4
+            })));
+    }
+
+    #[test]
+    fn test_events_outlive_next_read() {
+        let mut recorder = PerfMapRecorder::new(vec![]).unwrap();
+        for value in [7u32, 42].iter() {
+            let record = sample_record(*value);
+            let event = unsafe { Event::from_record(&record) }.unwrap();
+            recorder.record(&event).unwrap();
+        }
+        let bytes = recorder.into_inner().unwrap();
+
+        let replayer = PerfMapReplayer::from_reader(&bytes[..]).unwrap();
+        let first = replayer.read().unwrap();
+        let second = replayer.read().unwrap();
+        match (first, second) {
+            (Event::Sample(first), Event::Sample(second)) => unsafe {
+                assert_eq!(ptr::read_unaligned(first.data.as_ptr() as *const u32), 7);
+                assert_eq!(ptr::read_unaligned(second.data.as_ptr() as *const u32), 42);
+            },
+            _ => panic!("not samples"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_recording() {
+        let err = PerfMapReplayer::from_reader(&b"NOTPERF!\x01\0\0\0"[..])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
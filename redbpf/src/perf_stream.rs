@@ -0,0 +1,233 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Asynchronous Perf Event handling
+//!
+//! [`PerfMapStream`](struct.PerfMapStream.html) reads the samples of a
+//! `BPF_MAP_TYPE_PERF_EVENT_ARRAY` map as a `futures::Stream`, so that they
+//! can be consumed in a tokio application along with other I/O. It is
+//! enabled by the `async` feature.
+use futures::prelude::*;
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::slice;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tracing::error;
+
+use crate::{cpus, Error, Event, Map, PerfMap, Result};
+
+/// A perf buffer whose fd is registered with the reactor of the tokio
+/// runtime
+///
+/// It reads the samples of both `PerfMapStream` and the streams of perf maps
+/// that `load::Loader` spawns.
+pub(crate) struct PerfBufferReader {
+    // declared before `map` so that the fd is deregistered from the reactor
+    // before `PerfMap` closes it
+    poll: AsyncFd<RawFd>,
+    map: PerfMap,
+}
+
+impl PerfBufferReader {
+    pub(crate) fn new(map: PerfMap) -> io::Result<PerfBufferReader> {
+        let poll = AsyncFd::with_interest(map.fd, Interest::READABLE)?;
+        Ok(PerfBufferReader { poll, map })
+    }
+
+    /// Pass every sample in the buffer to `on_sample` once it is readable
+    ///
+    /// It returns the number of samples lost since the last read, or
+    /// `Poll::Pending` if the buffer is not readable yet, in which case the
+    /// task is woken up when it is. All samples are read after the ready
+    /// flag is cleared since the remaining ones would not be notified again.
+    pub(crate) fn poll_read(
+        &mut self,
+        cx: &mut Context,
+        mut on_sample: impl FnMut(&[u8]),
+    ) -> Poll<io::Result<u64>> {
+        match self.poll.poll_read_ready(cx) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Ready(Ok(mut rg)) => rg.clear_ready(),
+        }
+        let mut lost = 0;
+        while let Some(ev) = self.map.read() {
+            match ev {
+                Event::Lost(ev) => lost += ev.count,
+                Event::Sample(sample) => {
+                    let sample = unsafe {
+                        slice::from_raw_parts(sample.data.as_ptr(), sample.size as usize)
+                    };
+                    on_sample(sample);
+                }
+            }
+        }
+        Poll::Ready(Ok(lost))
+    }
+}
+
+/// A stream of the samples of a perf event array map
+///
+/// A perf buffer is bound to the map for every online CPU and its fd is
+/// registered with the reactor of the tokio runtime. The stream yields the
+/// samples of all CPUs, in order within a CPU. Samples dropped by the kernel
+/// because a buffer was full are counted by [`lost`](#method.lost).
+///
+/// # Example
+/// ```no_run
+/// use futures::stream::StreamExt;
+/// use redbpf::{Map, PerfMapStream};
+///
+/// # async fn run() {
+/// let mut map = Map::load("events", &vec![]).unwrap();
+/// let mut stream = PerfMapStream::new(&mut map, 16).unwrap();
+/// while let Some(event) = stream.next().await {
+///     println!("{} bytes, {} lost so far", event.len(), stream.lost());
+/// }
+/// # }
+/// ```
+pub struct PerfMapStream {
+    buffers: Vec<PerfBufferReader>,
+    pending: VecDeque<Vec<u8>>,
+    lost: u64,
+}
+
+impl PerfMapStream {
+    /// Bind perf buffers of `page_cnt` pages to `map` for all online CPUs
+    ///
+    /// `page_cnt` must be a power of two. This must be called within a tokio
+    /// runtime.
+    pub fn new(map: &mut Map, page_cnt: usize) -> Result<PerfMapStream> {
+        let mut buffers = Vec::new();
+        for cpu in cpus::get_online()? {
+            let perf_map = PerfMap::bind(map, -1, cpu, page_cnt, -1, 0)?;
+            let buffer = PerfBufferReader::new(perf_map).map_err(|e| {
+                error!("error registering perf buffer of cpu {}: {}", cpu, e);
+                Error::IO(e)
+            })?;
+            buffers.push(buffer);
+        }
+
+        Ok(PerfMapStream {
+            buffers,
+            pending: VecDeque::new(),
+            lost: 0,
+        })
+    }
+
+    /// Return the number of samples lost so far because the buffers were
+    /// full
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+}
+
+impl Stream for PerfMapStream {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some(sample) = this.pending.pop_front() {
+            return Poll::Ready(Some(sample));
+        }
+        for buffer in this.buffers.iter_mut() {
+            // Poll until pending so that every buffer wakes up the task on
+            // new samples
+            loop {
+                let pending = &mut this.pending;
+                match buffer.poll_read(cx, |sample| pending.push_back(sample.to_vec())) {
+                    Poll::Pending => break,
+                    Poll::Ready(Err(e)) => {
+                        // it should never happen
+                        error!("PerfMapStream error: {:?}", e);
+                        return Poll::Ready(None);
+                    }
+                    Poll::Ready(Ok(0)) => {}
+                    Poll::Ready(Ok(lost)) => {
+                        error!("Possibly lost {} samples", lost);
+                        this.lost += lost;
+                    }
+                }
+            }
+        }
+
+        match this.pending.pop_front() {
+            Some(sample) => Poll::Ready(Some(sample)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "kernel_tests")]
+    #[tokio::test]
+    async fn test_perf_map_stream() {
+        use crate::insn::*;
+        use crate::ProgramType;
+        use std::ptr;
+
+        let mut map = Map::create(
+            "events",
+            libbpf_sys::BPF_MAP_TYPE_PERF_EVENT_ARRAY,
+            4,
+            4,
+            cpus::get_possible().unwrap().len() as u32,
+            0,
+        )
+        .unwrap();
+        let mut stream = PerfMapStream::new(&mut map, 16).unwrap();
+
+        let mut builder = ProgramBuilder::new("output", ProgramType::SocketFilter);
+        builder
+            // output the current time to the buffer of the current cpu
+            .insn(mov64_reg(R6, R1))
+            .insn(call(libbpf_sys::BPF_FUNC_ktime_get_ns))
+            .insn(stx_mem(BPF_DW, R10, R0, -8))
+            .insn(mov64_reg(R1, R6))
+            .ld_map(R2, "events")
+            .insn(alu32_imm(BPF_MOV, R3, libbpf_sys::BPF_F_CURRENT_CPU as i32))
+            .insn(mov64_reg(R4, R10))
+            .insn(alu64_imm(BPF_ADD, R4, -8))
+            .insn(mov64_imm(R5, 8))
+            .insn(call(libbpf_sys::BPF_FUNC_perf_event_output))
+            .insn(mov64_imm(R0, 0))
+            .insn(exit())
+            .map("events", &map);
+        let program = builder.load().unwrap();
+        let prog_fd = program.fd().unwrap();
+        let packet = [0u8; 64];
+        for _ in 0..3 {
+            let (mut retval, mut duration) = (0, 0);
+            let ret = unsafe {
+                libbpf_sys::bpf_prog_test_run(
+                    prog_fd,
+                    1,
+                    packet.as_ptr() as *mut _,
+                    packet.len() as u32,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut retval,
+                    &mut duration,
+                )
+            };
+            assert_eq!(ret, 0);
+        }
+
+        let samples = stream.by_ref().take(3).collect::<Vec<_>>().await;
+        assert_eq!(samples.len(), 3);
+        // the kernel pads the samples to 8 bytes together with their u32 size
+        assert!(samples.iter().all(|sample| sample.len() >= 8));
+        assert_eq!(stream.lost(), 0);
+    }
+}
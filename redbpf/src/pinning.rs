@@ -0,0 +1,540 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Pinning namespaces
+//!
+//! When several independent BPF tools run on the same host, pinning all of
+//! their maps directly under `/sys/fs/bpf` easily leads to name collisions. A
+//! [`Pinner`] confines pinning and reopening of pinned maps to one directory of
+//! BPF FS, e.g., `/sys/fs/bpf/mytool`, so a tool never picks up the maps of
+//! another tool by accident.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::pinning::Pinner;
+//! use redbpf::ModuleBuilder;
+//! # static ELF: [u8; 128] = [0u8; 128];
+//!
+//! let pinner = Pinner::new("/sys/fs/bpf/mytool").expect("error on Pinner::new");
+//! let mut builder = ModuleBuilder::parse(&ELF).expect("error on ModuleBuilder::parse");
+//! // reuse `counts` if the previous run of the tool pinned it
+//! pinner
+//!     .reuse_maps(&mut builder, &["counts"])
+//!     .expect("error reusing pinned maps");
+//! let mut module = builder.to_module().expect("error on ModuleBuilder::to_module");
+//! let counts = module.map_mut("counts").expect("map not found");
+//! if !pinner.is_pinned("counts") {
+//!     pinner.pin_map(counts, "counts").expect("error on pinning");
+//! }
+//! ```
+//!
+//! # Versioning
+//!
+//! The layout of a map may change when a tool is upgraded, and then the map
+//! pinned by the previous version of the tool can not be reused. Maps pinned
+//! by [`Pinner::pin_map_versioned`] carry a layout version that is chosen by
+//! the tool. On the next run, [`Pinner::reuse_map_versioned`] reuses the map
+//! only if the version matches, and [`Pinner::migrate_map`] moves the entries
+//! of an outdated map into the new map through a user-provided callback.
+//!
+//! ```no_run
+//! use redbpf::pinning::{PinStatus, Pinner};
+//! use redbpf::{HashMap, ModuleBuilder};
+//! # static ELF: [u8; 128] = [0u8; 128];
+//!
+//! // bump this whenever the layout of `counts` changes
+//! const COUNTS_VERSION: u32 = 2;
+//!
+//! let pinner = Pinner::new("/sys/fs/bpf/mytool").unwrap();
+//! let mut builder = ModuleBuilder::parse(&ELF).unwrap();
+//! let status = pinner
+//!     .reuse_map_versioned(&mut builder, "counts", COUNTS_VERSION)
+//!     .unwrap();
+//! let mut module = builder.to_module().unwrap();
+//! let counts = module.map_mut("counts").unwrap();
+//! match status {
+//!     PinStatus::Compatible => {}
+//!     PinStatus::NotPinned => pinner
+//!         .pin_map_versioned(counts, "counts", COUNTS_VERSION)
+//!         .unwrap(),
+//!     PinStatus::Outdated(_) => pinner
+//!         .migrate_map(counts, "counts", COUNTS_VERSION, |old, new, _old_version| {
+//!             // version 1 had u32 values, version 2 has u64 values
+//!             let old = HashMap::<u32, u32>::new(old)?;
+//!             let new = HashMap::<u32, u64>::new(new)?;
+//!             for (k, v) in old.iter() {
+//!                 new.set(k, v as u64);
+//!             }
+//!             Ok(())
+//!         })
+//!         .unwrap(),
+//! }
+//! ```
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::mem;
+use std::path::{Component, Path, PathBuf};
+
+use tracing::{debug, error};
+
+use crate::{check_bpf_fs, Array, Error, Map, ModuleBuilder, Result};
+
+// Layout versions are pinned next to the maps as single entry arrays named
+// `<name>.version`
+const VERSION_SUFFIX: &str = ".version";
+// Migrated maps are pinned as `<name>.migrating` before they replace the
+// outdated map
+const MIGRATING_SUFFIX: &str = ".migrating";
+
+/// Status of a versioned pinned map
+///
+/// See [`Pinner::reuse_map_versioned`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinStatus {
+    /// The map is not pinned
+    NotPinned,
+    /// The map is pinned with the expected version
+    Compatible,
+    /// The map is pinned with another version, or without version
+    Outdated(Option<u32>),
+}
+
+/// Pins maps under a namespace directory of BPF FS
+///
+/// All paths handled by `Pinner` are resolved relative to its root
+/// directory. Names that would escape the root directory, e.g. names
+/// containing `/` or `..`, are rejected.
+#[derive(Debug, Clone)]
+pub struct Pinner {
+    root: PathBuf,
+}
+
+impl Pinner {
+    /// Create a `Pinner` whose namespace is the directory `root`
+    ///
+    /// `root` must be an absolute path on BPF FS. The directory is created
+    /// if it does not exist.
+    pub fn new(root: impl AsRef<Path>) -> Result<Pinner> {
+        let root = root.as_ref();
+        if !root.is_absolute() {
+            error!("pinning namespace should be an absolute path: {:?}", root);
+            return Err(Error::IO(io::Error::from(ErrorKind::InvalidInput)));
+        }
+        if root
+            .components()
+            .any(|comp| matches!(comp, Component::ParentDir | Component::CurDir))
+        {
+            error!("pinning namespace should be a normalized path: {:?}", root);
+            return Err(Error::IO(io::Error::from(ErrorKind::InvalidInput)));
+        }
+        check_bpf_fs(root)?;
+        fs::create_dir_all(root)?;
+        debug!("pinning namespace: {:?}", root);
+        Ok(Pinner {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// Return the root directory of the namespace
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Return the path that an object named `name` is pinned at
+    ///
+    /// `name` should be a single path component. An error is returned if
+    /// `name` is empty or contains `/`, or if `name` is `.` or `..`.
+    pub fn path(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            error!(
+                "invalid name in pinning namespace {:?}: `{}'",
+                self.root, name
+            );
+            return Err(Error::IO(io::Error::from(ErrorKind::InvalidInput)));
+        }
+        Ok(self.root.join(name))
+    }
+
+    /// Return true if an object named `name` is pinned in the namespace
+    pub fn is_pinned(&self, name: &str) -> bool {
+        self.path(name).map(|path| path.exists()).unwrap_or(false)
+    }
+
+    /// Pin `map` as `name` in the namespace
+    ///
+    /// This fails if `name` is already pinned in the namespace. Pinned maps
+    /// of other namespaces are never overwritten.
+    pub fn pin_map(&self, map: &mut Map, name: &str) -> Result<()> {
+        let path = self.path(name)?;
+        map.pin(path)
+    }
+
+    /// Open the map pinned as `name` in the namespace
+    pub fn open_map(&self, name: &str) -> Result<Map> {
+        let path = self.path(name)?;
+        Map::from_pin_file(path)
+    }
+
+    /// Unpin the object pinned as `name` in the namespace
+    ///
+    /// The layout version of the map is unpinned too if there is.
+    pub fn unpin(&self, name: &str) -> Result<()> {
+        let path = self.path(name)?;
+        fs::remove_file(path)?;
+        let version_path = self.version_path(name)?;
+        if version_path.exists() {
+            fs::remove_file(version_path)?;
+        }
+        Ok(())
+    }
+
+    /// Return the names of all objects pinned in the namespace
+    pub fn pinned_names(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if !name.ends_with(VERSION_SUFFIX) && !name.ends_with(MIGRATING_SUFFIX) {
+                    names.push(name);
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Replace maps of `builder` with the maps pinned in the namespace
+    ///
+    /// For each of `map_names`, if a map with the same name is pinned in the
+    /// namespace, it replaces the map of `builder`. Maps that are not pinned
+    /// are left untouched so they are created when the module is
+    /// built. Only the namespace is looked up, so maps pinned by other tools
+    /// are never reused.
+    ///
+    /// Returns the names of the maps that were replaced.
+    pub fn reuse_maps(
+        &self,
+        builder: &mut ModuleBuilder,
+        map_names: &[&str],
+    ) -> Result<Vec<String>> {
+        let mut replaced = vec![];
+        for name in map_names {
+            if !self.is_pinned(name) {
+                continue;
+            }
+            let map = self.open_map(name)?;
+            builder.replace_map(name, map)?;
+            replaced.push(name.to_string());
+        }
+        Ok(replaced)
+    }
+
+    fn version_path(&self, name: &str) -> Result<PathBuf> {
+        self.path(&format!("{}{}", name, VERSION_SUFFIX))
+    }
+
+    /// Pin `map` as `name` in the namespace with its layout `version`
+    ///
+    /// Like [`pin_map`](#method.pin_map), this fails if `name` is already
+    /// pinned in the namespace.
+    pub fn pin_map_versioned(&self, map: &mut Map, name: &str, version: u32) -> Result<()> {
+        let version_path = self.version_path(name)?;
+        self.pin_map(map, name)?;
+        let ret = Map::create(
+            "pin_version",
+            libbpf_sys::BPF_MAP_TYPE_ARRAY,
+            mem::size_of::<u32>() as u32,
+            mem::size_of::<u32>() as u32,
+            1,
+            0,
+        )
+        .and_then(|mut version_map| {
+            Array::<u32>::new(&version_map)?.set(0, version)?;
+            if version_path.exists() {
+                // left behind if the map was unpinned by `Map::unpin`
+                fs::remove_file(&version_path)?;
+            }
+            version_map.pin(&version_path)
+        });
+        if ret.is_err() {
+            error!("error pinning the version of `{}'", name);
+            let _ = map.unpin();
+        }
+        ret
+    }
+
+    /// Return the layout version of the map pinned as `name`
+    ///
+    /// `None` is returned if the map was pinned without version.
+    pub fn map_version(&self, name: &str) -> Result<Option<u32>> {
+        let version_path = self.version_path(name)?;
+        if !version_path.exists() {
+            return Ok(None);
+        }
+        let version_map = Map::from_pin_file(version_path)?;
+        let version = Array::<u32>::new(&version_map)?.get(0);
+        Ok(version)
+    }
+
+    /// Check the layout version of the map pinned as `name` against `version`
+    pub fn version_status(&self, name: &str, version: u32) -> Result<PinStatus> {
+        if !self.is_pinned(name) {
+            return Ok(PinStatus::NotPinned);
+        }
+        match self.map_version(name)? {
+            Some(pinned) if pinned == version => Ok(PinStatus::Compatible),
+            pinned => Ok(PinStatus::Outdated(pinned)),
+        }
+    }
+
+    /// Replace the map `name` of `builder` with the pinned map if their
+    /// layout versions match
+    ///
+    /// The map is replaced only if the status is `PinStatus::Compatible`.
+    /// Otherwise `builder` is left untouched so a new map is created when the
+    /// module is built. Then the new map should be pinned by
+    /// [`pin_map_versioned`](#method.pin_map_versioned) if the status is
+    /// `PinStatus::NotPinned`, or populated from the outdated map by
+    /// [`migrate_map`](#method.migrate_map).
+    pub fn reuse_map_versioned(
+        &self,
+        builder: &mut ModuleBuilder,
+        name: &str,
+        version: u32,
+    ) -> Result<PinStatus> {
+        let status = self.version_status(name, version)?;
+        if status == PinStatus::Compatible {
+            let map = self.open_map(name)?;
+            builder.replace_map(name, map)?;
+        }
+        Ok(status)
+    }
+
+    /// Replace the map `name` of `builder` with the pinned map, failing if
+    /// their layout versions differ
+    ///
+    /// This is for tools that can not migrate their maps. The error is
+    /// logged with the pinned path so that operators know what to remove.
+    pub fn require_map_version(
+        &self,
+        builder: &mut ModuleBuilder,
+        name: &str,
+        version: u32,
+    ) -> Result<PinStatus> {
+        let status = self.reuse_map_versioned(builder, name, version)?;
+        if let PinStatus::Outdated(pinned) = status {
+            error!(
+                "map `{}' is pinned with layout version {:?} but version {} is expected. \
+                 remove {:?} to start over or migrate the map with Pinner::migrate_map",
+                name,
+                pinned,
+                version,
+                self.path(name)?
+            );
+            return Err(Error::Map);
+        }
+        Ok(status)
+    }
+
+    /// Migrate the outdated map pinned as `name` into `new`
+    ///
+    /// `migrate` is called with the pinned map, `new` and the version of the
+    /// pinned map, and it should read entries of the pinned map and populate
+    /// `new` with them. If it succeeds, `new` is pinned with `version` and
+    /// then replaces the pinned map as `name`. If migrating or pinning fails,
+    /// the pinned map is left untouched.
+    pub fn migrate_map<F>(&self, new: &mut Map, name: &str, version: u32, migrate: F) -> Result<()>
+    where
+        F: FnOnce(&Map, &Map, Option<u32>) -> Result<()>,
+    {
+        let old_version = self.map_version(name)?;
+        let old = self.open_map(name)?;
+        debug!(
+            "migrating map `{}' from version {:?} to {}",
+            name, old_version, version
+        );
+        migrate(&old, new, old_version)?;
+
+        // renaming replaces the old pin atomically, so the old map stays
+        // pinned until the new one is pinned
+        let staging = format!("{}{}", name, MIGRATING_SUFFIX);
+        if self.is_pinned(&staging) {
+            // left behind by an interrupted migration
+            self.unpin(&staging)?;
+        }
+        self.pin_map_versioned(new, &staging, version)?;
+        let path = self.path(name)?;
+        if let Err(e) = fs::rename(self.path(&staging)?, &path) {
+            error!("error replacing pinned map `{}': {}", name, e);
+            let _ = new.unpin();
+            let _ = fs::remove_file(self.version_path(&staging)?);
+            return Err(Error::IO(e));
+        }
+        new.pin_file = Some(Box::from(path.as_path()));
+        fs::rename(self.version_path(&staging)?, self.version_path(name)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path_stays_in_namespace() {
+        let pinner = Pinner {
+            root: PathBuf::from("/sys/fs/bpf/mytool"),
+        };
+        assert_eq!(
+            pinner.path("counts").unwrap(),
+            Path::new("/sys/fs/bpf/mytool/counts")
+        );
+        for name in &["", ".", "..", "a/b", "../counts"] {
+            assert!(pinner.path(name).is_err(), "{:?}", name);
+        }
+        assert!(!pinner.is_pinned(".."));
+        assert!(Pinner::new("mytool").is_err());
+        assert!(Pinner::new("/sys/fs/bpf/../mytool").is_err());
+    }
+
+    // A namespace of BPF FS that is removed on drop
+    #[cfg(feature = "kernel_tests")]
+    struct TempNamespace(Pinner);
+
+    #[cfg(feature = "kernel_tests")]
+    impl TempNamespace {
+        fn new(name: &str) -> TempNamespace {
+            let root = format!("/sys/fs/bpf/redbpf_test_{}_{}", name, std::process::id());
+            TempNamespace(Pinner::new(root).unwrap())
+        }
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    impl Drop for TempNamespace {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.0.root());
+        }
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_pin_and_reopen() {
+        use crate::HashMap;
+
+        let ns = TempNamespace::new("pin_and_reopen");
+        let pinner = &ns.0;
+        assert!(pinner.root().is_dir());
+        assert!(!pinner.is_pinned("counts"));
+
+        let mut map = Map::create("counts", libbpf_sys::BPF_MAP_TYPE_HASH, 4, 8, 16, 0).unwrap();
+        HashMap::<u32, u64>::new(&map).unwrap().set(1, 10);
+        pinner.pin_map(&mut map, "counts").unwrap();
+        assert!(pinner.is_pinned("counts"));
+        // a name is pinned only once
+        let mut other = Map::create("counts", libbpf_sys::BPF_MAP_TYPE_HASH, 4, 8, 16, 0).unwrap();
+        assert!(pinner.pin_map(&mut other, "counts").is_err());
+        drop(map);
+
+        let reopened = pinner.open_map("counts").unwrap();
+        assert_eq!(
+            HashMap::<u32, u64>::new(&reopened).unwrap().get(1),
+            Some(10)
+        );
+        assert_eq!(pinner.pinned_names().unwrap(), vec!["counts".to_string()]);
+
+        pinner.unpin("counts").unwrap();
+        assert!(!pinner.is_pinned("counts"));
+        assert!(pinner.open_map("counts").is_err());
+        assert!(pinner.pinned_names().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_version_mismatch() {
+        use crate::HashMap;
+
+        let ns = TempNamespace::new("version_mismatch");
+        let pinner = &ns.0;
+        let create = || Map::create("counts", libbpf_sys::BPF_MAP_TYPE_HASH, 4, 8, 16, 0).unwrap();
+        assert_eq!(
+            pinner.version_status("counts", 1).unwrap(),
+            PinStatus::NotPinned
+        );
+
+        let mut v1 = create();
+        HashMap::<u32, u64>::new(&v1).unwrap().set(1, 10);
+        pinner.pin_map_versioned(&mut v1, "counts", 1).unwrap();
+        assert_eq!(pinner.map_version("counts").unwrap(), Some(1));
+        assert_eq!(
+            pinner.version_status("counts", 1).unwrap(),
+            PinStatus::Compatible
+        );
+        assert_eq!(
+            pinner.version_status("counts", 2).unwrap(),
+            PinStatus::Outdated(Some(1))
+        );
+        // the version is not listed as a pinned object
+        assert_eq!(pinner.pinned_names().unwrap(), vec!["counts".to_string()]);
+
+        // the outdated map is not reused
+        let elf = include_bytes!("../testdata/pinned_map.o");
+        let mut builder = ModuleBuilder::parse(elf).unwrap();
+        assert_eq!(
+            pinner
+                .reuse_map_versioned(&mut builder, "counts", 2)
+                .unwrap(),
+            PinStatus::Outdated(Some(1))
+        );
+        assert!(pinner
+            .require_map_version(&mut builder, "counts", 2)
+            .is_err());
+
+        // a failed migration leaves the pinned map untouched
+        let mut v2 = create();
+        assert!(pinner
+            .migrate_map(&mut v2, "counts", 2, |_, _, _| Err(Error::Map))
+            .is_err());
+        assert_eq!(pinner.map_version("counts").unwrap(), Some(1));
+
+        // so does a failure to pin the new map
+        let mut pinned = create();
+        pinner.pin_map(&mut pinned, "other").unwrap();
+        assert!(pinner
+            .migrate_map(&mut pinned, "counts", 2, |_, _, _| Ok(()))
+            .is_err());
+        pinner.unpin("other").unwrap();
+        assert_eq!(pinner.map_version("counts").unwrap(), Some(1));
+        let old = pinner.open_map("counts").unwrap();
+        assert_eq!(HashMap::<u32, u64>::new(&old).unwrap().get(1), Some(10));
+
+        pinner
+            .migrate_map(&mut v2, "counts", 2, |old, new, old_version| {
+                assert_eq!(old_version, Some(1));
+                let old = HashMap::<u32, u64>::new(old)?;
+                let new = HashMap::<u32, u64>::new(new)?;
+                for (k, v) in old.iter() {
+                    new.set(k, v * 2);
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            pinner.version_status("counts", 2).unwrap(),
+            PinStatus::Compatible
+        );
+        let migrated = pinner.open_map("counts").unwrap();
+        assert_eq!(
+            HashMap::<u32, u64>::new(&migrated).unwrap().get(1),
+            Some(20)
+        );
+        assert_eq!(pinner.pinned_names().unwrap(), vec!["counts".to_string()]);
+        // `v2` knows where it is pinned now
+        v2.unpin().unwrap();
+        assert!(!pinner.is_pinned("counts"));
+    }
+}
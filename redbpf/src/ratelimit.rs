@@ -0,0 +1,71 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Configuration of rate limiters of BPF programs
+//!
+//! BPF programs limit the rate of events per key with
+//! [`redbpf_probes::maps::RateLimiter`](../../redbpf_probes/maps/struct.RateLimiter.html),
+//! which reads its rate from an `Array<RateLimit>` map. [`RateLimitConfig`]
+//! sets the rate from userspace, so it can be changed while the programs are
+//! running.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::load::Loader;
+//! use redbpf::ratelimit::{RateLimit, RateLimitConfig};
+//!
+//! let loaded = Loader::load_file("ratelimit.elf").expect("error loading probe");
+//! let config = RateLimitConfig::new(loaded.map("LIMIT").expect("map not found"))
+//!     .expect("error creating RateLimitConfig");
+//! // 100 events per second with bursts of up to 500 events
+//! config.set(RateLimit { rate: 100, burst: 500 }).unwrap();
+//! ```
+use crate::{Array, Map, Result};
+
+/// Rate of a rate limiter
+///
+/// `rate` events per second are allowed on average, and up to `burst` events
+/// are allowed at once after a quiet period. The layout is the same as
+/// `redbpf_probes::maps::RateLimit`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+/// Userspace side of an `Array<RateLimit>` map
+pub struct RateLimitConfig<'a> {
+    array: Array<'a, RateLimit>,
+}
+
+impl<'a> RateLimitConfig<'a> {
+    /// Create `RateLimitConfig` from the `Array<RateLimit>` map `base`
+    pub fn new(base: &'a Map) -> Result<RateLimitConfig<'a>> {
+        Ok(RateLimitConfig {
+            array: Array::new(base)?,
+        })
+    }
+
+    /// Set the rate of the rate limiter at index 0
+    ///
+    /// Until the rate is set, the array holds zeroes and BPF programs allow
+    /// no events.
+    pub fn set(&self, limit: RateLimit) -> Result<()> {
+        self.set_at(0, limit)
+    }
+
+    /// Set the rate at `index` for programs that use several rate limiters
+    pub fn set_at(&self, index: u32, limit: RateLimit) -> Result<()> {
+        self.array.set(index, limit)
+    }
+
+    /// Get the rate of the rate limiter at index 0
+    pub fn get(&self) -> Option<RateLimit> {
+        self.array.get(0)
+    }
+}
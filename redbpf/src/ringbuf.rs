@@ -0,0 +1,368 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! # Ring buffer handling
+//!
+//! A ring buffer map (`BPF_MAP_TYPE_RINGBUF`, Linux 5.8 or later) is a single
+//! buffer shared by all CPUs. Unlike perf event arrays, records are read in
+//! the order they were committed by BPF programs and one buffer is enough for
+//! the whole system.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use redbpf::{Module, RingBuf};
+//!
+//! # let module = Module::parse(&std::fs::read("file.elf").unwrap()).unwrap();
+//! let mut ringbuf = RingBuf::new(module.map("events").unwrap()).unwrap();
+//! loop {
+//!     ringbuf
+//!         .poll(Duration::from_millis(100), |record| {
+//!             // do something with the record
+//!         })
+//!         .unwrap();
+//! }
+//! ```
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use libc::{
+    c_void, mmap, munmap, poll, pollfd, sysconf, _SC_PAGESIZE, MAP_FAILED, MAP_SHARED, POLLIN,
+    PROT_READ, PROT_WRITE,
+};
+use tracing::error;
+
+use crate::{Error, Map, Result};
+
+// the record is being written by a BPF program
+const BPF_RINGBUF_BUSY_BIT: u32 = 1 << 31;
+// the record was discarded by a BPF program and should be skipped
+const BPF_RINGBUF_DISCARD_BIT: u32 = 1 << 30;
+// the length of the header of records, i.e., `u32` length and `u32` page
+// offset
+const BPF_RINGBUF_HDR_SZ: usize = 8;
+
+/// A consumer of a ring buffer map
+///
+/// The consumer position page is mapped writable and the producer position
+/// page is mapped read-only followed by the data pages. The kernel maps the
+/// data pages twice in a row so a record wrapping around the end of the
+/// buffer can be read as one contiguous slice.
+pub struct RingBuf {
+    consumer: *mut c_void,
+    producer: *mut c_void,
+    page_size: usize,
+    size: usize,
+    /// The file descriptor of the map. It becomes readable when records
+    /// are available so it can be registered to an event loop.
+    pub fd: RawFd,
+}
+
+// The mapped pages are owned by `RingBuf` and accessed through atomics
+unsafe impl Send for RingBuf {}
+
+impl RingBuf {
+    /// Map the ring buffer of `map`
+    ///
+    /// `map` must be a `BPF_MAP_TYPE_RINGBUF` map whose `max_entries`, the
+    /// size of the buffer in bytes, is a power of 2 multiple of the page
+    /// size.
+    pub fn new(map: &Map) -> Result<RingBuf> {
+        if map.config.type_ != libbpf_sys::BPF_MAP_TYPE_RINGBUF {
+            error!("map `{}' is not a ring buffer", map.name);
+            return Err(Error::Map);
+        }
+        let page_size = unsafe { sysconf(_SC_PAGESIZE) } as usize;
+        let size = map.config.max_entries as usize;
+        let consumer = unsafe {
+            mmap(
+                ptr::null_mut(),
+                page_size,
+                PROT_READ | PROT_WRITE,
+                MAP_SHARED,
+                map.fd,
+                0,
+            )
+        };
+        if consumer == MAP_FAILED {
+            error!(
+                "error on mmap of consumer page: {}",
+                io::Error::last_os_error()
+            );
+            return Err(Error::IO(io::Error::last_os_error()));
+        }
+        let producer = unsafe {
+            mmap(
+                ptr::null_mut(),
+                page_size + 2 * size,
+                PROT_READ,
+                MAP_SHARED,
+                map.fd,
+                page_size as libc::off_t,
+            )
+        };
+        if producer == MAP_FAILED {
+            let e = io::Error::last_os_error();
+            error!("error on mmap of producer and data pages: {}", e);
+            unsafe { munmap(consumer, page_size) };
+            return Err(Error::IO(e));
+        }
+        Ok(RingBuf {
+            consumer,
+            producer,
+            page_size,
+            size,
+            fd: map.fd,
+        })
+    }
+
+    /// Call `f` with each record available and return the number of records
+    ///
+    /// Discarded records are skipped. Reading stops at a record that is
+    /// still being written, which is read by the next call.
+    pub fn consume<F: FnMut(&[u8])>(&mut self, f: F) -> usize {
+        unsafe {
+            let consumer_pos = &*(self.consumer as *const AtomicUsize);
+            let producer_pos = &*(self.producer as *const AtomicUsize);
+            let data = (self.producer as *const u8).add(self.page_size);
+            consume_records(consumer_pos, producer_pos, data, self.size - 1, f)
+        }
+    }
+
+    /// Wait at most `timeout` for records and call `f` with each of them
+    ///
+    /// Records already available are consumed without waiting. The number
+    /// of records is returned, which is 0 if `timeout` expired.
+    pub fn poll<F: FnMut(&[u8])>(&mut self, timeout: Duration, mut f: F) -> Result<usize> {
+        let count = self.consume(&mut f);
+        if count > 0 {
+            return Ok(count);
+        }
+        let mut fds = pollfd {
+            fd: self.fd,
+            events: POLLIN,
+            revents: 0,
+        };
+        if unsafe { poll(&mut fds, 1, timeout.as_millis() as i32) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            error!("error on poll of ring buffer: {}", e);
+            return Err(Error::IO(e));
+        }
+        Ok(self.consume(f))
+    }
+}
+
+impl Drop for RingBuf {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.producer, self.page_size + 2 * self.size);
+            munmap(self.consumer, self.page_size);
+        }
+    }
+}
+
+// Read the records between the consumer and producer positions
+//
+// `data` is the buffer of `mask + 1` bytes mapped twice in a row. Positions
+// only increase and are wrapped around into the buffer by `mask`.
+unsafe fn consume_records<F: FnMut(&[u8])>(
+    consumer_pos: &AtomicUsize,
+    producer_pos: &AtomicUsize,
+    data: *const u8,
+    mask: usize,
+    mut f: F,
+) -> usize {
+    let mut count = 0;
+    let mut cons = consumer_pos.load(Ordering::Acquire);
+    loop {
+        let prod = producer_pos.load(Ordering::Acquire);
+        if cons == prod {
+            break;
+        }
+        while cons != prod {
+            let hdr = data.add(cons & mask);
+            let len = (*(hdr as *const AtomicU32)).load(Ordering::Acquire);
+            if len & BPF_RINGBUF_BUSY_BIT != 0 {
+                return count;
+            }
+            let size = (len & !BPF_RINGBUF_DISCARD_BIT) as usize;
+            if len & BPF_RINGBUF_DISCARD_BIT == 0 {
+                f(slice::from_raw_parts(hdr.add(BPF_RINGBUF_HDR_SZ), size));
+                count += 1;
+            }
+            cons = cons.wrapping_add(round_up(size + BPF_RINGBUF_HDR_SZ, 8));
+            // Release the space of the record to BPF programs
+            consumer_pos.store(cons, Ordering::Release);
+        }
+    }
+    count
+}
+
+fn round_up(n: usize, align: usize) -> usize {
+    (n + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A buffer of `size` bytes mapped twice like the kernel does
+    struct FakeRing {
+        data: Vec<u64>,
+        size: usize,
+        producer: usize,
+    }
+
+    impl FakeRing {
+        fn new(size: usize) -> FakeRing {
+            FakeRing {
+                data: vec![0; 2 * size / 8],
+                size,
+                producer: 0,
+            }
+        }
+
+        fn write(&mut self, len: u32, payload: &[u8]) {
+            let mut record = len.to_ne_bytes().to_vec();
+            record.extend_from_slice(&[0; 4]);
+            record.extend_from_slice(payload);
+            record.resize(round_up(record.len(), 8), 0);
+            let bytes = unsafe {
+                slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut u8, 2 * self.size)
+            };
+            for (i, b) in record.iter().enumerate() {
+                let off = (self.producer + i) & (self.size - 1);
+                bytes[off] = *b;
+                bytes[off + self.size] = *b;
+            }
+            self.producer = self.producer.wrapping_add(record.len());
+        }
+    }
+
+    fn consume(ring: &FakeRing, consumer: &AtomicUsize) -> Vec<Vec<u8>> {
+        let producer = AtomicUsize::new(ring.producer);
+        let mut records = vec![];
+        unsafe {
+            consume_records(
+                consumer,
+                &producer,
+                ring.data.as_ptr() as *const u8,
+                ring.size - 1,
+                |record| records.push(record.to_vec()),
+            );
+        }
+        records
+    }
+
+    #[test]
+    fn test_consume_records() {
+        let mut ring = FakeRing::new(64);
+        let consumer = AtomicUsize::new(0);
+        ring.write(3, b"abc");
+        ring.write(BPF_RINGBUF_DISCARD_BIT | 2, b"xx");
+        ring.write(5, b"hello");
+        assert_eq!(
+            consume(&ring, &consumer),
+            vec![b"abc".to_vec(), b"hello".to_vec()]
+        );
+        assert_eq!(consumer.load(Ordering::Relaxed), 48);
+
+        // the record crosses the end of the buffer
+        ring.write(12, b"wraparound!!");
+        assert_eq!(consume(&ring, &consumer), vec![b"wraparound!!".to_vec()]);
+        assert_eq!(consumer.load(Ordering::Relaxed), 72);
+        assert!(consume(&ring, &consumer).is_empty());
+    }
+
+    #[test]
+    fn test_consume_busy_record() {
+        let mut ring = FakeRing::new(64);
+        let consumer = AtomicUsize::new(0);
+        ring.write(1, b"a");
+        ring.write(BPF_RINGBUF_BUSY_BIT | 1, b"b");
+        ring.write(1, b"c");
+        assert_eq!(consume(&ring, &consumer), vec![b"a".to_vec()]);
+        assert_eq!(consumer.load(Ordering::Relaxed), 16);
+    }
+
+    #[test]
+    fn test_round_up() {
+        assert_eq!(round_up(8, 8), 8);
+        assert_eq!(round_up(9, 8), 16);
+        assert_eq!(round_up(13, 8), 16);
+    }
+
+    #[cfg(feature = "kernel_tests")]
+    #[test]
+    fn test_ringbuf_program() {
+        use crate::insn::*;
+        use crate::ProgramType;
+
+        let map = Map::create("events", libbpf_sys::BPF_MAP_TYPE_RINGBUF, 0, 0, 4096, 0).unwrap();
+        let mut builder = ProgramBuilder::new("output", ProgramType::SocketFilter);
+        builder
+            // output the current time
+            .insn(call(libbpf_sys::BPF_FUNC_ktime_get_ns))
+            .insn(stx_mem(BPF_DW, R10, R0, -8))
+            .ld_map(R1, "events")
+            .insn(mov64_reg(R2, R10))
+            .insn(alu64_imm(BPF_ADD, R2, -8))
+            .insn(mov64_imm(R3, 8))
+            .insn(mov64_imm(R4, 0))
+            .insn(call(libbpf_sys::BPF_FUNC_ringbuf_output))
+            // reserve a record and discard it
+            .ld_map(R1, "events")
+            .insn(mov64_imm(R2, 8))
+            .insn(mov64_imm(R3, 0))
+            .insn(call(libbpf_sys::BPF_FUNC_ringbuf_reserve))
+            .insn(jmp_imm(BPF_JEQ, R0, 0, 4))
+            .insn(st_mem(BPF_DW, R0, 0, 0))
+            .insn(mov64_reg(R1, R0))
+            .insn(mov64_imm(R2, 0))
+            .insn(call(libbpf_sys::BPF_FUNC_ringbuf_discard))
+            .insn(mov64_imm(R0, 0))
+            .insn(exit())
+            .map("events", &map);
+        let program = builder.load().unwrap();
+        let prog_fd = program.fd().unwrap();
+        let packet = [0u8; 64];
+        for _ in 0..3 {
+            let (mut retval, mut duration) = (0, 0);
+            let ret = unsafe {
+                libbpf_sys::bpf_prog_test_run(
+                    prog_fd,
+                    1,
+                    packet.as_ptr() as *mut _,
+                    packet.len() as u32,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    &mut retval,
+                    &mut duration,
+                )
+            };
+            assert_eq!(ret, 0);
+        }
+
+        let mut ringbuf = RingBuf::new(&map).unwrap();
+        let mut times = vec![];
+        let count = ringbuf
+            .poll(Duration::from_secs(1), |record| {
+                let mut time = [0u8; 8];
+                time.copy_from_slice(record);
+                times.push(u64::from_ne_bytes(time));
+            })
+            .unwrap();
+        assert_eq!(count, 3);
+        assert!(times.windows(2).all(|w| w[0] <= w[1]));
+    }
+}
@@ -6,6 +6,8 @@
 // copied, modified, or distributed except according to those terms.
 
 use byteorder::{NativeEndian, ReadBytesExt};
+use goblin::elf::program_header::PT_LOAD;
+use goblin::elf::section_header::SHN_UNDEF;
 use goblin::elf::{Elf, Sym};
 use libc::pid_t;
 use std::ffi::CStr;
@@ -33,23 +35,30 @@ impl<'a> ElfSymbols<'a> {
         Ok(ElfSymbols { elf })
     }
 
+    // `sym_name` matches both the name of a symbol and its demangled path.
+    // Undefined symbols, e.g., functions imported from shared libraries, are
+    // skipped.
     fn resolve_dyn_syms(&self, sym_name: &str) -> Option<Sym> {
         self.elf.dynsyms.iter().find(|sym| {
-            self.elf
-                .dynstrtab
-                .get_at(sym.st_name)
-                .map(|n| n == sym_name)
-                .unwrap_or(false)
+            sym.st_shndx != SHN_UNDEF as usize
+                && self
+                    .elf
+                    .dynstrtab
+                    .get_at(sym.st_name)
+                    .map(|n| symbol_matches(n, sym_name))
+                    .unwrap_or(false)
         })
     }
 
     fn resolve_syms(&self, sym_name: &str) -> Option<Sym> {
         self.elf.syms.iter().find(|sym| {
-            self.elf
-                .strtab
-                .get_at(sym.st_name)
-                .map(|n| n == sym_name)
-                .unwrap_or(false)
+            sym.st_shndx != SHN_UNDEF as usize
+                && self
+                    .elf
+                    .strtab
+                    .get_at(sym.st_name)
+                    .map(|n| symbol_matches(n, sym_name))
+                    .unwrap_or(false)
         })
     }
 
@@ -57,6 +66,69 @@ impl<'a> ElfSymbols<'a> {
         self.resolve_dyn_syms(sym_name)
             .or_else(|| self.resolve_syms(sym_name))
     }
+
+    /// Return the file offset of the symbol `sym_name`
+    ///
+    /// Symbol values are virtual addresses, which are converted to file
+    /// offsets with the loadable segment containing them. They are the same
+    /// in the first segment of PIE executables and shared libraries but not in
+    /// non-PIE executables.
+    pub fn file_offset(&self, sym_name: &str) -> Option<u64> {
+        let addr = self.resolve(sym_name)?.st_value;
+        if self.elf.program_headers.is_empty() {
+            return Some(addr);
+        }
+        self.elf
+            .program_headers
+            .iter()
+            .find(|ph| ph.p_type == PT_LOAD && ph.vm_range().contains(&(addr as usize)))
+            .map(|ph| addr - ph.p_vaddr + ph.p_offset)
+    }
+}
+
+fn symbol_matches(name: &str, sym_name: &str) -> bool {
+    name == sym_name || matches!(demangle_path(name), Some(path) if path == sym_name)
+}
+
+/// Return the path of a symbol mangled by the legacy Rust mangling or the
+/// Itanium C++ ABI, e.g., `foo::bar` for `_ZN3foo3bar17h0123456789abcdefE` or
+/// `_ZN3foo3barEi`
+///
+/// The hash of Rust symbols and the parameter types of C++ functions are
+/// dropped. `None` is returned for names that are not mangled, and for
+/// special names like constructors which are not supported.
+pub(crate) fn demangle_path(name: &str) -> Option<String> {
+    let mangled = name.strip_prefix("_Z")?;
+    let (mut rest, nested) = match mangled.strip_prefix('N') {
+        // skip the cv-qualifiers of member functions
+        Some(rest) => (rest.trim_start_matches(&['r', 'V', 'K'][..]), true),
+        None => (mangled, false),
+    };
+    let mut parts = Vec::new();
+    loop {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            break;
+        }
+        let len = rest[..digits].parse::<usize>().ok()?;
+        parts.push(rest.get(digits..digits + len)?);
+        rest = &rest[digits + len..];
+        if !nested {
+            break;
+        }
+    }
+    if parts.is_empty() || (nested && !rest.starts_with('E')) {
+        return None;
+    }
+    if let Some(last) = parts.last() {
+        let is_hash = last.len() == 17
+            && last.starts_with('h')
+            && last[1..].bytes().all(|c| c.is_ascii_hexdigit());
+        if nested && parts.len() > 1 && is_hash {
+            parts.pop();
+        }
+    }
+    Some(parts.join("::"))
 }
 
 #[derive(Debug)]
@@ -175,3 +247,150 @@ pub(crate) fn resolve_proc_maps_lib(pid: pid_t, lib: &str) -> Option<String> {
 
     ret.map(|(_, v)| v.clone())
 }
+
+const AVAILABLE_FILTER_FUNCTIONS: [&str; 2] = [
+    "/sys/kernel/tracing/available_filter_functions",
+    "/sys/kernel/debug/tracing/available_filter_functions",
+];
+
+/// Return the names of kernel functions that can be kprobed and match the
+/// glob `pattern`
+pub(crate) fn kprobe_functions_matching(pattern: &str) -> io::Result<Vec<String>> {
+    let contents = AVAILABLE_FILTER_FUNCTIONS
+        .iter()
+        .find_map(|path| fs::read_to_string(path).ok())
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+    let mut names: Vec<String> = contents
+        .lines()
+        // module functions are followed by ` [module_name]`
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| glob_match(pattern, name))
+        .map(String::from)
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    Ok(names)
+}
+
+/// Split a kprobe target into the module name and the symbol name
+///
+/// `fn_name` is either `symbol` for functions of vmlinux or `module:symbol`
+/// for functions of a loadable kernel module. Dashes in module names are
+/// replaced with underscores as the kernel does.
+pub(crate) fn parse_kprobe_target(fn_name: &str) -> Option<(Option<String>, &str)> {
+    let valid = |name: &str| !name.is_empty() && !name.contains(char::is_whitespace);
+    match fn_name.split_once(':') {
+        Some((module, symbol)) => {
+            if !valid(module) || !valid(symbol) || symbol.contains(':') {
+                return None;
+            }
+            Some((Some(module.replace('-', "_")), symbol))
+        }
+        None if valid(fn_name) => Some((None, fn_name)),
+        None => None,
+    }
+}
+
+/// Match `name` against `pattern` that may contain `*` and `?` wildcards
+pub(crate) fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` in pattern and the matching position in name
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("tcp_v4_*", "tcp_v4_connect"));
+        assert!(glob_match("*_connect", "tcp_v4_connect"));
+        assert!(glob_match("tcp_v?_connect", "tcp_v6_connect"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("vfs_read", "vfs_read"));
+        assert!(!glob_match("vfs_read", "vfs_readv"));
+        assert!(!glob_match("tcp_*_close", "tcp_v4_connect"));
+    }
+
+    #[test]
+    fn test_demangle_path() {
+        assert_eq!(
+            demangle_path("_ZN4core3ptr13drop_in_place17h0123456789abcdefE").as_deref(),
+            Some("core::ptr::drop_in_place")
+        );
+        assert_eq!(demangle_path("_ZN3foo3barEi").as_deref(), Some("foo::bar"));
+        assert_eq!(demangle_path("_ZNK3Foo3getEv").as_deref(), Some("Foo::get"));
+        assert_eq!(demangle_path("_Z3fooi").as_deref(), Some("foo"));
+        assert_eq!(demangle_path("_ZN3fooC1Ev"), None);
+        assert_eq!(demangle_path("_ZN3foo"), None);
+        assert_eq!(demangle_path("malloc"), None);
+    }
+
+    #[inline(never)]
+    fn uprobe_target(x: u64) -> u64 {
+        x.wrapping_mul(31)
+    }
+
+    #[test]
+    fn test_elf_symbols_file_offset() {
+        let addr = uprobe_target as fn(u64) -> u64 as usize as u64;
+        assert_eq!(uprobe_target(1), 31);
+        // the file offset the function is mapped from
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        let expected = maps
+            .lines()
+            .find_map(|line| {
+                let mut parts = line.split_whitespace();
+                let (start, end) = parts.next()?.split_once('-')?;
+                let start = u64::from_str_radix(start, 16).ok()?;
+                let end = u64::from_str_radix(end, 16).ok()?;
+                let offset = u64::from_str_radix(parts.nth(1)?, 16).ok()?;
+                (start..end).contains(&addr).then(|| addr - start + offset)
+            })
+            .unwrap();
+
+        let data = fs::read(std::env::current_exe().unwrap()).unwrap();
+        let symbols = ElfSymbols::parse(&data).unwrap();
+        assert_eq!(
+            symbols.file_offset("redbpf::symbols::test::uprobe_target"),
+            Some(expected)
+        );
+        assert_eq!(
+            symbols.file_offset("redbpf::symbols::test::no_such_fn"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_kprobe_target() {
+        assert_eq!(parse_kprobe_target("vfs_read"), Some((None, "vfs_read")));
+        assert_eq!(
+            parse_kprobe_target("nf-conntrack:nf_conntrack_in"),
+            Some((Some("nf_conntrack".to_string()), "nf_conntrack_in"))
+        );
+        assert_eq!(parse_kprobe_target("e1000:"), None);
+        assert_eq!(parse_kprobe_target(":e1000_probe"), None);
+        assert_eq!(parse_kprobe_target("a:b:c"), None);
+        assert_eq!(parse_kprobe_target(""), None);
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reading and setting the sysctls of the BPF JIT compiler
+//!
+//! The performance of BPF programs depends on these sysctls:
+//!
+//! - `net.core.bpf_jit_enable`: programs are interpreted unless the JIT is
+//!   enabled when they are loaded
+//! - `net.core.bpf_jit_harden`: hardening blinds the constants of JIT-compiled
+//!   programs, which makes them bigger and slower
+//! - `net.core.bpf_jit_limit`: the memory available to the JIT compiler for
+//!   unprivileged programs, in bytes
+//!
+//! In addition, `kernel.bpf_stats_enabled` makes the kernel count the runs
+//! and the run time of programs, which are reported by
+//! [`Program::info`](../enum.Program.html#method.info). It adds overhead to
+//! every run, so it is disabled by default.
+//!
+//! Reading `bpf_jit_harden` and `bpf_jit_limit`, and setting any of them,
+//! requires `CAP_SYS_ADMIN`. Errors are returned with
+//! `ErrorKind::PermissionDenied` and a message naming the sysctl in that
+//! case.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::sysctl;
+//!
+//! if sysctl::is_jit_hardening_active().unwrap_or(false) {
+//!     eprintln!("BPF JIT hardening is active, programs run slower");
+//! }
+//! ```
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+
+pub const BPF_JIT_ENABLE: &str = "net.core.bpf_jit_enable";
+pub const BPF_JIT_HARDEN: &str = "net.core.bpf_jit_harden";
+pub const BPF_JIT_LIMIT: &str = "net.core.bpf_jit_limit";
+pub const BPF_STATS_ENABLED: &str = "kernel.bpf_stats_enabled";
+
+const PROC_SYS: &str = "/proc/sys";
+const PROC_SELF_STATUS: &str = "/proc/self/status";
+const CAP_SYS_ADMIN: u32 = 21;
+const CAP_BPF: u32 = 39;
+
+/// Values of `net.core.bpf_jit_enable`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitEnable {
+    Disabled = 0,
+    Enabled = 1,
+    /// Enabled, and the JIT compiler dumps the native code to the kernel log
+    Debug = 2,
+}
+
+/// Values of `net.core.bpf_jit_harden`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JitHarden {
+    Disabled = 0,
+    /// Hardening applies to programs loaded by unprivileged users
+    Unprivileged = 1,
+    /// Hardening applies to all programs
+    All = 2,
+}
+
+/// Return the value of the sysctl `name`, e.g., `net.core.bpf_jit_enable`
+pub fn read(name: &str) -> io::Result<String> {
+    fs::read_to_string(sysctl_path(name))
+        .map(|value| value.trim().to_string())
+        .map_err(|e| sysctl_error(name, "reading", e))
+}
+
+/// Set the sysctl `name` to `value`
+pub fn write(name: &str, value: &str) -> io::Result<()> {
+    fs::write(sysctl_path(name), value).map_err(|e| sysctl_error(name, "setting", e))
+}
+
+/// Return the value of `net.core.bpf_jit_enable`
+///
+/// `JitEnable::Disabled` is returned if the kernel is built without the
+/// JIT compiler.
+pub fn jit_enable() -> io::Result<JitEnable> {
+    match read(BPF_JIT_ENABLE) {
+        Ok(value) => match parse::<u32>(BPF_JIT_ENABLE, &value)? {
+            0 => Ok(JitEnable::Disabled),
+            1 => Ok(JitEnable::Enabled),
+            2 => Ok(JitEnable::Debug),
+            _ => Err(invalid_value(BPF_JIT_ENABLE, &value)),
+        },
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(JitEnable::Disabled),
+        Err(e) => Err(e),
+    }
+}
+
+/// Set `net.core.bpf_jit_enable`
+///
+/// The JIT compiler can not be disabled if the kernel is built with
+/// `CONFIG_BPF_JIT_ALWAYS_ON`.
+pub fn set_jit_enable(value: JitEnable) -> io::Result<()> {
+    write(BPF_JIT_ENABLE, &(value as u32).to_string())
+}
+
+/// Return the value of `net.core.bpf_jit_harden`
+pub fn jit_harden() -> io::Result<JitHarden> {
+    let value = read(BPF_JIT_HARDEN)?;
+    match parse::<u32>(BPF_JIT_HARDEN, &value)? {
+        0 => Ok(JitHarden::Disabled),
+        1 => Ok(JitHarden::Unprivileged),
+        2 => Ok(JitHarden::All),
+        _ => Err(invalid_value(BPF_JIT_HARDEN, &value)),
+    }
+}
+
+/// Set `net.core.bpf_jit_harden`
+pub fn set_jit_harden(value: JitHarden) -> io::Result<()> {
+    write(BPF_JIT_HARDEN, &(value as u32).to_string())
+}
+
+/// Return the value of `net.core.bpf_jit_limit` in bytes
+pub fn jit_limit() -> io::Result<u64> {
+    let value = read(BPF_JIT_LIMIT)?;
+    parse(BPF_JIT_LIMIT, &value)
+}
+
+/// Set `net.core.bpf_jit_limit` in bytes
+pub fn set_jit_limit(limit: u64) -> io::Result<()> {
+    write(BPF_JIT_LIMIT, &limit.to_string())
+}
+
+/// Return true if `kernel.bpf_stats_enabled` is on
+pub fn stats_enabled() -> io::Result<bool> {
+    let value = read(BPF_STATS_ENABLED)?;
+    Ok(parse::<u32>(BPF_STATS_ENABLED, &value)? != 0)
+}
+
+/// Set `kernel.bpf_stats_enabled`
+pub fn set_stats_enabled(enabled: bool) -> io::Result<()> {
+    write(BPF_STATS_ENABLED, if enabled { "1" } else { "0" })
+}
+
+/// Return true if the programs loaded by this process are JIT-compiled with
+/// hardening
+///
+/// Hardening applies if the JIT is enabled and `net.core.bpf_jit_harden` is
+/// `All`, or `Unprivileged` and the process has neither `CAP_BPF` nor
+/// `CAP_SYS_ADMIN`. `bpf_jit_harden` is readable only with `CAP_SYS_ADMIN`,
+/// so processes without it can't tell `Disabled` from `Unprivileged` and
+/// get an error of `ErrorKind::PermissionDenied` then.
+pub fn is_jit_hardening_active() -> io::Result<bool> {
+    if jit_enable()? == JitEnable::Disabled {
+        return Ok(false);
+    }
+    match jit_harden()? {
+        JitHarden::Disabled => Ok(false),
+        JitHarden::Unprivileged => Ok(!is_bpf_privileged()?),
+        JitHarden::All => Ok(true),
+    }
+}
+
+// Return true if the process has CAP_BPF or CAP_SYS_ADMIN in its effective
+// capability set
+fn is_bpf_privileged() -> io::Result<bool> {
+    let status = fs::read_to_string(PROC_SELF_STATUS)?;
+    let cap_eff = status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::InvalidData,
+                format!("no CapEff in {}", PROC_SELF_STATUS),
+            )
+        })?;
+    Ok(cap_eff & (1 << CAP_SYS_ADMIN | 1 << CAP_BPF) != 0)
+}
+
+fn sysctl_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(PROC_SYS);
+    path.extend(name.split('.'));
+    path
+}
+
+fn parse<T: std::str::FromStr>(name: &str, value: &str) -> io::Result<T> {
+    value.parse().map_err(|_| invalid_value(name, value))
+}
+
+fn invalid_value(name: &str, value: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid value of {}: `{}'", name, value),
+    )
+}
+
+fn sysctl_error(name: &str, action: &str, e: io::Error) -> io::Error {
+    let msg = match e.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EPERM) => {
+            format!("{} {} requires CAP_SYS_ADMIN: {}", action, name, e)
+        }
+        Some(libc::EROFS) => format!("{} is read-only in this mount namespace: {}", name, e),
+        Some(libc::EINVAL) => format!("{} does not accept the value: {}", name, e),
+        _ => format!("error {} {}: {}", action, name, e),
+    };
+    io::Error::new(e.kind(), msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sysctl_path() {
+        assert_eq!(
+            sysctl_path(BPF_JIT_HARDEN),
+            PathBuf::from("/proc/sys/net/core/bpf_jit_harden")
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse::<u64>(BPF_JIT_LIMIT, "264241152").unwrap(), 264241152);
+        let err = parse::<u32>(BPF_JIT_ENABLE, "on").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright 2019 Authors of Red Sift
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Checking whether BPF is usable on this system
+//!
+//! [`bpf_available`](fn.bpf_available.html) is a preflight check for tools
+//! to run at startup. It loads a canary program that returns 0 and creates a
+//! tiny array map. If either fails, BPF is not usable at all, e.g., the
+//! kernel is built without the `bpf()` syscall or the process lacks the
+//! privilege, and the error is logged with a diagnostic. Otherwise the
+//! program and map types supported by the kernel are probed and returned.
+//!
+//! # Example
+//! ```no_run
+//! use redbpf::map_type::MapType;
+//! use redbpf::system;
+//!
+//! let caps = system::bpf_available().expect("BPF is not usable");
+//! if !caps.supports_map_type(MapType::RingBuf) {
+//!     println!("falling back to perf event arrays");
+//! }
+//! ```
+use std::ffi::CString;
+use std::io;
+use std::ptr;
+
+use tracing::error;
+
+use crate::insn::{exit, mov64_imm, R0};
+use crate::map_type::MapType;
+use crate::{is_jit_enabled, sysctl, Error, ProgramType, Result};
+
+const UNPRIVILEGED_BPF_DISABLED: &str = "kernel.unprivileged_bpf_disabled";
+
+const PROGRAM_TYPES: [ProgramType; 16] = [
+    ProgramType::KProbe,
+    ProgramType::KRetProbe,
+    ProgramType::UProbe,
+    ProgramType::URetProbe,
+    ProgramType::SocketFilter,
+    ProgramType::TracePoint,
+    ProgramType::XDP,
+    ProgramType::StreamParser,
+    ProgramType::StreamVerdict,
+    ProgramType::SkLookup,
+    ProgramType::SockOps,
+    ProgramType::SkReuseport,
+    ProgramType::RawTracePoint,
+    ProgramType::CgroupSkb,
+    ProgramType::CgroupSock,
+    ProgramType::PerfEvent,
+];
+
+/// The BPF features detected by [`bpf_available`](fn.bpf_available.html)
+#[derive(Debug, Clone)]
+pub struct BpfCapabilities {
+    /// The program types that can be loaded
+    pub prog_types: Vec<ProgramType>,
+    /// The map types that can be created
+    pub map_types: Vec<MapType>,
+    /// True if programs are JIT-compiled
+    pub jit_enabled: bool,
+}
+
+impl BpfCapabilities {
+    /// Return true if programs of `prog_type` can be loaded
+    pub fn supports_prog_type(&self, prog_type: ProgramType) -> bool {
+        self.prog_types.contains(&prog_type)
+    }
+
+    /// Return true if maps of `map_type` can be created
+    pub fn supports_map_type(&self, map_type: MapType) -> bool {
+        self.map_types.contains(&map_type)
+    }
+}
+
+/// Check that BPF is usable and detect the supported program and map types
+///
+/// `Error::IO` is returned if the canary program can not be loaded or the
+/// canary map can not be created. The cause, e.g., `ENOSYS` or `EPERM`, is
+/// logged with a hint on how to fix it.
+pub fn bpf_available() -> Result<BpfCapabilities> {
+    load_canary_program().map_err(|e| diagnose("loading canary program", e))?;
+    create_canary_map().map_err(|e| diagnose("creating canary map", e))?;
+
+    let prog_types = PROGRAM_TYPES
+        .iter()
+        .copied()
+        .filter(|prog_type| unsafe { libbpf_sys::bpf_probe_prog_type(prog_type.to_prog_type(), 0) })
+        .collect();
+    let map_types = (1..)
+        .map_while(MapType::from_u32)
+        .filter(|map_type| unsafe { libbpf_sys::bpf_probe_map_type(*map_type as u32, 0) })
+        .collect();
+    Ok(BpfCapabilities {
+        prog_types,
+        map_types,
+        jit_enabled: is_jit_enabled(),
+    })
+}
+
+fn load_canary_program() -> io::Result<()> {
+    let insns = [mov64_imm(R0, 0), exit()];
+    let license = CString::new("GPL").unwrap();
+    unsafe {
+        let fd = libbpf_sys::bpf_load_program(
+            libbpf_sys::BPF_PROG_TYPE_SOCKET_FILTER,
+            insns.as_ptr(),
+            insns.len() as _,
+            license.as_ptr(),
+            0,
+            ptr::null_mut(),
+            0,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::close(fd);
+    }
+    Ok(())
+}
+
+fn create_canary_map() -> io::Result<()> {
+    unsafe {
+        let fd = libbpf_sys::bpf_create_map(libbpf_sys::BPF_MAP_TYPE_ARRAY, 4, 4, 1, 0);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::close(fd);
+    }
+    Ok(())
+}
+
+fn diagnose(action: &str, e: io::Error) -> Error {
+    match e.raw_os_error() {
+        Some(libc::ENOSYS) => error!(
+            "error {}: {}. The kernel is built without CONFIG_BPF_SYSCALL or bpf() is blocked by seccomp",
+            action, e
+        ),
+        Some(libc::EPERM) | Some(libc::EACCES) => {
+            let unprivileged = match sysctl::read(UNPRIVILEGED_BPF_DISABLED).as_deref() {
+                Ok("0") => "",
+                _ => " since unprivileged BPF is disabled",
+            };
+            error!(
+                "error {}: {}. CAP_BPF or CAP_SYS_ADMIN is required{}",
+                action, e, unprivileged
+            );
+        }
+        Some(libc::ENOMEM) => error!(
+            "error {}: {}. RLIMIT_MEMLOCK may be too low",
+            action, e
+        ),
+        _ => error!("error {}: {}", action, e),
+    }
+    Error::IO(e)
+}
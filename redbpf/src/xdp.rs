@@ -29,6 +29,54 @@ impl Default for Flags {
     }
 }
 
+/// The mode to attach an XDP program in
+///
+/// Used by [`XDP::attach_xdp_mode`](../struct.XDP.html#method.attach_xdp_mode).
+/// Native mode is much faster than generic mode but only some drivers
+/// support it, and fewer support offloading to the NIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum XdpFlags {
+    /// Native mode if the driver supports it, generic mode otherwise
+    #[default]
+    Default,
+    /// Generic mode, `XDP_FLAGS_SKB_MODE`. Programs run after socket buffers
+    /// are allocated, so it works with any interface.
+    Generic,
+    /// Native mode, `XDP_FLAGS_DRV_MODE`. Programs run in the driver.
+    Native,
+    /// Hardware offload, `XDP_FLAGS_HW_MODE`. Programs run on the NIC.
+    Offload,
+}
+
+impl XdpFlags {
+    /// Return the `XDP_FLAGS_*` bits to attach in this mode
+    ///
+    /// Unless `replace` is true, `XDP_FLAGS_UPDATE_IF_NOEXIST` is set so
+    /// that a program already attached to the interface is not replaced.
+    pub fn bits(self, replace: bool) -> u32 {
+        let mode = match self {
+            XdpFlags::Default => 0,
+            XdpFlags::Generic => XDP_FLAGS_SKB_MODE,
+            XdpFlags::Native => XDP_FLAGS_DRV_MODE,
+            XdpFlags::Offload => XDP_FLAGS_HW_MODE,
+        };
+        if replace {
+            mode
+        } else {
+            mode | XDP_FLAGS_UPDATE_IF_NOEXIST
+        }
+    }
+
+    pub(crate) fn from_bits(bits: u32) -> XdpFlags {
+        match bits & XDP_FLAGS_MODES {
+            XDP_FLAGS_SKB_MODE => XdpFlags::Generic,
+            XDP_FLAGS_DRV_MODE => XdpFlags::Native,
+            XDP_FLAGS_HW_MODE => XdpFlags::Offload,
+            _ => XdpFlags::Default,
+        }
+    }
+}
+
 /* NB: this needs to be kept in sync with redbpf_probes::xdp::MapData */
 #[repr(C)]
 pub struct MapData<T> {
@@ -116,3 +164,33 @@ impl<'a> DevMap<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_xdp_flags_bits() {
+        assert_eq!(XdpFlags::Default.bits(true), 0);
+        assert_eq!(XdpFlags::Generic.bits(true), XDP_FLAGS_SKB_MODE);
+        assert_eq!(XdpFlags::Native.bits(true), XDP_FLAGS_DRV_MODE);
+        assert_eq!(XdpFlags::Offload.bits(true), XDP_FLAGS_HW_MODE);
+        assert_eq!(
+            XdpFlags::Native.bits(false),
+            XDP_FLAGS_DRV_MODE | XDP_FLAGS_UPDATE_IF_NOEXIST
+        );
+        assert_eq!(XdpFlags::Default.bits(false), XDP_FLAGS_UPDATE_IF_NOEXIST);
+        for mode in [
+            XdpFlags::Default,
+            XdpFlags::Generic,
+            XdpFlags::Native,
+            XdpFlags::Offload,
+        ] {
+            assert_eq!(XdpFlags::from_bits(mode.bits(false)), mode);
+        }
+        assert_eq!(
+            XdpFlags::from_bits(Flags::SkbMode as u32),
+            XdpFlags::Generic
+        );
+    }
+}
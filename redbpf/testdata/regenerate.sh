@@ -0,0 +1,42 @@
+#!/bin/sh
+# Regenerate the ELF relocatable files that the tests of redbpf parse from
+# their sources in src/
+#
+# The programs and maps written in assembly are assembled by llvm-mc. Set
+# LLVM_MC to use another llvm-mc, e.g., `LLVM_MC=llvm-mc-14`.
+#
+# The maps with BTF are written in Rust. rustc emits their LLVM IR with debug
+# info for the host, the IR is retargeted to BPF and llc generates BTF from
+# the debug info, like cargo-bpf does with bitcode. Since the host IR may be
+# newer than llc, the attributes of functions are reduced to `nounwind` and
+# module flags are downgraded to a behavior every LLVM knows. The DWARF is
+# stripped afterwards. Set RUSTC, LLC and LLVM_OBJCOPY to use other tools.
+set -e
+cd "$(dirname "$0")"
+
+LLVM_MC=${LLVM_MC:-llvm-mc}
+RUSTC=${RUSTC:-rustc}
+LLC=${LLC:-llc}
+LLVM_OBJCOPY=${LLVM_OBJCOPY:-llvm-objcopy}
+
+for src in src/*.s; do
+    obj=$(basename "$src" .s).o
+    "$LLVM_MC" -triple bpfel -filetype=obj "$src" -o "$obj"
+    echo "generated $obj"
+done
+
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+for src in src/*.rs; do
+    name=$(basename "$src" .rs)
+    "$RUSTC" --crate-type=lib --crate-name "$name" --emit=llvm-ir -g \
+        -C opt-level=1 -C panic=abort "$src" -o "$tmp/$name.ll"
+    sed -e 's/^target datalayout = .*/target datalayout = "e-m:e-p:64:64-i64:64-i128:128-n32:64-S128"/' \
+        -e 's/^target triple = .*/target triple = "bpfel"/' \
+        -e 's/^\(attributes #[0-9]*\) = .*/\1 = { nounwind }/' \
+        -e 's/^\(![0-9]* = !{i32\) [0-9]*, \(!"[^"]*"\)/\1 2, \2/' \
+        "$tmp/$name.ll" > "$tmp/$name.bpf.ll"
+    "$LLC" -march=bpfel -filetype=obj "$tmp/$name.bpf.ll" -o "$tmp/$name.o"
+    "$LLVM_OBJCOPY" --strip-debug "$tmp/$name.o" "$name.o"
+    echo "generated $name.o"
+done
@@ -0,0 +1,28 @@
+# programs.s with the XDP program in the section `custom_xdp/pass`, which
+# is only loaded as an XDP program when `custom_xdp` is mapped to
+# `ProgramType::XDP` by `Module::with_section_map`.
+
+	.section	"kprobe/do_sys_open","ax",@progbits
+	.globl	do_sys_open
+	.type	do_sys_open,@function
+do_sys_open:
+	r0 = 0
+	exit
+.Lfunc_end0:
+	.size	do_sys_open, .Lfunc_end0-do_sys_open
+
+	.section	"custom_xdp/pass","ax",@progbits
+	.globl	pass
+	.type	pass,@function
+pass:
+	r0 = 2
+	exit
+.Lfunc_end1:
+	.size	pass, .Lfunc_end1-pass
+
+	.section	"license","aw",@progbits
+	.asciz	"GPL"
+
+	.section	"version","aw",@progbits
+	.p2align	2
+	.long	0x050a00
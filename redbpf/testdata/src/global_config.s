@@ -0,0 +1,40 @@
+# A socket filter that returns a global variable, like what
+#
+#     const volatile u64 LIMIT = 100;
+#     const volatile u32 VERDICT = 1;
+#
+#     SEC("socketfilter/verdict")
+#     int verdict(struct __sk_buff *skb)
+#     {
+#         return VERDICT;
+#     }
+#
+# is built to by clang. The constants live in `.rodata`, which the loader
+# turns into a map that the relocation of `VERDICT` refers to.
+
+	.section	"socketfilter/verdict","ax",@progbits
+	.globl	verdict
+	.type	verdict,@function
+verdict:
+	r1 = VERDICT ll
+	r0 = *(u32 *)(r1 + 0)
+	exit
+.Lfunc_end0:
+	.size	verdict, .Lfunc_end0-verdict
+
+	.section	.rodata,"a",@progbits
+	.globl	LIMIT
+	.type	LIMIT,@object
+	.p2align	3
+LIMIT:
+	.quad	100
+	.size	LIMIT, 8
+	.globl	VERDICT
+	.type	VERDICT,@object
+	.p2align	2
+VERDICT:
+	.long	1
+	.size	VERDICT, 4
+
+	.section	"license","aw",@progbits
+	.asciz	"GPL"
@@ -0,0 +1,46 @@
+# Maps defined in the single `maps` section like clang does for C programs,
+# e.g.,
+#
+#     struct bpf_map_def SEC("maps") counts = {
+#         .type = BPF_MAP_TYPE_ARRAY,
+#         .key_size = sizeof(u32),
+#         .value_size = sizeof(u64),
+#         .max_entries = 16,
+#     };
+#
+# and a socket filter that accepts every packet. The maps are found by the
+# symbols in the section.
+
+	.section	"socketfilter/accept","ax",@progbits
+	.globl	accept
+	.type	accept,@function
+accept:
+	r0 = -1
+	exit
+.Lfunc_end0:
+	.size	accept, .Lfunc_end0-accept
+
+	.section	maps,"aw",@progbits
+	.globl	counts
+	.type	counts,@object
+	.p2align	2
+counts:
+	.long	2
+	.long	4
+	.long	8
+	.long	16
+	.long	0
+	.size	counts, 20
+	.globl	events
+	.type	events,@object
+	.p2align	2
+events:
+	.long	4
+	.long	4
+	.long	4
+	.long	0
+	.long	0
+	.size	events, 20
+
+	.section	"license","aw",@progbits
+	.asciz	"GPL"
@@ -0,0 +1,106 @@
+// Maps with BTF like what
+//
+//     #[map]
+//     static mut connections: HashMap<Connection, u64> = HashMap::with_max_entries(1024);
+//     #[map]
+//     static mut counts: Array<u64> = Array::with_max_entries(16);
+//     #[map]
+//     static mut tasks: LruHashMap<u32, Task> = LruHashMap::with_max_entries(1024);
+//     #[map]
+//     static mut events: PerfMap<u32> = PerfMap::with_max_entries(0);
+//
+// is expanded to by redbpf-macros, without the dependency on redbpf-probes.
+// `events` has no BTF like perf maps.
+#![no_std]
+#![allow(non_upper_case_globals, non_snake_case)]
+
+#[repr(C)]
+pub struct bpf_map_def {
+    type_: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Connection {
+    pub saddr: u32,
+    pub daddr: u32,
+    pub sport: u16,
+    pub dport: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Task {
+    pub pid: u32,
+    pub start: u64,
+    pub comm: [u8; 16],
+}
+
+mod _connections {
+    #[repr(C)]
+    pub struct MapBtf {
+        key_type: super::Connection,
+        value_type: u64,
+    }
+    #[no_mangle]
+    #[link_section = "maps.ext"]
+    pub static MAP_BTF_connections: MapBtf = MapBtf { key_type: super::Connection { saddr: 0, daddr: 0, sport: 0, dport: 0 }, value_type: 0 };
+}
+
+mod _counts {
+    #[repr(C)]
+    pub struct MapBtf {
+        key_type: u32,
+        value_type: u64,
+    }
+    #[no_mangle]
+    #[link_section = "maps.ext"]
+    pub static MAP_BTF_counts: MapBtf = MapBtf { key_type: 0, value_type: 0 };
+}
+
+mod _tasks {
+    #[repr(C)]
+    pub struct MapBtf {
+        key_type: u32,
+        value_type: super::Task,
+    }
+    #[no_mangle]
+    #[link_section = "maps.ext"]
+    pub static MAP_BTF_tasks: MapBtf = MapBtf { key_type: 0, value_type: super::Task { pid: 0, start: 0, comm: [0; 16] } };
+}
+
+#[no_mangle]
+#[link_section = "maps/connections"]
+pub static mut connections: bpf_map_def = bpf_map_def { type_: 1, key_size: 12, value_size: 8, max_entries: 1024, map_flags: 0 };
+
+#[no_mangle]
+#[link_section = "maps/counts"]
+pub static mut counts: bpf_map_def = bpf_map_def { type_: 2, key_size: 4, value_size: 8, max_entries: 16, map_flags: 0 };
+
+#[no_mangle]
+#[link_section = "maps/tasks"]
+pub static mut tasks: bpf_map_def = bpf_map_def { type_: 9, key_size: 4, value_size: 32, max_entries: 1024, map_flags: 0 };
+
+#[no_mangle]
+#[link_section = "maps/events"]
+pub static mut events: bpf_map_def = bpf_map_def { type_: 4, key_size: 4, value_size: 4, max_entries: 0, map_flags: 0 };
+
+#[no_mangle]
+#[link_section = "kprobe/do_sys_open"]
+pub extern "C" fn do_sys_open() -> i32 {
+    0
+}
+
+#[no_mangle]
+#[link_section = "xdp/pass"]
+pub extern "C" fn pass() -> i32 {
+    2
+}
+
+#[no_mangle]
+#[link_section = "license"]
+pub static _license: [u8; 4] = *b"GPL\0";
@@ -0,0 +1,17 @@
+# A hash map pinned by the loader, like what
+#
+#     #[map(pinned = "/sys/fs/bpf/redbpf_test_pinned_counts")]
+#     static mut counts: HashMap<u32, u64> = HashMap::with_max_entries(16);
+#
+# is built to. The path to pin the map to is in `maps_pin/<map name>`.
+
+	.section	"maps/counts","aw"
+	.long	1
+	.long	4
+	.long	8
+	.long	16
+	.long	0
+	.section	"maps_pin/counts","a"
+	.asciz	"/sys/fs/bpf/redbpf_test_pinned_counts"
+	.section	"license","aw"
+	.asciz	"GPL"
@@ -0,0 +1,35 @@
+# A kprobe and an XDP program that do nothing, like what a probe with
+#
+#     #[kprobe]
+#     fn do_sys_open(regs: Registers) {}
+#
+#     #[xdp]
+#     fn pass(ctx: XdpContext) -> XdpResult { Ok(XdpAction::Pass) }
+#
+# is built to, for tests that parse modules without loading them. The
+# version is 5.10.0.
+
+	.section	"kprobe/do_sys_open","ax",@progbits
+	.globl	do_sys_open
+	.type	do_sys_open,@function
+do_sys_open:
+	r0 = 0
+	exit
+.Lfunc_end0:
+	.size	do_sys_open, .Lfunc_end0-do_sys_open
+
+	.section	"xdp/pass","ax",@progbits
+	.globl	pass
+	.type	pass,@function
+pass:
+	r0 = 2
+	exit
+.Lfunc_end1:
+	.size	pass, .Lfunc_end1-pass
+
+	.section	"license","aw",@progbits
+	.asciz	"GPL"
+
+	.section	"version","aw",@progbits
+	.p2align	2
+	.long	0x050a00